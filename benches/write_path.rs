@@ -0,0 +1,105 @@
+//! Benchmarks for the path a lookup's result takes on its way to the
+//! caller: the allocator, the `hostent` writer, and the whole
+//! `gethostbyname2_r` glue. These use internals, so they need the
+//! `fuzzing` feature:
+//!
+//! ```text
+//! cargo bench --features fuzzing
+//! ```
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use libc::{c_char, hostent, AF_INET6};
+use nsswitch_service::fuzzing::{self, BumpAllocator};
+use nsswitch_service::macros::call_gethostbyname2_r;
+use nsswitch_service::{AddressFamily, HostAddressList, HostEntry, HostLimits, NameService, Result};
+use std::borrow::Cow;
+use std::ffi::CStr;
+use std::hint::black_box;
+use std::mem;
+use std::net::{IpAddr, Ipv6Addr};
+
+/// Big enough for every entry here, so nothing is measuring the ERANGE path.
+const BUFLEN: usize = 16 * 1024;
+
+fn c_str(bytes: &'static [u8]) -> &'static CStr {
+    CStr::from_bytes_with_nul(bytes).unwrap()
+}
+
+fn host_entry(naddrs: usize) -> HostEntry<'static> {
+    HostEntry {
+        name: Cow::Borrowed(c_str(b"bench.example.test\0")),
+        aliases: vec![Cow::Borrowed(c_str(b"bench\0")), Cow::Borrowed(c_str(b"www.example.test\0"))],
+        addr_list: HostAddressList::V6(
+            (0..naddrs).map(|i| Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, i as u16 + 1)).collect(),
+        ),
+    }
+}
+
+fn bench_alloc(c: &mut Criterion) {
+    let mut buffer = vec![0_u8; BUFLEN];
+    let name = c_str(b"bench.example.test\0");
+    let mut group = c.benchmark_group("BumpAllocator");
+    group.bench_function("allocate", |b| b.iter(|| {
+        let mut alloc = BumpAllocator::new(&mut buffer);
+        for i in 0..64_u64 {
+            black_box(alloc.allocate(i).unwrap());
+        }
+    }));
+    group.bench_function("allocate_array", |b| b.iter(|| {
+        let mut alloc = BumpAllocator::new(&mut buffer);
+        black_box(alloc.allocate_array((0..64).map(|_| name.as_ptr())).unwrap());
+    }));
+    group.bench_function("copy_c_str", |b| b.iter(|| {
+        let mut alloc = BumpAllocator::new(&mut buffer);
+        for _ in 0..64 {
+            black_box(alloc.copy_c_str(black_box(name)).unwrap());
+        }
+    }));
+    group.finish();
+}
+
+fn bench_write_hostent(c: &mut Criterion) {
+    let mut buffer = vec![0_u8; BUFLEN];
+    let mut group = c.benchmark_group("HostEntry::write_to");
+    for &naddrs in &[1, 8, 64] {
+        let entry = host_entry(naddrs);
+        group.throughput(Throughput::Elements(naddrs as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(naddrs), &entry, |b, entry| b.iter(|| unsafe {
+            let mut result: hostent = mem::zeroed();
+            fuzzing::write_hostent(entry, &HostLimits::UNLIMITED, &mut result,
+                                   buffer.as_mut_ptr() as *mut c_char, BUFLEN).unwrap();
+            black_box(result);
+        }));
+    }
+    group.finish();
+}
+
+/// Answers every lookup with eight addresses, built fresh each time as a
+/// real service would.
+struct Eight;
+
+impl NameService for Eight {
+    fn gethostbyname2_r(name: &CStr, _af: AddressFamily) -> Result<Option<HostEntry<'_>>> {
+        Ok(Some(HostEntry { name: Cow::Borrowed(name), ..host_entry(8) }))
+    }
+
+    fn gethostbyaddr_r(_addr: &IpAddr) -> Result<Option<HostEntry<'_>>> {
+        Ok(None)
+    }
+}
+
+fn bench_glue(c: &mut Criterion) {
+    let mut buffer = vec![0_u8; BUFLEN];
+    let name = c_str(b"bench.example.test\0");
+    c.bench_function("call_gethostbyname2_r", |b| b.iter(|| unsafe {
+        let mut result: hostent = mem::zeroed();
+        let (mut errno, mut h_errno) = (0, 0);
+        let status = call_gethostbyname2_r::<Eight>(name.as_ptr(), AF_INET6, &mut result,
+                                                    buffer.as_mut_ptr() as *mut c_char, BUFLEN,
+                                                    &mut errno, &mut h_errno);
+        black_box((status, result));
+    }));
+}
+
+criterion_group!(benches, bench_alloc, bench_write_hostent, bench_glue);
+criterion_main!(benches);
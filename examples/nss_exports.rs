@@ -0,0 +1,100 @@
+//! NSSwitch service library built with both kinds of export macro, for
+//! `tests/exports.rs`, which loads it and checks what it exports.
+//!
+//! The hosts functions are exported with `#[nss_export]`, from an impl that
+//! defines only some of them, and the `passwd` and `group` functions with
+//! `nss_module!`. Hosts under `forked.test` are found only in the child of a
+//! `fork`, after the module's fork hook has run.
+
+use nsswitch_service::{nss_export, nss_module, AddressFamily, Entries, GroupEntry, GroupService, HostAddressList,
+                       HostEntry, NameService, PasswdEntry, PasswdService, Result};
+use std::borrow::Cow;
+use std::ffi::CStr;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static FORKED: AtomicBool = AtomicBool::new(false);
+
+fn c_str(s: &'static [u8]) -> Cow<'static, CStr> {
+    Cow::Borrowed(CStr::from_bytes_with_nul(s).unwrap())
+}
+
+struct ExportedHosts;
+
+#[nss_export(module = "exports")]
+impl NameService for ExportedHosts {
+    fn gethostbyname2_r(name: &CStr, af: AddressFamily) -> Result<Option<HostEntry<'_>>> {
+        let found = match name.to_bytes() {
+            b"exports.test" => true,
+            b"forked.test" => FORKED.load(Ordering::SeqCst),
+            _ => false,
+        };
+        if !found {
+            return Ok(None);
+        }
+        Ok(Some(HostEntry {
+            name: Cow::Borrowed(name),
+            aliases: vec![],
+            addr_list: match af {
+                AddressFamily::Ipv4 => HostAddressList::V4(vec![Ipv4Addr::new(192, 0, 2, 1)]),
+                AddressFamily::Ipv6 => HostAddressList::V6(vec![Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1)]),
+            },
+        }))
+    }
+
+    fn gethostbyaddr_r(_addr: &IpAddr) -> Result<Option<HostEntry<'_>>> {
+        Ok(None)
+    }
+
+    fn sethostent(_stay_open: bool) -> Result<Entries<HostEntry<'static>>> {
+        Ok(Box::new(std::iter::once(Ok(HostEntry {
+            name: c_str(b"exports.test\0"),
+            aliases: vec![],
+            addr_list: HostAddressList::V4(vec![Ipv4Addr::new(192, 0, 2, 1)]),
+        }))))
+    }
+
+    fn on_fork_child() {
+        FORKED.store(true, Ordering::SeqCst);
+    }
+}
+
+struct Accounts;
+
+fn user() -> PasswdEntry<'static> {
+    PasswdEntry {
+        name: c_str(b"exporter\0"),
+        passwd: c_str(b"x\0"),
+        uid: 4242,
+        gid: 4242,
+        gecos: c_str(b"\0"),
+        dir: c_str(b"/nonexistent\0"),
+        shell: c_str(b"/bin/false\0"),
+    }
+}
+
+fn group() -> GroupEntry<'static> {
+    GroupEntry { name: c_str(b"exporters\0"), passwd: c_str(b"x\0"), gid: 4242, members: vec![c_str(b"exporter\0")] }
+}
+
+impl PasswdService for Accounts {
+    fn getpwnam_r(name: &CStr) -> Result<Option<PasswdEntry<'_>>> {
+        Ok(Some(user()).filter(|user| *user.name == *name))
+    }
+
+    fn getpwuid_r(uid: nsswitch_service::uid_t) -> Result<Option<PasswdEntry<'static>>> {
+        Ok(Some(user()).filter(|user| user.uid == uid))
+    }
+}
+
+impl GroupService for Accounts {
+    fn getgrnam_r(name: &CStr) -> Result<Option<GroupEntry<'_>>> {
+        Ok(Some(group()).filter(|group| *group.name == *name))
+    }
+
+    fn getgrgid_r(gid: nsswitch_service::gid_t) -> Result<Option<GroupEntry<'static>>> {
+        Ok(Some(group()).filter(|group| group.gid == gid))
+    }
+}
+
+nss_module!("exports", Accounts, [passwd, group]);
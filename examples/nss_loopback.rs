@@ -1,20 +1,25 @@
-/// NSSwitch service library that maps whole top-level domains to localhost.
-///
-/// For example, with this library installed, the command
-/// `LOOPBACK_DOMAINS=test nc example.test 80` will try to connect to
-/// 127.0.0.1:80, because the domain `example.test` maps to 127.0.0.1.
-
-#[macro_use]
-extern crate nsswitch_service;
-
-use nsswitch_service::{AddressFamily, NameService, HostEntry, HostAddressList, Result};
+//! NSSwitch service library that maps whole top-level domains to localhost.
+//!
+//! For example, with this library installed, the command
+//! `LOOPBACK_DOMAINS=test nc example.test 80` will try to connect to
+//! 127.0.0.1:80, because the domain `example.test` maps to 127.0.0.1.
+//! Setuid programs ignore `LOOPBACK_DOMAINS` and use the default, `test`.
+
+use nsswitch_service::{env_var, nss_export, nss_rustinfo, AddressFamily, NameService, HostEntry, HostAddressList, Result};
 use std::ffi::CStr;
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 
 struct LoopbackService;
 
+#[nss_export(module = "loopback")]
 impl NameService for LoopbackService {
-    fn gethostbyname2_r(name: &CStr, af: AddressFamily) -> Result<Option<HostEntry>> {
+    // `#[nss_export]` exports only the methods defined here, and programs
+    // that call plain `gethostbyname` need this one.
+    fn gethostbyname_r(name: &CStr) -> Result<Option<HostEntry<'_>>> {
+        Self::gethostbyname2_r(name, AddressFamily::Ipv4)
+    }
+
+    fn gethostbyname2_r(name: &CStr, af: AddressFamily) -> Result<Option<HostEntry<'_>>> {
         use std::borrow::Cow;
 
         // Convert the C null-terminated string `name` to a Rust string.
@@ -28,7 +33,7 @@ impl NameService for LoopbackService {
             Some(index) => &name_str[index + 1..],
         };
 
-        let domains = std::env::var("LOOPBACK_DOMAINS").unwrap_or_else(|_| "test".to_string());
+        let domains = env_var("LOOPBACK_DOMAINS").unwrap_or_else(|| "test".to_string());
         for domain in domains.split(',') {
             if name_tld.eq_ignore_ascii_case(domain) {
                 return Ok(Some(HostEntry {
@@ -49,11 +54,9 @@ impl NameService for LoopbackService {
         Ok(None)
     }
 
-    fn gethostbyaddr_r(_addr: &IpAddr) -> Result<Option<HostEntry>> {
+    fn gethostbyaddr_r(_addr: &IpAddr) -> Result<Option<HostEntry<'_>>> {
         Ok(None)
     }
 }
 
-nssglue_gethostbyname_r!(_nss_loopback_gethostbyname_r, LoopbackService);
-nssglue_gethostbyname2_r!(_nss_loopback_gethostbyname2_r, LoopbackService);
-nssglue_gethostbyaddr_r!(_nss_loopback_gethostbyaddr_r, LoopbackService);
+nss_rustinfo!("loopback", [hosts]);
@@ -0,0 +1,14 @@
+//! NSSwitch service library that resolves the names listed in a TOML file,
+//! `/etc/nss_static_map.toml`. See `examples/static_map.toml` for the format.
+
+use nsswitch_service::{nss_rustinfo, nssglue_hosts, StaticMapConfig, StaticMapService};
+
+struct StaticMap;
+
+impl StaticMapConfig for StaticMap {
+    const PATH: &'static str = "/etc/nss_static_map.toml";
+}
+
+nssglue_hosts!("static_map", StaticMapService<StaticMap>);
+
+nss_rustinfo!("static_map", [hosts]);
@@ -0,0 +1,112 @@
+//! Run arbitrary allocations against a `BumpAllocator` over a buffer of
+//! arbitrary size and alignment, and check that every allocation lands
+//! inside the buffer, aligned, without overlapping another, and that
+//! nothing outside the buffer is written.
+
+#![no_main]
+
+use arbitrary::Arbitrary;
+use libfuzzer_sys::fuzz_target;
+use nsswitch_service::fuzzing::BumpAllocator;
+use std::ffi::CString;
+use std::mem;
+
+/// Bytes on either side of the buffer, which must never change.
+const GUARD: usize = 64;
+const CANARY: u8 = 0xa5;
+
+#[derive(Arbitrary, Debug)]
+enum Op {
+    U8(u8),
+    U16(u16),
+    U32(u32),
+    U64(u64),
+    Array(Vec<u32>),
+    CStr(Vec<u8>),
+}
+
+#[derive(Arbitrary, Debug)]
+struct Input {
+    offset: u8,
+    len: u16,
+    ops: Vec<Op>,
+}
+
+fn align_up(p: usize, align: usize) -> usize {
+    (p + align - 1) / align * align
+}
+
+fuzz_target!(|input: Input| {
+    let offset = input.offset as usize % 16;
+    let len = input.len as usize % 4096;
+    let start = GUARD + offset;
+    let mut backing = vec![CANARY; start + len + GUARD];
+    let base = backing[start..].as_ptr() as usize;
+    let end = base + len;
+
+    {
+        let mut allocator = BumpAllocator::new(&mut backing[start..start + len]);
+        // The end of the last allocation, while it's known: a failed array
+        // allocation can leave part of the array behind.
+        let mut point = Some(base);
+        let mut last_end = base;
+
+        for op in &input.ops {
+            // Each arm returns where the allocation went, if it did, plus
+            // its size and alignment.
+            let (result, size, align) = match *op {
+                Op::U8(v) => (allocator.allocate(v).map(|r| { assert_eq!(*r, v); r as *mut u8 as usize }), 1, 1),
+                Op::U16(v) => (allocator.allocate(v).map(|r| { assert_eq!(*r, v); r as *mut u16 as usize }),
+                               2, mem::align_of::<u16>()),
+                Op::U32(v) => (allocator.allocate(v).map(|r| { assert_eq!(*r, v); r as *mut u32 as usize }),
+                               4, mem::align_of::<u32>()),
+                Op::U64(v) => (allocator.allocate(v).map(|r| { assert_eq!(*r, v); r as *mut u64 as usize }),
+                               8, mem::align_of::<u64>()),
+                Op::Array(ref values) => {
+                    let result = allocator.allocate_array(values.iter().cloned()).map(|array| {
+                        assert_eq!(&array[..], &values[..]);
+                        array.as_ptr() as usize
+                    });
+                    (result, values.len() * 4, mem::align_of::<u32>())
+                }
+                Op::CStr(ref bytes) => {
+                    let s = CString::new(bytes.iter().cloned().filter(|&b| b != 0).collect::<Vec<u8>>()).unwrap();
+                    let result = allocator.copy_c_str(&s).map(|copy| {
+                        assert_eq!(copy, s.as_c_str());
+                        copy.as_ptr() as usize
+                    });
+                    (result, s.as_bytes_with_nul().len(), 1)
+                }
+            };
+
+            match result {
+                Ok(p) => {
+                    assert!(p >= last_end && p <= end && size <= end - p, "allocation outside the buffer");
+                    assert_eq!(p % align, 0, "misaligned allocation");
+                    if let Some(point) = point {
+                        assert_eq!(p, align_up(point, align), "allocation skipped space");
+                    }
+                    last_end = p + size;
+                    point = Some(last_end);
+                }
+                Err(_) => {
+                    if let Some(p) = point {
+                        let aligned = align_up(p, align);
+                        assert!(aligned + size > end, "allocation failed with room left");
+                        // The padding stays used if there was room for it.
+                        if aligned <= end {
+                            point = Some(aligned);
+                            last_end = aligned;
+                        }
+                    }
+                    if let Op::Array(_) = *op {
+                        point = None;
+                    }
+                }
+            }
+        }
+    }
+
+    assert!(backing[..start].iter().all(|&b| b == CANARY), "wrote before the buffer");
+    assert!(backing[start + len..].iter().all(|&b| b == CANARY), "wrote after the buffer");
+});
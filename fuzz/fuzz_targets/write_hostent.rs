@@ -0,0 +1,113 @@
+//! Write arbitrary host entries into buffers of arbitrary size and
+//! alignment, with arbitrary limits, and check the result: either it reads
+//! back as the entry cut down to the limits, or the write failed with
+//! `ERANGE` (and succeeds with a big enough buffer) or for a name over the
+//! limit. Either way, nothing outside the buffer and the `hostent` is
+//! written, and on failure the `hostent` is untouched.
+
+#![no_main]
+
+use arbitrary::Arbitrary;
+use libfuzzer_sys::fuzz_target;
+use nsswitch_service::fuzzing::write_hostent;
+use nsswitch_service::{HostAddressList, HostEntry, HostLimits, NssStatus};
+use std::borrow::Cow;
+use std::ffi::{CStr, CString};
+use std::mem::{self, MaybeUninit};
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+const GUARD: usize = 64;
+const CANARY: u8 = 0xa5;
+
+#[derive(Arbitrary, Debug)]
+struct Input {
+    offset: u8,
+    buflen: u16,
+    name: Vec<u8>,
+    aliases: Vec<Vec<u8>>,
+    ipv6: bool,
+    addrs: Vec<[u8; 16]>,
+    max_aliases: u8,
+    max_addresses: u8,
+    max_name_len: u16,
+}
+
+fn c_string(bytes: &[u8]) -> CString {
+    CString::new(bytes.iter().cloned().filter(|&b| b != 0).collect::<Vec<u8>>()).unwrap()
+}
+
+fn hostent_bytes(h: &MaybeUninit<libc::hostent>) -> &[u8] {
+    unsafe { std::slice::from_raw_parts(h.as_ptr() as *const u8, mem::size_of::<libc::hostent>()) }
+}
+
+fuzz_target!(|input: Input| {
+    let name = c_string(&input.name);
+    let aliases: Vec<CString> = input.aliases.iter().map(|a| c_string(a)).collect();
+    let addr_list = if input.ipv6 {
+        HostAddressList::V6(input.addrs.iter().map(|&a| Ipv6Addr::from(a)).collect())
+    } else {
+        HostAddressList::V4(input.addrs.iter().map(|a| Ipv4Addr::new(a[0], a[1], a[2], a[3])).collect())
+    };
+    let entry = HostEntry {
+        name: Cow::Borrowed(name.as_c_str()),
+        aliases: aliases.iter().map(|a| Cow::Borrowed(a.as_c_str())).collect(),
+        addr_list,
+    };
+    let limits = HostLimits {
+        max_aliases: input.max_aliases as usize,
+        max_addresses: input.max_addresses as usize,
+        max_name_len: input.max_name_len as usize,
+    };
+
+    let offset = input.offset as usize % 16;
+    let buflen = input.buflen as usize % 4096;
+    let start = GUARD + offset;
+    let mut backing = vec![CANARY; start + buflen + GUARD];
+    let mut result = MaybeUninit::<libc::hostent>::uninit();
+    unsafe {
+        std::ptr::write_bytes(result.as_mut_ptr(), CANARY, 1);
+    }
+
+    let buffer = backing[start..].as_mut_ptr() as *mut libc::c_char;
+    match unsafe { write_hostent(&entry, &limits, result.as_mut_ptr(), buffer, buflen) } {
+        Ok(()) => {
+            let copy = unsafe { HostEntry::read_from(&*result.as_ptr()) };
+            assert_eq!(copy.name, entry.name);
+            let expected_aliases: Vec<&CStr> = entry.aliases.iter()
+                .map(|a| &**a)
+                .filter(|a| a.to_bytes().len() <= limits.max_name_len)
+                .take(limits.max_aliases)
+                .collect();
+            let aliases: Vec<&CStr> = copy.aliases.iter().map(|a| &**a).collect();
+            assert_eq!(aliases, expected_aliases);
+            match (&copy.addr_list, &entry.addr_list) {
+                (HostAddressList::V4(a), HostAddressList::V4(b)) => {
+                    assert_eq!(a[..], b[..b.len().min(limits.max_addresses)]);
+                }
+                (HostAddressList::V6(a), HostAddressList::V6(b)) => {
+                    assert_eq!(a[..], b[..b.len().min(limits.max_addresses)]);
+                }
+                _ => panic!("address family changed"),
+            }
+        }
+        Err(err) => {
+            assert!(hostent_bytes(&result).iter().all(|&b| b == CANARY), "failed write changed the hostent");
+            if err.status() == NssStatus::TryAgain {
+                assert_eq!(err.errno(), libc::ERANGE);
+                // glibc's answer to ERANGE is a bigger buffer, which has
+                // to work eventually.
+                let mut big = vec![0_u8; 1 << 20];
+                let status = unsafe {
+                    write_hostent(&entry, &limits, result.as_mut_ptr(), big.as_mut_ptr() as *mut libc::c_char,
+                                  big.len())
+                };
+                assert!(status.is_ok(), "ERANGE with a big enough buffer");
+            } else {
+                assert!(name.as_bytes().len() > limits.max_name_len, "unexpected error {:?}", err);
+            }
+        }
+    }
+
+    assert!(backing[..start].iter().all(|&b| b == CANARY), "wrote before the buffer");
+    assert!(backing[start + buflen..].iter().all(|&b| b == CANARY), "wrote after the buffer");
+});
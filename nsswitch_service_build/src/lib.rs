@@ -0,0 +1,367 @@
+//! Helpers for the `build.rs` of a crate that builds an NSS module with
+//! `nsswitch_service`.
+//!
+//! Add `nsswitch_service_build` to `[build-dependencies]`, then in
+//! `build.rs`:
+//!
+//! ```ignore
+//! fn main() {
+//!     nsswitch_service_build::configure("loopback");
+//! }
+//! ```
+//!
+//! and in `Cargo.toml`, build the library as a `cdylib`:
+//!
+//! ```toml
+//! [lib]
+//! crate-type = ["cdylib"]
+//! ```
+//!
+//! glibc loads the module for the `loopback` entry in `/etc/nsswitch.conf`
+//! from a file named `libnss_loopback.so.2` (see `library_file_name`).
+//! Cargo names the library after the crate, and a build script can't change
+//! that, so install `target/release/libYOURCRATE.so` under the right name.
+//!
+//! On FreeBSD the file is `nss_loopback.so.1`, and the module also needs
+//! `nsswitch_service::nss_freebsd_module!`; on NetBSD, `nss_loopback.so.0`
+//! and `nss_netbsd_module!`; on illumos and Solaris, `nss_loopback.so.1`.
+//!
+//! For C test suites and packagers, `write_c_header` produces a header that
+//! declares the module's entry points with their exact C signatures.
+
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::process::Command;
+
+/// The environment variable `module_name` reads, and passes on to the
+/// compiler.
+pub const MODULE_NAME_VAR: &str = "NSS_MODULE_NAME";
+
+/// Decide the module's name at build time: the value of the `NSS_MODULE_NAME`
+/// environment variable if it is set, otherwise `default`.
+///
+/// This lets one crate build differently named modules, e.g.
+/// `NSS_MODULE_NAME=corp_stage cargo build --release` for
+/// `libnss_corp_stage.so.2`. The name is passed on to the compiler, so the
+/// export macros can use it with `env("NSS_MODULE_NAME", "corp")`, and Cargo
+/// rebuilds the crate when it changes.
+///
+/// ```ignore
+/// fn main() {
+///     let name = nsswitch_service_build::module_name("corp");
+///     nsswitch_service_build::configure(&name);
+/// }
+/// ```
+pub fn module_name(default: &str) -> String {
+    println!("cargo:rerun-if-env-changed={}", MODULE_NAME_VAR);
+    let name = env::var(MODULE_NAME_VAR).unwrap_or_else(|_| default.to_string());
+    check_module_name(&name);
+    println!("cargo:rustc-env={}={}", MODULE_NAME_VAR, name);
+    name
+}
+
+/// The file name glibc looks for when loading the NSS module named
+/// `module_name`, e.g. `libnss_loopback.so.2`.
+///
+/// The `.2` is the interface version of the NSS module API, which hasn't
+/// changed since glibc 2.1. When called from a build script for a BSD
+/// target, this is that system's name instead: `nss_loopback.so.1` on
+/// FreeBSD, `nss_loopback.so.0` on NetBSD. illumos and Solaris also use
+/// `nss_loopback.so.1`.
+pub fn library_file_name(module_name: &str) -> String {
+    match target_os().as_deref() {
+        Some("freebsd") | Some("illumos") | Some("solaris") => format!("nss_{}.so.1", module_name),
+        Some("netbsd") => format!("nss_{}.so.0", module_name),
+        _ => format!("libnss_{}.so.2", module_name),
+    }
+}
+
+/// The operating system a build script is building for. Outside build
+/// scripts the target is unknown.
+fn target_os() -> Option<String> {
+    env::var("CARGO_CFG_TARGET_OS").ok()
+}
+
+/// True if the target's `nsdispatch(3)` calls `nss_module_register`.
+fn is_bsd_target() -> bool {
+    matches!(target_os().as_deref(), Some("freebsd") | Some("netbsd"))
+}
+
+/// Do everything an NSS module's build script needs: set the library's
+/// soname to `library_file_name(module_name)`, keep dependencies' symbols
+/// out of its exports (see `export_only_nss_symbols`), and tell the export
+/// macros which glibc the module is for (see `set_glibc_version`).
+pub fn configure(module_name: &str) {
+    set_soname(module_name);
+    export_only_nss_symbols(module_name);
+    set_glibc_version();
+}
+
+/// The environment variable that gives the target's glibc version, as
+/// `MAJOR.MINOR`, both to `glibc_version` and to the export macros.
+pub const GLIBC_VERSION_VAR: &str = "NSS_GLIBC_VERSION";
+
+/// The version of glibc the module is being built for, if known.
+///
+/// This is `NSS_GLIBC_VERSION` if it's set, for example to build on a new
+/// system for an old one. Otherwise, for a native build, it's the build
+/// machine's glibc, as `getconf GNU_LIBC_VERSION` reports it. When
+/// cross-compiling, or for targets without glibc, it's unknown.
+pub fn glibc_version() -> Option<(u32, u32)> {
+    println!("cargo:rerun-if-env-changed={}", GLIBC_VERSION_VAR);
+    if let Ok(version) = env::var(GLIBC_VERSION_VAR) {
+        return Some(parse_glibc_version(&version).unwrap_or_else(|| {
+            panic!("invalid {} {:?}: expected a version like 2.17", GLIBC_VERSION_VAR, version)
+        }));
+    }
+    if env::var("CARGO_CFG_TARGET_ENV").ok().as_deref() != Some("gnu")
+        || env::var_os("TARGET") != env::var_os("HOST")
+    {
+        return None;
+    }
+    let output = Command::new("getconf").arg("GNU_LIBC_VERSION").output().ok()?;
+    // "glibc 2.36"
+    let output = String::from_utf8(output.stdout).ok()?;
+    parse_glibc_version(output.trim().strip_prefix("glibc ")?)
+}
+
+fn parse_glibc_version(version: &str) -> Option<(u32, u32)> {
+    let mut parts = version.trim().splitn(3, '.');
+    let major = parts.next()?.parse().ok()?;
+    let minor = parts.next()?.parse().ok()?;
+    Some((major, minor))
+}
+
+/// Pass `glibc_version()`, if known, on to the export macros, which then
+/// leave out functions that glibc never calls. For example, glibc before
+/// 2.9 has no use for `gethostbyname4_r`.
+///
+/// glibc looks functions up by name, so exporting one it doesn't know is
+/// harmless, but leaving it out keeps tests and tools that list a module's
+/// functions honest about what will run.
+pub fn set_glibc_version() {
+    if let Some((major, minor)) = glibc_version() {
+        println!("cargo:rustc-env={}={}.{}", GLIBC_VERSION_VAR, major, minor);
+    }
+}
+
+/// Set the `DT_SONAME` of the crate's `cdylib` to
+/// `library_file_name(module_name)`, so tools like `ldconfig` and package
+/// managers see the name glibc will use to load it.
+pub fn set_soname(module_name: &str) {
+    check_module_name(module_name);
+    println!("cargo:rustc-cdylib-link-arg=-Wl,-soname,{}", library_file_name(module_name));
+}
+
+/// The text of a linker version script that exports the `_nss_NAME_*`
+/// functions of the module named `module_name` and hides every other symbol.
+/// For a FreeBSD or NetBSD target, it also exports `nss_module_register`
+/// (see `nss_freebsd_module!`). On its own, it can't hide anything rustc
+/// exports; see `export_only_nss_symbols`.
+pub fn version_script(module_name: &str) -> String {
+    let register = if is_bsd_target() { "        nss_module_register;\n" } else { "" };
+    format!(
+        "{{\n    global:\n        _nss_{}_*;\n{}    local:\n        *;\n}};\n",
+        module_name, register
+    )
+}
+
+/// Link the crate's `cdylib` so that it exports the entry points of the NSS
+/// module named `module_name`, and nothing from its dependencies.
+///
+/// NSS modules are loaded into arbitrary processes, often next to other
+/// modules built with Rust. Any other symbol the library exports (for
+/// example, a `#[no_mangle]` function in a dependency, or a C library linked
+/// in statically) can collide with symbols of the same name in the process.
+///
+/// rustc gives the linker a version script of its own, which lists every
+/// `#[no_mangle]` function in the crate and its dependencies as global, and
+/// the linker merges it with this one, so `version_script` alone hides
+/// nothing. What does the hiding is `--exclude-libs ALL`, which keeps every
+/// symbol from a statically linked library out of the dynamic symbol table;
+/// each dependency, in Rust or C, is one. So the export macros have to be
+/// used in the crate that builds the `cdylib`, not in a dependency, and any
+/// other `#[no_mangle]` function in that crate is still exported.
+///
+/// This writes a version script to `OUT_DIR` and tells Cargo to pass it to
+/// the linker, along with `--exclude-libs ALL`. Call it from `build.rs`. It
+/// panics if it can't write the script, which makes the build fail.
+pub fn export_only_nss_symbols(module_name: &str) {
+    check_module_name(module_name);
+    let out_dir = PathBuf::from(env::var_os("OUT_DIR").expect("OUT_DIR not set; call this from build.rs"));
+    let path = out_dir.join(format!("libnss_{}.map", module_name));
+    fs::write(&path, version_script(module_name))
+        .unwrap_or_else(|err| panic!("can't write {}: {}", path.display(), err));
+    println!("cargo:rustc-cdylib-link-arg=-Wl,--version-script={}", path.display());
+    println!("cargo:rustc-cdylib-link-arg=-Wl,--exclude-libs,ALL");
+}
+
+/// The C prototypes of each database's NSS functions, with `NAME` standing
+/// for the module name.
+const PROTOTYPES: &[(&str, &[&str])] = &[
+    ("hosts", &[
+        "enum nss_status _nss_NAME_gethostbyname_r(const char *name, struct hostent *result, \
+         char *buffer, size_t buflen, int *errnop, int *h_errnop);",
+        "enum nss_status _nss_NAME_gethostbyname2_r(const char *name, int af, struct hostent *result, \
+         char *buffer, size_t buflen, int *errnop, int *h_errnop);",
+        "enum nss_status _nss_NAME_gethostbyname3_r(const char *name, int af, struct hostent *result, \
+         char *buffer, size_t buflen, int *errnop, int *h_errnop, int32_t *ttlp, char **canonp);",
+        "enum nss_status _nss_NAME_gethostbyname4_r(const char *name, struct gaih_addrtuple **pat, \
+         char *buffer, size_t buflen, int *errnop, int *h_errnop, int32_t *ttlp);",
+        "enum nss_status _nss_NAME_gethostbyaddr_r(const void *addr, int len, int af, \
+         struct hostent *result, char *buffer, size_t buflen, int *errnop, int *h_errnop);",
+        "enum nss_status _nss_NAME_gethostbyaddr2_r(const void *addr, int len, int af, \
+         struct hostent *result, char *buffer, size_t buflen, int *errnop, int *h_errnop, \
+         int32_t *ttlp);",
+        "enum nss_status _nss_NAME_sethostent(int stayopen);",
+        "enum nss_status _nss_NAME_gethostent_r(struct hostent *result, char *buffer, size_t buflen, \
+         int *errnop, int *h_errnop);",
+        "enum nss_status _nss_NAME_endhostent(void);",
+    ]),
+    ("passwd", &[
+        "enum nss_status _nss_NAME_getpwnam_r(const char *name, struct passwd *result, \
+         char *buffer, size_t buflen, int *errnop);",
+        "enum nss_status _nss_NAME_getpwuid_r(uid_t uid, struct passwd *result, \
+         char *buffer, size_t buflen, int *errnop);",
+        "enum nss_status _nss_NAME_setpwent(void);",
+        "enum nss_status _nss_NAME_getpwent_r(struct passwd *result, char *buffer, size_t buflen, \
+         int *errnop);",
+        "enum nss_status _nss_NAME_endpwent(void);",
+    ]),
+    ("group", &[
+        "enum nss_status _nss_NAME_getgrnam_r(const char *name, struct group *result, \
+         char *buffer, size_t buflen, int *errnop);",
+        "enum nss_status _nss_NAME_getgrgid_r(gid_t gid, struct group *result, \
+         char *buffer, size_t buflen, int *errnop);",
+        "enum nss_status _nss_NAME_setgrent(void);",
+        "enum nss_status _nss_NAME_getgrent_r(struct group *result, char *buffer, size_t buflen, \
+         int *errnop);",
+        "enum nss_status _nss_NAME_endgrent(void);",
+        "enum nss_status _nss_NAME_initgroups_dyn(const char *user, gid_t group, long int *start, \
+         long int *size, gid_t **groupsp, long int limit, int *errnop);",
+    ]),
+    ("shadow", &[
+        "enum nss_status _nss_NAME_getspnam_r(const char *name, struct spwd *result, \
+         char *buffer, size_t buflen, int *errnop);",
+        "enum nss_status _nss_NAME_setspent(void);",
+        "enum nss_status _nss_NAME_getspent_r(struct spwd *result, char *buffer, size_t buflen, \
+         int *errnop);",
+        "enum nss_status _nss_NAME_endspent(void);",
+    ]),
+];
+
+/// The text of a C header declaring the functions that the module named
+/// `module_name` exports for the given `databases` (`"hosts"`, `"passwd"`,
+/// `"group"`, `"shadow"`), for C test suites and packaging checks.
+///
+/// The header relies on glibc's `<nss.h>` for `enum nss_status` and
+/// `struct gaih_addrtuple`, and on the usual system headers for the record
+/// types. It declares every function of each database; a module that skips
+/// some of them simply doesn't define those.
+///
+/// Panics if a database name is unknown.
+pub fn c_header(module_name: &str, databases: &[&str]) -> String {
+    check_module_name(module_name);
+    let guard = format!("LIBNSS_{}_H", module_name.to_ascii_uppercase());
+    let mut header = format!(
+        "/* Entry points of the NSS module {}, generated by nsswitch_service_build. */\n\
+         \n\
+         #ifndef {guard}\n\
+         #define {guard}\n\
+         \n\
+         #include <stddef.h>\n\
+         #include <stdint.h>\n\
+         #include <sys/types.h>\n\
+         #include <nss.h>\n\
+         #include <netdb.h>\n\
+         #include <pwd.h>\n\
+         #include <grp.h>\n\
+         #include <shadow.h>\n\
+         \n\
+         #ifdef __cplusplus\n\
+         extern \"C\" {{\n\
+         #endif\n",
+        library_file_name(module_name),
+        guard = guard,
+    );
+    for database in databases {
+        let prototypes = PROTOTYPES.iter()
+            .find(|&&(db, _)| db == *database)
+            .unwrap_or_else(|| panic!("unknown NSS database {:?}", database))
+            .1;
+        header.push_str(&format!("\n/* {} */\n", database));
+        for prototype in prototypes {
+            header.push_str(&prototype.replace("NAME", module_name));
+            header.push('\n');
+        }
+    }
+    header.push_str(&format!(
+        "\n#ifdef __cplusplus\n}}\n#endif\n\n#endif /* {} */\n",
+        guard
+    ));
+    header
+}
+
+/// Write `c_header(module_name, databases)` to `libnss_NAME.h` in `OUT_DIR`
+/// and return its path. Call this from `build.rs`; copy the file wherever
+/// the C side of the build needs it.
+pub fn write_c_header(module_name: &str, databases: &[&str]) -> PathBuf {
+    let out_dir = PathBuf::from(env::var_os("OUT_DIR").expect("OUT_DIR not set; call this from build.rs"));
+    let path = out_dir.join(format!("libnss_{}.h", module_name));
+    fs::write(&path, c_header(module_name, databases))
+        .unwrap_or_else(|err| panic!("can't write {}: {}", path.display(), err));
+    path
+}
+
+fn check_module_name(module_name: &str) {
+    if module_name.is_empty()
+        || !module_name.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'_')
+    {
+        panic!(
+            "invalid NSS module name {:?}: must be nonempty and contain only ASCII letters, digits, and `_`",
+            module_name
+        );
+    }
+}
+
+#[test]
+fn test_version_script() {
+    assert_eq!(
+        version_script("loopback"),
+        "{\n    global:\n        _nss_loopback_*;\n    local:\n        *;\n};\n"
+    );
+}
+
+#[test]
+fn test_parse_glibc_version() {
+    assert_eq!(parse_glibc_version("2.17"), Some((2, 17)));
+    assert_eq!(parse_glibc_version("2.36.9000\n"), Some((2, 36)));
+    assert_eq!(parse_glibc_version("2"), None);
+}
+
+#[test]
+fn test_c_header() {
+    let header = c_header("loopback", &["passwd"]);
+    assert!(header.starts_with("/* Entry points of the NSS module libnss_loopback.so.2"));
+    assert!(header.contains("#ifndef LIBNSS_LOOPBACK_H\n"));
+    assert!(header.contains(
+        "enum nss_status _nss_loopback_getpwuid_r(uid_t uid, struct passwd *result, \
+         char *buffer, size_t buflen, int *errnop);\n"
+    ));
+    assert!(header.contains("enum nss_status _nss_loopback_endpwent(void);\n"));
+    assert!(!header.contains("hostent"));
+    assert!(header.contains("#include <shadow.h>\n"));
+
+    let header = c_header("loopback", &["group", "shadow"]);
+    assert!(header.contains(
+        "enum nss_status _nss_loopback_initgroups_dyn(const char *user, gid_t group, long int *start, \
+         long int *size, gid_t **groupsp, long int limit, int *errnop);\n"
+    ));
+    assert!(header.contains(
+        "enum nss_status _nss_loopback_getspnam_r(const char *name, struct spwd *result, \
+         char *buffer, size_t buflen, int *errnop);\n"
+    ));
+    assert!(header.contains("enum nss_status _nss_loopback_endspent(void);\n"));
+    assert!(header.ends_with("#endif /* LIBNSS_LOOPBACK_H */\n"));
+}
@@ -0,0 +1,62 @@
+//! Build a small NSS module whose build script calls `configure`, and check
+//! with `nm -D` which symbols it exports.
+
+#![cfg(target_os = "linux")]
+
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+fn write(path: &Path, contents: &str) {
+    fs::create_dir_all(path.parent().unwrap()).unwrap();
+    fs::write(path, contents).unwrap();
+}
+
+#[test]
+fn test_configure_hides_dependencies() {
+    let root = Path::new(env!("CARGO_TARGET_TMPDIR")).join("link_test");
+    let _ = fs::remove_dir_all(&root);
+
+    // A dependency with a `#[no_mangle]` function, which rustc exports from
+    // any `cdylib` it's linked into.
+    write(&root.join("dep/Cargo.toml"), "[package]\nname = \"dep\"\nversion = \"0.1.0\"\nedition = \"2018\"\n");
+    write(&root.join("dep/src/lib.rs"), "\
+        #[no_mangle]\n\
+        pub extern \"C\" fn dep_exported() -> i32 { 7 }\n");
+
+    write(&root.join("module/Cargo.toml"), &format!("\
+        [package]\nname = \"module\"\nversion = \"0.1.0\"\nedition = \"2018\"\n\
+        [lib]\ncrate-type = [\"cdylib\"]\n\
+        [dependencies]\ndep = {{ path = \"../dep\" }}\n\
+        [build-dependencies]\nnsswitch_service_build = {{ path = {:?} }}\n\
+        [workspace]\n",
+        env!("CARGO_MANIFEST_DIR")));
+    write(&root.join("module/build.rs"), "fn main() { nsswitch_service_build::configure(\"linktest\"); }\n");
+    write(&root.join("module/src/lib.rs"), "\
+        #[no_mangle]\n\
+        pub extern \"C\" fn _nss_linktest_endpwent() -> i32 { dep::dep_exported() }\n");
+
+    let cargo = env::var_os("CARGO").unwrap_or_else(|| "cargo".into());
+    let status = Command::new(cargo)
+        .args(["build", "--quiet", "--offline", "--manifest-path"])
+        .arg(root.join("module/Cargo.toml"))
+        .arg("--target-dir")
+        .arg(root.join("target"))
+        .status()
+        .unwrap();
+    assert!(status.success());
+
+    let library = root.join("target/debug/libmodule.so");
+    let output = Command::new("nm").args(["-D", "--defined-only"]).arg(&library).output().unwrap();
+    assert!(output.status.success());
+    let symbols: Vec<String> = String::from_utf8(output.stdout).unwrap()
+        .lines()
+        .filter_map(|line| line.split_whitespace().nth(2))
+        .map(String::from)
+        .collect();
+    assert_eq!(symbols, ["_nss_linktest_endpwent"]);
+
+    let output = Command::new("readelf").arg("-d").arg(&library).output().unwrap();
+    assert!(String::from_utf8_lossy(&output.stdout).contains("[libnss_linktest.so.2]"));
+}
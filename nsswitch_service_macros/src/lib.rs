@@ -0,0 +1,938 @@
+//! Procedural macros for `nsswitch_service`. Use them through the
+//! re-exports in that crate, not directly.
+
+use proc_macro::TokenStream;
+use proc_macro2::Span;
+use quote::quote;
+use std::env;
+use syn::spanned::Spanned;
+use syn::parse::{Parse, ParseStream};
+use syn::punctuated::Punctuated;
+use syn::{bracketed, parse_macro_input, Expr, ExprLit, ImplItem, ItemImpl, Lit, MetaNameValue};
+use syn::{Ident, LitStr, Token, Type};
+
+/// A piece of a database that a service may or may not implement: a name,
+/// which is what users write to skip it, and the functions it consists of.
+/// Each function is exported as `_nss_NAME_function`, using the
+/// `nssglue_function!` macro.
+type Piece = (&'static str, &'static [&'static str]);
+
+const HOSTS: &[Piece] = &[
+    ("gethostbyname_r", &["gethostbyname_r"]),
+    ("gethostbyname2_r", &["gethostbyname2_r"]),
+    ("gethostbyname3_r", &["gethostbyname3_r"]),
+    ("gethostbyname4_r", &["gethostbyname4_r"]),
+    ("gethostbyaddr_r", &["gethostbyaddr_r"]),
+    ("gethostbyaddr2_r", &["gethostbyaddr2_r"]),
+    ("gethostent_r", &["sethostent", "gethostent_r", "endhostent"]),
+];
+
+const PASSWD: &[Piece] = &[
+    ("getpwnam_r", &["getpwnam_r"]),
+    ("getpwuid_r", &["getpwuid_r"]),
+    ("getpwent_r", &["setpwent", "getpwent_r", "endpwent"]),
+];
+
+const GROUP: &[Piece] = &[
+    ("getgrnam_r", &["getgrnam_r"]),
+    ("getgrgid_r", &["getgrgid_r"]),
+    ("initgroups_dyn", &["initgroups_dyn"]),
+    ("getgrent_r", &["setgrent", "getgrent_r", "endgrent"]),
+];
+
+const SHADOW: &[Piece] = &[
+    ("getspnam_r", &["getspnam_r"]),
+    ("getspent_r", &["setspent", "getspent_r", "endspent"]),
+];
+
+const DATABASES: &[(&str, &[Piece])] = &[
+    ("hosts", HOSTS),
+    ("passwd", PASSWD),
+    ("group", GROUP),
+    ("shadow", SHADOW),
+];
+
+/// For each database, the service trait and the trait method that starts
+/// enumeration. The enumeration functions are the last piece of each
+/// database.
+const TRAITS: &[(&str, (&str, &str))] = &[
+    ("hosts", ("NameService", "sethostent")),
+    ("passwd", ("PasswdService", "setpwent")),
+    ("group", ("GroupService", "setgrent")),
+    ("shadow", ("ShadowService", "setspent")),
+];
+
+/// Arguments to `nss_module!`: `"name", Type` optionally followed by
+/// `, [database, ...]` and then by `, features`.
+struct ModuleArgs {
+    name: ModuleName,
+    service: Type,
+    databases: Option<Punctuated<Ident, Token![,]>>,
+    /// If true, each database's exports are conditional on the cargo
+    /// feature of the same name.
+    features: bool,
+}
+
+impl Parse for ModuleArgs {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let name = input.parse()?;
+        input.parse::<Token![,]>()?;
+        let service = input.parse()?;
+        let mut databases = None;
+        let mut features = false;
+        while input.parse::<Option<Token![,]>>()?.is_some() && !input.is_empty() {
+            if input.peek(syn::token::Bracket) && databases.is_none() {
+                databases = Some(parse_ident_list(input)?);
+            } else {
+                let keyword: Ident = input.parse()?;
+                if keyword != "features" || features {
+                    return Err(syn::Error::new(keyword.span(), "expected `[database, ...]` or `features`"));
+                }
+                features = true;
+            }
+        }
+        Ok(ModuleArgs { name, service, databases, features })
+    }
+}
+
+/// Arguments to the per-database bundle macros: `"name", Type` optionally
+/// followed by `, skip = [piece, ...]` and/or `, feature = "name"`.
+struct BundleArgs {
+    name: ModuleName,
+    service: Type,
+    skip: Punctuated<Ident, Token![,]>,
+    feature: Option<LitStr>,
+}
+
+impl Parse for BundleArgs {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let name = input.parse()?;
+        input.parse::<Token![,]>()?;
+        let service = input.parse()?;
+        let mut skip = Punctuated::new();
+        let mut feature = None;
+        while input.parse::<Option<Token![,]>>()?.is_some() && !input.is_empty() {
+            let keyword: Ident = input.parse()?;
+            input.parse::<Token![=]>()?;
+            if keyword == "skip" {
+                skip = parse_ident_list(input)?;
+            } else if keyword == "feature" {
+                feature = Some(input.parse()?);
+            } else {
+                return Err(syn::Error::new(keyword.span(), "expected `skip = [...]` or `feature = \"...\"`"));
+            }
+        }
+        Ok(BundleArgs { name, service, skip, feature })
+    }
+}
+
+fn parse_ident_list(input: ParseStream) -> syn::Result<Punctuated<Ident, Token![,]>> {
+    let content;
+    bracketed!(content in input);
+    content.parse_terminated(Ident::parse, Token![,])
+}
+
+/// The name of an NSS module, the `NAME` in `libnss_NAME.so.2` and
+/// `_nss_NAME_*`.
+///
+/// Macros accept either a string literal or `env("VAR", "default")`, which
+/// reads the name from the environment variable `VAR` at compile time (for
+/// example, as set by `nsswitch_service_build::module_name`). The default
+/// may be omitted, making the variable required.
+struct ModuleName {
+    value: String,
+    span: Span,
+}
+
+impl ModuleName {
+    fn from_expr(expr: &Expr) -> syn::Result<ModuleName> {
+        let value = match *expr {
+            Expr::Lit(ExprLit { lit: Lit::Str(ref s), .. }) => s.value(),
+            Expr::Call(ref call) if is_ident(&call.func, "env") => {
+                let args: Vec<String> = call.args.iter().map(|arg| match *arg {
+                    Expr::Lit(ExprLit { lit: Lit::Str(ref s), .. }) => Ok(s.value()),
+                    ref other => Err(syn::Error::new_spanned(other, "expected a string literal")),
+                }).collect::<syn::Result<_>>()?;
+                match (args.first(), args.get(1), args.len()) {
+                    (Some(var), default, 1..=2) => match (env::var(var), default) {
+                        (Ok(value), _) => value,
+                        (Err(_), Some(default)) => default.clone(),
+                        (Err(_), None) => {
+                            return Err(syn::Error::new_spanned(
+                                expr,
+                                format!("environment variable `{}` is not set", var),
+                            ))
+                        }
+                    },
+                    _ => return Err(syn::Error::new_spanned(expr, "expected `env(\"VAR\")` or `env(\"VAR\", \"default\")`")),
+                }
+            }
+            ref other => {
+                return Err(syn::Error::new_spanned(
+                    other,
+                    "expected a module name: a string literal, or `env(\"VAR\", \"default\")`",
+                ))
+            }
+        };
+        if value.is_empty() || !value.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'_') {
+            return Err(syn::Error::new_spanned(
+                expr,
+                format!(
+                    "invalid NSS module name {:?}: must be nonempty and contain only ASCII letters, digits, and `_`",
+                    value
+                ),
+            ));
+        }
+        Ok(ModuleName { value, span: expr.span() })
+    }
+}
+
+impl Parse for ModuleName {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        ModuleName::from_expr(&input.parse()?)
+    }
+}
+
+fn is_ident(expr: &Expr, name: &str) -> bool {
+    match *expr {
+        Expr::Path(ref path) => path.path.is_ident(name),
+        _ => false,
+    }
+}
+
+/// Look up `ident` in a table of names, or produce an error listing the
+/// valid choices.
+fn find<'t, T>(table: &'t [(&'static str, T)], ident: &Ident, what: &str) -> syn::Result<&'t (&'static str, T)> {
+    let wanted = ident.to_string();
+    table.iter().find(|&&(known, _)| known == wanted).ok_or_else(|| {
+        let known: Vec<&str> = table.iter().map(|&(known, _)| known).collect();
+        syn::Error::new(
+            ident.span(),
+            format!("unknown {} `{}`; expected one of: {}", what, wanted, known.join(", ")),
+        )
+    })
+}
+
+/// Functions that older glibc releases never call, with the first release
+/// that does. The export macros leave them out when the crate's build
+/// script says the module is for an older glibc (see
+/// `nsswitch_service_build::set_glibc_version`).
+const MIN_GLIBC: &[(&str, (u32, u32))] = &[
+    ("gethostbyname3_r", (2, 6)),
+    ("gethostbyname4_r", (2, 9)),
+    ("gethostbyaddr2_r", (2, 9)),
+];
+
+/// The glibc version the crate's build script set, if any.
+fn target_glibc() -> Option<(u32, u32)> {
+    let version = env::var("NSS_GLIBC_VERSION").ok()?;
+    let mut parts = version.split('.');
+    Some((parts.next()?.parse().ok()?, parts.next()?.parse().ok()?))
+}
+
+/// True if the target glibc is known to predate `function`.
+fn too_new(function: &str, glibc: Option<(u32, u32)>) -> bool {
+    match (glibc, MIN_GLIBC.iter().find(|&&(f, _)| f == function)) {
+        (Some(glibc), Some(&(_, min))) => glibc < min,
+        _ => false,
+    }
+}
+
+/// For each database, the glue functions that illumos backends have
+/// operations for: the function, the operation's `NSS_DBOP_*` number, and
+/// the operation in `nsswitch_service::illumos`.
+type IllumosOp = (&'static str, usize, &'static str);
+
+const ILLUMOS_OPS: &[(&str, &[IllumosOp])] = &[
+    ("hosts", &[
+        ("gethostbyname2_r", 4, "gethostbyname"),
+        ("gethostbyaddr_r", 5, "gethostbyaddr"),
+        ("sethostent", 2, "sethostent"),
+        ("gethostent_r", 3, "gethostent"),
+        ("endhostent", 1, "endhostent"),
+    ]),
+    ("passwd", &[
+        ("getpwnam_r", 4, "getpwnam"),
+        ("getpwuid_r", 5, "getpwuid"),
+        ("setpwent", 2, "setpwent"),
+        ("getpwent_r", 3, "getpwent"),
+        ("endpwent", 1, "endpwent"),
+    ]),
+    ("group", &[
+        ("getgrnam_r", 4, "getgrnam"),
+        ("getgrgid_r", 5, "getgrgid"),
+        ("setgrent", 2, "setgrent"),
+        ("getgrent_r", 3, "getgrent"),
+        ("endgrent", 1, "endgrent"),
+    ]),
+    // There is no adapter for illumos's `spwd` yet, so exporting `shadow`
+    // is an error there.
+    ("shadow", &[]),
+];
+
+/// Emit an `nssglue_*!` invocation for each function in `pieces`, the
+/// functions of `database`, except those the target glibc doesn't use; a
+/// constructor that registers the service's `on_fork_child` when the
+/// library is loaded; and for illumos and Solaris, the database's backend
+/// constructor, or an error if there's no backend for it. If `feature` is given, all of these are only defined when
+/// the crate is built with that cargo feature.
+fn expand_pieces(
+    name: &ModuleName,
+    service: &Type,
+    database: &str,
+    pieces: &[Piece],
+    feature: Option<&str>,
+) -> syn::Result<proc_macro2::TokenStream> {
+    let module = &name.value;
+    let cfg = feature.map(|feature| quote! { #[cfg(feature = #feature)] });
+    let illumos_ops = ILLUMOS_OPS.iter().find(|&&(db, _)| db == database).unwrap().1;
+    let glibc = target_glibc();
+    let mut exports = vec![];
+    let mut ops = vec![];
+    for &(_, functions) in pieces {
+        for function in functions.iter().filter(|function| !too_new(function, glibc)) {
+            let glue = Ident::new(&format!("nssglue_{}", function), Span::call_site());
+            let symbol = Ident::new(&format!("_nss_{}_{}", module, function), name.span);
+            exports.push(quote! {
+                #cfg
+                ::nsswitch_service::#glue!(#symbol, #service);
+            });
+            if let Some(&(_, index, op)) = illumos_ops.iter().find(|&&(f, _, _)| f == *function) {
+                let op = Ident::new(op, Span::call_site());
+                ops.push(quote! { (#index, Some(::nsswitch_service::illumos::#op::<#service> as _)) });
+            }
+        }
+    }
+    let &(_, (trait_name, _)) = TRAITS.iter().find(|&&(db, _)| db == database).unwrap();
+    let service_trait = Ident::new(trait_name, Span::call_site());
+    let constr = Ident::new(&format!("_nss_{}_{}_constr", module, database), name.span);
+    let backend = if illumos_ops.is_empty() {
+        let message = format!("the `{}` database can't be served on illumos or Solaris", database);
+        quote::quote_spanned! { name.span =>
+            #cfg
+            #[cfg(any(target_os = "illumos", target_os = "solaris"))]
+            compile_error!(#message);
+        }
+    } else {
+        quote! {
+            #cfg
+            #[cfg(any(target_os = "illumos", target_os = "solaris"))]
+            #[no_mangle]
+            pub unsafe extern "C" fn #constr(
+                _db_name: *const ::nsswitch_service::ffi::c_char,
+                _src_name: *const ::nsswitch_service::ffi::c_char,
+                _cfg_args: *const ::nsswitch_service::ffi::c_char,
+            ) -> *mut ::nsswitch_service::illumos::nss_backend_t {
+                ::nsswitch_service::illumos::backend(&[#(#ops),*])
+            }
+        }
+    };
+    Ok(quote! {
+        #(#exports)*
+
+        // Register the hook from `.init_array`, so it's in place before any
+        // lookup, even for a service whose state is all in its combinators.
+        #cfg
+        #[cfg(any(
+            target_os = "linux",
+            target_os = "android",
+            target_os = "freebsd",
+            target_os = "netbsd",
+            target_os = "openbsd",
+            target_os = "dragonfly",
+            target_os = "illumos",
+            target_os = "solaris",
+        ))]
+        const _: () = {
+            #[used]
+            #[link_section = ".init_array"]
+            static ADD_FORK_CHILD_HOOK: extern "C" fn() = {
+                extern "C" fn add_fork_child_hook() {
+                    ::nsswitch_service::add_fork_child_hook(
+                        <#service as ::nsswitch_service::#service_trait>::on_fork_child
+                    );
+                }
+                add_fork_child_hook
+            };
+        };
+
+        #backend
+    })
+}
+
+fn expand_module(args: ModuleArgs) -> syn::Result<proc_macro2::TokenStream> {
+    let mut databases = vec![];
+    match args.databases {
+        None => databases.extend(DATABASES.iter().filter(|&&(db, _)| db != "shadow")),
+        Some(ref idents) => {
+            for ident in idents {
+                databases.push(*find(DATABASES, ident, "NSS database")?);
+            }
+        }
+    }
+    let mut exports = vec![];
+    for (database, pieces) in databases {
+        let feature = if args.features { Some(database) } else { None };
+        exports.push(expand_pieces(&args.name, &args.service, database, pieces, feature)?);
+    }
+    Ok(quote! { #(#exports)* })
+}
+
+fn expand_bundle(database: &str, args: BundleArgs) -> syn::Result<proc_macro2::TokenStream> {
+    let all_pieces = DATABASES.iter().find(|&&(db, _)| db == database).unwrap().1;
+    let mut skipped = vec![];
+    for ident in &args.skip {
+        skipped.push(find(all_pieces, ident, &format!("{} function", database))?.0);
+    }
+    let pieces: Vec<Piece> = all_pieces.iter()
+        .filter(|&&(piece, _)| !skipped.contains(&piece))
+        .cloned()
+        .collect();
+    let feature = args.feature.as_ref().map(LitStr::value);
+    expand_pieces(&args.name, &args.service, database, &pieces, feature.as_deref())
+}
+
+/// Arguments to `#[nss_export]`.
+struct ExportArgs {
+    module: Option<ModuleName>,
+    database: Option<LitStr>,
+    skip: Vec<Ident>,
+    feature: Option<LitStr>,
+}
+
+fn parse_export_args(args: Punctuated<MetaNameValue, Token![,]>) -> syn::Result<ExportArgs> {
+    let mut parsed = ExportArgs { module: None, database: None, skip: vec![], feature: None };
+    for arg in args {
+        let key = arg.path.get_ident().map(|ident| ident.to_string()).unwrap_or_default();
+        match (key.as_str(), arg.value) {
+            ("module", ref value) => parsed.module = Some(ModuleName::from_expr(value)?),
+            ("database", Expr::Lit(ExprLit { lit: Lit::Str(s), .. })) => parsed.database = Some(s),
+            ("feature", Expr::Lit(ExprLit { lit: Lit::Str(s), .. })) => parsed.feature = Some(s),
+            ("skip", Expr::Array(array)) => {
+                for elem in array.elems {
+                    match elem {
+                        Expr::Path(ref path) if path.path.get_ident().is_some() => {
+                            parsed.skip.push(path.path.get_ident().unwrap().clone());
+                        }
+                        other => return Err(syn::Error::new_spanned(other, "expected a function name")),
+                    }
+                }
+            }
+            (_, value) => {
+                return Err(syn::Error::new_spanned(
+                    value,
+                    "expected `module = \"name\"`, `database = \"name\"`, `feature = \"name\"`, or `skip = [...]`",
+                ))
+            }
+        }
+    }
+    Ok(parsed)
+}
+
+fn expand_export(args: ExportArgs, item: ItemImpl) -> syn::Result<proc_macro2::TokenStream> {
+    let module = args.module.as_ref().ok_or_else(|| {
+        syn::Error::new(Span::call_site(), "missing `module = \"name\"` argument")
+    })?;
+    let trait_name = match item.trait_ {
+        Some((_, ref path, _)) => path.segments.last().unwrap().ident.to_string(),
+        None => return Err(syn::Error::new_spanned(&item.self_ty, "#[nss_export] goes on an `impl SomeService for Type` block")),
+    };
+    let database = match args.database {
+        Some(ref database) => database.value(),
+        None => match TRAITS.iter().find(|&&(_, (t, _))| t == trait_name) {
+            Some(&(database, _)) => database.to_string(),
+            None => return Err(syn::Error::new_spanned(
+                &item.trait_.as_ref().unwrap().1,
+                "can't tell which database this trait is for; add `database = \"...\"`",
+            )),
+        },
+    };
+    let &(_, (expected_trait, set_method)) = TRAITS.iter().find(|&&(db, _)| db == database).ok_or_else(|| {
+        syn::Error::new_spanned(args.database.as_ref().unwrap(), format!("unknown NSS database `{}`", database))
+    })?;
+    if trait_name != expected_trait {
+        return Err(syn::Error::new_spanned(
+            &item.trait_.as_ref().unwrap().1,
+            format!("the `{}` database is implemented by `{}`, not `{}`", database, expected_trait, trait_name),
+        ));
+    }
+
+    let all_pieces = DATABASES.iter().find(|&&(db, _)| db == database).unwrap().1;
+    let mut skipped = vec![];
+    for ident in &args.skip {
+        skipped.push(find(all_pieces, ident, &format!("{} function", database))?.0);
+    }
+    let defines = |name: &str| item.items.iter().any(|impl_item| match *impl_item {
+        ImplItem::Fn(ref method) => method.sig.ident == name,
+        _ => false,
+    });
+    // Each lookup piece is named after the method that implements it.
+    let (enumeration, lookups) = all_pieces.split_last().unwrap();
+    let mut pieces: Vec<Piece> = lookups.iter().filter(|&&(piece, _)| defines(piece)).cloned().collect();
+    if defines(set_method) {
+        pieces.push(*enumeration);
+    }
+    pieces.retain(|&(piece, _)| !skipped.contains(&piece));
+
+    let feature = args.feature.as_ref().map(LitStr::value);
+    let exports = expand_pieces(module, &item.self_ty, &database, &pieces, feature.as_deref())?;
+    Ok(quote! {
+        #item
+        #exports
+    })
+}
+
+fn bundle(database: &str, input: TokenStream) -> TokenStream {
+    let args = parse_macro_input!(input as BundleArgs);
+    match expand_bundle(database, args) {
+        Ok(tokens) => tokens.into(),
+        Err(err) => err.to_compile_error().into(),
+    }
+}
+
+/// Define every `_nss_NAME_*` function for an NSS module at once.
+///
+/// ```ignore
+/// nss_module!("loopback", LoopbackService, [hosts]);
+/// ```
+///
+/// expands to one `nssglue_*!` invocation per function of each listed
+/// database, naming them `_nss_loopback_gethostbyname_r` and so on, so the
+/// library can be installed as `libnss_loopback.so.2`.
+///
+/// The module name can also be taken from the environment at compile time,
+/// so that one crate can build differently named modules:
+///
+/// ```ignore
+/// nss_module!(env("NSS_MODULE_NAME", "corp"), CorpDirectory, [hosts]);
+/// ```
+///
+/// All the macros in this crate accept a name in this form. Pair it with
+/// `nsswitch_service_build::module_name` in `build.rs`, which makes Cargo
+/// rebuild the crate when the variable changes.
+///
+/// When the build script says which glibc the module is for (see
+/// `nsswitch_service_build::set_glibc_version`), this and the other export
+/// macros leave out functions that glibc never calls.
+///
+/// The known databases are `hosts` (which requires `NameService`), `passwd`
+/// (`PasswdService`), `group` (`GroupService`), and `shadow`
+/// (`ShadowService`). A macro can't tell which traits `LoopbackService`
+/// implements, so if the list is omitted, all databases but `shadow` are
+/// exported. Password hashes are rarely something a module should serve,
+/// so `shadow` has to be asked for.
+///
+/// To build slimmer variants of a module from one codebase, add `features`
+/// at the end. Each database's functions are then defined only if the crate
+/// is built with the cargo feature of the same name:
+///
+/// ```ignore
+/// // Cargo.toml has `[features] hosts = []` and `passwd = []`.
+/// nss_module!("corp", CorpDirectory, [hosts, passwd], features);
+/// ```
+#[proc_macro]
+pub fn nss_module(input: TokenStream) -> TokenStream {
+    let args = parse_macro_input!(input as ModuleArgs);
+    match expand_module(args) {
+        Ok(tokens) => tokens.into(),
+        Err(err) => err.to_compile_error().into(),
+    }
+}
+
+/// Define all the `hosts` functions of an NSS module, using a
+/// `NameService` implementation.
+///
+/// ```ignore
+/// nssglue_hosts!("loopback", LoopbackService);
+/// nssglue_hosts!("loopback", LoopbackService, skip = [gethostbyname4_r, gethostent_r]);
+/// ```
+///
+/// defines `_nss_loopback_gethostbyname_r`, `..._gethostbyname2_r`,
+/// `..._gethostbyname3_r`, `..._gethostbyname4_r`, `..._gethostbyaddr_r`,
+/// `..._gethostbyaddr2_r`, and the enumeration functions `..._sethostent`,
+/// `..._gethostent_r`, and `..._endhostent`, except the ones listed in
+/// `skip`. Skipping `gethostent_r` skips all three enumeration functions.
+///
+/// With `feature = "hosts"`, the functions are defined only when the crate
+/// is built with that cargo feature. The per-database macros and
+/// `#[nss_export]` all accept this option.
+#[proc_macro]
+pub fn nssglue_hosts(input: TokenStream) -> TokenStream {
+    bundle("hosts", input)
+}
+
+/// Define all the `passwd` functions of an NSS module, using a
+/// `PasswdService` implementation: `getpwnam_r`, `getpwuid_r`, and the
+/// enumeration functions `setpwent`, `getpwent_r`, and `endpwent`.
+///
+/// ```ignore
+/// nssglue_passwd!("corp", CorpDirectory, skip = [getpwent_r]);
+/// ```
+#[proc_macro]
+pub fn nssglue_passwd(input: TokenStream) -> TokenStream {
+    bundle("passwd", input)
+}
+
+/// Define all the `group` functions of an NSS module, using a
+/// `GroupService` implementation: `getgrnam_r`, `getgrgid_r`,
+/// `initgroups_dyn`, and the enumeration functions `setgrent`, `getgrent_r`,
+/// and `endgrent`.
+///
+/// ```ignore
+/// nssglue_group!("corp", CorpDirectory, skip = [getgrent_r]);
+/// ```
+#[proc_macro]
+pub fn nssglue_group(input: TokenStream) -> TokenStream {
+    bundle("group", input)
+}
+
+/// Define all the `shadow` functions of an NSS module, using a
+/// `ShadowService` implementation: `getspnam_r` and the enumeration
+/// functions `setspent`, `getspent_r`, and `endspent`.
+///
+/// ```ignore
+/// nssglue_shadow!("corp", CorpDirectory, skip = [getspent_r]);
+/// ```
+#[proc_macro]
+pub fn nssglue_shadow(input: TokenStream) -> TokenStream {
+    bundle("shadow", input)
+}
+
+/// Export the NSS functions implemented by an `impl` block.
+///
+/// ```ignore
+/// #[nss_export(module = "loopback")]
+/// impl NameService for LoopbackService {
+///     ...
+/// }
+/// ```
+///
+/// The database is inferred from the trait (`NameService` is `hosts`,
+/// `PasswdService` is `passwd`, `GroupService` is `group`, `ShadowService`
+/// is `shadow`), or can be given
+/// explicitly with `database = "hosts"`.
+///
+/// Exactly the functions the impl block defines are exported: a block that
+/// defines `gethostbyname2_r`, `gethostbyaddr_r`, and `gethostbyname4_r`
+/// exports `_nss_loopback_gethostbyname2_r` and so on, but not
+/// `_nss_loopback_gethostbyname_r`, even though the trait has a default
+/// for it. (To export every function, defaults and all, use
+/// `nssglue_hosts!`.) The enumeration functions (`sethostent`,
+/// `gethostent_r`, `endhostent`) are exported if the block defines
+/// `sethostent` (or `setpwent`, `setgrent`, or `setspent`). To leave out
+/// some of these, list them with `skip = [gethostbyname4_r, ...]`, as for
+/// `nssglue_hosts!`.
+///
+/// When building for illumos or Solaris, this and the other export macros
+/// also define the database's backend constructor, `_nss_loopback_hosts_constr`,
+/// which is how those systems load a module. There's no backend for
+/// `shadow` there yet, so exporting it is an error.
+///
+/// This and the other export macros register the service's `on_fork_child`
+/// with `nsswitch_service::add_fork_child_hook` when the library is loaded,
+/// whether or not the impl block defines it, since the combinators a
+/// service is built from have hooks of their own.
+#[proc_macro_attribute]
+pub fn nss_export(attr: TokenStream, item: TokenStream) -> TokenStream {
+    let args = parse_macro_input!(attr with Punctuated::<MetaNameValue, Token![,]>::parse_terminated);
+    let item = parse_macro_input!(item as ItemImpl);
+    match parse_export_args(args).and_then(|args| expand_export(args, item)) {
+        Ok(tokens) => tokens.into(),
+        Err(err) => err.to_compile_error().into(),
+    }
+}
+
+/// Arguments to `nss_rustinfo!`: `"name", [database, ...]`.
+struct RustInfoArgs {
+    name: ModuleName,
+    databases: Punctuated<Ident, Token![,]>,
+}
+
+impl Parse for RustInfoArgs {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let name = input.parse()?;
+        input.parse::<Token![,]>()?;
+        let databases = parse_ident_list(input)?;
+        input.parse::<Option<Token![,]>>()?;
+        Ok(RustInfoArgs { name, databases })
+    }
+}
+
+fn expand_rustinfo(args: RustInfoArgs) -> syn::Result<proc_macro2::TokenStream> {
+    let module = &args.name.value;
+    let mut databases = vec![];
+    for ident in &args.databases {
+        databases.push(find(DATABASES, ident, "NSS database")?.0);
+    }
+    let symbol = Ident::new(&format!("_nss_{}_rustinfo", module), args.name.span);
+    let fixed = format!(
+        "module={}\ndatabases={}\nnsswitch_service={}\n",
+        module,
+        databases.join(","),
+        env!("CARGO_PKG_VERSION"),
+    );
+    Ok(quote! {
+        const _: () = {
+            const INFO: &str = concat!(
+                #fixed,
+                "crate=", env!("CARGO_PKG_NAME"), "\n",
+                "crate_version=", env!("CARGO_PKG_VERSION"), "\n",
+                "debug_assertions=", cfg!(debug_assertions), "\n",
+            );
+
+            #[no_mangle]
+            #[allow(non_upper_case_globals)]
+            pub static #symbol: [u8; INFO.len() + 1] = ::nsswitch_service::macros::c_string_array(INFO);
+        };
+    })
+}
+
+/// Export a `_nss_NAME_rustinfo` symbol describing the module, for tools
+/// that inspect installed NSS modules.
+///
+/// ```ignore
+/// nss_rustinfo!("loopback", [hosts]);
+/// ```
+///
+/// The symbol is a null-terminated C string (`extern const char
+/// _nss_loopback_rustinfo[]`) made of `key=value` lines:
+///
+/// ```text
+/// module=loopback
+/// databases=hosts
+/// nsswitch_service=0.1.0
+/// crate=nss_loopback_rs
+/// crate_version=0.1.0
+/// debug_assertions=false
+/// ```
+///
+/// Tools should ignore keys they don't recognize; more may be added.
+#[proc_macro]
+pub fn nss_rustinfo(input: TokenStream) -> TokenStream {
+    let args = parse_macro_input!(input as RustInfoArgs);
+    match expand_rustinfo(args) {
+        Ok(tokens) => tokens.into(),
+        Err(err) => err.to_compile_error().into(),
+    }
+}
+
+/// A glue function a BSD `nsdispatch` can call through an adapter: the
+/// function, the method it implements, and the adapter, which FreeBSD's
+/// libc provides and `nsswitch_service::netbsd` provides for NetBSD.
+type BsdMethod = (&'static str, &'static str, &'static str);
+
+const FREEBSD_METHODS: &[(&str, &[BsdMethod])] = &[
+    ("hosts", &[
+        ("gethostbyname2_r", "gethostbyname2_r", "__nss_compat_gethostbyname2"),
+        ("gethostbyaddr_r", "gethostbyaddr_r", "__nss_compat_gethostbyaddr"),
+    ]),
+    ("passwd", &[
+        ("getpwnam_r", "getpwnam_r", "__nss_compat_getpwnam_r"),
+        ("getpwuid_r", "getpwuid_r", "__nss_compat_getpwuid_r"),
+        ("setpwent", "setpwent", "__nss_compat_setpwent"),
+        ("getpwent_r", "getpwent_r", "__nss_compat_getpwent_r"),
+        ("endpwent", "endpwent", "__nss_compat_endpwent"),
+    ]),
+    ("group", &[
+        ("getgrnam_r", "getgrnam_r", "__nss_compat_getgrnam_r"),
+        ("getgrgid_r", "getgrgid_r", "__nss_compat_getgrgid_r"),
+        ("setgrent", "setgrent", "__nss_compat_setgrent"),
+        ("getgrent_r", "getgrent_r", "__nss_compat_getgrent_r"),
+        ("endgrent", "endgrent", "__nss_compat_endgrent"),
+    ]),
+    ("shadow", &[]),
+];
+
+const NETBSD_METHODS: &[(&str, &[BsdMethod])] = &[
+    ("hosts", &[]),
+    ("passwd", &[
+        ("getpwnam_r", "getpwnam_r", "getpwnam_r"),
+        ("getpwuid_r", "getpwuid_r", "getpwuid_r"),
+        ("setpwent", "setpwent", "setpwent"),
+        ("getpwent_r", "getpwent_r", "getpwent_r"),
+        ("endpwent", "endpwent", "endpwent"),
+    ]),
+    ("group", &[
+        ("getgrnam_r", "getgrnam_r", "getgrnam_r"),
+        ("getgrgid_r", "getgrgid_r", "getgrgid_r"),
+        ("setgrent", "setgrent", "setgrent"),
+        ("getgrent_r", "getgrent_r", "getgrent_r"),
+        ("endgrent", "endgrent", "endgrent"),
+    ]),
+    ("shadow", &[]),
+];
+
+/// Arguments to `nss_freebsd_module!` and `nss_netbsd_module!`:
+/// `"name", [database, ...]` optionally followed by `, skip = [piece, ...]`.
+struct BsdArgs {
+    name: ModuleName,
+    databases: Punctuated<Ident, Token![,]>,
+    skip: Punctuated<Ident, Token![,]>,
+}
+
+impl Parse for BsdArgs {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let name = input.parse()?;
+        input.parse::<Token![,]>()?;
+        let databases = parse_ident_list(input)?;
+        let mut skip = Punctuated::new();
+        if input.parse::<Option<Token![,]>>()?.is_some() && !input.is_empty() {
+            let keyword: Ident = input.parse()?;
+            if keyword != "skip" {
+                return Err(syn::Error::new(keyword.span(), "expected `skip = [...]`"));
+            }
+            input.parse::<Token![=]>()?;
+            skip = parse_ident_list(input)?;
+            input.parse::<Option<Token![,]>>()?;
+        }
+        Ok(BsdArgs { name, databases, skip })
+    }
+}
+
+/// Emit `nss_module_register` for the BSD whose `target_os` is `os`, using
+/// the adapters in `methods`, found in the `nsswitch_service` module `os`.
+fn expand_bsd_module(
+    os: &str,
+    methods: &[(&str, &[BsdMethod])],
+    args: BsdArgs,
+) -> syn::Result<proc_macro2::TokenStream> {
+    let platform = Ident::new(os, Span::call_site());
+    let module = &args.name.value;
+    let mut databases = vec![];
+    let mut unsupported = vec![];
+    for ident in &args.databases {
+        let database = *find(DATABASES, ident, "NSS database")?;
+        if methods.iter().find(|&&(db, _)| db == database.0).unwrap().1.is_empty() {
+            // Only an error when building for `os`, like the rest.
+            let message = format!("the `{}` database can't be served on {}", database.0, os);
+            unsupported.push(quote::quote_spanned! { ident.span() => compile_error!(#message); });
+        }
+        databases.push(database);
+    }
+    let mut skipped = vec![];
+    for ident in &args.skip {
+        let piece = databases.iter()
+            .find_map(|&(_, pieces)| pieces.iter().find(|&&(piece, _)| ident == piece));
+        match piece {
+            Some(&(_, functions)) => skipped.extend_from_slice(functions),
+            None => return Err(syn::Error::new(ident.span(), format!("unknown function `{}`", ident))),
+        }
+    }
+
+    let mut declarations = vec![];
+    let mut entries = vec![];
+    for &(database, _) in &databases {
+        let database_methods = methods.iter().find(|&&(db, _)| db == database).unwrap().1;
+        for &(function, method, adapter) in database_methods {
+            if skipped.contains(&function) {
+                continue;
+            }
+            let symbol = Ident::new(&format!("_nss_{}_{}", module, function), args.name.span);
+            let adapter = Ident::new(adapter, Span::call_site());
+            let database = format!("{}\0", database);
+            let method = format!("{}\0", method);
+            declarations.push(quote! { fn #symbol(); });
+            entries.push(quote! {
+                ::nsswitch_service::#platform::ns_mtab::new(
+                    #database.as_bytes(),
+                    #method.as_bytes(),
+                    ::nsswitch_service::#platform::#adapter,
+                    #symbol as *mut ::nsswitch_service::ffi::c_void,
+                )
+            });
+        }
+    }
+
+    Ok(quote! {
+        #[cfg(target_os = #os)]
+        const _: () = {
+            #(#unsupported)*
+
+            #[no_mangle]
+            pub unsafe extern "C" fn nss_module_register(
+                _source: *const ::nsswitch_service::ffi::c_char,
+                nelems: *mut ::std::os::raw::c_uint,
+                unreg: *mut ::nsswitch_service::#platform::nss_module_unregister_fn,
+            ) -> *mut ::nsswitch_service::#platform::ns_mtab {
+                // The glue functions, defined elsewhere in this crate. Only
+                // their addresses are needed.
+                extern "C" {
+                    #(#declarations)*
+                }
+                ::nsswitch_service::#platform::register(vec![#(#entries),*], nelems, unreg)
+            }
+        };
+    })
+}
+
+/// Export `nss_module_register`, the entry point FreeBSD's `nsdispatch(3)`
+/// uses, for a module whose functions are exported as usual (with
+/// `nss_export` or `nss_module!`).
+///
+/// ```ignore
+/// nss_freebsd_module!("loopback", [hosts]);
+/// nss_freebsd_module!("corp", [passwd, group], skip = [getpwent_r, getgrent_r]);
+/// ```
+///
+/// List the same databases as the exports, and skip whatever they skip,
+/// including enumeration when the impl doesn't define `setpwent` or
+/// `setgrent`; otherwise the module fails to link. On other systems this
+/// expands to nothing, so it can sit next to the exports unconditionally.
+///
+/// FreeBSD loads the module from `nss_NAME.so.1`, which
+/// `nsswitch_service_build` names the library when building for FreeBSD.
+/// Only the lookups `nsswitch_service::freebsd` lists reach the service,
+/// and listing `shadow`, which has none, is an error there.
+#[proc_macro]
+pub fn nss_freebsd_module(input: TokenStream) -> TokenStream {
+    let args = parse_macro_input!(input as BsdArgs);
+    match expand_bsd_module("freebsd", FREEBSD_METHODS, args) {
+        Ok(tokens) => tokens.into(),
+        Err(err) => err.to_compile_error().into(),
+    }
+}
+
+/// Export `nss_module_register` for NetBSD's `nsdispatch(3)`, like
+/// `nss_freebsd_module!`.
+///
+/// ```ignore
+/// nss_netbsd_module!("corp", [passwd, group]);
+/// ```
+///
+/// NetBSD loads the module from `nss_NAME.so.0`. Only `passwd` and `group`
+/// lookups reach the service (see `nsswitch_service::netbsd`); listing
+/// `hosts` or `shadow` is an error when building for NetBSD.
+#[proc_macro]
+pub fn nss_netbsd_module(input: TokenStream) -> TokenStream {
+    let args = parse_macro_input!(input as BsdArgs);
+    match expand_bsd_module("netbsd", NETBSD_METHODS, args) {
+        Ok(tokens) => tokens.into(),
+        Err(err) => err.to_compile_error().into(),
+    }
+}
+
+#[test]
+fn test_too_new() {
+    assert!(too_new("gethostbyname4_r", Some((2, 8))));
+    assert!(!too_new("gethostbyname4_r", Some((2, 9))));
+    assert!(!too_new("gethostbyname4_r", None));
+    assert!(!too_new("gethostbyname2_r", Some((2, 1))));
+}
+
+#[test]
+fn test_bsd_unsupported_database() {
+    let expand = |os, methods, input| {
+        expand_bsd_module(os, methods, syn::parse_str(input).unwrap()).unwrap().to_string()
+    };
+    assert!(expand("netbsd", NETBSD_METHODS, r#""corp", [hosts, passwd]"#).contains("compile_error"));
+    assert!(expand("netbsd", NETBSD_METHODS, r#""corp", [shadow]"#).contains("compile_error"));
+    assert!(!expand("netbsd", NETBSD_METHODS, r#""corp", [passwd, group]"#).contains("compile_error"));
+    assert!(!expand("freebsd", FREEBSD_METHODS, r#""corp", [hosts]"#).contains("compile_error"));
+}
+
+#[test]
+fn test_illumos_shadow() {
+    let expand = |input| expand_module(syn::parse_str(input).unwrap()).unwrap().to_string();
+    assert!(expand(r#""corp", Corp, [shadow]"#).contains("compile_error"));
+    assert!(!expand(r#""corp", Corp, [hosts, passwd, group]"#).contains("compile_error"));
+}
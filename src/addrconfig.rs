@@ -0,0 +1,120 @@
+//! Dropping addresses this host has no way to reach.
+
+use crate::errors::{Error, HostError, NssStatus, Result};
+use crate::interfaces::{AddressFamily, Entries, HostAddresses, HostEntry, HostEntryWithTtl, HostLimits, NameService};
+use crate::netif::Connectivity;
+use libc::ENOENT;
+use std::ffi::CStr;
+use std::marker::PhantomData;
+use std::net::IpAddr;
+use std::time::Duration;
+
+/// The `hosts` service `S`, but with only the kinds of addresses this host
+/// can use, as `getaddrinfo` does for callers that pass `AI_ADDRCONFIG`,
+/// so that programs on an IPv4-only network don't try IPv6 addresses first
+/// and wait for them to time out:
+///
+/// ```ignore
+/// nssglue_hosts!("consul", AddrConfig<ConsulService>);
+/// ```
+///
+/// Which kinds those are comes from `Connectivity::current`. A lookup of
+/// one family only, such as `gethostbyname2_r` with `AF_INET6` on a host
+/// without global IPv6 addresses, reports `NO_DATA` without asking `S`.
+/// `gethostbyname4_r` drops the addresses that can't be used, and reports
+/// `NO_DATA` if that leaves none. Loopback addresses are always kept.
+///
+/// Reverse lookups and enumeration go to `S` unchanged, as do `S`'s
+/// settings, such as `LOOKUP_TIMEOUT`.
+pub struct AddrConfig<S>(PhantomData<S>);
+
+fn no_data<T>() -> Result<T> {
+    Err(Error::with_host(NssStatus::NotFound, ENOENT, HostError::NoData))
+}
+
+/// `found`, without the addresses `connectivity` doesn't allow.
+fn usable(mut found: HostAddresses<'_>, connectivity: Connectivity) -> Result<Option<HostAddresses<'_>>> {
+    let before = found.addrs.len();
+    found.addrs.retain(|addr| connectivity.allows(addr));
+    if found.addrs.is_empty() && before > 0 {
+        return no_data();
+    }
+    Ok(Some(found))
+}
+
+impl<S: NameService> AddrConfig<S> {
+    fn lookup<T>(af: AddressFamily, s: impl FnOnce() -> Result<Option<T>>) -> Result<Option<T>> {
+        if Connectivity::current().allows_family(af) {
+            s()
+        } else {
+            no_data()
+        }
+    }
+}
+
+impl<S: NameService> NameService for AddrConfig<S> {
+    const ALLOW_EMPTY_ADDRESS_LIST: bool = S::ALLOW_EMPTY_ADDRESS_LIST;
+    const LOOKUP_TIMEOUT: Option<Duration> = S::LOOKUP_TIMEOUT;
+    const LIMITS: HostLimits = S::LIMITS;
+    const VALIDATE_HOSTNAMES: bool = S::VALIDATE_HOSTNAMES;
+
+    fn gethostbyname_r(name: &CStr) -> Result<Option<HostEntry<'_>>> {
+        Self::lookup(AddressFamily::Ipv4, || S::gethostbyname_r(name))
+    }
+
+    fn gethostbyname2_r(name: &CStr, af: AddressFamily) -> Result<Option<HostEntry<'_>>> {
+        Self::lookup(af, || S::gethostbyname2_r(name, af))
+    }
+
+    fn gethostbyaddr_r(addr: &IpAddr) -> Result<Option<HostEntry<'_>>> {
+        S::gethostbyaddr_r(addr)
+    }
+
+    fn gethostbyname3_r(name: &CStr, af: AddressFamily) -> Result<Option<HostEntryWithTtl<'_>>> {
+        Self::lookup(af, || S::gethostbyname3_r(name, af))
+    }
+
+    fn gethostbyname4_r(name: &CStr) -> Result<Option<HostAddresses<'_>>> {
+        match S::gethostbyname4_r(name)? {
+            Some(found) => usable(found, Connectivity::current()),
+            None => Ok(None),
+        }
+    }
+
+    fn gethostbyaddr2_r(addr: &IpAddr) -> Result<Option<HostEntryWithTtl<'_>>> {
+        S::gethostbyaddr2_r(addr)
+    }
+
+    fn sethostent(stay_open: bool) -> Result<Entries<HostEntry<'static>>> {
+        S::sethostent(stay_open)
+    }
+
+    fn on_fork_child() {
+        S::on_fork_child()
+    }
+}
+
+#[test]
+fn test_addrconfig() {
+    use crate::testing::{gethostbyname4, FixedHosts};
+
+    let v4 = Connectivity { ipv4: true, ipv6: false };
+    let found = FixedHosts::gethostbyname4_r(CStr::from_bytes_with_nul(b"host.test\0").unwrap()).unwrap().unwrap();
+    assert_eq!(usable(found, v4).unwrap().unwrap().addrs, ["192.0.2.1".parse::<IpAddr>().unwrap()]);
+    let found = FixedHosts::gethostbyname4_r(CStr::from_bytes_with_nul(b"v6only.test\0").unwrap()).unwrap().unwrap();
+    assert_eq!(usable(found, v4).unwrap_err().host_error(), Some(HostError::NoData));
+
+    // Whatever this machine can reach, the answers agree with it.
+    let connectivity = Connectivity::current();
+    type Hosts = AddrConfig<FixedHosts>;
+    for &af in &[AddressFamily::Ipv4, AddressFamily::Ipv6] {
+        let name = CStr::from_bytes_with_nul(b"host.test\0").unwrap();
+        match Hosts::gethostbyname2_r(name, af) {
+            Ok(found) => assert!(connectivity.allows_family(af) && found.is_some()),
+            Err(err) => assert_eq!(err.host_error(), Some(HostError::NoData)),
+        }
+    }
+    if let Ok(Some(found)) = gethostbyname4::<Hosts>("host.test") {
+        assert!(found.addrs.iter().all(|addr| connectivity.allows(addr)));
+    }
+}
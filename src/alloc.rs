@@ -1,5 +1,5 @@
 use libc::c_char;
-use errors::{Error, Result};
+use crate::errors::{Error, Result};
 use std::{mem, ptr, slice};
 use std::ffi::CStr;
 use std::marker::PhantomData;
@@ -29,7 +29,7 @@ pub struct BumpAllocator<'buf> {
 }
 
 fn out_of_room<T>() -> Result<T> {
-    Err(Error::buffer_too_small())
+    Err(Error::insufficient_buffer())
 }
 
 impl<'buf> BumpAllocator<'buf> {
@@ -57,7 +57,7 @@ impl<'buf> BumpAllocator<'buf> {
     /// accident.
     pub unsafe fn from_ptr(buffer: *mut c_char, buflen: usize) -> Result<BumpAllocator<'buf>> {
         let point = buffer as usize;
-        if buflen > isize::max_value() as usize || buflen > usize::max_value() - point {
+        if buffer.is_null() || buflen > isize::MAX as usize || buflen > usize::MAX - point {
             return Err(Error::invalid_args());
         }
         Ok(BumpAllocator::new(slice::from_raw_parts_mut(buffer as *mut u8, buflen)))
@@ -93,6 +93,11 @@ impl<'buf> BumpAllocator<'buf> {
             return out_of_room();
         }
 
+        // Zero the bytes skipped, so that everything up to `self.point` is
+        // initialized when the caller gets the buffer back.
+        unsafe {
+            ptr::write_bytes(self.point as *mut u8, 0, aligned - self.point);
+        }
         self.point = aligned;
         Ok(())
     }
@@ -139,7 +144,7 @@ impl<'buf> BumpAllocator<'buf> {
             n += 1;
         }
         unsafe {
-            debug_assert!(array_ptr.offset(n as isize) as usize == self.point);
+            debug_assert!(array_ptr.add(n) as usize == self.point);
             Ok(slice::from_raw_parts_mut(array_ptr, n))
         }
     }
@@ -158,9 +163,6 @@ impl<'buf> BumpAllocator<'buf> {
         let dst = self.take(nbytes)? as *mut c_char;
         unsafe {
             ptr::copy(src as *const c_char, dst, nbytes);
-        }
-        self.point += nbytes;
-        unsafe {
             Ok(CStr::from_ptr(dst))
         }
     }
@@ -168,12 +170,12 @@ impl<'buf> BumpAllocator<'buf> {
 
 #[test]
 fn test_alloc() {
-    let mut buf = [0_u8; 16];
+    let mut buf = [0xff_u8; 16];
 
     // Find a slice of buf that is aligned to an 8-byte boundary.
     let addr = buf.as_ptr() as usize;
     let offset = (8 - addr % 8) % 8;
-    assert!((addr + offset) % 8 == 0);
+    assert!((addr + offset).is_multiple_of(8));
 
     {
         let mut a = BumpAllocator::new(&mut buf[offset..offset + 8]);
@@ -192,6 +194,18 @@ fn test_alloc() {
         assert!(a.allocate(0_u8).is_err());
     }
     assert_eq!((buf[offset + 4], offset), (0xfe, 0));
+    assert_eq!(buf[offset + 5], 0);  // alignment padding is zeroed
+}
+
+#[test]
+fn test_from_ptr_rejects_wrapping_buffer() {
+    // A buffer that would run past the top of the address space. This comes
+    // up on 32-bit targets, where `usize` is small enough to get there.
+    let top = (usize::MAX - 8) as *mut c_char;
+    unsafe {
+        assert!(BumpAllocator::from_ptr(top, 16).is_err());
+        assert!(BumpAllocator::from_ptr(ptr::null_mut(), 0).is_err());
+    }
 }
 
 #[test]
@@ -210,4 +224,11 @@ fn test_copy_c_str() {
     assert_eq!(copy2.to_str().unwrap(), "Jello squirreled");
 
     assert_eq!(copy1.to_str().unwrap(), "hello world");
+
+    // The strings are packed, so exactly enough room is enough.
+    let mut buf = [0_u8; 12];
+    let mut a = BumpAllocator::new(&mut buf);
+    a.copy_c_str(&CString::new("hello").unwrap()).unwrap();
+    a.copy_c_str(&CString::new("world").unwrap()).unwrap();
+    assert!(a.copy_c_str(&CString::new("").unwrap()).is_err());
 }
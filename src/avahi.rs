@@ -0,0 +1,664 @@
+//! A ready-made `hosts` service that resolves `.local` names by asking the
+//! Avahi daemon over D-Bus, as nss-mdns does.
+
+use crate::diag;
+use crate::errors::{Error, NssStatus, Result};
+use crate::interfaces::{AddressFamily, HostAddressList, HostEntry, NameService};
+use libc::{ECONNREFUSED, EINVAL, EIO};
+use std::borrow::Cow;
+use std::convert::TryFrom;
+use std::ffi::{CStr, CString};
+use std::io::{self, Read, Write};
+use std::marker::PhantomData;
+use std::net::IpAddr;
+use std::os::unix::net::UnixStream;
+use std::time::Duration;
+
+/// Where an `AvahiService` finds Avahi, and which names it asks about.
+pub trait AvahiConfig: 'static {
+    /// The system bus socket.
+    const BUS: &'static str = "/run/dbus/system_bus_socket";
+
+    /// The domains whose names are looked up; other names are left to the
+    /// next service. Add any wide-area domains Avahi browses. If empty,
+    /// every name is looked up.
+    const DOMAINS: &'static [&'static str] = &["local"];
+
+    /// How long to wait for an answer. Avahi gives up on a name after
+    /// about five seconds of its own.
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    /// If true, as in nss-mdns's `mdns_minimal`, only addresses that can't
+    /// be in DNS, `169.254.0.0/16` and `fe80::/10`, are looked up in
+    /// reverse. Otherwise every address is.
+    const MINIMAL: bool = true;
+}
+
+/// The usual settings: `.local` names, on the usual system bus.
+pub struct AvahiDefaults;
+
+impl AvahiConfig for AvahiDefaults {}
+
+/// A `hosts` service that resolves names by asking the Avahi daemon, for
+/// systems that run it: like nss-mdns, but in Rust, and with a choice of
+/// domains and timeout.
+///
+/// ```ignore
+/// nssglue_hosts!("avahi", AvahiService);
+/// ```
+///
+/// Each lookup calls `ResolveHostName` or `ResolveAddress` on Avahi's
+/// `org.freedesktop.Avahi.Server` interface, on a new connection to the
+/// system bus. Avahi answers with one address per name and family. A name
+/// Avahi can't resolve, or one outside `DOMAINS`, isn't found. If the bus
+/// or Avahi isn't running, lookups report `NssStatus::Unavailable`
+/// quietly.
+pub struct AvahiService<C = AvahiDefaults>(PhantomData<C>);
+
+/// `AVAHI_IF_UNSPEC` and `AVAHI_PROTO_UNSPEC`: any interface, and queries
+/// over either IPv4 or IPv6.
+const UNSPEC: i32 = -1;
+
+/// `AVAHI_PROTO_INET` and `AVAHI_PROTO_INET6`, for the kind of address
+/// wanted.
+const PROTO_INET: i32 = 0;
+const PROTO_INET6: i32 = 1;
+
+const METHOD_CALL: u8 = 1;
+const METHOD_RETURN: u8 = 2;
+const ERROR: u8 = 3;
+
+const FIELD_PATH: u8 = 1;
+const FIELD_INTERFACE: u8 = 2;
+const FIELD_MEMBER: u8 = 3;
+const FIELD_ERROR_NAME: u8 = 4;
+const FIELD_REPLY_SERIAL: u8 = 5;
+const FIELD_DESTINATION: u8 = 6;
+const FIELD_SIGNATURE: u8 = 8;
+
+/// The longest message D-Bus allows.
+const MAX_MESSAGE: usize = 128 << 20;
+
+/// A header field's value.
+enum Field<'a> {
+    Str(&'a str),
+    Path(&'a str),
+    Signature(&'a str),
+    /// Only replies, from the bus, have these.
+    #[cfg(test)]
+    U32(u32),
+}
+
+/// Marshalling, in little-endian order. Values are aligned relative to
+/// the start of the buffer, which is fine for bodies too, as headers are
+/// padded to a multiple of 8 bytes.
+#[derive(Default)]
+struct Writer(Vec<u8>);
+
+impl Writer {
+    fn align(&mut self, n: usize) {
+        while !self.0.len().is_multiple_of(n) {
+            self.0.push(0);
+        }
+    }
+
+    fn u8(&mut self, n: u8) -> &mut Self {
+        self.0.push(n);
+        self
+    }
+
+    fn u32(&mut self, n: u32) -> &mut Self {
+        self.align(4);
+        self.0.extend_from_slice(&n.to_le_bytes());
+        self
+    }
+
+    fn i32(&mut self, n: i32) -> &mut Self {
+        self.u32(n as u32)
+    }
+
+    fn string(&mut self, s: &str) -> &mut Self {
+        self.u32(s.len() as u32);
+        self.0.extend_from_slice(s.as_bytes());
+        self.u8(0)
+    }
+
+    fn signature(&mut self, s: &str) -> &mut Self {
+        self.u8(s.len() as u8);
+        self.0.extend_from_slice(s.as_bytes());
+        self.u8(0)
+    }
+}
+
+/// A whole message: the header, with `fields`, then `body`.
+fn message(kind: u8, serial: u32, fields: &[(u8, Field<'_>)], body: &[u8]) -> Vec<u8> {
+    let mut w = Writer::default();
+    w.u8(b'l').u8(kind).u8(0).u8(1).u32(body.len() as u32).u32(serial).u32(0);
+    w.align(8);
+    let start = w.0.len();
+    for (code, value) in fields {
+        w.align(8);
+        w.u8(*code);
+        match *value {
+            Field::Str(s) => w.signature("s").string(s),
+            Field::Path(s) => w.signature("o").string(s),
+            Field::Signature(s) => w.signature("g").signature(s),
+            #[cfg(test)]
+            Field::U32(n) => w.signature("u").u32(n),
+        };
+    }
+    let len = (w.0.len() - start) as u32;
+    w.0[12..16].copy_from_slice(&len.to_le_bytes());
+    w.align(8);
+    w.0.extend_from_slice(body);
+    w.0
+}
+
+/// Unmarshalling, in either byte order. Every method returns `None` if the
+/// data is cut short or malformed.
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+    big_endian: bool,
+}
+
+impl<'a> Reader<'a> {
+    fn align(&mut self, n: usize) -> Option<()> {
+        self.pos = self.pos.div_ceil(n) * n;
+        if self.pos > self.buf.len() { None } else { Some(()) }
+    }
+
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        let taken = self.buf.get(self.pos..self.pos.checked_add(len)?)?;
+        self.pos += len;
+        Some(taken)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        Some(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        self.align(4)?;
+        let bytes = <[u8; 4]>::try_from(self.take(4)?).unwrap();
+        Some(if self.big_endian { u32::from_be_bytes(bytes) } else { u32::from_le_bytes(bytes) })
+    }
+
+    fn i32(&mut self) -> Option<i32> {
+        Some(self.u32()? as i32)
+    }
+
+    fn string(&mut self) -> Option<&'a str> {
+        let len = self.u32()? as usize;
+        let s = std::str::from_utf8(self.take(len)?).ok()?;
+        if self.u8()? != 0 {
+            return None;
+        }
+        Some(s)
+    }
+
+    fn signature(&mut self) -> Option<&'a str> {
+        let len = self.u8()? as usize;
+        let s = std::str::from_utf8(self.take(len)?).ok()?;
+        if self.u8()? != 0 {
+            return None;
+        }
+        Some(s)
+    }
+}
+
+/// A message received.
+struct Message {
+    kind: u8,
+    reply_serial: Option<u32>,
+    error_name: Option<String>,
+    big_endian: bool,
+    body: Vec<u8>,
+}
+
+impl Message {
+    fn body(&self) -> Reader<'_> {
+        Reader { buf: &self.body, pos: 0, big_endian: self.big_endian }
+    }
+}
+
+fn invalid() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "malformed D-Bus message")
+}
+
+fn read_message(stream: &mut impl Read) -> io::Result<Message> {
+    let mut fixed = [0; 16];
+    stream.read_exact(&mut fixed)?;
+    let big_endian = match fixed[0] {
+        b'l' => false,
+        b'B' => true,
+        _ => return Err(invalid()),
+    };
+    let mut r = Reader { buf: &fixed, pos: 4, big_endian };
+    let body_len = r.u32().unwrap() as usize;
+    let _serial = r.u32();
+    let fields_len = r.u32().unwrap() as usize;
+    if fields_len > MAX_MESSAGE - 16 || body_len > MAX_MESSAGE - 16 - fields_len {
+        return Err(invalid());
+    }
+    let header_len = (16 + fields_len).div_ceil(8) * 8;
+    let mut rest = vec![0; header_len + body_len - 16];
+    stream.read_exact(&mut rest)?;
+    let mut buf = fixed.to_vec();
+    buf.extend(rest);
+
+    let mut message = Message { kind: fixed[1], reply_serial: None, error_name: None, big_endian, body: vec![] };
+    let mut signature = String::new();
+    let mut r = Reader { buf: &buf[..16 + fields_len], pos: 16, big_endian };
+    while r.pos < 16 + fields_len {
+        r.align(8).ok_or_else(invalid)?;
+        let code = r.u8().ok_or_else(invalid)?;
+        match r.signature().ok_or_else(invalid)? {
+            "s" | "o" => {
+                let s = r.string().ok_or_else(invalid)?;
+                if code == FIELD_ERROR_NAME {
+                    message.error_name = Some(s.to_string());
+                }
+            }
+            "g" => {
+                let s = r.signature().ok_or_else(invalid)?;
+                if code == FIELD_SIGNATURE {
+                    signature = s.to_string();
+                }
+            }
+            "u" => {
+                let n = r.u32().ok_or_else(invalid)?;
+                if code == FIELD_REPLY_SERIAL {
+                    message.reply_serial = Some(n);
+                }
+            }
+            _ => return Err(invalid()),
+        }
+    }
+    // A reply with no body has no signature.
+    if signature.is_empty() != (body_len == 0) {
+        return Err(invalid());
+    }
+    message.body = buf.split_off(header_len);
+    Ok(message)
+}
+
+/// Authenticate as this process's user, as every D-Bus client does first.
+fn authenticate(stream: &mut UnixStream) -> io::Result<()> {
+    let uid = unsafe { libc::geteuid() }.to_string();
+    let hex: String = uid.bytes().map(|b| format!("{:02x}", b)).collect();
+    stream.write_all(format!("\0AUTH EXTERNAL {}\r\n", hex).as_bytes())?;
+    let mut line = vec![];
+    let mut byte = [0];
+    while !line.ends_with(b"\r\n") {
+        stream.read_exact(&mut byte)?;
+        line.push(byte[0]);
+        if line.len() > 512 {
+            return Err(invalid());
+        }
+    }
+    if !line.starts_with(b"OK ") {
+        return Err(io::Error::new(io::ErrorKind::PermissionDenied, "the bus refused to authenticate us"));
+    }
+    stream.write_all(b"BEGIN\r\n")
+}
+
+/// The reply to calling `member` on Avahi's server object with `body`, of
+/// type `signature`, or the name of the error Avahi (or the bus) answered
+/// with.
+fn call(bus: &str, member: &str, signature: &str, body: &[u8], timeout: Duration)
+    -> io::Result<std::result::Result<Message, String>>
+{
+    let mut stream = UnixStream::connect(bus)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+    authenticate(&mut stream)?;
+
+    // Every connection has to say hello before anything else, but there's
+    // no need to wait for the answer.
+    let hello = message(METHOD_CALL, 1, &[
+        (FIELD_PATH, Field::Path("/org/freedesktop/DBus")),
+        (FIELD_INTERFACE, Field::Str("org.freedesktop.DBus")),
+        (FIELD_MEMBER, Field::Str("Hello")),
+        (FIELD_DESTINATION, Field::Str("org.freedesktop.DBus")),
+    ], &[]);
+    let request = message(METHOD_CALL, 2, &[
+        (FIELD_PATH, Field::Path("/")),
+        (FIELD_INTERFACE, Field::Str("org.freedesktop.Avahi.Server")),
+        (FIELD_MEMBER, Field::Str(member)),
+        (FIELD_DESTINATION, Field::Str("org.freedesktop.Avahi")),
+        (FIELD_SIGNATURE, Field::Signature(signature)),
+    ], body);
+    stream.write_all(&[hello, request].concat())?;
+
+    loop {
+        let reply = read_message(&mut stream)?;
+        if reply.reply_serial == Some(2) {
+            match reply.kind {
+                METHOD_RETURN => return Ok(Ok(reply)),
+                ERROR => return Ok(Err(reply.error_name.unwrap_or_default())),
+                _ => {}
+            }
+        }
+    }
+}
+
+fn is_link_local(addr: &IpAddr) -> bool {
+    match *addr {
+        IpAddr::V4(ip) => ip.is_link_local(),
+        IpAddr::V6(ip) => ip.segments()[0] & 0xffc0 == 0xfe80,
+    }
+}
+
+impl<C: AvahiConfig> AvahiService<C> {
+    fn in_domains(name: &str) -> bool {
+        let name = name.trim_end_matches('.');
+        C::DOMAINS.is_empty() || C::DOMAINS.iter().any(|domain| {
+            let domain = domain.trim_end_matches('.');
+            name.len() > domain.len() + 1
+                && name.as_bytes()[name.len() - domain.len() - 1] == b'.'
+                && name[name.len() - domain.len()..].eq_ignore_ascii_case(domain)
+        })
+    }
+
+    /// Call `member`, and read the name and address out of the reply with
+    /// `read`. Names Avahi can't find are `None`.
+    fn resolve(member: &str, signature: &str, body: &[u8], read: impl Fn(&mut Reader<'_>) -> Option<(String, IpAddr)>)
+        -> Result<Option<(String, IpAddr)>>
+    {
+        let reply = match call(C::BUS, member, signature, body, C::TIMEOUT) {
+            Ok(Ok(reply)) => reply,
+            Ok(Err(error_name)) => return match error_name.as_str() {
+                "org.freedesktop.Avahi.TimeoutError" | "org.freedesktop.Avahi.NotFoundError"
+                    | "org.freedesktop.Avahi.InvalidHostNameError" | "org.freedesktop.Avahi.InvalidAddressError" => {
+                    Ok(None)
+                }
+                // Avahi isn't running.
+                "org.freedesktop.DBus.Error.ServiceUnknown" | "org.freedesktop.DBus.Error.NameHasNoOwner" => {
+                    Err(Error::with_errno(NssStatus::Unavailable, ECONNREFUSED))
+                }
+                _ => {
+                    diag::log(format_args!("Avahi's {} failed: {}", member, error_name));
+                    Err(Error::with_errno(NssStatus::Unavailable, EIO))
+                }
+            },
+            Err(err) => {
+                if err.kind() != io::ErrorKind::NotFound && err.kind() != io::ErrorKind::ConnectionRefused {
+                    diag::log(format_args!("can't call Avahi's {} on {}: {}", member, C::BUS, err));
+                }
+                return Err(Error::with_errno(NssStatus::Unavailable, err.raw_os_error().unwrap_or(EIO)));
+            }
+        };
+        match read(&mut reply.body()) {
+            Some(found) => Ok(Some(found)),
+            None => {
+                diag::log(format_args!("malformed reply to Avahi's {}", member));
+                Err(Error::with_errno(NssStatus::Unavailable, EINVAL))
+            }
+        }
+    }
+}
+
+fn entry(name: String, addr: IpAddr, af: AddressFamily) -> Option<HostEntry<'static>> {
+    let addr_list = match (addr, af) {
+        (IpAddr::V4(ip), AddressFamily::Ipv4) => HostAddressList::V4(vec![ip]),
+        (IpAddr::V6(ip), AddressFamily::Ipv6) => HostAddressList::V6(vec![ip]),
+        _ => return None,
+    };
+    Some(HostEntry { name: Cow::Owned(CString::new(name).ok()?), aliases: vec![], addr_list })
+}
+
+impl<C: AvahiConfig> NameService for AvahiService<C> {
+    fn gethostbyname2_r(name: &CStr, af: AddressFamily) -> Result<Option<HostEntry<'_>>> {
+        let name = match name.to_str() {
+            Ok(name) if Self::in_domains(name) => name,
+            _ => return Ok(None),
+        };
+        let aprotocol = match af {
+            AddressFamily::Ipv4 => PROTO_INET,
+            AddressFamily::Ipv6 => PROTO_INET6,
+        };
+        let mut body = Writer::default();
+        body.i32(UNSPEC).i32(UNSPEC).string(name).i32(aprotocol).u32(0);
+        // (interface, protocol, name, aprotocol, address, flags)
+        let found = Self::resolve("ResolveHostName", "iisiu", &body.0, |r| {
+            r.i32()?;
+            r.i32()?;
+            let name = r.string()?.to_string();
+            r.i32()?;
+            Some((name, r.string()?.parse().ok()?))
+        })?;
+        Ok(found.and_then(|(name, addr)| entry(name, addr, af)))
+    }
+
+    fn gethostbyaddr_r(addr: &IpAddr) -> Result<Option<HostEntry<'_>>> {
+        if C::MINIMAL && !is_link_local(addr) {
+            return Ok(None);
+        }
+        let mut body = Writer::default();
+        body.i32(UNSPEC).i32(UNSPEC).string(&addr.to_string()).u32(0);
+        // (interface, protocol, aprotocol, address, name, flags)
+        let found = Self::resolve("ResolveAddress", "iisu", &body.0, |r| {
+            r.i32()?;
+            r.i32()?;
+            r.i32()?;
+            r.string()?;
+            Some((r.string()?.to_string(), *addr))
+        })?;
+        let af = if addr.is_ipv4() { AddressFamily::Ipv4 } else { AddressFamily::Ipv6 };
+        Ok(found.and_then(|(name, addr)| entry(name, addr, af)))
+    }
+}
+
+#[test]
+fn test_avahi_dbus() {
+    use std::os::unix::net::UnixListener;
+    use std::thread;
+
+    struct TestBus;
+    impl AvahiConfig for TestBus {
+        const BUS: &'static str = "/tmp/nsswitch_service-test-avahi.sock";
+        const DOMAINS: &'static [&'static str] = &["local", "lan."];
+        const MINIMAL: bool = false;
+    }
+
+    let _ = std::fs::remove_file(TestBus::BUS);
+    let listener = UnixListener::bind(TestBus::BUS).unwrap();
+    let bus = thread::spawn(move || {
+        let mut calls = vec![];
+        for _ in 0..3 {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut line = vec![];
+            let mut byte = [0];
+            while !line.ends_with(b"\r\n") {
+                stream.read_exact(&mut byte).unwrap();
+                line.push(byte[0]);
+            }
+            assert!(line.starts_with(b"\0AUTH EXTERNAL "));
+            stream.write_all(b"OK 1234deadbeef\r\n").unwrap();
+            let mut begin = [0; 7];
+            stream.read_exact(&mut begin).unwrap();
+            assert_eq!(&begin, b"BEGIN\r\n");
+
+            let hello = read_message(&mut stream).unwrap();
+            assert_eq!(hello.kind, METHOD_CALL);
+            let mut welcome = Writer::default();
+            welcome.string(":1.42");
+            stream.write_all(&message(METHOD_RETURN, 1, &[(FIELD_REPLY_SERIAL, Field::U32(1)),
+                                                (FIELD_SIGNATURE, Field::Signature("s"))], &welcome.0)).unwrap();
+
+            let request = read_message(&mut stream).unwrap();
+            let mut r = request.body();
+            let (_, _, arg) = (r.i32().unwrap(), r.i32().unwrap(), r.string().unwrap().to_string());
+            let reply = match arg.as_str() {
+                "printer.local" => {
+                    let mut body = Writer::default();
+                    body.i32(2).i32(PROTO_INET).string("printer.local").i32(PROTO_INET).string("192.168.1.20").u32(4);
+                    message(METHOD_RETURN, 7, &[(FIELD_REPLY_SERIAL, Field::U32(2)),
+                                    (FIELD_SIGNATURE, Field::Signature("iisisu"))], &body.0)
+                }
+                "192.168.1.20" => {
+                    let mut body = Writer::default();
+                    body.i32(2).i32(PROTO_INET).i32(PROTO_INET).string("192.168.1.20").string("printer.local").u32(4);
+                    message(METHOD_RETURN, 8, &[(FIELD_REPLY_SERIAL, Field::U32(2)),
+                                    (FIELD_SIGNATURE, Field::Signature("iiissu"))], &body.0)
+                }
+                _ => message(ERROR, 9, &[(FIELD_REPLY_SERIAL, Field::U32(2)),
+                                         (FIELD_ERROR_NAME, Field::Str("org.freedesktop.Avahi.TimeoutError"))], &[]),
+            };
+            stream.write_all(&reply).unwrap();
+            calls.push(arg);
+        }
+        calls
+    });
+
+    let name = |s: &'static [u8]| CStr::from_bytes_with_nul(s).unwrap();
+    let entry = AvahiService::<TestBus>::gethostbyname2_r(name(b"printer.local\0"), AddressFamily::Ipv4).unwrap().unwrap();
+    assert_eq!(entry.name.to_str(), Ok("printer.local"));
+    assert!(matches!(entry.addr_list, HostAddressList::V4(ref addrs) if addrs[0].octets() == [192, 168, 1, 20]));
+    let addr = "192.168.1.20".parse().unwrap();
+    let entry = AvahiService::<TestBus>::gethostbyaddr_r(&addr).unwrap().unwrap();
+    assert_eq!(entry.name.to_str(), Ok("printer.local"));
+    assert!(AvahiService::<TestBus>::gethostbyname2_r(name(b"nas.LAN.\0"), AddressFamily::Ipv6).unwrap().is_none());
+    assert!(AvahiService::<TestBus>::gethostbyname2_r(name(b"example.com\0"), AddressFamily::Ipv4).unwrap().is_none());
+    assert!(AvahiService::<TestBus>::gethostbyname2_r(name(b"local\0"), AddressFamily::Ipv4).unwrap().is_none());
+    assert_eq!(bus.join().unwrap(), vec!["printer.local", "192.168.1.20", "nas.LAN."]);
+    let _ = std::fs::remove_file(TestBus::BUS);
+}
+
+#[test]
+fn test_read_message() {
+    use std::io::Cursor;
+
+    let read = |bytes: &[u8]| read_message(&mut Cursor::new(bytes.to_vec())).map(|message| message.body);
+    let reply = |fields: &[(u8, Field<'_>)], body: &[u8]| message(METHOD_RETURN, 1, fields, body);
+    let fields = [(FIELD_REPLY_SERIAL, Field::U32(2)), (FIELD_SIGNATURE, Field::Signature("u"))];
+    let with_body = reply(&fields, &[7, 0, 0, 0]);
+    assert_eq!(read(&with_body).unwrap(), [7, 0, 0, 0]);
+
+    // Messages in big-endian order are read too.
+    let mut big_endian = Vec::from(&b"B\x02\0\x01\0\0\0\0\0\0\0\x01\0\0\0\x08"[..]);
+    big_endian.extend_from_slice(&[FIELD_REPLY_SERIAL, 1, b'u', 0, 0, 0, 0, 2]);
+    let message = read_message(&mut Cursor::new(big_endian)).unwrap();
+    assert_eq!((message.kind, message.reply_serial), (METHOD_RETURN, Some(2)));
+
+    // Messages cut short.
+    assert_eq!(read(&with_body[..10]).unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+    assert_eq!(read(&with_body[..with_body.len() - 1]).unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+
+    // An unknown byte order, lengths over the limit, a body without a
+    // signature or the other way around, a field of a type replies don't
+    // have, and a string without its NUL.
+    let mut bad_order = with_body.clone();
+    bad_order[0] = b'x';
+    let mut too_long = with_body.clone();
+    too_long[12..16].copy_from_slice(&(MAX_MESSAGE as u32).to_le_bytes());
+    let no_signature = reply(&[(FIELD_REPLY_SERIAL, Field::U32(2))], &[7, 0, 0, 0]);
+    let no_body = reply(&[(FIELD_SIGNATURE, Field::Signature("u"))], &[]);
+    let mut bad_type = reply(&[(FIELD_REPLY_SERIAL, Field::U32(2))], &[]);
+    bad_type[17] = b'x';
+    let mut no_nul = reply(&[(FIELD_ERROR_NAME, Field::Str("org.example.Error"))], &[]);
+    let nul = 16 + 8 + "org.example.Error".len();
+    no_nul[nul] = b'!';
+    for bad in &[bad_order, too_long, no_signature, no_body, bad_type, no_nul] {
+        assert_eq!(read(bad).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+}
+
+#[test]
+fn test_avahi_errors() {
+    use libc::{EAGAIN, ENOENT};
+    use std::os::unix::net::UnixListener;
+    use std::thread;
+
+    // A bus on `socket` that authenticates clients if `welcome`, and for
+    // each call, sends what `answer` returns for the name or address asked
+    // about, or nothing, waiting for the client to give up.
+    fn fake_bus(socket: &'static str, welcome: bool, answer: fn(&str) -> Option<Vec<u8>>) {
+        let _ = std::fs::remove_file(socket);
+        let listener = UnixListener::bind(socket).unwrap();
+        thread::spawn(move || {
+            for mut stream in listener.incoming().map(io::Result::unwrap) {
+                let mut line = vec![];
+                let mut byte = [0];
+                while !line.ends_with(b"\r\n") {
+                    stream.read_exact(&mut byte).unwrap();
+                    line.push(byte[0]);
+                }
+                if !welcome {
+                    stream.write_all(b"REJECTED EXTERNAL\r\n").unwrap();
+                    continue;
+                }
+                stream.write_all(b"OK 1234deadbeef\r\n").unwrap();
+                stream.read_exact(&mut [0; 7]).unwrap();
+                read_message(&mut stream).unwrap();
+                let request = read_message(&mut stream).unwrap();
+                let mut r = request.body();
+                let (_, _, arg) = (r.i32().unwrap(), r.i32().unwrap(), r.string().unwrap().to_string());
+                match answer(&arg) {
+                    Some(reply) => stream.write_all(&reply).unwrap(),
+                    None => {
+                        let _ = io::copy(&mut stream, &mut io::sink());
+                    }
+                }
+            }
+        });
+    }
+
+    struct Bus;
+    impl AvahiConfig for Bus {
+        const BUS: &'static str = "/tmp/nsswitch_service-test-avahi-errors.sock";
+        const TIMEOUT: Duration = Duration::from_millis(100);
+    }
+    struct Unwelcoming;
+    impl AvahiConfig for Unwelcoming {
+        const BUS: &'static str = "/tmp/nsswitch_service-test-avahi-unwelcoming.sock";
+    }
+    struct NoBus;
+    impl AvahiConfig for NoBus {
+        const BUS: &'static str = "/tmp/nsswitch_service-test-avahi-missing.sock";
+    }
+
+    fake_bus(Bus::BUS, true, |name| {
+        let error = |name| {
+            message(ERROR, 9, &[(FIELD_REPLY_SERIAL, Field::U32(2)), (FIELD_ERROR_NAME, Field::Str(name))], &[])
+        };
+        match name {
+            "gone.local" => Some(error("org.freedesktop.Avahi.NotFoundError")),
+            "stopped.local" => Some(error("org.freedesktop.DBus.Error.ServiceUnknown")),
+            "denied.local" => Some(error("org.freedesktop.DBus.Error.AccessDenied")),
+            "garbled.local" => {
+                let mut body = Writer::default();
+                body.string("garbled.local");
+                Some(message(METHOD_RETURN, 7, &[(FIELD_REPLY_SERIAL, Field::U32(2)),
+                                                 (FIELD_SIGNATURE, Field::Signature("s"))], &body.0))
+            }
+            _ => None,
+        }
+    });
+    fake_bus(Unwelcoming::BUS, false, |_| None);
+    let _ = std::fs::remove_file(NoBus::BUS);
+
+    // Names Avahi can't find aren't found; everything else is unavailable,
+    // with an errno that says why, if there is one.
+    let lookup = |name: &str| {
+        let name = CString::new(name).unwrap();
+        match AvahiService::<Bus>::gethostbyname2_r(&name, AddressFamily::Ipv4) {
+            Ok(found) => Ok(found.is_some()),
+            Err(err) => Err((err.status(), err.errno())),
+        }
+    };
+    assert_eq!(lookup("gone.local"), Ok(false));
+    assert_eq!(lookup("stopped.local"), Err((NssStatus::Unavailable, ECONNREFUSED)));
+    assert_eq!(lookup("denied.local"), Err((NssStatus::Unavailable, EIO)));
+    assert_eq!(lookup("garbled.local"), Err((NssStatus::Unavailable, EINVAL)));
+    assert_eq!(lookup("silent.local"), Err((NssStatus::Unavailable, EAGAIN)));
+
+    let name = CString::new("printer.local").unwrap();
+    let err = AvahiService::<Unwelcoming>::gethostbyname2_r(&name, AddressFamily::Ipv4).unwrap_err();
+    assert_eq!((err.status(), err.errno()), (NssStatus::Unavailable, EIO));
+    let err = AvahiService::<NoBus>::gethostbyname2_r(&name, AddressFamily::Ipv4).unwrap_err();
+    assert_eq!((err.status(), err.errno()), (NssStatus::Unavailable, ENOENT));
+    let _ = std::fs::remove_file(Bus::BUS);
+    let _ = std::fs::remove_file(Unwelcoming::BUS);
+}
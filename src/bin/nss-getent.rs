@@ -0,0 +1,258 @@
+//! `getent` for a single module, loaded directly rather than through
+//! `nsswitch.conf`:
+//!
+//! ```text
+//! nss-getent target/debug/libnss_example.so hosts example.test
+//! nss-getent target/debug/libnss_example.so passwd
+//! ```
+//!
+//! The databases are `hosts`, `ahosts` (the `getaddrinfo` lookup), `passwd`,
+//! and `group`. With no keys, it lists the whole database. The output and
+//! the exit status are the same as `getent`'s: 1 for bad arguments, 2 if a
+//! key wasn't found, 3 if the module can't list the database.
+
+use nsswitch_service::testing::Module;
+use nsswitch_service::{AddressFamily, Error, GroupEntry, HostAddressList, HostEntry, PasswdEntry};
+use std::ffi::CStr;
+use std::io::{self, Write};
+use std::net::IpAddr;
+use std::process;
+
+const USAGE: &str = "usage: nss-getent MODULE.so {hosts|ahosts|passwd|group} [KEY]...";
+
+/// Why a command failed, as `getent` reports it.
+enum Failure {
+    NotFound,
+    CantEnumerate,
+    Error(Error),
+    Output(io::Error),
+}
+
+impl From<io::Error> for Failure {
+    fn from(err: io::Error) -> Failure {
+        Failure::Output(err)
+    }
+}
+
+type Outcome = Result<(), Failure>;
+
+fn found<E>(result: nsswitch_service::Result<Option<E>>) -> Result<E, Failure> {
+    match result {
+        Ok(Some(entry)) => Ok(entry),
+        Ok(None) => Err(Failure::NotFound),
+        Err(err) => Err(Failure::Error(err)),
+    }
+}
+
+fn listed<E>(result: nsswitch_service::Result<Vec<E>>) -> Result<Vec<E>, Failure> {
+    result.map_err(Failure::Error)
+}
+
+/// Fail unless the module exports all of `functions`.
+fn require(module: &Module, functions: &[&str], failure: fn() -> Failure) -> Outcome {
+    for function in functions {
+        if module.symbol(function).is_none() {
+            eprintln!("nss-getent: module has no _nss_{}_{}", module.name(), function);
+            return Err(failure());
+        }
+    }
+    Ok(())
+}
+
+fn not_found() -> Failure {
+    Failure::NotFound
+}
+
+fn cant_enumerate() -> Failure {
+    Failure::CantEnumerate
+}
+
+fn bytes(s: &CStr) -> &[u8] {
+    s.to_bytes()
+}
+
+fn print_host(out: &mut impl Write, host: &HostEntry) -> io::Result<()> {
+    let addrs: Vec<IpAddr> = match host.addr_list {
+        HostAddressList::V4(ref addrs) => addrs.iter().map(|&ip| IpAddr::from(ip)).collect(),
+        HostAddressList::V6(ref addrs) => addrs.iter().map(|&ip| IpAddr::from(ip)).collect(),
+    };
+    for addr in addrs {
+        write!(out, "{:<15} ", addr)?;
+        out.write_all(bytes(&host.name))?;
+        for alias in &host.aliases {
+            out.write_all(b" ")?;
+            out.write_all(bytes(alias))?;
+        }
+        out.write_all(b"\n")?;
+    }
+    Ok(())
+}
+
+fn print_passwd(out: &mut impl Write, pw: &PasswdEntry) -> io::Result<()> {
+    out.write_all(bytes(&pw.name))?;
+    out.write_all(b":")?;
+    out.write_all(bytes(&pw.passwd))?;
+    write!(out, ":{}:{}:", pw.uid, pw.gid)?;
+    out.write_all(bytes(&pw.gecos))?;
+    out.write_all(b":")?;
+    out.write_all(bytes(&pw.dir))?;
+    out.write_all(b":")?;
+    out.write_all(bytes(&pw.shell))?;
+    out.write_all(b"\n")
+}
+
+fn print_group(out: &mut impl Write, gr: &GroupEntry) -> io::Result<()> {
+    out.write_all(bytes(&gr.name))?;
+    out.write_all(b":")?;
+    out.write_all(bytes(&gr.passwd))?;
+    write!(out, ":{}:", gr.gid)?;
+    for (i, member) in gr.members.iter().enumerate() {
+        if i > 0 {
+            out.write_all(b",")?;
+        }
+        out.write_all(bytes(member))?;
+    }
+    out.write_all(b"\n")
+}
+
+fn hosts(module: &Module, keys: &[String], out: &mut impl Write) -> Outcome {
+    if keys.is_empty() {
+        require(module, &["sethostent", "gethostent_r", "endhostent"], cant_enumerate)?;
+        for host in listed(module.hosts())? {
+            print_host(out, &host)?;
+        }
+        return Ok(());
+    }
+    let mut outcome = Ok(());
+    for key in keys {
+        // Like getent: addresses are looked up by address, and names as
+        // IPv6 first, then IPv4.
+        let host = match key.parse::<IpAddr>() {
+            Ok(addr) => require(module, &["gethostbyaddr_r"], not_found)
+                .and_then(|()| found(module.gethostbyaddr(addr))),
+            Err(_) => require(module, &["gethostbyname2_r"], not_found).and_then(|()| {
+                found(module.gethostbyname2(key, AddressFamily::Ipv6))
+                    .or_else(|_| found(module.gethostbyname2(key, AddressFamily::Ipv4)))
+            }),
+        };
+        match host {
+            Ok(host) => print_host(out, &host)?,
+            Err(failure) => outcome = Err(failure),
+        }
+    }
+    outcome
+}
+
+fn ahosts(module: &Module, keys: &[String], out: &mut impl Write) -> Outcome {
+    if keys.is_empty() {
+        return hosts(module, keys, out);
+    }
+    require(module, &["gethostbyname4_r"], not_found)?;
+    let mut outcome = Ok(());
+    for key in keys {
+        match found(module.gethostbyname4(key)) {
+            Ok(host) => {
+                for (i, addr) in host.addrs.iter().enumerate() {
+                    write!(out, "{:<15} STREAM", addr)?;
+                    if i == 0 {
+                        out.write_all(b" ")?;
+                        out.write_all(bytes(&host.name))?;
+                    }
+                    write!(out, "\n{:<15} DGRAM  \n{:<15} RAW    \n", addr, addr)?;
+                }
+            }
+            Err(failure) => outcome = Err(failure),
+        }
+    }
+    outcome
+}
+
+fn passwd(module: &Module, keys: &[String], out: &mut impl Write) -> Outcome {
+    if keys.is_empty() {
+        require(module, &["setpwent", "getpwent_r", "endpwent"], cant_enumerate)?;
+        for pw in listed(module.users())? {
+            print_passwd(out, &pw)?;
+        }
+        return Ok(());
+    }
+    let mut outcome = Ok(());
+    for key in keys {
+        let pw = match key.parse() {
+            Ok(uid) => require(module, &["getpwuid_r"], not_found)
+                .and_then(|()| found(module.getpwuid(uid))),
+            Err(_) => require(module, &["getpwnam_r"], not_found)
+                .and_then(|()| found(module.getpwnam(key))),
+        };
+        match pw {
+            Ok(pw) => print_passwd(out, &pw)?,
+            Err(failure) => outcome = Err(failure),
+        }
+    }
+    outcome
+}
+
+fn group(module: &Module, keys: &[String], out: &mut impl Write) -> Outcome {
+    if keys.is_empty() {
+        require(module, &["setgrent", "getgrent_r", "endgrent"], cant_enumerate)?;
+        for gr in listed(module.groups())? {
+            print_group(out, &gr)?;
+        }
+        return Ok(());
+    }
+    let mut outcome = Ok(());
+    for key in keys {
+        let gr = match key.parse() {
+            Ok(gid) => require(module, &["getgrgid_r"], not_found)
+                .and_then(|()| found(module.getgrgid(gid))),
+            Err(_) => require(module, &["getgrnam_r"], not_found)
+                .and_then(|()| found(module.getgrnam(key))),
+        };
+        match gr {
+            Ok(gr) => print_group(out, &gr)?,
+            Err(failure) => outcome = Err(failure),
+        }
+    }
+    outcome
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.len() < 2 {
+        eprintln!("{}", USAGE);
+        process::exit(1);
+    }
+    let module = Module::open(&args[0]).unwrap_or_else(|err| {
+        eprintln!("nss-getent: {}: {}", args[0], err);
+        process::exit(1);
+    });
+    let command = match args[1].as_str() {
+        "hosts" => hosts,
+        "ahosts" => ahosts,
+        "passwd" => passwd,
+        "group" => group,
+        other => {
+            eprintln!("nss-getent: unknown database: {}\n{}", other, USAGE);
+            process::exit(1);
+        }
+    };
+
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    let outcome = command(&module, &args[2..], &mut out);
+    let _ = out.flush();
+    process::exit(match outcome {
+        Ok(()) => 0,
+        Err(Failure::NotFound) => 2,
+        Err(Failure::CantEnumerate) => 3,
+        Err(Failure::Error(err)) => {
+            eprintln!("nss-getent: lookup failed: status {:?}, errno {}", err.status(), err.errno());
+            2
+        }
+        // Like getent, quietly stop when the reader does.
+        Err(Failure::Output(ref err)) if err.kind() == io::ErrorKind::BrokenPipe => 1,
+        Err(Failure::Output(err)) => {
+            eprintln!("nss-getent: {}", err);
+            1
+        }
+    });
+}
@@ -0,0 +1,275 @@
+//! A ready-made `hosts` service that blocks the names on ad and malware
+//! blocklists, the way Pi-hole does, without running a DNS server.
+
+use crate::diag;
+use crate::errors::{Error, HostError, NssStatus, Result};
+use crate::ffi::c_int;
+use crate::fork::ForkSafeMutex;
+use crate::interfaces::{AddressFamily, HostAddressList, HostEntry, NameService};
+use crate::reload::Reload;
+use libc::{EIO, ENOENT};
+use std::borrow::Cow;
+use std::collections::HashSet;
+use std::ffi::CStr;
+use std::fs;
+use std::io;
+use std::marker::PhantomData;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::sync::Arc;
+
+/// Where a `BlocklistService` gets its lists, and what it does with the
+/// names on them.
+pub trait BlocklistConfig: 'static {
+    /// The lists to read.
+    const PATHS: &'static [&'static str];
+
+    /// If true, blocking a domain blocks every name under it too, so a
+    /// list with `doubleclick.net` blocks `ad.doubleclick.net`.
+    const SUBDOMAINS: bool = true;
+
+    /// If true, blocked names resolve to `0.0.0.0` and `::`, so
+    /// connections to them fail at once, and other names aren't found.
+    ///
+    /// If false, blocked names aren't found, and other names are
+    /// `NssStatus::Unavailable`, so that `[NOTFOUND=return]` stops the
+    /// search at blocked names only:
+    ///
+    /// ```text
+    /// hosts: files blocklist [NOTFOUND=return] dns
+    /// ```
+    const SINKHOLE: bool = true;
+}
+
+/// A `hosts` service that blocks ad, tracking, and malware domains for a
+/// whole machine, the way Pi-hole does for a network, but without a DNS
+/// server. Put it before `dns`:
+///
+/// ```ignore
+/// struct Blocklist;
+///
+/// impl BlocklistConfig for Blocklist {
+///     const PATHS: &'static [&'static str] = &["/etc/blocklist.d/ads.hosts", "/etc/blocklist.d/malware.txt"];
+/// }
+///
+/// nssglue_hosts!("blocklist", BlocklistService<Blocklist>);
+/// ```
+///
+/// Lists can be in hosts format (`0.0.0.0 ads.example.com`, where the
+/// address doesn't matter and entries for `localhost` and the like are
+/// ignored), one domain per line, or Adblock's `||ads.example.com^`, and
+/// can be mixed; `#` and `!` start comments. Names match without regard to
+/// ASCII case or a trailing dot. Reverse lookups find nothing, and
+/// `sethostent` lists nothing.
+///
+/// The lists are read once, the first time the module needs them. Files
+/// that don't exist are skipped, but if none of them do, or one can't be
+/// read, every lookup reports `NssStatus::Unavailable` and a message goes
+/// to the diagnostic sink, so nothing is blocked.
+pub struct BlocklistService<C>(PhantomData<C>);
+
+/// Names that hosts-format lists map to real addresses, not blocked ones.
+const NOT_BLOCKED: &[&str] = &[
+    "localhost", "localhost.localdomain", "local", "broadcasthost",
+    "ip6-localhost", "ip6-loopback", "ip6-localnet", "ip6-mcastprefix",
+    "ip6-allnodes", "ip6-allrouters", "ip6-allhosts",
+];
+
+/// The blocked names, in lowercase and without trailing dots.
+#[derive(Debug, Default)]
+struct Blocklist {
+    names: HashSet<String>,
+}
+
+impl Blocklist {
+    /// Add the names in `text`.
+    fn parse(&mut self, text: &str) {
+        for line in text.lines() {
+            let line = line.split(['#', '!']).next().unwrap_or("");
+            let mut fields = line.split_whitespace().peekable();
+            // A hosts-format line starts with an address.
+            if fields.peek().is_some_and(|first| first.parse::<IpAddr>().is_ok()) {
+                fields.next();
+            }
+            for name in fields {
+                let name = name.strip_prefix("||").unwrap_or(name);
+                let name = name.strip_suffix('^').unwrap_or(name).trim_end_matches('.').to_ascii_lowercase();
+                if !name.is_empty() && name.parse::<IpAddr>().is_err() && !NOT_BLOCKED.contains(&name.as_str()) {
+                    self.names.insert(name);
+                }
+            }
+        }
+    }
+
+    fn is_blocked(&self, name: &str, subdomains: bool) -> bool {
+        let name = name.trim_end_matches('.').to_ascii_lowercase();
+        if !subdomains {
+            return self.names.contains(&name);
+        }
+        let mut rest = name.as_str();
+        loop {
+            if self.names.contains(rest) {
+                return true;
+            }
+            match rest.find('.') {
+                Some(dot) => rest = &rest[dot + 1..],
+                None => return false,
+            }
+        }
+    }
+}
+
+/// Read and parse all of `paths`, or return the errno to report.
+fn load(paths: &[&str]) -> std::result::Result<Arc<Blocklist>, c_int> {
+    let mut list = Blocklist::default();
+    let mut found = false;
+    for path in paths {
+        match fs::read(path) {
+            Ok(bytes) => {
+                found = true;
+                list.parse(&String::from_utf8_lossy(&bytes));
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => {
+                diag::log(format_args!("can't read {}: {}", path, err));
+                return Err(err.raw_os_error().unwrap_or(EIO));
+            }
+        }
+    }
+    if !found {
+        diag::log(format_args!("none of these blocklists exist: {}", paths.join(", ")));
+        return Err(ENOENT);
+    }
+    Ok(Arc::new(list))
+}
+
+type Loaded = std::result::Result<Arc<Blocklist>, c_int>;
+
+/// Every set of lists loaded so far.
+static LISTS: ForkSafeMutex<Vec<(&'static [&'static str], Loaded)>> = ForkSafeMutex::new();
+
+impl<C: BlocklistConfig> BlocklistService<C> {
+    /// What was loaded for these paths, if anything has been.
+    fn cached() -> Option<Loaded> {
+        LISTS.lock().iter().find(|&&(paths, _)| paths == C::PATHS).map(|(_, loaded)| loaded.clone())
+    }
+
+    /// The list, loaded the first time it's needed. The files are read
+    /// without holding the lock; if two threads both read them, the first
+    /// to finish wins.
+    fn list() -> Result<Arc<Blocklist>> {
+        let loaded = match Self::cached() {
+            Some(loaded) => loaded,
+            None => {
+                let loaded = load(C::PATHS);
+                let mut lists = LISTS.lock();
+                match lists.iter().find(|&&(paths, _)| paths == C::PATHS) {
+                    Some((_, existing)) => existing.clone(),
+                    None => {
+                        lists.push((C::PATHS, loaded.clone()));
+                        loaded
+                    }
+                }
+            }
+        };
+        loaded.map_err(|errno| Error::with_errno(NssStatus::Unavailable, errno))
+    }
+
+    /// What to report for a name that isn't blocked.
+    fn not_blocked<T>() -> Result<Option<T>> {
+        if C::SINKHOLE {
+            Ok(None)
+        } else {
+            Err(Error::with_errno(NssStatus::Unavailable, ENOENT))
+        }
+    }
+}
+
+impl<C: BlocklistConfig> Reload for BlocklistService<C> {
+    const FILES: &'static [&'static str] = C::PATHS;
+
+    fn reload() {
+        let new = load(C::PATHS);
+        let mut lists = LISTS.lock();
+        match lists.iter_mut().find(|(paths, _)| *paths == C::PATHS) {
+            // Keep the old list if the files are broken.
+            Some((_, loaded)) => if new.is_ok() || loaded.is_err() {
+                *loaded = new;
+            },
+            None => lists.push((C::PATHS, new)),
+        }
+    }
+}
+
+impl<C: BlocklistConfig> NameService for BlocklistService<C> {
+    fn gethostbyname2_r(name: &CStr, af: AddressFamily) -> Result<Option<HostEntry<'_>>> {
+        let blocked = match name.to_str() {
+            Ok(s) => Self::list()?.is_blocked(s, C::SUBDOMAINS),
+            Err(_) => false,
+        };
+        if !blocked {
+            return Self::not_blocked();
+        }
+        if !C::SINKHOLE {
+            return Err(Error::with_host(NssStatus::NotFound, ENOENT, HostError::HostNotFound));
+        }
+        let addr_list = match af {
+            AddressFamily::Ipv4 => HostAddressList::V4(vec![Ipv4Addr::UNSPECIFIED]),
+            AddressFamily::Ipv6 => HostAddressList::V6(vec![Ipv6Addr::UNSPECIFIED]),
+        };
+        Ok(Some(HostEntry { name: Cow::Borrowed(name), aliases: vec![], addr_list }))
+    }
+
+    fn gethostbyaddr_r(_addr: &IpAddr) -> Result<Option<HostEntry<'_>>> {
+        Self::not_blocked()
+    }
+
+    fn on_fork_child() {
+        LISTS.reset();
+    }
+}
+
+#[test]
+fn test_blocklist() {
+    let mut list = Blocklist::default();
+    list.parse("# StevenBlack-style\n\
+                127.0.0.1 localhost\n\
+                255.255.255.255 broadcasthost\n\
+                ::1 ip6-localhost ip6-loopback\n\
+                0.0.0.0 0.0.0.0\n\
+                0.0.0.0 Ads.Example.COM tracker.example.net # trailing comment\n\
+                \n\
+                malware.example.org.\n\
+                ||doubleclick.test^\n\
+                ! adblock comment\n");
+    assert_eq!(list.names.len(), 4);
+    assert!(list.is_blocked("ads.example.com.", false));
+    assert!(!list.is_blocked("example.com", true));
+    assert!(list.is_blocked("x.ad.DOUBLECLICK.test", true));
+    assert!(!list.is_blocked("x.ad.doubleclick.test", false));
+    assert!(!list.is_blocked("localhost", true));
+
+    let path = "/tmp/nsswitch_service-test-blocklist.txt";
+    fs::write(path, "0.0.0.0 ads.example.com\n").unwrap();
+    struct Sinkhole;
+    impl BlocklistConfig for Sinkhole {
+        const PATHS: &'static [&'static str] = &["/nonexistent/list", "/tmp/nsswitch_service-test-blocklist.txt"];
+    }
+    struct Refuse;
+    impl BlocklistConfig for Refuse {
+        const PATHS: &'static [&'static str] = &["/tmp/nsswitch_service-test-blocklist.txt"];
+        const SINKHOLE: bool = false;
+    }
+    let name = |s: &'static [u8]| CStr::from_bytes_with_nul(s).unwrap();
+    let entry = BlocklistService::<Sinkhole>::gethostbyname2_r(name(b"www.ads.example.com\0"), AddressFamily::Ipv6)
+        .unwrap().unwrap();
+    assert!(matches!(entry.addr_list, HostAddressList::V6(ref addrs) if addrs[0].is_unspecified()));
+    assert!(BlocklistService::<Sinkhole>::gethostbyname2_r(name(b"example.com\0"), AddressFamily::Ipv4)
+        .unwrap().is_none());
+    let err = BlocklistService::<Refuse>::gethostbyname2_r(name(b"ads.example.com\0"), AddressFamily::Ipv4)
+        .unwrap_err();
+    assert_eq!((err.status(), err.host_error()), (NssStatus::NotFound, Some(HostError::HostNotFound)));
+    let err = BlocklistService::<Refuse>::gethostbyname2_r(name(b"example.com\0"), AddressFamily::Ipv4)
+        .unwrap_err();
+    assert_eq!(err.status(), NssStatus::Unavailable);
+    let _ = fs::remove_file(path);
+}
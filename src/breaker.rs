@@ -0,0 +1,322 @@
+//! Not asking a service that keeps failing, so lookups fail fast instead of
+//! each waiting for it.
+
+use crate::diag;
+use crate::errors::{Error, HostError, NssStatus, Result};
+use crate::ffi::{gid_t, uid_t};
+use crate::fork::ForkSafeMutex;
+use crate::interfaces::{AddressFamily, Entries, GroupEntry, GroupService, HostAddresses, HostEntry,
+                        HostEntryWithTtl, HostLimits, NameService, PasswdEntry, PasswdService, ShadowEntry,
+                        ShadowService};
+use libc::ENOENT;
+use std::any::{type_name, TypeId};
+use std::collections::HashMap;
+use std::ffi::CStr;
+use std::marker::PhantomData;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+/// When a `CircuitBreaker` stops asking its service, and for how long.
+pub trait BreakerConfig: 'static {
+    /// How many lookups in a row must fail before the breaker opens. A
+    /// lookup fails if it reports `NssStatus::TryAgain` or
+    /// `NssStatus::Unavailable` (other than with errno `ENOENT`, which
+    /// services use to decline a lookup), or takes longer than `SLOW`.
+    const FAILURES: u32 = 5;
+
+    /// Lookups that take longer than this count as failures, whatever
+    /// their result. If `None`, the service's `LOOKUP_TIMEOUT`, if any.
+    const SLOW: Option<Duration> = None;
+
+    /// How long the breaker stays open before letting one lookup through to
+    /// see if the service has recovered.
+    const OPEN_FOR: Duration = Duration::from_secs(30);
+
+    /// What lookups get while the breaker is open: `NssStatus::TryAgain`
+    /// (the default), `NssStatus::NotFound`, which makes most programs give
+    /// up on the name at once, or `NssStatus::Unavailable`, which makes
+    /// glibc go on to the next service in `nsswitch.conf` by default.
+    const OPEN_STATUS: NssStatus = NssStatus::TryAgain;
+}
+
+/// The usual settings.
+pub struct BreakerDefaults;
+
+impl BreakerConfig for BreakerDefaults {}
+
+/// The service `S`, except that while it is clearly down, lookups fail at
+/// once without asking it, so that a dead server doesn't hold up every
+/// lookup in every process on the machine:
+///
+/// ```ignore
+/// nssglue_passwd!("ldap", CircuitBreaker<LdapService>);
+/// nssglue_group!("ldap", CircuitBreaker<LdapService>);
+/// ```
+///
+/// After `C::FAILURES` failed lookups in a row, the breaker opens, and
+/// lookups fail with `C::OPEN_STATUS` for `C::OPEN_FOR`. Then the next
+/// lookup is passed on to `S` as a probe, while the others keep failing; if
+/// the probe succeeds, or doesn't find the name, the breaker closes, and if
+/// not it stays open for another `C::OPEN_FOR`. Opening and closing are
+/// logged.
+///
+/// Each `CircuitBreaker` type has one breaker, shared by all its databases,
+/// since they usually depend on the same server. Enumeration counts like
+/// any other lookup. `S`'s settings, such as `LOOKUP_TIMEOUT`, are passed
+/// through unchanged. A lookup the glue gave up on counts when it finishes.
+pub struct CircuitBreaker<S, C = BreakerDefaults>(PhantomData<(S, C)>);
+
+#[derive(Default)]
+struct Circuit {
+    /// Failed lookups since the last one that succeeded.
+    failures: u32,
+    /// If the breaker is open, when to let a probe through.
+    open_until: Option<Instant>,
+    /// Whether the probe is under way.
+    probing: bool,
+}
+
+/// The state of each `CircuitBreaker` type.
+static CIRCUITS: ForkSafeMutex<HashMap<TypeId, Circuit>> = ForkSafeMutex::new();
+
+/// True if `err` suggests the service is in trouble, not just that it
+/// couldn't help with this lookup.
+fn is_failure(err: &Error) -> bool {
+    match err.status() {
+        NssStatus::TryAgain => !err.is_insufficient_buffer(),
+        NssStatus::Unavailable => err.errno() != ENOENT,
+        NssStatus::NotFound | NssStatus::Success => false,
+    }
+}
+
+/// A lookup under way. Dropping it records the outcome, which is a failure
+/// unless `succeeded` is set, so that a panic counts as one.
+struct Attempt<S: 'static, C: BreakerConfig> {
+    started: Instant,
+    slow: Option<Duration>,
+    succeeded: bool,
+    breaker: PhantomData<CircuitBreaker<S, C>>,
+}
+
+impl<S: 'static, C: BreakerConfig> Drop for Attempt<S, C> {
+    fn drop(&mut self) {
+        let failed = !self.succeeded || self.slow.is_some_and(|slow| self.started.elapsed() > slow);
+        let mut circuits = CIRCUITS.lock();
+        let circuit = circuits.entry(TypeId::of::<CircuitBreaker<S, C>>()).or_default();
+        if failed {
+            circuit.failures = circuit.failures.saturating_add(1);
+            if circuit.probing || circuit.failures >= C::FAILURES {
+                if circuit.open_until.is_none() {
+                    diag::log(format_args!("{} failed {} times in a row; not asking it for {:?}",
+                                           type_name::<S>(), circuit.failures, C::OPEN_FOR));
+                }
+                circuit.open_until = Some(Instant::now() + C::OPEN_FOR);
+            }
+            circuit.probing = false;
+        } else {
+            if circuit.open_until.is_some() {
+                diag::log(format_args!("{} is working again", type_name::<S>()));
+            }
+            *circuit = Circuit::default();
+        }
+    }
+}
+
+impl<S: 'static, C: BreakerConfig> CircuitBreaker<S, C> {
+    /// What lookups get while the breaker is open.
+    fn open_error() -> Error {
+        match C::OPEN_STATUS {
+            NssStatus::NotFound => Error::with_host(NssStatus::NotFound, ENOENT, HostError::HostNotFound),
+            NssStatus::Unavailable => Error::with_host(NssStatus::Unavailable, ENOENT, HostError::NoRecovery),
+            _ => Error::timed_out(),
+        }
+    }
+
+    /// Call `lookup`, unless the breaker is open. `timeout` is the
+    /// service's `LOOKUP_TIMEOUT` for this database.
+    fn call<R>(timeout: Option<Duration>, lookup: impl FnOnce() -> Result<R>) -> Result<R> {
+        {
+            let mut circuits = CIRCUITS.lock();
+            let circuit = circuits.entry(TypeId::of::<Self>()).or_default();
+            if let Some(until) = circuit.open_until {
+                if circuit.probing || Instant::now() < until {
+                    return Err(Self::open_error());
+                }
+                circuit.probing = true;
+            }
+        }
+        let mut attempt = Attempt::<S, C> {
+            started: Instant::now(),
+            slow: C::SLOW.or(timeout),
+            succeeded: false,
+            breaker: PhantomData,
+        };
+        let result = lookup();
+        attempt.succeeded = result.as_ref().err().is_none_or(|err| !is_failure(err));
+        result
+    }
+}
+
+impl<S: NameService + 'static, C: BreakerConfig> NameService for CircuitBreaker<S, C> {
+    const ALLOW_EMPTY_ADDRESS_LIST: bool = S::ALLOW_EMPTY_ADDRESS_LIST;
+    const LOOKUP_TIMEOUT: Option<Duration> = S::LOOKUP_TIMEOUT;
+    const LIMITS: HostLimits = S::LIMITS;
+    const VALIDATE_HOSTNAMES: bool = S::VALIDATE_HOSTNAMES;
+
+    fn gethostbyname_r(name: &CStr) -> Result<Option<HostEntry<'_>>> {
+        Self::call(S::LOOKUP_TIMEOUT, || S::gethostbyname_r(name))
+    }
+
+    fn gethostbyname2_r(name: &CStr, af: AddressFamily) -> Result<Option<HostEntry<'_>>> {
+        Self::call(S::LOOKUP_TIMEOUT, || S::gethostbyname2_r(name, af))
+    }
+
+    fn gethostbyaddr_r(addr: &IpAddr) -> Result<Option<HostEntry<'_>>> {
+        Self::call(S::LOOKUP_TIMEOUT, || S::gethostbyaddr_r(addr))
+    }
+
+    fn gethostbyname3_r(name: &CStr, af: AddressFamily) -> Result<Option<HostEntryWithTtl<'_>>> {
+        Self::call(S::LOOKUP_TIMEOUT, || S::gethostbyname3_r(name, af))
+    }
+
+    fn gethostbyname4_r(name: &CStr) -> Result<Option<HostAddresses<'_>>> {
+        Self::call(S::LOOKUP_TIMEOUT, || S::gethostbyname4_r(name))
+    }
+
+    fn gethostbyaddr2_r(addr: &IpAddr) -> Result<Option<HostEntryWithTtl<'_>>> {
+        Self::call(S::LOOKUP_TIMEOUT, || S::gethostbyaddr2_r(addr))
+    }
+
+    fn sethostent(stay_open: bool) -> Result<Entries<HostEntry<'static>>> {
+        Self::call(S::LOOKUP_TIMEOUT, || S::sethostent(stay_open))
+    }
+
+    fn on_fork_child() {
+        CIRCUITS.reset();
+        S::on_fork_child()
+    }
+}
+
+impl<S: PasswdService + 'static, C: BreakerConfig> PasswdService for CircuitBreaker<S, C> {
+    const LOOKUP_TIMEOUT: Option<Duration> = S::LOOKUP_TIMEOUT;
+
+    fn getpwnam_r(name: &CStr) -> Result<Option<PasswdEntry<'_>>> {
+        Self::call(S::LOOKUP_TIMEOUT, || S::getpwnam_r(name))
+    }
+
+    fn getpwuid_r(uid: uid_t) -> Result<Option<PasswdEntry<'static>>> {
+        Self::call(S::LOOKUP_TIMEOUT, || S::getpwuid_r(uid))
+    }
+
+    fn setpwent() -> Result<Entries<PasswdEntry<'static>>> {
+        Self::call(S::LOOKUP_TIMEOUT, S::setpwent)
+    }
+
+    fn on_fork_child() {
+        CIRCUITS.reset();
+        S::on_fork_child()
+    }
+}
+
+impl<S: GroupService + 'static, C: BreakerConfig> GroupService for CircuitBreaker<S, C> {
+    const LOOKUP_TIMEOUT: Option<Duration> = S::LOOKUP_TIMEOUT;
+
+    fn getgrnam_r(name: &CStr) -> Result<Option<GroupEntry<'_>>> {
+        Self::call(S::LOOKUP_TIMEOUT, || S::getgrnam_r(name))
+    }
+
+    fn getgrgid_r(gid: gid_t) -> Result<Option<GroupEntry<'static>>> {
+        Self::call(S::LOOKUP_TIMEOUT, || S::getgrgid_r(gid))
+    }
+
+    fn setgrent() -> Result<Entries<GroupEntry<'static>>> {
+        Self::call(S::LOOKUP_TIMEOUT, S::setgrent)
+    }
+
+    fn initgroups_dyn(user: &CStr, group: gid_t) -> Result<Option<Vec<gid_t>>> {
+        Self::call(S::LOOKUP_TIMEOUT, || S::initgroups_dyn(user, group))
+    }
+
+    fn on_fork_child() {
+        CIRCUITS.reset();
+        S::on_fork_child()
+    }
+}
+
+impl<S: ShadowService + 'static, C: BreakerConfig> ShadowService for CircuitBreaker<S, C> {
+    const LOOKUP_TIMEOUT: Option<Duration> = S::LOOKUP_TIMEOUT;
+
+    fn getspnam_r(name: &CStr) -> Result<Option<ShadowEntry<'_>>> {
+        Self::call(S::LOOKUP_TIMEOUT, || S::getspnam_r(name))
+    }
+
+    fn setspent() -> Result<Entries<ShadowEntry<'static>>> {
+        Self::call(S::LOOKUP_TIMEOUT, S::setspent)
+    }
+
+    fn on_fork_child() {
+        CIRCUITS.reset();
+        S::on_fork_child()
+    }
+}
+
+#[test]
+fn test_circuit_breaker() {
+    use crate::testing::FixedHosts;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+    static DOWN: AtomicBool = AtomicBool::new(true);
+    static CALLS: AtomicUsize = AtomicUsize::new(0);
+
+    struct Flaky;
+    impl NameService for Flaky {
+        fn gethostbyname2_r(name: &CStr, af: AddressFamily) -> Result<Option<HostEntry<'_>>> {
+            CALLS.fetch_add(1, Ordering::SeqCst);
+            if DOWN.load(Ordering::SeqCst) {
+                Err(Error::with_errno(NssStatus::Unavailable, libc::ECONNREFUSED))
+            } else {
+                FixedHosts::gethostbyname2_r(name, af)
+            }
+        }
+
+        fn gethostbyaddr_r(addr: &IpAddr) -> Result<Option<HostEntry<'_>>> {
+            FixedHosts::gethostbyaddr_r(addr)
+        }
+    }
+
+    struct Quick;
+    impl BreakerConfig for Quick {
+        const FAILURES: u32 = 3;
+        const OPEN_FOR: Duration = Duration::from_millis(100);
+        const OPEN_STATUS: NssStatus = NssStatus::NotFound;
+    }
+    type Hosts = CircuitBreaker<Flaky, Quick>;
+    let lookup = |name: &'static [u8]| {
+        Hosts::gethostbyname2_r(CStr::from_bytes_with_nul(name).unwrap(), AddressFamily::Ipv4)
+    };
+
+    // Declining isn't failing.
+    assert!(!is_failure(&Error::with_errno(NssStatus::Unavailable, ENOENT)));
+    assert!(!is_failure(&Error::insufficient_buffer()));
+
+    for _ in 0..3 {
+        assert_eq!(lookup(b"host.test\0").unwrap_err().status(), NssStatus::Unavailable);
+    }
+    assert_eq!(CALLS.load(Ordering::SeqCst), 3);
+    let err = lookup(b"host.test\0").unwrap_err();
+    assert_eq!((err.status(), err.host_error()), (NssStatus::NotFound, Some(HostError::HostNotFound)));
+    assert_eq!(CALLS.load(Ordering::SeqCst), 3);
+
+    // A failed probe opens the breaker again.
+    std::thread::sleep(Quick::OPEN_FOR);
+    assert_eq!(lookup(b"host.test\0").unwrap_err().status(), NssStatus::Unavailable);
+    assert_eq!(lookup(b"host.test\0").unwrap_err().status(), NssStatus::NotFound);
+    assert_eq!(CALLS.load(Ordering::SeqCst), 4);
+
+    // A successful one closes it.
+    DOWN.store(false, Ordering::SeqCst);
+    std::thread::sleep(Quick::OPEN_FOR);
+    assert!(lookup(b"host.test\0").unwrap().is_some());
+    assert!(lookup(b"localhost\0").unwrap().is_some());
+    assert_eq!(CALLS.load(Ordering::SeqCst), 6);
+}
@@ -0,0 +1,548 @@
+//! Caching another service's answers in memory.
+
+use crate::diag;
+use crate::errors::{NssStatus, Result};
+use crate::ffi::{gid_t, uid_t};
+use crate::fork::ForkSafeMutex;
+use crate::interfaces::{AddressFamily, Entries, GroupEntry, GroupService, HostAddresses, HostEntry,
+                        HostEntryWithTtl, HostLimits, NameService, PasswdEntry, PasswdService, ShadowEntry,
+                        ShadowService};
+use crate::pin::pin_module;
+use crate::reentry::LookupGuard;
+#[cfg(feature = "tracing")]
+use crate::spans;
+use std::any::{type_name, TypeId};
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::ffi::{CStr, CString};
+use std::hash::{BuildHasher, Hasher};
+use std::marker::PhantomData;
+use std::net::IpAddr;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How long a `Cached` service keeps answers.
+pub trait CacheConfig: 'static {
+    /// How long to keep a host, user, or group, if the service doesn't say.
+    /// Hosts with a TTL (from `gethostbyname3_r` and the like) are kept for
+    /// that long instead, up to `MAX_TTL`.
+    const TTL: Duration = Duration::from_secs(60);
+
+    /// The longest to keep anything, whatever its TTL.
+    const MAX_TTL: Duration = Duration::from_secs(3600);
+
+    /// How long to remember that something wasn't found: `Ok(None)`, or an
+    /// error with `NssStatus::NotFound`, such as `NO_DATA`. Errors with a
+    /// TTL (see `Error::ttl`) are kept for that long instead, up to
+    /// `MAX_TTL`.
+    const NEGATIVE_TTL: Duration = Duration::from_secs(10);
+
+    /// How long after an answer expires it may still be given, if asking
+    /// the service again fails with `NssStatus::TryAgain` or
+    /// `NssStatus::Unavailable`. With the default, zero, the failure is
+    /// reported.
+    const STALE_IF_ERROR: Duration = Duration::ZERO;
+
+    /// How long before an answer expires a lookup that finds it should ask
+    /// the service again, on a thread of its own, so that a name looked up
+    /// often is never waited for. Each answer starts being refreshed at a
+    /// random time between this and half this before it expires, or half
+    /// its TTL, if that's shorter, so answers cached together aren't all
+    /// refreshed together. With the default, zero, answers are only asked
+    /// for again once they expire.
+    const REFRESH_AHEAD: Duration = Duration::ZERO;
+
+    /// The most answers to keep. When the cache is full, the answer that
+    /// expires soonest is dropped.
+    const MAX_ENTRIES: usize = 1024;
+}
+
+/// The usual settings: a minute for answers, ten seconds for names that
+/// don't exist, and nothing stale.
+pub struct CacheDefaults;
+
+impl CacheConfig for CacheDefaults {}
+
+/// The service `S`, with its answers kept in memory for a while, so that
+/// repeated lookups don't ask its server each time:
+///
+/// ```ignore
+/// nssglue_hosts!("consul", Cached<ConsulService>);
+/// ```
+///
+/// Every lookup of a host, user, or group is cached, along with its TTL,
+/// if `S` gives one, and answers that say nothing was found. Errors other
+/// than `NssStatus::NotFound` aren't cached, but `C::STALE_IF_ERROR` can
+/// say to answer with an expired entry instead. A host's TTL, as reported
+/// by `gethostbyname3_r`, counts down while it's cached.
+///
+/// With `C::REFRESH_AHEAD`, answers that are still being asked for are
+/// refreshed in the background before they expire. Failed refreshes are
+/// logged, and the old answer is kept until it expires.
+///
+/// Enumeration and shadow passwords go straight to `S`, as do `S`'s
+/// settings, such as `LOOKUP_TIMEOUT`. The cache is per process, shared by
+/// every thread, and starts out empty in the child after a `fork`.
+pub struct Cached<S, C = CacheDefaults>(PhantomData<(S, C)>);
+
+/// What was looked up.
+#[derive(Clone, Eq, Hash, PartialEq)]
+pub(crate) enum Key {
+    Name(CString, AddressFamily),
+    AllAddresses(CString),
+    Addr(IpAddr),
+    User(CString),
+    Uid(uid_t),
+    Group(CString),
+    Gid(gid_t),
+    Groups(CString, gid_t),
+}
+
+/// What was found.
+#[derive(Clone)]
+pub(crate) enum Value {
+    Host(HostEntryWithTtl<'static>),
+    Addresses(HostAddresses<'static>),
+    Passwd(PasswdEntry<'static>),
+    Group(GroupEntry<'static>),
+    Gids(Vec<gid_t>),
+}
+
+struct Slot {
+    answer: Result<Option<Value>>,
+    stored: Instant,
+    expires: Instant,
+    /// When a lookup that finds this should refresh it, if ever.
+    refresh: Option<Instant>,
+    /// Whether a refresh is under way.
+    refreshing: bool,
+}
+
+/// The answers for each `Cached` type.
+type Tables = HashMap<TypeId, HashMap<Key, Slot>>;
+
+/// Every cached answer.
+static CACHES: ForkSafeMutex<Tables> = ForkSafeMutex::new();
+
+/// `answer`, seen through `from`.
+pub(crate) fn unpack<T>(answer: &Result<Option<Value>>, from: impl Fn(&Value) -> Option<T>)
+    -> Option<Result<Option<T>>>
+{
+    match answer {
+        Ok(Some(value)) => from(value).map(|found| Ok(Some(found))),
+        Ok(None) => Some(Ok(None)),
+        Err(err) => Some(Err(err.clone())),
+    }
+}
+
+/// A random time between half of `ahead` and all of it.
+fn jitter(ahead: Duration) -> Duration {
+    let random = RandomState::new().build_hasher().finish();
+    ahead / 2 + (ahead / 2).mul_f64((random % 1024) as f64 / 1024.0)
+}
+
+/// A host's TTL, less the time it's been cached.
+fn age_ttl(ttl: Option<u32>, age: Duration) -> Option<u32> {
+    ttl.map(|ttl| ttl.saturating_sub(u32::try_from(age.as_secs()).unwrap_or(u32::MAX)))
+}
+
+impl<S: 'static, C: CacheConfig> Cached<S, C> {
+    /// Look in the cache for `key`, or call `fetch` and cache its answer.
+    /// `into` and `from` convert to and from a `Value`, `from` with the
+    /// time the value has been cached; `ttl` is how long `S` said to keep
+    /// it, in seconds, if it did. `fetch` is given `key`, and may be called
+    /// on another thread to refresh the answer.
+    fn lookup<T: Send + 'static>(
+        key: Key,
+        into: fn(T) -> Value,
+        from: fn(&Value, Duration) -> Option<T>,
+        ttl: fn(&T) -> Option<u32>,
+        fetch: fn(&Key) -> Result<Option<T>>,
+    ) -> Result<Option<T>> {
+        let now = Instant::now();
+        let hit = {
+            let mut caches = CACHES.lock();
+            let table = caches.entry(TypeId::of::<Self>()).or_default();
+            table.get_mut(&key).filter(|slot| now < slot.expires).and_then(|slot| {
+                let answer = unpack(&slot.answer, |value| from(value, now - slot.stored))?;
+                let refresh = !slot.refreshing && slot.refresh.is_some_and(|refresh| refresh <= now);
+                slot.refreshing |= refresh;
+                Some((answer, refresh))
+            })
+        };
+        if let Some((answer, refresh)) = hit {
+            #[cfg(feature = "tracing")]
+            tracing::debug!(service = type_name::<S>(), refresh, "cache hit");
+            if refresh {
+                Self::refresh(key, into, ttl, fetch);
+            }
+            return answer;
+        }
+
+        #[cfg(feature = "tracing")]
+        tracing::debug!(service = type_name::<S>(), "cache miss");
+        let answer = fetch(&key);
+        let keep = match Self::keep(&answer, ttl) {
+            Some(keep) => keep,
+            None => match answer {
+                Err(err) if !err.is_insufficient_buffer() => {
+                    // Maybe there's something stale to give instead.
+                    let caches = CACHES.lock();
+                    let stale = caches.get(&TypeId::of::<Self>())
+                        .and_then(|table| table.get(&key))
+                        .filter(|slot| now < slot.expires + C::STALE_IF_ERROR)
+                        .and_then(|slot| unpack(&slot.answer, |value| from(value, now - slot.stored)));
+                    return stale.unwrap_or(Err(err));
+                }
+                _ => return answer,
+            },
+        };
+        let (answer, cached) = match answer {
+            Ok(Some(found)) => {
+                let value = into(found);
+                let found = from(&value, Duration::ZERO);
+                (Ok(found), Ok(Some(value)))
+            }
+            Ok(None) => (Ok(None), Ok(None)),
+            Err(err) => (Err(err.clone()), Err(err)),
+        };
+        Self::store(key, cached, now, keep);
+        answer
+    }
+
+    /// How long to cache `answer`, or `None` if it shouldn't be.
+    fn keep<T>(answer: &Result<Option<T>>, ttl: fn(&T) -> Option<u32>) -> Option<Duration> {
+        match answer {
+            Ok(Some(found)) => Some(ttl(found).map_or(C::TTL, |secs| Duration::from_secs(secs.into())).min(C::MAX_TTL)),
+            Ok(None) => Some(C::NEGATIVE_TTL),
+            Err(err) if err.status() == NssStatus::NotFound => {
+                Some(err.ttl().map_or(C::NEGATIVE_TTL, |secs| Duration::from_secs(secs.into())).min(C::MAX_TTL))
+            }
+            Err(_) => None,
+        }
+    }
+
+    /// Call `fetch` on a thread of its own, and cache its answer for `key`.
+    fn refresh<T: Send + 'static>(
+        key: Key,
+        into: fn(T) -> Value,
+        ttl: fn(&T) -> Option<u32>,
+        fetch: fn(&Key) -> Result<Option<T>>,
+    ) {
+        // The thread may outlive every lookup.
+        pin_module();
+        let refreshed = key.clone();
+        #[cfg(feature = "tracing")]
+        let span = tracing::Span::current();
+        let spawned = thread::Builder::new()
+            .name("nss cache refresh".to_string())
+            .spawn(move || {
+                // The refresh is part of a lookup, as far as reentry goes.
+                let _guard = LookupGuard::enter();
+                #[cfg(feature = "tracing")]
+                let _span = spans::enter_thread(span);
+                #[cfg(feature = "tracing")]
+                tracing::debug!(service = type_name::<S>(), "refreshing a cached answer");
+                let now = Instant::now();
+                let answer = fetch(&refreshed);
+                match Self::keep(&answer, ttl) {
+                    Some(keep) => Self::store(refreshed, answer.map(|found| found.map(into)), now, keep),
+                    None => {
+                        if let Err(err) = answer {
+                            diag::log(format_args!("can't refresh an answer cached from {}: {:?}, errno {}",
+                                                   type_name::<S>(), err.status(), err.errno()));
+                        }
+                        Self::refresh_failed(&refreshed);
+                    }
+                }
+            });
+        if spawned.is_err() {
+            Self::refresh_failed(&key);
+        }
+    }
+
+    /// Let a later lookup try refreshing `key` again.
+    fn refresh_failed(key: &Key) {
+        let mut caches = CACHES.lock();
+        if let Some(slot) = caches.get_mut(&TypeId::of::<Self>()).and_then(|table| table.get_mut(key)) {
+            slot.refreshing = false;
+        }
+    }
+
+    fn store(key: Key, answer: Result<Option<Value>>, stored: Instant, keep: Duration) {
+        let expires = stored + keep;
+        let refresh = match answer {
+            Ok(Some(_)) if C::REFRESH_AHEAD > Duration::ZERO => Some(expires - jitter(C::REFRESH_AHEAD.min(keep / 2))),
+            _ => None,
+        };
+        let mut caches = CACHES.lock();
+        let table = caches.entry(TypeId::of::<Self>()).or_default();
+        if table.len() >= C::MAX_ENTRIES && !table.contains_key(&key) {
+            table.retain(|_, slot| stored < slot.expires + C::STALE_IF_ERROR);
+            if table.len() >= C::MAX_ENTRIES {
+                let soonest = table.iter().min_by_key(|(_, slot)| slot.expires).map(|(key, _)| key.clone());
+                if let Some(soonest) = soonest {
+                    table.remove(&soonest);
+                }
+            }
+        }
+        if C::MAX_ENTRIES > 0 {
+            table.insert(key, Slot { answer, stored, expires, refresh, refreshing: false });
+        }
+    }
+}
+
+impl<S: NameService + 'static, C: CacheConfig> NameService for Cached<S, C> {
+    const ALLOW_EMPTY_ADDRESS_LIST: bool = S::ALLOW_EMPTY_ADDRESS_LIST;
+    const LOOKUP_TIMEOUT: Option<Duration> = S::LOOKUP_TIMEOUT;
+    const LIMITS: HostLimits = S::LIMITS;
+    const VALIDATE_HOSTNAMES: bool = S::VALIDATE_HOSTNAMES;
+
+    fn gethostbyname2_r(name: &CStr, af: AddressFamily) -> Result<Option<HostEntry<'_>>> {
+        Ok(Self::gethostbyname3_r(name, af)?.map(|found| found.entry))
+    }
+
+    fn gethostbyaddr_r(addr: &IpAddr) -> Result<Option<HostEntry<'_>>> {
+        Ok(Self::gethostbyaddr2_r(addr)?.map(|found| found.entry))
+    }
+
+    fn gethostbyname3_r(name: &CStr, af: AddressFamily) -> Result<Option<HostEntryWithTtl<'_>>> {
+        Self::lookup(
+            Key::Name(name.to_owned(), af),
+            Value::Host,
+            |value, age| match value {
+                Value::Host(found) => Some(HostEntryWithTtl { ttl: age_ttl(found.ttl, age), ..found.clone() }),
+                _ => None,
+            },
+            |found| found.ttl,
+            |key| match key {
+                Key::Name(name, af) => Ok(S::gethostbyname3_r(name, *af)?.map(HostEntryWithTtl::into_owned)),
+                _ => unreachable!(),
+            },
+        )
+    }
+
+    fn gethostbyname4_r(name: &CStr) -> Result<Option<HostAddresses<'_>>> {
+        Self::lookup(
+            Key::AllAddresses(name.to_owned()),
+            Value::Addresses,
+            |value, age| match value {
+                Value::Addresses(found) => Some(HostAddresses { ttl: age_ttl(found.ttl, age), ..found.clone() }),
+                _ => None,
+            },
+            |found| found.ttl,
+            |key| match key {
+                Key::AllAddresses(name) => Ok(S::gethostbyname4_r(name)?.map(HostAddresses::into_owned)),
+                _ => unreachable!(),
+            },
+        )
+    }
+
+    fn gethostbyaddr2_r(addr: &IpAddr) -> Result<Option<HostEntryWithTtl<'_>>> {
+        Self::lookup(
+            Key::Addr(*addr),
+            Value::Host,
+            |value, age| match value {
+                Value::Host(found) => Some(HostEntryWithTtl { ttl: age_ttl(found.ttl, age), ..found.clone() }),
+                _ => None,
+            },
+            |found| found.ttl,
+            |key| match key {
+                Key::Addr(addr) => Ok(S::gethostbyaddr2_r(addr)?.map(HostEntryWithTtl::into_owned)),
+                _ => unreachable!(),
+            },
+        )
+    }
+
+    fn sethostent(stay_open: bool) -> Result<Entries<HostEntry<'static>>> {
+        S::sethostent(stay_open)
+    }
+
+    fn on_fork_child() {
+        CACHES.reset();
+        S::on_fork_child();
+    }
+}
+
+fn passwd(value: &Value, _age: Duration) -> Option<PasswdEntry<'static>> {
+    match value {
+        Value::Passwd(found) => Some(found.clone()),
+        _ => None,
+    }
+}
+
+fn group(value: &Value, _age: Duration) -> Option<GroupEntry<'static>> {
+    match value {
+        Value::Group(found) => Some(found.clone()),
+        _ => None,
+    }
+}
+
+impl<S: PasswdService + 'static, C: CacheConfig> PasswdService for Cached<S, C> {
+    const LOOKUP_TIMEOUT: Option<Duration> = S::LOOKUP_TIMEOUT;
+
+    fn getpwnam_r(name: &CStr) -> Result<Option<PasswdEntry<'_>>> {
+        Self::lookup(Key::User(name.to_owned()), Value::Passwd, passwd, |_| None, |key| match key {
+            Key::User(name) => Ok(S::getpwnam_r(name)?.map(PasswdEntry::into_owned)),
+            _ => unreachable!(),
+        })
+    }
+
+    fn getpwuid_r(uid: uid_t) -> Result<Option<PasswdEntry<'static>>> {
+        Self::lookup(Key::Uid(uid), Value::Passwd, passwd, |_| None, |key| match key {
+            Key::Uid(uid) => S::getpwuid_r(*uid),
+            _ => unreachable!(),
+        })
+    }
+
+    fn setpwent() -> Result<Entries<PasswdEntry<'static>>> {
+        S::setpwent()
+    }
+
+    fn on_fork_child() {
+        CACHES.reset();
+        S::on_fork_child();
+    }
+}
+
+impl<S: GroupService + 'static, C: CacheConfig> GroupService for Cached<S, C> {
+    const LOOKUP_TIMEOUT: Option<Duration> = S::LOOKUP_TIMEOUT;
+
+    fn getgrnam_r(name: &CStr) -> Result<Option<GroupEntry<'_>>> {
+        Self::lookup(Key::Group(name.to_owned()), Value::Group, group, |_| None, |key| match key {
+            Key::Group(name) => Ok(S::getgrnam_r(name)?.map(GroupEntry::into_owned)),
+            _ => unreachable!(),
+        })
+    }
+
+    fn getgrgid_r(gid: gid_t) -> Result<Option<GroupEntry<'static>>> {
+        Self::lookup(Key::Gid(gid), Value::Group, group, |_| None, |key| match key {
+            Key::Gid(gid) => S::getgrgid_r(*gid),
+            _ => unreachable!(),
+        })
+    }
+
+    fn setgrent() -> Result<Entries<GroupEntry<'static>>> {
+        S::setgrent()
+    }
+
+    fn initgroups_dyn(user: &CStr, group: gid_t) -> Result<Option<Vec<gid_t>>> {
+        Self::lookup(
+            Key::Groups(user.to_owned(), group),
+            Value::Gids,
+            |value, _age| match value {
+                Value::Gids(gids) => Some(gids.clone()),
+                _ => None,
+            },
+            |_| None,
+            |key| match key {
+                Key::Groups(user, group) => S::initgroups_dyn(user, *group),
+                _ => unreachable!(),
+            },
+        )
+    }
+
+    fn on_fork_child() {
+        CACHES.reset();
+        S::on_fork_child();
+    }
+}
+
+impl<S: ShadowService, C: CacheConfig> ShadowService for Cached<S, C> {
+    const LOOKUP_TIMEOUT: Option<Duration> = S::LOOKUP_TIMEOUT;
+
+    fn getspnam_r(name: &CStr) -> Result<Option<ShadowEntry<'_>>> {
+        S::getspnam_r(name)
+    }
+
+    fn setspent() -> Result<Entries<ShadowEntry<'static>>> {
+        S::setspent()
+    }
+
+    fn on_fork_child() {
+        S::on_fork_child();
+    }
+}
+
+#[test]
+fn test_cached() {
+    use crate::errors::{Error, HostError};
+    use crate::interfaces::HostAddressList;
+    use std::borrow::Cow;
+    use std::net::Ipv4Addr;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+    static CALLS: AtomicUsize = AtomicUsize::new(0);
+    static DOWN: AtomicBool = AtomicBool::new(false);
+    struct Counting;
+    impl NameService for Counting {
+        fn gethostbyname2_r(name: &CStr, _af: AddressFamily) -> Result<Option<HostEntry<'_>>> {
+            CALLS.fetch_add(1, Ordering::SeqCst);
+            if DOWN.load(Ordering::SeqCst) {
+                return Err(Error::with_host(NssStatus::TryAgain, libc::EAGAIN, HostError::TryAgain));
+            }
+            match name.to_bytes() {
+                b"nodata.test" => Err(Error::with_host(NssStatus::NotFound, 0, HostError::NoData)),
+                b"host.test" => Ok(Some(HostEntry {
+                    name: Cow::Borrowed(name),
+                    aliases: vec![],
+                    addr_list: HostAddressList::V4(vec![Ipv4Addr::new(192, 0, 2, 1)]),
+                })),
+                _ => Ok(None),
+            }
+        }
+
+        fn gethostbyaddr_r(_addr: &IpAddr) -> Result<Option<HostEntry<'_>>> {
+            Ok(None)
+        }
+    }
+    let calls = || CALLS.load(Ordering::SeqCst);
+    let name = |s: &'static [u8]| CStr::from_bytes_with_nul(s).unwrap();
+
+    // Answers, misses, and NotFound errors are all cached.
+    type Plain = Cached<Counting>;
+    for _ in 0..3 {
+        assert!(Plain::gethostbyname2_r(name(b"host.test\0"), AddressFamily::Ipv4).unwrap().is_some());
+        assert!(Plain::gethostbyname2_r(name(b"other.test\0"), AddressFamily::Ipv4).unwrap().is_none());
+        let err = Plain::gethostbyname2_r(name(b"nodata.test\0"), AddressFamily::Ipv4).unwrap_err();
+        assert_eq!(err.host_error(), Some(HostError::NoData));
+    }
+    assert_eq!(calls(), 3);
+    assert!(Plain::gethostbyname2_r(name(b"host.test\0"), AddressFamily::Ipv6).unwrap().is_some());
+    assert_eq!(calls(), 4);
+
+    // Expired answers are given again only if the service fails, and only
+    // with `STALE_IF_ERROR`.
+    struct Stale;
+    impl CacheConfig for Stale {
+        const TTL: Duration = Duration::ZERO;
+        const STALE_IF_ERROR: Duration = Duration::from_secs(60);
+        const MAX_ENTRIES: usize = 1;
+    }
+    assert!(Cached::<Counting, Stale>::gethostbyname2_r(name(b"host.test\0"), AddressFamily::Ipv4).unwrap().is_some());
+    assert!(Cached::<Counting, Stale>::gethostbyname2_r(name(b"host.test\0"), AddressFamily::Ipv4).unwrap().is_some());
+    assert_eq!(calls(), 6);
+    DOWN.store(true, Ordering::SeqCst);
+    assert!(Cached::<Counting, Stale>::gethostbyname2_r(name(b"host.test\0"), AddressFamily::Ipv4).unwrap().is_some());
+    let err = Plain::gethostbyname2_r(name(b"new.test\0"), AddressFamily::Ipv4).unwrap_err();
+    assert_eq!(err.status(), NssStatus::TryAgain);
+    assert_eq!(calls(), 8);
+
+    // With `REFRESH_AHEAD`, an answer found shortly before it expires is
+    // refreshed in the background, so the next lookup still finds it.
+    struct Refresh;
+    impl CacheConfig for Refresh {
+        const TTL: Duration = Duration::from_secs(2);
+        const REFRESH_AHEAD: Duration = Duration::from_secs(2);
+    }
+    type Refreshed = Cached<Counting, Refresh>;
+    DOWN.store(false, Ordering::SeqCst);
+    assert!(Refreshed::gethostbyname2_r(name(b"host.test\0"), AddressFamily::Ipv4).unwrap().is_some());
+    std::thread::sleep(Duration::from_millis(1600));
+    assert!(Refreshed::gethostbyname2_r(name(b"host.test\0"), AddressFamily::Ipv4).unwrap().is_some());
+    std::thread::sleep(Duration::from_millis(600));
+    assert_eq!(calls(), 10);
+    assert!(Refreshed::gethostbyname2_r(name(b"host.test\0"), AddressFamily::Ipv4).unwrap().is_some());
+    assert_eq!(calls(), 10);
+}
@@ -0,0 +1,261 @@
+//! Combining two services into one that asks the second when the first
+//! doesn't know.
+
+use crate::errors::{Error, NssStatus, Result};
+use crate::ffi::{gid_t, uid_t};
+use crate::interfaces::{AddressFamily, Entries, GroupEntry, GroupService, HostAddresses, HostEntry,
+                        HostEntryWithTtl, HostLimits, NameService, PasswdEntry, PasswdService, ShadowEntry,
+                        ShadowService};
+use std::ffi::CStr;
+use std::marker::PhantomData;
+use std::net::IpAddr;
+use std::time::Duration;
+
+/// When a `ChainService` asks its second service.
+pub trait ChainConfig: 'static {
+    /// Ask the second service when the first fails with
+    /// `NssStatus::TryAgain`, such as when its server is down.
+    const ON_TRY_AGAIN: bool = false;
+
+    /// Ask the second service when the first fails with
+    /// `NssStatus::Unavailable`, such as when it isn't set up.
+    const ON_UNAVAILABLE: bool = false;
+}
+
+/// The usual settings: fall back only when the first service doesn't find
+/// anything.
+pub struct ChainDefaults;
+
+impl ChainConfig for ChainDefaults {}
+
+/// The service `A`, falling back to `B` for anything `A` doesn't find, the
+/// way `hosts: a b` does in `nsswitch.conf`, but inside one module:
+///
+/// ```ignore
+/// type Hosts = ChainService<HostsFileService<Overrides>, ChainService<ConsulService, DnsService>>;
+///
+/// nssglue_hosts!("overrides", Hosts);
+/// ```
+///
+/// `B` is asked when `A` returns `Ok(None)` or fails with
+/// `NssStatus::NotFound`, which includes `NO_DATA`, and when `C` says so,
+/// when it fails with `NssStatus::TryAgain` or `NssStatus::Unavailable`.
+/// Otherwise `A`'s answer stands; if `B` is asked, its answer stands, even
+/// if it's an error. Running out of buffer is never a reason to fall back.
+/// Chains nest, so `B` can be another `ChainService`.
+///
+/// Enumeration lists `A`'s entries, then `B`'s; if either can't start, and
+/// `C` would fall back on its error, the other's entries are listed alone.
+/// `initgroups` merges the groups from both, as glibc does across services.
+///
+/// `LIMITS` and `ALLOW_EMPTY_ADDRESS_LIST` are `A`'s. `VALIDATE_HOSTNAMES` is
+/// on if it's on for either, and `LOOKUP_TIMEOUT` is the sum of the two, or
+/// `None` if either has none.
+pub struct ChainService<A, B, C = ChainDefaults>(PhantomData<(A, B, C)>);
+
+impl<A, B, C: ChainConfig> ChainService<A, B, C> {
+    /// True if `B` should be asked after `A` fails with `err`.
+    fn falls_back(err: &Error) -> bool {
+        match err.status() {
+            _ if err.is_insufficient_buffer() => false,
+            NssStatus::NotFound => true,
+            NssStatus::TryAgain => C::ON_TRY_AGAIN,
+            NssStatus::Unavailable => C::ON_UNAVAILABLE,
+            NssStatus::Success => false,
+        }
+    }
+
+    fn lookup<R>(first: Result<Option<R>>, second: impl FnOnce() -> Result<Option<R>>) -> Result<Option<R>> {
+        match first {
+            Ok(Some(found)) => Ok(Some(found)),
+            Ok(None) => second(),
+            Err(err) if Self::falls_back(&err) => second(),
+            Err(err) => Err(err),
+        }
+    }
+
+    fn entries<E: 'static>(first: Result<Entries<E>>, second: impl FnOnce() -> Result<Entries<E>>)
+        -> Result<Entries<E>>
+    {
+        match (first, second()) {
+            (Ok(first), Ok(second)) => Ok(Box::new(first.chain(second))),
+            (Ok(first), Err(err)) if Self::falls_back(&err) => Ok(first),
+            (Err(err), second) if Self::falls_back(&err) => second,
+            (Err(err), _) | (_, Err(err)) => Err(err),
+        }
+    }
+}
+
+const fn sum(a: Option<Duration>, b: Option<Duration>) -> Option<Duration> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a.saturating_add(b)),
+        _ => None,
+    }
+}
+
+impl<A: NameService, B: NameService, C: ChainConfig> NameService for ChainService<A, B, C> {
+    const ALLOW_EMPTY_ADDRESS_LIST: bool = A::ALLOW_EMPTY_ADDRESS_LIST;
+    const LOOKUP_TIMEOUT: Option<Duration> = sum(A::LOOKUP_TIMEOUT, B::LOOKUP_TIMEOUT);
+    const LIMITS: HostLimits = A::LIMITS;
+    const VALIDATE_HOSTNAMES: bool = A::VALIDATE_HOSTNAMES || B::VALIDATE_HOSTNAMES;
+
+    fn gethostbyname_r(name: &CStr) -> Result<Option<HostEntry<'_>>> {
+        Self::lookup(A::gethostbyname_r(name), || B::gethostbyname_r(name))
+    }
+
+    fn gethostbyname2_r(name: &CStr, af: AddressFamily) -> Result<Option<HostEntry<'_>>> {
+        Self::lookup(A::gethostbyname2_r(name, af), || B::gethostbyname2_r(name, af))
+    }
+
+    fn gethostbyaddr_r(addr: &IpAddr) -> Result<Option<HostEntry<'_>>> {
+        Self::lookup(A::gethostbyaddr_r(addr), || B::gethostbyaddr_r(addr))
+    }
+
+    fn gethostbyname3_r(name: &CStr, af: AddressFamily) -> Result<Option<HostEntryWithTtl<'_>>> {
+        Self::lookup(A::gethostbyname3_r(name, af), || B::gethostbyname3_r(name, af))
+    }
+
+    fn gethostbyname4_r(name: &CStr) -> Result<Option<HostAddresses<'_>>> {
+        Self::lookup(A::gethostbyname4_r(name), || B::gethostbyname4_r(name))
+    }
+
+    fn gethostbyaddr2_r(addr: &IpAddr) -> Result<Option<HostEntryWithTtl<'_>>> {
+        Self::lookup(A::gethostbyaddr2_r(addr), || B::gethostbyaddr2_r(addr))
+    }
+
+    fn sethostent(stay_open: bool) -> Result<Entries<HostEntry<'static>>> {
+        Self::entries(A::sethostent(stay_open), || B::sethostent(stay_open))
+    }
+
+    fn on_fork_child() {
+        A::on_fork_child();
+        B::on_fork_child();
+    }
+}
+
+impl<A: PasswdService, B: PasswdService, C: ChainConfig> PasswdService for ChainService<A, B, C> {
+    const LOOKUP_TIMEOUT: Option<Duration> = sum(A::LOOKUP_TIMEOUT, B::LOOKUP_TIMEOUT);
+
+    fn getpwnam_r(name: &CStr) -> Result<Option<PasswdEntry<'_>>> {
+        Self::lookup(A::getpwnam_r(name), || B::getpwnam_r(name))
+    }
+
+    fn getpwuid_r(uid: uid_t) -> Result<Option<PasswdEntry<'static>>> {
+        Self::lookup(A::getpwuid_r(uid), || B::getpwuid_r(uid))
+    }
+
+    fn setpwent() -> Result<Entries<PasswdEntry<'static>>> {
+        Self::entries(A::setpwent(), B::setpwent)
+    }
+
+    fn on_fork_child() {
+        A::on_fork_child();
+        B::on_fork_child();
+    }
+}
+
+impl<A: GroupService, B: GroupService, C: ChainConfig> GroupService for ChainService<A, B, C> {
+    const LOOKUP_TIMEOUT: Option<Duration> = sum(A::LOOKUP_TIMEOUT, B::LOOKUP_TIMEOUT);
+
+    fn getgrnam_r(name: &CStr) -> Result<Option<GroupEntry<'_>>> {
+        Self::lookup(A::getgrnam_r(name), || B::getgrnam_r(name))
+    }
+
+    fn getgrgid_r(gid: gid_t) -> Result<Option<GroupEntry<'static>>> {
+        Self::lookup(A::getgrgid_r(gid), || B::getgrgid_r(gid))
+    }
+
+    fn setgrent() -> Result<Entries<GroupEntry<'static>>> {
+        Self::entries(A::setgrent(), B::setgrent)
+    }
+
+    fn initgroups_dyn(user: &CStr, group: gid_t) -> Result<Option<Vec<gid_t>>> {
+        let first = match A::initgroups_dyn(user, group) {
+            Err(err) if Self::falls_back(&err) => None,
+            first => first?,
+        };
+        let second = match B::initgroups_dyn(user, group) {
+            Err(err) if first.is_some() && Self::falls_back(&err) => None,
+            second => second?,
+        };
+        Ok(match (first, second) {
+            (Some(mut gids), Some(more)) => {
+                for gid in more {
+                    if !gids.contains(&gid) {
+                        gids.push(gid);
+                    }
+                }
+                Some(gids)
+            }
+            (first, second) => first.or(second),
+        })
+    }
+
+    fn on_fork_child() {
+        A::on_fork_child();
+        B::on_fork_child();
+    }
+}
+
+impl<A: ShadowService, B: ShadowService, C: ChainConfig> ShadowService for ChainService<A, B, C> {
+    const LOOKUP_TIMEOUT: Option<Duration> = sum(A::LOOKUP_TIMEOUT, B::LOOKUP_TIMEOUT);
+
+    fn getspnam_r(name: &CStr) -> Result<Option<ShadowEntry<'_>>> {
+        Self::lookup(A::getspnam_r(name), || B::getspnam_r(name))
+    }
+
+    fn setspent() -> Result<Entries<ShadowEntry<'static>>> {
+        Self::entries(A::setspent(), B::setspent)
+    }
+
+    fn on_fork_child() {
+        A::on_fork_child();
+        B::on_fork_child();
+    }
+}
+
+#[test]
+fn test_chain_service() {
+    use crate::interfaces::HostAddressList;
+    use crate::testing::{gethostbyname2, AlwaysTryAgain, FixedHosts};
+    use std::borrow::Cow;
+    use std::net::Ipv4Addr;
+
+    struct Override;
+    impl NameService for Override {
+        fn gethostbyname2_r(name: &CStr, _af: AddressFamily) -> Result<Option<HostEntry<'_>>> {
+            Ok(if name.to_bytes() == b"host.test" {
+                let addr_list = HostAddressList::V4(vec![Ipv4Addr::new(10, 0, 0, 1)]);
+                Some(HostEntry { name: Cow::Borrowed(name), aliases: vec![], addr_list })
+            } else {
+                None
+            })
+        }
+
+        fn gethostbyaddr_r(_addr: &IpAddr) -> Result<Option<HostEntry<'_>>> {
+            Ok(None)
+        }
+    }
+    struct Retry;
+    impl ChainConfig for Retry {
+        const ON_TRY_AGAIN: bool = true;
+    }
+
+    type Chain = ChainService<Override, FixedHosts>;
+    let entry = gethostbyname2::<Chain>("host.test", AddressFamily::Ipv4).unwrap().unwrap();
+    assert!(matches!(entry.addr_list, HostAddressList::V4(ref addrs) if addrs[0] == Ipv4Addr::new(10, 0, 0, 1)));
+    assert!(gethostbyname2::<Chain>("v4only.test", AddressFamily::Ipv4).unwrap().is_some());
+    assert!(gethostbyname2::<Chain>("nowhere.test", AddressFamily::Ipv4).unwrap().is_none());
+    assert_eq!(Chain::sethostent(false).unwrap().count(), FixedHosts::sethostent(false).unwrap().count());
+
+    // `B` gets NO_DATA names, and `TryAgain` only if the config says so.
+    type Nested = ChainService<FixedHosts, ChainService<AlwaysTryAgain, Override, Retry>>;
+    assert!(gethostbyname2::<Nested>("v6only.test", AddressFamily::Ipv4).unwrap().is_none());
+    let err = gethostbyname2::<ChainService<AlwaysTryAgain, FixedHosts>>("host.test", AddressFamily::Ipv4)
+        .unwrap_err();
+    assert_eq!(err.status(), NssStatus::TryAgain);
+    let entry = gethostbyname2::<ChainService<AlwaysTryAgain, FixedHosts, Retry>>("host.test", AddressFamily::Ipv4)
+        .unwrap().unwrap();
+    assert_eq!(entry.aliases.len(), 1);
+    assert!(Nested::sethostent(false).is_ok());
+}
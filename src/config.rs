@@ -0,0 +1,56 @@
+//! Configuration from the environment, and when not to trust it.
+//!
+//! An NSS module is loaded into every program that looks up a name,
+//! including setuid and setgid programs. Those run with privileges that
+//! the user who started them doesn't have, but with an environment that
+//! user controls, so a module that reads its configuration from environment
+//! variables would let any user reconfigure it inside `su` or `passwd`.
+//! glibc handles this by having the kernel flag such programs as "secure"
+//! (`AT_SECURE`) and ignoring dangerous variables there; these helpers do
+//! the same for the module's own variables.
+
+use std::ffi::OsString;
+
+/// True if this process is running in secure mode: it's setuid or setgid,
+/// or it gained capabilities on exec, so its environment can't be trusted.
+///
+/// On Linux this is the kernel's `AT_SECURE` flag, the same test glibc uses
+/// for `secure_getenv`.
+pub fn is_secure_mode() -> bool {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    unsafe {
+        libc::getauxval(libc::AT_SECURE) != 0
+    }
+
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    unsafe {
+        libc::getuid() != libc::geteuid() || libc::getgid() != libc::getegid()
+    }
+}
+
+/// The value of the environment variable `name`, or `None` if it isn't set
+/// or the process is in secure mode (see `is_secure_mode`).
+///
+/// Use this, not `std::env::var_os`, to read a module's configuration, and
+/// fall back to the default configuration on `None`.
+pub fn env_var_os(name: &str) -> Option<OsString> {
+    if is_secure_mode() {
+        None
+    } else {
+        std::env::var_os(name)
+    }
+}
+
+/// Like `env_var_os`, but also `None` if the value isn't valid Unicode.
+pub fn env_var(name: &str) -> Option<String> {
+    env_var_os(name).and_then(|value| value.into_string().ok())
+}
+
+#[test]
+#[cfg_attr(miri, ignore = "calls getauxval")]
+fn test_env_var() {
+    // `cargo test` is never setuid.
+    assert!(!is_secure_mode());
+    assert_eq!(env_var("NSSWITCH_SERVICE_TEST_UNSET_VARIABLE"), None);
+    assert!(env_var_os("PATH").is_some());
+}
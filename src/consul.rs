@@ -0,0 +1,324 @@
+//! A ready-made `hosts` service that resolves Consul service and node
+//! names by asking the local Consul agent's HTTP API.
+
+use crate::config::env_var;
+use crate::diag;
+use crate::errors::{Error, HostError, NssStatus, Result};
+use crate::http;
+use crate::interfaces::{AddressFamily, HostAddressList, HostEntry, NameService};
+use libc::{EINVAL, EIO};
+use serde_json::Value;
+use std::borrow::Cow;
+use std::convert::TryFrom;
+use std::ffi::CStr;
+use std::io;
+use std::marker::PhantomData;
+use std::net::{IpAddr, SocketAddr, TcpStream};
+use std::time::Duration;
+
+/// Where a `ConsulService` finds the agent, and which names it answers
+/// for.
+pub trait ConsulConfig: 'static {
+    /// The agent's HTTP API address.
+    const AGENT: &'static str = "127.0.0.1:8500";
+
+    /// The domain Consul's names are under, as in its `domain` setting.
+    const DOMAIN: &'static str = "consul";
+
+    /// The ACL token to send, if any. If `None`, the one in
+    /// `$CONSUL_HTTP_TOKEN` is used, if that's set.
+    const TOKEN: Option<&'static str> = None;
+
+    /// Whether a service name only gives instances passing their health
+    /// checks, as Consul's own DNS interface does.
+    const ONLY_PASSING: bool = true;
+
+    /// How long to wait for the agent.
+    const TIMEOUT: Duration = Duration::from_secs(1);
+}
+
+/// The usual settings: the local agent, and the `consul` domain.
+pub struct ConsulDefaults;
+
+impl ConsulConfig for ConsulDefaults {}
+
+/// A `hosts` service that resolves the names Consul's DNS interface does,
+/// without forwarding `.consul` to it through dnsmasq or the like:
+///
+/// ```ignore
+/// nssglue_hosts!("consul", ConsulService);
+/// ```
+///
+/// `web.service.consul` has the addresses of every healthy instance of the
+/// service `web`, and `v2.web.service.consul` those with the tag `v2`.
+/// `db1.node.consul` is the node `db1`. Either can name a datacenter, as in
+/// `web.service.dc2.consul`. Instances whose address is a hostname rather
+/// than an IP address are left out. Other names under the domain aren't
+/// found, and names outside it are left to the next service. There are no
+/// reverse lookups.
+///
+/// `ConsulService::instances` gives the nodes and ports as well, like SRV
+/// records do. If no agent is running, lookups report
+/// `NssStatus::Unavailable` quietly.
+pub struct ConsulService<C = ConsulDefaults>(PhantomData<C>);
+
+/// One instance of a service.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ConsulInstance {
+    /// The node it runs on.
+    pub node: String,
+    pub addr: IpAddr,
+    pub port: u16,
+}
+
+/// What a name under the domain asks for.
+#[derive(Debug, PartialEq)]
+enum Query<'a> {
+    Service { name: &'a str, tag: Option<&'a str>, dc: Option<&'a str> },
+    Node { name: &'a str, dc: Option<&'a str> },
+}
+
+/// Whether `label` is safe to put in a URL as it is.
+fn is_plain_label(label: &str) -> bool {
+    !label.is_empty() && label.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+}
+
+/// What `name` asks for, if it's under `domain` and has one of Consul's
+/// forms.
+fn parse_query<'a>(name: &'a str, domain: &str) -> Option<Query<'a>> {
+    let name = name.trim_end_matches('.');
+    let domain = domain.trim_matches('.');
+    let dot = name.len().checked_sub(domain.len() + 1)?;
+    if name.as_bytes().get(dot) != Some(&b'.') || !name.get(dot + 1..)?.eq_ignore_ascii_case(domain) {
+        return None;
+    }
+    let mut labels: Vec<&str> = name[..dot].split('.').collect();
+    if !labels.iter().all(|label| is_plain_label(label)) {
+        return None;
+    }
+    let is_kind = |label: &str| label.eq_ignore_ascii_case("service") || label.eq_ignore_ascii_case("node");
+    let dc = match labels.len() {
+        n if n >= 3 && is_kind(labels[n - 2]) => labels.pop(),
+        _ => None,
+    };
+    let kind = labels.pop()?;
+    match (&labels[..], kind.to_ascii_lowercase().as_str()) {
+        ([name], "service") => Some(Query::Service { name, tag: None, dc }),
+        ([tag, name], "service") => Some(Query::Service { name, tag: Some(tag), dc }),
+        ([name], "node") => Some(Query::Node { name, dc }),
+        _ => None,
+    }
+}
+
+/// The instances in the agent's answer to `GET /v1/health/service/...`.
+fn parse_instances(json: &[u8]) -> std::result::Result<Vec<ConsulInstance>, String> {
+    let list: Value = serde_json::from_slice(json).map_err(|err| err.to_string())?;
+    let list = list.as_array().ok_or("expected a list of instances")?;
+    Ok(list.iter()
+        .filter_map(|entry| {
+            let node = &entry["Node"];
+            let service = &entry["Service"];
+            // An empty service address means the node's.
+            let addr = service["Address"].as_str().filter(|addr| !addr.is_empty())
+                .or_else(|| node["Address"].as_str())?;
+            Some(ConsulInstance {
+                node: node["Node"].as_str()?.to_string(),
+                addr: addr.parse().ok()?,
+                port: service["Port"].as_u64().and_then(|port| u16::try_from(port).ok())?,
+            })
+        })
+        .collect())
+}
+
+/// The address in the agent's answer to `GET /v1/catalog/node/...`, which
+/// is `null` if there's no such node.
+fn parse_node(json: &[u8]) -> std::result::Result<Option<IpAddr>, String> {
+    let node: Value = serde_json::from_slice(json).map_err(|err| err.to_string())?;
+    Ok(node["Node"]["Address"].as_str().and_then(|addr| addr.parse().ok()))
+}
+
+/// How to ask the agent.
+struct Agent<'a> {
+    addr: &'a str,
+    token: Option<String>,
+    only_passing: bool,
+    timeout: Duration,
+}
+
+impl Agent<'_> {
+    /// Ask the agent for `path`.
+    fn get(&self, path: &str) -> Result<Vec<u8>> {
+        let addr: SocketAddr = self.addr.parse().map_err(|_| {
+            diag::log(format_args!("not a Consul agent address: {:?}", self.addr));
+            Error::with_errno(NssStatus::Unavailable, EINVAL)
+        })?;
+        let headers: Vec<(&str, &str)> = self.token.iter().map(|token| ("X-Consul-Token", &token[..])).collect();
+        let response = TcpStream::connect_timeout(&addr, self.timeout)
+            .and_then(|stream| {
+                stream.set_read_timeout(Some(self.timeout))?;
+                stream.set_write_timeout(Some(self.timeout))?;
+                http::get(&stream, self.addr, path, &headers)
+            })
+            .map_err(|err| {
+                if err.kind() != io::ErrorKind::ConnectionRefused {
+                    diag::log(format_args!("can't get {} from the Consul agent at {}: {}", path, self.addr, err));
+                }
+                Error::with_errno(NssStatus::Unavailable, err.raw_os_error().unwrap_or(EIO))
+            })?;
+        if response.status != 200 {
+            diag::log(format_args!("can't get {} from the Consul agent at {}: HTTP status {}",
+                                   path, self.addr, response.status));
+            return Err(Error::with_errno(NssStatus::Unavailable, EIO));
+        }
+        Ok(response.body)
+    }
+
+    fn bad_answer(&self, path: &str, message: String) -> Error {
+        diag::log(format_args!("can't read {} from the Consul agent at {}: {}", path, self.addr, message));
+        Error::with_errno(NssStatus::Unavailable, EINVAL)
+    }
+
+    fn instances(&self, service: &str, tag: Option<&str>, dc: Option<&str>) -> Result<Vec<ConsulInstance>> {
+        let mut path = format!("/v1/health/service/{}", service);
+        let params = IntoIterator::into_iter([
+            if self.only_passing { Some("passing".to_string()) } else { None },
+            tag.map(|tag| format!("tag={}", tag)),
+            dc.map(|dc| format!("dc={}", dc)),
+        ]);
+        for (i, param) in params.flatten().enumerate() {
+            path += if i == 0 { "?" } else { "&" };
+            path += &param;
+        }
+        parse_instances(&self.get(&path)?).map_err(|message| self.bad_answer(&path, message))
+    }
+
+    /// The addresses `query` gives, without repeats.
+    fn addresses(&self, query: Query<'_>) -> Result<Vec<IpAddr>> {
+        let mut addrs = match query {
+            Query::Service { name, tag, dc } => {
+                self.instances(name, tag, dc)?.into_iter().map(|instance| instance.addr).collect()
+            }
+            Query::Node { name, dc } => {
+                let path = match dc {
+                    Some(dc) => format!("/v1/catalog/node/{}?dc={}", name, dc),
+                    None => format!("/v1/catalog/node/{}", name),
+                };
+                let found = parse_node(&self.get(&path)?).map_err(|message| self.bad_answer(&path, message))?;
+                found.into_iter().collect::<Vec<IpAddr>>()
+            }
+        };
+        let mut seen = Vec::new();
+        addrs.retain(|addr| {
+            let new = !seen.contains(addr);
+            seen.push(*addr);
+            new
+        });
+        Ok(addrs)
+    }
+}
+
+impl<C: ConsulConfig> ConsulService<C> {
+    fn agent() -> Agent<'static> {
+        Agent {
+            addr: C::AGENT,
+            token: C::TOKEN.map(str::to_string).or_else(|| env_var("CONSUL_HTTP_TOKEN")),
+            only_passing: C::ONLY_PASSING,
+            timeout: C::TIMEOUT,
+        }
+    }
+
+    /// The instances of `service`, with `tag` if given, in the local
+    /// datacenter. Only healthy ones if `C::ONLY_PASSING`.
+    pub fn instances(service: &str, tag: Option<&str>) -> Result<Vec<ConsulInstance>> {
+        if !is_plain_label(service) || !tag.is_none_or(is_plain_label) {
+            return Err(Error::with_errno(NssStatus::NotFound, EINVAL));
+        }
+        Self::agent().instances(service, tag, None)
+    }
+}
+
+impl<C: ConsulConfig> NameService for ConsulService<C> {
+    fn gethostbyname2_r(name: &CStr, af: AddressFamily) -> Result<Option<HostEntry<'_>>> {
+        let query = match name.to_str().ok().and_then(|name| parse_query(name, C::DOMAIN)) {
+            Some(query) => query,
+            None => return Ok(None),
+        };
+        let addrs = Self::agent().addresses(query)?;
+        if addrs.is_empty() {
+            return Ok(None);
+        }
+        let addr_list = match af {
+            AddressFamily::Ipv4 => HostAddressList::V4(addrs.iter().filter_map(|addr| match *addr {
+                IpAddr::V4(ip) => Some(ip),
+                IpAddr::V6(_) => None,
+            }).collect()),
+            AddressFamily::Ipv6 => HostAddressList::V6(addrs.iter().filter_map(|addr| match *addr {
+                IpAddr::V4(_) => None,
+                IpAddr::V6(ip) => Some(ip),
+            }).collect()),
+        };
+        if addr_list.is_empty() {
+            return Err(Error::with_host(NssStatus::NotFound, libc::ENOENT, HostError::NoData));
+        }
+        Ok(Some(HostEntry { name: Cow::Borrowed(name), aliases: vec![], addr_list }))
+    }
+
+    fn gethostbyaddr_r(_addr: &IpAddr) -> Result<Option<HostEntry<'_>>> {
+        Ok(None)
+    }
+}
+
+#[test]
+fn test_consul() {
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    assert_eq!(parse_query("web.service.consul.", "consul"),
+               Some(Query::Service { name: "web", tag: None, dc: None }));
+    assert_eq!(parse_query("v2.web.Service.dc2.consul", "consul"),
+               Some(Query::Service { name: "web", tag: Some("v2"), dc: Some("dc2") }));
+    assert_eq!(parse_query("db1.node.consul", "consul"), Some(Query::Node { name: "db1", dc: None }));
+    assert_eq!(parse_query("web.consul", "consul"), None);
+    assert_eq!(parse_query("web.service.example.com", "consul"), None);
+    assert_eq!(parse_query("we%2fb.service.consul", "consul"), None);
+
+    // An agent that knows two instances of "web", one of them using its
+    // node's address, and no nodes.
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let serving = thread::spawn(move || {
+        let mut requests = vec![];
+        for _ in 0..2 {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = [0_u8; 1024];
+            let len = stream.read(&mut request).unwrap();
+            let request = String::from_utf8(request[..len].to_vec()).unwrap();
+            let body = if request.starts_with("GET /v1/health/service/web?") {
+                r#"[{"Node": {"Node": "a", "Address": "10.0.0.1"}, "Service": {"Address": "", "Port": 8080}},
+                    {"Node": {"Node": "b", "Address": "10.0.0.2"}, "Service": {"Address": "fd00::2", "Port": 8080}},
+                    {"Node": {"Node": "c", "Address": "c.example"}, "Service": {"Address": "", "Port": 8080}}]"#
+            } else {
+                "null"
+            };
+            write!(stream, "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\n\r\n{}", body).unwrap();
+            requests.push(request);
+        }
+        requests
+    });
+
+    let agent_addr = format!("127.0.0.1:{}", port);
+    let agent = Agent { addr: &agent_addr, token: Some("t".to_string()), only_passing: true, timeout: Duration::from_secs(5) };
+    let web = agent.addresses(parse_query("v2.web.service.consul", "consul").unwrap()).unwrap();
+    assert_eq!(web, ["10.0.0.1".parse::<IpAddr>().unwrap(), "fd00::2".parse().unwrap()]);
+    assert!(agent.addresses(Query::Node { name: "db1", dc: None }).unwrap().is_empty());
+    let requests = serving.join().unwrap();
+    assert!(requests[0].starts_with("GET /v1/health/service/web?passing&tag=v2 HTTP/1.0\r\n"));
+    assert!(requests[0].contains("X-Consul-Token: t\r\n"));
+    assert!(requests[1].starts_with("GET /v1/catalog/node/db1 HTTP/1.0\r\n"));
+
+    let instances = parse_instances(br#"[{"Node": {"Node": "a", "Address": "10.0.0.1"},
+                                          "Service": {"Address": "10.0.0.9", "Port": 443}}]"#).unwrap();
+    assert_eq!(instances, [ConsulInstance { node: "a".to_string(), addr: "10.0.0.9".parse().unwrap(), port: 443 }]);
+    assert!(parse_instances(b"{}").is_err());
+}
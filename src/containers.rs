@@ -0,0 +1,243 @@
+//! A ready-made `hosts` service that resolves the names of running Docker
+//! or Podman containers.
+
+use crate::diag;
+use crate::errors::{Error, NssStatus, Result};
+use crate::http;
+use crate::interfaces::{AddressFamily, Entries, HostAddressList, HostEntry, NameService};
+use libc::{EINVAL, EIO};
+use serde_json::Value;
+use std::borrow::Cow;
+use std::ffi::{CStr, CString};
+use std::io;
+use std::marker::PhantomData;
+use std::net::IpAddr;
+use std::os::unix::net::UnixStream;
+use std::time::Duration;
+
+/// Where a `ContainerService` finds the container engine, and which names
+/// it answers for.
+pub trait ContainerConfig: 'static {
+    /// The engine's API sockets, in order. The first one that accepts a
+    /// connection is asked. Podman's is compatible with Docker's.
+    const SOCKETS: &'static [&'static str] = &["/var/run/docker.sock", "/run/podman/podman.sock"];
+
+    /// If set, only names under this domain are looked up, with the domain
+    /// taken off: with `Some("docker")`, `web.docker` is the container
+    /// `web`. Otherwise every name is looked up as it is.
+    const DOMAIN: Option<&'static str> = None;
+
+    /// How long to wait for the engine to answer.
+    const TIMEOUT: Duration = Duration::from_secs(1);
+}
+
+/// The usual settings: Docker's socket, then Podman's, and every name.
+pub struct ContainerDefaults;
+
+impl ContainerConfig for ContainerDefaults {}
+
+/// A `hosts` service that resolves the names of running containers to
+/// their addresses on the engine's networks, so the host can reach
+/// `db` or `myproject-web-1` by name the way other containers can:
+///
+/// ```ignore
+/// struct Local;
+///
+/// impl ContainerConfig for Local {
+///     const DOMAIN: Option<&'static str> = Some("docker");
+/// }
+///
+/// nssglue_hosts!("containers", ContainerService<Local>);
+/// ```
+///
+/// A container answers to its names, its Compose service name, and the
+/// first 12 digits of its ID. If several containers answer to a name, as
+/// when a Compose service is scaled up, the entry has all their
+/// addresses. Reverse lookups give the container's name, with its other
+/// names as aliases, and `sethostent` lists every container.
+///
+/// Every lookup asks the engine for the list of containers (its `GET
+/// /containers/json`). If no engine is running, lookups report
+/// `NssStatus::Unavailable` quietly; other failures, such as not being
+/// allowed to connect to the socket, also go to the diagnostic sink.
+pub struct ContainerService<C = ContainerDefaults>(PhantomData<C>);
+
+/// A running container.
+#[derive(Debug, PartialEq)]
+struct Container {
+    /// The main name first.
+    names: Vec<String>,
+    addrs: Vec<IpAddr>,
+}
+
+impl Container {
+    fn has_name(&self, name: &str) -> bool {
+        self.names.iter().any(|n| n.eq_ignore_ascii_case(name))
+    }
+
+    fn entry(&self, af: AddressFamily, keep: impl Fn(&IpAddr) -> bool) -> Option<HostEntry<'static>> {
+        let addrs = self.addrs.iter().filter(|addr| keep(addr));
+        let addr_list = match af {
+            AddressFamily::Ipv4 => HostAddressList::V4(addrs.filter_map(|addr| match *addr {
+                IpAddr::V4(ip) => Some(ip),
+                IpAddr::V6(_) => None,
+            }).collect()),
+            AddressFamily::Ipv6 => HostAddressList::V6(addrs.filter_map(|addr| match *addr {
+                IpAddr::V4(_) => None,
+                IpAddr::V6(ip) => Some(ip),
+            }).collect()),
+        };
+        let mut names = self.names.iter().filter_map(|name| CString::new(name.as_bytes()).ok()).map(Cow::Owned);
+        if addr_list.is_empty() {
+            return None;
+        }
+        Some(HostEntry { name: names.next()?, aliases: names.collect(), addr_list })
+    }
+}
+
+/// Read the containers out of the engine's answer to `GET
+/// /containers/json`.
+fn parse_containers(json: &[u8]) -> std::result::Result<Vec<Container>, String> {
+    let list: Value = serde_json::from_slice(json).map_err(|err| err.to_string())?;
+    let list = list.as_array().ok_or("expected a list of containers")?;
+    Ok(list.iter().map(|container| {
+        let mut names: Vec<String> = container["Names"].as_array().into_iter().flatten()
+            .filter_map(Value::as_str)
+            .map(|name| name.trim_start_matches('/').to_string())
+            .collect();
+        if let Some(service) = container["Labels"]["com.docker.compose.service"].as_str() {
+            names.push(service.to_string());
+        }
+        if let Some(id) = container["Id"].as_str().and_then(|id| id.get(..12)) {
+            names.push(id.to_string());
+        }
+        let networks = container["NetworkSettings"]["Networks"].as_object();
+        let addrs = networks.into_iter().flat_map(|networks| networks.values())
+            .flat_map(|network| IntoIterator::into_iter([&network["IPAddress"], &network["GlobalIPv6Address"]]))
+            .filter_map(|addr| addr.as_str()?.parse().ok())
+            .collect();
+        Container { names, addrs }
+    }).collect())
+}
+
+impl<C: ContainerConfig> ContainerService<C> {
+    /// The name to look for, or `None` if `name` isn't in `C::DOMAIN`.
+    fn container_name(name: &CStr) -> Option<&str> {
+        let name = name.to_str().ok()?.trim_end_matches('.');
+        match C::DOMAIN {
+            None => Some(name),
+            Some(domain) => {
+                let domain = domain.trim_end_matches('.');
+                let dot = name.len().checked_sub(domain.len() + 1)?;
+                if dot == 0 || name.as_bytes()[dot] != b'.' || !name.get(dot + 1..)?.eq_ignore_ascii_case(domain) {
+                    return None;
+                }
+                name.get(..dot)
+            }
+        }
+    }
+
+    fn containers() -> Result<Vec<Container>> {
+        let mut last_err = io::Error::from(io::ErrorKind::NotFound);
+        for &path in C::SOCKETS {
+            let stream = match UnixStream::connect(path) {
+                Ok(stream) => stream,
+                Err(err) => {
+                    if err.kind() != io::ErrorKind::NotFound && err.kind() != io::ErrorKind::ConnectionRefused {
+                        diag::log(format_args!("can't connect to {}: {}", path, err));
+                    }
+                    last_err = err;
+                    continue;
+                }
+            };
+            let response = stream.set_read_timeout(Some(C::TIMEOUT))
+                .and_then(|()| stream.set_write_timeout(Some(C::TIMEOUT)))
+                .and_then(|()| http::get(&stream, "localhost", "/containers/json", &[]))
+                .map_err(|err| {
+                    diag::log(format_args!("can't list containers from {}: {}", path, err));
+                    Error::with_errno(NssStatus::Unavailable, err.raw_os_error().unwrap_or(EIO))
+                })?;
+            if response.status != 200 {
+                diag::log(format_args!("can't list containers from {}: HTTP status {}", path, response.status));
+                return Err(Error::with_errno(NssStatus::Unavailable, EIO));
+            }
+            return parse_containers(&response.body).map_err(|message| {
+                diag::log(format_args!("can't read the container list from {}: {}", path, message));
+                Error::with_errno(NssStatus::Unavailable, EINVAL)
+            });
+        }
+        Err(Error::with_errno(NssStatus::Unavailable, last_err.raw_os_error().unwrap_or(EIO)))
+    }
+}
+
+impl<C: ContainerConfig> NameService for ContainerService<C> {
+    fn gethostbyname2_r(name: &CStr, af: AddressFamily) -> Result<Option<HostEntry<'_>>> {
+        let wanted = match Self::container_name(name) {
+            Some(wanted) => wanted,
+            None => return Ok(None),
+        };
+        let containers = Self::containers()?;
+        let matching = Container {
+            names: vec![wanted.to_string()],
+            addrs: containers.iter()
+                .filter(|container| container.has_name(wanted))
+                .flat_map(|container| container.addrs.iter().copied())
+                .collect(),
+        };
+        Ok(matching.entry(af, |_| true).map(|entry| HostEntry { name: Cow::Borrowed(name), ..entry }))
+    }
+
+    fn gethostbyaddr_r(addr: &IpAddr) -> Result<Option<HostEntry<'_>>> {
+        let af = if addr.is_ipv4() { AddressFamily::Ipv4 } else { AddressFamily::Ipv6 };
+        Ok(Self::containers()?.iter().find_map(|container| container.entry(af, |a| a == addr)))
+    }
+
+    fn sethostent(_stay_open: bool) -> Result<Entries<HostEntry<'static>>> {
+        let entries: Vec<_> = Self::containers()?.iter()
+            .flat_map(|container| {
+                IntoIterator::into_iter([AddressFamily::Ipv4, AddressFamily::Ipv6])
+                    .filter_map(move |af| container.entry(af, |_| true))
+            })
+            .map(Ok)
+            .collect();
+        Ok(Box::new(entries.into_iter()))
+    }
+}
+
+#[test]
+fn test_containers() {
+    let json = br#"[
+        {"Id": "3f4e5d6c7b8a9f0e1d2c", "Names": ["/myproject-web-1"],
+         "Labels": {"com.docker.compose.service": "web"},
+         "NetworkSettings": {"Networks": {"myproject_default":
+             {"IPAddress": "172.18.0.3", "GlobalIPv6Address": "fd00:18::3"}}}},
+        {"Id": "0a1b2c3d4e5f60718293", "Names": ["/myproject-web-2"],
+         "Labels": {"com.docker.compose.service": "web"},
+         "NetworkSettings": {"Networks": {"myproject_default": {"IPAddress": "172.18.0.4", "GlobalIPv6Address": ""}}}},
+        {"Id": "ffff", "Names": ["/no-network"], "NetworkSettings": {"Networks": {}}}
+    ]"#;
+    let containers = parse_containers(json).unwrap();
+    assert_eq!(containers[0], Container {
+        names: vec!["myproject-web-1".to_string(), "web".to_string(), "3f4e5d6c7b8a".to_string()],
+        addrs: vec!["172.18.0.3".parse().unwrap(), "fd00:18::3".parse().unwrap()],
+    });
+    assert_eq!(containers[1].addrs.len(), 1);
+    assert!(containers[1].has_name("WEB") && containers[2].names == ["no-network"]);
+    assert!(containers[2].entry(AddressFamily::Ipv4, |_| true).is_none());
+
+    let entry = containers[0].entry(AddressFamily::Ipv6, |_| true).unwrap();
+    assert_eq!((entry.name.to_str(), entry.aliases.len()), (Ok("myproject-web-1"), 2));
+    assert!(parse_containers(br#"{"message": "permission denied"}"#).is_err());
+
+    struct Docker;
+    impl ContainerConfig for Docker {
+        const SOCKETS: &'static [&'static str] = &["/nonexistent/docker.sock"];
+        const DOMAIN: Option<&'static str> = Some("docker.");
+    }
+    let name = |s: &'static [u8]| CStr::from_bytes_with_nul(s).unwrap();
+    assert_eq!(ContainerService::<Docker>::container_name(name(b"web.Docker.\0")), Some("web"));
+    assert_eq!(ContainerService::<Docker>::container_name(name(b"docker\0")), None);
+    assert_eq!(ContainerService::<Docker>::container_name(name(b"web.example\0")), None);
+    let err = ContainerService::<Docker>::gethostbyname2_r(name(b"web.docker\0"), AddressFamily::Ipv4).unwrap_err();
+    assert_eq!((err.status(), err.errno()), (NssStatus::Unavailable, libc::ENOENT));
+}
@@ -0,0 +1,205 @@
+//! Enumeration state for the `setXXent`, `getXXent_r`, and `endXXent`
+//! functions.
+//!
+//! Unlike lookups, enumeration is stateful: each `getXXent_r` call returns
+//! the next entry. glibc serializes these calls with one lock per database,
+//! and this module keeps one cursor per database to match. The cursor also
+//! remembers an entry that didn't fit in the caller's buffer, because glibc
+//! retries with a bigger buffer and expects to get the same entry again.
+//!
+//! # Threads
+//!
+//! The cursor belongs to the process, not the thread, as in glibc: a
+//! program that calls `getpwent` from two threads gets the entries shared
+//! out between them. Each cursor is behind its own mutex, held for the whole
+//! of each `set`, `next`, and `end`, so even when glibc's lock is bypassed
+//! (say, by calling the `_nss_*` functions directly):
+//!
+//! *   every entry is handed out exactly once per enumeration, and never to
+//!     two threads;
+//! *   an entry that didn't fit is handed to the next caller, whichever
+//!     thread that is;
+//! *   `set` and `end` take effect between two `next` calls, never during
+//!     one.
+//!
+//! The service's iterator is only ever used by one thread at a time, which
+//! is why `Entries` only needs to be `Send`.
+//!
+//! # Fork
+//!
+//! The lock is held while the service's iterator runs, which may mean
+//! waiting on a server. A fork meanwhile would leave it locked forever in
+//! the child, so the child forgets every cursor, and its first `getXXent_r`
+//! starts over, as if it had never called `setXXent`.
+
+use crate::errors::Result;
+use crate::fork::{register_fork_handlers, ForkSafeMutex};
+use crate::interfaces::{Entries, GroupEntry, HostEntry, PasswdEntry, ShadowEntry};
+use std::sync::MutexGuard;
+
+pub(crate) struct Cursor<E> {
+    entries: Entries<E>,
+
+    /// An entry that was taken from `entries` but couldn't be written to the
+    /// caller's buffer.
+    pending: Option<E>,
+}
+
+pub(crate) type CursorSlot<E> = ForkSafeMutex<Option<Cursor<E>>>;
+
+pub(crate) static HOSTS: CursorSlot<HostEntry<'static>> = ForkSafeMutex::new();
+pub(crate) static PASSWD: CursorSlot<PasswdEntry<'static>> = ForkSafeMutex::new();
+pub(crate) static GROUP: CursorSlot<GroupEntry<'static>> = ForkSafeMutex::new();
+pub(crate) static SHADOW: CursorSlot<ShadowEntry<'static>> = ForkSafeMutex::new();
+
+fn lock<E>(slot: &CursorSlot<E>) -> MutexGuard<'_, Option<Cursor<E>>> {
+    register_fork_handlers();
+    // A panic while enumerating leaves the cursor in a usable state, so
+    // there's no reason to refuse to carry on.
+    slot.lock()
+}
+
+/// Forget every cursor, in the child after a fork.
+pub(crate) fn reset_all() {
+    HOSTS.reset();
+    PASSWD.reset();
+    GROUP.reset();
+    SHADOW.reset();
+}
+
+/// Start (or restart) enumerating a database.
+pub(crate) fn set<E>(slot: &CursorSlot<E>, entries: Entries<E>) {
+    *lock(slot) = Some(Cursor { entries, pending: None });
+}
+
+/// Stop enumerating a database, dropping the service's iterator.
+pub(crate) fn end<E>(slot: &CursorSlot<E>) {
+    *lock(slot) = None;
+}
+
+/// Take the next entry from the cursor and `write` it. If the program never
+/// called `setXXent`, start enumerating using `start`, as glibc does.
+///
+/// Returns `Ok(false)` at the end of the entries. If `write` fails because
+/// the caller's buffer is too small, the entry is kept for the next call.
+pub(crate) fn next<E, S, W>(slot: &CursorSlot<E>, start: S, write: W) -> Result<bool>
+where
+    S: FnOnce() -> Result<Entries<E>>,
+    W: FnOnce(&E) -> Result<()>,
+{
+    let mut guard = lock(slot);
+    if guard.is_none() {
+        *guard = Some(Cursor { entries: start()?, pending: None });
+    }
+    let cursor = guard.as_mut().unwrap();
+
+    let entry = match cursor.pending.take() {
+        Some(entry) => entry,
+        None => match cursor.entries.next() {
+            None => return Ok(false),
+            Some(entry) => entry?,
+        },
+    };
+    match write(&entry) {
+        Err(err) => {
+            if err.is_insufficient_buffer() {
+                cursor.pending = Some(entry);
+            }
+            Err(err)
+        }
+        Ok(()) => Ok(true),
+    }
+}
+
+#[test]
+fn test_pending_entry_is_retried() {
+    use crate::errors::Error;
+
+    let slot: CursorSlot<u32> = ForkSafeMutex::new();
+    set(&slot, Box::new(vec![Ok(1), Ok(2)].into_iter()));
+    let no_start = || -> Result<Entries<u32>> { panic!("already started") };
+
+    assert!(next(&slot, no_start, |_| Err(Error::insufficient_buffer())).is_err());
+    let mut seen = vec![];
+    while next(&slot, no_start, |&n| { seen.push(n); Ok(()) }).unwrap() {}
+    assert_eq!(seen, vec![1, 2]);
+
+    end(&slot);
+    assert!(!next(&slot, || Ok(Box::new(None.into_iter())), |_| Ok(())).unwrap());
+}
+
+#[test]
+fn test_concurrent_next() {
+    use crate::errors::Error;
+    use std::sync::Arc;
+    use std::thread;
+
+    let slot: Arc<CursorSlot<u32>> = Arc::new(ForkSafeMutex::new());
+    set(&slot, Box::new((0..10_000).map(Ok)));
+
+    let threads: Vec<_> = (0..8).map(|t| {
+        let slot = Arc::clone(&slot);
+        thread::spawn(move || {
+            let mut seen = vec![];
+            let mut calls = 0_u32;
+            loop {
+                calls += 1;
+                // Every few calls, the caller's buffer is too small.
+                let result = next(&slot, || panic!("already started"), |&n| {
+                    if (calls + t).is_multiple_of(7) {
+                        Err(Error::insufficient_buffer())
+                    } else {
+                        seen.push(n);
+                        Ok(())
+                    }
+                });
+                match result {
+                    Ok(true) | Err(_) => {}
+                    Ok(false) => return seen,
+                }
+            }
+        })
+    }).collect();
+
+    let mut all: Vec<u32> = threads.into_iter().flat_map(|t| t.join().unwrap()).collect();
+    all.sort_unstable();
+    assert_eq!(all, (0..10_000).collect::<Vec<u32>>());
+}
+
+#[test]
+fn test_concurrent_set_and_end() {
+    use std::sync::Arc;
+    use std::thread;
+
+    // Restarting and ending enumeration while other threads are in the
+    // middle of it hands them entries from one enumeration or another,
+    // never anything else.
+    let slot: Arc<CursorSlot<u32>> = Arc::new(ForkSafeMutex::new());
+    let restarter = {
+        let slot = Arc::clone(&slot);
+        thread::spawn(move || {
+            for i in 0..1000 {
+                if i % 2 == 0 {
+                    set(&slot, Box::new((0..100).map(Ok)));
+                } else {
+                    end(&slot);
+                }
+            }
+        })
+    };
+    let readers: Vec<_> = (0..4).map(|_| {
+        let slot = Arc::clone(&slot);
+        thread::spawn(move || {
+            for _ in 0..5000 {
+                let _ = next(&slot, || Ok(Box::new((0..100).map(Ok))), |&n| {
+                    assert!(n < 100);
+                    Ok(())
+                });
+            }
+        })
+    }).collect();
+    restarter.join().unwrap();
+    for reader in readers {
+        reader.join().unwrap();
+    }
+}
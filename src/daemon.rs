@@ -0,0 +1,909 @@
+//! Splitting a module in two: a thin client, loaded into every process,
+//! that passes each lookup over a Unix socket to a daemon, which runs the
+//! real services.
+//!
+//! Only the daemon holds credentials, caches, and connections to
+//! directories, and upgrading it upgrades every process at once. The
+//! protocol is private to this crate, so the client and daemon should be
+//! built from the same version.
+//!
+//! Each message, either way, is a 4-byte big-endian length and then that
+//! many bytes. A request is an operation code and its arguments. A reply
+//! is a status, `errno`, and `h_errno`, as three 4-byte integers, then, on
+//! success, a count and that many records. Strings and lists carry their
+//! lengths; integers are big-endian.
+
+use crate::diag;
+use crate::errors::{Error, HostError, NssStatus, Result, NETDB_INTERNAL};
+use crate::ffi::{c_long, c_ulong};
+use crate::interfaces::{AddressFamily, Entries, GroupEntry, GroupService, HostAddressList, HostAddresses, HostEntry};
+use crate::interfaces::{HostEntryWithTtl, NameService, PasswdEntry, PasswdService, ShadowEntry, ShadowService};
+use libc::{gid_t, uid_t, EACCES, EAGAIN, EINVAL, EIO, ERANGE};
+use std::borrow::Cow;
+use std::convert::TryFrom;
+use std::ffi::{CStr, CString};
+use std::io::{self, Read, Write};
+use std::marker::PhantomData;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+const HOST_BY_NAME: u8 = 1;
+const HOST_BY_ADDR: u8 = 2;
+const HOST_LIST: u8 = 3;
+const PASSWD_BY_NAME: u8 = 4;
+const PASSWD_BY_UID: u8 = 5;
+const PASSWD_LIST: u8 = 6;
+const GROUP_BY_NAME: u8 = 7;
+const GROUP_BY_GID: u8 = 8;
+const GROUP_LIST: u8 = 9;
+const INITGROUPS: u8 = 10;
+const SHADOW_BY_NAME: u8 = 11;
+const SHADOW_LIST: u8 = 12;
+
+/// The family byte of `HOST_BY_NAME` that asks for both, as
+/// `gethostbyname4_r` does.
+const BOTH_FAMILIES: u8 = 0;
+
+/// The longest message either side accepts.
+const MAX_MESSAGE: usize = 16 << 20;
+
+/// The most connections a `DaemonServer` serves at once. Any more are
+/// closed as soon as they're accepted, and those clients report
+/// `NssStatus::Unavailable`.
+const MAX_CONNECTIONS: usize = 256;
+
+/// How long a `DaemonServer` waits on a client that has stopped sending
+/// (or reading) before hanging up on it.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Where a `DaemonService` finds the daemon.
+pub trait DaemonConfig: 'static {
+    /// The daemon's socket, as passed to `DaemonServer::serve`.
+    const SOCKET: &'static str = "/run/nss-daemon.sock";
+
+    /// How long to wait for the daemon.
+    const TIMEOUT: Duration = Duration::from_secs(5);
+}
+
+/// The usual settings: a daemon on `/run/nss-daemon.sock`.
+pub struct DaemonDefaults;
+
+impl DaemonConfig for DaemonDefaults {}
+
+/// The thin half of a module split in two: a service for every database
+/// that asks a daemon running a `DaemonServer`:
+///
+/// ```ignore
+/// nss_module!("corp", DaemonService);
+/// ```
+///
+/// and, in the daemon:
+///
+/// ```ignore
+/// let listener = UnixListener::bind("/run/nss-daemon.sock")?;
+/// DaemonServer::new()
+///     .hosts::<CorpHosts>()
+///     .passwd::<LdapService<Corp>>()
+///     .group::<LdapService<Corp>>()
+///     .serve(&listener)?;
+/// ```
+///
+/// Each lookup is one request on a new connection, and gets whatever the
+/// daemon's service said, errors included. Enumeration reads every entry
+/// in `setXXent`. If the daemon isn't running, lookups report
+/// `NssStatus::Unavailable` without logging anything, so the next service
+/// in `nsswitch.conf` gets a turn.
+pub struct DaemonService<C = DaemonDefaults>(PhantomData<C>);
+
+/// A daemon's services, by database, for answering `DaemonService`s.
+///
+/// A database without a service has nothing in it: lookups find nothing.
+/// Shadow entries go only to clients running as root, as
+/// `/etc/shadow` does; others get `NssStatus::Unavailable` with `EACCES`.
+#[derive(Clone, Copy, Default)]
+pub struct DaemonServer {
+    hosts: Option<Handler>,
+    passwd: Option<Handler>,
+    group: Option<Handler>,
+    shadow: Option<Handler>,
+}
+
+/// Answer a request for one database: its operation code and arguments.
+/// Returns the records found, or `None` for an operation the database
+/// doesn't have.
+type Handler = fn(u8, &[u8]) -> Option<Result<Vec<Vec<u8>>>>;
+
+/// Building messages.
+#[derive(Default)]
+struct Writer(Vec<u8>);
+
+impl Writer {
+    fn u32(&mut self, n: u32) -> &mut Self {
+        self.0.extend_from_slice(&n.to_be_bytes());
+        self
+    }
+
+    fn i64(&mut self, n: i64) -> &mut Self {
+        self.0.extend_from_slice(&n.to_be_bytes());
+        self
+    }
+
+    // `c_long` is 32 bits on some targets.
+    #[allow(clippy::useless_conversion)]
+    fn long(&mut self, n: c_long) -> &mut Self {
+        self.i64(i64::from(n))
+    }
+
+    fn bytes(&mut self, bytes: &[u8]) -> &mut Self {
+        self.u32(bytes.len() as u32);
+        self.0.extend_from_slice(bytes);
+        self
+    }
+
+    fn list<T: AsRef<[u8]>>(&mut self, items: &[T]) -> &mut Self {
+        self.u32(items.len() as u32);
+        for item in items {
+            self.bytes(item.as_ref());
+        }
+        self
+    }
+}
+
+/// Reading them. Every method returns `None` if the message is too short.
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        if self.0.len() < len {
+            return None;
+        }
+        let (taken, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Some(taken)
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        Some(u32::from_be_bytes(<[u8; 4]>::try_from(self.take(4)?).unwrap()))
+    }
+
+    fn i64(&mut self) -> Option<i64> {
+        Some(i64::from_be_bytes(<[u8; 8]>::try_from(self.take(8)?).unwrap()))
+    }
+
+    fn bytes(&mut self) -> Option<&'a [u8]> {
+        let len = self.u32()? as usize;
+        self.take(len)
+    }
+
+    fn c_string(&mut self) -> Option<Cow<'static, CStr>> {
+        Some(Cow::Owned(CString::new(self.bytes()?).ok()?))
+    }
+
+    fn list(&mut self) -> Option<Vec<&'a [u8]>> {
+        (0..self.u32()?).map(|_| self.bytes()).collect()
+    }
+
+    fn c_strings(&mut self) -> Option<Vec<Cow<'static, CStr>>> {
+        self.list()?.into_iter().map(|s| Some(Cow::Owned(CString::new(s).ok()?))).collect()
+    }
+}
+
+fn addr_bytes(addr: &IpAddr) -> Vec<u8> {
+    match addr {
+        IpAddr::V4(ip) => ip.octets().to_vec(),
+        IpAddr::V6(ip) => ip.octets().to_vec(),
+    }
+}
+
+fn addr_from(bytes: &[u8]) -> Option<IpAddr> {
+    match bytes.len() {
+        4 => Some(IpAddr::V4(Ipv4Addr::from(<[u8; 4]>::try_from(bytes).unwrap()))),
+        16 => Some(IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(bytes).unwrap()))),
+        _ => None,
+    }
+}
+
+/// A host, as sent: its name, aliases, addresses, and TTL, if any.
+fn host_record(name: &CStr, aliases: &[Cow<'_, CStr>], addrs: &[IpAddr], ttl: Option<u32>) -> Vec<u8> {
+    let aliases: Vec<&[u8]> = aliases.iter().map(|alias| alias.to_bytes()).collect();
+    let addrs: Vec<Vec<u8>> = addrs.iter().map(addr_bytes).collect();
+    let mut w = Writer::default();
+    w.bytes(name.to_bytes()).list(&aliases).list(&addrs).i64(ttl.map_or(-1, i64::from));
+    w.0
+}
+
+fn entry_addrs(list: &HostAddressList) -> Vec<IpAddr> {
+    match list {
+        HostAddressList::V4(addrs) => addrs.iter().copied().map(IpAddr::V4).collect(),
+        HostAddressList::V6(addrs) => addrs.iter().copied().map(IpAddr::V6).collect(),
+    }
+}
+
+/// A host's name, aliases, addresses, and TTL.
+type HostRecord = (Cow<'static, CStr>, Vec<Cow<'static, CStr>>, Vec<IpAddr>, Option<u32>);
+
+fn read_host(record: &[u8]) -> Option<HostRecord> {
+    let mut r = Reader(record);
+    let name = r.c_string()?;
+    let aliases = r.c_strings()?;
+    let addrs = r.list()?.into_iter().map(addr_from).collect::<Option<_>>()?;
+    let ttl = u32::try_from(r.i64()?).ok();
+    Some((name, aliases, addrs, ttl))
+}
+
+/// A host entry with the addresses of family `af` in `record`.
+fn read_host_entry(record: &[u8], af: AddressFamily) -> Option<HostEntryWithTtl<'static>> {
+    let (name, aliases, addrs, ttl) = read_host(record)?;
+    let addr_list = match af {
+        AddressFamily::Ipv4 => HostAddressList::V4(addrs.iter().filter_map(|addr| match *addr {
+            IpAddr::V4(ip) => Some(ip),
+            IpAddr::V6(_) => None,
+        }).collect()),
+        AddressFamily::Ipv6 => HostAddressList::V6(addrs.iter().filter_map(|addr| match *addr {
+            IpAddr::V4(_) => None,
+            IpAddr::V6(ip) => Some(ip),
+        }).collect()),
+    };
+    Some(HostEntryWithTtl { entry: HostEntry { name, aliases, addr_list }, ttl })
+}
+
+fn passwd_record(entry: &PasswdEntry<'_>) -> Vec<u8> {
+    let mut w = Writer::default();
+    w.bytes(entry.name.to_bytes()).bytes(entry.passwd.to_bytes()).u32(entry.uid).u32(entry.gid)
+        .bytes(entry.gecos.to_bytes()).bytes(entry.dir.to_bytes()).bytes(entry.shell.to_bytes());
+    w.0
+}
+
+fn read_passwd(record: &[u8]) -> Option<PasswdEntry<'static>> {
+    let mut r = Reader(record);
+    Some(PasswdEntry {
+        name: r.c_string()?,
+        passwd: r.c_string()?,
+        uid: r.u32()?,
+        gid: r.u32()?,
+        gecos: r.c_string()?,
+        dir: r.c_string()?,
+        shell: r.c_string()?,
+    })
+}
+
+fn group_record(entry: &GroupEntry<'_>) -> Vec<u8> {
+    let members: Vec<&[u8]> = entry.members.iter().map(|member| member.to_bytes()).collect();
+    let mut w = Writer::default();
+    w.bytes(entry.name.to_bytes()).bytes(entry.passwd.to_bytes()).u32(entry.gid).list(&members);
+    w.0
+}
+
+fn read_group(record: &[u8]) -> Option<GroupEntry<'static>> {
+    let mut r = Reader(record);
+    Some(GroupEntry { name: r.c_string()?, passwd: r.c_string()?, gid: r.u32()?, members: r.c_strings()? })
+}
+
+fn shadow_record(entry: &ShadowEntry<'_>) -> Vec<u8> {
+    let mut w = Writer::default();
+    w.bytes(entry.name.to_bytes()).bytes(entry.passwd.to_bytes());
+    for &n in &[entry.last_change, entry.min, entry.max, entry.warn, entry.inactive, entry.expire] {
+        w.long(n);
+    }
+    w.i64(entry.flag as i64);
+    w.0
+}
+
+fn read_shadow(record: &[u8]) -> Option<ShadowEntry<'static>> {
+    let mut r = Reader(record);
+    let (name, passwd) = (r.c_string()?, r.c_string()?);
+    let mut numbers = [0 as c_long; 6];
+    for n in &mut numbers {
+        *n = c_long::try_from(r.i64()?).ok()?;
+    }
+    let [last_change, min, max, warn, inactive, expire] = numbers;
+    let flag = r.i64()? as c_ulong;
+    Some(ShadowEntry { name, passwd, last_change, min, max, warn, inactive, expire, flag })
+}
+
+/// Turn what a service found into records.
+fn found<E>(result: Result<Option<E>>, record: impl FnOnce(E) -> Vec<u8>) -> Result<Vec<Vec<u8>>> {
+    Ok(result?.map(record).into_iter().collect())
+}
+
+fn listed<E>(entries: Result<Entries<E>>, record: impl Fn(E) -> Vec<u8>) -> Result<Vec<Vec<u8>>> {
+    entries?.map(|entry| entry.map(&record)).collect()
+}
+
+fn name_arg(args: &[u8]) -> Result<CString> {
+    CString::new(args).map_err(|_| Error::with_errno(NssStatus::Unavailable, EINVAL))
+}
+
+fn id_arg(args: &[u8]) -> Result<u32> {
+    Reader(args).u32().ok_or_else(|| Error::with_errno(NssStatus::Unavailable, EINVAL))
+}
+
+fn hosts<T: NameService>(op: u8, args: &[u8]) -> Option<Result<Vec<Vec<u8>>>> {
+    Some(match op {
+        HOST_BY_NAME if !args.is_empty() => name_arg(&args[1..]).and_then(|name| {
+            let af = match args[0] {
+                4 => AddressFamily::Ipv4,
+                6 => AddressFamily::Ipv6,
+                _ => return found(T::gethostbyname4_r(&name), |found| {
+                    host_record(&found.name, &[], &found.addrs, found.ttl)
+                }),
+            };
+            found(T::gethostbyname3_r(&name, af), |found| {
+                let entry = found.entry;
+                host_record(&entry.name, &entry.aliases, &entry_addrs(&entry.addr_list), found.ttl)
+            })
+        }),
+        HOST_BY_ADDR => match addr_from(args) {
+            None => Err(Error::with_errno(NssStatus::Unavailable, EINVAL)),
+            Some(addr) => found(T::gethostbyaddr2_r(&addr), |found| {
+                let entry = found.entry;
+                host_record(&entry.name, &entry.aliases, &entry_addrs(&entry.addr_list), found.ttl)
+            }),
+        },
+        HOST_LIST => listed(T::sethostent(false), |entry| {
+            host_record(&entry.name, &entry.aliases, &entry_addrs(&entry.addr_list), None)
+        }),
+        _ => return None,
+    })
+}
+
+fn passwd<T: PasswdService>(op: u8, args: &[u8]) -> Option<Result<Vec<Vec<u8>>>> {
+    Some(match op {
+        PASSWD_BY_NAME => name_arg(args).and_then(|name| found(T::getpwnam_r(&name), |entry| passwd_record(&entry))),
+        PASSWD_BY_UID => id_arg(args).and_then(|uid| found(T::getpwuid_r(uid), |entry| passwd_record(&entry))),
+        PASSWD_LIST => listed(T::setpwent(), |entry| passwd_record(&entry)),
+        _ => return None,
+    })
+}
+
+fn group<T: GroupService>(op: u8, args: &[u8]) -> Option<Result<Vec<Vec<u8>>>> {
+    Some(match op {
+        GROUP_BY_NAME => name_arg(args).and_then(|name| found(T::getgrnam_r(&name), |entry| group_record(&entry))),
+        GROUP_BY_GID => id_arg(args).and_then(|gid| found(T::getgrgid_r(gid), |entry| group_record(&entry))),
+        GROUP_LIST => listed(T::setgrent(), |entry| group_record(&entry)),
+        // One record with all the group ids, or none if there's no such user.
+        INITGROUPS if args.len() >= 4 => name_arg(&args[4..]).and_then(|user| {
+            found(T::initgroups_dyn(&user, id_arg(args)?), |gids| {
+                gids.iter().flat_map(|gid| gid.to_be_bytes()).collect()
+            })
+        }),
+        _ => return None,
+    })
+}
+
+fn shadow<T: ShadowService>(op: u8, args: &[u8]) -> Option<Result<Vec<Vec<u8>>>> {
+    Some(match op {
+        SHADOW_BY_NAME => name_arg(args).and_then(|name| found(T::getspnam_r(&name), |entry| shadow_record(&entry))),
+        SHADOW_LIST => listed(T::setspent(), |entry| shadow_record(&entry)),
+        _ => return None,
+    })
+}
+
+/// The user id of the process at the other end of `stream`, if the system
+/// says.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn peer_uid(stream: &UnixStream) -> Option<uid_t> {
+    let mut cred: libc::ucred = unsafe { std::mem::zeroed() };
+    let mut len = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+    let rc = unsafe {
+        libc::getsockopt(stream.as_raw_fd(), libc::SOL_SOCKET, libc::SO_PEERCRED,
+                         &mut cred as *mut libc::ucred as *mut libc::c_void, &mut len)
+    };
+    if rc == 0 { Some(cred.uid) } else { None }
+}
+
+#[cfg(any(target_os = "freebsd", target_os = "netbsd", target_os = "openbsd", target_os = "dragonfly",
+          target_os = "macos", target_os = "illumos", target_os = "solaris"))]
+fn peer_uid(stream: &UnixStream) -> Option<uid_t> {
+    let (mut uid, mut gid) = (0, 0);
+    let rc = unsafe { libc::getpeereid(stream.as_raw_fd(), &mut uid, &mut gid) };
+    if rc == 0 { Some(uid) } else { None }
+}
+
+fn read_message(stream: &mut impl Read) -> io::Result<Option<Vec<u8>>> {
+    let mut len = [0; 4];
+    match stream.read_exact(&mut len) {
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        result => result?,
+    }
+    let len = u32::from_be_bytes(len) as usize;
+    if len > MAX_MESSAGE {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "message too long"));
+    }
+    // Read the body as it arrives rather than allocating all of it up front,
+    // so that a length alone can't make the other side allocate 16 MiB.
+    let mut message = Vec::new();
+    stream.take(len as u64).read_to_end(&mut message)?;
+    if message.len() < len {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(Some(message))
+}
+
+fn write_message(stream: &mut impl Write, message: &[u8]) -> io::Result<()> {
+    let mut framed = (message.len() as u32).to_be_bytes().to_vec();
+    framed.extend_from_slice(message);
+    stream.write_all(&framed)
+}
+
+impl DaemonServer {
+    pub fn new() -> DaemonServer {
+        DaemonServer::default()
+    }
+
+    /// Answer `hosts` lookups from `T`.
+    pub fn hosts<T: NameService>(mut self) -> DaemonServer {
+        self.hosts = Some(hosts::<T>);
+        self
+    }
+
+    /// Answer `passwd` lookups from `T`.
+    pub fn passwd<T: PasswdService>(mut self) -> DaemonServer {
+        self.passwd = Some(passwd::<T>);
+        self
+    }
+
+    /// Answer `group` lookups, and `initgroups`, from `T`.
+    pub fn group<T: GroupService>(mut self) -> DaemonServer {
+        self.group = Some(group::<T>);
+        self
+    }
+
+    /// Answer `shadow` lookups from `T`, for clients running as root.
+    pub fn shadow<T: ShadowService>(mut self) -> DaemonServer {
+        self.shadow = Some(shadow::<T>);
+        self
+    }
+
+    /// Accept connections on `listener` and answer their requests, each
+    /// connection on a thread of its own, forever. This returns only if
+    /// accepting fails.
+    ///
+    /// At most 256 connections are served at once, and a client that goes
+    /// quiet for 30 seconds is hung up on, so that stuck or hostile clients
+    /// can't tie up every thread.
+    pub fn serve(&self, listener: &UnixListener) -> io::Result<()> {
+        let open = Arc::new(AtomicUsize::new(0));
+        loop {
+            let (stream, _) = listener.accept()?;
+            if open.fetch_add(1, Ordering::SeqCst) >= MAX_CONNECTIONS {
+                open.fetch_sub(1, Ordering::SeqCst);
+                continue;
+            }
+            let open = Arc::clone(&open);
+            let server = *self;
+            thread::spawn(move || {
+                server.serve_connection(stream);
+                open.fetch_sub(1, Ordering::SeqCst);
+            });
+        }
+    }
+
+    fn serve_connection(&self, mut stream: UnixStream) {
+        let timeouts = stream.set_read_timeout(Some(IDLE_TIMEOUT)).and(stream.set_write_timeout(Some(IDLE_TIMEOUT)));
+        if timeouts.is_err() {
+            return;
+        }
+        let peer_uid = peer_uid(&stream);
+        while let Ok(Some(request)) = read_message(&mut stream) {
+            let reply = self.answer(&request, peer_uid);
+            if write_message(&mut stream, &reply).is_err() {
+                break;
+            }
+        }
+    }
+
+    /// The reply to `request`, from a client running as `peer_uid`.
+    fn answer(&self, request: &[u8], peer_uid: Option<uid_t>) -> Vec<u8> {
+        let result = match request.split_first() {
+            None => Err(Error::with_errno(NssStatus::Unavailable, EINVAL)),
+            Some((&op, args)) => {
+                let handler = match op {
+                    HOST_BY_NAME..=HOST_LIST => self.hosts,
+                    PASSWD_BY_NAME..=PASSWD_LIST => self.passwd,
+                    GROUP_BY_NAME..=INITGROUPS => self.group,
+                    SHADOW_BY_NAME..=SHADOW_LIST if peer_uid != Some(0) => {
+                        return reply(Err(Error::with_errno(NssStatus::Unavailable, EACCES)));
+                    }
+                    SHADOW_BY_NAME..=SHADOW_LIST => self.shadow,
+                    _ => return reply(Err(Error::with_errno(NssStatus::Unavailable, EINVAL))),
+                };
+                match handler {
+                    None => Ok(vec![]),
+                    Some(handler) => match panic::catch_unwind(AssertUnwindSafe(|| handler(op, args))) {
+                        Ok(Some(result)) => result,
+                        Ok(None) => Err(Error::with_errno(NssStatus::Unavailable, EINVAL)),
+                        Err(payload) => {
+                            let err = Error::from_panic(payload);
+                            diag::log(format_args!("service panicked, reporting NSS_STATUS_UNAVAIL: {}",
+                                                   err.panic_message().unwrap_or("")));
+                            Err(err)
+                        }
+                    },
+                }
+            }
+        };
+        reply(result)
+    }
+}
+
+/// A reply with `records`, or the error.
+fn reply(result: Result<Vec<Vec<u8>>>) -> Vec<u8> {
+    let mut w = Writer::default();
+    match result {
+        Ok(records) => {
+            w.u32(NssStatus::Success.as_raw() as u32).u32(0).u32(0).u32(records.len() as u32);
+            for record in &records {
+                w.bytes(record);
+            }
+        }
+        Err(err) => {
+            let h_errno = err.host_error().map_or(NETDB_INTERNAL, HostError::as_raw);
+            w.u32(err.status().as_raw() as u32).u32(err.errno() as u32).u32(h_errno as u32);
+        }
+    }
+    w.0
+}
+
+/// Read a reply: its records, or the error. No records means nothing was
+/// found.
+fn read_reply(reply: &[u8]) -> Option<Result<Vec<&[u8]>>> {
+    let mut r = Reader(reply);
+    let status = r.u32()? as i32;
+    let errno = r.u32()? as i32;
+    let h_errno = r.u32()? as i32;
+    Some(match NssStatus::from_raw(status)? {
+        NssStatus::Success if errno == 0 => Ok(r.list()?),
+        NssStatus::Success => return None,
+        status => {
+            // Pass the error on as is, except for what would make no sense
+            // to report here. The daemon's buffers aren't the caller's, so
+            // its running out of room isn't something a bigger buffer here
+            // would fix.
+            let h_errno = if h_errno == 0 { NETDB_INTERNAL } else { h_errno };
+            let errno = match errno {
+                0 if h_errno == NETDB_INTERNAL => EIO,
+                ERANGE => EAGAIN,
+                errno => errno,
+            };
+            Err(Error::from_raw_parts(status, errno, h_errno))
+        }
+    })
+}
+
+impl<C: DaemonConfig> DaemonService<C> {
+    /// Send `request` to the daemon, and convert each record in the reply
+    /// with `convert`.
+    fn ask<T>(request: &[u8], convert: impl Fn(&[u8]) -> Option<T>) -> Result<Vec<T>> {
+        let reply = (|| {
+            let mut stream = UnixStream::connect(C::SOCKET)?;
+            stream.set_read_timeout(Some(C::TIMEOUT))?;
+            stream.set_write_timeout(Some(C::TIMEOUT))?;
+            write_message(&mut stream, request)?;
+            read_message(&mut stream)?.ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))
+        })().map_err(|err: io::Error| {
+            if !matches!(err.kind(), io::ErrorKind::NotFound | io::ErrorKind::ConnectionRefused) {
+                diag::log(format_args!("can't ask the daemon on {}: {}", C::SOCKET, err));
+            }
+            Error::with_errno(NssStatus::Unavailable, err.raw_os_error().unwrap_or(EIO))
+        })?;
+        let malformed = || {
+            diag::log(format_args!("malformed reply from the daemon on {}", C::SOCKET));
+            Error::with_errno(NssStatus::Unavailable, EINVAL)
+        };
+        read_reply(&reply).ok_or_else(malformed)??
+            .into_iter()
+            .map(|record| convert(record).ok_or_else(malformed))
+            .collect()
+    }
+
+    /// `ask`, for a lookup that finds at most one entry.
+    fn ask_one<T>(request: &[u8], convert: impl Fn(&[u8]) -> Option<T>) -> Result<Option<T>> {
+        Ok(Self::ask(request, convert)?.into_iter().next())
+    }
+}
+
+fn with_name(op: u8, name: &CStr) -> Vec<u8> {
+    let mut request = vec![op];
+    request.extend_from_slice(name.to_bytes());
+    request
+}
+
+fn with_id(op: u8, id: u32) -> Vec<u8> {
+    let mut request = vec![op];
+    request.extend_from_slice(&id.to_be_bytes());
+    request
+}
+
+impl<C: DaemonConfig> NameService for DaemonService<C> {
+    fn gethostbyname2_r(name: &CStr, af: AddressFamily) -> Result<Option<HostEntry<'_>>> {
+        Ok(Self::gethostbyname3_r(name, af)?.map(|found| found.entry))
+    }
+
+    fn gethostbyname3_r(name: &CStr, af: AddressFamily) -> Result<Option<HostEntryWithTtl<'_>>> {
+        let family = match af {
+            AddressFamily::Ipv4 => 4,
+            AddressFamily::Ipv6 => 6,
+        };
+        let mut request = vec![HOST_BY_NAME, family];
+        request.extend_from_slice(name.to_bytes());
+        Self::ask_one(&request, |record| read_host_entry(record, af))
+    }
+
+    fn gethostbyname4_r(name: &CStr) -> Result<Option<HostAddresses<'_>>> {
+        let mut request = vec![HOST_BY_NAME, BOTH_FAMILIES];
+        request.extend_from_slice(name.to_bytes());
+        Self::ask_one(&request, |record| {
+            let (name, _, addrs, ttl) = read_host(record)?;
+            Some(HostAddresses { name, addrs, ttl })
+        })
+    }
+
+    fn gethostbyaddr_r(addr: &IpAddr) -> Result<Option<HostEntry<'_>>> {
+        Ok(Self::gethostbyaddr2_r(addr)?.map(|found| found.entry))
+    }
+
+    fn gethostbyaddr2_r(addr: &IpAddr) -> Result<Option<HostEntryWithTtl<'_>>> {
+        let mut request = vec![HOST_BY_ADDR];
+        request.extend(addr_bytes(addr));
+        let af = if addr.is_ipv4() { AddressFamily::Ipv4 } else { AddressFamily::Ipv6 };
+        Self::ask_one(&request, |record| read_host_entry(record, af))
+    }
+
+    fn sethostent(_stay_open: bool) -> Result<Entries<HostEntry<'static>>> {
+        // Each listed host has addresses of one family.
+        let hosts = Self::ask(&[HOST_LIST], |record| {
+            let (_, _, addrs, _) = read_host(record)?;
+            let af = if addrs.first().is_some_and(IpAddr::is_ipv6) { AddressFamily::Ipv6 } else { AddressFamily::Ipv4 };
+            read_host_entry(record, af)
+        })?;
+        Ok(Box::new(hosts.into_iter().map(|found| Ok(found.entry))))
+    }
+}
+
+impl<C: DaemonConfig> PasswdService for DaemonService<C> {
+    fn getpwnam_r(name: &CStr) -> Result<Option<PasswdEntry<'_>>> {
+        Self::ask_one(&with_name(PASSWD_BY_NAME, name), read_passwd)
+    }
+
+    fn getpwuid_r(uid: uid_t) -> Result<Option<PasswdEntry<'static>>> {
+        Self::ask_one(&with_id(PASSWD_BY_UID, uid), read_passwd)
+    }
+
+    fn setpwent() -> Result<Entries<PasswdEntry<'static>>> {
+        Ok(Box::new(Self::ask(&[PASSWD_LIST], read_passwd)?.into_iter().map(Ok)))
+    }
+}
+
+impl<C: DaemonConfig> GroupService for DaemonService<C> {
+    fn getgrnam_r(name: &CStr) -> Result<Option<GroupEntry<'_>>> {
+        Self::ask_one(&with_name(GROUP_BY_NAME, name), read_group)
+    }
+
+    fn getgrgid_r(gid: gid_t) -> Result<Option<GroupEntry<'static>>> {
+        Self::ask_one(&with_id(GROUP_BY_GID, gid), read_group)
+    }
+
+    fn setgrent() -> Result<Entries<GroupEntry<'static>>> {
+        Ok(Box::new(Self::ask(&[GROUP_LIST], read_group)?.into_iter().map(Ok)))
+    }
+
+    fn initgroups_dyn(user: &CStr, group: gid_t) -> Result<Option<Vec<gid_t>>> {
+        let mut request = with_id(INITGROUPS, group);
+        request.extend_from_slice(user.to_bytes());
+        Self::ask_one(&request, |record| {
+            record.chunks(4).map(|gid| Some(gid_t::from_be_bytes(<[u8; 4]>::try_from(gid).ok()?))).collect()
+        })
+    }
+}
+
+impl<C: DaemonConfig> ShadowService for DaemonService<C> {
+    fn getspnam_r(name: &CStr) -> Result<Option<ShadowEntry<'_>>> {
+        Self::ask_one(&with_name(SHADOW_BY_NAME, name), read_shadow)
+    }
+
+    fn setspent() -> Result<Entries<ShadowEntry<'static>>> {
+        Ok(Box::new(Self::ask(&[SHADOW_LIST], read_shadow)?.into_iter().map(Ok)))
+    }
+}
+
+#[test]
+fn test_daemon_round_trip() {
+    use crate::testing::{AlwaysTryAgain, FixedHosts};
+
+    struct TestDaemon;
+    impl DaemonConfig for TestDaemon {
+        const SOCKET: &'static str = "/tmp/nsswitch_service-test-daemon.sock";
+    }
+    type Client = DaemonService<TestDaemon>;
+
+    let _ = std::fs::remove_file(TestDaemon::SOCKET);
+    let listener = UnixListener::bind(TestDaemon::SOCKET).unwrap();
+    let server = DaemonServer::new().hosts::<FixedHosts>().passwd::<AlwaysTryAgain>();
+    thread::spawn(move || server.serve(&listener));
+
+    let name = CString::new("www.test").unwrap();
+    let found = Client::gethostbyname3_r(&name, AddressFamily::Ipv6).unwrap().unwrap();
+    assert_eq!(found.entry.name.to_str(), Ok("host.test"));
+    assert_eq!(found.entry.aliases.len(), 1);
+    assert!(matches!(found.entry.addr_list, HostAddressList::V6(ref addrs) if addrs.len() == 1));
+    let found = Client::gethostbyname4_r(&name).unwrap().unwrap();
+    assert_eq!(found.addrs.len(), 2);
+    let (v6only, nope) = (CString::new("v6only.test").unwrap(), CString::new("nope.test").unwrap());
+    let err = Client::gethostbyname2_r(&v6only, AddressFamily::Ipv4).unwrap_err();
+    assert_eq!((err.status(), err.host_error()), (NssStatus::NotFound, Some(HostError::NoData)));
+    assert!(Client::gethostbyname2_r(&nope, AddressFamily::Ipv4).unwrap().is_none());
+    let addr = "192.0.2.2".parse().unwrap();
+    let found = Client::gethostbyaddr_r(&addr).unwrap().unwrap();
+    assert_eq!(found.name.to_str(), Ok("v4only.test"));
+    assert_eq!(Client::sethostent(false).unwrap().count(), 6);
+
+    let alice = CString::new("alice").unwrap();
+    let err = Client::getpwnam_r(&alice).unwrap_err();
+    assert_eq!((err.status(), err.errno()), (NssStatus::TryAgain, EAGAIN));
+    assert!(Client::getgrgid_r(0).unwrap().is_none());
+
+    // Shadow entries are for root only.
+    let request = with_name(SHADOW_BY_NAME, &alice);
+    let err = read_reply(&server.answer(&request, Some(1000))).unwrap().unwrap_err();
+    assert_eq!((err.status(), err.errno()), (NssStatus::Unavailable, EACCES));
+    assert_eq!(read_reply(&server.answer(&request, Some(0))).unwrap().unwrap().len(), 0);
+    let _ = std::fs::remove_file(TestDaemon::SOCKET);
+}
+
+#[test]
+fn test_read_message() {
+    use std::io::Cursor;
+
+    let read = |bytes: &[u8]| read_message(&mut Cursor::new(bytes.to_vec()));
+    assert_eq!(read(b"\0\0\0\x03abc").unwrap(), Some(b"abc".to_vec()));
+
+    // The other side hanging up between messages is the end, not an error,
+    // but hanging up in the middle of one is.
+    assert_eq!(read(b"").unwrap(), None);
+    assert_eq!(read(b"\0\0\0\x03ab").unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+
+    // A length over the limit is refused before anything is read, and one
+    // under it that isn't followed by that many bytes is cut short.
+    let too_long = (MAX_MESSAGE as u32 + 1).to_be_bytes();
+    assert_eq!(read(&too_long).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    let longest = [&(MAX_MESSAGE as u32).to_be_bytes()[..], b"abc"].concat();
+    assert_eq!(read(&longest).unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+}
+
+#[test]
+fn test_read_reply() {
+    use libc::ENOENT;
+
+    let header = |status: NssStatus, errno: i32, h_errno: i32| {
+        [(status.as_raw() as u32).to_be_bytes(), (errno as u32).to_be_bytes(), (h_errno as u32).to_be_bytes()].concat()
+    };
+
+    // Replies cut short, with an unknown status, or claiming success with an
+    // errno, are malformed.
+    let found = reply(Ok(vec![b"one".to_vec(), b"two".to_vec()]));
+    assert_eq!(read_reply(&found).unwrap().unwrap(), vec![&b"one"[..], b"two"]);
+    for len in 0..found.len() {
+        assert!(read_reply(&found[..len]).is_none(), "{} bytes", len);
+    }
+    assert!(read_reply(&[[0, 0, 0, 9], [0; 4], [0; 4]].concat()).is_none());
+    assert!(read_reply(&[header(NssStatus::Success, EIO, 0), 0_u32.to_be_bytes().to_vec()].concat()).is_none());
+
+    // Errors are passed on, except that the daemon running out of room is
+    // transient here, and an error without an errno or h_errno is EIO.
+    let err = read_reply(&reply(Err(Error::from_dns_rcode(3, None)))).unwrap().unwrap_err();
+    assert_eq!((err.status(), err.errno()), (NssStatus::NotFound, ENOENT));
+    assert_eq!(err.host_error(), Some(HostError::HostNotFound));
+    let err = read_reply(&header(NssStatus::TryAgain, ERANGE, NETDB_INTERNAL)).unwrap().unwrap_err();
+    assert_eq!((err.status(), err.errno()), (NssStatus::TryAgain, EAGAIN));
+    let err = read_reply(&header(NssStatus::Unavailable, 0, 0)).unwrap().unwrap_err();
+    assert_eq!((err.status(), err.errno(), err.host_error()), (NssStatus::Unavailable, EIO, None));
+}
+
+#[test]
+fn test_malformed_requests() {
+    use crate::testing::{AlwaysTryAgain, FixedHosts};
+
+    struct Panics;
+    impl PasswdService for Panics {
+        fn getpwnam_r(_name: &CStr) -> Result<Option<PasswdEntry<'_>>> {
+            panic!("directory unreachable")
+        }
+
+        fn getpwuid_r(_uid: uid_t) -> Result<Option<PasswdEntry<'static>>> {
+            Ok(None)
+        }
+    }
+
+    let server = DaemonServer::new().hosts::<FixedHosts>().passwd::<Panics>().group::<AlwaysTryAgain>();
+    let status = |request: &[u8]| match read_reply(&server.answer(request, Some(1000))).unwrap() {
+        Ok(records) => Ok(records.len()),
+        Err(err) => Err((err.status(), err.errno())),
+    };
+    let invalid = Err((NssStatus::Unavailable, EINVAL));
+
+    // Requests that are empty, unknown, or have arguments of the wrong size.
+    assert_eq!(status(b""), invalid);
+    assert_eq!(status(&[99]), invalid);
+    assert_eq!(status(&[HOST_BY_NAME]), invalid);
+    assert_eq!(status(&[HOST_BY_ADDR, 192, 0, 2]), invalid);
+    assert_eq!(status(&[PASSWD_BY_UID, 0, 0]), invalid);
+    assert_eq!(status(&[INITGROUPS, 0]), invalid);
+    assert_eq!(status(b"\x04al\0ice"), invalid);
+
+    // Errors from the services are passed on, and a service that panics is
+    // unavailable. A database without a service has nothing in it.
+    assert_eq!(status(&with_id(GROUP_BY_GID, 0)), Err((NssStatus::TryAgain, EAGAIN)));
+    assert_eq!(status(&with_name(PASSWD_BY_NAME, &CString::new("alice").unwrap())),
+               Err((NssStatus::Unavailable, EIO)));
+    assert_eq!(status(&[HOST_BY_ADDR, 192, 0, 2, 2]), Ok(1));
+    assert_eq!(read_reply(&DaemonServer::new().answer(&with_id(GROUP_BY_GID, 0), None)).unwrap().unwrap().len(), 0);
+}
+
+#[test]
+fn test_client_errors() {
+    use libc::ENOENT;
+
+    // A daemon on `socket` that reads each request and then does `answer`.
+    fn fake_daemon(socket: &str, answer: fn(&mut UnixStream)) {
+        let _ = std::fs::remove_file(socket);
+        let listener = UnixListener::bind(socket).unwrap();
+        thread::spawn(move || {
+            for mut stream in listener.incoming().map(io::Result::unwrap) {
+                read_message(&mut stream).unwrap();
+                answer(&mut stream);
+            }
+        });
+    }
+
+    struct Missing;
+    impl DaemonConfig for Missing {
+        const SOCKET: &'static str = "/tmp/nsswitch_service-test-missing.sock";
+    }
+    struct HangsUp;
+    impl DaemonConfig for HangsUp {
+        const SOCKET: &'static str = "/tmp/nsswitch_service-test-hangs-up.sock";
+    }
+    struct Silent;
+    impl DaemonConfig for Silent {
+        const SOCKET: &'static str = "/tmp/nsswitch_service-test-silent.sock";
+        const TIMEOUT: Duration = Duration::from_millis(100);
+    }
+    struct Garbled;
+    impl DaemonConfig for Garbled {
+        const SOCKET: &'static str = "/tmp/nsswitch_service-test-garbled.sock";
+    }
+    struct BadRecord;
+    impl DaemonConfig for BadRecord {
+        const SOCKET: &'static str = "/tmp/nsswitch_service-test-bad-record.sock";
+    }
+
+    let _ = std::fs::remove_file(Missing::SOCKET);
+    fake_daemon(HangsUp::SOCKET, |_| {});
+    fake_daemon(Silent::SOCKET, |stream| {
+        let _ = stream.read(&mut [0]);
+    });
+    fake_daemon(Garbled::SOCKET, |stream| write_message(stream, b"\0\0\0\x01").unwrap());
+    fake_daemon(BadRecord::SOCKET, |stream| write_message(stream, &reply(Ok(vec![b"junk".to_vec()]))).unwrap());
+
+    // Every failure is reported as unavailable, with the errno of the
+    // underlying error if there is one.
+    let alice = CString::new("alice").unwrap();
+    let status = |result: Result<Option<PasswdEntry<'_>>>| {
+        result.map(|_| ()).map_err(|err| (err.status(), err.errno()))
+    };
+    assert_eq!(status(DaemonService::<Missing>::getpwnam_r(&alice)), Err((NssStatus::Unavailable, ENOENT)));
+    assert_eq!(status(DaemonService::<HangsUp>::getpwnam_r(&alice)), Err((NssStatus::Unavailable, EIO)));
+    assert_eq!(status(DaemonService::<Silent>::getpwnam_r(&alice)), Err((NssStatus::Unavailable, EAGAIN)));
+    assert_eq!(status(DaemonService::<Garbled>::getpwnam_r(&alice)), Err((NssStatus::Unavailable, EINVAL)));
+    assert_eq!(status(DaemonService::<BadRecord>::getpwnam_r(&alice)), Err((NssStatus::Unavailable, EINVAL)));
+    for socket in &[HangsUp::SOCKET, Silent::SOCKET, Garbled::SOCKET, BadRecord::SOCKET] {
+        let _ = std::fs::remove_file(socket);
+    }
+}
@@ -0,0 +1,554 @@
+//! A ready-made service that forwards every call to another NSS module,
+//! loaded with `dlopen`, for modules that wrap one glibc already has.
+
+use crate::diag;
+use crate::errors::{Error, NssStatus, Result};
+use crate::ffi::{c_int, c_long, c_void, gaih_addrtuple, gid_t, group, hostent, passwd, spwd, uid_t};
+use crate::foreign::{retry, EndEntFn, GrByGidFn, GrByNameFn, GrEntFn, HostByAddr2Fn, HostByAddrFn, HostByName2Fn};
+use crate::foreign::{HostByName3Fn, HostByName4Fn, HostEntFn, InitgroupsDynFn, PwByNameFn, PwByUidFn, PwEntFn};
+use crate::foreign::{SetEntFn, SetHostEntFn, SpByNameFn, SpEntFn};
+use crate::fork::ForkSafeMutex;
+use crate::interfaces::{lookup_both_families, AddressFamily, Entries, GroupEntry, GroupService, HostAddresses};
+use crate::interfaces::{HostEntry, HostEntryWithTtl, NameService, PasswdEntry, PasswdService, ShadowEntry};
+use crate::interfaces::ShadowService;
+use libc::{AF_INET, AF_INET6, ENOENT, ENOSYS, ERANGE};
+use std::convert::TryFrom;
+use std::ffi::{CStr, CString};
+use std::marker::PhantomData;
+use std::net::IpAddr;
+use std::{mem, ptr};
+
+/// Which module a `DelegateService` forwards to.
+pub trait DelegateConfig: 'static {
+    /// The service name of the module, as in `/etc/nsswitch.conf`: `dns`
+    /// loads `libnss_dns.so.2`.
+    const MODULE: &'static str = "dns";
+}
+
+/// The usual settings: glibc's `dns` module.
+pub struct DelegateDefaults;
+
+impl DelegateConfig for DelegateDefaults {}
+
+/// A service that forwards to another installed module, so a module can
+/// filter, log, or override what that one says without reimplementing it:
+///
+/// ```ignore
+/// struct Logged;
+///
+/// impl NameService for Logged {
+///     fn gethostbyname2_r(name: &CStr, af: AddressFamily) -> Result<Option<HostEntry<'_>>> {
+///         let result = DelegateService::dlopen("dns")?.gethostbyname2_r(name, af);
+///         log_lookup(name, &result);
+///         result
+///     }
+///     ...
+/// }
+/// ```
+///
+/// `DelegateService<C>` implements every trait by forwarding to
+/// `C::MODULE`; `DelegateService::dlopen` gives the module itself, for
+/// forwarding only some calls. Each call retries with a bigger buffer
+/// until the result fits, and the result is copied out, so it borrows
+/// nothing.
+///
+/// A module is loaded the first time it's needed, and stays loaded. If it
+/// can't be loaded, that's logged, and calls report
+/// `NssStatus::Unavailable`; so do calls to functions it doesn't have,
+/// which is what glibc does too. Enumeration reads all the module's
+/// entries at once, in `setXXent`, since the module has only one
+/// enumeration going at a time.
+///
+/// Don't delegate to the module doing the delegating: the call would come
+/// right back, and `in_lookup` would turn it away.
+pub struct DelegateService<C = DelegateDefaults>(PhantomData<C>);
+
+/// A loaded module's functions, for forwarding calls to it.
+pub struct DelegateModule {
+    name: String,
+    gethostbyname2_r: Option<HostByName2Fn>,
+    gethostbyname3_r: Option<HostByName3Fn>,
+    gethostbyname4_r: Option<HostByName4Fn>,
+    gethostbyaddr_r: Option<HostByAddrFn>,
+    gethostbyaddr2_r: Option<HostByAddr2Fn>,
+    sethostent: Option<SetHostEntFn>,
+    gethostent_r: Option<HostEntFn>,
+    endhostent: Option<EndEntFn>,
+    getpwnam_r: Option<PwByNameFn>,
+    getpwuid_r: Option<PwByUidFn>,
+    setpwent: Option<SetEntFn>,
+    getpwent_r: Option<PwEntFn>,
+    endpwent: Option<EndEntFn>,
+    getgrnam_r: Option<GrByNameFn>,
+    getgrgid_r: Option<GrByGidFn>,
+    setgrent: Option<SetEntFn>,
+    getgrent_r: Option<GrEntFn>,
+    endgrent: Option<EndEntFn>,
+    initgroups_dyn: Option<InitgroupsDynFn>,
+    getspnam_r: Option<SpByNameFn>,
+    setspent: Option<SetEntFn>,
+    getspent_r: Option<SpEntFn>,
+    endspent: Option<EndEntFn>,
+}
+
+/// The modules loaded so far. They're never unloaded, so references to
+/// them last as long as the process.
+static MODULES: ForkSafeMutex<Vec<&'static DelegateModule>> = ForkSafeMutex::new();
+
+/// Read a status returned by a module, treating one this crate doesn't
+/// know as `Unavailable`.
+fn status(raw: c_int) -> NssStatus {
+    NssStatus::from_raw(raw).unwrap_or(NssStatus::Unavailable)
+}
+
+fn af_number(af: AddressFamily) -> c_int {
+    match af {
+        AddressFamily::Ipv4 => AF_INET,
+        AddressFamily::Ipv6 => AF_INET6,
+    }
+}
+
+impl DelegateModule {
+    fn open(name: &str) -> Option<DelegateModule> {
+        let path = CString::new(format!("libnss_{}.so.2", name)).ok()?;
+        // glibc loads modules this way too.
+        let handle = unsafe { libc::dlopen(path.as_ptr(), libc::RTLD_LAZY) };
+        if handle.is_null() {
+            let message = unsafe { libc::dlerror() };
+            let message = if message.is_null() {
+                "unknown error".into()
+            } else {
+                unsafe { CStr::from_ptr(message) }.to_string_lossy()
+            };
+            diag::log(format_args!("can't load the {} module: {}", name, message));
+            return None;
+        }
+        unsafe fn symbol<F: Copy>(handle: *mut c_void, name: &str, function: &str) -> Option<F> {
+            let symbol = CString::new(format!("_nss_{}_{}", name, function)).ok()?;
+            let p = libc::dlsym(handle, symbol.as_ptr());
+            if p.is_null() { None } else { Some(mem::transmute_copy(&p)) }
+        }
+        unsafe {
+            Some(DelegateModule {
+                name: name.to_string(),
+                gethostbyname2_r: symbol(handle, name, "gethostbyname2_r"),
+                gethostbyname3_r: symbol(handle, name, "gethostbyname3_r"),
+                gethostbyname4_r: symbol(handle, name, "gethostbyname4_r"),
+                gethostbyaddr_r: symbol(handle, name, "gethostbyaddr_r"),
+                gethostbyaddr2_r: symbol(handle, name, "gethostbyaddr2_r"),
+                sethostent: symbol(handle, name, "sethostent"),
+                gethostent_r: symbol(handle, name, "gethostent_r"),
+                endhostent: symbol(handle, name, "endhostent"),
+                getpwnam_r: symbol(handle, name, "getpwnam_r"),
+                getpwuid_r: symbol(handle, name, "getpwuid_r"),
+                setpwent: symbol(handle, name, "setpwent"),
+                getpwent_r: symbol(handle, name, "getpwent_r"),
+                endpwent: symbol(handle, name, "endpwent"),
+                getgrnam_r: symbol(handle, name, "getgrnam_r"),
+                getgrgid_r: symbol(handle, name, "getgrgid_r"),
+                setgrent: symbol(handle, name, "setgrent"),
+                getgrent_r: symbol(handle, name, "getgrent_r"),
+                endgrent: symbol(handle, name, "endgrent"),
+                initgroups_dyn: symbol(handle, name, "initgroups_dyn"),
+                getspnam_r: symbol(handle, name, "getspnam_r"),
+                setspent: symbol(handle, name, "setspent"),
+                getspent_r: symbol(handle, name, "getspent_r"),
+                endspent: symbol(handle, name, "endspent"),
+            })
+        }
+    }
+
+    /// The module's service name.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn missing<T>(function: Option<T>) -> Result<T> {
+        function.ok_or_else(|| Error::with_errno(NssStatus::Unavailable, ENOSYS))
+    }
+
+    pub fn gethostbyname2_r(&self, name: &CStr, af: AddressFamily) -> Result<Option<HostEntry<'static>>> {
+        if self.gethostbyname2_r.is_none() && self.gethostbyname3_r.is_some() {
+            return Ok(self.gethostbyname3_r(name, af)?.map(|found| found.entry));
+        }
+        let f = Self::missing(self.gethostbyname2_r)?;
+        retry(|buffer, buflen, errno, h_errno| unsafe {
+            let mut result: hostent = mem::zeroed();
+            match f(name.as_ptr(), af_number(af), &mut result, buffer, buflen, errno, h_errno) {
+                1 => Ok(HostEntry::read_from(&result)),
+                raw => Err(status(raw)),
+            }
+        })
+    }
+
+    pub fn gethostbyname3_r(&self, name: &CStr, af: AddressFamily) -> Result<Option<HostEntryWithTtl<'static>>> {
+        let f = match self.gethostbyname3_r {
+            Some(f) => f,
+            None => return Ok(self.gethostbyname2_r(name, af)?.map(|entry| HostEntryWithTtl { entry, ttl: None })),
+        };
+        retry(|buffer, buflen, errno, h_errno| unsafe {
+            let mut result: hostent = mem::zeroed();
+            let mut ttl = -1;
+            match f(name.as_ptr(), af_number(af), &mut result, buffer, buflen, errno, h_errno, &mut ttl,
+                    ptr::null_mut()) {
+                1 => Ok(HostEntryWithTtl { entry: HostEntry::read_from(&result), ttl: u32::try_from(ttl).ok() }),
+                raw => Err(status(raw)),
+            }
+        })
+    }
+
+    /// `None` if the module has no `gethostbyname4_r`.
+    fn gethostbyname4_r(&self, name: &CStr) -> Option<Result<Option<HostAddresses<'static>>>> {
+        let f = self.gethostbyname4_r?;
+        Some(retry(|buffer, buflen, errno, h_errno| unsafe {
+            let mut pat: *mut gaih_addrtuple = ptr::null_mut();
+            let mut ttl = -1;
+            match f(name.as_ptr(), &mut pat, buffer, buflen, errno, h_errno, &mut ttl) {
+                1 => Ok(HostAddresses { ttl: u32::try_from(ttl).ok(), ..HostAddresses::read_from(pat) }),
+                raw => Err(status(raw)),
+            }
+        }))
+    }
+
+    pub fn gethostbyaddr2_r(&self, addr: &IpAddr) -> Result<Option<HostEntryWithTtl<'static>>> {
+        let (af, octets) = match addr {
+            IpAddr::V4(ip) => (AF_INET, ip.octets().to_vec()),
+            IpAddr::V6(ip) => (AF_INET6, ip.octets().to_vec()),
+        };
+        let (addr, len) = (octets.as_ptr() as *const c_void, octets.len() as c_int);
+        match (self.gethostbyaddr2_r, self.gethostbyaddr_r) {
+            (Some(f), _) => retry(|buffer, buflen, errno, h_errno| unsafe {
+                let mut result: hostent = mem::zeroed();
+                let mut ttl = -1;
+                match f(addr, len, af, &mut result, buffer, buflen, errno, h_errno, &mut ttl) {
+                    1 => Ok(HostEntryWithTtl { entry: HostEntry::read_from(&result), ttl: u32::try_from(ttl).ok() }),
+                    raw => Err(status(raw)),
+                }
+            }),
+            (None, f) => {
+                let f = Self::missing(f)?;
+                retry(|buffer, buflen, errno, h_errno| unsafe {
+                    let mut result: hostent = mem::zeroed();
+                    match f(addr, len, af, &mut result, buffer, buflen, errno, h_errno) {
+                        1 => Ok(HostEntryWithTtl { entry: HostEntry::read_from(&result), ttl: None }),
+                        raw => Err(status(raw)),
+                    }
+                })
+            }
+        }
+    }
+
+    pub fn getpwnam_r(&self, name: &CStr) -> Result<Option<PasswdEntry<'static>>> {
+        let f = Self::missing(self.getpwnam_r)?;
+        retry(|buffer, buflen, errno, _| unsafe {
+            let mut result: passwd = mem::zeroed();
+            match f(name.as_ptr(), &mut result, buffer, buflen, errno) {
+                1 => Ok(PasswdEntry::read_from(&result)),
+                raw => Err(status(raw)),
+            }
+        })
+    }
+
+    pub fn getpwuid_r(&self, uid: uid_t) -> Result<Option<PasswdEntry<'static>>> {
+        let f = Self::missing(self.getpwuid_r)?;
+        retry(|buffer, buflen, errno, _| unsafe {
+            let mut result: passwd = mem::zeroed();
+            match f(uid, &mut result, buffer, buflen, errno) {
+                1 => Ok(PasswdEntry::read_from(&result)),
+                raw => Err(status(raw)),
+            }
+        })
+    }
+
+    pub fn getgrnam_r(&self, name: &CStr) -> Result<Option<GroupEntry<'static>>> {
+        let f = Self::missing(self.getgrnam_r)?;
+        retry(|buffer, buflen, errno, _| unsafe {
+            let mut result: group = mem::zeroed();
+            match f(name.as_ptr(), &mut result, buffer, buflen, errno) {
+                1 => Ok(GroupEntry::read_from(&result)),
+                raw => Err(status(raw)),
+            }
+        })
+    }
+
+    pub fn getgrgid_r(&self, gid: gid_t) -> Result<Option<GroupEntry<'static>>> {
+        let f = Self::missing(self.getgrgid_r)?;
+        retry(|buffer, buflen, errno, _| unsafe {
+            let mut result: group = mem::zeroed();
+            match f(gid, &mut result, buffer, buflen, errno) {
+                1 => Ok(GroupEntry::read_from(&result)),
+                raw => Err(status(raw)),
+            }
+        })
+    }
+
+    /// The groups `user` is in, by the module's `initgroups_dyn`, or
+    /// `None` if the module has none.
+    fn initgroups_dyn(&self, user: &CStr, group: gid_t) -> Option<Result<Option<Vec<gid_t>>>> {
+        let f = self.initgroups_dyn?;
+        // The module adds to a malloc'd array, growing it with realloc.
+        let mut size: c_long = 16;
+        let mut groups = unsafe { libc::malloc(size as usize * mem::size_of::<gid_t>()) } as *mut gid_t;
+        if groups.is_null() {
+            return Some(Err(Error::out_of_memory()));
+        }
+        let (mut start, mut errno) = (0, 0);
+        let raw = unsafe { f(user.as_ptr(), group, &mut start, &mut size, &mut groups, 0, &mut errno) };
+        let gids = unsafe { std::slice::from_raw_parts(groups, start.max(0) as usize) }.to_vec();
+        unsafe { libc::free(groups as *mut c_void) };
+        Some(match NssStatus::from_raw(raw) {
+            Some(NssStatus::Success) => Ok(Some(gids)),
+            Some(NssStatus::NotFound) => Ok(if gids.is_empty() { None } else { Some(gids) }),
+            Some(status @ NssStatus::TryAgain) | Some(status @ NssStatus::Unavailable) => {
+                Err(Error::with_errno(status, if errno == 0 || errno == ERANGE { libc::EIO } else { errno }))
+            }
+            None => Err(Error::with_errno(NssStatus::Unavailable, libc::EIO)),
+        })
+    }
+
+    pub fn getspnam_r(&self, name: &CStr) -> Result<Option<ShadowEntry<'static>>> {
+        let f = Self::missing(self.getspnam_r)?;
+        retry(|buffer, buflen, errno, _| unsafe {
+            let mut result: spwd = mem::zeroed();
+            match f(name.as_ptr(), &mut result, buffer, buflen, errno) {
+                1 => Ok(ShadowEntry::read_from(&result)),
+                raw => Err(status(raw)),
+            }
+        })
+    }
+
+    /// Read every entry with `set`, `next`, and `end`. A module without
+    /// them has none.
+    fn enumerate<E, S, F>(set: Option<S>, mut next: F, end: Option<EndEntFn>) -> Result<Vec<E>>
+    where
+        S: FnOnce() -> c_int,
+        F: FnMut() -> Result<Option<E>>,
+    {
+        let (set, end) = match (set, end) {
+            (Some(set), Some(end)) => (set, end),
+            _ => return Ok(vec![]),
+        };
+        if NssStatus::from_raw(set()) != Some(NssStatus::Success) {
+            return Ok(vec![]);
+        }
+        let mut entries = vec![];
+        let result = loop {
+            match next() {
+                Ok(Some(entry)) => entries.push(entry),
+                Ok(None) => break Ok(entries),
+                Err(err) => break Err(err),
+            }
+        };
+        unsafe {
+            end();
+        }
+        result
+    }
+
+    pub fn hosts(&self) -> Result<Vec<HostEntry<'static>>> {
+        let get = match self.gethostent_r {
+            None => return Ok(vec![]),
+            Some(get) => get,
+        };
+        let set = self.sethostent.map(|set| move || unsafe { set(0) });
+        Self::enumerate(set, || retry(|buffer, buflen, errno, h_errno| unsafe {
+            let mut result: hostent = mem::zeroed();
+            match get(&mut result, buffer, buflen, errno, h_errno) {
+                1 => Ok(HostEntry::read_from(&result)),
+                raw => Err(status(raw)),
+            }
+        }), self.endhostent)
+    }
+
+    pub fn users(&self) -> Result<Vec<PasswdEntry<'static>>> {
+        let get = match self.getpwent_r {
+            None => return Ok(vec![]),
+            Some(get) => get,
+        };
+        Self::enumerate(self.setpwent.map(|set| move || unsafe { set() }), || retry(|buffer, buflen, errno, _| unsafe {
+            let mut result: passwd = mem::zeroed();
+            match get(&mut result, buffer, buflen, errno) {
+                1 => Ok(PasswdEntry::read_from(&result)),
+                raw => Err(status(raw)),
+            }
+        }), self.endpwent)
+    }
+
+    pub fn groups(&self) -> Result<Vec<GroupEntry<'static>>> {
+        let get = match self.getgrent_r {
+            None => return Ok(vec![]),
+            Some(get) => get,
+        };
+        Self::enumerate(self.setgrent.map(|set| move || unsafe { set() }), || retry(|buffer, buflen, errno, _| unsafe {
+            let mut result: group = mem::zeroed();
+            match get(&mut result, buffer, buflen, errno) {
+                1 => Ok(GroupEntry::read_from(&result)),
+                raw => Err(status(raw)),
+            }
+        }), self.endgrent)
+    }
+
+    pub fn shadow_entries(&self) -> Result<Vec<ShadowEntry<'static>>> {
+        let get = match self.getspent_r {
+            None => return Ok(vec![]),
+            Some(get) => get,
+        };
+        Self::enumerate(self.setspent.map(|set| move || unsafe { set() }), || retry(|buffer, buflen, errno, _| unsafe {
+            let mut result: spwd = mem::zeroed();
+            match get(&mut result, buffer, buflen, errno) {
+                1 => Ok(ShadowEntry::read_from(&result)),
+                raw => Err(status(raw)),
+            }
+        }), self.endspent)
+    }
+}
+
+impl DelegateService {
+    /// The module for the service `name`, loading it if it isn't yet. It's
+    /// loaded without holding the lock; if two threads both load it, the
+    /// first to finish wins, and the other's copy is one more reference to
+    /// the same library.
+    pub fn dlopen(name: &str) -> Result<&'static DelegateModule> {
+        let loaded = MODULES.lock().iter().copied().find(|module| module.name == name);
+        if let Some(module) = loaded {
+            return Ok(module);
+        }
+        let module: &'static DelegateModule = Box::leak(Box::new(
+            DelegateModule::open(name).ok_or_else(|| Error::with_errno(NssStatus::Unavailable, ENOENT))?,
+        ));
+        let mut modules = MODULES.lock();
+        match modules.iter().copied().find(|module| module.name == name) {
+            Some(first) => Ok(first),
+            None => {
+                modules.push(module);
+                Ok(module)
+            }
+        }
+    }
+}
+
+impl<C: DelegateConfig> DelegateService<C> {
+    fn module() -> Result<&'static DelegateModule> {
+        DelegateService::dlopen(C::MODULE)
+    }
+}
+
+impl<C: DelegateConfig> NameService for DelegateService<C> {
+    fn gethostbyname2_r(name: &CStr, af: AddressFamily) -> Result<Option<HostEntry<'_>>> {
+        Self::module()?.gethostbyname2_r(name, af)
+    }
+
+    fn gethostbyname3_r(name: &CStr, af: AddressFamily) -> Result<Option<HostEntryWithTtl<'_>>> {
+        Self::module()?.gethostbyname3_r(name, af)
+    }
+
+    fn gethostbyname4_r(name: &CStr) -> Result<Option<HostAddresses<'_>>> {
+        let module = Self::module()?;
+        if let Some(result) = module.gethostbyname4_r(name) {
+            return result;
+        }
+        // As glibc does without it: IPv6, then IPv4.
+        lookup_both_families(|af| module.gethostbyname3_r(name, af))
+    }
+
+    fn gethostbyaddr_r(addr: &IpAddr) -> Result<Option<HostEntry<'_>>> {
+        Ok(Self::module()?.gethostbyaddr2_r(addr)?.map(|found| found.entry))
+    }
+
+    fn gethostbyaddr2_r(addr: &IpAddr) -> Result<Option<HostEntryWithTtl<'_>>> {
+        Self::module()?.gethostbyaddr2_r(addr)
+    }
+
+    fn sethostent(_stay_open: bool) -> Result<Entries<HostEntry<'static>>> {
+        Ok(Box::new(Self::module()?.hosts()?.into_iter().map(Ok)))
+    }
+
+    fn on_fork_child() {
+        MODULES.reset();
+    }
+}
+
+impl<C: DelegateConfig> PasswdService for DelegateService<C> {
+    fn getpwnam_r(name: &CStr) -> Result<Option<PasswdEntry<'_>>> {
+        Self::module()?.getpwnam_r(name)
+    }
+
+    fn getpwuid_r(uid: uid_t) -> Result<Option<PasswdEntry<'static>>> {
+        Self::module()?.getpwuid_r(uid)
+    }
+
+    fn setpwent() -> Result<Entries<PasswdEntry<'static>>> {
+        Ok(Box::new(Self::module()?.users()?.into_iter().map(Ok)))
+    }
+
+    fn on_fork_child() {
+        MODULES.reset();
+    }
+}
+
+impl<C: DelegateConfig> GroupService for DelegateService<C> {
+    fn getgrnam_r(name: &CStr) -> Result<Option<GroupEntry<'_>>> {
+        Self::module()?.getgrnam_r(name)
+    }
+
+    fn getgrgid_r(gid: gid_t) -> Result<Option<GroupEntry<'static>>> {
+        Self::module()?.getgrgid_r(gid)
+    }
+
+    fn setgrent() -> Result<Entries<GroupEntry<'static>>> {
+        Ok(Box::new(Self::module()?.groups()?.into_iter().map(Ok)))
+    }
+
+    fn initgroups_dyn(user: &CStr, group: gid_t) -> Result<Option<Vec<gid_t>>> {
+        let module = Self::module()?;
+        if let Some(result) = module.initgroups_dyn(user, group) {
+            return result;
+        }
+        let gids: Vec<gid_t> = module.groups()?.into_iter()
+            .filter(|entry| entry.members.iter().any(|member| **member == *user))
+            .map(|entry| entry.gid)
+            .collect();
+        Ok(if gids.is_empty() { None } else { Some(gids) })
+    }
+
+    fn on_fork_child() {
+        MODULES.reset();
+    }
+}
+
+impl<C: DelegateConfig> ShadowService for DelegateService<C> {
+    fn getspnam_r(name: &CStr) -> Result<Option<ShadowEntry<'_>>> {
+        Self::module()?.getspnam_r(name)
+    }
+
+    fn setspent() -> Result<Entries<ShadowEntry<'static>>> {
+        Ok(Box::new(Self::module()?.shadow_entries()?.into_iter().map(Ok)))
+    }
+
+    fn on_fork_child() {
+        MODULES.reset();
+    }
+}
+
+#[cfg(all(target_os = "linux", target_env = "gnu"))]
+#[test]
+#[cfg_attr(miri, ignore = "calls dlopen")]
+fn test_delegate_to_files() {
+    struct Files;
+    impl DelegateConfig for Files {
+        const MODULE: &'static str = "files";
+    }
+
+    let root = DelegateService::<Files>::getpwuid_r(0).unwrap().unwrap();
+    assert_eq!(root.name.to_str(), Ok("root"));
+    let name = CString::new("root").unwrap();
+    assert_eq!(DelegateService::<Files>::getpwnam_r(&name).unwrap().unwrap().uid, 0);
+    let name = CString::new("no-such-user-anywhere").unwrap();
+    assert!(DelegateService::<Files>::getpwnam_r(&name).unwrap().is_none());
+    let users: Vec<_> = DelegateService::<Files>::setpwent().unwrap().collect::<Result<_>>().unwrap();
+    assert!(users.iter().any(|user| user.uid == 0));
+    assert!(std::ptr::eq(DelegateService::dlopen("files").unwrap(), DelegateService::<Files>::module().unwrap()));
+
+    let err = DelegateService::dlopen("no_such_module").err().unwrap();
+    assert_eq!(err.status(), NssStatus::Unavailable);
+}
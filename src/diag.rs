@@ -0,0 +1,224 @@
+//! Diagnostics: messages about bugs in the service or in this crate.
+//!
+//! NSS modules run inside other people's programs, so there's nobody to
+//! return these to. By default they go to stderr, but the program may have
+//! closed or repurposed it, so a module can send them elsewhere with
+//! `set_diagnostic_sink`.
+//!
+//! Messages are formatted into a fixed buffer on the stack and written with
+//! a single `write(2)`, without allocating or taking locks, so this is safe
+//! to use in the child after `fork`, in signal handlers, and while the heap
+//! is in a bad state just before aborting. Long messages are truncated.
+//!
+//! With the `tracing` feature, `log_to_tracing` sends messages to the
+//! subscriber given to `init_tracing` instead. That isn't safe in those
+//! situations, so it's a choice a module makes, and the message a bug
+//! aborts with still goes to stderr.
+
+use std::fmt::{self, Write};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+/// Where the crate's diagnostic messages go.
+#[derive(Clone, Copy, Debug)]
+pub enum DiagnosticSink {
+    /// Write each message to file descriptor 2. This is the default.
+    Stderr,
+
+    /// Send each message to the system log, with priority `LOG_ERR`. Unlike
+    /// the other sinks, `syslog(3)` is not async-signal-safe. With the
+    /// `syslog` feature, `log_to_syslog` does this with rate limiting, and
+    /// with the `journald` feature, `log_to_journald` sends messages to the
+    /// journal directly.
+    Syslog,
+
+    /// Drop all messages.
+    Discard,
+
+    /// Pass each message, without a trailing newline, to a function. It is
+    /// subject to the same restrictions as a fork hook (see
+    /// `add_fork_child_hook`), since it may be called in the same situations.
+    Custom(fn(&str)),
+}
+
+const STDERR: usize = 0;
+const SYSLOG: usize = 1;
+const DISCARD: usize = 2;
+#[cfg(feature = "syslog")]
+const LIMITED_SYSLOG: usize = 3;
+#[cfg(feature = "journald")]
+const JOURNALD: usize = 4;
+#[cfg(feature = "tracing")]
+const TRACING: usize = 5;
+
+/// The current sink: one of the constants above, or else the address of a
+/// `Custom` function, which can't be any of them.
+static SINK: AtomicUsize = AtomicUsize::new(STDERR);
+
+/// Send this crate's diagnostic messages to `sink` from now on.
+pub fn set_diagnostic_sink(sink: DiagnosticSink) {
+    let value = match sink {
+        DiagnosticSink::Stderr => STDERR,
+        DiagnosticSink::Syslog => SYSLOG,
+        DiagnosticSink::Discard => DISCARD,
+        DiagnosticSink::Custom(f) => f as usize,
+    };
+    SINK.store(value, Ordering::Release);
+}
+
+/// Send messages to the system log, as `crate::syslog` says.
+#[cfg(feature = "syslog")]
+pub(crate) fn log_to_limited_syslog() {
+    SINK.store(LIMITED_SYSLOG, Ordering::Release);
+}
+
+/// Send messages to the systemd journal, as `crate::journald` says.
+#[cfg(feature = "journald")]
+pub(crate) fn log_to_journald() {
+    SINK.store(JOURNALD, Ordering::Release);
+}
+
+/// Send messages to `tracing`, as `crate::spans` says.
+#[cfg(feature = "tracing")]
+pub(crate) fn log_to_tracing() {
+    SINK.store(TRACING, Ordering::Release);
+}
+
+const PREFIX: &str = "nsswitch resolver: ";
+
+/// A message under construction. Room for the prefix, the message, and a
+/// trailing newline and NUL, so it can be written as a line or passed to
+/// C as a string.
+struct Message {
+    buf: [u8; 512],
+    len: usize,
+}
+
+impl Write for Message {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        // Leave room for "\n\0"; keep whole characters only.
+        let room = self.buf.len() - 2 - self.len;
+        let mut n = s.len().min(room);
+        while !s.is_char_boundary(n) {
+            n -= 1;
+        }
+        self.buf[self.len..self.len + n].copy_from_slice(&s.as_bytes()[..n]);
+        self.len += n;
+        Ok(())
+    }
+}
+
+impl Message {
+    fn format(args: fmt::Arguments<'_>) -> Message {
+        let mut message = Message { buf: [0; 512], len: 0 };
+        let _ = message.write_str(PREFIX);
+        let _ = message.write_fmt(args);
+        // Messages are single lines.
+        for byte in &mut message.buf[..message.len] {
+            if *byte == b'\n' || *byte == 0 {
+                *byte = b' ';
+            }
+        }
+        message
+    }
+
+    fn write_line(mut self, fd: libc::c_int) {
+        self.buf[self.len] = b'\n';
+        unsafe {
+            libc::write(fd, self.buf.as_ptr() as *const libc::c_void, self.len + 1);
+        }
+    }
+
+    fn syslog(&self, priority: libc::c_int) {
+        // The NUL after the message is already there.
+        let skip = PREFIX.len();
+        unsafe {
+            libc::syslog(priority, b"%s\0".as_ptr() as *const libc::c_char,
+                         self.buf[skip..].as_ptr() as *const libc::c_char);
+        }
+    }
+
+    fn as_str(&self) -> &str {
+        // Only whole characters of `str`s were copied in.
+        std::str::from_utf8(&self.buf[..self.len]).unwrap_or(PREFIX)
+    }
+
+    /// The message without `PREFIX`, for logs that say where it's from.
+    #[cfg(any(feature = "journald", feature = "tracing"))]
+    fn text(&self) -> &str {
+        &self.as_str()[PREFIX.len()..]
+    }
+}
+
+/// Write a message to the file descriptor `fd` as a single line, the way
+/// messages go to stderr.
+pub(crate) fn write_line(fd: libc::c_int, args: fmt::Arguments<'_>) {
+    Message::format(args).write_line(fd);
+}
+
+/// Send a message to the current sink.
+#[track_caller]
+pub(crate) fn log(args: fmt::Arguments<'_>) {
+    let message = Message::format(args);
+    match SINK.load(Ordering::Acquire) {
+        STDERR => message.write_line(2),
+        SYSLOG => message.syslog(libc::LOG_ERR),
+        #[cfg(feature = "syslog")]
+        LIMITED_SYSLOG => {
+            if let Some(dropped) = crate::syslog::admit(std::panic::Location::caller()) {
+                let priority = crate::syslog::priority();
+                if dropped > 0 {
+                    Message::format(format_args!("dropped {} messages like the next one", dropped)).syslog(priority);
+                }
+                message.syslog(priority);
+            }
+        }
+        #[cfg(feature = "journald")]
+        JOURNALD => crate::journald::send_message(message.text(), std::panic::Location::caller()),
+        #[cfg(feature = "tracing")]
+        TRACING => crate::spans::diagnostic(message.text()),
+        DISCARD => {}
+        custom => {
+            let f: fn(&str) = unsafe { std::mem::transmute::<usize, fn(&str)>(custom) };
+            f(message.as_str());
+        }
+    }
+}
+
+/// Log a message about a bug and abort the process.
+pub(crate) fn abort(args: fmt::Arguments<'_>) -> ! {
+    // A subscriber may allocate or take locks, which may be what's broken.
+    #[cfg(feature = "tracing")]
+    {
+        if SINK.load(Ordering::Acquire) == TRACING {
+            Message::format(args).write_line(2);
+            unsafe {
+                libc::abort();
+            }
+        }
+    }
+    log(args);
+    unsafe {
+        libc::abort();
+    }
+}
+
+/// Log a panic that the glue caught and reported to the caller as an error.
+pub(crate) fn log_panic(message: &str) {
+    log(format_args!("service panicked, reporting NSS_STATUS_UNAVAIL: {}", message));
+}
+
+/// Log a lookup that the glue gave up on (see `NameService::LOOKUP_TIMEOUT`).
+pub(crate) fn log_timeout(function: &str, timeout: Duration) {
+    log(format_args!("{} took longer than {:?}, reporting NSS_STATUS_TRYAGAIN",
+                     function, timeout));
+}
+
+#[test]
+fn test_message_is_truncated_to_one_line() {
+    let long = "é".repeat(1000);
+    let message = Message::format(format_args!("two\nlines {}", long));
+    let text = message.as_str();
+    assert!(text.starts_with("nsswitch resolver: two lines éé"));
+    assert_eq!(text.len(), 509);  // 510 bytes of room, minus half an `é`
+}
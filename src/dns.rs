@@ -0,0 +1,292 @@
+//! The DNS wire format (RFC 1035), as much of it as this crate needs.
+
+// Each feature that needs this uses only part of it.
+#![allow(dead_code)]
+
+pub(crate) const TYPE_A: u16 = 1;
+pub(crate) const TYPE_CNAME: u16 = 5;
+pub(crate) const TYPE_SOA: u16 = 6;
+pub(crate) const TYPE_PTR: u16 = 12;
+pub(crate) const TYPE_AAAA: u16 = 28;
+pub(crate) const CLASS_IN: u16 = 1;
+pub(crate) const CLASS_ANY: u16 = 255;
+
+pub(crate) const FLAG_QR: u16 = 0x8000;
+pub(crate) const FLAG_TC: u16 = 0x0200;
+pub(crate) const FLAG_RD: u16 = 0x0100;
+pub(crate) const FLAG_RA: u16 = 0x0080;
+
+pub(crate) const RCODE_NOERROR: u16 = 0;
+pub(crate) const RCODE_FORMERR: u16 = 1;
+pub(crate) const RCODE_SERVFAIL: u16 = 2;
+pub(crate) const RCODE_NXDOMAIN: u16 = 3;
+pub(crate) const RCODE_NOTIMP: u16 = 4;
+
+/// The largest message that can go over UDP without EDNS.
+pub(crate) const MAX_UDP_MESSAGE: usize = 512;
+
+/// The fixed part of every message.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub(crate) struct Header {
+    pub id: u16,
+    pub flags: u16,
+    pub qdcount: u16,
+    pub ancount: u16,
+    pub nscount: u16,
+    pub arcount: u16,
+}
+
+impl Header {
+    pub fn opcode(&self) -> u16 {
+        (self.flags >> 11) & 0xf
+    }
+}
+
+/// A question. The name is in dotted form, without the final dot.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Question {
+    pub name: Vec<u8>,
+    pub qtype: u16,
+    pub qclass: u16,
+}
+
+/// A resource record of any type. The data is left as it is, except that
+/// the target name of a `CNAME` or `PTR` record, which may be compressed,
+/// is read into `target`.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Record<'a> {
+    pub name: Vec<u8>,
+    pub rtype: u16,
+    pub rclass: u16,
+    pub ttl: u32,
+    pub data: &'a [u8],
+    pub target: Option<Vec<u8>>,
+}
+
+/// A whole message. If the message was cut short, the records that didn't
+/// fit are left out.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Message<'a> {
+    pub header: Header,
+    pub questions: Vec<Question>,
+    pub answers: Vec<Record<'a>>,
+    pub authority: Vec<Record<'a>>,
+    pub additional: Vec<Record<'a>>,
+}
+
+/// Reads a message front to back. Every method returns `None` if the
+/// message is malformed or ends too soon.
+pub(crate) struct Reader<'a> {
+    msg: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(msg: &'a [u8]) -> Reader<'a> {
+        Reader { msg, pos: 0 }
+    }
+
+    pub fn u16(&mut self) -> Option<u16> {
+        let bytes = self.msg.get(self.pos..self.pos + 2)?;
+        self.pos += 2;
+        Some(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    pub fn u32(&mut self) -> Option<u32> {
+        let bytes = self.msg.get(self.pos..self.pos + 4)?;
+        self.pos += 4;
+        Some(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    pub fn header(&mut self) -> Option<Header> {
+        Some(Header {
+            id: self.u16()?,
+            flags: self.u16()?,
+            qdcount: self.u16()?,
+            ancount: self.u16()?,
+            nscount: self.u16()?,
+            arcount: self.u16()?,
+        })
+    }
+
+    /// Read a possibly compressed name. Names with a `.` or NUL inside a
+    /// label can't be written in dotted form, and are rejected.
+    pub fn name(&mut self) -> Option<Vec<u8>> {
+        let mut name = Vec::new();
+        let mut pos = self.pos;
+        let mut jumps = 0;
+        loop {
+            let len = *self.msg.get(pos)? as usize;
+            match len & 0xc0 {
+                0x00 if len == 0 => {
+                    if jumps == 0 {
+                        self.pos = pos + 1;
+                    }
+                    return if name.len() > 253 { None } else { Some(name) };
+                }
+                0x00 => {
+                    let label = self.msg.get(pos + 1..pos + 1 + len)?;
+                    if label.iter().any(|&b| b == b'.' || b == 0) {
+                        return None;
+                    }
+                    if !name.is_empty() {
+                        name.push(b'.');
+                    }
+                    name.extend_from_slice(label);
+                    pos += 1 + len;
+                }
+                0xc0 => {
+                    let target = (len & 0x3f) << 8 | *self.msg.get(pos + 1)? as usize;
+                    if jumps == 0 {
+                        self.pos = pos + 2;
+                    }
+                    // Pointers must point backwards, which rules out loops.
+                    jumps += 1;
+                    if target >= pos || jumps > 64 {
+                        return None;
+                    }
+                    pos = target;
+                }
+                _ => return None,
+            }
+        }
+    }
+
+    pub fn question(&mut self) -> Option<Question> {
+        Some(Question { name: self.name()?, qtype: self.u16()?, qclass: self.u16()? })
+    }
+
+    pub fn record(&mut self) -> Option<Record<'a>> {
+        let name = self.name()?;
+        let (rtype, rclass, ttl, len) = (self.u16()?, self.u16()?, self.u32()?, self.u16()? as usize);
+        let data = self.msg.get(self.pos..self.pos + len)?;
+        let target = match rtype {
+            TYPE_CNAME | TYPE_PTR => Some(Reader { msg: self.msg, pos: self.pos }.name()?),
+            _ => None,
+        };
+        self.pos += len;
+        Some(Record { name, rtype, rclass, ttl, data, target })
+    }
+
+    pub fn message(&mut self) -> Option<Message<'a>> {
+        let header = self.header()?;
+        let questions = (0..header.qdcount).map(|_| self.question()).collect::<Option<_>>()?;
+        let (ancount, nscount) = (header.ancount as usize, header.nscount as usize);
+        let mut answers = Vec::new();
+        while answers.len() < ancount + nscount + header.arcount as usize {
+            match self.record() {
+                Some(record) => answers.push(record),
+                None => break,
+            }
+        }
+        let additional = answers.split_off((ancount + nscount).min(answers.len()));
+        let authority = answers.split_off(ancount.min(answers.len()));
+        Some(Message { header, questions, answers, authority, additional })
+    }
+}
+
+/// Builds a message front to back, without compression.
+pub(crate) struct Writer {
+    buf: Vec<u8>,
+}
+
+impl Writer {
+    pub fn new(header: &Header) -> Writer {
+        let mut writer = Writer { buf: Vec::with_capacity(MAX_UDP_MESSAGE) };
+        for field in &[header.id, header.flags, header.qdcount, header.ancount, header.nscount, header.arcount] {
+            writer.u16(*field);
+        }
+        writer
+    }
+
+    pub fn len(&self) -> usize {
+        self.buf.len()
+    }
+
+    /// Throw away everything written after the first `len` bytes.
+    pub fn truncate(&mut self, len: usize) {
+        self.buf.truncate(len);
+    }
+
+    pub fn u16(&mut self, value: u16) {
+        self.buf.extend_from_slice(&value.to_be_bytes());
+    }
+
+    /// Write a name given in dotted form, with or without the final dot.
+    /// Returns `None`, having written nothing, if it isn't a valid name.
+    pub fn name(&mut self, name: &[u8]) -> Option<()> {
+        let name = name.strip_suffix(b".").unwrap_or(name);
+        if name.len() > 253 {
+            return None;
+        }
+        let start = self.buf.len();
+        if !name.is_empty() {
+            for label in name.split(|&b| b == b'.') {
+                if label.is_empty() || label.len() > 63 {
+                    self.buf.truncate(start);
+                    return None;
+                }
+                self.buf.push(label.len() as u8);
+                self.buf.extend_from_slice(label);
+            }
+        }
+        self.buf.push(0);
+        Some(())
+    }
+
+    pub fn question(&mut self, question: &Question) -> Option<()> {
+        self.name(&question.name)?;
+        self.u16(question.qtype);
+        self.u16(question.qclass);
+        Some(())
+    }
+
+    /// Write a resource record of class `IN`.
+    pub fn record(&mut self, name: &[u8], rtype: u16, ttl: u32, rdata: &[u8]) -> Option<()> {
+        self.name(name)?;
+        self.u16(rtype);
+        self.u16(CLASS_IN);
+        self.buf.extend_from_slice(&ttl.to_be_bytes());
+        self.u16(rdata.len() as u16);
+        self.buf.extend_from_slice(rdata);
+        Some(())
+    }
+
+    /// Write a record whose data is a name, like `CNAME` or `PTR`.
+    pub fn name_record(&mut self, name: &[u8], rtype: u16, ttl: u32, target: &[u8]) -> Option<()> {
+        let mut rdata = Writer { buf: Vec::new() };
+        rdata.name(target)?;
+        self.record(name, rtype, ttl, &rdata.buf)
+    }
+
+    /// Overwrite the answer count in the header.
+    pub fn set_ancount(&mut self, ancount: u16) {
+        self.buf[6..8].copy_from_slice(&ancount.to_be_bytes());
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        self.buf
+    }
+}
+
+#[test]
+fn test_names() {
+    let header = Header { id: 7, qdcount: 1, ..Header::default() };
+    let mut writer = Writer::new(&header);
+    writer.question(&Question { name: b"www.example.test.".to_vec(), qtype: TYPE_A, qclass: CLASS_IN }).unwrap();
+    let mut msg = writer.finish();
+    // A second name, "mail" + a pointer to "example.test" at offset 16.
+    msg.extend_from_slice(b"\x04mail\xc0\x10");
+
+    let mut reader = Reader::new(&msg);
+    assert_eq!(reader.header(), Some(header));
+    let question = reader.question().unwrap();
+    assert_eq!(question.name, b"www.example.test");
+    assert_eq!(reader.name().unwrap(), b"mail.example.test");
+    assert_eq!(reader.u16(), None);
+
+    // Pointer loops and labels containing dots are rejected.
+    assert_eq!(Reader::new(b"\xc0\x00").name(), None);
+    assert_eq!(Reader::new(b"\x03a.b\x00").name(), None);
+    assert!(Writer::new(&header).name(&[b'x'; 64]).is_none());
+}
@@ -0,0 +1,324 @@
+//! Asking DNS questions and turning the answers into host entries, for the
+//! services that ask DNS servers or, by multicast, the local link.
+
+// Each feature that needs this uses only part of it.
+#![allow(dead_code)]
+
+use crate::dns::{self, Header, Question, Reader, Record, Writer};
+use crate::errors::{Error, HostError, NssStatus, Result};
+use crate::interfaces::{AddressFamily, HostAddressList, HostAddresses, HostEntry, HostEntryWithTtl};
+use libc::{EAGAIN, EINVAL};
+use std::borrow::Cow;
+use std::collections::hash_map::RandomState;
+use std::ffi::{CStr, CString};
+use std::hash::{BuildHasher, Hasher};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::time::{Duration, Instant};
+
+/// What a server said about one question.
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct Answer {
+    pub rcode: u16,
+    /// The name at the end of the `CNAME` chain.
+    pub name: Vec<u8>,
+    /// The names before it.
+    pub aliases: Vec<Vec<u8>>,
+    /// The data of each record of the type asked for, or for `PTR`
+    /// records, the target name.
+    pub data: Vec<Vec<u8>>,
+    /// The smallest TTL of the records used.
+    pub ttl: Option<u32>,
+    /// How long a negative answer may be cached; see `negative_ttl`.
+    pub negative_ttl: Option<u32>,
+    /// Whether the server said the message was cut short (the `TC` bit),
+    /// so that records may be missing.
+    pub truncated: bool,
+}
+
+impl Answer {
+    /// The answer to `question` in `records`, read the way glibc's resolver
+    /// reads them: `CNAME`s lead from the name asked about to the canonical
+    /// name, and the records of the type asked for are the canonical name's.
+    pub fn new(rcode: u16, question: &Question, records: &[Record<'_>]) -> Answer {
+        let mut answer = Answer { rcode, name: question.name.clone(), ..Answer::default() };
+        let use_ttl = |answer: &mut Answer, ttl: u32| answer.ttl = Some(answer.ttl.map_or(ttl, |min| min.min(ttl)));
+        // mDNS uses the top bit of the class to mean "flush your cache".
+        let records = || records.iter().filter(|record| record.rclass & 0x7fff == dns::CLASS_IN);
+        // Follow the CNAME chain, but not around a loop.
+        while answer.aliases.len() < 16 {
+            let cname = records()
+                .find(|record| record.rtype == dns::TYPE_CNAME && same_name(&record.name, &answer.name));
+            match cname.and_then(|record| Some((record.ttl, record.target.clone()?))) {
+                Some((ttl, target)) => {
+                    use_ttl(&mut answer, ttl);
+                    answer.aliases.push(std::mem::replace(&mut answer.name, target));
+                }
+                None => break,
+            }
+        }
+        for record in records() {
+            if record.rtype == question.qtype && same_name(&record.name, &answer.name) {
+                use_ttl(&mut answer, record.ttl);
+                answer.data.push(record.target.clone().unwrap_or_else(|| record.data.to_vec()));
+            }
+        }
+        if answer.data.is_empty() {
+            answer.ttl = None;
+        }
+        answer
+    }
+
+    /// The error to report if this answer has nothing in it. A truncated
+    /// answer with nothing in it says nothing about the name, so it's
+    /// reported as a temporary failure, never cached as `NO_DATA`.
+    pub fn error(&self) -> Option<Error> {
+        if self.truncated && self.data.is_empty() {
+            Some(Error::with_host(NssStatus::TryAgain, EAGAIN, HostError::TryAgain))
+        } else if self.rcode != dns::RCODE_NOERROR || self.data.is_empty() {
+            Some(Error::from_dns_rcode(self.rcode, self.negative_ttl))
+        } else {
+            None
+        }
+    }
+
+    pub fn addrs(&self) -> impl Iterator<Item = IpAddr> + '_ {
+        self.data.iter().filter_map(|data| match data.len() {
+            4 => Some(IpAddr::V4(Ipv4Addr::new(data[0], data[1], data[2], data[3]))),
+            16 => {
+                let mut octets = [0_u8; 16];
+                octets.copy_from_slice(data);
+                Some(IpAddr::V6(Ipv6Addr::from(octets)))
+            }
+            _ => None,
+        })
+    }
+}
+
+/// How long a negative answer with `authority` in its authority section
+/// may be cached: the smaller of the `SOA` record's TTL and its `MINIMUM`
+/// field, the last in its data (RFC 2308, section 5).
+pub(crate) fn negative_ttl(authority: &[Record<'_>]) -> Option<u32> {
+    let soa = authority.iter().find(|record| record.rtype == dns::TYPE_SOA)?;
+    let minimum = soa.data.get(soa.data.len().checked_sub(4)?..)?;
+    Some(soa.ttl.min(u32::from_be_bytes([minimum[0], minimum[1], minimum[2], minimum[3]])))
+}
+
+pub(crate) fn same_name(a: &[u8], b: &[u8]) -> bool {
+    a.eq_ignore_ascii_case(b)
+}
+
+/// Whether `name` is `domain` or a name under it. Neither has a final dot.
+pub(crate) fn in_domain(name: &[u8], domain: &[u8]) -> bool {
+    domain.is_empty()
+        || same_name(name, domain)
+        || (name.len() > domain.len()
+            && same_name(&name[name.len() - domain.len()..], domain)
+            && name[name.len() - domain.len() - 1] == b'.')
+}
+
+pub(crate) fn address_type(af: AddressFamily) -> u16 {
+    match af {
+        AddressFamily::Ipv4 => dns::TYPE_A,
+        AddressFamily::Ipv6 => dns::TYPE_AAAA,
+    }
+}
+
+/// The name to look up the `PTR` records of `addr` under.
+pub(crate) fn reverse_name(addr: &IpAddr) -> Vec<u8> {
+    let mut name = String::new();
+    match *addr {
+        IpAddr::V4(ip) => {
+            for octet in ip.octets().iter().rev() {
+                name += &format!("{}.", octet);
+            }
+            name += "in-addr.arpa";
+        }
+        IpAddr::V6(ip) => {
+            for octet in ip.octets().iter().rev() {
+                name += &format!("{:x}.{:x}.", octet & 0xf, octet >> 4);
+            }
+            name += "ip6.arpa";
+        }
+    }
+    name.into_bytes()
+}
+
+/// A transaction ID, unpredictable enough to make forged answers hard to
+/// slip in.
+pub(crate) fn random_id() -> u16 {
+    RandomState::new().build_hasher().finish() as u16
+}
+
+/// Send `dest` a query with `flags` for each of `qtypes` about `name`, and
+/// wait up to `timeout` for the answers. An answer is `None` if none came
+/// in time. If `dest` is a multicast group, the first answer from anyone
+/// counts.
+pub(crate) fn exchange(socket: &UdpSocket, dest: SocketAddr, flags: u16, name: &[u8], qtypes: &[u16],
+                       timeout: Duration) -> io::Result<Vec<Option<Answer>>> {
+    let mut pending = Vec::new();
+    for &qtype in qtypes {
+        let header = Header { id: random_id(), flags, qdcount: 1, ..Header::default() };
+        let question = Question { name: name.to_vec(), qtype, qclass: dns::CLASS_IN };
+        let mut writer = Writer::new(&header);
+        writer.question(&question).ok_or_else(|| io::Error::from_raw_os_error(EINVAL))?;
+        socket.send_to(&writer.finish(), dest)?;
+        pending.push((header.id, question, None));
+    }
+
+    let deadline = Instant::now() + timeout;
+    let mut buf = [0_u8; 1500];
+    while pending.iter().any(|(_, _, answer)| answer.is_none()) {
+        let now = Instant::now();
+        if now >= deadline {
+            break;
+        }
+        socket.set_read_timeout(Some(deadline - now))?;
+        let (len, from) = match socket.recv_from(&mut buf) {
+            Ok(received) => received,
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock || err.kind() == io::ErrorKind::TimedOut => break,
+            Err(err) => return Err(err),
+        };
+        if from != dest && !dest.ip().is_multicast() {
+            continue;
+        }
+        // Ignore anything that isn't an answer to one of our questions.
+        let message = match Reader::new(&buf[..len]).message() {
+            Some(message) if message.header.flags & dns::FLAG_QR != 0 => message,
+            _ => continue,
+        };
+        let echoed = match message.questions.first() {
+            Some(echoed) => echoed,
+            None => continue,
+        };
+        for (id, question, answer) in &mut pending {
+            if *id == message.header.id && answer.is_none()
+                && echoed.qtype == question.qtype && same_name(&echoed.name, &question.name)
+            {
+                let mut new = Answer::new(message.header.flags & 0xf, question, &message.answers);
+                new.negative_ttl = negative_ttl(&message.authority);
+                new.truncated = message.header.flags & dns::FLAG_TC != 0;
+                *answer = Some(new);
+            }
+        }
+    }
+    Ok(pending.into_iter().map(|(_, _, answer)| answer).collect())
+}
+
+fn c_string(name: Vec<u8>) -> Option<Cow<'static, CStr>> {
+    CString::new(name).ok().map(Cow::Owned)
+}
+
+/// The entry for `answer`, an answer to an `A` or `AAAA` question about
+/// `name`.
+pub(crate) fn host_entry(name: &CStr, af: AddressFamily, answer: Answer) -> Result<HostEntryWithTtl<'_>> {
+    if let Some(err) = answer.error() {
+        return Err(err);
+    }
+    let addr_list = match af {
+        AddressFamily::Ipv4 => HostAddressList::V4(answer.addrs().filter_map(|addr| match addr {
+            IpAddr::V4(ip) => Some(ip),
+            IpAddr::V6(_) => None,
+        }).collect()),
+        AddressFamily::Ipv6 => HostAddressList::V6(answer.addrs().filter_map(|addr| match addr {
+            IpAddr::V4(_) => None,
+            IpAddr::V6(ip) => Some(ip),
+        }).collect()),
+    };
+    let ttl = answer.ttl;
+    let entry = HostEntry {
+        name: c_string(answer.name).unwrap_or(Cow::Borrowed(name)),
+        aliases: answer.aliases.into_iter().filter_map(c_string).collect(),
+        addr_list,
+    };
+    Ok(HostEntryWithTtl { entry, ttl })
+}
+
+/// The addresses in `answers`, answers to `AAAA` and `A` questions about
+/// `name`. Either family will do. If there are neither, `NXDOMAIN` trumps
+/// other failures, and those trump `NO_DATA`.
+pub(crate) fn host_addresses<'a>(name: &'a CStr, answers: &[Answer]) -> Result<HostAddresses<'a>> {
+    let found: Vec<&Answer> = answers.iter().filter(|answer| answer.error().is_none()).collect();
+    if found.is_empty() {
+        let worst = answers.iter()
+            .max_by_key(|answer| match answer.rcode {
+                dns::RCODE_NXDOMAIN => 2,
+                dns::RCODE_NOERROR => 0,
+                _ => 1,
+            })
+            .and_then(Answer::error);
+        return Err(worst.unwrap_or_else(|| Error::from_dns_rcode(dns::RCODE_NXDOMAIN, None)));
+    }
+    Ok(HostAddresses {
+        name: c_string(found[0].name.clone()).unwrap_or(Cow::Borrowed(name)),
+        addrs: found.iter().flat_map(|answer| answer.addrs()).collect(),
+        ttl: found.iter().filter_map(|answer| answer.ttl).min(),
+    })
+}
+
+/// The entry for `answer`, an answer to a `PTR` question about `addr`.
+pub(crate) fn reverse_entry(addr: &IpAddr, answer: Answer) -> Result<HostEntryWithTtl<'static>> {
+    if let Some(err) = answer.error() {
+        return Err(err);
+    }
+    let negative_ttl = answer.negative_ttl;
+    let mut names = answer.data.into_iter().filter_map(c_string);
+    let name = names.next().ok_or_else(|| Error::from_dns_rcode(dns::RCODE_NOERROR, negative_ttl))?;
+    let addr_list = match *addr {
+        IpAddr::V4(ip) => HostAddressList::V4(vec![ip]),
+        IpAddr::V6(ip) => HostAddressList::V6(vec![ip]),
+    };
+    let entry = HostEntry { name, aliases: names.collect(), addr_list };
+    Ok(HostEntryWithTtl { entry, ttl: answer.ttl })
+}
+
+#[test]
+fn test_answers() {
+    use crate::dns::{Header, Reader, Writer};
+
+    let mut writer = Writer::new(&Header { ancount: 4, ..Header::default() });
+    writer.name_record(b"www.test", dns::TYPE_CNAME, 600, b"Web.test").unwrap();
+    writer.record(b"web.test", dns::TYPE_A, 300, &[10, 0, 0, 1]).unwrap();
+    writer.record(b"web.test", dns::TYPE_AAAA, 60, &Ipv6Addr::LOCALHOST.octets()).unwrap();
+    writer.record(b"other.test", dns::TYPE_A, 5, &[10, 0, 0, 2]).unwrap();
+    let msg = writer.finish();
+    let records = Reader::new(&msg).message().unwrap().answers;
+    let question = |qtype| Question { name: b"www.test".to_vec(), qtype, qclass: dns::CLASS_IN };
+
+    let answer = Answer::new(dns::RCODE_NOERROR, &question(dns::TYPE_A), &records);
+    assert_eq!((&answer.name[..], &answer.aliases[..], answer.ttl), (&b"Web.test"[..], &[b"www.test".to_vec()][..], Some(300)));
+    assert_eq!(answer.addrs().collect::<Vec<_>>(), ["10.0.0.1".parse::<IpAddr>().unwrap()]);
+    let answers = [Answer::new(dns::RCODE_NOERROR, &question(dns::TYPE_AAAA), &records), answer];
+    let name = CStr::from_bytes_with_nul(b"www.test\0").unwrap();
+    let found = host_addresses(name, &answers).unwrap();
+    assert_eq!((found.addrs.len(), found.ttl), (2, Some(60)));
+
+    let nodata = Answer::new(dns::RCODE_NOERROR, &question(dns::TYPE_PTR), &records);
+    let err = host_entry(name, AddressFamily::Ipv4, nodata).unwrap_err();
+    assert_eq!(err.host_error(), Some(HostError::NoData));
+    let answers = [Answer::new(dns::RCODE_NOERROR, &question(dns::TYPE_PTR), &records),
+                   Answer { rcode: dns::RCODE_NXDOMAIN, ..Answer::default() }];
+    assert_eq!(host_addresses(name, &answers).unwrap_err().host_error(), Some(HostError::HostNotFound));
+
+    // A truncated answer with no records isn't a negative answer.
+    let truncated = Answer { truncated: true, ..Answer::new(dns::RCODE_NOERROR, &question(dns::TYPE_PTR), &records) };
+    let err = truncated.error().unwrap();
+    assert_eq!((err.status(), err.host_error()), (NssStatus::TryAgain, Some(HostError::TryAgain)));
+
+    // A negative answer may be cached as long as its SOA record says.
+    let mut writer = Writer::new(&Header { nscount: 1, ..Header::default() });
+    let mut soa = vec![0, 0];
+    for field in &[1_u32, 7200, 900, 86400, 300] {
+        soa.extend_from_slice(&field.to_be_bytes());
+    }
+    writer.record(b"test", dns::TYPE_SOA, 3600, &soa).unwrap();
+    let msg = writer.finish();
+    let message = Reader::new(&msg).message().unwrap();
+    assert_eq!((message.answers.len(), message.authority.len()), (0, 1));
+    let mut nxdomain = Answer::new(dns::RCODE_NXDOMAIN, &question(dns::TYPE_A), &message.answers);
+    nxdomain.negative_ttl = negative_ttl(&message.authority);
+    assert_eq!(nxdomain.error().unwrap().ttl(), Some(300));
+
+    assert_eq!(reverse_name(&"2001:db8::1".parse().unwrap()),
+               b"1.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.8.b.d.0.1.0.0.2.ip6.arpa".to_vec());
+}
@@ -0,0 +1,413 @@
+//! A ready-made `hosts` service that forwards lookups to DNS servers chosen
+//! by the domain of the name being looked up.
+
+use crate::diag;
+use crate::dns_client::{self, in_domain, random_id, Answer};
+use crate::errors::{Error, HostError, NssStatus, Result};
+use crate::interfaces::{AddressFamily, HostAddresses, HostEntry, HostEntryWithTtl, NameService};
+use hickory_proto::op::{Message, MessageType, OpCode, Query, ResponseCode};
+use hickory_proto::rr::{DNSClass, Name, RData, RecordType};
+use libc::{EAGAIN, EINVAL};
+use std::ffi::CStr;
+use std::io::{self, Read, Write};
+use std::marker::PhantomData;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream, UdpSocket};
+use std::time::{Duration, Instant};
+
+/// Which DNS servers a `DnsForwarderService` asks about which names.
+pub trait DnsForwarderConfig: 'static {
+    /// Domains and the servers for names in them. A server is an address,
+    /// with or without a port (53 by default). The domain `.` matches every
+    /// name.
+    const ROUTES: &'static [(&'static str, &'static [&'static str])];
+
+    /// How long to wait for each server to answer.
+    const TIMEOUT: Duration = Duration::from_secs(2);
+
+    /// How many times to go through the list of servers before giving up.
+    const ATTEMPTS: u32 = 2;
+}
+
+/// A `hosts` service that sends lookups for some domains to particular DNS
+/// servers, such as names in the corporate domain to the resolver at the
+/// other end of a VPN:
+///
+/// ```ignore
+/// struct Corp;
+///
+/// impl DnsForwarderConfig for Corp {
+///     const ROUTES: &'static [(&'static str, &'static [&'static str])] = &[
+///         ("corp.example", &["10.8.0.1", "10.8.0.2"]),
+///         ("10.in-addr.arpa", &["10.8.0.1", "10.8.0.2"]),
+///     ];
+/// }
+///
+/// nssglue_hosts!("corpdns", DnsForwarderService<Corp>);
+/// ```
+///
+/// A route matches its domain and every name under it, and the longest
+/// matching domain wins. Names no route matches are left to the next
+/// service, so with `hosts: files corpdns dns` in `nsswitch.conf` every
+/// other name goes to the usual resolver. Reverse lookups are routed by
+/// their `in-addr.arpa` or `ip6.arpa` name.
+///
+/// The servers are asked in order, over UDP, with recursion desired. An
+/// answer of `NXDOMAIN` or `NOERROR` is final; after a timeout, `SERVFAIL`,
+/// or `REFUSED` the next server gets a try. Answers are read the way
+/// glibc's resolver reads them: `CNAME`s lead to the canonical name, whose
+/// owners become aliases, and the TTL reported by `gethostbyname3_r` and
+/// `gethostbyname4_r` is the smallest of all the records used.
+/// `gethostbyname4_r` asks for `AAAA` and `A` records at once. A truncated
+/// answer is asked for again over TCP, as glibc does; if that fails, the
+/// next server gets a try. There is no caching.
+pub struct DnsForwarderService<C>(PhantomData<C>);
+
+/// The servers for `name`, from the longest matching route.
+fn route<'a>(routes: &[(&str, &'a [&'a str])], name: &[u8]) -> Option<&'a [&'a str]> {
+    let name = name.strip_suffix(b".").unwrap_or(name);
+    routes.iter()
+        .map(|&(domain, servers)| (domain.trim_end_matches('.').as_bytes(), servers))
+        .filter(|&(domain, _)| in_domain(name, domain))
+        .max_by_key(|&(domain, _)| domain.len())
+        .map(|(_, servers)| servers)
+}
+
+fn address_type(af: AddressFamily) -> RecordType {
+    match af {
+        AddressFamily::Ipv4 => RecordType::A,
+        AddressFamily::Ipv6 => RecordType::AAAA,
+    }
+}
+
+fn parse_server(server: &str) -> Option<SocketAddr> {
+    server.parse().ok().or_else(|| Some(SocketAddr::new(server.parse().ok()?, 53)))
+}
+
+fn invalid(err: impl ToString) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, err.to_string())
+}
+
+/// The name to ask about, or `None` if `name` isn't one DNS can carry.
+fn query_name(name: &[u8]) -> Option<Name> {
+    let mut name = Name::from_ascii(std::str::from_utf8(name).ok()?).ok()?;
+    name.set_fqdn(true);
+    Some(name)
+}
+
+/// A name in dotted form, without the final dot, as `Answer` has them.
+fn dotted(name: &Name) -> Vec<u8> {
+    let mut name = name.to_ascii();
+    if name.ends_with('.') {
+        name.pop();
+    }
+    name.into_bytes()
+}
+
+/// A query for `qtype` records about `name`, with recursion desired.
+fn query_message(name: &Name, qtype: RecordType) -> Message {
+    let mut message = Message::new(random_id(), MessageType::Query, OpCode::Query);
+    message.metadata.recursion_desired = true;
+    message.add_query(Query::query(name.clone(), qtype));
+    message
+}
+
+/// Whether `reply` answers `query`: a response with the same ID and
+/// question.
+fn is_reply_to(query: &Message, reply: &Message) -> bool {
+    reply.metadata.message_type == MessageType::Response
+        && reply.metadata.id == query.metadata.id
+        && reply.queries.first() == query.queries.first()
+}
+
+/// What `reply` says about `query`, read the way glibc's resolver reads
+/// it: `CNAME`s lead from the name asked about to the canonical name, and
+/// the records of the type asked for are the canonical name's.
+fn read_answer(query: &Query, reply: &Message) -> Answer {
+    let mut name = query.name().clone();
+    let mut aliases = Vec::new();
+    let mut ttl: Option<u32> = None;
+    let records = || reply.answers.iter().filter(|record| record.dns_class == DNSClass::IN);
+    // Follow the CNAME chain, but not around a loop.
+    while aliases.len() < 16 {
+        let cname = records().find_map(|record| match record.data {
+            RData::CNAME(ref target) if record.name == name => Some((record.ttl, target.0.clone())),
+            _ => None,
+        });
+        match cname {
+            Some((cname_ttl, target)) => {
+                ttl = Some(ttl.map_or(cname_ttl, |min| min.min(cname_ttl)));
+                aliases.push(std::mem::replace(&mut name, target));
+            }
+            None => break,
+        }
+    }
+    let mut data = Vec::new();
+    for record in records().filter(|record| record.name == name) {
+        data.push(match (&record.data, query.query_type()) {
+            (RData::A(a), RecordType::A) => a.0.octets().to_vec(),
+            (RData::AAAA(aaaa), RecordType::AAAA) => aaaa.0.octets().to_vec(),
+            (RData::PTR(ptr), RecordType::PTR) => dotted(&ptr.0),
+            _ => continue,
+        });
+        ttl = Some(ttl.map_or(record.ttl, |min| min.min(record.ttl)));
+    }
+    // How long a negative answer may be cached: the smaller of the `SOA`
+    // record's TTL and its `MINIMUM` field (RFC 2308, section 5).
+    let negative_ttl = reply.authorities.iter().find_map(|record| match record.data {
+        RData::SOA(ref soa) => Some(record.ttl.min(soa.minimum)),
+        _ => None,
+    });
+    Answer {
+        rcode: u16::from(reply.metadata.response_code),
+        name: dotted(&name),
+        aliases: aliases.iter().map(dotted).collect(),
+        ttl: if data.is_empty() { None } else { ttl },
+        data,
+        negative_ttl,
+        truncated: reply.metadata.truncation,
+    }
+}
+
+/// Ask `server` `query` over TCP, for an answer that didn't fit in a UDP
+/// message.
+fn exchange_tcp(server: SocketAddr, query: &Message, timeout: Duration) -> io::Result<Message> {
+    let mut stream = TcpStream::connect_timeout(&server, timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+    // Each message is preceded by its length, as two bytes.
+    let message = query.to_vec().map_err(invalid)?;
+    let mut framed = (message.len() as u16).to_be_bytes().to_vec();
+    framed.extend_from_slice(&message);
+    stream.write_all(&framed)?;
+    let mut len = [0; 2];
+    stream.read_exact(&mut len)?;
+    let mut reply = vec![0; u16::from_be_bytes(len) as usize];
+    stream.read_exact(&mut reply)?;
+    let reply = Message::from_vec(&reply).map_err(invalid)?;
+    if !is_reply_to(query, &reply) {
+        return Err(invalid("reply to a different question"));
+    }
+    Ok(reply)
+}
+
+/// Ask `server` each of `qtypes` about `name`, all at once over UDP, and
+/// wait for all the answers. Answers that were truncated are asked for
+/// again over TCP.
+fn exchange(server: SocketAddr, name: &Name, qtypes: &[RecordType], timeout: Duration) -> io::Result<Vec<Answer>> {
+    let local: IpAddr = if server.is_ipv4() { Ipv4Addr::UNSPECIFIED.into() } else { Ipv6Addr::UNSPECIFIED.into() };
+    let socket = UdpSocket::bind((local, 0))?;
+    socket.connect(server)?;
+    let mut pending = Vec::new();
+    for &qtype in qtypes {
+        let query = query_message(name, qtype);
+        socket.send(&query.to_vec().map_err(invalid)?)?;
+        pending.push((query, None));
+    }
+
+    let deadline = Instant::now() + timeout;
+    let mut buf = [0_u8; 4096];
+    while pending.iter().any(|(_, reply)| reply.is_none()) {
+        let now = Instant::now();
+        if now >= deadline {
+            return Err(io::ErrorKind::TimedOut.into());
+        }
+        socket.set_read_timeout(Some(deadline - now))?;
+        let len = match socket.recv(&mut buf) {
+            Ok(len) => len,
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => return Err(io::ErrorKind::TimedOut.into()),
+            Err(err) => return Err(err),
+        };
+        // Ignore anything that isn't an answer to one of our questions.
+        if let Ok(reply) = Message::from_vec(&buf[..len]) {
+            if let Some(slot) = pending.iter_mut().find(|(query, slot)| slot.is_none() && is_reply_to(query, &reply)) {
+                slot.1 = Some(reply);
+            }
+        }
+    }
+
+    pending.into_iter().map(|(query, reply)| {
+        let mut reply = reply.expect("every question has a reply");
+        if reply.metadata.truncation {
+            reply = exchange_tcp(server, &query, timeout)?;
+        }
+        Ok(read_answer(&query.queries[0], &reply))
+    }).collect()
+}
+
+/// Ask `servers` each of `qtypes` about `name`, until one gives a final
+/// answer or they've all had `attempts` tries.
+fn forward(servers: &[&str], name: &[u8], qtypes: &[RecordType], timeout: Duration, attempts: u32)
+           -> Result<Vec<Answer>> {
+    let addrs = servers.iter()
+        .map(|server| parse_server(server).ok_or(server))
+        .collect::<std::result::Result<Vec<_>, _>>()
+        .map_err(|server| {
+            diag::log(format_args!("not a DNS server address: {:?}", server));
+            Error::with_errno(NssStatus::Unavailable, EINVAL)
+        })?;
+    let query = query_name(name)
+        .ok_or_else(|| Error::with_host(NssStatus::Unavailable, EINVAL, HostError::NoRecovery))?;
+    let is_final = |answer: &Answer| {
+        let rcode = ResponseCode::from_low(answer.rcode as u8);
+        !answer.truncated && (rcode == ResponseCode::NoError || rcode == ResponseCode::NXDomain)
+    };
+    let mut last = None;
+    for _ in 0..attempts {
+        for &addr in &addrs {
+            match exchange(addr, &query, qtypes, timeout) {
+                Ok(answers) if answers.iter().all(is_final) => return Ok(answers),
+                Ok(answers) => last = Some(answers),
+                Err(err) => diag::log(format_args!("no answer from {} about {}: {}",
+                                                   addr, String::from_utf8_lossy(name), err)),
+            }
+        }
+    }
+    last.ok_or_else(|| Error::with_host(NssStatus::TryAgain, EAGAIN, HostError::TryAgain))
+}
+
+impl<C: DnsForwarderConfig> DnsForwarderService<C> {
+    /// Forward the question, or return `None` if no route matches `name`.
+    fn forward(name: &[u8], qtypes: &[RecordType]) -> Result<Option<Vec<Answer>>> {
+        match route(C::ROUTES, name) {
+            None => Ok(None),
+            Some(servers) => forward(servers, name, qtypes, C::TIMEOUT, C::ATTEMPTS).map(Some),
+        }
+    }
+}
+
+impl<C: DnsForwarderConfig> NameService for DnsForwarderService<C> {
+    fn gethostbyname2_r(name: &CStr, af: AddressFamily) -> Result<Option<HostEntry<'_>>> {
+        Ok(Self::gethostbyname3_r(name, af)?.map(|found| found.entry))
+    }
+
+    fn gethostbyaddr_r(addr: &IpAddr) -> Result<Option<HostEntry<'_>>> {
+        Ok(Self::gethostbyaddr2_r(addr)?.map(|found| found.entry))
+    }
+
+    fn gethostbyname3_r(name: &CStr, af: AddressFamily) -> Result<Option<HostEntryWithTtl<'_>>> {
+        match Self::forward(name.to_bytes(), &[address_type(af)])? {
+            Some(mut answers) => dns_client::host_entry(name, af, answers.remove(0)).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn gethostbyname4_r(name: &CStr) -> Result<Option<HostAddresses<'_>>> {
+        match Self::forward(name.to_bytes(), &[RecordType::AAAA, RecordType::A])? {
+            Some(answers) => dns_client::host_addresses(name, &answers).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn gethostbyaddr2_r(addr: &IpAddr) -> Result<Option<HostEntryWithTtl<'_>>> {
+        match Self::forward(&dns_client::reverse_name(addr), &[RecordType::PTR])? {
+            Some(mut answers) => dns_client::reverse_entry(addr, answers.remove(0)).map(Some),
+            None => Ok(None),
+        }
+    }
+}
+
+#[test]
+fn test_dns_forwarder() {
+    use hickory_proto::rr::rdata::{A, AAAA, CNAME, PTR};
+    use hickory_proto::rr::Record;
+    use std::net::TcpListener;
+    use std::thread;
+
+    // A server that knows www.corp.test, a CNAME for web.corp.test,
+    // v4.corp.test, and big.corp.test, whose answer only fits over TCP;
+    // everything else is NXDOMAIN.
+    fn respond(query: &Message, over_udp: bool) -> Vec<u8> {
+        let question = &query.queries[0];
+        let name = |name: &str| Name::from_ascii(name).unwrap();
+        let mut reply = Message::response(query.metadata.id, OpCode::Query);
+        reply.add_query(question.clone());
+        let mut answer = |owner: &str, ttl, rdata| {
+            reply.add_answer(Record::from_rdata(name(owner), ttl, rdata));
+        };
+        match (&question.name().to_ascii()[..], question.query_type()) {
+            ("www.corp.test.", qtype) => {
+                answer("www.corp.test.", 600, RData::CNAME(CNAME(name("web.corp.test."))));
+                if qtype == RecordType::A {
+                    answer("web.corp.test.", 300, RData::A(A::new(10, 0, 0, 1)));
+                    answer("web.corp.test.", 30, RData::A(A::new(10, 0, 0, 2)));
+                } else if qtype == RecordType::AAAA {
+                    answer("web.corp.test.", 60, RData::AAAA(AAAA(Ipv6Addr::LOCALHOST)));
+                }
+            }
+            ("v4.corp.test.", RecordType::A) => answer("v4.corp.test.", 100, RData::A(A::new(10, 0, 0, 4))),
+            ("v4.corp.test.", _) => {}
+            ("big.corp.test.", _) if over_udp => reply.metadata.truncation = true,
+            ("big.corp.test.", _) => {
+                for i in 0..64 {
+                    answer("big.corp.test.", 100, RData::A(A::new(10, 1, 0, i)));
+                }
+            }
+            ("1.0.0.10.in-addr.arpa.", _) => {
+                answer("1.0.0.10.in-addr.arpa.", 100, RData::PTR(PTR(name("web.corp.test."))))
+            }
+            _ => reply.metadata.response_code = ResponseCode::NXDomain,
+        }
+        reply.to_vec().unwrap()
+    }
+
+    let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let addr = server.local_addr().unwrap();
+    let tcp_server = TcpListener::bind(addr).unwrap();
+    server.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let serving = thread::spawn(move || {
+        let mut buf = [0_u8; 512];
+        // Two lookups of both families, one of one family, one PTR, and
+        // one that has to be asked again over TCP.
+        for _ in 0..7 {
+            let (len, peer) = server.recv_from(&mut buf).unwrap();
+            let query = Message::from_vec(&buf[..len]).unwrap();
+            server.send_to(&respond(&query, true), peer).unwrap();
+        }
+    });
+    let tcp_serving = thread::spawn(move || {
+        let (mut stream, _) = tcp_server.accept().unwrap();
+        let mut len = [0; 2];
+        stream.read_exact(&mut len).unwrap();
+        let mut query = vec![0; u16::from_be_bytes(len) as usize];
+        stream.read_exact(&mut query).unwrap();
+        let reply = respond(&Message::from_vec(&query).unwrap(), false);
+        stream.write_all(&(reply.len() as u16).to_be_bytes()).unwrap();
+        stream.write_all(&reply).unwrap();
+    });
+
+    let addr = addr.to_string();
+    let servers = &[&addr[..]][..];
+    let routes = [("corp.test.", servers), ("10.in-addr.arpa", servers)];
+    assert_eq!(route(&routes, b"WWW.Corp.Test."), Some(servers));
+    assert_eq!(route(&routes, b"notcorp.test"), None);
+    let ask = |name: &[u8], qtypes: &[RecordType]| {
+        let servers = route(&routes, name).unwrap();
+        forward(servers, name, qtypes, Duration::from_secs(5), 1)
+    };
+
+    let answers = ask(b"www.corp.test", &[RecordType::AAAA, RecordType::A]).unwrap();
+    assert_eq!(answers[0].name, b"web.corp.test");
+    assert_eq!(answers[0].aliases, [b"www.corp.test".to_vec()]);
+    assert_eq!((answers[0].addrs().count(), answers[0].ttl), (1, Some(60)));
+    assert_eq!((answers[1].addrs().count(), answers[1].ttl), (2, Some(30)));
+
+    let answers = ask(b"v4.corp.test", &[RecordType::AAAA, RecordType::A]).unwrap();
+    assert_eq!(answers[0].error().unwrap().host_error(), Some(HostError::NoData));
+    assert_eq!(answers[1].data, [vec![10, 0, 0, 4]]);
+    let answers = ask(b"nowhere.corp.test", &[RecordType::A]).unwrap();
+    assert_eq!(answers[0].error().unwrap().host_error(), Some(HostError::HostNotFound));
+    let answers = ask(&dns_client::reverse_name(&"10.0.0.1".parse().unwrap()), &[RecordType::PTR]).unwrap();
+    assert_eq!(answers[0].data, [b"web.corp.test".to_vec()]);
+
+    // The truncated answer is asked for again over TCP.
+    let answers = ask(b"big.corp.test", &[RecordType::A]).unwrap();
+    assert!(!answers[0].truncated);
+    assert_eq!(answers[0].addrs().count(), 64);
+
+    // Nobody listens on the discard port.
+    let err = forward(&["127.0.0.1:9"], b"x.test", &[RecordType::A], Duration::from_millis(50), 1).unwrap_err();
+    assert_eq!(err.status(), NssStatus::TryAgain);
+
+    serving.join().unwrap();
+    tcp_serving.join().unwrap();
+}
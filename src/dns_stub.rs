@@ -0,0 +1,331 @@
+//! Answering DNS queries from a `NameService`, for systems whose libc has no
+//! name service switch.
+//!
+//! Android's bionic never loads NSS modules: every lookup goes to `netd`,
+//! which asks DNS servers. To put a service in that path, run it as a small
+//! DNS server on the device, with `serve_dns`, and point the resolver at it
+//! (through a local VPN's DNS setting, or the network configuration on
+//! rooted and test devices). The same works for musl-based systems.
+//!
+//! The stub answers `A`, `AAAA`, and `PTR` queries; queries of other types
+//! for names the service knows get empty answers. It speaks UDP only and
+//! doesn't support EDNS, so it sends at most 512 bytes, leaving out the
+//! addresses that don't fit rather than asking the client to retry over
+//! TCP.
+
+use crate::diag;
+use crate::dns::{self, Header, Question, Reader, Writer};
+use crate::errors::{Error, HostError, Result};
+use crate::hostname::is_valid_hostname;
+use crate::interfaces::{AddressFamily, HostAddressList, HostEntryWithTtl, NameService};
+use std::ffi::{CStr, CString};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, UdpSocket};
+use std::panic::{self, AssertUnwindSafe};
+
+/// The TTL of answers when the service doesn't give one (see
+/// `NameService::gethostbyname3_r`). Zero means clients shouldn't cache
+/// them, so changes in the service show up at once.
+const DEFAULT_TTL: u32 = 0;
+
+/// Answer DNS queries arriving on `socket` from `T`, forever.
+///
+/// Malformed packets are ignored. This returns only if receiving fails;
+/// failures to send a response are ignored, since UDP clients retry.
+/// Answers carry the TTL from `NameService::gethostbyname3_r`, or else 0,
+/// so clients don't cache them.
+///
+/// ```no_run
+/// # use nsswitch_service::{serve_dns, AddressFamily, HostEntry, NameService, Result};
+/// # use std::ffi::CStr;
+/// # use std::net::{IpAddr, UdpSocket};
+/// # struct MyService;
+/// # impl NameService for MyService {
+/// #     fn gethostbyname2_r(_: &CStr, _: AddressFamily) -> Result<Option<HostEntry<'_>>> { Ok(None) }
+/// #     fn gethostbyaddr_r(_: &IpAddr) -> Result<Option<HostEntry<'_>>> { Ok(None) }
+/// # }
+/// let socket = UdpSocket::bind("127.0.0.1:5353")?;
+/// serve_dns::<MyService>(&socket)?;
+/// # Ok::<(), std::io::Error>(())
+/// ```
+pub fn serve_dns<T: NameService>(socket: &UdpSocket) -> io::Result<()> {
+    let mut buf = [0_u8; 1500];
+    loop {
+        let (len, peer) = socket.recv_from(&mut buf)?;
+        if let Some(response) = answer_dns_query::<T>(&buf[..len]) {
+            let _ = socket.send_to(&response, peer);
+        }
+    }
+}
+
+/// The response to the DNS query `query`, answered from `T`, or `None` if
+/// `query` is too malformed to answer (or isn't a query at all).
+pub fn answer_dns_query<T: NameService>(query: &[u8]) -> Option<Vec<u8>> {
+    let mut reader = Reader::new(query);
+    let header = reader.header()?;
+    if header.flags & dns::FLAG_QR != 0 {
+        return None;
+    }
+    let question = if header.qdcount == 1 { reader.question() } else { None };
+    let question = match question {
+        Some(question) => question,
+        None => return Some(response(&header, None, dns::RCODE_FORMERR, &[])),
+    };
+    if header.opcode() != 0 || (question.qclass != dns::CLASS_IN && question.qclass != dns::CLASS_ANY) {
+        return Some(response(&header, Some(&question), dns::RCODE_NOTIMP, &[]));
+    }
+
+    let answer = panic::catch_unwind(AssertUnwindSafe(|| answer::<T>(&question)));
+    let (rcode, records) = match answer {
+        Ok(Ok(records)) => (dns::RCODE_NOERROR, records),
+        Ok(Err(err)) => (rcode_for(&err), vec![]),
+        Err(payload) => {
+            let err = Error::from_panic(payload);
+            diag::log(format_args!("service panicked, answering SERVFAIL: {}",
+                                   err.panic_message().unwrap_or("")));
+            (dns::RCODE_SERVFAIL, vec![])
+        }
+    };
+    Some(response(&header, Some(&question), rcode, &records))
+}
+
+/// A record to put in the answer section.
+enum Record {
+    Address(Vec<u8>, IpAddr, u32),
+    Name(Vec<u8>, u16, Vec<u8>, u32),
+}
+
+fn rcode_for(err: &Error) -> u16 {
+    match err.host_error() {
+        Some(HostError::NoData) => dns::RCODE_NOERROR,
+        Some(HostError::HostNotFound) => dns::RCODE_NXDOMAIN,
+        _ => dns::RCODE_SERVFAIL,
+    }
+}
+
+fn not_found() -> Error {
+    Error::from_dns_rcode(dns::RCODE_NXDOMAIN, None)
+}
+
+fn no_data() -> Error {
+    Error::from_dns_rcode(dns::RCODE_NOERROR, None)
+}
+
+/// The answer records for `question`, or an error that says which `RCODE`
+/// to answer with.
+fn answer<T: NameService>(question: &Question) -> Result<Vec<Record>> {
+    if question.qtype == dns::TYPE_PTR {
+        return answer_ptr::<T>(question);
+    }
+    let name = CString::new(question.name.clone()).map_err(|_| not_found())?;
+    if T::VALIDATE_HOSTNAMES && !is_valid_hostname(&name) {
+        return Err(not_found());
+    }
+    let af = match question.qtype {
+        dns::TYPE_A => AddressFamily::Ipv4,
+        dns::TYPE_AAAA => AddressFamily::Ipv6,
+        _ => {
+            // Some other type: say whether the name exists.
+            return if exists::<T>(&name, AddressFamily::Ipv4)? || exists::<T>(&name, AddressFamily::Ipv6)? {
+                Ok(vec![])
+            } else {
+                Err(not_found())
+            };
+        }
+    };
+    let HostEntryWithTtl { entry, ttl } = match T::gethostbyname3_r(&name, af)? {
+        Some(found) => found,
+        None => {
+            let other = match af {
+                AddressFamily::Ipv4 => AddressFamily::Ipv6,
+                AddressFamily::Ipv6 => AddressFamily::Ipv4,
+            };
+            return if exists::<T>(&name, other)? { Ok(vec![]) } else { Err(not_found()) };
+        }
+    };
+    let ttl = ttl.unwrap_or(DEFAULT_TTL);
+    let mut records = vec![];
+    let mut owner = question.name.clone();
+    let canonical = entry.name.to_bytes();
+    if !canonical.is_empty() && !canonical.eq_ignore_ascii_case(&question.name) {
+        records.push(Record::Name(owner, dns::TYPE_CNAME, canonical.to_vec(), ttl));
+        owner = canonical.to_vec();
+    }
+    match entry.addr_list {
+        HostAddressList::V4(ref addrs) if af == AddressFamily::Ipv4 => {
+            records.extend(addrs.iter().map(|&addr| Record::Address(owner.clone(), IpAddr::V4(addr), ttl)));
+        }
+        HostAddressList::V6(ref addrs) if af == AddressFamily::Ipv6 => {
+            records.extend(addrs.iter().map(|&addr| Record::Address(owner.clone(), IpAddr::V6(addr), ttl)));
+        }
+        _ => {}
+    }
+    Ok(records)
+}
+
+/// True if `T` has an entry for `name` in the family `af`, whether or not
+/// it has addresses.
+fn exists<T: NameService>(name: &CStr, af: AddressFamily) -> Result<bool> {
+    match T::gethostbyname2_r(name, af) {
+        Ok(found) => Ok(found.is_some()),
+        Err(ref err) if err.host_error() == Some(HostError::HostNotFound) => Ok(false),
+        Err(ref err) if err.host_error() == Some(HostError::NoData) => Ok(true),
+        Err(err) => Err(err),
+    }
+}
+
+fn answer_ptr<T: NameService>(question: &Question) -> Result<Vec<Record>> {
+    let addr = reverse_name_to_addr(&question.name).ok_or_else(not_found)?;
+    match T::gethostbyaddr_r(&addr)? {
+        Some(entry) if !entry.name.to_bytes().is_empty() => Ok(vec![Record::Name(
+            question.name.clone(),
+            dns::TYPE_PTR,
+            entry.name.to_bytes().to_vec(),
+            DEFAULT_TTL,
+        )]),
+        Some(_) => Err(no_data()),
+        None => Err(not_found()),
+    }
+}
+
+/// Parse an `in-addr.arpa` or `ip6.arpa` name.
+fn reverse_name_to_addr(name: &[u8]) -> Option<IpAddr> {
+    let name = std::str::from_utf8(name).ok()?.to_ascii_lowercase();
+    if let Some(rest) = name.strip_suffix(".in-addr.arpa") {
+        let mut octets = [0_u8; 4];
+        let mut labels = rest.split('.');
+        for octet in octets.iter_mut().rev() {
+            let label = labels.next()?;
+            if label.len() > 1 && label.starts_with('0') {
+                return None;
+            }
+            *octet = label.parse().ok()?;
+        }
+        if labels.next().is_some() {
+            return None;
+        }
+        Some(IpAddr::V4(Ipv4Addr::from(octets)))
+    } else if let Some(rest) = name.strip_suffix(".ip6.arpa") {
+        let mut addr = 0_u128;
+        let mut count = 0;
+        for label in rest.split('.').rev() {
+            if label.len() != 1 {
+                return None;
+            }
+            addr = addr << 4 | u128::from_str_radix(label, 16).ok()?;
+            count += 1;
+        }
+        if count != 32 {
+            return None;
+        }
+        Some(IpAddr::V6(Ipv6Addr::from(addr)))
+    } else {
+        None
+    }
+}
+
+/// Build a response, with as many of `records` as fit in a UDP message.
+fn response(query: &Header, question: Option<&Question>, rcode: u16, records: &[Record]) -> Vec<u8> {
+    let header = Header {
+        id: query.id,
+        flags: dns::FLAG_QR | (query.flags & (0xf << 11 | dns::FLAG_RD)) | dns::FLAG_RA | rcode,
+        qdcount: question.is_some() as u16,
+        ..Header::default()
+    };
+    let mut writer = Writer::new(&header);
+    if let Some(question) = question {
+        // This can't fail: the name was read from a message.
+        let _ = writer.question(question);
+    }
+    let mut ancount = 0;
+    for record in records {
+        let mark = writer.len();
+        let written = match *record {
+            Record::Address(ref owner, IpAddr::V4(addr), ttl) => writer.record(owner, dns::TYPE_A, ttl, &addr.octets()),
+            Record::Address(ref owner, IpAddr::V6(addr), ttl) => writer.record(owner, dns::TYPE_AAAA, ttl, &addr.octets()),
+            Record::Name(ref owner, rtype, ref target, ttl) => writer.name_record(owner, rtype, ttl, target),
+        };
+        if written.is_none() || writer.len() > dns::MAX_UDP_MESSAGE {
+            writer.truncate(mark);
+            break;
+        }
+        ancount += 1;
+    }
+    writer.set_ancount(ancount);
+    writer.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interfaces::HostEntry;
+    use std::borrow::Cow;
+
+    struct OneHost;
+
+    impl NameService for OneHost {
+        fn gethostbyname2_r(name: &CStr, af: AddressFamily) -> Result<Option<HostEntry<'_>>> {
+            if name.to_bytes() != b"www.example.test" && name.to_bytes() != b"host.example.test" {
+                return Ok(None);
+            }
+            Ok(Some(HostEntry {
+                name: Cow::Borrowed(CStr::from_bytes_with_nul(b"host.example.test\0").unwrap()),
+                aliases: vec![],
+                addr_list: match af {
+                    AddressFamily::Ipv4 => HostAddressList::V4(vec![Ipv4Addr::new(10, 0, 0, 1)]),
+                    AddressFamily::Ipv6 => HostAddressList::V6(vec![]),
+                },
+            }))
+        }
+
+        fn gethostbyaddr_r(addr: &IpAddr) -> Result<Option<HostEntry<'_>>> {
+            if *addr != IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)) {
+                return Ok(None);
+            }
+            Self::gethostbyname2_r(CStr::from_bytes_with_nul(b"host.example.test\0").unwrap(), AddressFamily::Ipv4)
+        }
+    }
+
+    fn ask(name: &[u8], qtype: u16) -> (Header, Vec<u8>) {
+        let mut writer = Writer::new(&Header { id: 0x1234, flags: dns::FLAG_RD, qdcount: 1, ..Header::default() });
+        writer.question(&Question { name: name.to_vec(), qtype, qclass: dns::CLASS_IN }).unwrap();
+        let response = answer_dns_query::<OneHost>(&writer.finish()).unwrap();
+        let mut reader = Reader::new(&response);
+        let header = reader.header().unwrap();
+        assert_eq!(reader.question().unwrap().name, name);
+        // The answers follow the header and the question.
+        (header, response[12 + name.len() + 2 + 4..].to_vec())
+    }
+
+    #[test]
+    fn test_address_query() {
+        let (header, answers) = ask(b"www.example.test", dns::TYPE_A);
+        assert_eq!((header.id, header.flags & 0xf, header.ancount), (0x1234, dns::RCODE_NOERROR, 2));
+        assert_eq!(header.flags & (dns::FLAG_QR | dns::FLAG_RD), dns::FLAG_QR | dns::FLAG_RD);
+        // A CNAME to the canonical name, then its address.
+        let mut reader = Reader::new(&answers);
+        assert_eq!(reader.name().unwrap(), b"www.example.test");
+        assert_eq!(reader.u16(), Some(dns::TYPE_CNAME));
+        assert!(answers.ends_with(&[0, 1, 0, 1, 0, 0, 0, 0, 0, 4, 10, 0, 0, 1]));
+    }
+
+    #[test]
+    fn test_negative_answers() {
+        let (header, _) = ask(b"host.example.test", dns::TYPE_AAAA);
+        assert_eq!((header.flags & 0xf, header.ancount), (dns::RCODE_NOERROR, 0));
+        let (header, _) = ask(b"nowhere.example.test", dns::TYPE_A);
+        assert_eq!((header.flags & 0xf, header.ancount), (dns::RCODE_NXDOMAIN, 0));
+        let (header, _) = ask(b"host.example.test", 16);  // TXT
+        assert_eq!((header.flags & 0xf, header.ancount), (dns::RCODE_NOERROR, 0));
+    }
+
+    #[test]
+    fn test_ptr_query() {
+        let (header, answers) = ask(b"1.0.0.10.in-addr.arpa", dns::TYPE_PTR);
+        assert_eq!((header.flags & 0xf, header.ancount), (dns::RCODE_NOERROR, 1));
+        assert!(answers.ends_with(b"\x04host\x07example\x04test\x00"));
+        assert_eq!(reverse_name_to_addr(b"1.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.ip6.arpa"),
+                   Some(IpAddr::V6(Ipv6Addr::LOCALHOST)));
+        assert_eq!(reverse_name_to_addr(b"01.0.0.10.in-addr.arpa"), None);
+    }
+}
@@ -0,0 +1,36 @@
+//! The calling thread's `errno`.
+//!
+//! Service code clobbers `errno` all the time, through sockets and files,
+//! even when a lookup succeeds. The NSS contract is that a function reports
+//! errors through `*errnop` and leaves `errno` alone otherwise, and some
+//! programs (wrongly, but commonly) look at `errno` after a successful
+//! `gethostbyname_r`. So the glue puts it back.
+
+use libc::c_int;
+
+#[cfg(any(target_os = "linux", target_os = "emscripten", target_os = "redox"))]
+use libc::__errno_location as errno_location;
+#[cfg(target_os = "android")]
+use libc::__errno as errno_location;
+#[cfg(any(target_os = "freebsd", target_os = "dragonfly", target_os = "macos", target_os = "ios"))]
+use libc::__error as errno_location;
+#[cfg(any(target_os = "netbsd", target_os = "openbsd"))]
+use libc::__errno as errno_location;
+#[cfg(any(target_os = "solaris", target_os = "illumos"))]
+use libc::___errno as errno_location;
+
+/// The value of `errno` when created, which `restore` puts back.
+pub(crate) struct SavedErrno(c_int);
+
+impl SavedErrno {
+    pub(crate) fn save() -> SavedErrno {
+        SavedErrno(unsafe { *errno_location() })
+    }
+
+    pub(crate) fn restore(self) {
+        unsafe {
+            *errno_location() = self.0;
+        }
+    }
+}
+
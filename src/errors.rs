@@ -1,5 +1,8 @@
-use libc::{self, c_int, EINVAL, ERANGE};
-use std::result;
+use libc::{self, c_int, EAGAIN, EDEADLK, EINVAL, EIO, ENOENT, ENOMEM, ERANGE};
+use std::any::Any;
+use std::collections::TryReserveError;
+use std::convert::TryFrom;
+use std::{fmt, result};
 
 #[repr(C)]
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
@@ -10,11 +13,62 @@ pub enum NssStatus {
     Success = 1,
 }
 
+impl NssStatus {
+    /// Interpret a raw `enum nss_status` value, such as the return value of an
+    /// `_nss_*` function. Returns `None` for values this crate doesn't know
+    /// about (including glibc's internal `NSS_STATUS_RETURN`).
+    pub fn from_raw(status: c_int) -> Option<NssStatus> {
+        match status {
+            -2 => Some(NssStatus::TryAgain),
+            -1 => Some(NssStatus::Unavailable),
+            0 => Some(NssStatus::NotFound),
+            1 => Some(NssStatus::Success),
+            _ => None,
+        }
+    }
+
+    /// The raw `enum nss_status` value for this status.
+    pub fn as_raw(self) -> c_int {
+        self as c_int
+    }
+}
+
+impl TryFrom<c_int> for NssStatus {
+    type Error = UnknownCode;
+
+    fn try_from(status: c_int) -> result::Result<NssStatus, UnknownCode> {
+        NssStatus::from_raw(status).ok_or(UnknownCode(status))
+    }
+}
+
+impl From<NssStatus> for c_int {
+    fn from(status: NssStatus) -> c_int {
+        status.as_raw()
+    }
+}
+
+/// The error returned when converting a raw C integer to one of this crate's
+/// enums fails because the value isn't one the enum knows about.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct UnknownCode(pub c_int);
+
+impl fmt::Display for UnknownCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "unrecognized code {}", self.0)
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct Error {
     status: NssStatus,
     errno: c_int,
     h_errno: c_int,
+
+    /// For a negative DNS answer, how long it may be cached, in seconds.
+    ttl: Option<u32>,
+
+    /// If this error was made from a caught panic, the panic message.
+    panic_message: Option<String>,
 }
 
 pub type Result<T> = result::Result<T, Error>;
@@ -38,28 +92,143 @@ pub enum HostError {
     NoData = 4,
 }
 
+impl HostError {
+    /// `NO_ADDRESS` is an old name for `NO_DATA`; glibc defines them as the
+    /// same value.
+    pub const NO_ADDRESS: HostError = HostError::NoData;
+
+    /// Interpret a raw `h_errno` value. Returns `None` for values that aren't
+    /// host errors, including `NETDB_INTERNAL` and `NETDB_SUCCESS`.
+    pub fn from_raw(h_errno: c_int) -> Option<HostError> {
+        match h_errno {
+            1 => Some(HostError::HostNotFound),
+            2 => Some(HostError::TryAgain),
+            3 => Some(HostError::NoRecovery),
+            4 => Some(HostError::NoData),
+            _ => None,
+        }
+    }
+
+    /// The raw `h_errno` value for this error.
+    pub fn as_raw(self) -> c_int {
+        self as c_int
+    }
+}
+
+impl TryFrom<c_int> for HostError {
+    type Error = UnknownCode;
+
+    fn try_from(h_errno: c_int) -> result::Result<HostError, UnknownCode> {
+        HostError::from_raw(h_errno).ok_or(UnknownCode(h_errno))
+    }
+}
+
+impl From<HostError> for c_int {
+    fn from(h_errno: HostError) -> c_int {
+        h_errno.as_raw()
+    }
+}
+
+impl fmt::Display for HostError {
+    /// Formats the error the way glibc's `hstrerror` does.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            HostError::HostNotFound => "Unknown host",
+            HostError::TryAgain => "Host name lookup failure",
+            HostError::NoRecovery => "Unknown server error",
+            HostError::NoData => "No address associated with name",
+        })
+    }
+}
+
+impl From<TryReserveError> for Error {
+    fn from(_: TryReserveError) -> Error {
+        Error::out_of_memory()
+    }
+}
+
 macro_rules! abort {
     ($($message: expr),*) => {
-        eprintln!($($message),*);
-        unsafe {
-            libc::abort();
-        }
+        crate::diag::abort(format_args!($($message),*))
     }
 }
 
 impl Error {
-    pub(crate) fn buffer_too_small() -> Error {
+    /// The error for "the caller's buffer is too small to hold the result".
+    ///
+    /// This is the `NSS_STATUS_TRYAGAIN` + `ERANGE` combination, which tells
+    /// glibc to call the function again with a bigger buffer. The glue
+    /// reports it automatically when a `HostEntry` doesn't fit, so ordinary
+    /// services never need it, and `Error::with_errno` refuses to construct
+    /// it. Use this only from code that writes into the caller's buffer
+    /// itself and has actually run out of room there: returning it for any
+    /// other reason makes glibc keep retrying with ever larger buffers.
+    pub fn insufficient_buffer() -> Error {
         Error {
             status: NssStatus::TryAgain,
             errno: ERANGE,
             h_errno: NETDB_INTERNAL,
+            ttl: None,
+            panic_message: None,
         }
     }
 
+    /// The status a function reporting this error should return.
+    pub fn status(&self) -> NssStatus {
+        self.status
+    }
+
+    /// The `h_errno` a host lookup reporting this error should set, or `None`
+    /// for `NETDB_INTERNAL`, which means "see `errno`".
+    pub fn host_error(&self) -> Option<HostError> {
+        HostError::from_raw(self.h_errno)
+    }
+
+    /// The `errno` value a function reporting this error should set. This
+    /// is 0 for some host errors, whose `h_errno` says it all.
+    pub fn errno(&self) -> c_int {
+        self.errno
+    }
+
+    /// How long, in seconds, the server that gave a negative answer said it
+    /// may be cached, if it said; see `Error::from_dns_rcode`.
+    pub fn ttl(&self) -> Option<u32> {
+        self.ttl
+    }
+
+    pub(crate) fn is_insufficient_buffer(&self) -> bool {
+        self.status == NssStatus::TryAgain && self.errno == ERANGE
+    }
+
     pub(crate) fn invalid_args() -> Error {
         Error::new(NssStatus::Unavailable, EINVAL, NETDB_INTERNAL)
     }
 
+    /// The error for "a memory allocation failed": `NssStatus::TryAgain`
+    /// with errno `ENOMEM`.
+    ///
+    /// Rust aborts the process when an ordinary allocation fails, and an
+    /// NSS module runs inside someone else's process. So the glue never
+    /// allocates while writing results, and a service that builds large
+    /// entries should do the same: reserve space with `Vec::try_reserve`
+    /// and the like, and use `?` to turn a failure into this error (there's
+    /// a `From<TryReserveError>` conversion).
+    pub fn out_of_memory() -> Error {
+        Error::new(NssStatus::TryAgain, ENOMEM, NETDB_INTERNAL)
+    }
+
+    /// The error for a call made while the same thread was already inside
+    /// the module (see `in_lookup`).
+    pub(crate) fn reentered() -> Error {
+        Error::new(NssStatus::Unavailable, EDEADLK, NETDB_INTERNAL)
+    }
+
+    /// The error for a lookup that took longer than the service's
+    /// `LOOKUP_TIMEOUT`.
+    pub(crate) fn timed_out() -> Error {
+        Error::with_host(NssStatus::TryAgain, EAGAIN, HostError::TryAgain)
+    }
+
     pub fn with_errno(status: NssStatus, errno: c_int) -> Error {
         Error::new(status, errno, NETDB_INTERNAL)
     }
@@ -68,48 +237,198 @@ impl Error {
         Error::new(status, errno, h_errno as c_int)
     }
 
+    /// The error to report when a DNS server answered a query with response
+    /// code `rcode` and no usable records, following the conventions of
+    /// glibc's own `dns` service:
+    ///
+    /// | RCODE                       | status      | h_errno          |
+    /// |-----------------------------|-------------|------------------|
+    /// | NOERROR (0), no answers     | `NotFound`  | `NO_DATA`        |
+    /// | SERVFAIL (2)                | `TryAgain`  | `TRY_AGAIN`      |
+    /// | NXDOMAIN (3)                | `NotFound`  | `HOST_NOT_FOUND` |
+    /// | FORMERR, NOTIMP, REFUSED... | `NotFound`  | `NO_RECOVERY`    |
+    ///
+    /// `SERVFAIL` is the only transient failure: the server may well have an
+    /// answer next time, once it can reach the authoritative servers.
+    ///
+    /// `negative_ttl` is how long the answer may be cached, if the server
+    /// said: per RFC 2308, the smaller of the TTL of the `SOA` record in the
+    /// authority section and that record's `MINIMUM` field. It's kept, and
+    /// returned by `ttl()`, only for `NO_DATA` and `NXDOMAIN`, the answers
+    /// it applies to; `Cached` remembers those for that long.
+    pub fn from_dns_rcode(rcode: u16, negative_ttl: Option<u32>) -> Error {
+        match rcode {
+            0 => Error::with_host(NssStatus::NotFound, ENOENT, HostError::NoData).with_ttl(negative_ttl),
+            2 => Error::with_host(NssStatus::TryAgain, EAGAIN, HostError::TryAgain),
+            3 => Error::with_host(NssStatus::NotFound, ENOENT, HostError::HostNotFound).with_ttl(negative_ttl),
+            _ => Error::with_host(NssStatus::NotFound, ENOENT, HostError::NoRecovery),
+        }
+    }
+
+    fn with_ttl(self, ttl: Option<u32>) -> Error {
+        Error { ttl, ..self }
+    }
+
     fn new(status: NssStatus, errno: c_int, h_errno: c_int) -> Error {
         // Check for invalid combinations. Don't allow nsswitch resolvers to
         // fail while claiming success, as that would lead to undefined
         // behavior (the out-parameters are left uninitialized on error, but
         // users will think they are populated).
         if status == NssStatus::Success {
-            abort!("nsswitch resolver: internal error reporting an error: status == NSS_STATUS_SUCCESS");
+            abort!("internal error reporting an error: status == NSS_STATUS_SUCCESS");
         }
         if h_errno == NETDB_SUCCESS {
-            abort!("nsswitch resolver: internal error reporting an error: h_errno == 0");
+            abort!("internal error reporting an error: h_errno == 0");
         }
         if h_errno == NETDB_INTERNAL && errno == 0 {
-            abort!("nsswitch resolver: internal error reporting an error: errno == 0");
+            abort!("internal error reporting an error: errno == 0");
         }
         if status == NssStatus::TryAgain && errno == ERANGE {
             // The NSSwitch documentation reserves this combination of error
             // codes for complaining that the user-provided buffer is not large
             // enough. Since we never let safe Rust code see `buflen`, safe
-            // Rust can't legitimately use this combination.
-            abort!("nsswitch resolver: internal error reporting an error: errno == ERANGE is reserved");
+            // Rust can't legitimately use this combination, except through
+            // `Error::insufficient_buffer()`.
+            abort!("internal error reporting an error: errno == ERANGE is reserved (see Error::insufficient_buffer)");
+        }
+
+        Error { status, errno, h_errno, ttl: None, panic_message: None }
+    }
+
+    /// An error with exactly these codes, as reported by a call through the
+    /// glue, without the checks `new` makes: anything a function can report
+    /// is fair game, including `ERANGE`.
+    pub(crate) fn from_raw_parts(status: NssStatus, errno: c_int, h_errno: c_int) -> Error {
+        Error { status, errno, h_errno, ttl: None, panic_message: None }
+    }
+
+    /// Convert the payload of a caught panic (the `Err` value returned by
+    /// `std::panic::catch_unwind`) into an error that is safe to report to
+    /// C: `NssStatus::Unavailable` with errno `EIO`.
+    ///
+    /// The panic message, if the payload is a string (as it is for `panic!`
+    /// with a message), is kept and can be retrieved with `panic_message()`.
+    pub fn from_panic(payload: Box<dyn Any + Send>) -> Error {
+        let message = match payload.downcast::<String>() {
+            Ok(s) => *s,
+            Err(payload) => match payload.downcast::<&'static str>() {
+                Ok(s) => s.to_string(),
+                Err(_) => "Box<dyn Any>".to_string(),
+            },
+        };
+        Error {
+            panic_message: Some(message),
+            ..Error::with_errno(NssStatus::Unavailable, EIO)
         }
+    }
 
-        Error { status, errno, h_errno }
+    /// The message of the panic this error was made from, if any; see
+    /// `Error::from_panic`.
+    pub fn panic_message(&self) -> Option<&str> {
+        self.panic_message.as_deref()
     }
 
-/*
-    pub(crate) unsafe fn report(self, errnop: *mut c_int) -> NssStatus {
-        if self.h_errno != NETDB_INTERNAL {
-            eprintln!("nsswitch resolver: internal error reporting an error: host errors not supported for this function");
-            libc::abort();
+    /// Report this error to the caller of a function that has no `h_errnop`
+    /// out-parameter, such as `getpwnam_r` or `getgrgid_r`, by storing the
+    /// errno value in `*errnop`. Returns the status the function should
+    /// return.
+    ///
+    /// Errors created with `Error::with_host` can be reported this way too;
+    /// the host error is simply dropped, since there is nowhere to put it.
+    /// If such an error has no errno, `ENOENT` is reported for `NotFound`
+    /// and `EIO` for anything else, since callers of these functions treat
+    /// errno as the whole story.
+    ///
+    /// # Safety
+    ///
+    /// `errnop` must be null or valid for writes. (A null `errnop` breaks the
+    /// NSS contract, so the errno value is lost, but the status is still
+    /// returned.)
+    pub unsafe fn report(self, errnop: *mut c_int) -> NssStatus {
+        if !errnop.is_null() {
+            *errnop = match (self.errno, self.status) {
+                (0, NssStatus::NotFound) => ENOENT,
+                (0, _) => EIO,
+                (errno, _) => errno,
+            };
         }
-        *errnop = self.errno;
         self.status
     }
-*/
 
     pub(crate) unsafe fn report_with_host(self, errnop: *mut c_int, h_errnop: *mut c_int) -> NssStatus {
-        *h_errnop = self.h_errno;
-        if self.h_errno == NETDB_INTERNAL {
+        if !h_errnop.is_null() {
+            *h_errnop = self.h_errno;
+        }
+        if self.h_errno == NETDB_INTERNAL && !errnop.is_null() {
             *errnop = self.errno;
         }
         self.status
     }
 }
 
+
+#[test]
+fn test_nss_status_raw() {
+    for &status in &[NssStatus::TryAgain, NssStatus::Unavailable, NssStatus::NotFound, NssStatus::Success] {
+        assert_eq!(NssStatus::from_raw(status.as_raw()), Some(status));
+        assert_eq!(NssStatus::try_from(c_int::from(status)), Ok(status));
+    }
+    assert_eq!(NssStatus::from_raw(2), None);
+    assert_eq!(NssStatus::try_from(-3), Err(UnknownCode(-3)));
+}
+
+#[test]
+fn test_host_error_raw() {
+    for &err in &[HostError::HostNotFound, HostError::TryAgain, HostError::NoRecovery, HostError::NoData] {
+        assert_eq!(HostError::try_from(err.as_raw()), Ok(err));
+    }
+    assert_eq!(HostError::NO_ADDRESS, HostError::NoData);
+    assert_eq!(HostError::from_raw(NETDB_INTERNAL), None);
+    assert_eq!(HostError::from_raw(NETDB_SUCCESS), None);
+    assert_eq!(HostError::NoRecovery.to_string(), "Unknown server error");
+}
+
+#[test]
+fn test_from_dns_rcode() {
+    let check = |rcode, status, h_errno: HostError, ttl| {
+        let err = Error::from_dns_rcode(rcode, Some(30));
+        assert_eq!((err.status, err.h_errno, err.ttl()), (status, h_errno as c_int, ttl));
+    };
+    check(0, NssStatus::NotFound, HostError::NoData, Some(30));
+    check(1, NssStatus::NotFound, HostError::NoRecovery, None);
+    check(2, NssStatus::TryAgain, HostError::TryAgain, None);
+    check(3, NssStatus::NotFound, HostError::HostNotFound, Some(30));
+    check(5, NssStatus::NotFound, HostError::NoRecovery, None);
+}
+
+#[test]
+fn test_report() {
+    let mut errno = 0;
+    let status = unsafe { Error::with_errno(NssStatus::Unavailable, libc::EIO).report(&mut errno) };
+    assert_eq!((status, errno), (NssStatus::Unavailable, libc::EIO));
+
+    let status = unsafe { Error::from_dns_rcode(3, None).report(&mut errno) };
+    assert_eq!((status, errno), (NssStatus::NotFound, ENOENT));
+
+    // A host error without an errno still reports one.
+    let status = unsafe { Error::with_host(NssStatus::NotFound, 0, HostError::NoData).report(&mut errno) };
+    assert_eq!((status, errno), (NssStatus::NotFound, ENOENT));
+    let status = unsafe { Error::with_host(NssStatus::TryAgain, 0, HostError::TryAgain).report(&mut errno) };
+    assert_eq!((status, errno), (NssStatus::TryAgain, EIO));
+}
+
+#[test]
+fn test_from_panic() {
+    let payload = std::panic::catch_unwind(|| panic!("lookup failed: {}", 42)).unwrap_err();
+    let err = Error::from_panic(payload);
+    assert_eq!((err.status, err.errno), (NssStatus::Unavailable, EIO));
+    assert_eq!(err.panic_message(), Some("lookup failed: 42"));
+}
+
+#[test]
+fn test_out_of_memory() {
+    let err: Error = Vec::<u8>::new().try_reserve(usize::MAX).unwrap_err().into();
+    let mut errno = 0;
+    assert_eq!(unsafe { err.report(&mut errno) }, NssStatus::TryAgain);
+    assert_eq!(errno, ENOMEM);
+}
@@ -0,0 +1,370 @@
+//! A ready-made `hosts` service that reads hosts from keys in etcd, and
+//! watches them to keep its copy current.
+
+use crate::diag;
+use crate::errors::{Error, NssStatus, Result};
+use crate::fork::ForkSafeMutex;
+use crate::host_table::{Host, HostTable};
+use crate::http;
+use crate::interfaces::{AddressFamily, Entries, HostEntry, NameService};
+use crate::pin::pin_module;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use libc::{EINVAL, EIO};
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::ffi::{CStr, CString};
+use std::io::{self, BufRead, BufReader};
+use std::marker::PhantomData;
+use std::net::{IpAddr, SocketAddr, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// Where an `EtcdService` finds its hosts.
+pub trait EtcdConfig: 'static {
+    /// The client addresses of the cluster's members, tried in order.
+    const ENDPOINTS: &'static [&'static str] = &["127.0.0.1:2379"];
+
+    /// The prefix of the keys with hosts in them.
+    const PREFIX: &'static str = "/hosts/";
+
+    /// How long to wait for a member to answer.
+    const TIMEOUT: Duration = Duration::from_secs(1);
+}
+
+/// The usual settings: the local member, and keys under `/hosts/`.
+pub struct EtcdDefaults;
+
+impl EtcdConfig for EtcdDefaults {}
+
+/// A `hosts` service that reads hosts from an etcd cluster, for small
+/// clusters that already run etcd and want every machine to agree on
+/// names without running DNS servers:
+///
+/// ```ignore
+/// nssglue_hosts!("etcd", EtcdService);
+/// ```
+///
+/// Each key under `PREFIX` is a host: the rest of the key is its name, and
+/// the value lists its addresses and then any aliases, separated by
+/// spaces, as in `/etc/hosts`:
+///
+/// ```text
+/// $ etcdctl put /hosts/db1 "10.0.0.5 fd00::5 db"
+/// ```
+///
+/// The first lookup reads all the keys, through etcd's JSON gateway, and
+/// starts a thread that watches them for changes, so later lookups don't
+/// wait for the network and see changes as soon as etcd does. If the watch
+/// ends, because the member went away or the revision was compacted, the
+/// next lookup reads the keys again. If no member answers, lookups report
+/// `NssStatus::Unavailable` rather than old answers. Reverse lookups and
+/// `sethostent` work too.
+///
+/// Only plain HTTP is supported, so the members should be reached over a
+/// trusted network.
+pub struct EtcdService<C = EtcdDefaults>(PhantomData<C>);
+
+/// How long a watch can go without a message before it's presumed dead.
+/// Members send progress notifications every 10 minutes.
+const WATCH_IDLE: Duration = Duration::from_secs(11 * 60);
+
+/// Keys and their values.
+type Kvs = BTreeMap<Vec<u8>, Vec<u8>>;
+
+/// The hosts under one prefix.
+struct Cache {
+    endpoints: &'static [&'static str],
+    prefix: &'static str,
+    state: Mutex<Snapshot>,
+}
+
+#[derive(Default)]
+struct Snapshot {
+    kvs: Kvs,
+    /// `None` until the keys are read, and after the watch ends.
+    table: Option<Arc<HostTable>>,
+    watching: bool,
+}
+
+/// Every `Cache`. The child of a fork starts afresh, since the watch
+/// threads that keep these up to date don't exist there.
+static CACHES: ForkSafeMutex<Vec<Arc<Cache>>> = ForkSafeMutex::new();
+
+/// The end of the range of keys that start with `prefix`.
+fn range_end(prefix: &[u8]) -> Vec<u8> {
+    let mut end = prefix.to_vec();
+    while let Some(last) = end.pop() {
+        if last < 0xff {
+            end.push(last + 1);
+            return end;
+        }
+    }
+    // Every key.
+    vec![0]
+}
+
+/// An integer that may be sent as a string, as the gateway does with 64-bit
+/// ones.
+fn int(value: &Value) -> Option<i64> {
+    value.as_i64().or_else(|| value.as_str()?.parse().ok())
+}
+
+fn decode(value: &Value) -> Option<Vec<u8>> {
+    // The gateway leaves out empty values.
+    BASE64.decode(value.as_str().unwrap_or("")).ok()
+}
+
+/// The keys, values, and revision in an answer to `POST /v3/kv/range`.
+fn parse_range(json: &[u8]) -> std::result::Result<(Kvs, i64), String> {
+    let response: Value = serde_json::from_slice(json).map_err(|err| err.to_string())?;
+    let revision = int(&response["header"]["revision"]).ok_or("no revision")?;
+    let kvs = response["kvs"].as_array().into_iter().flatten()
+        .map(|kv| Ok((decode(&kv["key"]).ok_or("bad key")?, decode(&kv["value"]).ok_or("bad value")?)))
+        .collect::<std::result::Result<_, &str>>()?;
+    Ok((kvs, revision))
+}
+
+/// Apply one message from a watch to `kvs`. It's an error if the watch
+/// has ended.
+fn apply_watch_message(json: &[u8], kvs: &mut Kvs) -> std::result::Result<(), String> {
+    let message: Value = serde_json::from_slice(json).map_err(|err| err.to_string())?;
+    if let Some(error) = message.get("error") {
+        return Err(error["message"].as_str().unwrap_or("error").to_string());
+    }
+    let result = &message["result"];
+    if result["canceled"].as_bool() == Some(true) || int(&result["compact_revision"]).is_some_and(|rev| rev > 0) {
+        return Err(result["cancel_reason"].as_str().unwrap_or("the watch was canceled").to_string());
+    }
+    for event in result["events"].as_array().into_iter().flatten() {
+        let kv = &event["kv"];
+        let key = decode(&kv["key"]).ok_or("bad key")?;
+        // The gateway leaves out the type of PUT events, which is 0.
+        if event["type"].as_str() == Some("DELETE") {
+            kvs.remove(&key);
+        } else {
+            kvs.insert(key, decode(&kv["value"]).ok_or("bad value")?);
+        }
+    }
+    Ok(())
+}
+
+/// The hosts in `kvs`, whose keys start with `prefix`.
+fn build_table(prefix: &[u8], kvs: &Kvs) -> HostTable {
+    let hosts = kvs.iter()
+        .filter_map(|(key, value)| {
+            let name = CString::new(key.strip_prefix(prefix)?).ok().filter(|name| !name.as_bytes().is_empty())?;
+            let value = std::str::from_utf8(value).ok()?;
+            let (addrs, aliases): (Vec<&str>, Vec<&str>) = value.split_whitespace()
+                .partition(|word| word.parse::<IpAddr>().is_ok());
+            Some(Host {
+                name,
+                aliases: aliases.into_iter().filter_map(|alias| CString::new(alias).ok()).collect(),
+                addrs: addrs.iter().filter_map(|addr| addr.parse().ok()).collect(),
+            })
+        })
+        .filter(|host| !host.addrs.is_empty())
+        .collect();
+    HostTable { hosts }
+}
+
+fn connect(endpoint: &str, timeout: Duration) -> io::Result<TcpStream> {
+    let addr: SocketAddr = endpoint.parse().map_err(|_| io::Error::from_raw_os_error(EINVAL))?;
+    let stream = TcpStream::connect_timeout(&addr, timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+    Ok(stream)
+}
+
+fn range_request(prefix: &str) -> Vec<u8> {
+    let key = prefix.as_bytes();
+    json!({"key": BASE64.encode(key), "range_end": BASE64.encode(range_end(key))}).to_string().into_bytes()
+}
+
+/// Read every key under `prefix`, asking each of `endpoints` in turn until
+/// one answers. Also returns the endpoint that answered, and the revision.
+fn load(endpoints: &'static [&'static str], prefix: &str, timeout: Duration)
+    -> Result<(&'static str, Kvs, i64)>
+{
+    let mut last_err = io::Error::from(io::ErrorKind::NotFound);
+    for &endpoint in endpoints {
+        let response = match connect(endpoint, timeout)
+            .and_then(|stream| http::post(&stream, endpoint, "/v3/kv/range", &[], &range_request(prefix)))
+        {
+            Ok(response) => response,
+            Err(err) => {
+                diag::log(format_args!("can't read {} from etcd at {}: {}", prefix, endpoint, err));
+                last_err = err;
+                continue;
+            }
+        };
+        if response.status != 200 {
+            diag::log(format_args!("can't read {} from etcd at {}: HTTP status {}", prefix, endpoint, response.status));
+            return Err(Error::with_errno(NssStatus::Unavailable, EIO));
+        }
+        let (kvs, revision) = parse_range(&response.body).map_err(|message| {
+            diag::log(format_args!("can't read {} from etcd at {}: {}", prefix, endpoint, message));
+            Error::with_errno(NssStatus::Unavailable, EINVAL)
+        })?;
+        return Ok((endpoint, kvs, revision));
+    }
+    Err(Error::with_errno(NssStatus::Unavailable, last_err.raw_os_error().unwrap_or(EIO)))
+}
+
+/// Watch `cache`'s keys on `endpoint`, from `revision` on, until the watch
+/// ends.
+fn watch(cache: &Cache, endpoint: &str, revision: i64, timeout: Duration) -> std::result::Result<(), String> {
+    let key = cache.prefix.as_bytes();
+    let request = json!({"create_request": {
+        "key": BASE64.encode(key),
+        "range_end": BASE64.encode(range_end(key)),
+        "start_revision": revision + 1,
+        "progress_notify": true,
+    }});
+    let mut stream = connect(endpoint, timeout).map_err(|err| err.to_string())?;
+    http::send(&mut stream, "POST", endpoint, "/v3/watch", &[], request.to_string().as_bytes())
+        .map_err(|err| err.to_string())?;
+    let mut reader = BufReader::new(&stream);
+    let mut head = Vec::new();
+    while !head.ends_with(b"\r\n\r\n") {
+        if reader.read_until(b'\n', &mut head).map_err(|err| err.to_string())? == 0 {
+            return Err("the connection was closed".to_string());
+        }
+    }
+    let status = http::parse(&head).map_err(|err| err.to_string())?.status;
+    if status != 200 {
+        return Err(format!("HTTP status {}", status));
+    }
+    stream.set_read_timeout(Some(WATCH_IDLE)).map_err(|err| err.to_string())?;
+
+    // Each message is a line of JSON.
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        if reader.read_until(b'\n', &mut line).map_err(|err| err.to_string())? == 0 {
+            return Err("the connection was closed".to_string());
+        }
+        if line.iter().all(u8::is_ascii_whitespace) {
+            continue;
+        }
+        let mut snapshot = cache.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        apply_watch_message(&line, &mut snapshot.kvs)?;
+        snapshot.table = Some(Arc::new(build_table(key, &snapshot.kvs)));
+    }
+}
+
+impl<C: EtcdConfig> EtcdService<C> {
+    fn cache() -> Arc<Cache> {
+        let mut caches = CACHES.lock();
+        let found = caches.iter().find(|cache| cache.endpoints == C::ENDPOINTS && cache.prefix == C::PREFIX);
+        match found {
+            Some(cache) => cache.clone(),
+            None => {
+                let cache = Arc::new(Cache { endpoints: C::ENDPOINTS, prefix: C::PREFIX, state: Mutex::default() });
+                caches.push(cache.clone());
+                cache
+            }
+        }
+    }
+
+    /// The hosts, read now if they aren't being watched.
+    fn table() -> Result<Arc<HostTable>> {
+        let cache = Self::cache();
+        if let Some(table) = &cache.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).table {
+            return Ok(table.clone());
+        }
+        let (endpoint, kvs, revision) = load(C::ENDPOINTS, C::PREFIX, C::TIMEOUT)?;
+        let table = Arc::new(build_table(C::PREFIX.as_bytes(), &kvs));
+        let mut snapshot = cache.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if snapshot.watching {
+            // Another lookup got here first.
+            return Ok(snapshot.table.clone().unwrap_or(table));
+        }
+        *snapshot = Snapshot { kvs, table: Some(table.clone()), watching: true };
+        drop(snapshot);
+
+        pin_module();
+        let watched = cache.clone();
+        let spawned = thread::Builder::new()
+            .name("nss etcd watch".to_string())
+            .spawn(move || {
+                if let Err(message) = watch(&watched, endpoint, revision, C::TIMEOUT) {
+                    diag::log(format_args!("stopped watching {} on etcd at {}: {}", C::PREFIX, endpoint, message));
+                }
+                let mut snapshot = watched.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+                *snapshot = Snapshot::default();
+            });
+        if spawned.is_err() {
+            *cache.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner()) = Snapshot::default();
+        }
+        Ok(table)
+    }
+}
+
+impl<C: EtcdConfig> NameService for EtcdService<C> {
+    fn gethostbyname2_r(name: &CStr, af: AddressFamily) -> Result<Option<HostEntry<'_>>> {
+        Ok(Self::table()?.by_name(name, af))
+    }
+
+    fn gethostbyaddr_r(addr: &IpAddr) -> Result<Option<HostEntry<'_>>> {
+        Ok(Self::table()?.by_addr(addr))
+    }
+
+    fn sethostent(_stay_open: bool) -> Result<Entries<HostEntry<'static>>> {
+        Ok(HostTable::entries(Self::table()?))
+    }
+
+    fn on_fork_child() {
+        CACHES.reset();
+    }
+}
+
+#[test]
+fn test_etcd() {
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+
+    assert_eq!(range_end(b"/hosts/"), b"/hosts0");
+    assert_eq!(range_end(b"a\xff\xff"), b"b");
+
+    // A member with two hosts, and a key that isn't one.
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let endpoint: &'static str = Box::leak(listener.local_addr().unwrap().to_string().into_boxed_str());
+    let serving = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut request = [0_u8; 1024];
+        let len = stream.read(&mut request).unwrap();
+        let body = json!({"header": {"revision": "42"}, "count": "3", "kvs": [
+            {"key": BASE64.encode("/hosts/db1"), "value": BASE64.encode("10.0.0.5 fd00::5 db")},
+            {"key": BASE64.encode("/hosts/web"), "value": BASE64.encode("10.0.0.6")},
+            {"key": BASE64.encode("/hosts/junk"), "value": BASE64.encode("not an address")},
+        ]});
+        write!(stream, "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\n\r\n{}", body).unwrap();
+        String::from_utf8(request[..len].to_vec()).unwrap()
+    });
+    let endpoints: &'static [&'static str] = Box::leak(Box::new(["127.0.0.1:9", endpoint]));
+    let (answered, mut kvs, revision) = load(endpoints, "/hosts/", Duration::from_secs(5)).unwrap();
+    assert_eq!((answered, revision, kvs.len()), (endpoint, 42, 3));
+    let request = serving.join().unwrap();
+    assert!(request.starts_with("POST /v3/kv/range HTTP/1.0\r\n"));
+    assert!(request.ends_with(&range_request("/hosts/").iter().map(|&b| b as char).collect::<String>()));
+
+    let name = |s: &'static [u8]| CStr::from_bytes_with_nul(s).unwrap();
+    let table = build_table(b"/hosts/", &kvs);
+    assert_eq!(table.hosts.len(), 2);
+    let entry = table.by_name(name(b"DB\0"), AddressFamily::Ipv6).unwrap();
+    assert_eq!(entry.name.to_str(), Ok("db1"));
+
+    // A watch message with a change and a deletion, then a cancellation.
+    let events = json!({"result": {"header": {"revision": "44"}, "events": [
+        {"kv": {"key": BASE64.encode("/hosts/web"), "value": BASE64.encode("10.0.0.7")}},
+        {"type": "DELETE", "kv": {"key": BASE64.encode("/hosts/db1")}},
+    ]}});
+    apply_watch_message(events.to_string().as_bytes(), &mut kvs).unwrap();
+    let table = build_table(b"/hosts/", &kvs);
+    assert!(table.by_name(name(b"db1\0"), AddressFamily::Ipv4).is_none());
+    assert!(table.by_addr(&"10.0.0.7".parse().unwrap()).is_some());
+    let compacted = br#"{"result": {"canceled": true, "compact_revision": "43"}}"#;
+    assert!(apply_watch_message(compacted, &mut kvs).is_err());
+}
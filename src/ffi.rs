@@ -0,0 +1,37 @@
+//! The C types of the NSS interface.
+//!
+//! The `nssglue_*!` macros refer to these through `$crate::ffi`, so crates
+//! using the macros don't need to depend on `libc` themselves.
+
+pub use crate::errors::NssStatus;
+pub use libc::{c_char, c_int, c_long, c_ulong, c_void, gid_t, group, hostent, passwd, uid_t};
+
+/// The record type of `gethostbyname4_r`, a linked list of addresses.
+/// glibc declares it in `<nss.h>`, which the `libc` crate doesn't cover.
+#[repr(C)]
+#[allow(non_camel_case_types)]
+pub struct gaih_addrtuple {
+    pub next: *mut gaih_addrtuple,
+    pub name: *mut c_char,
+    pub family: c_int,
+    /// The address, in network byte order. IPv4 addresses use only the
+    /// first element.
+    pub addr: [u32; 4],
+    pub scopeid: u32,
+}
+
+/// The record type of `getspnam_r`, from `<shadow.h>`. The `libc` crate has
+/// it only for Linux, so it is spelled out here, as glibc lays it out.
+#[repr(C)]
+#[allow(non_camel_case_types)]
+pub struct spwd {
+    pub sp_namp: *mut c_char,
+    pub sp_pwdp: *mut c_char,
+    pub sp_lstchg: c_long,
+    pub sp_min: c_long,
+    pub sp_max: c_long,
+    pub sp_warn: c_long,
+    pub sp_inact: c_long,
+    pub sp_expire: c_long,
+    pub sp_flag: c_ulong,
+}
@@ -0,0 +1,302 @@
+//! Passing only some lookups on to another service.
+
+use crate::diag;
+use crate::errors::{Error, HostError, NssStatus, Result};
+use crate::fork::ForkSafeMutex;
+use crate::interfaces::{AddressFamily, Entries, HostAddresses, HostEntry, HostEntryWithTtl, HostLimits, NameService};
+use libc::{EINVAL, ENOENT};
+use regex::Regex;
+use std::ffi::CStr;
+use std::marker::PhantomData;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Which lookups a `Filtered` service passes on.
+///
+/// Each rule is one of these:
+///
+/// *   a domain, such as `docker.internal`, which matches it and every
+///     name under it, without regard to ASCII case or a trailing dot;
+/// *   `~` and a regular expression, such as `~^web-[0-9]+\.lan$`, which
+///     matches names it finds a match in, in lowercase, without a trailing
+///     dot;
+/// *   an address or a network, such as `172.17.0.0/16` or `fd00::/8`,
+///     which matches reverse lookups of the addresses in it.
+pub trait FilterConfig: 'static {
+    /// The lookups to pass on. If empty, every lookup not in `DENY` is.
+    const ALLOW: &'static [&'static str] = &[];
+
+    /// The lookups never to pass on, even if they're in `ALLOW`.
+    const DENY: &'static [&'static str] = &[];
+
+    /// If true, lookups that aren't passed on aren't found
+    /// (`HOST_NOT_FOUND`), so that `[NOTFOUND=return]` after this service
+    /// stops the search there. If false, they're `NssStatus::Unavailable`,
+    /// which never stops it.
+    const HARD_DENY: bool = false;
+}
+
+/// The `hosts` service `S`, but asked only about the names and addresses
+/// `C` allows, so a specialized backend never sees, and can't leak,
+/// lookups of other names:
+///
+/// ```ignore
+/// struct DockerOnly;
+///
+/// impl FilterConfig for DockerOnly {
+///     const ALLOW: &'static [&'static str] = &["docker.internal", "172.17.0.0/16"];
+/// }
+///
+/// nssglue_hosts!("docker", Filtered<DockerService, DockerOnly>);
+/// ```
+///
+/// Enumeration lists only the hosts whose names are allowed. `S`'s
+/// settings, such as `LOOKUP_TIMEOUT`, are passed through.
+///
+/// The rules are compiled once, the first time the module needs them. If
+/// one doesn't parse, a message goes to the diagnostic sink and every
+/// lookup reports `NssStatus::Unavailable`, without asking `S`.
+pub struct Filtered<S, C>(PhantomData<(S, C)>);
+
+enum Rule {
+    /// Lowercase, with no trailing dot.
+    Domain(String),
+    Pattern(Regex),
+    Network(IpAddr, u8),
+}
+
+impl Rule {
+    fn parse(rule: &str) -> std::result::Result<Rule, String> {
+        if let Some(pattern) = rule.strip_prefix('~') {
+            return Regex::new(pattern).map(Rule::Pattern).map_err(|err| err.to_string());
+        }
+        let (addr, prefix) = match rule.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (rule, None),
+        };
+        if let Ok(addr) = addr.parse::<IpAddr>() {
+            let bits = if addr.is_ipv4() { 32 } else { 128 };
+            let prefix = match prefix {
+                None => bits,
+                Some(prefix) => prefix.parse().ok().filter(|&len| len <= bits).ok_or("bad prefix length")?,
+            };
+            return Ok(Rule::Network(addr, prefix));
+        }
+        let domain = rule.trim_end_matches('.').to_ascii_lowercase();
+        if domain.is_empty() || prefix.is_some() {
+            return Err("not a domain, pattern, or network".to_string());
+        }
+        Ok(Rule::Domain(domain))
+    }
+
+    /// Whether this rule matches `name`, lowercase and without a trailing dot.
+    fn matches_name(&self, name: &str) -> bool {
+        match self {
+            Rule::Domain(domain) => {
+                name == domain
+                    || name.strip_suffix(domain.as_str()).is_some_and(|rest| rest.ends_with('.'))
+            }
+            Rule::Pattern(pattern) => pattern.is_match(name),
+            Rule::Network(..) => false,
+        }
+    }
+
+    fn matches_addr(&self, addr: &IpAddr) -> bool {
+        match (self, addr) {
+            (Rule::Network(IpAddr::V4(network), prefix), IpAddr::V4(addr)) => {
+                let mask = u32::MAX.checked_shl(32 - u32::from(*prefix)).unwrap_or(0);
+                u32::from(*network) & mask == u32::from(*addr) & mask
+            }
+            (Rule::Network(IpAddr::V6(network), prefix), IpAddr::V6(addr)) => {
+                let mask = u128::MAX.checked_shl(128 - u32::from(*prefix)).unwrap_or(0);
+                u128::from(*network) & mask == u128::from(*addr) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+struct Rules {
+    allow: Vec<Rule>,
+    deny: Vec<Rule>,
+}
+
+impl Rules {
+    fn parse(allow: &[&str], deny: &[&str]) -> std::result::Result<Rules, String> {
+        let parse = |rules: &[&str]| -> std::result::Result<Vec<Rule>, String> {
+            rules.iter().map(|rule| Rule::parse(rule).map_err(|err| format!("bad rule {:?}: {}", rule, err))).collect()
+        };
+        Ok(Rules { allow: parse(allow)?, deny: parse(deny)? })
+    }
+
+    fn allows(&self, matches: impl Fn(&Rule) -> bool) -> bool {
+        (self.allow.is_empty() || self.allow.iter().any(&matches)) && !self.deny.iter().any(matches)
+    }
+
+    fn allows_name(&self, name: &CStr) -> bool {
+        match name.to_str() {
+            Ok(name) => {
+                let name = name.trim_end_matches('.').to_ascii_lowercase();
+                self.allows(|rule| rule.matches_name(&name))
+            }
+            Err(_) => false,
+        }
+    }
+
+    fn allows_addr(&self, addr: &IpAddr) -> bool {
+        self.allows(|rule| rule.matches_addr(addr))
+    }
+}
+
+type Loaded = Option<Arc<Rules>>;
+
+/// The rules compiled so far, by `ALLOW` and `DENY`.
+type Key = (&'static [&'static str], &'static [&'static str]);
+
+static RULES: ForkSafeMutex<Vec<(Key, Loaded)>> = ForkSafeMutex::new();
+
+impl<S, C: FilterConfig> Filtered<S, C> {
+    const KEY: Key = (C::ALLOW, C::DENY);
+
+    /// The rules compiled for this configuration, if they have been.
+    fn cached() -> Option<Loaded> {
+        RULES.lock().iter().find(|&&(key, _)| key == Self::KEY).map(|(_, loaded)| loaded.clone())
+    }
+
+    /// The rules, compiled the first time they're needed. They're compiled
+    /// without holding the lock; if two threads both compile them, the
+    /// first to finish wins.
+    fn rules() -> Result<Arc<Rules>> {
+        let loaded = match Self::cached() {
+            Some(loaded) => loaded,
+            None => {
+                let loaded = match Rules::parse(C::ALLOW, C::DENY) {
+                    Ok(parsed) => Some(Arc::new(parsed)),
+                    Err(message) => {
+                        diag::log(format_args!("{}", message));
+                        None
+                    }
+                };
+                let mut rules = RULES.lock();
+                match rules.iter().find(|&&(key, _)| key == Self::KEY) {
+                    Some((_, existing)) => existing.clone(),
+                    None => {
+                        rules.push((Self::KEY, loaded.clone()));
+                        loaded
+                    }
+                }
+            }
+        };
+        loaded.ok_or_else(|| Error::with_errno(NssStatus::Unavailable, EINVAL))
+    }
+
+    /// What to report for a lookup that isn't passed on.
+    fn denied<T>() -> Result<T> {
+        if C::HARD_DENY {
+            Err(Error::with_host(NssStatus::NotFound, ENOENT, HostError::HostNotFound))
+        } else {
+            Err(Error::with_errno(NssStatus::Unavailable, ENOENT))
+        }
+    }
+
+    fn by_name<T>(name: &CStr, lookup: impl FnOnce() -> Result<T>) -> Result<T> {
+        if Self::rules()?.allows_name(name) {
+            lookup()
+        } else {
+            Self::denied()
+        }
+    }
+
+    fn by_addr<T>(addr: &IpAddr, lookup: impl FnOnce() -> Result<T>) -> Result<T> {
+        if Self::rules()?.allows_addr(addr) {
+            lookup()
+        } else {
+            Self::denied()
+        }
+    }
+}
+
+impl<S: NameService, C: FilterConfig> NameService for Filtered<S, C> {
+    const ALLOW_EMPTY_ADDRESS_LIST: bool = S::ALLOW_EMPTY_ADDRESS_LIST;
+    const LOOKUP_TIMEOUT: Option<Duration> = S::LOOKUP_TIMEOUT;
+    const LIMITS: HostLimits = S::LIMITS;
+    const VALIDATE_HOSTNAMES: bool = S::VALIDATE_HOSTNAMES;
+
+    fn gethostbyname_r(name: &CStr) -> Result<Option<HostEntry<'_>>> {
+        Self::by_name(name, || S::gethostbyname_r(name))
+    }
+
+    fn gethostbyname2_r(name: &CStr, af: AddressFamily) -> Result<Option<HostEntry<'_>>> {
+        Self::by_name(name, || S::gethostbyname2_r(name, af))
+    }
+
+    fn gethostbyaddr_r(addr: &IpAddr) -> Result<Option<HostEntry<'_>>> {
+        Self::by_addr(addr, || S::gethostbyaddr_r(addr))
+    }
+
+    fn gethostbyname3_r(name: &CStr, af: AddressFamily) -> Result<Option<HostEntryWithTtl<'_>>> {
+        Self::by_name(name, || S::gethostbyname3_r(name, af))
+    }
+
+    fn gethostbyname4_r(name: &CStr) -> Result<Option<HostAddresses<'_>>> {
+        Self::by_name(name, || S::gethostbyname4_r(name))
+    }
+
+    fn gethostbyaddr2_r(addr: &IpAddr) -> Result<Option<HostEntryWithTtl<'_>>> {
+        Self::by_addr(addr, || S::gethostbyaddr2_r(addr))
+    }
+
+    fn sethostent(stay_open: bool) -> Result<Entries<HostEntry<'static>>> {
+        let rules = Self::rules()?;
+        let entries = S::sethostent(stay_open)?;
+        Ok(Box::new(entries.filter(move |entry| entry.as_ref().map_or(true, |entry| rules.allows_name(&entry.name)))))
+    }
+
+    fn on_fork_child() {
+        RULES.reset();
+        S::on_fork_child()
+    }
+}
+
+#[test]
+fn test_filtered() {
+    use crate::testing::{gethostbyname2, FixedHosts};
+
+    let rules = Rules::parse(&["Internal.", "~^web-[0-9]+\\.lan$", "10.0.0.0/8", "fd00::/8"],
+                             &["secret.internal", "10.9.0.0/16"]).unwrap();
+    let name = |s: &'static [u8]| CStr::from_bytes_with_nul(s).unwrap();
+    assert!(rules.allows_name(name(b"internal\0")));
+    assert!(rules.allows_name(name(b"db.INTERNAL.\0")));
+    assert!(!rules.allows_name(name(b"notinternal\0")));
+    assert!(!rules.allows_name(name(b"x.secret.internal\0")));
+    assert!(rules.allows_name(name(b"web-12.lan\0")));
+    assert!(!rules.allows_name(name(b"web-x.lan\0")));
+    assert!(rules.allows_addr(&"10.1.2.3".parse().unwrap()));
+    assert!(!rules.allows_addr(&"10.9.2.3".parse().unwrap()));
+    assert!(rules.allows_addr(&"fd12::1".parse().unwrap()));
+    assert!(!rules.allows_addr(&"192.0.2.1".parse().unwrap()));
+    assert!(Rules::parse(&["10.0.0.0/33"], &[]).is_err());
+    assert!(Rules::parse(&["~("], &[]).is_err());
+
+    struct TestOnly;
+    impl FilterConfig for TestOnly {
+        const ALLOW: &'static [&'static str] = &["test"];
+        const DENY: &'static [&'static str] = &["v6only.test"];
+        const HARD_DENY: bool = true;
+    }
+    type Hosts = Filtered<FixedHosts, TestOnly>;
+    assert!(gethostbyname2::<Hosts>("host.test", AddressFamily::Ipv4).unwrap().is_some());
+    let err = Hosts::gethostbyname2_r(name(b"localhost\0"), AddressFamily::Ipv4).unwrap_err();
+    assert_eq!((err.status(), err.host_error()), (NssStatus::NotFound, Some(HostError::HostNotFound)));
+    assert!(gethostbyname2::<Hosts>("v6only.test", AddressFamily::Ipv6).unwrap().is_none());
+    assert!(Hosts::gethostbyaddr_r(&"192.0.2.1".parse().unwrap()).is_err());
+    assert_eq!(Hosts::sethostent(false).unwrap().count(), 3);
+
+    struct Bad;
+    impl FilterConfig for Bad {
+        const ALLOW: &'static [&'static str] = &["10.0.0.0/99"];
+    }
+    let err = gethostbyname2::<Filtered<FixedHosts, Bad>>("host.test", AddressFamily::Ipv4).unwrap_err();
+    assert_eq!((err.status(), err.errno()), (NssStatus::Unavailable, EINVAL));
+}
@@ -0,0 +1,91 @@
+//! Calling another module's NSS functions through pointers, the way glibc
+//! does: their C signatures, and the loop that retries with bigger buffers.
+//! `DelegateService` and the `testing` helpers share these.
+
+// Each feature that needs this uses only part of it.
+#![allow(dead_code)]
+
+use crate::errors::{Error, HostError, NssStatus, Result, NETDB_INTERNAL};
+use crate::ffi::{c_char, c_int, c_long, c_void, gaih_addrtuple, gid_t, group, hostent, passwd, spwd, uid_t};
+use libc::{ENOENT, ERANGE};
+
+pub(crate) type HostByNameFn = unsafe extern "C" fn(
+    *const c_char, *mut hostent, *mut c_char, usize, *mut c_int, *mut c_int) -> c_int;
+pub(crate) type HostByName2Fn = unsafe extern "C" fn(
+    *const c_char, c_int, *mut hostent, *mut c_char, usize, *mut c_int, *mut c_int) -> c_int;
+pub(crate) type HostByName3Fn = unsafe extern "C" fn(
+    *const c_char, c_int, *mut hostent, *mut c_char, usize, *mut c_int, *mut c_int, *mut i32, *mut *mut c_char)
+    -> c_int;
+pub(crate) type HostByName4Fn = unsafe extern "C" fn(
+    *const c_char, *mut *mut gaih_addrtuple, *mut c_char, usize, *mut c_int, *mut c_int, *mut i32) -> c_int;
+pub(crate) type HostByAddrFn = unsafe extern "C" fn(
+    *const c_void, c_int, c_int, *mut hostent, *mut c_char, usize, *mut c_int, *mut c_int) -> c_int;
+pub(crate) type HostByAddr2Fn = unsafe extern "C" fn(
+    *const c_void, c_int, c_int, *mut hostent, *mut c_char, usize, *mut c_int, *mut c_int, *mut i32) -> c_int;
+pub(crate) type PwByNameFn = unsafe extern "C" fn(*const c_char, *mut passwd, *mut c_char, usize, *mut c_int) -> c_int;
+pub(crate) type PwByUidFn = unsafe extern "C" fn(uid_t, *mut passwd, *mut c_char, usize, *mut c_int) -> c_int;
+pub(crate) type GrByNameFn = unsafe extern "C" fn(*const c_char, *mut group, *mut c_char, usize, *mut c_int) -> c_int;
+pub(crate) type GrByGidFn = unsafe extern "C" fn(gid_t, *mut group, *mut c_char, usize, *mut c_int) -> c_int;
+pub(crate) type InitgroupsDynFn = unsafe extern "C" fn(
+    *const c_char, gid_t, *mut c_long, *mut c_long, *mut *mut gid_t, c_long, *mut c_int) -> c_int;
+pub(crate) type SpByNameFn = unsafe extern "C" fn(*const c_char, *mut spwd, *mut c_char, usize, *mut c_int) -> c_int;
+pub(crate) type SetHostEntFn = unsafe extern "C" fn(c_int) -> c_int;
+pub(crate) type SetEntFn = unsafe extern "C" fn() -> c_int;
+pub(crate) type EndEntFn = unsafe extern "C" fn() -> c_int;
+pub(crate) type HostEntFn = unsafe extern "C" fn(*mut hostent, *mut c_char, usize, *mut c_int, *mut c_int) -> c_int;
+pub(crate) type PwEntFn = unsafe extern "C" fn(*mut passwd, *mut c_char, usize, *mut c_int) -> c_int;
+pub(crate) type GrEntFn = unsafe extern "C" fn(*mut group, *mut c_char, usize, *mut c_int) -> c_int;
+pub(crate) type SpEntFn = unsafe extern "C" fn(*mut spwd, *mut c_char, usize, *mut c_int) -> c_int;
+
+/// The size of glibc's first buffer for these calls (its `NSS_BUFLEN_*`
+/// constants, and the initial size of its scratch buffers).
+const INITIAL_BUFLEN: usize = 1024;
+
+/// glibc keeps doubling until `malloc` fails. Stop well before that: a
+/// function that still wants more room than this is never going to fit.
+const MAX_BUFLEN: usize = 1 << 24;
+
+/// Call `attempt` with bigger and bigger buffers until the result fits,
+/// as glibc does. `attempt` makes the call and, if it succeeds, reads the
+/// result out of the buffer; otherwise it returns the status.
+///
+/// Returns `Ok(None)` for a plain "no such entry", and an error with the
+/// codes the function reported for anything else.
+pub(crate) fn retry<R, F>(mut attempt: F) -> Result<Option<R>>
+where
+    F: FnMut(*mut c_char, usize, &mut c_int, &mut c_int) -> std::result::Result<R, NssStatus>,
+{
+    let mut buflen = INITIAL_BUFLEN;
+    loop {
+        let mut buffer = Vec::new();
+        buffer.try_reserve_exact(buflen)?;
+        buffer.resize(buflen, 0_u8);
+        let (mut errno, mut h_errno) = (0, NETDB_INTERNAL);
+        let status = match attempt(buffer.as_mut_ptr() as *mut c_char, buflen, &mut errno, &mut h_errno) {
+            Ok(found) => return Ok(Some(found)),
+            Err(status) => status,
+        };
+        match status {
+            NssStatus::TryAgain if errno == ERANGE && h_errno == NETDB_INTERNAL => {
+                if buflen >= MAX_BUFLEN {
+                    return Err(Error::out_of_memory());
+                }
+                buflen *= 2;
+            }
+            // Plain "no such entry", which modules don't always set errno
+            // for. Anything more specific is passed on.
+            NssStatus::NotFound
+                if (errno == ENOENT || errno == 0)
+                    && (h_errno == NETDB_INTERNAL || h_errno == HostError::HostNotFound.as_raw()) =>
+            {
+                return Ok(None);
+            }
+            // Only `attempt` can read a success.
+            NssStatus::Success => return Err(Error::with_errno(NssStatus::Unavailable, libc::EIO)),
+            _ => {
+                let errno = if errno == 0 && h_errno == NETDB_INTERNAL { libc::EIO } else { errno };
+                return Err(Error::from_raw_parts(status, errno, h_errno));
+            }
+        }
+    }
+}
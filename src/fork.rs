@@ -0,0 +1,167 @@
+//! Resetting service state in the child after `fork`.
+//!
+//! Programs that look up names fork constantly, and the child gets a copy
+//! of whatever the service had set up: sockets shared with the parent,
+//! mutexes that some other thread held at the moment of the fork (and that
+//! nothing will ever unlock in the child), background threads that don't
+//! exist there. The crate's own state, such as the enumeration state kept
+//! for `getXXent_r` and the interfaces `Connectivity::current` last saw, is
+//! reset before any hooks run.
+
+use crate::cursor;
+use crate::netif;
+use std::marker::PhantomData;
+use std::mem;
+use std::ptr;
+use std::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
+use std::sync::{Mutex, MutexGuard, Once};
+
+const MAX_HOOKS: usize = 16;
+
+/// The registered hooks, as `fn()` pointers cast to `usize`; 0 means an
+/// empty slot. Slots are filled in order and never emptied, so the child can
+/// read them without taking a lock.
+static HOOKS: [AtomicUsize; MAX_HOOKS] = [const { AtomicUsize::new(0) }; MAX_HOOKS];
+
+static REGISTER: Once = Once::new();
+
+/// Install this crate's `pthread_atfork` handler, which runs the hooks added
+/// with `add_fork_child_hook` in the child process. Calling this more than
+/// once has no further effect.
+///
+/// The handler is unregistered automatically if the module is unloaded.
+pub fn register_fork_handlers() {
+    REGISTER.call_once(|| {
+        let rc = unsafe { libc::pthread_atfork(None, None, Some(run_child_hooks)) };
+        // The only possible error is ENOMEM, and then there's nothing to do
+        // but carry on without the hooks.
+        debug_assert_eq!(rc, 0);
+    });
+}
+
+/// Arrange for `hook` to be called in the child process after each `fork`,
+/// to throw away state that isn't valid there. The export macros do this
+/// for each service's `on_fork_child`. Adding the same hook twice has no
+/// effect.
+///
+/// Hooks run in the child right after `fork` returns, when only the forking
+/// thread exists and the heap lock may be held by a thread that's gone. They
+/// must only do async-signal-safe things: store to atomics, `close` file
+/// descriptors, `mem::forget` values rather than drop them. A hook that
+/// panics aborts the child.
+///
+/// Panics if more than 16 distinct hooks are added.
+pub fn add_fork_child_hook(hook: fn()) {
+    register_fork_handlers();
+    let hook = hook as usize;
+    for slot in &HOOKS {
+        match slot.compare_exchange(0, hook, Ordering::AcqRel, Ordering::Acquire) {
+            Ok(_) => return,
+            Err(existing) if existing == hook => return,
+            Err(_) => {}
+        }
+    }
+    panic!("too many fork hooks (the limit is {})", MAX_HOOKS);
+}
+
+/// A global `Mutex<T>`, made the first time it's locked, that a fork hook
+/// can throw away.
+///
+/// In the child, another thread may have held the lock at the moment of the
+/// fork, and nothing there will ever release it. So `reset` doesn't clear
+/// the value; it forgets the whole mutex, leaking it, and the next `lock`
+/// makes a new one. Dropping the old one would run destructors that may
+/// take locks of their own.
+pub(crate) struct ForkSafeMutex<T> {
+    current: AtomicPtr<Mutex<T>>,
+    _contents: PhantomData<Mutex<T>>,
+}
+
+impl<T: Default> ForkSafeMutex<T> {
+    pub(crate) const fn new() -> ForkSafeMutex<T> {
+        ForkSafeMutex { current: AtomicPtr::new(ptr::null_mut()), _contents: PhantomData }
+    }
+
+    fn get(&self) -> &Mutex<T> {
+        let mut current = self.current.load(Ordering::Acquire);
+        if current.is_null() {
+            let new = Box::into_raw(Box::default());
+            current = match self.current.compare_exchange(ptr::null_mut(), new, Ordering::AcqRel, Ordering::Acquire) {
+                Ok(_) => new,
+                Err(existing) => {
+                    drop(unsafe { Box::from_raw(new) });
+                    existing
+                }
+            };
+        }
+        // Never freed, only forgotten by `reset`.
+        unsafe { &*current }
+    }
+
+    /// Lock the mutex. A panic while it was held doesn't stop anyone else
+    /// from using it: everything kept in one of these is safe to use after a
+    /// panic left it half updated.
+    pub(crate) fn lock(&self) -> MutexGuard<'_, T> {
+        self.get().lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Forget the mutex and what's in it, in the child after a fork.
+    pub(crate) fn reset(&self) {
+        self.current.store(ptr::null_mut(), Ordering::Release);
+    }
+}
+
+impl<T> Drop for ForkSafeMutex<T> {
+    fn drop(&mut self) {
+        let current = *self.current.get_mut();
+        if !current.is_null() {
+            drop(unsafe { Box::from_raw(current) });
+        }
+    }
+}
+
+extern "C" fn run_child_hooks() {
+    cursor::reset_all();
+    netif::reset();
+    for slot in &HOOKS {
+        let hook = slot.load(Ordering::Acquire);
+        if hook == 0 {
+            break;
+        }
+        let hook: fn() = unsafe { mem::transmute::<usize, fn()>(hook) };
+        hook();
+    }
+}
+
+#[test]
+#[cfg_attr(miri, ignore = "forks")]
+fn test_fork_child_hook() {
+    use std::sync::atomic::AtomicBool;
+
+    static RAN: AtomicBool = AtomicBool::new(false);
+    fn hook() {
+        RAN.store(true, Ordering::SeqCst);
+    }
+    add_fork_child_hook(hook);
+    add_fork_child_hook(hook);
+
+    unsafe {
+        match libc::fork() {
+            -1 => panic!("fork failed"),
+            0 => libc::_exit(if RAN.load(Ordering::SeqCst) { 0 } else { 1 }),
+            child => {
+                let mut status = 0;
+                assert_eq!(libc::waitpid(child, &mut status, 0), child);
+                assert!(libc::WIFEXITED(status) && libc::WEXITSTATUS(status) == 0);
+            }
+        }
+    }
+    assert!(!RAN.load(Ordering::SeqCst));
+
+    // A mutex left locked is forgotten, along with what's in it.
+    static LIST: ForkSafeMutex<Vec<u32>> = ForkSafeMutex::new();
+    LIST.lock().push(1);
+    mem::forget(LIST.lock());
+    LIST.reset();
+    assert!(LIST.lock().is_empty());
+}
@@ -0,0 +1,33 @@
+//! FreeBSD's name service switch, `nsdispatch(3)`.
+//!
+//! FreeBSD doesn't call `_nss_NAME_*` functions directly. It loads
+//! `nss_NAME.so.1`, calls its `nss_module_register`, and gets back a table
+//! of methods, each taking its arguments as a C `va_list`. Stable Rust can't
+//! read a `va_list`, but FreeBSD's libc has adapters, `__nss_compat_*`,
+//! that read one and call a glibc-style function passed as the method's
+//! data. So a FreeBSD module is the usual glue functions plus a table
+//! pairing each one with its adapter, which `nss_freebsd_module!` writes.
+//!
+//! FreeBSD has no adapter for `getaddrinfo`, so it doesn't reach the
+//! service; `gethostbyname2`, `gethostbyaddr`, and friends do.
+
+use libc::{c_int, c_void};
+
+pub use crate::nsdispatch::{ns_mtab, nss_method, nss_module_unregister_fn, register};
+
+extern "C" {
+    pub fn __nss_compat_gethostbyname2(retval: *mut c_void, mdata: *mut c_void, ap: *mut c_void) -> c_int;
+    pub fn __nss_compat_gethostbyaddr(retval: *mut c_void, mdata: *mut c_void, ap: *mut c_void) -> c_int;
+
+    pub fn __nss_compat_getpwnam_r(retval: *mut c_void, mdata: *mut c_void, ap: *mut c_void) -> c_int;
+    pub fn __nss_compat_getpwuid_r(retval: *mut c_void, mdata: *mut c_void, ap: *mut c_void) -> c_int;
+    pub fn __nss_compat_getpwent_r(retval: *mut c_void, mdata: *mut c_void, ap: *mut c_void) -> c_int;
+    pub fn __nss_compat_setpwent(retval: *mut c_void, mdata: *mut c_void, ap: *mut c_void) -> c_int;
+    pub fn __nss_compat_endpwent(retval: *mut c_void, mdata: *mut c_void, ap: *mut c_void) -> c_int;
+
+    pub fn __nss_compat_getgrnam_r(retval: *mut c_void, mdata: *mut c_void, ap: *mut c_void) -> c_int;
+    pub fn __nss_compat_getgrgid_r(retval: *mut c_void, mdata: *mut c_void, ap: *mut c_void) -> c_int;
+    pub fn __nss_compat_getgrent_r(retval: *mut c_void, mdata: *mut c_void, ap: *mut c_void) -> c_int;
+    pub fn __nss_compat_setgrent(retval: *mut c_void, mdata: *mut c_void, ap: *mut c_void) -> c_int;
+    pub fn __nss_compat_endgrent(retval: *mut c_void, mdata: *mut c_void, ap: *mut c_void) -> c_int;
+}
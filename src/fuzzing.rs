@@ -0,0 +1,24 @@
+//! Internals the fuzz targets in `fuzz/` need, behind the `fuzzing` feature.
+//! This is not a stable API.
+
+use crate::ffi::{c_char, hostent};
+use crate::errors::Result;
+use crate::interfaces::{HostEntry, HostLimits};
+
+pub use crate::alloc::BumpAllocator;
+
+/// Store `entry` in `buffer` the way the glue does for `gethostbyname2_r`.
+///
+/// # Safety
+///
+/// `result` must be valid for writes, and `buffer` must point to `buflen`
+/// writable bytes.
+pub unsafe fn write_hostent(
+    entry: &HostEntry,
+    limits: &HostLimits,
+    result: *mut hostent,
+    buffer: *mut c_char,
+    buflen: usize,
+) -> Result<()> {
+    entry.write_to(limits, result, buffer, buflen)
+}
@@ -0,0 +1,33 @@
+//! Which glibc the module was loaded into.
+//!
+//! The export macros can leave out functions an old glibc never calls (see
+//! `nsswitch_service_build::set_glibc_version`), but a module built for
+//! several releases may also want to adapt at run time.
+
+/// The version of the running glibc, like `(2, 36)`, or `None` when the
+/// program isn't using glibc.
+pub fn glibc_version() -> Option<(u32, u32)> {
+    #[cfg(all(target_os = "linux", target_env = "gnu"))]
+    {
+        use std::ffi::CStr;
+
+        // "2.36", a static string.
+        let version = unsafe { CStr::from_ptr(libc::gnu_get_libc_version()) };
+        let mut parts = version.to_str().ok()?.split('.');
+        Some((parts.next()?.parse().ok()?, parts.next()?.parse().ok()?))
+    }
+
+    #[cfg(not(all(target_os = "linux", target_env = "gnu")))]
+    {
+        None
+    }
+}
+
+#[test]
+#[cfg_attr(miri, ignore = "calls into glibc")]
+fn test_glibc_version() {
+    if cfg!(all(target_os = "linux", target_env = "gnu")) {
+        let (major, minor) = glibc_version().unwrap();
+        assert!(major == 2 && minor >= 17);
+    }
+}
@@ -0,0 +1,86 @@
+//! Comparing a service with glibc's own `files` service, which reads
+//! `/etc/hosts`. Built only with the `golden-tests` feature, since the
+//! results depend on the machine.
+//!
+//! Both are called the same way, through the testing harness, and every
+//! difference in the entries or error codes is reported, not just the
+//! first.
+
+use crate::errors::Result;
+use crate::host_table::Host;
+use crate::hosts_file::{self, HostsFileConfig, HostsFileService};
+use crate::interfaces::{AddressFamily, HostAddressList, HostEntry, NameService};
+use crate::testing::{self, Module};
+use std::fmt::Debug;
+use std::fs;
+use std::iter;
+use std::net::IpAddr;
+
+/// Describe an entry or error in a way that can be compared.
+fn describe(result: &Result<Option<HostEntry>>) -> String {
+    match result {
+        Ok(None) => "not found".to_string(),
+        Ok(Some(entry)) => format!("{:?} {:?} {:?}", entry.name, entry.aliases, addrs(&entry.addr_list)),
+        Err(err) => format!("error: status {:?}, errno {}, h_errno {:?}", err.status(), err.errno(), err.host_error()),
+    }
+}
+
+fn addrs(list: &HostAddressList) -> Vec<IpAddr> {
+    match list {
+        HostAddressList::V4(addrs) => addrs.iter().map(|&ip| IpAddr::from(ip)).collect(),
+        HostAddressList::V6(addrs) => addrs.iter().map(|&ip| IpAddr::from(ip)).collect(),
+    }
+}
+
+fn compare<K: Debug>(divergences: &mut Vec<String>, what: &str, key: K, files: String, ours: String) {
+    if files != ours {
+        divergences.push(format!("{}({:?}):\n  files: {}\n  ours:  {}", what, key, files, ours));
+    }
+}
+
+/// Look up every name and address with both `libnss_files` and `T`, and
+/// return a description of each difference.
+pub(crate) fn compare_with_files<T: NameService + 'static>(names: &[&str], addrs: &[IpAddr]) -> Vec<String> {
+    let files = Module::open("libnss_files.so.2").expect("can't load libnss_files.so.2");
+    let mut divergences = vec![];
+    for &name in names {
+        for &af in &[AddressFamily::Ipv4, AddressFamily::Ipv6] {
+            compare(&mut divergences, "gethostbyname2", (name, af),
+                    describe(&files.gethostbyname2(name, af)),
+                    describe(&testing::gethostbyname2::<T>(name, af)));
+        }
+    }
+    for &addr in addrs {
+        compare(&mut divergences, "gethostbyaddr", addr,
+                describe(&files.gethostbyaddr(addr)),
+                describe(&testing::gethostbyaddr::<T>(addr)));
+    }
+    divergences
+}
+
+/// The real `/etc/hosts`, read the way the `files` service reads it.
+struct EtcHosts;
+
+impl HostsFileConfig for EtcHosts {
+    const PATHS: &'static [&'static str] = &["/etc/hosts"];
+}
+
+#[test]
+fn test_etc_hosts_matches_files() {
+    let lines = hosts_file::parse(&fs::read_to_string("/etc/hosts").unwrap_or_default());
+    let line_names = |host: &Host| -> Vec<String> {
+        iter::once(&host.name).chain(&host.aliases).map(|name| name.to_string_lossy().into_owned()).collect()
+    };
+    // Names listed once; with `multi on`, `files` merges repeated names.
+    let mut names: Vec<String> = lines.iter().flat_map(line_names).collect();
+    names.retain(|name| lines.iter().filter(|host| line_names(host).contains(name)).count() == 1);
+    names.push("no-such-host.invalid".to_string());
+    let names: Vec<&str> = names.iter().map(String::as_str).collect();
+    let mut addrs: Vec<IpAddr> = lines.iter().map(|host| host.addrs[0]).collect();
+    addrs.sort();
+    addrs.dedup();
+    addrs.push("192.0.2.255".parse().unwrap());
+
+    let divergences = compare_with_files::<HostsFileService<EtcHosts>>(&names, &addrs);
+    assert!(divergences.is_empty(), "differences from libnss_files:\n{}", divergences.join("\n"));
+}
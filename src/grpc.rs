@@ -0,0 +1,484 @@
+//! A ready-made service for `hosts`, `passwd`, and `group` that calls a
+//! gRPC server, with the `Directory` service in `proto/directory.proto`.
+
+use crate::diag;
+use crate::errors::{Error, NssStatus, Result};
+use crate::grpc_client::{self, Call, Failure, Target, NOT_FOUND, UNIMPLEMENTED};
+use crate::host_table::{Host, HostTable};
+use crate::interfaces::{AddressFamily, Entries, GroupEntry, GroupService, HostEntry, NameService, PasswdEntry};
+use crate::interfaces::PasswdService;
+use crate::tls;
+use libc::{gid_t, uid_t, EINVAL, EIO};
+use std::borrow::Cow;
+use std::convert::TryFrom;
+use std::ffi::{CStr, CString};
+use std::fs;
+use std::io;
+use std::iter;
+use std::marker::PhantomData;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Where a `GrpcService` finds its server.
+pub trait GrpcConfig: 'static {
+    /// The server: `http://host:port` for HTTP/2 without TLS,
+    /// `https://host:port`, or `unix:/path` for a Unix socket.
+    const TARGET: &'static str = "unix:/run/nss-directory.sock";
+
+    /// A file with a token to send as `authorization: Bearer` metadata. If
+    /// this process can't read it, calls go without, so the file can be
+    /// readable only by root.
+    const TOKEN_FILE: Option<&'static str> = None;
+
+    /// The certificate authorities to check an `https:` server's
+    /// certificate against, in PEM.
+    const CA_FILE: &'static str = "/etc/ssl/certs/ca-certificates.crt";
+
+    /// How long to wait for the server. This is also each call's deadline.
+    const TIMEOUT: Duration = Duration::from_secs(2);
+}
+
+/// The usual settings: a server on `/run/nss-directory.sock`.
+pub struct GrpcDefaults;
+
+impl GrpcConfig for GrpcDefaults {}
+
+/// A service that gets hosts, users, and groups from a gRPC server, for
+/// organizations with an identity service of their own:
+///
+/// ```ignore
+/// struct Corp;
+///
+/// impl GrpcConfig for Corp {
+///     const TARGET: &'static str = "https://directory.corp.example.com:8443";
+/// }
+///
+/// nssglue_hosts!("corp", GrpcService<Corp>);
+/// nssglue_passwd!("corp", GrpcService<Corp>);
+/// nssglue_group!("corp", GrpcService<Corp>);
+/// ```
+///
+/// The server implements `nsswitch_service.v1.Directory`, defined in
+/// `GrpcService::PROTO`. Each lookup is one call on a new connection; a
+/// `NOT_FOUND` status means there's no such entry. A host is found if the
+/// name looked up is its name or one of its aliases, and a user or group
+/// only if its name or id is the one looked up. Enumeration streams
+/// entries as the program reads them; a server that leaves the `List`
+/// methods `UNIMPLEMENTED` has none. `initgroups` calls `GetGroupsForUser`,
+/// or, if that's `UNIMPLEMENTED`, enumerates the groups.
+///
+/// Any other status, a reply that doesn't decode, or a server that can't
+/// be reached is logged, and the lookup reports `NssStatus::Unavailable`.
+/// Only a server that isn't there (a refused connection or a missing
+/// socket) goes unlogged.
+pub struct GrpcService<C = GrpcDefaults>(PhantomData<C>);
+
+fn invalid(what: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("malformed message: {}", what))
+}
+
+fn put_varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push(0x80 | (value & 0x7f) as u8);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn put_bytes(out: &mut Vec<u8>, field: u32, bytes: &[u8]) {
+    put_varint(out, (field as u64) << 3 | 2);
+    put_varint(out, bytes.len() as u64);
+    out.extend_from_slice(bytes);
+}
+
+fn put_uint(out: &mut Vec<u8>, field: u32, value: u64) {
+    put_varint(out, (field as u64) << 3);
+    put_varint(out, value);
+}
+
+/// A field's value, as read from a message.
+#[derive(Debug, PartialEq)]
+enum Value<'a> {
+    Varint(u64),
+    Bytes(&'a [u8]),
+    /// A fixed-size value, which nothing in the schema is.
+    Fixed,
+}
+
+fn varint(message: &[u8], pos: &mut usize) -> io::Result<u64> {
+    let mut value = 0;
+    for shift in (0..64).step_by(7) {
+        let b = *message.get(*pos).ok_or_else(|| invalid("cut short"))?;
+        *pos += 1;
+        value |= ((b & 0x7f) as u64) << shift;
+        if b & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(invalid("varint too long"))
+}
+
+/// The fields of `message`, by number, in order.
+fn fields(message: &[u8]) -> io::Result<Vec<(u64, Value<'_>)>> {
+    let mut fields = vec![];
+    let mut pos = 0;
+    while pos < message.len() {
+        let key = varint(message, &mut pos)?;
+        let value = match key & 7 {
+            0 => Value::Varint(varint(message, &mut pos)?),
+            1 | 5 => {
+                pos += if key & 7 == 1 { 8 } else { 4 };
+                Value::Fixed
+            }
+            2 => {
+                let len = usize::try_from(varint(message, &mut pos)?).map_err(|_| invalid("length"))?;
+                let bytes = message.get(pos..pos.saturating_add(len)).ok_or_else(|| invalid("cut short"))?;
+                pos += len;
+                Value::Bytes(bytes)
+            }
+            _ => return Err(invalid("wire type")),
+        };
+        fields.push((key >> 3, value));
+    }
+    if pos > message.len() {
+        return Err(invalid("cut short"));
+    }
+    Ok(fields)
+}
+
+fn string(value: &Value<'_>) -> io::Result<CString> {
+    match *value {
+        Value::Bytes(bytes) => CString::new(bytes).map_err(|_| invalid("string with a NUL")),
+        _ => Err(invalid("expected a string")),
+    }
+}
+
+fn uint32(value: &Value<'_>) -> io::Result<u32> {
+    match *value {
+        Value::Varint(n) => u32::try_from(n).map_err(|_| invalid("id out of range")),
+        _ => Err(invalid("expected an integer")),
+    }
+}
+
+/// A `NameRequest`.
+fn name_request(name: &CStr) -> Vec<u8> {
+    let mut request = vec![];
+    put_bytes(&mut request, 1, name.to_bytes());
+    request
+}
+
+/// An `IdRequest`.
+fn id_request(id: u32) -> Vec<u8> {
+    let mut request = vec![];
+    put_uint(&mut request, 1, id as u64);
+    request
+}
+
+/// An `AddrRequest`.
+fn addr_request(addr: &IpAddr) -> Vec<u8> {
+    let mut request = vec![];
+    match addr {
+        IpAddr::V4(ip) => put_bytes(&mut request, 1, &ip.octets()),
+        IpAddr::V6(ip) => put_bytes(&mut request, 1, &ip.octets()),
+    }
+    request
+}
+
+/// Read a `Host`.
+fn host(message: &[u8]) -> io::Result<Host> {
+    let mut host = Host { name: CString::default(), aliases: vec![], addrs: vec![] };
+    for (field, value) in fields(message)? {
+        match (field, value) {
+            (1, value) => host.name = string(&value)?,
+            (2, value) => host.aliases.push(string(&value)?),
+            (3, Value::Bytes(addr)) => host.addrs.push(match addr.len() {
+                4 => IpAddr::V4(Ipv4Addr::from(<[u8; 4]>::try_from(addr).unwrap())),
+                16 => IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(addr).unwrap())),
+                _ => return Err(invalid("address not 4 or 16 bytes")),
+            }),
+            (3, _) => return Err(invalid("expected an address")),
+            _ => {}
+        }
+    }
+    if host.name.as_bytes().is_empty() {
+        return Err(invalid("host without a name"));
+    }
+    Ok(host)
+}
+
+/// Read a `User`.
+fn passwd_entry(message: &[u8]) -> io::Result<PasswdEntry<'static>> {
+    let mut entry = PasswdEntry {
+        name: Cow::Owned(CString::default()),
+        passwd: Cow::Borrowed(CStr::from_bytes_with_nul(b"x\0").unwrap()),
+        uid: 0,
+        gid: 0,
+        gecos: Cow::Owned(CString::default()),
+        dir: Cow::Owned(CString::default()),
+        shell: Cow::Owned(CString::default()),
+    };
+    for (field, value) in fields(message)? {
+        match field {
+            1 => entry.name = Cow::Owned(string(&value)?),
+            2 => entry.uid = uint32(&value)?,
+            3 => entry.gid = uint32(&value)?,
+            4 => entry.gecos = Cow::Owned(string(&value)?),
+            5 => entry.dir = Cow::Owned(string(&value)?),
+            6 => entry.shell = Cow::Owned(string(&value)?),
+            _ => {}
+        }
+    }
+    if entry.name.to_bytes().is_empty() {
+        return Err(invalid("user without a name"));
+    }
+    if entry.shell.to_bytes().is_empty() {
+        entry.shell = Cow::Borrowed(CStr::from_bytes_with_nul(b"/bin/sh\0").unwrap());
+    }
+    Ok(entry)
+}
+
+/// Read a `Group`.
+fn group_entry(message: &[u8]) -> io::Result<GroupEntry<'static>> {
+    let mut entry = GroupEntry {
+        name: Cow::Owned(CString::default()),
+        passwd: Cow::Borrowed(CStr::from_bytes_with_nul(b"*\0").unwrap()),
+        gid: 0,
+        members: vec![],
+    };
+    for (field, value) in fields(message)? {
+        match field {
+            1 => entry.name = Cow::Owned(string(&value)?),
+            2 => entry.gid = uint32(&value)?,
+            3 => entry.members.push(Cow::Owned(string(&value)?)),
+            _ => {}
+        }
+    }
+    if entry.name.to_bytes().is_empty() {
+        return Err(invalid("group without a name"));
+    }
+    Ok(entry)
+}
+
+/// Read `GroupIds`, whose ids may be packed or not.
+fn group_ids(message: &[u8]) -> io::Result<Vec<gid_t>> {
+    let mut gids = vec![];
+    for (field, value) in fields(message)? {
+        match (field, value) {
+            (1, Value::Bytes(packed)) => {
+                let mut pos = 0;
+                while pos < packed.len() {
+                    gids.push(uint32(&Value::Varint(varint(packed, &mut pos)?))?);
+                }
+            }
+            (1, value) => gids.push(uint32(&value)?),
+            _ => {}
+        }
+    }
+    Ok(gids)
+}
+
+impl<C: GrpcConfig> GrpcService<C> {
+    /// The protocol buffer definitions of the service the server must
+    /// implement.
+    pub const PROTO: &'static str = include_str!("../proto/directory.proto");
+
+    /// Start calling `method` with `request`.
+    fn start(method: &str, request: &[u8]) -> io::Result<Call> {
+        let target = Target::parse(C::TARGET)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "not an http:, https:, or unix: target"))?;
+        let token = C::TOKEN_FILE.and_then(|file| fs::read_to_string(file).ok());
+        let authorization = token.map(|token| format!("Bearer {}", token.trim()));
+        let metadata: Vec<(&str, &str)> = authorization.iter().map(|value| ("authorization", &value[..])).collect();
+        let tls = || {
+            let mut config = tls::client_config(C::CA_FILE)?;
+            config.alpn_protocols = vec![b"h2".to_vec()];
+            Ok(Arc::new(config))
+        };
+        let path = format!("/nsswitch_service.v1.Directory/{}", method);
+        grpc_client::call(&target, &tls, &path, &metadata, request, C::TIMEOUT)
+    }
+
+    /// Call `method`, which has a single message in reply, and return it.
+    fn unary(method: &str, request: &[u8]) -> std::result::Result<Vec<u8>, Failure> {
+        let mut call = Self::start(method, request)?;
+        let reply = call.next()?.ok_or_else(|| invalid("no reply"))?;
+        match call.next()? {
+            None => Ok(reply),
+            Some(_) => Err(invalid("more than one reply").into()),
+        }
+    }
+
+    /// Log `failure`, if it's worth logging, and turn it into an error.
+    fn failed(method: &str, failure: Failure) -> Error {
+        let errno = match &failure {
+            Failure::Io(err) => err.raw_os_error().unwrap_or(match err.kind() {
+                io::ErrorKind::InvalidData | io::ErrorKind::InvalidInput => EINVAL,
+                _ => EIO,
+            }),
+            Failure::Status(..) => EIO,
+        };
+        let quiet = match &failure {
+            Failure::Io(err) => matches!(err.kind(), io::ErrorKind::ConnectionRefused | io::ErrorKind::NotFound),
+            Failure::Status(..) => false,
+        };
+        if !quiet {
+            diag::log(format_args!("{} on {} failed: {}", method, C::TARGET, failure));
+        }
+        Error::with_errno(NssStatus::Unavailable, errno)
+    }
+
+    /// Call `method` and read the reply with `convert`, or return `None` if
+    /// the server says there's no such entry.
+    fn get<T>(method: &str, request: &[u8], convert: fn(&[u8]) -> io::Result<T>) -> Result<Option<T>> {
+        match Self::unary(method, request).and_then(|reply| Ok(convert(&reply)?)) {
+            Ok(entry) => Ok(Some(entry)),
+            Err(Failure::Status(NOT_FOUND, _)) => Ok(None),
+            Err(failure) => Err(Self::failed(method, failure)),
+        }
+    }
+
+    /// Call `method`, which streams entries, and read each with `convert`
+    /// as it's asked for.
+    fn list<T: 'static>(method: &'static str, convert: fn(&[u8]) -> io::Result<T>) -> Result<Entries<T>> {
+        let mut call = Some(Self::start(method, &[]).map_err(|err| Self::failed(method, err.into()))?);
+        Ok(Box::new(iter::from_fn(move || {
+            let next = call.as_mut()?.next();
+            match next {
+                Ok(Some(message)) => Some(convert(&message).map_err(|err| Self::failed(method, err.into()))),
+                Ok(None) | Err(Failure::Status(UNIMPLEMENTED, _)) => {
+                    call = None;
+                    None
+                }
+                Err(failure) => {
+                    call = None;
+                    Some(Err(Self::failed(method, failure)))
+                }
+            }
+        })))
+    }
+}
+
+impl<C: GrpcConfig> NameService for GrpcService<C> {
+    fn gethostbyname2_r(name: &CStr, af: AddressFamily) -> Result<Option<HostEntry<'_>>> {
+        let host = Self::get("GetHostByName", &name_request(name), host)?;
+        Ok(host.and_then(|host| HostTable { hosts: vec![host] }.by_name(name, af)))
+    }
+
+    fn gethostbyaddr_r(addr: &IpAddr) -> Result<Option<HostEntry<'_>>> {
+        let host = Self::get("GetHostByAddr", &addr_request(addr), host)?;
+        Ok(host.and_then(|host| HostTable { hosts: vec![host] }.by_addr(addr)))
+    }
+
+    fn sethostent(_stay_open: bool) -> Result<Entries<HostEntry<'static>>> {
+        let hosts = Self::list("ListHosts", host)?;
+        Ok(Box::new(hosts.flat_map(|host| -> Entries<HostEntry<'static>> {
+            match host {
+                Ok(host) => HostTable::entries(Arc::new(HostTable { hosts: vec![host] })),
+                Err(err) => Box::new(iter::once(Err(err))),
+            }
+        })))
+    }
+}
+
+impl<C: GrpcConfig> PasswdService for GrpcService<C> {
+    fn getpwnam_r(name: &CStr) -> Result<Option<PasswdEntry<'_>>> {
+        let entry = Self::get("GetUserByName", &name_request(name), passwd_entry)?;
+        Ok(entry.filter(|entry| *entry.name == *name))
+    }
+
+    fn getpwuid_r(uid: uid_t) -> Result<Option<PasswdEntry<'static>>> {
+        let entry = Self::get("GetUserByUid", &id_request(uid), passwd_entry)?;
+        Ok(entry.filter(|entry| entry.uid == uid))
+    }
+
+    fn setpwent() -> Result<Entries<PasswdEntry<'static>>> {
+        Self::list("ListUsers", passwd_entry)
+    }
+}
+
+impl<C: GrpcConfig> GroupService for GrpcService<C> {
+    fn getgrnam_r(name: &CStr) -> Result<Option<GroupEntry<'_>>> {
+        let entry = Self::get("GetGroupByName", &name_request(name), group_entry)?;
+        Ok(entry.filter(|entry| *entry.name == *name))
+    }
+
+    fn getgrgid_r(gid: gid_t) -> Result<Option<GroupEntry<'static>>> {
+        let entry = Self::get("GetGroupByGid", &id_request(gid), group_entry)?;
+        Ok(entry.filter(|entry| entry.gid == gid))
+    }
+
+    fn setgrent() -> Result<Entries<GroupEntry<'static>>> {
+        Self::list("ListGroups", group_entry)
+    }
+
+    fn initgroups_dyn(user: &CStr, _group: gid_t) -> Result<Option<Vec<gid_t>>> {
+        let method = "GetGroupsForUser";
+        match Self::unary(method, &name_request(user)).and_then(|reply| Ok(group_ids(&reply)?)) {
+            Ok(gids) => Ok(Some(gids)),
+            Err(Failure::Status(NOT_FOUND, _)) => Ok(None),
+            Err(Failure::Status(UNIMPLEMENTED, _)) => {
+                let mut gids = vec![];
+                for entry in Self::setgrent()? {
+                    let entry = entry?;
+                    if entry.members.iter().any(|member| **member == *user) {
+                        gids.push(entry.gid);
+                    }
+                }
+                Ok(if gids.is_empty() { None } else { Some(gids) })
+            }
+            Err(failure) => Err(Self::failed(method, failure)),
+        }
+    }
+}
+
+#[test]
+fn test_grpc_messages() {
+    struct Test;
+    impl GrpcConfig for Test {
+        const TARGET: &'static str = "unix:/nonexistent/directory.sock";
+    }
+    let err = GrpcService::<Test>::getpwuid_r(0).unwrap_err();
+    assert_eq!((err.status(), err.errno()), (NssStatus::Unavailable, libc::ENOENT));
+    assert!(GrpcService::<Test>::PROTO.contains("service Directory {"));
+
+    let name = CString::new("alice").unwrap();
+    assert_eq!(name_request(&name), b"\x0a\x05alice");
+    assert_eq!(id_request(1000), b"\x08\xe8\x07");
+    assert_eq!(addr_request(&"10.0.0.5".parse().unwrap()), b"\x0a\x04\x0a\x00\x00\x05");
+
+    let mut user = vec![];
+    put_bytes(&mut user, 1, b"alice");
+    put_uint(&mut user, 2, 1000);
+    put_uint(&mut user, 3, 100);
+    put_bytes(&mut user, 5, b"/home/alice");
+    // A field from a later version of the schema.
+    put_uint(&mut user, 99, 1);
+    let entry = passwd_entry(&user).unwrap();
+    assert_eq!((entry.name.to_str(), entry.uid, entry.gid), (Ok("alice"), 1000, 100));
+    assert_eq!((entry.gecos.to_str(), entry.dir.to_str(), entry.shell.to_str()), (Ok(""), Ok("/home/alice"), Ok("/bin/sh")));
+    put_uint(&mut user, 2, 1 << 32);
+    assert!(passwd_entry(&user).is_err());
+    assert!(passwd_entry(&user[..user.len() - 1]).is_err());
+
+    let mut db1 = vec![];
+    put_bytes(&mut db1, 1, b"db1.example.com");
+    put_bytes(&mut db1, 2, b"db1");
+    put_bytes(&mut db1, 3, &[10, 0, 0, 5]);
+    put_bytes(&mut db1, 3, &"fd00::5".parse::<Ipv6Addr>().unwrap().octets());
+    let table = HostTable { hosts: vec![host(&db1).unwrap()] };
+    let name = CString::new("DB1").unwrap();
+    assert_eq!(table.by_name(&name, AddressFamily::Ipv6).unwrap().name.to_str(), Ok("db1.example.com"));
+    put_bytes(&mut db1, 3, &[10, 0, 0]);
+    assert!(host(&db1).is_err());
+
+    let mut group = vec![];
+    put_bytes(&mut group, 1, b"staff");
+    put_uint(&mut group, 2, 50);
+    put_bytes(&mut group, 3, b"alice");
+    put_bytes(&mut group, 3, b"bob");
+    let entry = group_entry(&group).unwrap();
+    assert_eq!((entry.name.to_str(), entry.gid, entry.members.len()), (Ok("staff"), 50, 2));
+
+    assert_eq!(group_ids(b"\x0a\x03\x32\xe8\x07\x08\x07").unwrap(), vec![50, 1000, 7]);
+}
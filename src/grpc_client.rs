@@ -0,0 +1,770 @@
+//! Just enough HTTP/2 to make gRPC calls: one call per connection, to a
+//! server reached over TCP, with or without TLS, or a Unix socket.
+//!
+//! Requests are small enough to go out in single frames, so flow control
+//! only matters for responses, which are acknowledged as they're read.
+//! Header blocks get a full HPACK decoder, except that Huffman-coded
+//! strings may only hold printable ASCII, which is all gRPC puts in them.
+
+use crate::http;
+use std::collections::VecDeque;
+use std::convert::TryFrom;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::os::unix::net::UnixStream;
+use std::sync::Arc;
+use std::time::Duration;
+
+const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+const DATA: u8 = 0x0;
+const HEADERS: u8 = 0x1;
+const RST_STREAM: u8 = 0x3;
+const SETTINGS: u8 = 0x4;
+const PING: u8 = 0x6;
+const GOAWAY: u8 = 0x7;
+const WINDOW_UPDATE: u8 = 0x8;
+const CONTINUATION: u8 = 0x9;
+
+const END_STREAM: u8 = 0x1;
+const ACK: u8 = 0x1;
+const END_HEADERS: u8 = 0x4;
+const PADDED: u8 = 0x8;
+const PRIORITY: u8 = 0x20;
+
+const SETTINGS_ENABLE_PUSH: u16 = 0x2;
+
+/// The only stream this client opens.
+const STREAM: u32 = 1;
+
+/// The largest frame a server may send without being told otherwise.
+const MAX_FRAME: usize = 16384;
+
+/// The largest message accepted, as in most gRPC libraries.
+const MAX_MESSAGE: usize = 4 << 20;
+
+/// The most a header block may grow to.
+const MAX_HEADER_BLOCK: usize = 64 << 10;
+
+pub(crate) const NOT_FOUND: u32 = 5;
+pub(crate) const UNIMPLEMENTED: u32 = 12;
+
+/// Why a call failed: the connection, or the server's answer.
+#[derive(Debug)]
+pub(crate) enum Failure {
+    Io(io::Error),
+    /// A gRPC status other than `OK`, with its message.
+    Status(u32, String),
+}
+
+impl From<io::Error> for Failure {
+    fn from(err: io::Error) -> Failure {
+        Failure::Io(err)
+    }
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Failure::Io(err) => err.fmt(f),
+            Failure::Status(code, message) if message.is_empty() => write!(f, "gRPC status {}", code),
+            Failure::Status(code, message) => write!(f, "gRPC status {}: {}", code, message),
+        }
+    }
+}
+
+fn invalid(what: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("malformed HTTP/2 response: {}", what))
+}
+
+/// Where a server is.
+#[derive(Debug, PartialEq)]
+pub(crate) enum Target<'a> {
+    /// `http://host:port`: HTTP/2 without TLS.
+    Tcp(&'a str, u16),
+    /// `https://host:port`.
+    Tls(&'a str, u16),
+    /// `unix:/path` or `unix:///path`.
+    Unix(&'a str),
+}
+
+impl<'a> Target<'a> {
+    pub fn parse(target: &'a str) -> Option<Target<'a>> {
+        if let Some(path) = target.strip_prefix("unix:") {
+            let path = path.strip_prefix("//").unwrap_or(path);
+            return if path.starts_with('/') { Some(Target::Unix(path)) } else { None };
+        }
+        match http::split_url(target)? {
+            (_, _, _, path) if path != "/" => None,
+            (true, host, port, _) => Some(Target::Tls(host, port)),
+            (false, host, port, _) => Some(Target::Tcp(host, port)),
+        }
+    }
+}
+
+trait Transport: Read + Write + Send {}
+
+impl<T: Read + Write + Send> Transport for T {}
+
+/// Connect to the first of `host`'s addresses that answers.
+fn connect_tcp(host: &str, port: u16, timeout: Duration) -> io::Result<TcpStream> {
+    let mut last_err = io::Error::from(io::ErrorKind::AddrNotAvailable);
+    for addr in (host, port).to_socket_addrs()? {
+        match TcpStream::connect_timeout(&addr, timeout) {
+            Ok(tcp) => {
+                tcp.set_read_timeout(Some(timeout))?;
+                tcp.set_write_timeout(Some(timeout))?;
+                tcp.set_nodelay(true)?;
+                return Ok(tcp);
+            }
+            Err(err) => last_err = err,
+        }
+    }
+    Err(last_err)
+}
+
+/// Call the method at `path` (as in `/package.Service/Method`) on `target`
+/// with `message`, a serialized protobuf, sending `metadata` as extra
+/// headers. `tls` makes the TLS settings, if they're needed; they must
+/// offer `h2` by ALPN. `timeout` applies to each read and write, and is
+/// sent to the server as the call's deadline.
+pub(crate) fn call(target: &Target<'_>, tls: &dyn Fn() -> io::Result<Arc<rustls::ClientConfig>>, path: &str,
+                   metadata: &[(&str, &str)], message: &[u8], timeout: Duration) -> io::Result<Call> {
+    let authority = |host: &str, port: u16| match host.contains(':') {
+        true => format!("[{}]:{}", host, port),
+        false => format!("{}:{}", host, port),
+    };
+    match *target {
+        Target::Tcp(host, port) => {
+            let tcp = connect_tcp(host, port, timeout)?;
+            Call::start(Box::new(tcp), "http", &authority(host, port), path, metadata, message, timeout)
+        }
+        Target::Tls(host, port) => {
+            let server_name = rustls::pki_types::ServerName::try_from(host.to_string())
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+            let connection = rustls::ClientConnection::new(tls()?, server_name)
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+            let tcp = connect_tcp(host, port, timeout)?;
+            let stream = rustls::StreamOwned::new(connection, tcp);
+            Call::start(Box::new(stream), "https", &authority(host, port), path, metadata, message, timeout)
+        }
+        Target::Unix(socket) => {
+            let unix = UnixStream::connect(socket)?;
+            unix.set_read_timeout(Some(timeout))?;
+            unix.set_write_timeout(Some(timeout))?;
+            Call::start(Box::new(unix), "http", "localhost", path, metadata, message, timeout)
+        }
+    }
+}
+
+/// A frame, as sent.
+pub(crate) fn frame(kind: u8, flags: u8, stream: u32, payload: &[u8]) -> Vec<u8> {
+    let len = payload.len().to_be_bytes();
+    let mut frame = len[len.len() - 3..].to_vec();
+    frame.push(kind);
+    frame.push(flags);
+    frame.extend_from_slice(&stream.to_be_bytes());
+    frame.extend_from_slice(payload);
+    frame
+}
+
+/// Append `value` as an HPACK integer, with `prefix` bits in the first
+/// byte, whose other bits are `first`.
+fn put_integer(out: &mut Vec<u8>, first: u8, prefix: u32, mut value: usize) {
+    let max = (1 << prefix) - 1;
+    if value < max {
+        out.push(first | value as u8);
+        return;
+    }
+    out.push(first | max as u8);
+    value -= max;
+    while value >= 0x80 {
+        out.push(0x80 | (value & 0x7f) as u8);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+/// A header block with each field as a literal that isn't indexed, which
+/// any decoder can read without keeping track of anything.
+pub(crate) fn encode_headers(fields: &[(&str, &str)]) -> Vec<u8> {
+    let mut block = Vec::new();
+    for (name, value) in fields {
+        block.push(0);
+        for string in &[name, value] {
+            put_integer(&mut block, 0, 7, string.len());
+            block.extend_from_slice(string.as_bytes());
+        }
+    }
+    block
+}
+
+/// A gRPC message, as sent in `DATA` frames.
+pub(crate) fn length_prefixed(message: &[u8]) -> Vec<u8> {
+    let mut framed = vec![0];
+    framed.extend_from_slice(&(message.len() as u32).to_be_bytes());
+    framed.extend_from_slice(message);
+    framed
+}
+
+/// The payload of a `DATA` or `HEADERS` frame without its padding.
+fn unpad(payload: &[u8], flags: u8) -> io::Result<&[u8]> {
+    if flags & PADDED == 0 {
+        return Ok(payload);
+    }
+    let (&pad, rest) = payload.split_first().ok_or_else(|| invalid("padding"))?;
+    rest.get(..rest.len().wrapping_sub(pad as usize)).ok_or_else(|| invalid("padding"))
+}
+
+/// Decode the `%XX` escapes in a `grpc-message`.
+fn percent_decode(encoded: &str) -> String {
+    let bytes = encoded.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes.get(i + 1..i + 3).and_then(|hex| u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok()) {
+            Some(b) if bytes[i] == b'%' => {
+                decoded.push(b);
+                i += 3;
+            }
+            _ => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// A call in progress.
+pub(crate) struct Call {
+    stream: Box<dyn Transport>,
+    decoder: Decoder,
+    /// Response data not yet split into messages.
+    data: Vec<u8>,
+    /// The response headers, then the trailers.
+    headers: Vec<(String, String)>,
+    /// Whether the server has finished the response.
+    ended: bool,
+}
+
+impl Call {
+    fn start(stream: Box<dyn Transport>, scheme: &str, authority: &str, path: &str, metadata: &[(&str, &str)],
+             message: &[u8], timeout: Duration) -> io::Result<Call> {
+        let deadline = format!("{}m", timeout.as_millis().clamp(1, 99_999_999));
+        let mut fields = vec![
+            (":method", "POST"),
+            (":scheme", scheme),
+            (":path", path),
+            (":authority", authority),
+            ("content-type", "application/grpc"),
+            ("te", "trailers"),
+            ("grpc-timeout", &deadline[..]),
+        ];
+        fields.extend_from_slice(metadata);
+        let block = encode_headers(&fields);
+        let body = length_prefixed(message);
+        if block.len() > MAX_FRAME || body.len() > MAX_FRAME {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "request too big"));
+        }
+
+        let mut request = PREFACE.to_vec();
+        let mut settings = SETTINGS_ENABLE_PUSH.to_be_bytes().to_vec();
+        settings.extend_from_slice(&0u32.to_be_bytes());
+        request.extend(frame(SETTINGS, 0, 0, &settings));
+        request.extend(frame(HEADERS, END_HEADERS, STREAM, &block));
+        request.extend(frame(DATA, END_STREAM, STREAM, &body));
+        let mut call = Call { stream, decoder: Decoder::new(), data: vec![], headers: vec![], ended: false };
+        call.stream.write_all(&request)?;
+        call.stream.flush()?;
+        Ok(call)
+    }
+
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|(n, _)| n == name).map(|(_, value)| &value[..])
+    }
+
+    /// The next message in the response, or `None` once the server has
+    /// ended it with an `OK` status.
+    pub fn next(&mut self) -> Result<Option<Vec<u8>>, Failure> {
+        loop {
+            if self.data.len() >= 5 {
+                if self.data[0] != 0 {
+                    return Err(invalid("compressed message").into());
+                }
+                let len = u32::from_be_bytes([self.data[1], self.data[2], self.data[3], self.data[4]]) as usize;
+                if len > MAX_MESSAGE {
+                    return Err(invalid("message too big").into());
+                }
+                if self.data.len() >= 5 + len {
+                    let message = self.data[5..5 + len].to_vec();
+                    self.data.drain(..5 + len);
+                    return Ok(Some(message));
+                }
+            }
+            if self.ended {
+                if !self.data.is_empty() {
+                    return Err(invalid("message cut short").into());
+                }
+                return self.status().map(|()| None);
+            }
+            self.read_frame()?;
+        }
+    }
+
+    /// What the server said about how the call went.
+    fn status(&self) -> Result<(), Failure> {
+        match self.header(":status") {
+            Some("200") => {}
+            Some(status) => return Err(io::Error::other(format!("HTTP status {}", status)).into()),
+            None => return Err(invalid("no :status").into()),
+        }
+        match self.header("grpc-status") {
+            Some("0") => Ok(()),
+            Some(code) => {
+                let message = percent_decode(self.header("grpc-message").unwrap_or(""));
+                // Codes this doesn't understand are UNKNOWN.
+                Err(Failure::Status(code.parse().unwrap_or(2), message))
+            }
+            None => Err(invalid("no grpc-status").into()),
+        }
+    }
+
+    fn read_raw_frame(&mut self) -> io::Result<(u8, u8, u32, Vec<u8>)> {
+        let mut head = [0; 9];
+        self.stream.read_exact(&mut head)?;
+        let len = (head[0] as usize) << 16 | (head[1] as usize) << 8 | head[2] as usize;
+        if len > MAX_FRAME {
+            return Err(invalid("frame too big"));
+        }
+        let stream = u32::from_be_bytes([head[5], head[6], head[7], head[8]]) & 0x7fff_ffff;
+        let mut payload = vec![0; len];
+        self.stream.read_exact(&mut payload)?;
+        Ok((head[3], head[4], stream, payload))
+    }
+
+    fn write_frame(&mut self, kind: u8, flags: u8, stream: u32, payload: &[u8]) -> io::Result<()> {
+        self.stream.write_all(&frame(kind, flags, stream, payload))?;
+        self.stream.flush()
+    }
+
+    /// Read a frame and do what it says.
+    fn read_frame(&mut self) -> io::Result<()> {
+        let (kind, flags, stream, payload) = self.read_raw_frame()?;
+        match kind {
+            DATA if stream == STREAM => {
+                self.data.extend_from_slice(unpad(&payload, flags)?);
+                if flags & END_STREAM != 0 {
+                    self.ended = true;
+                }
+                // Let the server send as much again.
+                if !payload.is_empty() {
+                    let increment = (payload.len() as u32).to_be_bytes();
+                    self.write_frame(WINDOW_UPDATE, 0, 0, &increment)?;
+                    if !self.ended {
+                        self.write_frame(WINDOW_UPDATE, 0, STREAM, &increment)?;
+                    }
+                }
+            }
+            HEADERS if stream == STREAM => {
+                let mut fragment = unpad(&payload, flags)?;
+                if flags & PRIORITY != 0 {
+                    fragment = fragment.get(5..).ok_or_else(|| invalid("priority"))?;
+                }
+                let mut block = fragment.to_vec();
+                let mut last_flags = flags;
+                while last_flags & END_HEADERS == 0 {
+                    let (kind, flags, stream, payload) = self.read_raw_frame()?;
+                    if kind != CONTINUATION || stream != STREAM || block.len() + payload.len() > MAX_HEADER_BLOCK {
+                        return Err(invalid("header block"));
+                    }
+                    block.extend(payload);
+                    last_flags = flags;
+                }
+                let fields = self.decoder.decode(&block)?;
+                self.headers.extend(fields);
+                if flags & END_STREAM != 0 {
+                    self.ended = true;
+                }
+            }
+            RST_STREAM if stream == STREAM => {
+                let code = payload.get(..4).map_or(0, |code| u32::from_be_bytes([code[0], code[1], code[2], code[3]]));
+                return Err(io::Error::new(io::ErrorKind::ConnectionReset,
+                                          format!("server reset the call, error code {}", code)));
+            }
+            SETTINGS if flags & ACK == 0 => self.write_frame(SETTINGS, ACK, 0, &[])?,
+            PING if flags & ACK == 0 => self.write_frame(PING, ACK, 0, &payload)?,
+            GOAWAY => {
+                // The server may still finish the calls it has started.
+                let last = payload.get(..4).map_or(0, |id| u32::from_be_bytes([id[0], id[1], id[2], id[3]]));
+                if last & 0x7fff_ffff < STREAM {
+                    return Err(io::Error::new(io::ErrorKind::ConnectionAborted, "server went away"));
+                }
+            }
+            // WINDOW_UPDATE, PRIORITY, acknowledgments, and anything
+            // unknown, which must be ignored.
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+/// The fields every HPACK decoder knows, from index 1.
+const STATIC_TABLE: [(&str, &str); 61] = [
+    (":authority", ""), (":method", "GET"), (":method", "POST"), (":path", "/"), (":path", "/index.html"),
+    (":scheme", "http"), (":scheme", "https"), (":status", "200"), (":status", "204"), (":status", "206"),
+    (":status", "304"), (":status", "400"), (":status", "404"), (":status", "500"), ("accept-charset", ""),
+    ("accept-encoding", "gzip, deflate"), ("accept-language", ""), ("accept-ranges", ""), ("accept", ""),
+    ("access-control-allow-origin", ""), ("age", ""), ("allow", ""), ("authorization", ""),
+    ("cache-control", ""), ("content-disposition", ""), ("content-encoding", ""), ("content-language", ""),
+    ("content-length", ""), ("content-location", ""), ("content-range", ""), ("content-type", ""),
+    ("cookie", ""), ("date", ""), ("etag", ""), ("expect", ""), ("expires", ""), ("from", ""), ("host", ""),
+    ("if-match", ""), ("if-modified-since", ""), ("if-none-match", ""), ("if-range", ""),
+    ("if-unmodified-since", ""), ("last-modified", ""), ("link", ""), ("location", ""), ("max-forwards", ""),
+    ("proxy-authenticate", ""), ("proxy-authorization", ""), ("range", ""), ("referer", ""), ("refresh", ""),
+    ("retry-after", ""), ("server", ""), ("set-cookie", ""), ("strict-transport-security", ""),
+    ("transfer-encoding", ""), ("user-agent", ""), ("vary", ""), ("via", ""), ("www-authenticate", ""),
+];
+
+/// The symbols with the shortest Huffman codes, by code length, in the
+/// order their codes are assigned. The code is canonical, so this is
+/// enough to rebuild it. Every printable ASCII character is here; the
+/// codes after the last one are for bytes gRPC doesn't put in headers.
+const HUFFMAN_SYMBOLS: [(u32, &[u8]); 11] = [
+    (5, b"012aceiost"),
+    (6, b" %-./3456789=A_bdfghlmnpru"),
+    (7, b":BCDEFGHIJKLMNOPQRSTUVWYjkqvwxyz"),
+    (8, b"&*,;XZ"),
+    (10, b"!\"()?"),
+    (11, b"'+|"),
+    (12, b"#>"),
+    (13, b"\0$@[]~"),
+    (14, b"^}"),
+    (15, b"<`{"),
+    (19, b"\\"),
+];
+
+/// Decode a Huffman-coded string.
+fn huffman_decode(encoded: &[u8]) -> io::Result<Vec<u8>> {
+    let mut decoded = Vec::with_capacity(encoded.len() * 8 / 5);
+    let (mut code, mut len) = (0u32, 0u32);
+    for i in 0..encoded.len() * 8 {
+        code = code << 1 | (encoded[i / 8] >> (7 - i % 8) & 1) as u32;
+        len += 1;
+        let (mut first, mut first_len) = (0u32, 5u32);
+        for &(symbol_len, symbols) in &HUFFMAN_SYMBOLS {
+            first <<= symbol_len - first_len;
+            first_len = symbol_len;
+            if symbol_len == len && code >= first && code - first < symbols.len() as u32 {
+                decoded.push(symbols[(code - first) as usize]);
+                code = 0;
+                len = 0;
+                break;
+            }
+            first += symbols.len() as u32;
+        }
+        if len > 19 {
+            return Err(invalid("Huffman-coded byte not supported"));
+        }
+    }
+    // What's left must be padding: fewer than 8 bits, all ones.
+    if len >= 8 || code != (1 << len) - 1 {
+        return Err(invalid("Huffman padding"));
+    }
+    Ok(decoded)
+}
+
+/// An HPACK decoder, which keeps the table of recent fields that the
+/// server's header blocks refer back to.
+struct Decoder {
+    table: VecDeque<(String, String)>,
+    size: usize,
+    max_size: usize,
+}
+
+/// The table size a decoder starts with, and the largest allowed, since
+/// this client never asks for another.
+const TABLE_SIZE: usize = 4096;
+
+impl Decoder {
+    fn new() -> Decoder {
+        Decoder { table: VecDeque::new(), size: 0, max_size: TABLE_SIZE }
+    }
+
+    fn evict(&mut self) {
+        while self.size > self.max_size {
+            let (name, value) = self.table.pop_back().expect("size counts fields in the table");
+            self.size -= name.len() + value.len() + 32;
+        }
+    }
+
+    fn get(&self, index: usize) -> io::Result<(String, String)> {
+        let (name, value) = match index.checked_sub(1).and_then(|i| STATIC_TABLE.get(i)) {
+            Some(&(name, value)) => (name.to_string(), value.to_string()),
+            None => index.checked_sub(STATIC_TABLE.len() + 1).and_then(|i| self.table.get(i)).cloned()
+                .ok_or_else(|| invalid("header index"))?,
+        };
+        Ok((name, value))
+    }
+
+    fn decode(&mut self, block: &[u8]) -> io::Result<Vec<(String, String)>> {
+        let mut reader = Reader { block, pos: 0 };
+        let mut fields = vec![];
+        while let Some(&first) = block.get(reader.pos) {
+            if first & 0x80 != 0 {
+                fields.push(self.get(reader.integer(7)?)?);
+            } else if first & 0xe0 == 0x20 {
+                self.max_size = reader.integer(5)?;
+                if self.max_size > TABLE_SIZE {
+                    return Err(invalid("table size"));
+                }
+                self.evict();
+            } else {
+                // A literal, added to the table or not.
+                let indexed = first & 0xc0 == 0x40;
+                let index = reader.integer(if indexed { 6 } else { 4 })?;
+                let name = match index {
+                    0 => reader.string()?,
+                    _ => self.get(index)?.0,
+                };
+                let value = reader.string()?;
+                if indexed {
+                    self.size += name.len() + value.len() + 32;
+                    self.table.push_front((name.clone(), value.clone()));
+                    self.evict();
+                }
+                fields.push((name, value));
+            }
+        }
+        Ok(fields)
+    }
+}
+
+struct Reader<'a> {
+    block: &'a [u8],
+    pos: usize,
+}
+
+impl Reader<'_> {
+    fn byte(&mut self) -> io::Result<u8> {
+        let b = *self.block.get(self.pos).ok_or_else(|| invalid("header block cut short"))?;
+        self.pos += 1;
+        Ok(b)
+    }
+
+    fn integer(&mut self, prefix: u32) -> io::Result<usize> {
+        let max = (1 << prefix) - 1;
+        let mut value = (self.byte()? & max) as usize;
+        if value < max as usize {
+            return Ok(value);
+        }
+        for shift in (0..28).step_by(7) {
+            let b = self.byte()?;
+            value += ((b & 0x7f) as usize) << shift;
+            if b & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(invalid("integer too big"))
+    }
+
+    fn string(&mut self) -> io::Result<String> {
+        let huffman = self.block.get(self.pos).is_some_and(|b| b & 0x80 != 0);
+        let len = self.integer(7)?;
+        let raw = self.block.get(self.pos..self.pos.saturating_add(len)).ok_or_else(|| invalid("string"))?;
+        self.pos += len;
+        let bytes = if huffman { huffman_decode(raw)? } else { raw.to_vec() };
+        String::from_utf8(bytes).map_err(|_| invalid("header not UTF-8"))
+    }
+}
+
+#[test]
+fn test_huffman_decode() {
+    // The examples in RFC 7541, appendix C.4.
+    let hex = |s: &str| (0..s.len()).step_by(2).map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap()).collect::<Vec<u8>>();
+    assert_eq!(huffman_decode(&hex("f1e3c2e5f23a6ba0ab90f4ff")).unwrap(), b"www.example.com");
+    assert_eq!(huffman_decode(&hex("a8eb10649cbf")).unwrap(), b"no-cache");
+    assert_eq!(huffman_decode(&hex("25a849e95bb8e8b4bf")).unwrap(), b"custom-value");
+    assert!(huffman_decode(&hex("f1e3c2e5f23a6ba0ab90f400")).is_err());
+}
+
+#[test]
+fn test_target_parse() {
+    assert_eq!(Target::parse("http://127.0.0.1:50051"), Some(Target::Tcp("127.0.0.1", 50051)));
+    assert_eq!(Target::parse("https://[::1]:443"), Some(Target::Tls("::1", 443)));
+    assert_eq!(Target::parse("unix:///run/id.sock"), Some(Target::Unix("/run/id.sock")));
+    assert_eq!(Target::parse("unix:run/id.sock"), None);
+    assert_eq!(Target::parse("http://id.example.com/api"), None);
+}
+
+#[test]
+fn test_grpc_call() {
+    use std::thread;
+
+    let (client, mut server) = UnixStream::pair().unwrap();
+    let server = thread::spawn(move || {
+        let mut preface = [0; 24];
+        server.read_exact(&mut preface).unwrap();
+        assert_eq!(&preface[..], PREFACE);
+        // Read up to the end of the request.
+        let mut request = vec![];
+        loop {
+            let mut head = [0; 9];
+            server.read_exact(&mut head).unwrap();
+            let mut payload = vec![0; (head[1] as usize) << 8 | head[2] as usize];
+            server.read_exact(&mut payload).unwrap();
+            if head[3] == DATA {
+                request = payload;
+            }
+            if head[3] == DATA && head[4] & END_STREAM != 0 {
+                break;
+            }
+        }
+        assert_eq!(request, length_prefixed(b"ask"));
+
+        let mut response = frame(SETTINGS, 0, 0, &[]);
+        // :status 200 from the static table, and content-type, indexed.
+        let mut block = vec![0x88, 0x5f, 16];
+        block.extend_from_slice(b"application/grpc");
+        response.extend(frame(HEADERS, END_HEADERS, STREAM, &block));
+        let mut data = length_prefixed(b"one");
+        data.extend(length_prefixed(b"two"));
+        response.extend(frame(DATA, 0, STREAM, &data[..4]));
+        response.extend(frame(PING, 0, 0, b"12345678"));
+        response.extend(frame(DATA, 0, STREAM, &data[4..]));
+        // The trailers, with the status Huffman-coded: "0" is 00000, plus
+        // three bits of padding.
+        let mut block = vec![0x40, 11];
+        block.extend_from_slice(b"grpc-status");
+        block.extend_from_slice(&[0x81, 0x07]);
+        response.extend(frame(HEADERS, END_HEADERS | END_STREAM, STREAM, &block));
+        server.write_all(&response).unwrap();
+
+        // The client acknowledges the settings, the ping, and the data.
+        let mut head = [0; 9];
+        server.read_exact(&mut head).unwrap();
+        assert_eq!((head[3], head[4]), (SETTINGS, ACK));
+        server
+    });
+    let mut call = Call::start(Box::new(client), "http", "localhost", "/test.Test/List", &[], b"ask",
+                               Duration::from_secs(5)).unwrap();
+    assert_eq!(call.next().unwrap(), Some(b"one".to_vec()));
+    assert_eq!(call.next().unwrap(), Some(b"two".to_vec()));
+    assert_eq!(call.next().unwrap(), None);
+    assert_eq!(call.header("content-type"), Some("application/grpc"));
+    drop(server.join().unwrap());
+}
+
+#[test]
+fn test_trailers_only() {
+    // A trailers-only response, which refers back to the table.
+    let mut decoder = Decoder::new();
+    let mut block = encode_headers(&[(":status", "200")]);
+    block.extend_from_slice(&[0x40, 11]);
+    block.extend_from_slice(b"grpc-status");
+    block.extend_from_slice(&[1, b'5']);
+    block.extend_from_slice(&[0x40, 12]);
+    block.extend_from_slice(b"grpc-message");
+    block.extend_from_slice(&[14]);
+    block.extend_from_slice(b"no%20such user");
+    let headers = decoder.decode(&block).unwrap();
+    assert_eq!(decoder.decode(&[0xbf]).unwrap(), vec![("grpc-status".to_string(), "5".to_string())]);
+    let (client, _server) = UnixStream::pair().unwrap();
+    let call = Call { stream: Box::new(client), decoder, data: vec![], headers, ended: true };
+    match call.status() {
+        Err(Failure::Status(NOT_FOUND, message)) => assert_eq!(message, "no such user"),
+        other => panic!("expected NOT_FOUND, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_malformed_responses() {
+    // The error `next` returns when the server sends `response`.
+    fn failure(response: &[u8]) -> Failure {
+        let (client, mut server) = UnixStream::pair().unwrap();
+        server.write_all(response).unwrap();
+        server.shutdown(std::net::Shutdown::Write).unwrap();
+        let decoder = Decoder::new();
+        let mut call = Call { stream: Box::new(client), decoder, data: vec![], headers: vec![], ended: false };
+        loop {
+            match call.next() {
+                Ok(Some(_)) => {}
+                Ok(None) => panic!("the call succeeded"),
+                Err(failure) => return failure,
+            }
+        }
+    }
+    fn kind(response: &[u8]) -> io::ErrorKind {
+        match failure(response) {
+            Failure::Io(err) => err.kind(),
+            other => panic!("expected an I/O error, got {:?}", other),
+        }
+    }
+
+    let ok = frame(HEADERS, END_HEADERS, STREAM, &[0x88]);
+    let trailers = |fields: &[(&str, &str)]| frame(HEADERS, END_HEADERS | END_STREAM, STREAM, &encode_headers(fields));
+    let with = |frames: &[Vec<u8>]| frames.concat();
+
+    // Frames cut short or over the size limit.
+    assert_eq!(kind(&ok[..5]), io::ErrorKind::UnexpectedEof);
+    assert_eq!(kind(&ok[..9]), io::ErrorKind::UnexpectedEof);
+    assert_eq!(kind(&frame(DATA, 0, STREAM, &[0; MAX_FRAME + 1])), io::ErrorKind::InvalidData);
+    assert_eq!(kind(&with(&[ok.clone(), frame(DATA, PADDED, STREAM, &[9, 0, 0])])), io::ErrorKind::InvalidData);
+
+    // Messages that are compressed, too big, or cut short by the end of
+    // the response.
+    assert_eq!(kind(&frame(DATA, 0, STREAM, &[1, 0, 0, 0, 0])), io::ErrorKind::InvalidData);
+    let too_big = [&[0][..], &(MAX_MESSAGE as u32 + 1).to_be_bytes()].concat();
+    assert_eq!(kind(&frame(DATA, 0, STREAM, &too_big)), io::ErrorKind::InvalidData);
+    let cut_short = length_prefixed(b"message");
+    assert_eq!(kind(&with(&[ok.clone(), frame(DATA, END_STREAM, STREAM, &cut_short[..7])])),
+               io::ErrorKind::InvalidData);
+
+    // Header blocks that are cut short, refer to fields that aren't there,
+    // or are continued by anything but CONTINUATION frames.
+    assert_eq!(kind(&frame(HEADERS, END_HEADERS, STREAM, &[0, 5, b'a'])), io::ErrorKind::InvalidData);
+    assert_eq!(kind(&frame(HEADERS, END_HEADERS, STREAM, &[0xbf])), io::ErrorKind::InvalidData);
+    assert_eq!(kind(&with(&[frame(HEADERS, 0, STREAM, &[0x88]), frame(DATA, 0, STREAM, &[])])),
+               io::ErrorKind::InvalidData);
+
+    // Responses without the statuses a call needs.
+    assert_eq!(kind(&trailers(&[("grpc-status", "0")])), io::ErrorKind::InvalidData);
+    assert_eq!(kind(&trailers(&[(":status", "200")])), io::ErrorKind::InvalidData);
+    let unavailable = failure(&trailers(&[(":status", "503")]));
+    assert!(matches!(unavailable, Failure::Io(ref err) if err.to_string() == "HTTP status 503"), "{:?}", unavailable);
+    match failure(&trailers(&[(":status", "200"), ("grpc-status", "teapot")])) {
+        Failure::Status(2, message) => assert_eq!(message, ""),
+        other => panic!("expected UNKNOWN, got {:?}", other),
+    }
+
+    // The server giving up on the call, or the connection.
+    assert_eq!(kind(&frame(RST_STREAM, 0, STREAM, &8_u32.to_be_bytes())), io::ErrorKind::ConnectionReset);
+    assert_eq!(kind(&frame(GOAWAY, 0, 0, &[0; 8])), io::ErrorKind::ConnectionAborted);
+}
+
+#[test]
+fn test_call_timeout() {
+    use std::os::unix::net::UnixListener;
+    use std::thread;
+
+    // A server that reads the request and never answers.
+    let socket = "/tmp/nsswitch_service-test-grpc-silent.sock";
+    let _ = std::fs::remove_file(socket);
+    let listener = UnixListener::bind(socket).unwrap();
+    let server = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let _ = io::copy(&mut stream, &mut io::sink());
+    });
+
+    let no_tls = || -> io::Result<Arc<rustls::ClientConfig>> { panic!("no TLS here") };
+    let mut call = call(&Target::Unix(socket), &no_tls, "/test.Test/Get", &[], b"ask", Duration::from_millis(100))
+        .unwrap();
+    match call.next() {
+        Err(Failure::Io(err)) => assert!(matches!(err.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut)),
+        other => panic!("expected a timeout, got {:?}", other),
+    }
+    drop(call);
+    server.join().unwrap();
+    let _ = std::fs::remove_file(socket);
+}
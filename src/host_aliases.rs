@@ -0,0 +1,126 @@
+//! The `HOSTALIASES` file, which lets a user give hosts short names of
+//! their own.
+
+use crate::config::env_var_os;
+use crate::errors::{Error, HostError, NssStatus, Result};
+use crate::hostname::is_valid_hostname;
+use crate::interfaces::{AddressFamily, Entries, HostAddresses, HostEntry, HostEntryWithTtl, HostLimits, NameService};
+use libc::ENOENT;
+use std::borrow::Cow;
+use std::ffi::{CStr, CString};
+use std::fs;
+use std::marker::PhantomData;
+use std::net::IpAddr;
+use std::time::Duration;
+
+/// The `hosts` service `S`, but with names looked up in the file named by
+/// the `HOSTALIASES` environment variable first, as glibc's resolver does:
+///
+/// ```ignore
+/// nssglue_hosts!("consul", HostAliases<ConsulService>);
+/// ```
+///
+/// Each line of the file is an alias and the name it stands for, separated
+/// by whitespace, like `db db1.prod.example.com`. A name with no dots in it
+/// that matches an alias, ignoring case, is looked up as the name on the
+/// first matching line instead, so results have that name. Other names, and
+/// all of them if `HOSTALIASES` isn't set, the file can't be read, or the
+/// process is in secure mode (see `is_secure_mode`), are passed on
+/// unchanged. The file is read again on each lookup, as glibc does.
+///
+/// If `S` sets `VALIDATE_HOSTNAMES`, it applies to the name `S` is asked
+/// for. Reverse lookups and enumeration go to `S` unchanged, as do `S`'s
+/// other settings, such as `LOOKUP_TIMEOUT`.
+pub struct HostAliases<S>(PhantomData<S>);
+
+/// What `aliases`, the text of a `HOSTALIASES` file, says `name` stands for.
+fn find_alias(aliases: &[u8], name: &[u8]) -> Option<CString> {
+    aliases.split(|&b| b == b'\n').find_map(|line| {
+        let mut fields = line.split(u8::is_ascii_whitespace).filter(|field| !field.is_empty());
+        match (fields.next(), fields.next()) {
+            (Some(alias), Some(target)) if alias.eq_ignore_ascii_case(name) => CString::new(target).ok(),
+            _ => None,
+        }
+    })
+}
+
+/// The name to ask for in place of `name`.
+fn resolve(name: &CStr) -> Cow<'_, CStr> {
+    if name.to_bytes().contains(&b'.') {
+        return Cow::Borrowed(name);
+    }
+    env_var_os("HOSTALIASES")
+        .and_then(|path| fs::read(path).ok())
+        .and_then(|aliases| find_alias(&aliases, name.to_bytes()))
+        .map_or(Cow::Borrowed(name), Cow::Owned)
+}
+
+impl<S: NameService> HostAliases<S> {
+    fn lookup<T>(name: &CStr, s: impl FnOnce(&CStr) -> Result<Option<T>>) -> Result<Option<T>> {
+        let name = resolve(name);
+        if S::VALIDATE_HOSTNAMES && !is_valid_hostname(&name) {
+            return Err(Error::with_host(NssStatus::NotFound, ENOENT, HostError::HostNotFound));
+        }
+        s(&name)
+    }
+}
+
+impl<S: NameService> NameService for HostAliases<S> {
+    const ALLOW_EMPTY_ADDRESS_LIST: bool = S::ALLOW_EMPTY_ADDRESS_LIST;
+    const LOOKUP_TIMEOUT: Option<Duration> = S::LOOKUP_TIMEOUT;
+    const LIMITS: HostLimits = S::LIMITS;
+    // `lookup` checks the name it asks for instead.
+    const VALIDATE_HOSTNAMES: bool = false;
+
+    fn gethostbyname_r(name: &CStr) -> Result<Option<HostEntry<'_>>> {
+        Self::lookup(name, |name| Ok(S::gethostbyname_r(name)?.map(HostEntry::into_owned)))
+    }
+
+    fn gethostbyname2_r(name: &CStr, af: AddressFamily) -> Result<Option<HostEntry<'_>>> {
+        Self::lookup(name, |name| Ok(S::gethostbyname2_r(name, af)?.map(HostEntry::into_owned)))
+    }
+
+    fn gethostbyaddr_r(addr: &IpAddr) -> Result<Option<HostEntry<'_>>> {
+        S::gethostbyaddr_r(addr)
+    }
+
+    fn gethostbyname3_r(name: &CStr, af: AddressFamily) -> Result<Option<HostEntryWithTtl<'_>>> {
+        Self::lookup(name, |name| Ok(S::gethostbyname3_r(name, af)?.map(HostEntryWithTtl::into_owned)))
+    }
+
+    fn gethostbyname4_r(name: &CStr) -> Result<Option<HostAddresses<'_>>> {
+        Self::lookup(name, |name| Ok(S::gethostbyname4_r(name)?.map(HostAddresses::into_owned)))
+    }
+
+    fn gethostbyaddr2_r(addr: &IpAddr) -> Result<Option<HostEntryWithTtl<'_>>> {
+        S::gethostbyaddr2_r(addr)
+    }
+
+    fn sethostent(stay_open: bool) -> Result<Entries<HostEntry<'static>>> {
+        S::sethostent(stay_open)
+    }
+
+    fn on_fork_child() {
+        S::on_fork_child()
+    }
+}
+
+#[test]
+fn test_host_aliases() {
+    use crate::testing::{gethostbyname2, FixedHosts};
+
+    let aliases = b"# comment\nweb\n  Web   www.test  extra\nweb other.test\ndb\tdb1.example.com\n";
+    let alias = |name: &str| find_alias(aliases, name.as_bytes()).map(|target| target.to_str().unwrap().to_string());
+    assert_eq!(alias("WEB").as_deref(), Some("www.test"));
+    assert_eq!(alias("db").as_deref(), Some("db1.example.com"));
+    assert_eq!(alias("www.test"), None);
+    assert_eq!(alias("comment"), None);
+
+    // Names with dots are never aliases.
+    let name = CStr::from_bytes_with_nul(b"host.test\0").unwrap();
+    assert!(matches!(resolve(name), Cow::Borrowed(_)));
+
+    // Names without aliases pass through.
+    type Hosts = HostAliases<FixedHosts>;
+    assert!(gethostbyname2::<Hosts>("localhost", AddressFamily::Ipv4).unwrap().is_some());
+}
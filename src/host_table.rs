@@ -0,0 +1,98 @@
+//! An in-memory table of hosts, for the built-in services that read one
+//! from a file.
+
+use crate::interfaces::{AddressFamily, Entries, HostAddressList, HostEntry};
+use std::borrow::Cow;
+use std::ffi::{CStr, CString};
+use std::net::IpAddr;
+use std::sync::Arc;
+
+/// A host: its canonical name, its aliases, and its addresses, in order.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Host {
+    pub name: CString,
+    pub aliases: Vec<CString>,
+    pub addrs: Vec<IpAddr>,
+}
+
+impl Host {
+    fn has_name(&self, name: &[u8]) -> bool {
+        self.name.as_bytes().eq_ignore_ascii_case(name)
+            || self.aliases.iter().any(|alias| alias.as_bytes().eq_ignore_ascii_case(name))
+    }
+
+    /// An entry with those of the host's addresses of family `af` that
+    /// `keep` accepts, or `None` if there aren't any.
+    fn entry(&self, af: AddressFamily, keep: impl Fn(&IpAddr) -> bool) -> Option<HostEntry<'static>> {
+        let addrs = self.addrs.iter().filter(|&addr| keep(addr));
+        let addr_list = match af {
+            AddressFamily::Ipv4 => {
+                let addrs: Vec<_> = addrs.filter_map(|addr| match *addr {
+                    IpAddr::V4(ip) => Some(ip),
+                    IpAddr::V6(_) => None,
+                }).collect();
+                if addrs.is_empty() {
+                    return None;
+                }
+                HostAddressList::V4(addrs)
+            }
+            AddressFamily::Ipv6 => {
+                let addrs: Vec<_> = addrs.filter_map(|addr| match *addr {
+                    IpAddr::V4(_) => None,
+                    IpAddr::V6(ip) => Some(ip),
+                }).collect();
+                if addrs.is_empty() {
+                    return None;
+                }
+                HostAddressList::V6(addrs)
+            }
+        };
+        Some(HostEntry {
+            name: Cow::Owned(self.name.clone()),
+            aliases: self.aliases.iter().map(|alias| Cow::Owned(alias.clone())).collect(),
+            addr_list,
+        })
+    }
+}
+
+/// Hosts, looked up the way the `files` service looks up `/etc/hosts`:
+/// names match without regard to ASCII case, and the first host with the
+/// name and an address of the family asked for, or with the address, wins.
+#[derive(Debug, Default)]
+pub(crate) struct HostTable {
+    pub hosts: Vec<Host>,
+}
+
+impl HostTable {
+    /// Whether any host has the name `name`.
+    #[cfg(feature = "static-map")]
+    pub fn has_name(&self, name: &CStr) -> bool {
+        self.hosts.iter().any(|host| host.has_name(name.to_bytes()))
+    }
+
+    /// Look up `name`, taking the first host with that name and addresses
+    /// of family `af`.
+    pub fn by_name(&self, name: &CStr, af: AddressFamily) -> Option<HostEntry<'static>> {
+        let name = name.to_bytes();
+        self.hosts.iter()
+            .filter(|host| host.has_name(name))
+            .find_map(|host| host.entry(af, |_| true))
+    }
+
+    /// Look up `addr`. The entry has just that address.
+    pub fn by_addr(&self, addr: &IpAddr) -> Option<HostEntry<'static>> {
+        let af = if addr.is_ipv4() { AddressFamily::Ipv4 } else { AddressFamily::Ipv6 };
+        self.hosts.iter().find_map(|host| host.entry(af, |a| a == addr))
+    }
+
+    /// Every host, once for each family it has addresses of.
+    pub fn entries(table: Arc<HostTable>) -> Entries<HostEntry<'static>> {
+        let count = table.hosts.len();
+        Box::new((0..count).flat_map(move |i| {
+            let table = table.clone();
+            IntoIterator::into_iter([AddressFamily::Ipv4, AddressFamily::Ipv6])
+                .filter_map(move |af| table.hosts[i].entry(af, |_| true))
+                .map(Ok)
+        }))
+    }
+}
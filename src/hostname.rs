@@ -0,0 +1,73 @@
+//! Hostname syntax.
+
+use std::ffi::CStr;
+
+/// The longest hostname, not counting a trailing dot: 253 characters, which
+/// take up DNS's limit of 255 bytes in wire format.
+const MAX_HOSTNAME_LEN: usize = 253;
+
+const MAX_LABEL_LEN: usize = 63;
+
+/// True if `name` is a syntactically valid hostname (RFC 1123): labels of
+/// 1 to 63 ASCII letters, digits, and hyphens, not starting or ending with a
+/// hyphen, separated by dots, 253 characters at most. A single trailing dot,
+/// marking the name as fully qualified, is allowed.
+///
+/// Anything that passes is safe to paste into a URL path, an SQL string, or
+/// a shell word without quoting. See `NameService::VALIDATE_HOSTNAMES`.
+pub fn is_valid_hostname(name: &CStr) -> bool {
+    let bytes = name.to_bytes();
+    let bytes = bytes.strip_suffix(b".").unwrap_or(bytes);
+    !bytes.is_empty()
+        && bytes.len() <= MAX_HOSTNAME_LEN
+        && bytes.split(|&b| b == b'.').all(|label| {
+            !label.is_empty()
+                && label.len() <= MAX_LABEL_LEN
+                && !label.starts_with(b"-")
+                && !label.ends_with(b"-")
+                && label.iter().all(|&b| b.is_ascii_alphanumeric() || b == b'-')
+        })
+}
+
+/// True unless `name` obviously can't be a hostname in any naming system:
+/// it is empty, has an empty label or one longer than 63 bytes, is longer
+/// than 253 bytes, or has a space or control character in it. A single
+/// trailing dot is allowed. Unlike `is_valid_hostname`, this lets through
+/// underscores, non-ASCII names, and the like, which some services use.
+pub(crate) fn is_plausible_hostname(name: &CStr) -> bool {
+    let bytes = name.to_bytes();
+    let bytes = bytes.strip_suffix(b".").unwrap_or(bytes);
+    !bytes.is_empty()
+        && bytes.len() <= MAX_HOSTNAME_LEN
+        && bytes.split(|&b| b == b'.').all(|label| !label.is_empty() && label.len() <= MAX_LABEL_LEN)
+        && !bytes.iter().any(|&b| b == b' ' || b.is_ascii_control())
+}
+
+#[test]
+fn test_is_valid_hostname() {
+    let valid = |s: &str| is_valid_hostname(&std::ffi::CString::new(s).unwrap());
+    assert!(valid("localhost"));
+    assert!(valid("www.example.com."));
+    assert!(valid("3com.example"));
+    assert!(valid(&["a"; 127].join(".")));
+
+    assert!(!valid(""));
+    assert!(!valid("."));
+    assert!(!valid("a..b"));
+    assert!(!valid("-a.example"));
+    assert!(!valid("a-.example"));
+    assert!(!valid("a b.example"));
+    assert!(!valid("a\tb"));
+    assert!(!valid("x'; DROP TABLE hosts; --"));
+    assert!(!valid("_srv.example"));
+    assert!(!valid(&"a".repeat(64)));
+    assert!(!valid(&["a"; 128].join(".")));
+
+    let plausible = |s: &str| is_plausible_hostname(&std::ffi::CString::new(s).unwrap());
+    assert!(plausible("_srv._tcp.example."));
+    assert!(plausible("bücher.example"));
+    assert!(!plausible("."));
+    assert!(!plausible("a..b"));
+    assert!(!plausible("a\tb"));
+    assert!(!plausible(&"a".repeat(64)));
+}
@@ -0,0 +1,181 @@
+//! A ready-made `hosts` service that reads files in `/etc/hosts` syntax.
+
+use crate::diag;
+use crate::errors::{Error, NssStatus, Result};
+use crate::ffi::c_int;
+use crate::fork::ForkSafeMutex;
+use crate::host_table::{Host, HostTable};
+use crate::interfaces::{AddressFamily, Entries, HostEntry, NameService};
+use crate::reload::Reload;
+use libc::{EIO, ENOENT};
+use std::ffi::{CStr, CString};
+use std::fs;
+use std::io;
+use std::marker::PhantomData;
+use std::net::IpAddr;
+use std::sync::Arc;
+
+/// Where a `HostsFileService` gets its hosts.
+pub trait HostsFileConfig: 'static {
+    /// The files to read, in order.
+    const PATHS: &'static [&'static str];
+}
+
+/// A `hosts` service that reads files in the same syntax as `/etc/hosts`,
+/// for per-application overrides like `/etc/hosts.d/app.hosts` that
+/// shouldn't touch the real `/etc/hosts`.
+///
+/// Each line is an address followed by a canonical name and any aliases;
+/// `#` starts a comment, and lines that don't parse are skipped. Lookups
+/// work like the `files` service's with `multi off`: names match without
+/// regard to ASCII case, and the first line with the name and an address
+/// of the family asked for, or with the address, wins. The files are
+/// searched in the order listed.
+///
+/// The files are read once, the first time the module needs them. Files
+/// that don't exist are skipped, but if none of them do, or one can't be
+/// read, every lookup reports `NssStatus::Unavailable` and a message goes
+/// to the diagnostic sink.
+///
+/// ```ignore
+/// struct AppHosts;
+///
+/// impl HostsFileConfig for AppHosts {
+///     const PATHS: &'static [&'static str] = &["/etc/hosts.d/app.hosts"];
+/// }
+///
+/// nssglue_hosts!("apphosts", HostsFileService<AppHosts>);
+/// ```
+pub struct HostsFileService<C>(PhantomData<C>);
+
+/// The hosts in `text`, one per line that parses.
+pub(crate) fn parse(text: &str) -> Vec<Host> {
+    text.lines()
+        .filter_map(|line| {
+            let mut fields = line.split('#').next().unwrap_or("").split_whitespace();
+            let addr: IpAddr = fields.next()?.parse().ok()?;
+            let mut names = fields.map(|name| CString::new(name).ok());
+            let name = names.next()??;
+            let aliases = names.collect::<Option<Vec<_>>>()?;
+            Some(Host { name, aliases, addrs: vec![addr] })
+        })
+        .collect()
+}
+
+/// Read and parse all of `paths`, or return the errno to report.
+fn load(paths: &[&str]) -> std::result::Result<Arc<HostTable>, c_int> {
+    let mut table = HostTable::default();
+    let mut found = false;
+    for path in paths {
+        match fs::read(path) {
+            Ok(bytes) => {
+                found = true;
+                table.hosts.extend(parse(&String::from_utf8_lossy(&bytes)));
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => {
+                diag::log(format_args!("can't read {}: {}", path, err));
+                return Err(err.raw_os_error().unwrap_or(EIO));
+            }
+        }
+    }
+    if !found {
+        diag::log(format_args!("none of these hosts files exist: {}", paths.join(", ")));
+        return Err(ENOENT);
+    }
+    Ok(Arc::new(table))
+}
+
+type Loaded = std::result::Result<Arc<HostTable>, c_int>;
+
+/// Every set of files loaded so far.
+static TABLES: ForkSafeMutex<Vec<(&'static [&'static str], Loaded)>> = ForkSafeMutex::new();
+
+impl<C: HostsFileConfig> HostsFileService<C> {
+    /// What was loaded for these paths, if anything has been.
+    fn cached() -> Option<Loaded> {
+        TABLES.lock().iter().find(|&&(paths, _)| paths == C::PATHS).map(|(_, loaded)| loaded.clone())
+    }
+
+    /// The table, loaded the first time it's needed. The files are read
+    /// without holding the lock; if two threads both read them, the first
+    /// to finish wins.
+    fn table() -> Result<Arc<HostTable>> {
+        let loaded = match Self::cached() {
+            Some(loaded) => loaded,
+            None => {
+                let loaded = load(C::PATHS);
+                let mut tables = TABLES.lock();
+                match tables.iter().find(|&&(paths, _)| paths == C::PATHS) {
+                    Some((_, existing)) => existing.clone(),
+                    None => {
+                        tables.push((C::PATHS, loaded.clone()));
+                        loaded
+                    }
+                }
+            }
+        };
+        loaded.map_err(|errno| Error::with_errno(NssStatus::Unavailable, errno))
+    }
+}
+
+impl<C: HostsFileConfig> Reload for HostsFileService<C> {
+    const FILES: &'static [&'static str] = C::PATHS;
+
+    fn reload() {
+        let new = load(C::PATHS);
+        let mut tables = TABLES.lock();
+        match tables.iter_mut().find(|(paths, _)| *paths == C::PATHS) {
+            // Keep the old table if the files are broken.
+            Some((_, loaded)) => if new.is_ok() || loaded.is_err() {
+                *loaded = new;
+            },
+            None => tables.push((C::PATHS, new)),
+        }
+    }
+}
+
+impl<C: HostsFileConfig> NameService for HostsFileService<C> {
+    fn gethostbyname2_r(name: &CStr, af: AddressFamily) -> Result<Option<HostEntry<'_>>> {
+        Ok(Self::table()?.by_name(name, af))
+    }
+
+    fn gethostbyaddr_r(addr: &IpAddr) -> Result<Option<HostEntry<'_>>> {
+        Ok(Self::table()?.by_addr(addr))
+    }
+
+    fn sethostent(_stay_open: bool) -> Result<Entries<HostEntry<'static>>> {
+        Ok(HostTable::entries(Self::table()?))
+    }
+
+    fn on_fork_child() {
+        TABLES.reset();
+    }
+}
+
+#[test]
+fn test_parse_hosts_file() {
+    let hosts = parse("# comment\n\
+                       127.0.0.1\tlocalhost   # trailing comment\n\
+                       ::1 localhost ip6-localhost\n\
+                       10.0.0.300 bad-address\n\
+                       10.0.0.1\n\
+                       \n\
+                       fd00::1 app.internal app\n");
+    let names: Vec<(&str, usize, IpAddr)> = hosts.iter()
+        .map(|host| (host.name.to_str().unwrap(), host.aliases.len(), host.addrs[0]))
+        .collect();
+    assert_eq!(names, [
+        ("localhost", 0, "127.0.0.1".parse().unwrap()),
+        ("localhost", 1, "::1".parse().unwrap()),
+        ("app.internal", 1, "fd00::1".parse().unwrap()),
+    ]);
+
+    // The first line with the family asked for wins.
+    let table = HostTable { hosts };
+    let name = CStr::from_bytes_with_nul(b"LOCALHOST\0").unwrap();
+    let entry = table.by_name(name, AddressFamily::Ipv6).unwrap();
+    assert_eq!(entry.aliases.len(), 1);
+    let name = CStr::from_bytes_with_nul(b"app\0").unwrap();
+    assert!(table.by_name(name, AddressFamily::Ipv4).is_none());
+}
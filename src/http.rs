@@ -0,0 +1,229 @@
+//! Just enough HTTP/1.0 to ask local daemons and API servers for JSON: one
+//! `GET` per connection, with the body read until the server closes it.
+
+// Each feature that needs this uses only part of it.
+#![allow(dead_code)]
+
+#[cfg(feature = "rustls")]
+use std::convert::TryFrom;
+use std::io::{self, Read, Write};
+#[cfg(feature = "rustls")]
+use std::net::{TcpStream, ToSocketAddrs};
+#[cfg(feature = "rustls")]
+use std::sync::Arc;
+#[cfg(feature = "rustls")]
+use std::time::Duration;
+
+/// A response. Header names are lowercase.
+#[derive(Debug, PartialEq)]
+pub(crate) struct Response {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|(n, _)| n == name).map(|(_, value)| &value[..])
+    }
+}
+
+fn invalid(what: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("malformed HTTP response: {}", what))
+}
+
+/// Send a request for `path` over `stream`, with `headers` and, if it isn't
+/// empty, a JSON `body`.
+pub(crate) fn send<S: Write>(stream: &mut S, method: &str, host: &str, path: &str, headers: &[(&str, &str)],
+                             body: &[u8]) -> io::Result<()> {
+    let mut request = format!("{} {} HTTP/1.0\r\nHost: {}\r\nAccept: application/json\r\n", method, path, host);
+    for (name, value) in headers {
+        request += &format!("{}: {}\r\n", name, value);
+    }
+    if !body.is_empty() {
+        request += &format!("Content-Type: application/json\r\nContent-Length: {}\r\n", body.len());
+    }
+    request += "\r\n";
+    let mut request = request.into_bytes();
+    request.extend_from_slice(body);
+    stream.write_all(&request)?;
+    stream.flush()
+}
+
+/// Read the rest of a response from `stream`.
+fn receive<S: Read>(mut stream: S) -> io::Result<Response> {
+    let mut raw = Vec::new();
+    match stream.read_to_end(&mut raw) {
+        // Some TLS servers just hang up. A body cut short won't parse.
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof && !raw.is_empty() => {}
+        result => {
+            result?;
+        }
+    }
+    parse(&raw)
+}
+
+/// Send a `GET` request for `path` over `stream`, with `headers`, and read
+/// the whole response. Any timeout is up to the caller to set on the
+/// stream.
+pub(crate) fn get<S: Read + Write>(mut stream: S, host: &str, path: &str, headers: &[(&str, &str)])
+    -> io::Result<Response>
+{
+    send(&mut stream, "GET", host, path, headers, &[])?;
+    receive(stream)
+}
+
+/// Like `get`, but a `POST` with a JSON `body`.
+pub(crate) fn post<S: Read + Write>(mut stream: S, host: &str, path: &str, headers: &[(&str, &str)], body: &[u8])
+    -> io::Result<Response>
+{
+    send(&mut stream, "POST", host, path, headers, body)?;
+    receive(stream)
+}
+
+/// Percent-encode `value` for use in a path or query: everything but the characters
+/// RFC 3986 calls unreserved.
+pub(crate) fn percent_encode(value: &[u8]) -> String {
+    let mut encoded = String::with_capacity(value.len());
+    for &b in value {
+        if b.is_ascii_alphanumeric() || b"-._~".contains(&b) {
+            encoded.push(b as char);
+        } else {
+            encoded += &format!("%{:02X}", b);
+        }
+    }
+    encoded
+}
+
+/// Split an `http://` or `https://` URL into whether it's `https`, its
+/// host, port, and path.
+pub(crate) fn split_url(url: &str) -> Option<(bool, &str, u16, &str)> {
+    let (tls, rest) = match url.strip_prefix("https://") {
+        Some(rest) => (true, rest),
+        None => (false, url.strip_prefix("http://")?),
+    };
+    let (authority, path) = match rest.find('/') {
+        Some(slash) => rest.split_at(slash),
+        None => (rest, "/"),
+    };
+    // An IPv6 address is in brackets.
+    let (host, port) = match authority.rfind(':') {
+        Some(colon) if !authority[colon..].contains(']') => (&authority[..colon], authority[colon + 1..].parse().ok()?),
+        _ => (authority, if tls { 443 } else { 80 }),
+    };
+    let host = host.strip_prefix('[').and_then(|h| h.strip_suffix(']')).unwrap_or(host);
+    if host.is_empty() {
+        return None;
+    }
+    Some((tls, host, port, path))
+}
+
+/// Split an `https://host[:port]/path` URL into its host, port, and path.
+#[cfg(feature = "rustls")]
+pub(crate) fn split_https_url(url: &str) -> Option<(&str, u16, &str)> {
+    match split_url(url)? {
+        (true, host, port, path) => Some((host, port, path)),
+        (false, ..) => None,
+    }
+}
+
+/// `get` over TLS, from `host` and `port`, checking the server's
+/// certificate as `config` says.
+#[cfg(feature = "rustls")]
+pub(crate) fn get_https(config: Arc<rustls::ClientConfig>, host: &str, port: u16, path: &str,
+                        headers: &[(&str, &str)], timeout: Duration) -> io::Result<Response> {
+    let server_name = rustls::pki_types::ServerName::try_from(host.to_string())
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+    let mut last_err = io::Error::from(io::ErrorKind::AddrNotAvailable);
+    for addr in (host, port).to_socket_addrs()? {
+        let tcp = match TcpStream::connect_timeout(&addr, timeout) {
+            Ok(tcp) => tcp,
+            Err(err) => {
+                last_err = err;
+                continue;
+            }
+        };
+        tcp.set_read_timeout(Some(timeout))?;
+        tcp.set_write_timeout(Some(timeout))?;
+        let connection = rustls::ClientConnection::new(config, server_name)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        let authority = if port == 443 { host.to_string() } else { format!("{}:{}", host, port) };
+        return get(rustls::StreamOwned::new(connection, tcp), &authority, path, headers);
+    }
+    Err(last_err)
+}
+
+/// Parse a whole response, as read from the connection.
+pub(crate) fn parse(raw: &[u8]) -> io::Result<Response> {
+    let end = raw.windows(4).position(|w| w == b"\r\n\r\n").ok_or_else(|| invalid("no end of headers"))?;
+    let head = std::str::from_utf8(&raw[..end]).map_err(|_| invalid("headers aren't UTF-8"))?;
+    let mut lines = head.split("\r\n");
+    let status = lines.next()
+        .and_then(|line| line.split(' ').nth(1))
+        .and_then(|code| code.parse().ok())
+        .ok_or_else(|| invalid("bad status line"))?;
+    let headers: Vec<(String, String)> = lines
+        .filter_map(|line| {
+            let colon = line.find(':')?;
+            Some((line[..colon].trim().to_ascii_lowercase(), line[colon + 1..].trim().to_string()))
+        })
+        .collect();
+    let mut response = Response { status, headers, body: raw[end + 4..].to_vec() };
+    if response.header("transfer-encoding").is_some_and(|te| te.eq_ignore_ascii_case("chunked")) {
+        response.body = dechunk(&response.body)?;
+    }
+    Ok(response)
+}
+
+/// Undo chunked transfer encoding, which some servers use even in answer
+/// to HTTP/1.0 requests.
+fn dechunk(mut body: &[u8]) -> io::Result<Vec<u8>> {
+    let mut out = Vec::new();
+    loop {
+        let line_end = body.windows(2).position(|w| w == b"\r\n").ok_or_else(|| invalid("bad chunk"))?;
+        let size = std::str::from_utf8(&body[..line_end]).ok()
+            .and_then(|line| usize::from_str_radix(line.split(';').next()?.trim(), 16).ok())
+            .ok_or_else(|| invalid("bad chunk size"))?;
+        body = &body[line_end + 2..];
+        if size == 0 {
+            return Ok(out);
+        }
+        let chunk = body.get(..size).ok_or_else(|| invalid("short chunk"))?;
+        out.extend_from_slice(chunk);
+        body = body.get(size + 2..).ok_or_else(|| invalid("short chunk"))?;
+    }
+}
+
+#[test]
+fn test_http_get() {
+    use std::os::unix::net::UnixStream;
+    use std::thread;
+
+    let (client, mut server) = UnixStream::pair().unwrap();
+    let serving = thread::spawn(move || {
+        let mut request = [0_u8; 512];
+        let len = server.read(&mut request).unwrap();
+        server.write_all(b"HTTP/1.1 200 OK\r\nContent-Type: application/json\r\n\
+                           Transfer-Encoding: chunked\r\n\r\n\
+                           3\r\n[1,\r\n2\r\n2]\r\n0\r\n\r\n").unwrap();
+        String::from_utf8(request[..len].to_vec()).unwrap()
+    });
+    let response = get(client, "localhost", "/containers/json", &[("X-Token", "t")]).unwrap();
+    assert_eq!((response.status, &response.body[..]), (200, &b"[1,2]"[..]));
+    assert_eq!(response.header("content-type"), Some("application/json"));
+    let request = serving.join().unwrap();
+    assert!(request.starts_with("GET /containers/json HTTP/1.0\r\nHost: localhost\r\n"));
+    assert!(request.ends_with("X-Token: t\r\n\r\n"));
+
+    assert!(parse(b"HTTP/1.0 404 Not Found\r\n\r\n").unwrap().body.is_empty());
+    assert!(parse(b"HTTP/1.0 200 OK\r\n").is_err());
+    assert_eq!(split_url("http://127.0.0.1:8080/api"), Some((false, "127.0.0.1", 8080, "/api")));
+    assert_eq!(split_url("ftp://example.test/"), None);
+
+    #[cfg(feature = "rustls")]
+    {
+        assert_eq!(split_https_url("https://10.0.0.1:6443"), Some(("10.0.0.1", 6443, "/")));
+        assert_eq!(split_https_url("https://[fd00::1]/api/x"), Some(("fd00::1", 443, "/api/x")));
+        assert_eq!(split_https_url("http://example.test/"), None);
+    }
+}
@@ -0,0 +1,145 @@
+//! Internationalized hostnames, looked up by their ASCII forms.
+
+use crate::diag;
+use crate::errors::{Error, HostError, NssStatus, Result};
+use crate::hostname::is_valid_hostname;
+use crate::interfaces::{AddressFamily, Entries, HostAddresses, HostEntry, HostEntryWithTtl, HostLimits, NameService};
+use libc::ENOENT;
+use std::borrow::Cow;
+use std::ffi::{CStr, CString};
+use std::marker::PhantomData;
+use std::net::IpAddr;
+use std::time::Duration;
+
+/// The `hosts` service `S`, but with Unicode hostnames converted to
+/// A-labels, the ASCII form DNS uses (UTS #46 and Punycode), before `S`
+/// sees them, for services that only know ASCII names:
+///
+/// ```ignore
+/// nssglue_hosts!("consul", Idn<ConsulService>);
+/// ```
+///
+/// A lookup of `bücher.example` asks `S` for `xn--bcher-kva.example`. Names
+/// that are already ASCII, or aren't UTF-8, are passed on unchanged. A name
+/// UTS #46 rejects isn't found. Results keep `S`'s ASCII names, as glibc's
+/// `dns` service does; `getaddrinfo` converts them back for callers that
+/// pass `AI_CANONIDN`.
+///
+/// If `S` sets `VALIDATE_HOSTNAMES`, it applies to the converted name, not
+/// the Unicode one. When a converted lookup fails, other than by not
+/// finding the name, the message logged gives both forms of the name.
+/// Reverse lookups and enumeration go to `S` unchanged, as do `S`'s other
+/// settings, such as `LOOKUP_TIMEOUT`.
+pub struct Idn<S>(PhantomData<S>);
+
+/// The name to ask for in place of `name`.
+fn to_ascii(name: &CStr) -> Result<Cow<'_, CStr>> {
+    let text = match name.to_str() {
+        Ok(text) if !text.is_ascii() => text,
+        _ => return Ok(Cow::Borrowed(name)),
+    };
+    idna::domain_to_ascii(text)
+        .ok()
+        .and_then(|ascii| CString::new(ascii).ok())
+        .map(Cow::Owned)
+        .ok_or_else(|| Error::with_host(NssStatus::NotFound, ENOENT, HostError::HostNotFound))
+}
+
+/// `name` in Unicode, for messages.
+fn to_unicode(name: &CStr) -> String {
+    let (unicode, _) = idna::domain_to_unicode(&name.to_string_lossy());
+    unicode
+}
+
+impl<S: NameService> Idn<S> {
+    fn lookup<T>(name: &CStr, s: impl FnOnce(&CStr) -> Result<Option<T>>) -> Result<Option<T>> {
+        let ascii = to_ascii(name)?;
+        if S::VALIDATE_HOSTNAMES && !is_valid_hostname(&ascii) {
+            return Err(Error::with_host(NssStatus::NotFound, ENOENT, HostError::HostNotFound));
+        }
+        let result = s(&ascii);
+        if let (Cow::Owned(ascii), Err(err)) = (&ascii, &result) {
+            if err.status() != NssStatus::NotFound && !err.is_insufficient_buffer() {
+                diag::log(format_args!("can't look up {} (as {}): {:?}, errno {}",
+                                       to_unicode(ascii), ascii.to_string_lossy(), err.status(), err.errno()));
+            }
+        }
+        result
+    }
+}
+
+impl<S: NameService> NameService for Idn<S> {
+    const ALLOW_EMPTY_ADDRESS_LIST: bool = S::ALLOW_EMPTY_ADDRESS_LIST;
+    const LOOKUP_TIMEOUT: Option<Duration> = S::LOOKUP_TIMEOUT;
+    const LIMITS: HostLimits = S::LIMITS;
+    // `lookup` checks the converted name instead.
+    const VALIDATE_HOSTNAMES: bool = false;
+
+    fn gethostbyname_r(name: &CStr) -> Result<Option<HostEntry<'_>>> {
+        Self::lookup(name, |name| Ok(S::gethostbyname_r(name)?.map(HostEntry::into_owned)))
+    }
+
+    fn gethostbyname2_r(name: &CStr, af: AddressFamily) -> Result<Option<HostEntry<'_>>> {
+        Self::lookup(name, |name| Ok(S::gethostbyname2_r(name, af)?.map(HostEntry::into_owned)))
+    }
+
+    fn gethostbyaddr_r(addr: &IpAddr) -> Result<Option<HostEntry<'_>>> {
+        S::gethostbyaddr_r(addr)
+    }
+
+    fn gethostbyname3_r(name: &CStr, af: AddressFamily) -> Result<Option<HostEntryWithTtl<'_>>> {
+        Self::lookup(name, |name| Ok(S::gethostbyname3_r(name, af)?.map(HostEntryWithTtl::into_owned)))
+    }
+
+    fn gethostbyname4_r(name: &CStr) -> Result<Option<HostAddresses<'_>>> {
+        Self::lookup(name, |name| Ok(S::gethostbyname4_r(name)?.map(HostAddresses::into_owned)))
+    }
+
+    fn gethostbyaddr2_r(addr: &IpAddr) -> Result<Option<HostEntryWithTtl<'_>>> {
+        S::gethostbyaddr2_r(addr)
+    }
+
+    fn sethostent(stay_open: bool) -> Result<Entries<HostEntry<'static>>> {
+        S::sethostent(stay_open)
+    }
+
+    fn on_fork_child() {
+        S::on_fork_child()
+    }
+}
+
+#[test]
+fn test_idn() {
+    use crate::testing::{gethostbyname2, FixedHosts};
+
+    let name = |s: &'static [u8]| CStr::from_bytes_with_nul(s).unwrap();
+    let ascii = |s: &str| to_ascii(&CString::new(s).unwrap()).ok().map(|s| s.to_str().unwrap().to_string());
+    assert_eq!(ascii("bücher.example").as_deref(), Some("xn--bcher-kva.example"));
+    assert_eq!(ascii("BÜCHER.example.").as_deref(), Some("xn--bcher-kva.example."));
+    assert_eq!(ascii("Plain_ASCII.example").as_deref(), Some("Plain_ASCII.example"));
+    assert_eq!(ascii("bad\u{fffd}.ü"), None);
+    assert_eq!(to_unicode(name(b"xn--bcher-kva.example\0")), "bücher.example");
+
+    struct Punycoded;
+    impl NameService for Punycoded {
+        const VALIDATE_HOSTNAMES: bool = true;
+
+        fn gethostbyname2_r(name: &CStr, af: AddressFamily) -> Result<Option<HostEntry<'_>>> {
+            match name.to_bytes() {
+                b"xn--bcher-kva.test" => {
+                    FixedHosts::gethostbyname2_r(CStr::from_bytes_with_nul(b"host.test\0").unwrap(), af)
+                }
+                _ => FixedHosts::gethostbyname2_r(name, af),
+            }
+        }
+
+        fn gethostbyaddr_r(addr: &IpAddr) -> Result<Option<HostEntry<'_>>> {
+            FixedHosts::gethostbyaddr_r(addr)
+        }
+    }
+    let entry = gethostbyname2::<Idn<Punycoded>>("Bücher.test", AddressFamily::Ipv4).unwrap().unwrap();
+    assert_eq!(entry.name.to_str(), Ok("host.test"));
+    assert!(gethostbyname2::<Idn<Punycoded>>("localhost", AddressFamily::Ipv4).unwrap().is_some());
+    let err = Idn::<Punycoded>::gethostbyname2_r(name(b"a b.test\0"), AddressFamily::Ipv4).unwrap_err();
+    assert_eq!(err.host_error(), Some(HostError::HostNotFound));
+}
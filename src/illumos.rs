@@ -0,0 +1,475 @@
+//! The illumos and Solaris name service switch.
+//!
+//! Solaris-derived systems load `nss_NAME.so.1` and call a constructor for
+//! each database, `_nss_NAME_passwd_constr` and so on, which returns a
+//! backend: a table of operations, indexed by the `NSS_DBOP_*` numbers of
+//! `<nss_dbdefs.h>`. Each lookup operation gets an `nss_XbyY_args_t` holding
+//! the key and the caller's record and buffer.
+//!
+//! The operations here call the same glue as the glibc entry points, so
+//! records are filled in exactly as on Linux. The export macros emit the
+//! constructors when building for illumos or Solaris.
+//!
+//! When the caller is `nscd`, there is no record: it wants the entry as a
+//! line in the format of the files under `/etc`. Those lines are made from
+//! a record filled in on the side.
+//!
+//! Only the `hosts` database is adapted for host lookups, so IPv6 lookups
+//! through `ipnodes` don't reach the service.
+
+use crate::errors::NssStatus;
+use crate::macros;
+use crate::{GroupService, NameService, PasswdService};
+use libc::{c_char, c_int, c_void, gid_t, group, hostent, passwd, uid_t};
+use std::ffi::CStr;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::{mem, ptr};
+
+/// `nss_status_t`.
+const NSS_SUCCESS: c_int = 0;
+const NSS_NOTFOUND: c_int = 1;
+const NSS_UNAVAIL: c_int = 2;
+const NSS_TRYAGAIN: c_int = 3;
+
+// Operation numbers, from `<nss_common.h>` and `<nss_dbdefs.h>`. Every
+// database numbers its lookups from 4.
+pub const NSS_DBOP_DESTRUCTOR: usize = 0;
+pub const NSS_DBOP_ENDENT: usize = 1;
+pub const NSS_DBOP_SETENT: usize = 2;
+pub const NSS_DBOP_GETENT: usize = 3;
+pub const NSS_DBOP_BYNAME: usize = 4;
+pub const NSS_DBOP_BYID: usize = 5;
+
+/// An operation of a backend.
+#[allow(non_camel_case_types)]
+pub type nss_backend_op_t = Option<unsafe extern "C" fn(be: *mut nss_backend_t, args: *mut c_void) -> c_int>;
+
+/// The head of every backend.
+#[allow(non_camel_case_types)]
+#[repr(C)]
+pub struct nss_backend_t {
+    pub ops: *const nss_backend_op_t,
+    pub n_ops: c_int,
+}
+
+/// `nss_XbyY_buf_t`: where the caller wants the result.
+#[allow(non_camel_case_types)]
+#[repr(C)]
+pub struct nss_XbyY_buf_t {
+    pub result: *mut c_void,
+    pub buffer: *mut c_char,
+    pub buflen: c_int,
+}
+
+/// The `hostaddr` member of `union nss_XbyY_key`.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct HostAddrKey {
+    pub addr: *const c_char,
+    pub len: c_int,
+    pub type_: c_int,
+}
+
+/// `union nss_XbyY_key`, with the members this crate uses. The largest
+/// members are two pointers wide.
+#[allow(non_camel_case_types)]
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub union nss_XbyY_key {
+    pub uid: uid_t,
+    pub gid: gid_t,
+    pub name: *const c_char,
+    pub hostaddr: HostAddrKey,
+    _size: [*const c_void; 2],
+}
+
+/// `nss_XbyY_args_t`, the argument of every lookup and `getent`.
+#[allow(non_camel_case_types)]
+#[repr(C)]
+pub struct nss_XbyY_args_t {
+    pub buf: nss_XbyY_buf_t,
+    pub stayopen: c_int,
+    pub str2ent: Option<unsafe extern "C" fn(*const c_char, c_int, *mut c_void, *mut c_char, c_int) -> c_int>,
+    pub key: nss_XbyY_key,
+    pub returnval: *mut c_void,
+    pub erange: c_int,
+    pub h_errno: c_int,
+    pub status: c_int,
+    pub key2str: *mut c_void,
+    pub returnlen: usize,
+}
+
+/// A backend, as the constructors allocate it.
+#[repr(C)]
+struct Backend {
+    base: nss_backend_t,
+    ops: Vec<nss_backend_op_t>,
+}
+
+/// The body of a constructor: allocate a backend with the given operations,
+/// plus a destructor. Returns null if out of memory, which the switch treats
+/// as the source being unavailable.
+pub fn backend(ops: &[(usize, nss_backend_op_t)]) -> *mut nss_backend_t {
+    let len = ops.iter().map(|&(index, _)| index + 1).max().unwrap_or(1);
+    let mut table = Vec::new();
+    if table.try_reserve_exact(len).is_err() {
+        return ptr::null_mut();
+    }
+    table.resize(len, None);
+    table[NSS_DBOP_DESTRUCTOR] = Some(destroy as unsafe extern "C" fn(_, _) -> _);
+    for &(index, op) in ops {
+        table[index] = op;
+    }
+    let backend = Box::new(Backend {
+        base: nss_backend_t { ops: table.as_ptr(), n_ops: len as c_int },
+        ops: table,
+    });
+    Box::into_raw(backend) as *mut nss_backend_t
+}
+
+unsafe extern "C" fn destroy(be: *mut nss_backend_t, _args: *mut c_void) -> c_int {
+    if !be.is_null() {
+        drop(Box::from_raw(be as *mut Backend));
+    }
+    NSS_SUCCESS
+}
+
+/// Translate a glue function's status, leaving `args.erange` set if the
+/// caller's buffer was too small.
+fn status(args: &mut nss_XbyY_args_t, status: NssStatus, errno: c_int) -> c_int {
+    match status {
+        NssStatus::Success => NSS_SUCCESS,
+        NssStatus::NotFound => NSS_NOTFOUND,
+        NssStatus::Unavailable => NSS_UNAVAIL,
+        NssStatus::TryAgain if errno == libc::ERANGE => {
+            args.erange = 1;
+            NSS_NOTFOUND
+        }
+        NssStatus::TryAgain => NSS_TRYAGAIN,
+    }
+}
+
+/// Run a lookup or `getent`: call `glue` on the caller's record and buffer,
+/// or, if the caller wants a line of text instead, on a record and buffer
+/// of our own, and format the line with `to_line`.
+unsafe fn lookup<R>(
+    args: *mut c_void,
+    glue: impl FnOnce(*mut R, *mut c_char, usize, *mut c_int, *mut c_int) -> NssStatus,
+    to_line: unsafe fn(&R) -> Vec<u8>,
+) -> c_int {
+    if args.is_null() {
+        return NSS_UNAVAIL;
+    }
+    let args = &mut *(args as *mut nss_XbyY_args_t);
+    let buflen = args.buf.buflen.max(0) as usize;
+    let mut errno = 0;
+    let mut h_errno = 0;
+
+    if !args.buf.result.is_null() {
+        let record = args.buf.result as *mut R;
+        let result = glue(record, args.buf.buffer, buflen, &mut errno, &mut h_errno);
+        args.h_errno = h_errno;
+        let result = status(args, result, errno);
+        if result == NSS_SUCCESS {
+            args.returnval = record as *mut c_void;
+        }
+        return result;
+    }
+
+    // nscd: fill in a record of our own, then write it out as text.
+    let mut scratch = Vec::new();
+    if args.buf.buffer.is_null() || scratch.try_reserve_exact(buflen).is_err() {
+        return NSS_UNAVAIL;
+    }
+    scratch.resize(buflen, 0_u8);
+    let mut record: R = mem::zeroed();
+    let result = glue(&mut record, scratch.as_mut_ptr() as *mut c_char, buflen, &mut errno, &mut h_errno);
+    args.h_errno = h_errno;
+    let result = status(args, result, errno);
+    if result != NSS_SUCCESS {
+        return result;
+    }
+    let line = to_line(&record);
+    if line.len() >= buflen {
+        args.erange = 1;
+        return NSS_NOTFOUND;
+    }
+    ptr::copy_nonoverlapping(line.as_ptr(), args.buf.buffer as *mut u8, line.len());
+    *args.buf.buffer.add(line.len()) = 0;
+    args.returnval = args.buf.buffer as *mut c_void;
+    args.returnlen = line.len();
+    NSS_SUCCESS
+}
+
+unsafe fn c_str(p: *const c_char) -> &'static [u8] {
+    if p.is_null() { b"" } else { CStr::from_ptr(p).to_bytes() }
+}
+
+/// Append the strings of a null-terminated array, separated by `separator`.
+unsafe fn push_list(line: &mut Vec<u8>, mut list: *const *mut c_char, separator: u8) {
+    let mut first = true;
+    while !list.is_null() && !(*list).is_null() {
+        if !first {
+            line.push(separator);
+        }
+        line.extend_from_slice(c_str(*list));
+        first = false;
+        list = list.add(1);
+    }
+}
+
+/// A line of `/etc/passwd`.
+unsafe fn passwd_line(pw: &passwd) -> Vec<u8> {
+    let mut line = Vec::new();
+    line.extend_from_slice(c_str(pw.pw_name));
+    line.push(b':');
+    line.extend_from_slice(c_str(pw.pw_passwd));
+    line.extend_from_slice(format!(":{}:{}:", pw.pw_uid, pw.pw_gid).as_bytes());
+    line.extend_from_slice(c_str(pw.pw_gecos));
+    line.push(b':');
+    line.extend_from_slice(c_str(pw.pw_dir));
+    line.push(b':');
+    line.extend_from_slice(c_str(pw.pw_shell));
+    line
+}
+
+/// A line of `/etc/group`.
+unsafe fn group_line(gr: &group) -> Vec<u8> {
+    let mut line = Vec::new();
+    line.extend_from_slice(c_str(gr.gr_name));
+    line.push(b':');
+    line.extend_from_slice(c_str(gr.gr_passwd));
+    line.extend_from_slice(format!(":{}:", gr.gr_gid).as_bytes());
+    push_list(&mut line, gr.gr_mem, b',');
+    line
+}
+
+/// Lines of `/etc/hosts`, one per address.
+unsafe fn hosts_lines(host: &hostent) -> Vec<u8> {
+    let mut lines = Vec::new();
+    let mut addr = host.h_addr_list as *const *mut c_char;
+    while !addr.is_null() && !(*addr).is_null() {
+        if !lines.is_empty() {
+            lines.push(b'\n');
+        }
+        let text = if host.h_addrtype == libc::AF_INET6 {
+            Ipv6Addr::from(*(*addr as *const [u8; 16])).to_string()
+        } else {
+            Ipv4Addr::from(*(*addr as *const [u8; 4])).to_string()
+        };
+        lines.extend_from_slice(text.as_bytes());
+        lines.push(b' ');
+        lines.extend_from_slice(c_str(host.h_name));
+        if !host.h_aliases.is_null() && !(*host.h_aliases).is_null() {
+            lines.push(b' ');
+            push_list(&mut lines, host.h_aliases, b' ');
+        }
+        addr = addr.add(1);
+    }
+    lines
+}
+
+unsafe fn key(args: *mut c_void) -> nss_XbyY_key {
+    if args.is_null() {
+        nss_XbyY_key { _size: [ptr::null(); 2] }
+    } else {
+        (*(args as *mut nss_XbyY_args_t)).key
+    }
+}
+
+/// # Safety
+///
+/// `args` must point to the `nss_XbyY_args_t` the switch passes to this
+/// operation.
+pub unsafe extern "C" fn gethostbyname<T: NameService + 'static>(_be: *mut nss_backend_t, args: *mut c_void) -> c_int {
+    // The `hosts` database is IPv4 only; IPv6 goes through `ipnodes`.
+    let name = key(args).name;
+    lookup(args, |result, buffer, buflen, errnop, h_errnop| {
+        macros::call_gethostbyname2_r::<T>(name, libc::AF_INET, result, buffer, buflen, errnop, h_errnop)
+    }, hosts_lines)
+}
+
+/// # Safety
+///
+/// `args` must point to the `nss_XbyY_args_t` the switch passes to this
+/// operation.
+pub unsafe extern "C" fn gethostbyaddr<T: NameService + 'static>(_be: *mut nss_backend_t, args: *mut c_void) -> c_int {
+    let addr = key(args).hostaddr;
+    lookup(args, |result, buffer, buflen, errnop, h_errnop| {
+        macros::call_gethostbyaddr_r::<T>(addr.addr as *const c_void, addr.len, addr.type_,
+                                          result, buffer, buflen, errnop, h_errnop)
+    }, hosts_lines)
+}
+
+pub extern "C" fn sethostent<T: NameService>(_be: *mut nss_backend_t, _args: *mut c_void) -> c_int {
+    macros::call_sethostent::<T>(0);
+    NSS_SUCCESS
+}
+
+/// # Safety
+///
+/// `args` must point to the `nss_XbyY_args_t` the switch passes to this
+/// operation.
+pub unsafe extern "C" fn gethostent<T: NameService>(_be: *mut nss_backend_t, args: *mut c_void) -> c_int {
+    lookup(args, |result, buffer, buflen, errnop, h_errnop| {
+        macros::call_gethostent_r::<T>(result, buffer, buflen, errnop, h_errnop)
+    }, hosts_lines)
+}
+
+pub extern "C" fn endhostent<T: NameService>(_be: *mut nss_backend_t, _args: *mut c_void) -> c_int {
+    macros::call_endhostent::<T>();
+    NSS_SUCCESS
+}
+
+/// # Safety
+///
+/// `args` must point to the `nss_XbyY_args_t` the switch passes to this
+/// operation.
+pub unsafe extern "C" fn getpwnam<T: PasswdService + 'static>(_be: *mut nss_backend_t, args: *mut c_void) -> c_int {
+    let name = key(args).name;
+    lookup(args, |result, buffer, buflen, errnop, _| {
+        macros::call_getpwnam_r::<T>(name, result, buffer, buflen, errnop)
+    }, passwd_line)
+}
+
+/// # Safety
+///
+/// `args` must point to the `nss_XbyY_args_t` the switch passes to this
+/// operation.
+pub unsafe extern "C" fn getpwuid<T: PasswdService + 'static>(_be: *mut nss_backend_t, args: *mut c_void) -> c_int {
+    let uid = key(args).uid;
+    lookup(args, |result, buffer, buflen, errnop, _| {
+        macros::call_getpwuid_r::<T>(uid, result, buffer, buflen, errnop)
+    }, passwd_line)
+}
+
+pub extern "C" fn setpwent<T: PasswdService>(_be: *mut nss_backend_t, _args: *mut c_void) -> c_int {
+    macros::call_setpwent::<T>();
+    NSS_SUCCESS
+}
+
+/// # Safety
+///
+/// `args` must point to the `nss_XbyY_args_t` the switch passes to this
+/// operation.
+pub unsafe extern "C" fn getpwent<T: PasswdService>(_be: *mut nss_backend_t, args: *mut c_void) -> c_int {
+    lookup(args, |result, buffer, buflen, errnop, _| {
+        macros::call_getpwent_r::<T>(result, buffer, buflen, errnop)
+    }, passwd_line)
+}
+
+pub extern "C" fn endpwent<T: PasswdService>(_be: *mut nss_backend_t, _args: *mut c_void) -> c_int {
+    macros::call_endpwent::<T>();
+    NSS_SUCCESS
+}
+
+/// # Safety
+///
+/// `args` must point to the `nss_XbyY_args_t` the switch passes to this
+/// operation.
+pub unsafe extern "C" fn getgrnam<T: GroupService + 'static>(_be: *mut nss_backend_t, args: *mut c_void) -> c_int {
+    let name = key(args).name;
+    lookup(args, |result, buffer, buflen, errnop, _| {
+        macros::call_getgrnam_r::<T>(name, result, buffer, buflen, errnop)
+    }, group_line)
+}
+
+/// # Safety
+///
+/// `args` must point to the `nss_XbyY_args_t` the switch passes to this
+/// operation.
+pub unsafe extern "C" fn getgrgid<T: GroupService + 'static>(_be: *mut nss_backend_t, args: *mut c_void) -> c_int {
+    let gid = key(args).gid;
+    lookup(args, |result, buffer, buflen, errnop, _| {
+        macros::call_getgrgid_r::<T>(gid, result, buffer, buflen, errnop)
+    }, group_line)
+}
+
+pub extern "C" fn setgrent<T: GroupService>(_be: *mut nss_backend_t, _args: *mut c_void) -> c_int {
+    macros::call_setgrent::<T>();
+    NSS_SUCCESS
+}
+
+/// # Safety
+///
+/// `args` must point to the `nss_XbyY_args_t` the switch passes to this
+/// operation.
+pub unsafe extern "C" fn getgrent<T: GroupService>(_be: *mut nss_backend_t, args: *mut c_void) -> c_int {
+    lookup(args, |result, buffer, buflen, errnop, _| {
+        macros::call_getgrent_r::<T>(result, buffer, buflen, errnop)
+    }, group_line)
+}
+
+pub extern "C" fn endgrent<T: GroupService>(_be: *mut nss_backend_t, _args: *mut c_void) -> c_int {
+    macros::call_endgrent::<T>();
+    NSS_SUCCESS
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{PasswdEntry, Result};
+    use std::borrow::Cow;
+
+    struct OneUser;
+
+    fn c(s: &'static [u8]) -> Cow<'static, CStr> {
+        Cow::Borrowed(CStr::from_bytes_with_nul(s).unwrap())
+    }
+
+    impl PasswdService for OneUser {
+        fn getpwnam_r(name: &CStr) -> Result<Option<PasswdEntry<'_>>> {
+            Ok(if name.to_bytes() == b"alice" { Self::getpwuid_r(1000)? } else { None })
+        }
+
+        fn getpwuid_r(uid: uid_t) -> Result<Option<PasswdEntry<'static>>> {
+            Ok(if uid != 1000 { None } else {
+                Some(PasswdEntry {
+                    name: c(b"alice\0"), passwd: c(b"x\0"), uid: 1000, gid: 100,
+                    gecos: c(b"Alice\0"), dir: c(b"/home/alice\0"), shell: c(b"/bin/sh\0"),
+                })
+            })
+        }
+    }
+
+    unsafe fn call(op: nss_backend_op_t, args: &mut nss_XbyY_args_t) -> c_int {
+        op.unwrap()(ptr::null_mut(), args as *mut nss_XbyY_args_t as *mut c_void)
+    }
+
+    #[test]
+    fn test_passwd_backend() {
+        let be = backend(&[(NSS_DBOP_BYNAME, Some(getpwnam::<OneUser>)), (NSS_DBOP_BYID, Some(getpwuid::<OneUser>))]);
+        let mut pw: passwd = unsafe { mem::zeroed() };
+        let mut buffer = [0 as c_char; 100];
+        let mut args: nss_XbyY_args_t = unsafe { mem::zeroed() };
+        args.buf = nss_XbyY_buf_t { result: &mut pw as *mut passwd as *mut c_void, buffer: buffer.as_mut_ptr(), buflen: 100 };
+        unsafe {
+            let ops = std::slice::from_raw_parts((*be).ops, (*be).n_ops as usize);
+            assert_eq!(ops.len(), 6);
+
+            args.key.name = b"alice\0".as_ptr() as *const c_char;
+            assert_eq!(call(ops[NSS_DBOP_BYNAME], &mut args), NSS_SUCCESS);
+            assert_eq!(args.returnval, &mut pw as *mut passwd as *mut c_void);
+            assert_eq!(CStr::from_ptr(pw.pw_dir).to_bytes(), b"/home/alice");
+
+            args.key.uid = 1001;
+            assert_eq!(call(ops[NSS_DBOP_BYID], &mut args), NSS_NOTFOUND);
+
+            // Too small.
+            args.key.uid = 1000;
+            args.buf.buflen = 10;
+            assert_eq!(call(ops[NSS_DBOP_BYID], &mut args), NSS_NOTFOUND);
+            assert_eq!(args.erange, 1);
+
+            // nscd wants a line of /etc/passwd.
+            args.erange = 0;
+            args.buf.result = ptr::null_mut();
+            args.buf.buflen = 100;
+            assert_eq!(call(ops[NSS_DBOP_BYID], &mut args), NSS_SUCCESS);
+            assert_eq!(CStr::from_ptr(buffer.as_ptr()).to_bytes(), b"alice:x:1000:100:Alice:/home/alice:/bin/sh");
+            assert_eq!(args.returnlen, 42);
+
+            assert_eq!(ops[NSS_DBOP_DESTRUCTOR].unwrap()(be, ptr::null_mut()), NSS_SUCCESS);
+        }
+    }
+}
@@ -2,10 +2,13 @@
 
 use std::borrow::Cow;
 use std::ffi::CStr;
+use std::iter;
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
-use errors::Result;
+use std::time::Duration;
+use crate::errors::Result;
+use libc::{c_long, c_ulong, gid_t, uid_t};
 
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
 pub enum AddressFamily {
     Ipv4,
     Ipv6
@@ -13,23 +16,292 @@ pub enum AddressFamily {
 
 /// A list of addresses that are of the same address family (either all IPv4 or
 /// all IPv6).
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub enum HostAddressList {
     V4(Vec<Ipv4Addr>),
     V6(Vec<Ipv6Addr>),
 }
 
+impl HostAddressList {
+    /// The number of addresses in the list.
+    pub fn len(&self) -> usize {
+        match *self {
+            HostAddressList::V4(ref addrs) => addrs.len(),
+            HostAddressList::V6(ref addrs) => addrs.len(),
+        }
+    }
+
+    /// True if the list contains no addresses.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
 /// Information about a host, the type of record returned by `gethostbyname`
 /// and friends.
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct HostEntry<'a> {
     pub name: Cow<'a, CStr>,
     pub aliases: Vec<Cow<'a, CStr>>,
     pub addr_list: HostAddressList,
 }
 
+fn owned(s: Cow<'_, CStr>) -> Cow<'static, CStr> {
+    Cow::Owned(s.into_owned())
+}
+
+impl<'a> HostEntry<'a> {
+    /// Copy any borrowed strings, so that the entry borrows nothing.
+    pub fn into_owned(self) -> HostEntry<'static> {
+        HostEntry {
+            name: owned(self.name),
+            aliases: self.aliases.into_iter().map(owned).collect(),
+            addr_list: self.addr_list,
+        }
+    }
+}
+
+/// A `HostEntry` plus the number of seconds the caller may cache it, for
+/// `gethostbyname3_r` and `gethostbyaddr2_r`. A `ttl` of `None` means the
+/// service doesn't know.
+#[derive(Clone, Debug)]
+pub struct HostEntryWithTtl<'a> {
+    pub entry: HostEntry<'a>,
+    pub ttl: Option<u32>,
+}
+
+impl<'a> HostEntryWithTtl<'a> {
+    /// Copy any borrowed strings, so that the entry borrows nothing.
+    pub fn into_owned(self) -> HostEntryWithTtl<'static> {
+        HostEntryWithTtl { entry: self.entry.into_owned(), ttl: self.ttl }
+    }
+}
+
+/// All the addresses of a host, of both address families, the type of
+/// record returned by `gethostbyname4_r` (which is what `getaddrinfo` uses
+/// when it can).
+#[derive(Clone, Debug)]
+pub struct HostAddresses<'a> {
+    pub name: Cow<'a, CStr>,
+    pub addrs: Vec<IpAddr>,
+    pub ttl: Option<u32>,
+}
+
+impl<'a> HostAddresses<'a> {
+    /// Copy the name if it's borrowed, so that the record borrows nothing.
+    pub fn into_owned(self) -> HostAddresses<'static> {
+        HostAddresses { name: owned(self.name), addrs: self.addrs, ttl: self.ttl }
+    }
+}
+
+/// Limits on the host records the glue passes on to callers, so that a
+/// service that returns a huge answer (say, 100,000 addresses) costs the
+/// caller a bounded amount of buffer and time.
+///
+/// Lists longer than the limit are cut short, and aliases that are too long
+/// are left out. A result whose name is too long is reported as
+/// `NssStatus::NotFound` with `h_errno` set to `NO_RECOVERY`, as glibc's
+/// `dns` service does for a malformed answer.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct HostLimits {
+    /// The most aliases to pass on.
+    pub max_aliases: usize,
+
+    /// The most addresses to pass on.
+    pub max_addresses: usize,
+
+    /// The longest name or alias to pass on, in bytes, not counting the
+    /// terminating NUL.
+    pub max_name_len: usize,
+}
+
+impl HostLimits {
+    /// The limits glibc's own `dns` service applies: 48 aliases, 48
+    /// addresses, and names that fit in `NS_MAXDNAME` bytes.
+    pub const DEFAULT: HostLimits = HostLimits {
+        max_aliases: 48,
+        max_addresses: 48,
+        max_name_len: 1024,
+    };
+
+    /// No limits at all.
+    pub const UNLIMITED: HostLimits = HostLimits {
+        max_aliases: usize::MAX,
+        max_addresses: usize::MAX,
+        max_name_len: usize::MAX,
+    };
+}
+
+impl Default for HostLimits {
+    fn default() -> HostLimits {
+        HostLimits::DEFAULT
+    }
+}
+
+/// The entries of a database, in the order `getXXent` should return them.
+///
+/// Services return one of these when a program starts enumerating a
+/// database (`sethostent`, `setpwent`, `setgrent`). The glue keeps it until
+/// the program calls `endXXent` and takes care of handing out each entry
+/// exactly once, even when the caller has to retry with a bigger buffer.
+pub type Entries<E> = Box<dyn Iterator<Item = Result<E>> + Send>;
+
+/// An `Entries` iterator with nothing in it, for services that don't
+/// support enumeration.
+pub fn no_entries<E: 'static>() -> Entries<E> {
+    Box::new(iter::empty())
+}
+
+/// Look up a name's addresses with `lookup`, IPv6 and then IPv4, and merge
+/// the two results, keeping the shorter TTL. An error from one family is
+/// returned only if the other found nothing.
+pub(crate) fn lookup_both_families<'a, F>(mut lookup: F) -> Result<Option<HostAddresses<'a>>>
+where
+    F: FnMut(AddressFamily) -> Result<Option<HostEntryWithTtl<'a>>>,
+{
+    let mut merged: Option<HostAddresses> = None;
+    let mut first_error = None;
+    for &af in &[AddressFamily::Ipv6, AddressFamily::Ipv4] {
+        match lookup(af) {
+            Err(err) => first_error = first_error.or(Some(err)),
+            Ok(None) => {}
+            Ok(Some(HostEntryWithTtl { entry, ttl })) => {
+                let addrs: Vec<IpAddr> = match entry.addr_list {
+                    HostAddressList::V4(addrs) => addrs.into_iter().map(IpAddr::V4).collect(),
+                    HostAddressList::V6(addrs) => addrs.into_iter().map(IpAddr::V6).collect(),
+                };
+                match merged {
+                    None => merged = Some(HostAddresses { name: entry.name, addrs, ttl }),
+                    Some(ref mut m) => {
+                        m.addrs.extend(addrs);
+                        m.ttl = match (m.ttl, ttl) {
+                            (Some(a), Some(b)) => Some(a.min(b)),
+                            (a, b) => a.or(b),
+                        };
+                    }
+                }
+            }
+        }
+    }
+    match (merged, first_error) {
+        (None, Some(err)) => Err(err),
+        (merged, _) => Ok(merged),
+    }
+}
+
+/// A user account, the type of record returned by `getpwnam` and friends.
+#[derive(Clone, Debug)]
+pub struct PasswdEntry<'a> {
+    pub name: Cow<'a, CStr>,
+    pub passwd: Cow<'a, CStr>,
+    pub uid: uid_t,
+    pub gid: gid_t,
+    pub gecos: Cow<'a, CStr>,
+    pub dir: Cow<'a, CStr>,
+    pub shell: Cow<'a, CStr>,
+}
+
+impl<'a> PasswdEntry<'a> {
+    /// Copy any borrowed strings, so that the entry borrows nothing.
+    pub fn into_owned(self) -> PasswdEntry<'static> {
+        PasswdEntry {
+            name: owned(self.name),
+            passwd: owned(self.passwd),
+            uid: self.uid,
+            gid: self.gid,
+            gecos: owned(self.gecos),
+            dir: owned(self.dir),
+            shell: owned(self.shell),
+        }
+    }
+}
+
+/// A group, the type of record returned by `getgrnam` and friends.
+#[derive(Clone, Debug)]
+pub struct GroupEntry<'a> {
+    pub name: Cow<'a, CStr>,
+    pub passwd: Cow<'a, CStr>,
+    pub gid: gid_t,
+    pub members: Vec<Cow<'a, CStr>>,
+}
+
+impl<'a> GroupEntry<'a> {
+    /// Copy any borrowed strings, so that the entry borrows nothing.
+    pub fn into_owned(self) -> GroupEntry<'static> {
+        GroupEntry {
+            name: owned(self.name),
+            passwd: owned(self.passwd),
+            gid: self.gid,
+            members: self.members.into_iter().map(owned).collect(),
+        }
+    }
+}
+
+/// A shadow password entry, the type of record returned by `getspnam` and
+/// friends. The day counts are days since 1970-01-01, and -1 in any of the
+/// numeric fields means the field is empty, as in `/etc/shadow`.
+#[derive(Debug)]
+pub struct ShadowEntry<'a> {
+    pub name: Cow<'a, CStr>,
+    pub passwd: Cow<'a, CStr>,
+    pub last_change: c_long,
+    pub min: c_long,
+    pub max: c_long,
+    pub warn: c_long,
+    pub inactive: c_long,
+    pub expire: c_long,
+    pub flag: c_ulong,
+}
+
+impl<'a> ShadowEntry<'a> {
+    /// Copy any borrowed strings, so that the entry borrows nothing.
+    pub fn into_owned(self) -> ShadowEntry<'static> {
+        ShadowEntry {
+            name: owned(self.name),
+            passwd: owned(self.passwd),
+            ..self
+        }
+    }
+}
+
 pub trait NameService {
-    fn gethostbyname_r(name: &CStr) -> Result<Option<HostEntry>> {
+    /// Whether a `HostEntry` with an empty `addr_list` may be passed through
+    /// to the caller as a successful result.
+    ///
+    /// Many callers assume that a successful `gethostbyname` returns at least
+    /// one address, so by default the glue reports such results as
+    /// `NssStatus::NotFound` with `h_errno` set to `NO_DATA` ("valid name, no
+    /// data for requested type"), which is what DNS-based resolvers do.
+    /// Services that really mean to return an empty list can set this to
+    /// `true`.
+    const ALLOW_EMPTY_ADDRESS_LIST: bool = false;
+
+    /// How long a lookup may take before the glue gives up on it.
+    ///
+    /// A service that hangs hangs every program on the machine that looks up
+    /// a name. With a timeout, the glue runs each lookup on a separate
+    /// thread, and if it hasn't finished in time, logs the overrun and
+    /// reports `NssStatus::TryAgain` (errno `EAGAIN`, h_errno `TRY_AGAIN`).
+    /// The abandoned lookup runs to completion in the background, and its
+    /// result is thrown away. While 16 lookups are running that way, new ones
+    /// fail the same way without being started. Enumeration is not covered.
+    ///
+    /// The default is `None`, no timeout, which runs lookups on the calling
+    /// thread.
+    const LOOKUP_TIMEOUT: Option<Duration> = None;
+
+    /// Limits on the results passed on to callers. See `HostLimits`.
+    const LIMITS: HostLimits = HostLimits::DEFAULT;
+
+    /// Whether the glue should answer lookups of names that aren't valid
+    /// hostnames (see `is_valid_hostname`) with `NssStatus::NotFound` itself,
+    /// without calling the service. Services that build queries out of the
+    /// name, in SQL, URLs, or the like, can turn this on and get only
+    /// well-behaved names. The default is `false`, which passes any name
+    /// through.
+    const VALIDATE_HOSTNAMES: bool = false;
+
+    fn gethostbyname_r(name: &CStr) -> Result<Option<HostEntry<'_>>> {
         Self::gethostbyname2_r(name, AddressFamily::Ipv4)
     }
 
@@ -49,7 +321,7 @@ pub trait NameService {
     /// # use nsswitch_service::*;
     /// # use std::ffi::CStr;
     /// # #[allow(dead_code)]
-    /// # fn my_gethostbyname2_r(name: &CStr) -> Result<Option<HostEntry>> {
+    /// # fn my_gethostbyname2_r(name: &CStr) -> Result<Option<HostEntry<'_>>> {
     /// // Convert the C null-terminated string `name` to a Rust &str.
     /// let name_str = match name.to_str() {
     ///     Err(_) => return Ok(None),  // `name` isn't UTF-8, so bail out.
@@ -67,8 +339,124 @@ pub trait NameService {
     /// *   `Ok(None)` to indicate that no addresses exist for the name;
     /// *   `Ok(Some(HostEntry))`, a successful query result.
     ///
-    fn gethostbyname2_r(name: &CStr, af: AddressFamily) -> Result<Option<HostEntry>>;
+    fn gethostbyname2_r(name: &CStr, af: AddressFamily) -> Result<Option<HostEntry<'_>>>;
+
+    fn gethostbyaddr_r(addr: &IpAddr) -> Result<Option<HostEntry<'_>>>;
+
+    /// Like `gethostbyname2_r`, but also says how long the result may be
+    /// cached. The default implementation calls `gethostbyname2_r` and
+    /// reports no TTL.
+    fn gethostbyname3_r(name: &CStr, af: AddressFamily) -> Result<Option<HostEntryWithTtl<'_>>> {
+        Ok(Self::gethostbyname2_r(name, af)?.map(|entry| HostEntryWithTtl { entry, ttl: None }))
+    }
+
+    /// Look up addresses of both families for `name`.
+    ///
+    /// The default implementation calls `gethostbyname3_r` for IPv6 and then
+    /// IPv4, and merges the results. If only one of the two lookups fails,
+    /// the other's addresses are returned.
+    fn gethostbyname4_r(name: &CStr) -> Result<Option<HostAddresses<'_>>> {
+        lookup_both_families(|af| Self::gethostbyname3_r(name, af))
+    }
+
+    /// Like `gethostbyaddr_r`, but also says how long the result may be
+    /// cached. The default implementation calls `gethostbyaddr_r` and reports
+    /// no TTL.
+    fn gethostbyaddr2_r(addr: &IpAddr) -> Result<Option<HostEntryWithTtl<'_>>> {
+        Ok(Self::gethostbyaddr_r(addr)?.map(|entry| HostEntryWithTtl { entry, ttl: None }))
+    }
+
+    /// Start enumerating all hosts this service knows about, for
+    /// `gethostent`. `stay_open` is a hint that the program will also do
+    /// lookups before calling `endhostent`. By default, there are no entries.
+    fn sethostent(_stay_open: bool) -> Result<Entries<HostEntry<'static>>> {
+        Ok(no_entries())
+    }
+
+    /// Throw away state that isn't valid in the child after `fork`, such as
+    /// connections and locks. The export macros register this with
+    /// `add_fork_child_hook` when the library is loaded, and it must follow
+    /// the rules given there. By default, it does nothing.
+    fn on_fork_child() {}
+}
+
+/// A service that can look up user accounts, for the `passwd` database.
+pub trait PasswdService {
+    /// See `NameService::LOOKUP_TIMEOUT`.
+    const LOOKUP_TIMEOUT: Option<Duration> = None;
+
+    /// Look up the account named `name`.
+    fn getpwnam_r(name: &CStr) -> Result<Option<PasswdEntry<'_>>>;
+
+    /// Look up the account with user id `uid`.
+    fn getpwuid_r(uid: uid_t) -> Result<Option<PasswdEntry<'static>>>;
+
+    /// Start enumerating all accounts, for `getpwent`. By default, there are
+    /// no entries.
+    fn setpwent() -> Result<Entries<PasswdEntry<'static>>> {
+        Ok(no_entries())
+    }
+
+    /// See `NameService::on_fork_child`.
+    fn on_fork_child() {}
+}
+
+/// A service that can look up groups, for the `group` database.
+pub trait GroupService {
+    /// See `NameService::LOOKUP_TIMEOUT`.
+    const LOOKUP_TIMEOUT: Option<Duration> = None;
+
+    /// Look up the group named `name`.
+    fn getgrnam_r(name: &CStr) -> Result<Option<GroupEntry<'_>>>;
+
+    /// Look up the group with group id `gid`.
+    fn getgrgid_r(gid: gid_t) -> Result<Option<GroupEntry<'static>>>;
+
+    /// Start enumerating all groups, for `getgrent`. By default, there are
+    /// no entries.
+    fn setgrent() -> Result<Entries<GroupEntry<'static>>> {
+        Ok(no_entries())
+    }
+
+    /// List the groups `user` is a member of, for `initgroups` and
+    /// `getgrouplist`, or return `None` if the service knows of no such
+    /// user. `group` is the user's primary group, which the caller already
+    /// has; it needn't be left out.
+    ///
+    /// By default, this enumerates the groups with `setgrent` and picks the
+    /// ones that list `user` as a member, which is what glibc does for
+    /// modules without `initgroups_dyn`. Services that can ask their backend
+    /// directly should.
+    fn initgroups_dyn(user: &CStr, _group: gid_t) -> Result<Option<Vec<gid_t>>> {
+        let mut gids = vec![];
+        for entry in Self::setgrent()? {
+            let entry = entry?;
+            if entry.members.iter().any(|member| **member == *user) {
+                gids.push(entry.gid);
+            }
+        }
+        Ok(if gids.is_empty() { None } else { Some(gids) })
+    }
 
-    fn gethostbyaddr_r(addr: &IpAddr) -> Result<Option<HostEntry>>;
+    /// See `NameService::on_fork_child`.
+    fn on_fork_child() {}
 }
 
+
+/// A service that can look up shadow passwords, for the `shadow` database.
+pub trait ShadowService {
+    /// See `NameService::LOOKUP_TIMEOUT`.
+    const LOOKUP_TIMEOUT: Option<Duration> = None;
+
+    /// Look up the shadow entry for the account named `name`.
+    fn getspnam_r(name: &CStr) -> Result<Option<ShadowEntry<'_>>>;
+
+    /// Start enumerating all shadow entries, for `getspent`. By default,
+    /// there are no entries.
+    fn setspent() -> Result<Entries<ShadowEntry<'static>>> {
+        Ok(no_entries())
+    }
+
+    /// See `NameService::on_fork_child`.
+    fn on_fork_child() {}
+}
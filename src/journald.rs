@@ -0,0 +1,258 @@
+//! Sending diagnostic messages and traces to the systemd journal, with
+//! fields that `journalctl` can match on.
+//!
+//! Each message is sent as one datagram to journald's native socket, so an
+//! operator can ask for one module's failures without grepping:
+//!
+//! ```text
+//! journalctl MODULE=corp DATABASE=hosts STATUS=NSS_STATUS_UNAVAIL
+//! ```
+//!
+//! `log_to_journald` sends diagnostic messages there, with priority `err`,
+//! and `MODULE`, `CODE_FILE`, and `CODE_LINE` fields. Traced calls (see
+//! `TraceSink::Journald`) are sent with priority `debug` and the fields
+//! `MODULE`, `DATABASE`, `QUERY` (the call's arguments, as traced),
+//! `STATUS`, and `LATENCY_USEC`.
+//!
+//! Like the rest of the diagnostics, this takes no locks and allocates
+//! nothing. Entries are built in a fixed buffer on the stack, and long
+//! fields are truncated. If the journal isn't running, nothing is sent.
+
+use crate::diag;
+use libc::c_int;
+use std::fmt::{self, Write};
+use std::mem;
+use std::panic::Location;
+use std::sync::atomic::{AtomicI32, AtomicUsize, Ordering};
+use std::time::Duration;
+
+/// What `log_to_journald` says about the module.
+pub trait JournaldConfig: 'static {
+    /// The module's name, `NAME` in `libnss_NAME.so.2`, sent as the
+    /// `MODULE` field of every entry.
+    const MODULE: &'static str;
+}
+
+/// journald's socket for the native protocol.
+const SOCKET_PATH: &[u8] = b"/run/systemd/journal/socket";
+
+/// `SOCKET` before a socket has been made.
+const UNSET: c_int = -2;
+
+/// `SOCKET` if a socket couldn't be made.
+const FAILED: c_int = -1;
+
+/// The socket entries are sent from, or one of the constants above.
+static SOCKET: AtomicI32 = AtomicI32::new(UNSET);
+
+/// A function returning `JournaldConfig::MODULE`, or 0 before
+/// `log_to_journald` is called.
+static MODULE: AtomicUsize = AtomicUsize::new(0);
+
+fn module_of<C: JournaldConfig>() -> &'static str {
+    C::MODULE
+}
+
+/// Send this crate's diagnostic messages to the systemd journal from now
+/// on, naming the module `C::MODULE`. Traced calls go there too if
+/// `set_trace_sink(TraceSink::Journald)` is called or `NSS_DEBUG` is
+/// `journald`.
+pub fn log_to_journald<C: JournaldConfig>() {
+    MODULE.store(module_of::<C> as fn() -> &'static str as usize, Ordering::Release);
+    diag::log_to_journald();
+}
+
+fn module() -> Option<&'static str> {
+    match MODULE.load(Ordering::Acquire) {
+        0 => None,
+        f => {
+            let f: fn() -> &'static str = unsafe { mem::transmute::<usize, fn() -> &'static str>(f) };
+            Some(f())
+        }
+    }
+}
+
+/// The database a glue function belongs to.
+fn database(function: &str) -> &'static str {
+    if function.contains("host") {
+        "hosts"
+    } else if function.contains("pw") {
+        "passwd"
+    } else if function.contains("gr") {
+        // Including `initgroups_dyn`.
+        "group"
+    } else {
+        "shadow"
+    }
+}
+
+/// A field's value, formatted into a fixed buffer.
+struct Value {
+    buf: [u8; 1024],
+    len: usize,
+}
+
+impl Write for Value {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut n = s.len().min(self.buf.len() - self.len);
+        while !s.is_char_boundary(n) {
+            n -= 1;
+        }
+        self.buf[self.len..self.len + n].copy_from_slice(&s.as_bytes()[..n]);
+        self.len += n;
+        Ok(())
+    }
+}
+
+/// An entry under construction, in journald's native format: `KEY=value`
+/// lines, except that a value with a newline in it follows its key and a
+/// newline, as a 64-bit little-endian length and then the bytes.
+struct Entry {
+    buf: [u8; 2048],
+    len: usize,
+}
+
+impl Entry {
+    fn new() -> Entry {
+        Entry { buf: [0; 2048], len: 0 }
+    }
+
+    fn push(&mut self, bytes: &[u8]) {
+        self.buf[self.len..self.len + bytes.len()].copy_from_slice(bytes);
+        self.len += bytes.len();
+    }
+
+    /// Add a field, truncating its value to fit. If there isn't room even
+    /// for the key, the field is left out.
+    fn field(&mut self, key: &str, value: fmt::Arguments<'_>) {
+        let mut formatted = Value { buf: [0; 1024], len: 0 };
+        let _ = formatted.write_fmt(value);
+        let value = &formatted.buf[..formatted.len];
+        let binary = value.contains(&b'\n');
+        let overhead = key.len() + if binary { 10 } else { 2 };
+        let room = self.buf.len() - self.len;
+        if room < overhead {
+            return;
+        }
+        let value = &value[..value.len().min(room - overhead)];
+        self.push(key.as_bytes());
+        if binary {
+            self.push(b"\n");
+            self.push(&(value.len() as u64).to_le_bytes());
+        } else {
+            self.push(b"=");
+        }
+        self.push(value);
+        self.push(b"\n");
+    }
+
+    fn as_bytes(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+
+    fn send(&self) {
+        let fd = socket();
+        if fd == FAILED {
+            return;
+        }
+        let mut addr: libc::sockaddr_un = unsafe { mem::zeroed() };
+        addr.sun_family = libc::AF_UNIX as libc::sa_family_t;
+        for (dst, &src) in addr.sun_path.iter_mut().zip(SOCKET_PATH) {
+            *dst = src as libc::c_char;
+        }
+        // A journal that's gone or too busy to take the entry loses it.
+        let bytes = self.as_bytes();
+        unsafe {
+            libc::sendto(fd, bytes.as_ptr() as *const libc::c_void, bytes.len(), libc::MSG_NOSIGNAL,
+                         &addr as *const libc::sockaddr_un as *const libc::sockaddr,
+                         mem::size_of::<libc::sockaddr_un>() as libc::socklen_t);
+        }
+    }
+}
+
+/// The socket to send entries from, made the first time it's needed, or
+/// `FAILED`.
+fn socket() -> c_int {
+    let fd = SOCKET.load(Ordering::Acquire);
+    if fd != UNSET {
+        return fd;
+    }
+    let fd = unsafe { libc::socket(libc::AF_UNIX, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0) };
+    let fd = if fd < 0 { FAILED } else { fd };
+    match SOCKET.compare_exchange(UNSET, fd, Ordering::AcqRel, Ordering::Acquire) {
+        Ok(_) => fd,
+        Err(current) => {
+            // Another thread made one meanwhile.
+            if fd >= 0 {
+                unsafe {
+                    libc::close(fd);
+                }
+            }
+            current
+        }
+    }
+}
+
+fn message_entry(message: &str, location: &Location<'_>) -> Entry {
+    let mut entry = Entry::new();
+    entry.field("MESSAGE", format_args!("{}", message));
+    entry.field("PRIORITY", format_args!("{}", libc::LOG_ERR));
+    if let Some(module) = module() {
+        entry.field("MODULE", format_args!("{}", module));
+    }
+    entry.field("CODE_FILE", format_args!("{}", location.file()));
+    entry.field("CODE_LINE", format_args!("{}", location.line()));
+    entry
+}
+
+fn call_entry(function: &str, args: fmt::Arguments<'_>, status: &str, elapsed: Duration) -> Entry {
+    let mut entry = Entry::new();
+    entry.field("MESSAGE", format_args!("{}({}) = {} in {:?}", function, args, status, elapsed));
+    entry.field("PRIORITY", format_args!("{}", libc::LOG_DEBUG));
+    if let Some(module) = module() {
+        entry.field("MODULE", format_args!("{}", module));
+    }
+    entry.field("DATABASE", format_args!("{}", database(function)));
+    entry.field("QUERY", args);
+    entry.field("STATUS", format_args!("{}", status));
+    entry.field("LATENCY_USEC", format_args!("{}", elapsed.as_micros()));
+    entry
+}
+
+/// Send a diagnostic message, logged at `location`, to the journal.
+pub(crate) fn send_message(message: &str, location: &Location<'_>) {
+    message_entry(message, location).send();
+}
+
+/// Send a traced call to the journal.
+pub(crate) fn send_call(function: &str, args: fmt::Arguments<'_>, status: &str, elapsed: Duration) {
+    call_entry(function, args, status, elapsed).send();
+}
+
+#[test]
+fn test_journald_entries() {
+    let entry = call_entry("getgrnam_r", format_args!("{:?}", "wheel"), "NSS_STATUS_SUCCESS",
+                           Duration::from_micros(1500));
+    assert_eq!(std::str::from_utf8(entry.as_bytes()).unwrap(),
+               "MESSAGE=getgrnam_r(\"wheel\") = NSS_STATUS_SUCCESS in 1.5ms\n\
+                PRIORITY=7\n\
+                DATABASE=group\n\
+                QUERY=\"wheel\"\n\
+                STATUS=NSS_STATUS_SUCCESS\n\
+                LATENCY_USEC=1500\n");
+    assert_eq!(database("initgroups_dyn"), "group");
+    assert_eq!(database("gethostbyaddr2_r"), "hosts");
+    assert_eq!(database("endspent"), "shadow");
+
+    // Newlines need the binary form, and long values are truncated.
+    let mut entry = Entry::new();
+    entry.field("MESSAGE", format_args!("two\nlines"));
+    assert_eq!(entry.as_bytes(), &b"MESSAGE\n\x09\0\0\0\0\0\0\0two\nlines\n"[..]);
+    let long = "x".repeat(3000);
+    entry.field("A", format_args!("{}", long));
+    entry.field("B", format_args!("{}", long));
+    entry.field("C", format_args!("{}", long));
+    assert_eq!(entry.len, 2048);
+    assert_eq!(&entry.as_bytes()[1053..1056], b"B=x");
+    assert!(entry.as_bytes().ends_with(b"xx\n"));
+}
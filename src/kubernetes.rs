@@ -0,0 +1,572 @@
+//! A ready-made `hosts` service that resolves Kubernetes Services and Pods
+//! by asking the cluster's API server, for running code outside the cluster
+//! that expects cluster DNS names to work.
+
+use crate::config::env_var;
+use crate::diag;
+use crate::errors::{Error, HostError, NssStatus, Result};
+use crate::fork::ForkSafeMutex;
+use crate::http;
+use crate::interfaces::{AddressFamily, HostAddressList, HostEntry, NameService};
+use base64::Engine;
+use libc::{EINVAL, EIO, ENOENT};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use serde::Deserialize;
+use serde_json::Value;
+use std::borrow::Cow;
+use std::ffi::CStr;
+use std::fs;
+use std::marker::PhantomData;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Which names a `KubernetesService` answers for, and how it reaches the
+/// cluster.
+pub trait KubernetesConfig: 'static {
+    /// The domains this service claims. `web.shop.svc.cluster.local` is the
+    /// Service or Pod `web` in the namespace `shop`. Names under no suffix
+    /// here are left to the next service.
+    const SUFFIXES: &'static [&'static str] = &["svc.cluster.local", "svc"];
+
+    /// The kubeconfig file to use. If `None`, the one in `$KUBECONFIG` or
+    /// `~/.kube/config`, or else the credentials a Pod is given.
+    const KUBECONFIG: Option<&'static str> = None;
+
+    /// How long to remember an answer, including that there's no such
+    /// Service or Pod.
+    const CACHE_TTL: Duration = Duration::from_secs(30);
+
+    /// How long to wait for the API server.
+    const TIMEOUT: Duration = Duration::from_secs(2);
+}
+
+/// The usual settings: the cluster's own DNS suffixes, and the current
+/// kubeconfig context.
+pub struct KubernetesDefaults;
+
+impl KubernetesConfig for KubernetesDefaults {}
+
+/// A `hosts` service that resolves `name.namespace.svc.cluster.local` the
+/// way cluster DNS does, for developers running code on their own machine
+/// against a cluster they can reach:
+///
+/// ```ignore
+/// nssglue_hosts!("kubernetes", KubernetesService);
+/// ```
+///
+/// A name is first looked up as a Service, giving its ClusterIPs, or the
+/// addresses of its ready endpoints if it's headless, and then as a Pod,
+/// giving the Pod's IPs. Answers are cached for `CACHE_TTL`. There are no
+/// reverse lookups.
+///
+/// The credentials come from the kubeconfig's current context, which must
+/// name the cluster's certificate authority and use a token or a client
+/// certificate; exec plugins aren't supported. Without a kubeconfig, the
+/// service account credentials Kubernetes mounts in every Pod are used. If
+/// there are no credentials at all, lookups report `NssStatus::Unavailable`
+/// quietly.
+pub struct KubernetesService<C = KubernetesDefaults>(PhantomData<C>);
+
+/// Where Kubernetes mounts a Pod's service account credentials.
+const SERVICE_ACCOUNT_DIR: &str = "/var/run/secrets/kubernetes.io/serviceaccount";
+
+/// The most answers cached.
+const MAX_CACHED: usize = 256;
+
+/// An answer: `None` means there's no such Service or Pod.
+type Found = Option<Vec<IpAddr>>;
+
+/// Answers, by `name.namespace` in lowercase, with when to forget them.
+static CACHE: ForkSafeMutex<Vec<(String, Instant, Found)>> = ForkSafeMutex::new();
+
+/// What's needed to talk to the API server.
+#[derive(Debug, PartialEq)]
+struct Credentials {
+    /// An `https:` URL.
+    server: String,
+    /// PEM.
+    ca: Vec<u8>,
+    token: Option<String>,
+    /// PEM certificate chain and key.
+    client: Option<(Vec<u8>, Vec<u8>)>,
+}
+
+/// The parts of a kubeconfig file this uses.
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct Kubeconfig {
+    #[serde(default)]
+    current_context: String,
+    #[serde(default)]
+    contexts: Vec<Named<Context>>,
+    #[serde(default)]
+    clusters: Vec<Named<Cluster>>,
+    #[serde(default)]
+    users: Vec<Named<User>>,
+}
+
+#[derive(Deserialize)]
+struct Named<T> {
+    name: String,
+    #[serde(alias = "context", alias = "cluster", alias = "user")]
+    value: T,
+}
+
+#[derive(Deserialize)]
+struct Context {
+    cluster: String,
+    #[serde(default)]
+    user: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct Cluster {
+    server: String,
+    certificate_authority: Option<String>,
+    certificate_authority_data: Option<String>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct User {
+    token: Option<String>,
+    #[serde(rename = "tokenFile")]
+    token_file: Option<String>,
+    client_certificate: Option<String>,
+    client_certificate_data: Option<String>,
+    client_key: Option<String>,
+    client_key_data: Option<String>,
+    exec: Option<serde_yaml::Value>,
+}
+
+/// The contents of a kubeconfig field that can be given inline, in base64,
+/// or as a file, relative to `dir`.
+fn read_field(data: &Option<String>, file: &Option<String>, dir: &Path) -> std::result::Result<Option<Vec<u8>>, String> {
+    if let Some(data) = data {
+        let decoded = base64::engine::general_purpose::STANDARD.decode(data.trim())
+            .map_err(|err| format!("bad base64: {}", err))?;
+        return Ok(Some(decoded));
+    }
+    match file {
+        Some(file) => {
+            let path = dir.join(file);
+            fs::read(&path).map(Some).map_err(|err| format!("can't read {}: {}", path.display(), err))
+        }
+        None => Ok(None),
+    }
+}
+
+/// The credentials for the current context of the kubeconfig `text`, read
+/// from a file in `dir`.
+fn parse_kubeconfig(text: &str, dir: &Path) -> std::result::Result<Credentials, String> {
+    let config: Kubeconfig = serde_yaml::from_str(text).map_err(|err| err.to_string())?;
+    let context = config.contexts.iter()
+        .find(|context| context.name == config.current_context)
+        .ok_or_else(|| format!("no context {:?}", config.current_context))?;
+    let cluster = config.clusters.iter()
+        .find(|cluster| cluster.name == context.value.cluster)
+        .ok_or_else(|| format!("no cluster {:?}", context.value.cluster))?;
+    let no_user = User::default();
+    let user = config.users.iter()
+        .find(|user| user.name == context.value.user)
+        .map_or(&no_user, |user| &user.value);
+    if user.exec.is_some() {
+        return Err(format!("user {:?} uses an exec plugin, which isn't supported", context.value.user));
+    }
+
+    let cluster = &cluster.value;
+    let ca = read_field(&cluster.certificate_authority_data, &cluster.certificate_authority, dir)?
+        .ok_or_else(|| format!("no certificate authority for cluster {:?}", context.value.cluster))?;
+    let token = match (&user.token, &user.token_file) {
+        (Some(token), _) => Some(token.clone()),
+        (None, Some(file)) => {
+            let path = dir.join(file);
+            let token = fs::read_to_string(&path).map_err(|err| format!("can't read {}: {}", path.display(), err))?;
+            Some(token.trim().to_string())
+        }
+        (None, None) => None,
+    };
+    let cert = read_field(&user.client_certificate_data, &user.client_certificate, dir)?;
+    let key = read_field(&user.client_key_data, &user.client_key, dir)?;
+    Ok(Credentials { server: cluster.server.clone(), ca, token, client: cert.zip(key) })
+}
+
+/// The credentials Kubernetes gives a Pod, or `None` outside a cluster.
+fn in_cluster() -> Option<std::result::Result<Credentials, String>> {
+    let host = env_var("KUBERNETES_SERVICE_HOST")?;
+    let port = env_var("KUBERNETES_SERVICE_PORT").unwrap_or_else(|| "443".to_string());
+    let host = if host.contains(':') { format!("[{}]", host) } else { host };
+    let dir = Path::new(SERVICE_ACCOUNT_DIR);
+    let read = |file: &str| {
+        let path = dir.join(file);
+        fs::read(&path).map_err(|err| format!("can't read {}: {}", path.display(), err))
+    };
+    Some(read("ca.crt").and_then(|ca| {
+        let token = String::from_utf8_lossy(&read("token")?).trim().to_string();
+        Ok(Credentials { server: format!("https://{}:{}", host, port), ca, token: Some(token), client: None })
+    }))
+}
+
+/// The TLS settings for talking to the API server with `credentials`.
+fn tls_config(credentials: &Credentials) -> std::result::Result<Arc<rustls::ClientConfig>, String> {
+    let mut roots = rustls::RootCertStore::empty();
+    for cert in CertificateDer::pem_slice_iter(&credentials.ca) {
+        let cert = cert.map_err(|err| format!("bad certificate authority: {}", err))?;
+        roots.add(cert).map_err(|err| format!("bad certificate authority: {}", err))?;
+    }
+    let builder = rustls::ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .map_err(|err| err.to_string())?
+        .with_root_certificates(roots);
+    let config = match &credentials.client {
+        Some((cert, key)) => {
+            let chain = CertificateDer::pem_slice_iter(cert)
+                .collect::<std::result::Result<Vec<_>, _>>()
+                .map_err(|err| format!("bad client certificate: {}", err))?;
+            let key = PrivateKeyDer::from_pem_slice(key).map_err(|err| format!("bad client key: {}", err))?;
+            builder.with_client_auth_cert(chain, key).map_err(|err| err.to_string())?
+        }
+        None => builder.with_no_client_auth(),
+    };
+    Ok(Arc::new(config))
+}
+
+/// The ClusterIPs of a Service, or `None` if it's headless.
+fn service_addrs(service: &Value) -> Option<Vec<IpAddr>> {
+    let spec = &service["spec"];
+    let ips: Vec<&str> = match spec["clusterIPs"].as_array() {
+        Some(ips) => ips.iter().filter_map(Value::as_str).collect(),
+        None => spec["clusterIP"].as_str().into_iter().collect(),
+    };
+    if ips.contains(&"None") {
+        return None;
+    }
+    Some(ips.iter().filter_map(|ip| ip.parse().ok()).collect())
+}
+
+/// The ready addresses in an Endpoints object.
+fn endpoint_addrs(endpoints: &Value) -> Vec<IpAddr> {
+    endpoints["subsets"].as_array().into_iter().flatten()
+        .flat_map(|subset| subset["addresses"].as_array().into_iter().flatten())
+        .filter_map(|address| address["ip"].as_str()?.parse().ok())
+        .collect()
+}
+
+/// The IPs of a Pod.
+fn pod_addrs(pod: &Value) -> Vec<IpAddr> {
+    let status = &pod["status"];
+    match status["podIPs"].as_array() {
+        Some(ips) => ips.iter().filter_map(|ip| ip["ip"].as_str()?.parse().ok()).collect(),
+        None => status["podIP"].as_str().and_then(|ip| ip.parse().ok()).into_iter().collect(),
+    }
+}
+
+/// Whether `s` can be a Kubernetes object name, and so is safe to put in a
+/// URL path.
+fn is_object_name(s: &str) -> bool {
+    !s.is_empty() && s.len() <= 253 && s.bytes().all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'-' || b == b'.')
+}
+
+impl<C: KubernetesConfig> KubernetesService<C> {
+    /// The object name and namespace `name` is for, in lowercase, or `None`
+    /// if it isn't under one of `C::SUFFIXES`.
+    fn split_name(name: &CStr) -> Option<(String, String)> {
+        let name = name.to_str().ok()?.trim_end_matches('.').to_ascii_lowercase();
+        let rest = C::SUFFIXES.iter()
+            .map(|suffix| suffix.trim_matches('.').to_ascii_lowercase())
+            .filter(|suffix| !suffix.is_empty())
+            .find_map(|suffix| name.strip_suffix(&suffix)?.strip_suffix('.').map(str::to_string))?;
+        let (object, namespace) = rest.rsplit_once('.')?;
+        if !is_object_name(object) || !is_object_name(namespace) {
+            return None;
+        }
+        Some((object.to_string(), namespace.to_string()))
+    }
+
+    fn credentials() -> Result<Credentials> {
+        let home_config = || env_var("HOME").map(|home| Path::new(&home).join(".kube/config"));
+        let path: Option<PathBuf> = match C::KUBECONFIG {
+            Some(path) => Some(PathBuf::from(path)),
+            None => env_var("KUBECONFIG")
+                .and_then(|list| list.split(':').find(|path| !path.is_empty()).map(PathBuf::from))
+                .or_else(|| home_config().filter(|path| path.exists())),
+        };
+        let found = match path {
+            Some(path) => Some(fs::read_to_string(&path)
+                .map_err(|err| format!("can't read {}: {}", path.display(), err))
+                .and_then(|text| parse_kubeconfig(&text, path.parent().unwrap_or_else(|| Path::new(".")))
+                    .map_err(|message| format!("{}: {}", path.display(), message)))),
+            None => in_cluster(),
+        };
+        match found {
+            Some(Ok(credentials)) => Ok(credentials),
+            Some(Err(message)) => {
+                diag::log(format_args!("no Kubernetes credentials: {}", message));
+                Err(Error::with_errno(NssStatus::Unavailable, EINVAL))
+            }
+            None => Err(Error::with_errno(NssStatus::Unavailable, ENOENT)),
+        }
+    }
+
+    /// Fetch `path` from the API server, or `None` if it doesn't exist.
+    fn get(credentials: &Credentials, config: &Arc<rustls::ClientConfig>, path: &str) -> Result<Option<Value>> {
+        let (host, port, base) = http::split_https_url(&credentials.server).ok_or_else(|| {
+            diag::log(format_args!("not an https: URL: {:?}", credentials.server));
+            Error::with_errno(NssStatus::Unavailable, EINVAL)
+        })?;
+        let path = format!("{}{}", base.trim_end_matches('/'), path);
+        let authorization = credentials.token.as_ref().map(|token| format!("Bearer {}", token));
+        let headers: Vec<(&str, &str)> = authorization.iter().map(|value| ("Authorization", &value[..])).collect();
+        let response = http::get_https(config.clone(), host, port, &path, &headers, C::TIMEOUT).map_err(|err| {
+            diag::log(format_args!("can't get {} from {}: {}", path, credentials.server, err));
+            Error::with_errno(NssStatus::Unavailable, err.raw_os_error().unwrap_or(EIO))
+        })?;
+        match response.status {
+            200 => serde_json::from_slice(&response.body).map(Some).map_err(|err| {
+                diag::log(format_args!("can't read {} from {}: {}", path, credentials.server, err));
+                Error::with_errno(NssStatus::Unavailable, EINVAL)
+            }),
+            404 => Ok(None),
+            status => {
+                diag::log(format_args!("can't get {} from {}: HTTP status {}", path, credentials.server, status));
+                Err(Error::with_errno(NssStatus::Unavailable, EIO))
+            }
+        }
+    }
+
+    /// The addresses of the Service or Pod `object` in `namespace`, or
+    /// `None` if there's neither.
+    fn fetch(object: &str, namespace: &str) -> Result<Found> {
+        let credentials = Self::credentials()?;
+        let config = tls_config(&credentials).map_err(|message| {
+            diag::log(format_args!("can't set up TLS for {}: {}", credentials.server, message));
+            Error::with_errno(NssStatus::Unavailable, EINVAL)
+        })?;
+        let base = format!("/api/v1/namespaces/{}", namespace);
+        if let Some(service) = Self::get(&credentials, &config, &format!("{}/services/{}", base, object))? {
+            return match service_addrs(&service) {
+                Some(addrs) => Ok(Some(addrs)),
+                None => Ok(Some(Self::get(&credentials, &config, &format!("{}/endpoints/{}", base, object))?
+                    .map_or_else(Vec::new, |endpoints| endpoint_addrs(&endpoints)))),
+            };
+        }
+        Ok(Self::get(&credentials, &config, &format!("{}/pods/{}", base, object))?.map(|pod| pod_addrs(&pod)))
+    }
+
+    /// `fetch`, through the cache.
+    fn resolve(object: &str, namespace: &str) -> Result<Found> {
+        let key = format!("{}.{}", object, namespace);
+        let now = Instant::now();
+        {
+            let mut cache = CACHE.lock();
+            cache.retain(|&(_, expires, _)| expires > now);
+            if let Some((_, _, addrs)) = cache.iter().find(|(k, _, _)| *k == key) {
+                return Ok(addrs.clone());
+            }
+        }
+        let addrs = Self::fetch(object, namespace)?;
+        let mut cache = CACHE.lock();
+        if cache.len() >= MAX_CACHED {
+            cache.remove(0);
+        }
+        cache.push((key, now + C::CACHE_TTL, addrs.clone()));
+        Ok(addrs)
+    }
+}
+
+impl<C: KubernetesConfig> NameService for KubernetesService<C> {
+    fn gethostbyname2_r(name: &CStr, af: AddressFamily) -> Result<Option<HostEntry<'_>>> {
+        let (object, namespace) = match Self::split_name(name) {
+            Some(split) => split,
+            None => return Ok(None),
+        };
+        let addrs = match Self::resolve(&object, &namespace)? {
+            Some(addrs) => addrs,
+            None => return Ok(None),
+        };
+        let addr_list = match af {
+            AddressFamily::Ipv4 => HostAddressList::V4(addrs.iter().filter_map(|addr| match *addr {
+                IpAddr::V4(ip) => Some(ip),
+                IpAddr::V6(_) => None,
+            }).collect()),
+            AddressFamily::Ipv6 => HostAddressList::V6(addrs.iter().filter_map(|addr| match *addr {
+                IpAddr::V4(_) => None,
+                IpAddr::V6(ip) => Some(ip),
+            }).collect()),
+        };
+        if addr_list.is_empty() {
+            return Err(Error::with_host(NssStatus::NotFound, ENOENT, HostError::NoData));
+        }
+        Ok(Some(HostEntry { name: Cow::Borrowed(name), aliases: vec![], addr_list }))
+    }
+
+    fn gethostbyaddr_r(_addr: &IpAddr) -> Result<Option<HostEntry<'_>>> {
+        Ok(None)
+    }
+
+    fn on_fork_child() {
+        CACHE.reset();
+    }
+}
+
+#[test]
+fn test_split_name() {
+    let name = |s: &'static [u8]| CStr::from_bytes_with_nul(s).unwrap();
+    let split = |s| KubernetesService::<KubernetesDefaults>::split_name(name(s));
+    assert_eq!(split(b"Web.Shop.svc.cluster.local.\0"), Some(("web".to_string(), "shop".to_string())));
+    assert_eq!(split(b"web-0.db.shop.svc\0"), Some(("web-0.db".to_string(), "shop".to_string())));
+    assert_eq!(split(b"shop.svc.cluster.local\0"), None);
+    assert_eq!(split(b"web.shop.example.com\0"), None);
+    assert_eq!(split(b"we_b.shop.svc\0"), None);
+}
+
+#[test]
+fn test_parse_kubeconfig() {
+    let kubeconfig = "
+apiVersion: v1
+kind: Config
+current-context: dev
+contexts:
+- name: dev
+  context: {cluster: kind, user: me, namespace: shop}
+- name: prod
+  context: {cluster: kind, user: sso}
+clusters:
+- name: kind
+  cluster: {server: 'https://127.0.0.1:6443', certificate-authority-data: UEVN}
+users:
+- name: me
+  user: {token: secret}
+- name: sso
+  user: {exec: {command: kubelogin}}
+";
+    let credentials = parse_kubeconfig(kubeconfig, Path::new("/nonexistent")).unwrap();
+    assert_eq!(credentials, Credentials {
+        server: "https://127.0.0.1:6443".to_string(),
+        ca: b"PEM".to_vec(),
+        token: Some("secret".to_string()),
+        client: None,
+    });
+    let prod = kubeconfig.replace("current-context: dev", "current-context: prod");
+    assert!(parse_kubeconfig(&prod, Path::new("/nonexistent")).unwrap_err().contains("exec plugin"));
+}
+
+#[test]
+fn test_object_addrs() {
+    use serde_json::json;
+
+    let service = json!({"spec": {"clusterIP": "10.96.0.10", "clusterIPs": ["10.96.0.10", "fd00:10:96::a"]}});
+    assert_eq!(service_addrs(&service).unwrap().len(), 2);
+    assert_eq!(service_addrs(&json!({"spec": {"clusterIP": "None"}})), None);
+    let endpoints = json!({"subsets": [{"addresses": [{"ip": "10.244.0.5"}, {"ip": "10.244.1.7"}],
+                                        "notReadyAddresses": [{"ip": "10.244.2.9"}]}]});
+    assert_eq!(endpoint_addrs(&endpoints).len(), 2);
+    let pod = json!({"status": {"podIP": "10.244.0.5", "podIPs": [{"ip": "10.244.0.5"}]}});
+    assert_eq!(pod_addrs(&pod), ["10.244.0.5".parse::<IpAddr>().unwrap()]);
+}
+
+#[test]
+fn test_malformed_kubeconfig() {
+    let dir = std::env::temp_dir().join(format!("nsswitch_service-test-kubeconfig-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("client.crt"), "CERT").unwrap();
+    let config = |cluster: &str, user: &str| {
+        format!("current-context: dev\ncontexts:\n- name: dev\n  context: {{cluster: kind, user: me}}\n\
+                 clusters:\n- name: kind\n  cluster: {{server: 'https://127.0.0.1:6443', {}}}\n\
+                 users:\n- name: me\n  user: {{{}}}\n", cluster, user)
+    };
+    let parse = |text: &str| parse_kubeconfig(text, &dir);
+
+    // Files are relative to the kubeconfig, and a user with neither a token
+    // nor a certificate is anonymous.
+    let credentials = parse(&config("certificate-authority-data: UEVN", "client-certificate: client.crt, \
+                                     client-key-data: S0VZ")).unwrap();
+    assert_eq!(credentials.client, Some((b"CERT".to_vec(), b"KEY".to_vec())));
+    assert_eq!(parse(&config("certificate-authority: client.crt", "")).unwrap().token, None);
+
+    // What doesn't parse, and what's missing or can't be read.
+    let error = |text: &str| parse(text).unwrap_err();
+    assert!(!error("contexts: [").is_empty());
+    assert!(error(&config("certificate-authority-data: UEVN", "").replace("current-context: dev", ""))
+        .contains("no context \"\""));
+    assert!(error(&config("certificate-authority-data: UEVN", "").replace("cluster: kind,", "cluster: gone,"))
+        .contains("no cluster \"gone\""));
+    assert!(error(&config("insecure-skip-tls-verify: true", "")).contains("no certificate authority"));
+    assert!(error(&config("certificate-authority-data: '*'", "")).contains("bad base64"));
+    assert!(error(&config("certificate-authority: missing.crt", "")).contains("missing.crt"));
+    assert!(error(&config("certificate-authority-data: UEVN", "tokenFile: missing-token")).contains("missing-token"));
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_malformed_objects() {
+    use serde_json::json;
+
+    // Whatever isn't where it should be, or isn't an address, is left out.
+    assert_eq!(service_addrs(&json!({})), Some(vec![]));
+    assert_eq!(service_addrs(&json!({"spec": {"clusterIPs": ["10.96.0.10", 7, "ten"]}})).unwrap().len(), 1);
+    assert!(endpoint_addrs(&json!({"subsets": {"addresses": []}})).is_empty());
+    assert!(endpoint_addrs(&json!({"subsets": [{"addresses": [{"ip": "10.244.0.999"}, {}]}]})).is_empty());
+    assert!(pod_addrs(&json!({"status": {"podIP": "pending"}})).is_empty());
+    assert!(pod_addrs(&json!({"status": {"podIPs": [{"ip": null}]}})).is_empty());
+}
+
+#[test]
+fn test_service_errors() {
+    use libc::{EAGAIN, ECONNREFUSED};
+    use std::io::Read;
+    use std::net::TcpListener;
+    use std::thread;
+
+    struct Impatient;
+    impl KubernetesConfig for Impatient {
+        const KUBECONFIG: Option<&'static str> = Some("/nonexistent/kubeconfig");
+        const TIMEOUT: Duration = Duration::from_millis(100);
+    }
+    type Service = KubernetesService<Impatient>;
+    let status = |result: Result<Option<Value>>| result.map(|found| found.is_some()).map_err(|err| {
+        (err.status(), err.errno())
+    });
+
+    // Credentials that can't be read.
+    let name = CStr::from_bytes_with_nul(b"web.shop.svc\0").unwrap();
+    let err = Service::gethostbyname2_r(name, AddressFamily::Ipv4).unwrap_err();
+    assert_eq!((err.status(), err.errno()), (NssStatus::Unavailable, EINVAL));
+
+    // An API server that isn't https:, isn't there, hangs up, or never
+    // answers.
+    let credentials = |server: String| Credentials { server, ca: vec![], token: None, client: None };
+    let get = |server: String| {
+        let credentials = credentials(server);
+        Service::get(&credentials, &tls_config(&credentials).unwrap(), "/api/v1/namespaces/shop/pods/web")
+    };
+    assert_eq!(status(get("http://127.0.0.1:6443".to_string())), Err((NssStatus::Unavailable, EINVAL)));
+    let closed = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    assert_eq!(status(get(format!("https://{}", closed))), Err((NssStatus::Unavailable, ECONNREFUSED)));
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = thread::spawn(move || {
+        // Hang up on the first client once it has said hello, and say
+        // nothing to the second.
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut header = [0; 5];
+        stream.read_exact(&mut header).unwrap();
+        stream.read_exact(&mut vec![0; usize::from(u16::from_be_bytes([header[3], header[4]]))]).unwrap();
+        drop(stream);
+        let (mut stream, _) = listener.accept().unwrap();
+        let _ = std::io::copy(&mut stream, &mut std::io::sink());
+    });
+    assert_eq!(status(get(format!("https://{}", addr))), Err((NssStatus::Unavailable, EIO)));
+    assert_eq!(status(get(format!("https://{}", addr))), Err((NssStatus::Unavailable, EAGAIN)));
+    server.join().unwrap();
+
+    // Credentials TLS can't use.
+    let mut bad_key = credentials("https://127.0.0.1:6443".to_string());
+    bad_key.client = Some((vec![], b"KEY".to_vec()));
+    assert!(tls_config(&bad_key).unwrap_err().contains("bad client key"));
+}
@@ -0,0 +1,84 @@
+//! Compile-time checks of the layout of every C struct the glue writes.
+//!
+//! The writers fill in these structs through the `libc` crate's
+//! definitions, and the callers read them through the C headers. If the two
+//! ever disagree, on a new target or after a `libc` update, results come
+//! out garbled, so the expected layouts are written out here independently
+//! of `libc`, and a mismatch fails the build.
+//!
+//! The layouts follow from the C declarations, so they're given in terms of
+//! the pointer size `P` and the `long` size `L`, which cover 32- and 64-bit
+//! targets alike.
+
+use crate::ffi::{c_long, gaih_addrtuple, group, hostent, spwd};
+use std::mem;
+
+const P: usize = mem::size_of::<*const u8>();
+const L: usize = mem::size_of::<c_long>();
+
+macro_rules! assert_layout {
+    ($t:ty, size = $size:expr, align = $align:expr, { $($field:ident: $offset:expr),* $(,)? }) => {
+        const _: () = assert!(mem::size_of::<$t>() == $size, concat!("size of ", stringify!($t)));
+        const _: () = assert!(mem::align_of::<$t>() == $align, concat!("alignment of ", stringify!($t)));
+        $(
+            const _: () = assert!(mem::offset_of!($t, $field) == $offset,
+                                  concat!("offset of ", stringify!($t), "::", stringify!($field)));
+        )*
+    };
+}
+
+assert_layout!(hostent, size = 3 * P + 8, align = P, {
+    h_name: 0,
+    h_aliases: P,
+    h_addrtype: 2 * P,
+    h_length: 2 * P + 4,
+    h_addr_list: 2 * P + 8,
+});
+
+assert_layout!(group, size = 4 * P, align = P, {
+    gr_name: 0,
+    gr_passwd: P,
+    gr_gid: 2 * P,
+    gr_mem: 3 * P,
+});
+
+assert_layout!(libc::servent, size = 4 * P, align = P, {
+    s_name: 0,
+    s_aliases: P,
+    s_port: 2 * P,
+    s_proto: 3 * P,
+});
+
+assert_layout!(gaih_addrtuple, size = 2 * P + 24, align = P, {
+    next: 0,
+    name: P,
+    family: 2 * P,
+    addr: 2 * P + 4,
+    scopeid: 2 * P + 20,
+});
+
+// `sp_flag` is an `unsigned long`, the same size as `long`.
+assert_layout!(spwd, size = 2 * P + 7 * L, align = P, {
+    sp_namp: 0,
+    sp_pwdp: P,
+    sp_lstchg: 2 * P,
+    sp_min: 2 * P + L,
+    sp_max: 2 * P + 2 * L,
+    sp_warn: 2 * P + 3 * L,
+    sp_inact: 2 * P + 4 * L,
+    sp_expire: 2 * P + 5 * L,
+    sp_flag: 2 * P + 6 * L,
+});
+
+// The BSDs and illumos add fields to `passwd`, which the writers leave
+// zeroed; only Linux's is checked.
+#[cfg(target_os = "linux")]
+assert_layout!(crate::ffi::passwd, size = 5 * P + 8, align = P, {
+    pw_name: 0,
+    pw_passwd: P,
+    pw_uid: 2 * P,
+    pw_gid: 2 * P + 4,
+    pw_gecos: 2 * P + 8,
+    pw_dir: 3 * P + 8,
+    pw_shell: 4 * P + 8,
+});
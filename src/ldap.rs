@@ -0,0 +1,1196 @@
+//! A ready-made service for `passwd`, `group`, and `shadow` that reads them
+//! from an LDAP directory, with the RFC 2307 schema that `nss-pam-ldapd`
+//! and `sssd` use.
+
+use crate::diag;
+use crate::errors::{Error, NssStatus, Result};
+use crate::interfaces::{Entries, GroupEntry, GroupService, PasswdEntry, PasswdService, ShadowEntry, ShadowService};
+use crate::tls;
+use libc::{c_long, c_ulong, gid_t, uid_t, EINVAL, EIO};
+use rustls::pki_types::ServerName;
+use std::borrow::Cow;
+use std::convert::TryFrom;
+use std::ffi::{CStr, CString};
+use std::fs;
+use std::io::{self, Read, Write};
+use std::marker::PhantomData;
+use std::net::{TcpStream, ToSocketAddrs};
+use std::os::unix::net::UnixStream;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// The attributes a `passwd` entry is read from.
+#[derive(Clone, Copy, Debug)]
+pub struct PasswdAttributes {
+    pub name: &'static str,
+    pub uid: &'static str,
+    pub gid: &'static str,
+    pub gecos: &'static str,
+    pub dir: &'static str,
+    pub shell: &'static str,
+}
+
+impl PasswdAttributes {
+    /// The `posixAccount` object class.
+    pub const RFC2307: PasswdAttributes = PasswdAttributes {
+        name: "uid",
+        uid: "uidNumber",
+        gid: "gidNumber",
+        gecos: "gecos",
+        dir: "homeDirectory",
+        shell: "loginShell",
+    };
+}
+
+/// The attributes a `group` entry is read from.
+#[derive(Clone, Copy, Debug)]
+pub struct GroupAttributes {
+    pub name: &'static str,
+    pub gid: &'static str,
+    /// Members named by user name.
+    pub member_uid: &'static str,
+    /// Members named by DN, if the directory has them.
+    pub member: Option<&'static str>,
+}
+
+impl GroupAttributes {
+    /// The `posixGroup` object class.
+    pub const RFC2307: GroupAttributes = GroupAttributes {
+        name: "cn",
+        gid: "gidNumber",
+        member_uid: "memberUid",
+        member: None,
+    };
+
+    /// `posixGroup` as amended by RFC 2307bis, with members named by DN as
+    /// well.
+    pub const RFC2307BIS: GroupAttributes = GroupAttributes {
+        member: Some("member"),
+        ..GroupAttributes::RFC2307
+    };
+}
+
+/// The attributes a `shadow` entry is read from.
+#[derive(Clone, Copy, Debug)]
+pub struct ShadowAttributes {
+    pub name: &'static str,
+    pub passwd: &'static str,
+    pub last_change: &'static str,
+    pub min: &'static str,
+    pub max: &'static str,
+    pub warn: &'static str,
+    pub inactive: &'static str,
+    pub expire: &'static str,
+    pub flag: &'static str,
+}
+
+impl ShadowAttributes {
+    /// The `shadowAccount` object class.
+    pub const RFC2307: ShadowAttributes = ShadowAttributes {
+        name: "uid",
+        passwd: "userPassword",
+        last_change: "shadowLastChange",
+        min: "shadowMin",
+        max: "shadowMax",
+        warn: "shadowWarning",
+        inactive: "shadowInactive",
+        expire: "shadowExpire",
+        flag: "shadowFlag",
+    };
+}
+
+/// Where an `LdapService` finds the directory, and how accounts are kept
+/// there.
+pub trait LdapConfig: 'static {
+    /// The servers, tried in order: `ldap://host[:port]`,
+    /// `ldaps://host[:port]`, or `ldapi://` followed by the percent-encoded
+    /// path of a Unix socket.
+    const URIS: &'static [&'static str] = &["ldap://localhost"];
+
+    /// Whether to upgrade `ldap://` connections with StartTLS.
+    const START_TLS: bool = false;
+
+    /// The certificate authorities to check servers' certificates against,
+    /// in PEM, for `ldaps://` and StartTLS.
+    const CA_FILE: &'static str = "/etc/ssl/certs/ca-certificates.crt";
+
+    /// The DN to bind as, or `None` to search anonymously.
+    const BIND_DN: Option<&'static str> = None;
+
+    /// The file with `BIND_DN`'s password. If this process can't read it,
+    /// the service searches anonymously instead, so the file can be made
+    /// readable only by root, and only root sees what needs the password.
+    const BIND_PASSWORD_FILE: &'static str = "/etc/nss-ldap.secret";
+
+    /// Where to search for everything. If empty, the naming contexts the
+    /// server advertises are searched.
+    const BASES: &'static [&'static str] = &[];
+
+    /// Where to search for users, if not `BASES`.
+    const PASSWD_BASES: &'static [&'static str] = &[];
+
+    /// Where to search for groups, if not `BASES`.
+    const GROUP_BASES: &'static [&'static str] = &[];
+
+    /// Where to search for shadow entries, if not `BASES`.
+    const SHADOW_BASES: &'static [&'static str] = &[];
+
+    /// The filter, in RFC 4515 form, that users match.
+    const PASSWD_FILTER: &'static str = "(objectClass=posixAccount)";
+
+    /// The filter that groups match.
+    const GROUP_FILTER: &'static str = "(objectClass=posixGroup)";
+
+    /// The filter that shadow entries match.
+    const SHADOW_FILTER: &'static str = "(objectClass=shadowAccount)";
+
+    const PASSWD_ATTRIBUTES: PasswdAttributes = PasswdAttributes::RFC2307;
+
+    const GROUP_ATTRIBUTES: GroupAttributes = GroupAttributes::RFC2307;
+
+    const SHADOW_ATTRIBUTES: ShadowAttributes = ShadowAttributes::RFC2307;
+
+    /// How long to wait for each server.
+    const TIMEOUT: Duration = Duration::from_secs(3);
+}
+
+/// The usual settings: the local server, searched anonymously, with the
+/// RFC 2307 schema.
+pub struct LdapDefaults;
+
+impl LdapConfig for LdapDefaults {}
+
+/// A service that reads users, groups, and shadow passwords from an LDAP
+/// directory, for sites that keep their accounts there:
+///
+/// ```ignore
+/// nssglue_passwd!("ldap", LdapService<Corp>);
+/// nssglue_group!("ldap", LdapService<Corp>);
+/// nssglue_shadow!("ldap", LdapService<Corp>);
+/// ```
+///
+/// Each lookup connects to the first server in `URIS` that answers, binds
+/// if there's a `BIND_DN`, and searches each base for an entry matching
+/// both the configured filter and the name or id being looked up. Nothing
+/// is kept between lookups, so there is nothing to go stale or to clean up
+/// after `fork`; put `nscd` in front of it if lookups are frequent.
+///
+/// Entries without a name or a numeric id are skipped. A group's members
+/// are its `memberUid` values and, with `GroupAttributes::RFC2307BIS`, the
+/// `member` DNs whose first component names a user, as in
+/// `uid=alice,ou=people,dc=example,dc=com`. `initgroups` asks the
+/// directory for the user's groups directly, rather than enumerating them
+/// all. A shadow entry's password is the `userPassword` value with a
+/// `{CRYPT}` prefix, minus the prefix; any other kind of password is
+/// reported as `*`, which matches nothing.
+///
+/// If no server can be reached, or a search fails, lookups report
+/// `NssStatus::Unavailable`. Only a refused connection (no server
+/// listening) goes unlogged.
+pub struct LdapService<C = LdapDefaults>(PhantomData<C>);
+
+const BOOLEAN: u8 = 0x01;
+const INTEGER: u8 = 0x02;
+const OCTET_STRING: u8 = 0x04;
+const ENUMERATED: u8 = 0x0a;
+const SEQUENCE: u8 = 0x30;
+const SET: u8 = 0x31;
+
+const BIND_REQUEST: u8 = 0x60;
+const BIND_RESPONSE: u8 = 0x61;
+const UNBIND_REQUEST: u8 = 0x42;
+const SEARCH_REQUEST: u8 = 0x63;
+const SEARCH_RESULT_ENTRY: u8 = 0x64;
+const SEARCH_RESULT_DONE: u8 = 0x65;
+const SEARCH_RESULT_REFERENCE: u8 = 0x73;
+const EXTENDED_REQUEST: u8 = 0x77;
+const EXTENDED_RESPONSE: u8 = 0x78;
+
+const SCOPE_BASE: u8 = 0;
+const SCOPE_SUBTREE: u8 = 2;
+
+const SUCCESS: i64 = 0;
+const SIZE_LIMIT_EXCEEDED: i64 = 4;
+const REFERRAL: i64 = 10;
+const NO_SUCH_OBJECT: i64 = 32;
+
+const START_TLS_OID: &[u8] = b"1.3.6.1.4.1.1466.20037";
+
+/// The largest message we're willing to read.
+const MAX_MESSAGE: usize = 16 << 20;
+
+fn invalid(what: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("malformed LDAP message: {}", what))
+}
+
+/// A BER element with `tag` and `contents`.
+fn tlv(tag: u8, contents: &[u8]) -> Vec<u8> {
+    let mut out = vec![tag];
+    if contents.len() < 0x80 {
+        out.push(contents.len() as u8);
+    } else {
+        let len = contents.len().to_be_bytes();
+        let skip = len.iter().take_while(|&&b| b == 0).count();
+        out.push(0x80 | (len.len() - skip) as u8);
+        out.extend_from_slice(&len[skip..]);
+    }
+    out.extend_from_slice(contents);
+    out
+}
+
+/// The contents of a BER integer, in as few bytes as will do.
+fn integer(n: i64) -> Vec<u8> {
+    let bytes = n.to_be_bytes();
+    let mut start = 0;
+    while start < 7 && ((bytes[start] == 0 && bytes[start + 1] < 0x80) || (bytes[start] == 0xff && bytes[start + 1] >= 0x80)) {
+        start += 1;
+    }
+    bytes[start..].to_vec()
+}
+
+fn parse_integer(contents: &[u8]) -> io::Result<i64> {
+    if contents.is_empty() || contents.len() > 8 {
+        return Err(invalid("bad integer"));
+    }
+    let sign = if contents[0] >= 0x80 { -1 } else { 0 };
+    Ok(contents.iter().fold(sign, |n, &b| (n << 8) | i64::from(b)))
+}
+
+/// A cursor over a sequence of BER elements.
+struct Ber<'a>(&'a [u8]);
+
+impl<'a> Ber<'a> {
+    fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// The next element's tag and contents.
+    fn next(&mut self) -> io::Result<(u8, &'a [u8])> {
+        let (&tag, rest) = self.0.split_first().ok_or_else(|| invalid("truncated"))?;
+        let (&first, mut rest) = rest.split_first().ok_or_else(|| invalid("truncated"))?;
+        let len = if first < 0x80 {
+            usize::from(first)
+        } else {
+            let n = usize::from(first & 0x7f);
+            if n == 0 || n > 4 || rest.len() < n {
+                return Err(invalid("bad length"));
+            }
+            let (len, after) = rest.split_at(n);
+            rest = after;
+            len.iter().fold(0, |len, &b| (len << 8) | usize::from(b))
+        };
+        if rest.len() < len {
+            return Err(invalid("truncated"));
+        }
+        let (contents, rest) = rest.split_at(len);
+        self.0 = rest;
+        Ok((tag, contents))
+    }
+
+    /// The contents of the next element, which must have tag `tag`.
+    fn expect(&mut self, tag: u8) -> io::Result<&'a [u8]> {
+        match self.next()? {
+            (t, contents) if t == tag => Ok(contents),
+            (t, _) => Err(invalid(&format!("expected tag {:#04x}, got {:#04x}", tag, t))),
+        }
+    }
+}
+
+/// Read one whole message from `stream`, as a BER element.
+fn read_message<R: Read>(stream: &mut R) -> io::Result<Vec<u8>> {
+    let mut head = [0; 2];
+    stream.read_exact(&mut head)?;
+    let mut message = head.to_vec();
+    let len = if head[1] < 0x80 {
+        usize::from(head[1])
+    } else {
+        let n = usize::from(head[1] & 0x7f);
+        if n == 0 || n > 4 {
+            return Err(invalid("bad length"));
+        }
+        let mut len = [0; 4];
+        stream.read_exact(&mut len[4 - n..])?;
+        message.extend_from_slice(&len[4 - n..]);
+        u32::from_be_bytes(len) as usize
+    };
+    if len > MAX_MESSAGE {
+        return Err(invalid("too long"));
+    }
+    let start = message.len();
+    message.resize(start + len, 0);
+    stream.read_exact(&mut message[start..])?;
+    Ok(message)
+}
+
+/// Split a message into its id, its operation's tag, and the operation's
+/// contents. Controls are ignored.
+fn parse_message(message: &[u8]) -> io::Result<(i64, u8, &[u8])> {
+    let mut outer = Ber(message);
+    let mut fields = Ber(outer.expect(SEQUENCE)?);
+    let id = parse_integer(fields.expect(INTEGER)?)?;
+    let (tag, op) = fields.next()?;
+    Ok((id, tag, op))
+}
+
+/// The result code and diagnostic message of an `LDAPResult`.
+fn parse_result(contents: &[u8]) -> io::Result<(i64, String)> {
+    let mut fields = Ber(contents);
+    let code = parse_integer(fields.expect(ENUMERATED)?)?;
+    fields.expect(OCTET_STRING)?;
+    let message = String::from_utf8_lossy(fields.expect(OCTET_STRING)?).into_owned();
+    Ok((code, message))
+}
+
+/// Escape `value` for an RFC 4515 filter. Anything but printable ASCII is
+/// escaped, along with the characters that mean something in a filter.
+fn escape(value: &[u8]) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for &b in value {
+        if b.is_ascii_graphic() && !b"*()\\".contains(&b) || b == b' ' {
+            escaped.push(b as char);
+        } else {
+            escaped += &format!("\\{:02x}", b);
+        }
+    }
+    escaped
+}
+
+fn unescape(raw: &[u8]) -> std::result::Result<Vec<u8>, String> {
+    let mut value = Vec::with_capacity(raw.len());
+    let mut i = 0;
+    while i < raw.len() {
+        if raw[i] == b'\\' {
+            let hex = raw.get(i + 1..i + 3)
+                .and_then(|hex| std::str::from_utf8(hex).ok())
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                .ok_or_else(|| "bad escape".to_string())?;
+            value.push(hex);
+            i += 3;
+        } else {
+            value.push(raw[i]);
+            i += 1;
+        }
+    }
+    Ok(value)
+}
+
+/// Encode an RFC 4515 filter, like `(&(objectClass=posixAccount)(uid=alice))`.
+/// Extensible matches (`:=`) aren't supported.
+fn encode_filter(filter: &str) -> std::result::Result<Vec<u8>, String> {
+    let mut parser = FilterParser { text: filter.as_bytes(), pos: 0 };
+    let encoded = parser.filter()?;
+    if parser.pos != parser.text.len() {
+        return Err(parser.error("trailing characters"));
+    }
+    Ok(encoded)
+}
+
+struct FilterParser<'a> {
+    text: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> FilterParser<'a> {
+    fn error(&self, what: &str) -> String {
+        format!("{} at offset {}", what, self.pos)
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.text.get(self.pos).cloned()
+    }
+
+    fn eat(&mut self, b: u8) -> std::result::Result<(), String> {
+        if self.peek() != Some(b) {
+            return Err(self.error(&format!("expected '{}'", b as char)));
+        }
+        self.pos += 1;
+        Ok(())
+    }
+
+    fn filter(&mut self) -> std::result::Result<Vec<u8>, String> {
+        self.eat(b'(')?;
+        let encoded = match self.peek() {
+            Some(b'&') => self.set(0xa0)?,
+            Some(b'|') => self.set(0xa1)?,
+            Some(b'!') => {
+                self.pos += 1;
+                tlv(0xa2, &self.filter()?)
+            }
+            _ => self.item()?,
+        };
+        self.eat(b')')?;
+        Ok(encoded)
+    }
+
+    /// An `and` or `or` of the filters that follow.
+    fn set(&mut self, tag: u8) -> std::result::Result<Vec<u8>, String> {
+        self.pos += 1;
+        let mut contents = vec![];
+        while self.peek() == Some(b'(') {
+            contents.extend(self.filter()?);
+        }
+        Ok(tlv(tag, &contents))
+    }
+
+    fn item(&mut self) -> std::result::Result<Vec<u8>, String> {
+        let start = self.pos;
+        while self.peek().is_some_and(|b| !b"=~<>()".contains(&b)) {
+            self.pos += 1;
+        }
+        let attr = &self.text[start..self.pos];
+        if attr.is_empty() {
+            return Err(self.error("expected an attribute"));
+        }
+        let tag = match self.peek() {
+            Some(b'=') => 0xa3,
+            Some(b'>') => 0xa5,
+            Some(b'<') => 0xa6,
+            Some(b'~') => 0xa8,
+            _ => return Err(self.error("expected '='")),
+        };
+        if tag != 0xa3 {
+            self.pos += 1;
+        }
+        self.eat(b'=')?;
+        let start = self.pos;
+        while self.peek().is_some_and(|b| b != b'(' && b != b')') {
+            self.pos += 1;
+        }
+        let raw = &self.text[start..self.pos];
+
+        let mut contents = tlv(OCTET_STRING, attr);
+        if tag == 0xa3 && raw == b"*" {
+            return Ok(tlv(0x87, attr));
+        } else if tag == 0xa3 && raw.contains(&b'*') {
+            let pieces: Vec<&[u8]> = raw.split(|&b| b == b'*').collect();
+            let last = pieces.len() - 1;
+            let mut substrings = vec![];
+            for (i, piece) in pieces.into_iter().enumerate().filter(|(_, piece)| !piece.is_empty()) {
+                let kind = if i == 0 { 0x80 } else if i == last { 0x82 } else { 0x81 };
+                substrings.extend(tlv(kind, &unescape(piece)?));
+            }
+            contents.extend(tlv(SEQUENCE, &substrings));
+            return Ok(tlv(0xa4, &contents));
+        }
+        contents.extend(tlv(OCTET_STRING, &unescape(raw)?));
+        Ok(tlv(tag, &contents))
+    }
+}
+
+/// An entry from a search: its DN and the values of its attributes.
+#[derive(Debug)]
+struct Entry {
+    dn: String,
+    attributes: Vec<(String, Vec<Vec<u8>>)>,
+}
+
+impl Entry {
+    fn parse(contents: &[u8]) -> io::Result<Entry> {
+        let mut fields = Ber(contents);
+        let dn = String::from_utf8_lossy(fields.expect(OCTET_STRING)?).into_owned();
+        let mut list = Ber(fields.expect(SEQUENCE)?);
+        let mut attributes = vec![];
+        while !list.is_empty() {
+            let mut attribute = Ber(list.expect(SEQUENCE)?);
+            let name = String::from_utf8_lossy(attribute.expect(OCTET_STRING)?).into_owned();
+            let mut set = Ber(attribute.expect(SET)?);
+            let mut values = vec![];
+            while !set.is_empty() {
+                values.push(set.expect(OCTET_STRING)?.to_vec());
+            }
+            attributes.push((name, values));
+        }
+        Ok(Entry { dn, attributes })
+    }
+
+    /// All the values of `attribute`. Attribute names are case-insensitive.
+    fn values(&self, attribute: &str) -> &[Vec<u8>] {
+        self.attributes.iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(attribute))
+            .map_or(&[], |(_, values)| &values[..])
+    }
+
+    fn value(&self, attribute: &str) -> Option<&[u8]> {
+        self.values(attribute).first().map(|value| &value[..])
+    }
+
+    fn c_string(&self, attribute: &str) -> Option<CString> {
+        CString::new(self.value(attribute)?).ok()
+    }
+
+    fn number<T: FromStr>(&self, attribute: &str) -> Option<T> {
+        std::str::from_utf8(self.value(attribute)?).ok()?.trim().parse().ok()
+    }
+
+    /// The entry's name: `wanted`, if it's one of the values of
+    /// `attribute`, or the first value if nothing in particular is wanted.
+    fn name(&self, attribute: &str, wanted: Option<&CStr>) -> Option<CString> {
+        match wanted {
+            None => self.c_string(attribute),
+            Some(wanted) if self.values(attribute).iter().any(|value| value[..] == *wanted.to_bytes()) => {
+                Some(wanted.to_owned())
+            }
+            Some(_) => None,
+        }
+    }
+}
+
+enum Stream {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+    Tls(Box<rustls::StreamOwned<rustls::ClientConnection, TcpStream>>),
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => stream.read(buf),
+            Stream::Unix(stream) => stream.read(buf),
+            Stream::Tls(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => stream.write(buf),
+            Stream::Unix(stream) => stream.write(buf),
+            Stream::Tls(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.flush(),
+            Stream::Unix(stream) => stream.flush(),
+            Stream::Tls(stream) => stream.flush(),
+        }
+    }
+}
+
+/// A connection to one server, unbound when dropped.
+struct Connection {
+    stream: Stream,
+    last_id: i64,
+    timeout: Duration,
+}
+
+/// Decode the `%XX` escapes in the socket path of an `ldapi://` URI.
+fn percent_decode(encoded: &str) -> String {
+    let bytes = encoded.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes.get(i + 1..i + 3).and_then(|hex| std::str::from_utf8(hex).ok());
+        match hex.and_then(|hex| u8::from_str_radix(hex, 16).ok()) {
+            Some(b) if bytes[i] == b'%' => {
+                decoded.push(b);
+                i += 3;
+            }
+            _ => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+impl Connection {
+    /// Connect to the server at `uri`. `tls` is used for `ldaps://`, and
+    /// for StartTLS if `start_tls` is true.
+    fn open(uri: &str, start_tls: bool, tls: &dyn Fn() -> io::Result<Arc<rustls::ClientConfig>>,
+            timeout: Duration) -> io::Result<Connection> {
+        let bad_uri = || io::Error::new(io::ErrorKind::InvalidInput, format!("not an LDAP URI: {:?}", uri));
+        let (scheme, rest) = uri.split_once("://").ok_or_else(bad_uri)?;
+        let authority = rest.split('/').next().unwrap_or("");
+        if scheme == "ldapi" {
+            let path = if authority.is_empty() { "/run/slapd/ldapi".to_string() } else { percent_decode(authority) };
+            let stream = UnixStream::connect(path)?;
+            stream.set_read_timeout(Some(timeout))?;
+            stream.set_write_timeout(Some(timeout))?;
+            return Ok(Connection { stream: Stream::Unix(stream), last_id: 0, timeout });
+        }
+        let default_port = match scheme {
+            "ldap" => 389,
+            "ldaps" => 636,
+            _ => return Err(bad_uri()),
+        };
+        // An IPv6 address is in brackets.
+        let (host, port) = match authority.rfind(':') {
+            Some(colon) if !authority[colon..].contains(']') => {
+                (&authority[..colon], authority[colon + 1..].parse().map_err(|_| bad_uri())?)
+            }
+            _ => (authority, default_port),
+        };
+        let host = host.strip_prefix('[').and_then(|h| h.strip_suffix(']')).unwrap_or(host);
+        if host.is_empty() {
+            return Err(bad_uri());
+        }
+
+        let mut last_err = io::Error::from(io::ErrorKind::AddrNotAvailable);
+        for addr in (host, port).to_socket_addrs()? {
+            let tcp = match TcpStream::connect_timeout(&addr, timeout) {
+                Ok(tcp) => tcp,
+                Err(err) => {
+                    last_err = err;
+                    continue;
+                }
+            };
+            tcp.set_read_timeout(Some(timeout))?;
+            tcp.set_write_timeout(Some(timeout))?;
+            let mut connection = Connection { stream: Stream::Tcp(tcp), last_id: 0, timeout };
+            if scheme == "ldaps" {
+                connection.start_tls(host, tls()?)?;
+            } else if start_tls {
+                let id = connection.send(&tlv(EXTENDED_REQUEST, &tlv(0x80, START_TLS_OID)))?;
+                let response = connection.receive(id)?;
+                let (_, tag, contents) = parse_message(&response)?;
+                let (code, message) = parse_result(contents)?;
+                if tag != EXTENDED_RESPONSE || code != SUCCESS {
+                    return Err(io::Error::other(format!("StartTLS refused: {} {}", code, message)));
+                }
+                connection.start_tls(host, tls()?)?;
+            }
+            return Ok(connection);
+        }
+        Err(last_err)
+    }
+
+    /// Switch the connection to TLS, checking that the server is `host`.
+    fn start_tls(&mut self, host: &str, config: Arc<rustls::ClientConfig>) -> io::Result<()> {
+        let tcp = match self.stream {
+            Stream::Tcp(ref tcp) => tcp.try_clone()?,
+            _ => return Err(io::Error::other("TLS on a connection that isn't plain TCP")),
+        };
+        let server_name = ServerName::try_from(host.to_string())
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+        let tls = rustls::ClientConnection::new(config, server_name)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        self.stream = Stream::Tls(Box::new(rustls::StreamOwned::new(tls, tcp)));
+        Ok(())
+    }
+
+    /// Send the operation `op` in a message with a fresh id, and return the
+    /// id.
+    fn send(&mut self, op: &[u8]) -> io::Result<i64> {
+        self.last_id += 1;
+        let mut contents = tlv(INTEGER, &integer(self.last_id));
+        contents.extend_from_slice(op);
+        self.stream.write_all(&tlv(SEQUENCE, &contents))?;
+        self.stream.flush()?;
+        Ok(self.last_id)
+    }
+
+    /// Read the next message for `id`, skipping any others (such as
+    /// unsolicited notifications, id 0).
+    fn receive(&mut self, id: i64) -> io::Result<Vec<u8>> {
+        loop {
+            let message = read_message(&mut self.stream)?;
+            if parse_message(&message)?.0 == id {
+                return Ok(message);
+            }
+        }
+    }
+
+    /// Bind with a simple password.
+    fn bind(&mut self, dn: &str, password: &str) -> io::Result<()> {
+        let mut request = tlv(INTEGER, &integer(3));
+        request.extend(tlv(OCTET_STRING, dn.as_bytes()));
+        request.extend(tlv(0x80, password.as_bytes()));
+        let id = self.send(&tlv(BIND_REQUEST, &request))?;
+        let response = self.receive(id)?;
+        let (_, tag, contents) = parse_message(&response)?;
+        let (code, message) = parse_result(contents)?;
+        if tag != BIND_RESPONSE || code != SUCCESS {
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied,
+                                      format!("can't bind as {}: {} {}", dn, code, message)));
+        }
+        Ok(())
+    }
+
+    /// Search `base` for entries matching the encoded `filter`, returning
+    /// `attributes` of each. A base that doesn't exist has no entries, and
+    /// if the server stops at its size limit, the entries it did return are
+    /// all there are.
+    fn search(&mut self, base: &str, scope: u8, filter: &[u8], attributes: &[&str]) -> io::Result<Vec<Entry>> {
+        let mut request = tlv(OCTET_STRING, base.as_bytes());
+        request.extend(tlv(ENUMERATED, &[scope]));
+        request.extend(tlv(ENUMERATED, &[0]));
+        request.extend(tlv(INTEGER, &[0]));
+        request.extend(tlv(INTEGER, &integer(self.timeout.as_secs().max(1) as i64)));
+        request.extend(tlv(BOOLEAN, &[0]));
+        request.extend_from_slice(filter);
+        let list: Vec<u8> = attributes.iter().flat_map(|attribute| tlv(OCTET_STRING, attribute.as_bytes())).collect();
+        request.extend(tlv(SEQUENCE, &list));
+        let id = self.send(&tlv(SEARCH_REQUEST, &request))?;
+
+        let mut entries = vec![];
+        loop {
+            let message = self.receive(id)?;
+            let (_, tag, contents) = parse_message(&message)?;
+            match tag {
+                SEARCH_RESULT_ENTRY => entries.push(Entry::parse(contents)?),
+                SEARCH_RESULT_REFERENCE => {}
+                SEARCH_RESULT_DONE => {
+                    return match parse_result(contents)? {
+                        (SUCCESS, _) | (REFERRAL, _) | (NO_SUCH_OBJECT, _) => Ok(entries),
+                        (SIZE_LIMIT_EXCEEDED, _) => {
+                            diag::log(format_args!("LDAP search of {} stopped at the server's size limit", base));
+                            Ok(entries)
+                        }
+                        (code, message) => Err(io::Error::other(format!("search of {} failed: {} {}", base, code, message))),
+                    };
+                }
+                _ => return Err(invalid("unexpected response to a search")),
+            }
+        }
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        let _ = self.send(&[UNBIND_REQUEST, 0]);
+    }
+}
+
+fn passwd_entry(entry: &Entry, attributes: &PasswdAttributes, name: Option<&CStr>) -> Option<PasswdEntry<'static>> {
+    Some(PasswdEntry {
+        name: Cow::Owned(entry.name(attributes.name, name)?),
+        passwd: Cow::Borrowed(CStr::from_bytes_with_nul(b"x\0").unwrap()),
+        uid: entry.number(attributes.uid)?,
+        gid: entry.number(attributes.gid)?,
+        gecos: Cow::Owned(entry.c_string(attributes.gecos).unwrap_or_default()),
+        dir: Cow::Owned(entry.c_string(attributes.dir).unwrap_or_default()),
+        shell: Cow::Owned(entry.c_string(attributes.shell).unwrap_or_default()),
+    })
+}
+
+/// The user a member DN names, if its first component is `user_attribute`,
+/// as in `uid=alice,ou=people,dc=example,dc=com`. DNs with escapes in the
+/// first component are passed over.
+fn member_name(dn: &[u8], user_attribute: &str) -> Option<CString> {
+    let first = dn.split(|&b| b == b',').next()?;
+    let equals = first.iter().position(|&b| b == b'=')?;
+    let (attribute, value) = (&first[..equals], &first[equals + 1..]);
+    if !attribute.trim_ascii().eq_ignore_ascii_case(user_attribute.as_bytes()) || value.contains(&b'\\') {
+        return None;
+    }
+    CString::new(value.trim_ascii()).ok()
+}
+
+fn group_entry(entry: &Entry, attributes: &GroupAttributes, user_attribute: &str, name: Option<&CStr>)
+    -> Option<GroupEntry<'static>>
+{
+    let mut members: Vec<Cow<'static, CStr>> = vec![];
+    let by_dn = attributes.member.map_or(&[][..], |member| entry.values(member));
+    let names = entry.values(attributes.member_uid).iter().filter_map(|value| CString::new(&value[..]).ok())
+        .chain(by_dn.iter().filter_map(|dn| member_name(dn, user_attribute)));
+    for member in names {
+        if !members.iter().any(|m| **m == *member) {
+            members.push(Cow::Owned(member));
+        }
+    }
+    Some(GroupEntry {
+        name: Cow::Owned(entry.name(attributes.name, name)?),
+        passwd: Cow::Borrowed(CStr::from_bytes_with_nul(b"*\0").unwrap()),
+        gid: entry.number(attributes.gid)?,
+        members,
+    })
+}
+
+fn shadow_entry(entry: &Entry, attributes: &ShadowAttributes, name: Option<&CStr>) -> Option<ShadowEntry<'static>> {
+    let passwd = entry.values(attributes.passwd).iter()
+        .find(|value| value.len() >= 7 && value[..7].eq_ignore_ascii_case(b"{CRYPT}"))
+        .and_then(|value| CString::new(&value[7..]).ok())
+        .unwrap_or_else(|| CString::new("*").unwrap());
+    let days = |attribute| entry.number::<c_long>(attribute).unwrap_or(-1);
+    Some(ShadowEntry {
+        name: Cow::Owned(entry.name(attributes.name, name)?),
+        passwd: Cow::Owned(passwd),
+        last_change: days(attributes.last_change),
+        min: days(attributes.min),
+        max: days(attributes.max),
+        warn: days(attributes.warn),
+        inactive: days(attributes.inactive),
+        expire: days(attributes.expire),
+        flag: entry.number::<c_ulong>(attributes.flag).unwrap_or(c_ulong::MAX),
+    })
+}
+
+impl<C: LdapConfig> LdapService<C> {
+    fn failed(what: std::fmt::Arguments<'_>, err: io::Error) -> Error {
+        diag::log(format_args!("LDAP {}: {}", what, err));
+        Error::with_errno(NssStatus::Unavailable, err.raw_os_error().unwrap_or(EIO))
+    }
+
+    /// A connection to the first server that answers, bound as `BIND_DN` if
+    /// the password file can be read.
+    fn connect() -> Result<Connection> {
+        let tls = || tls::client_config(C::CA_FILE).map(Arc::new);
+        let mut last_err = io::Error::new(io::ErrorKind::InvalidInput, "no servers configured");
+        for uri in C::URIS {
+            match Connection::open(uri, C::START_TLS, &tls, C::TIMEOUT) {
+                Ok(mut connection) => {
+                    if let Some(dn) = C::BIND_DN {
+                        match fs::read_to_string(C::BIND_PASSWORD_FILE) {
+                            Ok(password) => connection.bind(dn, password.trim_end_matches('\n'))
+                                .map_err(|err| Self::failed(format_args!("server {}", uri), err))?,
+                            Err(err) if err.kind() == io::ErrorKind::PermissionDenied => {}
+                            Err(err) => return Err(Self::failed(format_args!("password file {}", C::BIND_PASSWORD_FILE), err)),
+                        }
+                    }
+                    return Ok(connection);
+                }
+                Err(err) => {
+                    if err.kind() != io::ErrorKind::ConnectionRefused && err.kind() != io::ErrorKind::NotFound {
+                        diag::log(format_args!("can't connect to LDAP server {}: {}", uri, err));
+                    }
+                    last_err = err;
+                }
+            }
+        }
+        Err(Error::with_errno(NssStatus::Unavailable, last_err.raw_os_error().unwrap_or(EIO)))
+    }
+
+    /// The entries under `bases` (or `BASES`, or the server's naming
+    /// contexts) that match both `filter` and `condition`, an RFC 4515
+    /// filter or the empty string.
+    fn search(bases: &[&str], filter: &str, condition: &str, attributes: &[&str]) -> Result<Vec<Entry>> {
+        let full = if condition.is_empty() { filter.to_string() } else { format!("(&{}{})", filter, condition) };
+        let encoded = encode_filter(&full).map_err(|message| {
+            diag::log(format_args!("bad LDAP filter {:?}: {}", full, message));
+            Error::with_errno(NssStatus::Unavailable, EINVAL)
+        })?;
+        let mut connection = Self::connect()?;
+        let mut bases: Vec<String> = bases.iter().chain(if bases.is_empty() { C::BASES } else { &[] })
+            .map(|base| base.to_string())
+            .collect();
+        if bases.is_empty() {
+            let any = encode_filter("(objectClass=*)").unwrap();
+            let root = connection.search("", SCOPE_BASE, &any, &["namingContexts"])
+                .map_err(|err| Self::failed(format_args!("root DSE"), err))?;
+            bases = root.iter()
+                .flat_map(|entry| entry.values("namingContexts"))
+                .map(|base| String::from_utf8_lossy(base).into_owned())
+                .collect();
+        }
+        let mut entries = vec![];
+        for base in &bases {
+            let found = connection.search(base, SCOPE_SUBTREE, &encoded, attributes)
+                .map_err(|err| Self::failed(format_args!("search of {}", base), err))?;
+            entries.extend(found);
+        }
+        Ok(entries)
+    }
+
+    fn find_passwd(condition: &str, name: Option<&CStr>) -> Result<Vec<PasswdEntry<'static>>> {
+        let a = &C::PASSWD_ATTRIBUTES;
+        let entries = Self::search(C::PASSWD_BASES, C::PASSWD_FILTER, condition,
+                                   &[a.name, a.uid, a.gid, a.gecos, a.dir, a.shell])?;
+        Ok(entries.iter().filter_map(|entry| passwd_entry(entry, a, name)).collect())
+    }
+
+    fn find_groups(condition: &str, name: Option<&CStr>) -> Result<Vec<GroupEntry<'static>>> {
+        let a = &C::GROUP_ATTRIBUTES;
+        let mut wanted = vec![a.name, a.gid, a.member_uid];
+        wanted.extend(a.member);
+        let entries = Self::search(C::GROUP_BASES, C::GROUP_FILTER, condition, &wanted)?;
+        Ok(entries.iter().filter_map(|entry| group_entry(entry, a, C::PASSWD_ATTRIBUTES.name, name)).collect())
+    }
+
+    fn find_shadow(condition: &str, name: Option<&CStr>) -> Result<Vec<ShadowEntry<'static>>> {
+        let a = &C::SHADOW_ATTRIBUTES;
+        let entries = Self::search(C::SHADOW_BASES, C::SHADOW_FILTER, condition,
+                                   &[a.name, a.passwd, a.last_change, a.min, a.max, a.warn, a.inactive,
+                                     a.expire, a.flag])?;
+        Ok(entries.iter().filter_map(|entry| shadow_entry(entry, a, name)).collect())
+    }
+}
+
+impl<C: LdapConfig> PasswdService for LdapService<C> {
+    fn getpwnam_r(name: &CStr) -> Result<Option<PasswdEntry<'_>>> {
+        let condition = format!("({}={})", C::PASSWD_ATTRIBUTES.name, escape(name.to_bytes()));
+        Ok(Self::find_passwd(&condition, Some(name))?.into_iter().next())
+    }
+
+    fn getpwuid_r(uid: uid_t) -> Result<Option<PasswdEntry<'static>>> {
+        let condition = format!("({}={})", C::PASSWD_ATTRIBUTES.uid, uid);
+        Ok(Self::find_passwd(&condition, None)?.into_iter().find(|entry| entry.uid == uid))
+    }
+
+    fn setpwent() -> Result<Entries<PasswdEntry<'static>>> {
+        Ok(Box::new(Self::find_passwd("", None)?.into_iter().map(Ok)))
+    }
+}
+
+impl<C: LdapConfig> GroupService for LdapService<C> {
+    fn getgrnam_r(name: &CStr) -> Result<Option<GroupEntry<'_>>> {
+        let condition = format!("({}={})", C::GROUP_ATTRIBUTES.name, escape(name.to_bytes()));
+        Ok(Self::find_groups(&condition, Some(name))?.into_iter().next())
+    }
+
+    fn getgrgid_r(gid: gid_t) -> Result<Option<GroupEntry<'static>>> {
+        let condition = format!("({}={})", C::GROUP_ATTRIBUTES.gid, gid);
+        Ok(Self::find_groups(&condition, None)?.into_iter().find(|entry| entry.gid == gid))
+    }
+
+    fn setgrent() -> Result<Entries<GroupEntry<'static>>> {
+        Ok(Box::new(Self::find_groups("", None)?.into_iter().map(Ok)))
+    }
+
+    fn initgroups_dyn(user: &CStr, _group: gid_t) -> Result<Option<Vec<gid_t>>> {
+        let a = &C::GROUP_ATTRIBUTES;
+        let mut condition = format!("({}={})", a.member_uid, escape(user.to_bytes()));
+        if let Some(member) = a.member {
+            // Members named by DN need the user's DN.
+            let name_condition = format!("({}={})", C::PASSWD_ATTRIBUTES.name, escape(user.to_bytes()));
+            let users = Self::search(C::PASSWD_BASES, C::PASSWD_FILTER, &name_condition,
+                                     &[C::PASSWD_ATTRIBUTES.name])?;
+            for user_entry in users.iter().filter(|entry| entry.name(C::PASSWD_ATTRIBUTES.name, Some(user)).is_some()) {
+                condition += &format!("({}={})", member, escape(user_entry.dn.as_bytes()));
+            }
+            condition = format!("(|{})", condition);
+        }
+        let entries = Self::search(C::GROUP_BASES, C::GROUP_FILTER, &condition, &[a.gid])?;
+        let gids: Vec<gid_t> = entries.iter().filter_map(|entry| entry.number(a.gid)).collect();
+        Ok(if gids.is_empty() { None } else { Some(gids) })
+    }
+}
+
+impl<C: LdapConfig> ShadowService for LdapService<C> {
+    fn getspnam_r(name: &CStr) -> Result<Option<ShadowEntry<'_>>> {
+        let condition = format!("({}={})", C::SHADOW_ATTRIBUTES.name, escape(name.to_bytes()));
+        Ok(Self::find_shadow(&condition, Some(name))?.into_iter().next())
+    }
+
+    fn setspent() -> Result<Entries<ShadowEntry<'static>>> {
+        Ok(Box::new(Self::find_shadow("", None)?.into_iter().map(Ok)))
+    }
+}
+
+#[test]
+fn test_encode_filter() {
+    // Filters encode as in RFC 4511, with values escaped.
+    let filter = format!("(&(objectClass=posixAccount)(!(uid={}))(cn=a*b*))", escape(b"x*(y)"));
+    assert_eq!(filter, "(&(objectClass=posixAccount)(!(uid=x\\2a\\28y\\29))(cn=a*b*))");
+    let mut expected = tlv(0xa3, &[tlv(OCTET_STRING, b"objectClass"), tlv(OCTET_STRING, b"posixAccount")].concat());
+    expected.extend(tlv(0xa2, &tlv(0xa3, &[tlv(OCTET_STRING, b"uid"), tlv(OCTET_STRING, b"x*(y)")].concat())));
+    expected.extend(tlv(0xa4, &[tlv(OCTET_STRING, b"cn"), tlv(SEQUENCE, &[tlv(0x80, b"a"), tlv(0x81, b"b")].concat())]
+        .concat()));
+    assert_eq!(encode_filter(&filter), Ok(tlv(0xa0, &expected)));
+    assert!(encode_filter("(uid=alice").is_err());
+}
+
+#[test]
+fn test_ldap_search() {
+    use std::net::TcpListener;
+    use std::thread;
+
+    // A server with one user, a group, and a shadow entry.
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let uri = format!("ldap://{}", listener.local_addr().unwrap());
+    let server = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let attribute = |name: &str, values: &[&str]| {
+            let set: Vec<u8> = values.iter().flat_map(|value| tlv(OCTET_STRING, value.as_bytes())).collect();
+            tlv(SEQUENCE, &[tlv(OCTET_STRING, name.as_bytes()), tlv(SET, &set)].concat())
+        };
+        let entry = [
+            tlv(OCTET_STRING, b"uid=alice,ou=people,dc=example,dc=com"),
+            tlv(SEQUENCE, &[
+                attribute("uid", &["alice", "al"]),
+                attribute("UIDNUMBER", &["1000"]),
+                attribute("gidNumber", &["100"]),
+                attribute("homeDirectory", &["/home/alice"]),
+                attribute("userPassword", &["{SSHA}salted", "{crypt}$6$salt$hash"]),
+                attribute("member", &["uid=bob,ou=people,dc=example,dc=com", "cn=admins,dc=example,dc=com"]),
+                attribute("memberUid", &["alice"]),
+            ].concat()),
+        ].concat();
+        let done = [tlv(ENUMERATED, &[0]), tlv(OCTET_STRING, b""), tlv(OCTET_STRING, b"")].concat();
+        let reply = |stream: &mut TcpStream, id: i64, op: Vec<u8>| {
+            stream.write_all(&tlv(SEQUENCE, &[tlv(INTEGER, &integer(id)), op].concat())).unwrap();
+        };
+
+        let request = read_message(&mut stream).unwrap();
+        let (id, tag, contents) = parse_message(&request).unwrap();
+        assert_eq!(tag, SEARCH_REQUEST);
+        let mut fields = Ber(contents);
+        assert_eq!(fields.expect(OCTET_STRING).unwrap(), b"dc=example,dc=com");
+        reply(&mut stream, 0, tlv(EXTENDED_RESPONSE, &done));
+        reply(&mut stream, id, tlv(SEARCH_RESULT_ENTRY, &entry));
+        reply(&mut stream, id, tlv(SEARCH_RESULT_DONE, &done));
+
+        let unbind = read_message(&mut stream).unwrap();
+        assert_eq!(parse_message(&unbind).unwrap().1, UNBIND_REQUEST);
+    });
+
+    let no_tls = || -> io::Result<Arc<rustls::ClientConfig>> { panic!("no TLS here") };
+    let mut connection = Connection::open(&uri, false, &no_tls, Duration::from_secs(5)).unwrap();
+    let filter = encode_filter("(uid=al)").unwrap();
+    let entries = connection.search("dc=example,dc=com", SCOPE_SUBTREE, &filter, &["uid"]).unwrap();
+    drop(connection);
+    server.join().unwrap();
+    assert_eq!(entries.len(), 1);
+
+    let al = CString::new("al").unwrap();
+    let passwd = passwd_entry(&entries[0], &PasswdAttributes::RFC2307, Some(&al)).unwrap();
+    assert_eq!((passwd.name.to_str(), passwd.uid, passwd.gid), (Ok("al"), 1000, 100));
+    assert_eq!((passwd.dir.to_str(), passwd.shell.to_str()), (Ok("/home/alice"), Ok("")));
+    assert!(passwd_entry(&entries[0], &PasswdAttributes::RFC2307, Some(&CString::new("Al").unwrap())).is_none());
+
+    let attributes = GroupAttributes { name: "uid", ..GroupAttributes::RFC2307BIS };
+    let group = group_entry(&entries[0], &attributes, "uid", None).unwrap();
+    let members: Vec<_> = group.members.iter().map(|member| member.to_str().unwrap()).collect();
+    assert_eq!(members, ["alice", "bob"]);
+
+    let shadow = shadow_entry(&entries[0], &ShadowAttributes::RFC2307, None).unwrap();
+    assert_eq!(shadow.passwd.to_str(), Ok("$6$salt$hash"));
+    assert_eq!((shadow.last_change, shadow.flag), (-1, c_ulong::MAX));
+}
+
+#[test]
+fn test_malformed_messages() {
+    use std::io::Cursor;
+
+    let read = |bytes: &[u8]| read_message(&mut Cursor::new(bytes.to_vec()));
+    let long = tlv(SEQUENCE, &[0; 200]);
+    assert_eq!(read(&long).unwrap(), long);
+
+    // A message cut short, in its length or its contents.
+    assert_eq!(read(&long[..2]).unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+    assert_eq!(read(&long[..50]).unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+
+    // Indefinite lengths, lengths of more than four bytes, and messages
+    // over the limit are refused before anything is read into memory.
+    for bad in &[&[SEQUENCE, 0x80][..], &[SEQUENCE, 0x85, 0, 0, 0, 0, 1], &[SEQUENCE, 0x84, 0x7f, 0xff, 0xff, 0xff]] {
+        assert_eq!(read(bad).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    // Elements that are truncated or have the wrong tag.
+    assert!(parse_message(&tlv(SEQUENCE, &[INTEGER, 5, 1])).is_err());
+    assert!(parse_message(&tlv(SEQUENCE, &tlv(OCTET_STRING, b"1"))).is_err());
+    assert!(parse_message(&tlv(SET, &tlv(INTEGER, &[1]))).is_err());
+    assert!(parse_result(&tlv(ENUMERATED, &[0])).is_err());
+    assert!(parse_integer(&[]).is_err());
+    assert!(parse_integer(&[0; 9]).is_err());
+    assert_eq!(parse_integer(&[0xff]).unwrap(), -1);
+
+    let attribute = tlv(SEQUENCE, &[tlv(OCTET_STRING, b"uid"), tlv(SET, &tlv(INTEGER, &[1]))].concat());
+    assert!(Entry::parse(&[tlv(OCTET_STRING, b"uid=alice"), tlv(SEQUENCE, &attribute)].concat()).is_err());
+    assert!(Entry::parse(&tlv(OCTET_STRING, b"uid=alice")).is_err());
+}
+
+#[test]
+fn test_search_result_codes() {
+    use std::net::TcpListener;
+    use std::thread;
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let uri = format!("ldap://{}", listener.local_addr().unwrap());
+    let server = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let done = |code: i64, message: &[u8]| {
+            tlv(SEARCH_RESULT_DONE, &[tlv(ENUMERATED, &integer(code)), tlv(OCTET_STRING, b""),
+                                      tlv(OCTET_STRING, message)].concat())
+        };
+        let entry = tlv(SEARCH_RESULT_ENTRY, &[tlv(OCTET_STRING, b"uid=alice"), tlv(SEQUENCE, b"")].concat());
+        let replies = vec![
+            vec![entry, done(SIZE_LIMIT_EXCEEDED, b"")],
+            vec![done(NO_SUCH_OBJECT, b"")],
+            vec![done(50, b"no access")],
+            vec![tlv(BIND_RESPONSE, &[tlv(ENUMERATED, &[0]), tlv(OCTET_STRING, b""), tlv(OCTET_STRING, b"")].concat())],
+        ];
+        for ops in replies {
+            let (id, tag, _) = parse_message(&read_message(&mut stream).unwrap()).unwrap();
+            assert_eq!(tag, SEARCH_REQUEST);
+            for op in ops {
+                stream.write_all(&tlv(SEQUENCE, &[tlv(INTEGER, &integer(id)), op].concat())).unwrap();
+            }
+        }
+        let unbind = read_message(&mut stream).unwrap();
+        assert_eq!(parse_message(&unbind).unwrap().1, UNBIND_REQUEST);
+    });
+
+    let no_tls = || -> io::Result<Arc<rustls::ClientConfig>> { panic!("no TLS here") };
+    let mut connection = Connection::open(&uri, false, &no_tls, Duration::from_secs(5)).unwrap();
+    let filter = encode_filter("(uid=alice)").unwrap();
+    let mut search = || connection.search("dc=example,dc=com", SCOPE_SUBTREE, &filter, &["uid"]);
+
+    // Stopping at the size limit keeps the entries found so far, and a
+    // base that doesn't exist has none.
+    assert_eq!(search().unwrap().len(), 1);
+    assert!(search().unwrap().is_empty());
+
+    // Any other result code fails the search, as does a reply that isn't
+    // part of one.
+    let err = search().unwrap_err();
+    assert!(err.to_string().contains("50 no access"), "{}", err);
+    assert_eq!(search().unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+    drop(connection);
+    server.join().unwrap();
+}
+
+#[test]
+fn test_search_timeout() {
+    use std::net::TcpListener;
+    use std::thread;
+    use std::time::Instant;
+
+    // A server that reads the search and never answers it.
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let uri = format!("ldap://{}", listener.local_addr().unwrap());
+    let server = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        assert_eq!(parse_message(&read_message(&mut stream).unwrap()).unwrap().1, SEARCH_REQUEST);
+        assert_eq!(parse_message(&read_message(&mut stream).unwrap()).unwrap().1, UNBIND_REQUEST);
+    });
+
+    let no_tls = || -> io::Result<Arc<rustls::ClientConfig>> { panic!("no TLS here") };
+    let mut connection = Connection::open(&uri, false, &no_tls, Duration::from_millis(100)).unwrap();
+    let filter = encode_filter("(uid=alice)").unwrap();
+    let start = Instant::now();
+    let err = connection.search("dc=example,dc=com", SCOPE_SUBTREE, &filter, &["uid"]).unwrap_err();
+    assert!(matches!(err.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut), "{}", err);
+    assert!(start.elapsed() < Duration::from_secs(5));
+    drop(connection);
+    server.join().unwrap();
+}
+
+#[test]
+fn test_service_errors() {
+    use libc::{ENOENT, ETIMEDOUT};
+
+    struct NoSocket;
+    impl LdapConfig for NoSocket {
+        const URIS: &'static [&'static str] = &["ldapi://%2Fnonexistent%2Fldapi"];
+    }
+    struct BadUri;
+    impl LdapConfig for BadUri {
+        const URIS: &'static [&'static str] = &["http://ldap.example.com"];
+    }
+    struct BadFilter;
+    impl LdapConfig for BadFilter {
+        const URIS: &'static [&'static str] = &["ldapi://%2Fnonexistent%2Fldapi"];
+        const PASSWD_FILTER: &'static str = "(objectClass=posixAccount";
+    }
+
+    // Every failure is reported as unavailable, with the errno of the
+    // underlying error, or EIO if there isn't one.
+    let alice = CString::new("alice").unwrap();
+    let err = LdapService::<NoSocket>::getpwnam_r(&alice).err().unwrap();
+    assert_eq!((err.status(), err.errno()), (NssStatus::Unavailable, ENOENT));
+    let err = LdapService::<BadUri>::getgrgid_r(100).err().unwrap();
+    assert_eq!((err.status(), err.errno()), (NssStatus::Unavailable, EIO));
+    let err = LdapService::<BadFilter>::getpwuid_r(1000).err().unwrap();
+    assert_eq!((err.status(), err.errno()), (NssStatus::Unavailable, EINVAL));
+
+    let err = LdapService::<LdapDefaults>::failed(format_args!("search"), io::Error::from_raw_os_error(ETIMEDOUT));
+    assert_eq!((err.status(), err.errno()), (NssStatus::Unavailable, ETIMEDOUT));
+    let err = LdapService::<LdapDefaults>::failed(format_args!("search"), invalid("truncated"));
+    assert_eq!((err.status(), err.errno()), (NssStatus::Unavailable, EIO));
+}
@@ -1,11 +1,270 @@
 //! Library for creating NSSwitch resolver libraries for Linux.
+//!
+//! The export macros can be imported like any other item:
+//!
+//! ```
+//! use nsswitch_service::{nssglue_gethostbyname2_r, AddressFamily, HostEntry, NameService, Result};
+//! use std::ffi::CStr;
+//! use std::net::IpAddr;
+//!
+//! struct NoHosts;
+//!
+//! impl NameService for NoHosts {
+//!     fn gethostbyname2_r(_name: &CStr, _af: AddressFamily) -> Result<Option<HostEntry<'_>>> {
+//!         Ok(None)
+//!     }
+//!
+//!     fn gethostbyaddr_r(_addr: &IpAddr) -> Result<Option<HostEntry<'_>>> {
+//!         Ok(None)
+//!     }
+//! }
+//!
+//! nssglue_gethostbyname2_r!(_nss_nohosts_gethostbyname2_r, NoHosts);
+//! # fn main() {}
+//! ```
+//!
+//! Modules work with glibc, which is what implements the name service
+//! switch. musl libc has none, and building for a musl target is an error
+//! unless the `musl` feature says it's deliberate. FreeBSD's and NetBSD's
+//! `nsdispatch(3)` can load the same modules, given an
+//! `nss_freebsd_module!` or `nss_netbsd_module!`, and the export macros
+//! also build modules for illumos and Solaris (see `illumos`). Where there
+//! is no name service switch to load a module, as on Android, the
+//! `dns-stub` feature serves a `NameService` as a local DNS server instead
+//! (see `serve_dns`).
+//!
+//! Services run inside other people's programs, so they should fail lookups
+//! rather than take the program down: the glue catches panics, and entries
+//! can be built without risking an abort on a failed allocation (see
+//! `Error::out_of_memory`).
+//!
+//! To test a service without installing it, call it through the glue with
+//! the functions in `testing`, which the `testing` feature enables. It isn't
+//! needed in the modules themselves.
 
-extern crate libc;
+// musl libc never loads `libnss_*.so` modules, so a module built against it
+// would compile and then silently do nothing. Say so up front.
+#[cfg(all(target_env = "musl", not(feature = "musl")))]
+compile_error!(
+    "nsswitch_service: musl libc has no name service switch and will never load this module \
+     (Alpine and other musl systems only consult /etc/hosts, DNS, and nscd). \
+     To build anyway, for example to test services, enable the `musl` feature."
+);
 
+mod addrconfig;
 mod alloc;
+#[cfg(feature = "avahi")]
+mod avahi;
+#[cfg(feature = "blocklist")]
+mod blocklist;
+mod breaker;
+mod cached;
+mod chain;
+mod config;
+#[cfg(feature = "consul")]
+mod consul;
+#[cfg(feature = "containers")]
+mod containers;
+mod cursor;
+#[cfg(feature = "daemon")]
+mod daemon;
+#[cfg(feature = "delegate")]
+mod delegate;
+mod diag;
+#[cfg(any(feature = "dns-stub", feature = "dns-forwarder", feature = "mdns", feature = "llmnr", feature = "wins"))]
+mod dns;
+#[cfg(any(feature = "dns-forwarder", feature = "mdns", feature = "llmnr", feature = "wins"))]
+mod dns_client;
+#[cfg(feature = "dns-forwarder")]
+mod dns_forwarder;
+#[cfg(feature = "dns-stub")]
+mod dns_stub;
+mod errno;
 mod errors;
+#[cfg(feature = "etcd")]
+mod etcd;
+#[cfg(feature = "filter")]
+mod filtered;
+mod foreign;
+mod fork;
+#[cfg(feature = "fuzzing")]
+#[doc(hidden)]
+pub mod fuzzing;
+mod glibc;
+#[cfg(all(test, feature = "golden-tests", target_os = "linux", target_env = "gnu", not(miri)))]
+mod golden;
+#[cfg(target_os = "freebsd")]
+pub mod freebsd;
+#[cfg(feature = "grpc")]
+mod grpc;
+#[cfg(feature = "grpc")]
+mod grpc_client;
+mod host_aliases;
+mod host_table;
+mod hostname;
+mod hosts_file;
+#[cfg(any(feature = "consul", feature = "containers", feature = "etcd", feature = "grpc", feature = "kubernetes",
+          feature = "mesh", feature = "oslogin", feature = "rest"))]
+mod http;
+#[cfg(feature = "idn")]
+mod idn;
+#[cfg(any(target_os = "illumos", target_os = "solaris"))]
+pub mod illumos;
+pub mod ffi;
 mod interfaces;
-#[macro_use] pub mod macros;
+#[cfg(feature = "journald")]
+mod journald;
+#[cfg(feature = "kubernetes")]
+mod kubernetes;
+mod layout;
+#[cfg(feature = "ldap")]
+mod ldap;
+#[cfg(feature = "libvirt")]
+mod libvirt;
+#[cfg(feature = "llmnr")]
+mod llmnr;
+pub mod macros;
+#[cfg(feature = "mdns")]
+mod mdns;
+#[cfg(feature = "mesh")]
+mod mesh;
+#[cfg(target_os = "netbsd")]
+pub mod netbsd;
+mod netif;
+#[cfg(feature = "nis")]
+mod nis;
+mod normalize;
+#[cfg(any(target_os = "freebsd", target_os = "netbsd"))]
+mod nsdispatch;
+#[cfg(feature = "oslogin")]
+mod oslogin;
+mod pin;
+mod post_process;
+#[cfg(test)]
+mod proptests;
+mod ptrcheck;
+mod readers;
+#[cfg(feature = "redis")]
+mod redis;
+mod reentry;
+mod reload;
+#[cfg(feature = "resolved")]
+mod resolved;
+#[cfg(feature = "rest")]
+mod rest;
+mod reverse_map;
+mod rewrite;
+mod search;
+mod shim;
+mod singleflight;
+#[cfg(feature = "tracing")]
+mod spans;
+mod split_horizon;
+#[cfg(feature = "sqlite")]
+mod sqlite;
+#[cfg(feature = "static-map")]
+mod static_map;
+#[cfg(feature = "syslog")]
+mod syslog;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+mod timeout;
+#[cfg(any(feature = "grpc", feature = "ldap", feature = "rest"))]
+mod tls;
+mod trace;
+mod watchdog;
+mod wildcard;
+#[cfg(feature = "wins")]
+mod wins;
 
 pub use interfaces::{AddressFamily, NameService, HostAddressList, HostEntry};
-pub use errors::{Error, HostError, NssStatus, Result};
+pub use interfaces::{HostAddresses, HostEntryWithTtl, HostLimits};
+pub use interfaces::{Entries, no_entries};
+pub use interfaces::{GroupEntry, GroupService, PasswdEntry, PasswdService, ShadowEntry, ShadowService};
+pub use libc::{gid_t, uid_t};
+pub use nsswitch_service_macros::{nss_export, nss_freebsd_module, nss_module, nss_netbsd_module, nss_rustinfo};
+pub use nsswitch_service_macros::{nssglue_group, nssglue_hosts, nssglue_passwd, nssglue_shadow};
+pub use diag::{set_diagnostic_sink, DiagnosticSink};
+#[cfg(feature = "syslog")]
+pub use syslog::{log_to_syslog, SyslogConfig, SyslogDefaults};
+#[cfg(feature = "journald")]
+pub use journald::{log_to_journald, JournaldConfig};
+#[cfg(feature = "tracing")]
+pub use spans::{init_tracing, log_to_tracing};
+pub use trace::{set_trace_sink, TraceSink, TRACE_ENV_VAR};
+pub use config::{env_var, env_var_os, is_secure_mode};
+pub use glibc::glibc_version;
+pub use hostname::is_valid_hostname;
+#[cfg(feature = "avahi")]
+pub use avahi::{AvahiConfig, AvahiDefaults, AvahiService};
+#[cfg(feature = "blocklist")]
+pub use blocklist::{BlocklistConfig, BlocklistService};
+pub use addrconfig::AddrConfig;
+pub use breaker::{BreakerConfig, BreakerDefaults, CircuitBreaker};
+pub use cached::{CacheConfig, CacheDefaults, Cached};
+pub use chain::{ChainConfig, ChainDefaults, ChainService};
+pub use host_aliases::HostAliases;
+pub use netif::Connectivity;
+pub use normalize::Normalized;
+pub use post_process::{PostProcess, PostProcessed};
+pub use reload::{ConfigWatcher, Reload, Reloading};
+pub use reverse_map::{ReverseMapConfig, ReverseMapDefaults, ReverseMapped};
+pub use rewrite::{RewriteConfig, Rewritten};
+pub use search::{SearchConfig, SearchDefaults, Searched};
+pub use singleflight::Singleflight;
+pub use split_horizon::{Route, SplitHorizon, SplitHorizonConfig};
+pub use timeout::{Timeout, TimeoutConfig, TimeoutDefaults};
+#[cfg(feature = "filter")]
+pub use filtered::{FilterConfig, Filtered};
+#[cfg(feature = "idn")]
+pub use idn::Idn;
+#[cfg(feature = "consul")]
+pub use consul::{ConsulConfig, ConsulDefaults, ConsulInstance, ConsulService};
+#[cfg(feature = "containers")]
+pub use containers::{ContainerConfig, ContainerDefaults, ContainerService};
+#[cfg(feature = "daemon")]
+pub use daemon::{DaemonConfig, DaemonDefaults, DaemonServer, DaemonService};
+#[cfg(feature = "delegate")]
+pub use delegate::{DelegateConfig, DelegateDefaults, DelegateModule, DelegateService};
+#[cfg(feature = "etcd")]
+pub use etcd::{EtcdConfig, EtcdDefaults, EtcdService};
+#[cfg(feature = "grpc")]
+pub use grpc::{GrpcConfig, GrpcDefaults, GrpcService};
+pub use hosts_file::{HostsFileConfig, HostsFileService};
+#[cfg(feature = "kubernetes")]
+pub use kubernetes::{KubernetesConfig, KubernetesDefaults, KubernetesService};
+#[cfg(feature = "ldap")]
+pub use ldap::{GroupAttributes, LdapConfig, LdapDefaults, LdapService, PasswdAttributes, ShadowAttributes};
+#[cfg(feature = "redis")]
+pub use redis::{RedisConfig, RedisDefaults, RedisService};
+#[cfg(feature = "resolved")]
+pub use resolved::{ResolvedConfig, ResolvedDefaults, ResolvedService};
+#[cfg(feature = "rest")]
+pub use rest::{RestConfig, RestDefaults, RestService};
+#[cfg(feature = "sqlite")]
+pub use sqlite::{SqliteConfig, SqliteDefaults, SqliteService};
+pub use wildcard::{WildcardConfig, WildcardService};
+#[cfg(feature = "wins")]
+pub use wins::{WinsConfig, WinsDefaults, WinsService};
+#[cfg(feature = "dns-forwarder")]
+pub use dns_forwarder::{DnsForwarderConfig, DnsForwarderService};
+#[cfg(feature = "libvirt")]
+pub use libvirt::{LibvirtConfig, LibvirtDefaults, LibvirtService};
+#[cfg(feature = "llmnr")]
+pub use llmnr::{LlmnrConfig, LlmnrDefaults, LlmnrService};
+#[cfg(feature = "mdns")]
+pub use mdns::{MdnsConfig, MdnsDefaults, MdnsService};
+#[cfg(feature = "mesh")]
+pub use mesh::{MeshConfig, MeshDefaults, MeshService};
+#[cfg(feature = "nis")]
+pub use nis::{NisConfig, NisDefaults, NisService};
+#[cfg(feature = "oslogin")]
+pub use oslogin::{OsLoginConfig, OsLoginDefaults, OsLoginService};
+#[cfg(feature = "dns-stub")]
+pub use dns_stub::{answer_dns_query, serve_dns};
+pub use pin::pin_module;
+#[cfg(feature = "static-map")]
+pub use static_map::{StaticMapConfig, StaticMapService};
+pub use reentry::in_lookup;
+pub use fork::{add_fork_child_hook, register_fork_handlers};
+pub use errors::{Error, HostError, NssStatus, Result, UnknownCode};
@@ -0,0 +1,197 @@
+//! A ready-made `hosts` service that resolves libvirt guests from the DHCP
+//! leases libvirt's dnsmasq hands out.
+
+use crate::diag;
+use crate::errors::{Error, NssStatus, Result};
+use crate::host_table::{Host, HostTable};
+use crate::interfaces::{AddressFamily, Entries, HostEntry, NameService};
+use libc::EIO;
+use serde_json::Value;
+use std::ffi::{CStr, CString};
+use std::fs;
+use std::io;
+use std::marker::PhantomData;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Where a `LibvirtService` finds libvirt's leases.
+pub trait LibvirtConfig: 'static {
+    /// The directory with each network's `.status` and `.macs` files.
+    const DIR: &'static str = "/var/lib/libvirt/dnsmasq";
+}
+
+/// The usual settings: the system libvirt daemon's networks.
+pub struct LibvirtDefaults;
+
+impl LibvirtConfig for LibvirtDefaults {}
+
+/// A `hosts` service that resolves the guests on libvirt's virtual
+/// networks, so `ssh myvm` works without editing `/etc/hosts` whenever a
+/// guest gets a new address:
+///
+/// ```ignore
+/// nssglue_hosts!("libvirt", LibvirtService);
+/// ```
+///
+/// libvirt keeps each network's current DHCP leases in a `.status` file,
+/// and the MAC addresses of each guest (domain) in a `.macs` file. A guest
+/// answers to its domain name, which is its canonical name, and to the
+/// hostname its DHCP client sent, which is an alias: between them, this
+/// does what libvirt's own `libvirt` and `libvirt_guest` modules do.
+/// Expired leases are ignored. Reverse lookups and `sethostent` work too.
+///
+/// The files are read on every lookup, since they change whenever a guest
+/// starts. If the directory doesn't exist, lookups report
+/// `NssStatus::Unavailable`.
+pub struct LibvirtService<C = LibvirtDefaults>(PhantomData<C>);
+
+/// A current lease.
+#[derive(Debug, PartialEq)]
+struct Lease {
+    addr: IpAddr,
+    mac: String,
+    hostname: Option<String>,
+}
+
+/// The unexpired leases in the contents of a `.status` file.
+fn parse_status(json: &str, now: u64) -> std::result::Result<Vec<Lease>, String> {
+    let list: Value = serde_json::from_str(json).map_err(|err| err.to_string())?;
+    let list = list.as_array().ok_or("expected a list of leases")?;
+    Ok(list.iter()
+        .filter(|lease| lease["expiry-time"].as_u64().is_none_or(|expiry| expiry > now))
+        .filter_map(|lease| Some(Lease {
+            addr: lease["ip-address"].as_str()?.parse().ok()?,
+            mac: lease["mac-address"].as_str()?.to_ascii_lowercase(),
+            hostname: lease["hostname"].as_str().map(str::to_string),
+        }))
+        .collect())
+}
+
+/// The domain name for each MAC address in the contents of a `.macs`
+/// file.
+fn parse_macs(json: &str) -> std::result::Result<Vec<(String, String)>, String> {
+    let list: Value = serde_json::from_str(json).map_err(|err| err.to_string())?;
+    let list = list.as_array().ok_or("expected a list of domains")?;
+    Ok(list.iter()
+        .filter_map(|domain| Some((domain["domain"].as_str()?, domain["macs"].as_array()?)))
+        .flat_map(|(name, macs)| {
+            macs.iter().filter_map(Value::as_str).map(move |mac| (mac.to_ascii_lowercase(), name.to_string()))
+        })
+        .collect())
+}
+
+/// One host per guest, with all its leases' addresses.
+fn build_table(leases: Vec<Lease>, macs: &[(String, String)]) -> HostTable {
+    let mut table = HostTable::default();
+    for lease in leases {
+        let domain = macs.iter().find(|(mac, _)| *mac == lease.mac).map(|(_, name)| name.clone());
+        let mut names = domain.into_iter().chain(lease.hostname)
+            .filter_map(|name| CString::new(name).ok());
+        let name = match names.next() {
+            Some(name) => name,
+            None => continue,
+        };
+        let aliases: Vec<CString> = names.filter(|alias| *alias != name).collect();
+        match table.hosts.iter_mut().find(|host| host.name == name) {
+            Some(host) => {
+                host.addrs.push(lease.addr);
+                for alias in aliases {
+                    if !host.aliases.contains(&alias) {
+                        host.aliases.push(alias);
+                    }
+                }
+            }
+            None => table.hosts.push(Host { name, aliases, addrs: vec![lease.addr] }),
+        }
+    }
+    table
+}
+
+/// Read every network's leases and guests from `dir`.
+fn load(dir: &str) -> io::Result<HostTable> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |since| since.as_secs());
+    let mut leases = Vec::new();
+    let mut macs = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let ext = path.extension().and_then(|ext| ext.to_str());
+        if ext != Some("status") && ext != Some("macs") {
+            continue;
+        }
+        // Networks with no leases have empty files.
+        let text = fs::read_to_string(&path)?;
+        if text.trim().is_empty() {
+            continue;
+        }
+        let parsed = if ext == Some("status") {
+            parse_status(&text, now).map(|found| leases.extend(found))
+        } else {
+            parse_macs(&text).map(|found| macs.extend(found))
+        };
+        // A file that's being rewritten may be cut short; skip it this
+        // time.
+        if let Err(message) = parsed {
+            diag::log(format_args!("skipping {}: {}", path.display(), message));
+        }
+    }
+    Ok(build_table(leases, &macs))
+}
+
+impl<C: LibvirtConfig> LibvirtService<C> {
+    fn table() -> Result<Arc<HostTable>> {
+        load(C::DIR).map(Arc::new).map_err(|err| {
+            if err.kind() != io::ErrorKind::NotFound {
+                diag::log(format_args!("can't read {}: {}", C::DIR, err));
+            }
+            Error::with_errno(NssStatus::Unavailable, err.raw_os_error().unwrap_or(EIO))
+        })
+    }
+}
+
+impl<C: LibvirtConfig> NameService for LibvirtService<C> {
+    fn gethostbyname2_r(name: &CStr, af: AddressFamily) -> Result<Option<HostEntry<'_>>> {
+        Ok(Self::table()?.by_name(name, af))
+    }
+
+    fn gethostbyaddr_r(addr: &IpAddr) -> Result<Option<HostEntry<'_>>> {
+        Ok(Self::table()?.by_addr(addr))
+    }
+
+    fn sethostent(_stay_open: bool) -> Result<Entries<HostEntry<'static>>> {
+        Ok(HostTable::entries(Self::table()?))
+    }
+}
+
+#[test]
+fn test_libvirt_leases() {
+    let status = r#"[
+        {"ip-address": "192.168.122.45", "mac-address": "52:54:00:AA:BB:01", "hostname": "fedora",
+         "expiry-time": 2000},
+        {"ip-address": "192.168.122.46", "mac-address": "52:54:00:aa:bb:02", "expiry-time": 2000},
+        {"ip-address": "fd00:122::46", "mac-address": "52:54:00:aa:bb:02", "expiry-time": 2000},
+        {"ip-address": "192.168.122.47", "mac-address": "52:54:00:aa:bb:03", "hostname": "old",
+         "expiry-time": 1000}
+    ]"#;
+    let macs = r#"[{"domain": "myvm", "macs": ["52:54:00:aa:bb:01"]},
+                   {"domain": "builder", "macs": ["52:54:00:aa:bb:02"]}]"#;
+    let leases = parse_status(status, 1500).unwrap();
+    assert_eq!(leases.len(), 3);
+    let table = build_table(leases, &parse_macs(macs).unwrap());
+
+    let name = |s: &'static [u8]| CStr::from_bytes_with_nul(s).unwrap();
+    let entry = table.by_name(name(b"fedora\0"), AddressFamily::Ipv4).unwrap();
+    assert_eq!(entry.name.to_str(), Ok("myvm"));
+    assert!(table.by_name(name(b"builder\0"), AddressFamily::Ipv6).is_some());
+    assert!(table.by_name(name(b"old\0"), AddressFamily::Ipv4).is_none());
+    let entry = table.by_addr(&"192.168.122.46".parse().unwrap()).unwrap();
+    assert_eq!((entry.name.to_str(), entry.aliases.len()), (Ok("builder"), 0));
+
+    assert!(parse_status("", 0).is_err());
+    struct Missing;
+    impl LibvirtConfig for Missing {
+        const DIR: &'static str = "/nonexistent/dnsmasq";
+    }
+    let err = LibvirtService::<Missing>::gethostbyname2_r(name(b"myvm\0"), AddressFamily::Ipv4).unwrap_err();
+    assert_eq!((err.status(), err.errno()), (NssStatus::Unavailable, libc::ENOENT));
+}
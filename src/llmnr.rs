@@ -0,0 +1,200 @@
+//! A ready-made `hosts` service that resolves single-label names with
+//! Link-Local Multicast Name Resolution, as Windows machines do.
+
+use crate::diag;
+use crate::dns;
+use crate::dns_client::{self, Answer};
+use crate::errors::{Error, NssStatus, Result};
+use crate::fork::ForkSafeMutex;
+use crate::interfaces::{AddressFamily, HostAddresses, HostEntry, HostEntryWithTtl, NameService};
+use libc::EIO;
+use std::ffi::CStr;
+use std::io;
+use std::marker::PhantomData;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket};
+use std::time::{Duration, Instant};
+
+/// Settings for an `LlmnrService`.
+pub trait LlmnrConfig: 'static {
+    /// How long to wait for answers. RFC 4795 suggests a second.
+    const TIMEOUT: Duration = Duration::from_secs(1);
+
+    /// How long to remember that nobody answered for a name, so that
+    /// looking it up again fails at once instead of after `TIMEOUT`.
+    const NEGATIVE_TTL: Duration = Duration::from_secs(30);
+}
+
+/// The usual settings.
+pub struct LlmnrDefaults;
+
+impl LlmnrConfig for LlmnrDefaults {}
+
+/// A `hosts` service that resolves single-label names, like `fileserver`,
+/// by asking the local link with LLMNR (RFC 4795), for LANs where the
+/// Windows machines answer LLMNR and nothing else:
+///
+/// ```ignore
+/// nssglue_hosts!("llmnr", LlmnrService);
+/// ```
+///
+/// Each lookup sends a query to `224.0.0.252`, port 5355, for each family
+/// it wants, and takes the first answer to each within `TIMEOUT`. A name
+/// nobody answers for isn't found, and is remembered for `NEGATIVE_TTL`;
+/// a name whose owner has no address of a family is `NO_DATA`, as usual.
+/// Names with dots in them are left to the next service, so it works
+/// alongside `MdnsService` and DNS. There are no reverse lookups, and
+/// queries are only sent over IPv4.
+pub struct LlmnrService<C = LlmnrDefaults>(PhantomData<C>);
+
+/// Where LLMNR queries go.
+const LLMNR_GROUP: (Ipv4Addr, u16) = (Ipv4Addr::new(224, 0, 0, 252), 5355);
+
+/// The most names remembered as not found.
+const MAX_NEGATIVE: usize = 256;
+
+/// Names nobody answered for, in lowercase, with the type asked for and
+/// when to forget them, oldest first.
+static NEGATIVE: ForkSafeMutex<Vec<(Vec<u8>, u16, Instant)>> = ForkSafeMutex::new();
+
+fn is_single_label(name: &[u8]) -> bool {
+    let name = name.strip_suffix(b".").unwrap_or(name);
+    !name.is_empty() && !name.contains(&b'.')
+}
+
+/// Ask `dest` each of `qtypes` about `name`, except those nobody answered
+/// lately. The answer is `None` where nobody answers.
+fn lookup(dest: SocketAddr, name: &[u8], qtypes: &[u16], timeout: Duration, negative_ttl: Duration)
+    -> io::Result<Vec<Option<Answer>>>
+{
+    let key = name.strip_suffix(b".").unwrap_or(name).to_ascii_lowercase();
+    let now = Instant::now();
+    let ask: Vec<u16> = {
+        let mut negative = NEGATIVE.lock();
+        negative.retain(|&(_, _, expires)| expires > now);
+        qtypes.iter()
+            .copied()
+            .filter(|&qtype| !negative.iter().any(|(name, t, _)| *name == key && *t == qtype))
+            .collect()
+    };
+    let mut answers = if ask.is_empty() {
+        vec![]
+    } else {
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+        // RFC 4795, section 2.5.
+        socket.set_multicast_ttl_v4(255)?;
+        dns_client::exchange(&socket, dest, 0, name, &ask, timeout)?
+    };
+
+    let mut negative = NEGATIVE.lock();
+    for (&qtype, _) in ask.iter().zip(&answers).filter(|&(_, answer)| answer.is_none()) {
+        if negative.len() >= MAX_NEGATIVE {
+            negative.remove(0);
+        }
+        negative.push((key.clone(), qtype, now + negative_ttl));
+    }
+    Ok(qtypes.iter()
+        .map(|qtype| match ask.iter().position(|t| t == qtype) {
+            Some(i) => answers[i].take(),
+            None => None,
+        })
+        .collect())
+}
+
+impl<C: LlmnrConfig> LlmnrService<C> {
+    fn lookup(name: &[u8], qtypes: &[u16]) -> Result<Vec<Option<Answer>>> {
+        lookup(LLMNR_GROUP.into(), name, qtypes, C::TIMEOUT, C::NEGATIVE_TTL).map_err(|err| {
+            diag::log(format_args!("can't send an LLMNR query: {}", err));
+            Error::with_errno(NssStatus::Unavailable, err.raw_os_error().unwrap_or(EIO))
+        })
+    }
+}
+
+impl<C: LlmnrConfig> NameService for LlmnrService<C> {
+    fn gethostbyname2_r(name: &CStr, af: AddressFamily) -> Result<Option<HostEntry<'_>>> {
+        Ok(Self::gethostbyname3_r(name, af)?.map(|found| found.entry))
+    }
+
+    fn gethostbyaddr_r(_addr: &IpAddr) -> Result<Option<HostEntry<'_>>> {
+        Ok(None)
+    }
+
+    fn gethostbyname3_r(name: &CStr, af: AddressFamily) -> Result<Option<HostEntryWithTtl<'_>>> {
+        if !is_single_label(name.to_bytes()) {
+            return Ok(None);
+        }
+        match Self::lookup(name.to_bytes(), &[dns_client::address_type(af)])?.remove(0) {
+            Some(answer) => dns_client::host_entry(name, af, answer).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn gethostbyname4_r(name: &CStr) -> Result<Option<HostAddresses<'_>>> {
+        if !is_single_label(name.to_bytes()) {
+            return Ok(None);
+        }
+        let answers: Vec<Answer> = Self::lookup(name.to_bytes(), &[dns::TYPE_AAAA, dns::TYPE_A])?
+            .into_iter()
+            .flatten()
+            .collect();
+        if answers.is_empty() {
+            return Ok(None);
+        }
+        dns_client::host_addresses(name, &answers).map(Some)
+    }
+
+    fn on_fork_child() {
+        NEGATIVE.reset();
+    }
+}
+
+#[test]
+fn test_llmnr_lookup() {
+    use crate::dns::{Header, Reader, Writer};
+    use std::thread;
+
+    assert!(is_single_label(b"fileserver") && is_single_label(b"FileServer."));
+    assert!(!is_single_label(b"fileserver.corp") && !is_single_label(b"."));
+
+    // A responder that has an IPv4 address for "fileserver" and says so
+    // for A queries, and answers AAAA queries with no records. It ignores
+    // other names.
+    let responder = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let dest = responder.local_addr().unwrap();
+    let responding = thread::spawn(move || {
+        let mut buf = [0_u8; 512];
+        let mut queries = 0;
+        for _ in 0..3 {
+            let (len, peer) = responder.recv_from(&mut buf).unwrap();
+            queries += 1;
+            let query = Reader::new(&buf[..len]).message().unwrap();
+            let question = &query.questions[0];
+            if !question.name.eq_ignore_ascii_case(b"fileserver") {
+                continue;
+            }
+            let has_record = question.qtype == dns::TYPE_A;
+            let header = Header { flags: dns::FLAG_QR, ancount: has_record as u16, ..query.header };
+            let mut writer = Writer::new(&header);
+            writer.question(question).unwrap();
+            if has_record {
+                writer.record(&question.name, dns::TYPE_A, 30, &[192, 168, 1, 10]).unwrap();
+            }
+            responder.send_to(&writer.finish(), peer).unwrap();
+        }
+        queries
+    });
+
+    let ttl = Duration::from_secs(60);
+    let answers = lookup(dest, b"FileServer", &[dns::TYPE_AAAA, dns::TYPE_A], Duration::from_secs(5), ttl).unwrap();
+    let name = CStr::from_bytes_with_nul(b"FileServer\0").unwrap();
+    let answers: Vec<Answer> = answers.into_iter().flatten().collect();
+    let found = dns_client::host_addresses(name, &answers).unwrap();
+    assert_eq!(found.addrs, ["192.168.1.10".parse::<IpAddr>().unwrap()]);
+
+    // Nobody answers for "nobody", and the second time, nobody is asked.
+    let answers = lookup(dest, b"nobody", &[dns::TYPE_A], Duration::from_millis(100), ttl).unwrap();
+    assert!(answers[0].is_none());
+    let start = Instant::now();
+    let answers = lookup(dest, b"NOBODY.", &[dns::TYPE_A], Duration::from_secs(5), ttl).unwrap();
+    assert!(answers[0].is_none() && start.elapsed() < Duration::from_secs(1));
+    assert_eq!(responding.join().unwrap(), 3);
+}
@@ -1,10 +1,27 @@
-use alloc::BumpAllocator;
-use errors::{Error, Result};
-pub use errors::NssStatus;
-use interfaces::{AddressFamily, HostEntry, HostAddressList, NameService};
-use libc::{AF_INET, AF_INET6, in_addr_t, in6_addr };
-pub use libc::{c_char, c_int, c_void, ENOENT, hostent};
-use std::{iter, mem, ptr};
+use crate::alloc::BumpAllocator;
+use crate::cursor;
+use crate::diag;
+use crate::errno::SavedErrno;
+use crate::errors::{Error, HostError, Result};
+use crate::ffi::{c_char, c_int, c_long, c_void, gaih_addrtuple, gid_t, group, hostent, passwd, spwd,
+                 uid_t, NssStatus};
+use crate::interfaces::{AddressFamily, GroupEntry, GroupService, HostAddresses, HostEntry,
+                        HostEntryWithTtl, HostAddressList, HostLimits, NameService, PasswdEntry, PasswdService,
+                        ShadowEntry, ShadowService};
+use crate::hostname::is_valid_hostname;
+use crate::ptrcheck;
+use crate::shim;
+#[cfg(feature = "tracing")]
+use crate::spans;
+use crate::trace;
+use crate::reentry::LookupGuard;
+use crate::watchdog;
+use libc::{AF_INET, AF_INET6, EMSGSIZE, ENOENT, in_addr_t, in6_addr };
+use std::{iter, ptr};
+#[cfg(test)]
+use std::mem;
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
 use std::ffi::CStr;
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 
@@ -24,55 +41,73 @@ fn relax_array_ptr<T>(p: &mut [T]) -> *mut T {
     p as *mut [T] as *mut T
 }
 
-fn to_in_addr_t(ip: Ipv4Addr) -> in_addr_t {
-    <Ipv4Addr as Into<u32>>::into(ip)
+/// Zero the caller's struct at `p`, padding included, so that it can be
+/// filled in a field at a time. Assigning a whole struct leaves its padding
+/// uninitialized, which shows up in MemorySanitizer and valgrind reports
+/// and can leak bytes from our stack.
+///
+/// # Safety
+///
+/// `p` must be valid for writes, and the struct must be one for which all
+/// zero bytes is a valid value (C structs of integers and pointers).
+unsafe fn zeroed_out<'a, T>(p: *mut T) -> &'a mut T {
+    ptr::write_bytes(p, 0, 1);
+    &mut *p
 }
 
-fn to_in6_addr(ipv6: Ipv6Addr) -> in6_addr {
-    // in6_addr has, unfortunately, a private field, for alignment.
-    // This means we can't construct it just by writing
-    //     in6_addr { s6_addr: ipv6.octets() }
-    // as we would like. Fortunately the private field is 0-size.
-    unsafe {
-        mem::transmute(ipv6.octets())
+/// Copy `strings` into the buffer as a null-terminated array of pointers to
+/// C strings, the form of `h_aliases` and `gr_mem`. Callers loop over these
+/// until they hit a null pointer, so there is always a null, even when
+/// there are no strings.
+///
+/// This doesn't touch the heap: the array is allocated in the buffer first,
+/// and filled in as the strings are copied.
+fn copy_c_str_array<'s, I>(allocator: &mut BumpAllocator, strings: I) -> Result<*mut *mut c_char>
+where
+    I: Iterator<Item = &'s CStr> + Clone,
+{
+    let array = allocator.allocate_array(
+        strings.clone().map(|_| ptr::null_mut()).chain(iter::once(ptr::null_mut()))
+    )?;
+    for (slot, string) in array.iter_mut().zip(strings) {
+        *slot = allocator.copy_c_str(string)?.as_ptr() as *mut c_char;
+    }
+    Ok(relax_array_ptr(array))
+}
+
+/// Check a host record's name against `limits`.
+fn check_name_len(name: &CStr, limits: &HostLimits) -> Result<()> {
+    if name.to_bytes().len() > limits.max_name_len {
+        Err(Error::with_host(NssStatus::NotFound, EMSGSIZE, HostError::NoRecovery))
+    } else {
+        Ok(())
     }
 }
 
 impl<'a> HostEntry<'a> {
-    fn write_to(
+    pub(crate) fn write_to(
         &self,
+        limits: &HostLimits,
         resultp: *mut hostent,
         buffer: *mut c_char,
         buflen: usize
     ) -> Result<()> {
+        check_name_len(&self.name, limits)?;
         let mut allocator = unsafe { BumpAllocator::from_ptr(buffer, buflen) }?;
 
         let h_name = allocator.copy_c_str(&self.name)?.as_ptr() as *mut c_char;
-        let h_aliases =
-            if self.aliases.is_empty() {
-                ptr::null_mut()
-            } else {
-                let copied_aliases: Result<Vec<*mut c_char>> =
-                    self.aliases.iter()
-                    .map(|alias| {
-                        allocator.copy_c_str(alias)
-                            .map(|cstr| cstr.as_ptr() as *mut c_char)
-                    })
-                    .collect();
-                allocator.allocate_array(copied_aliases?.into_iter())?.as_mut_ptr()
-            };
+        let aliases = self.aliases.iter()
+            .map(|alias| &**alias)
+            .filter(|alias| alias.to_bytes().len() <= limits.max_name_len)
+            .take(limits.max_aliases);
+        let h_aliases = copy_c_str_array(&mut allocator, aliases)?;
 
         let (h_addrtype, h_length, h_addr_list) =
             match self.addr_list {
                 HostAddressList::V4(ref addrs) => {
-                    const INADDRSZ: c_int = 4;
-                    debug_assert_eq!(INADDRSZ, mem::size_of::<in_addr_t>() as i32);
-
                     // First, store all the addresses in the user's buffer.
-                    // This API uses network byte order, hence the `.to_be()`.
                     let buf_addrs: &mut [in_addr_t] = allocator.allocate_array(
-                        addrs.iter()
-                            .map(|ip| to_in_addr_t(*ip).to_be())
+                        addrs.iter().take(limits.max_addresses).map(|ip| shim::in_addr(*ip))
                     )?;
 
                     // Make a null-terminated array of pointers to the elements of buf_addrs.
@@ -82,15 +117,12 @@ impl<'a> HostEntry<'a> {
                             .map(|ip_ref| ip_ref as *mut in_addr_t as *mut c_char)
                             .chain(iter::once(ptr::null_mut()))
                     )?;
-                    (AF_INET, INADDRSZ, relax_array_ptr(addr_ptrs))
+                    (AF_INET, shim::INADDRSZ, relax_array_ptr(addr_ptrs))
                 }
                 HostAddressList::V6(ref addrs) => {
                     // See the V4 case for an explanation.
-                    const IN6ADDRSZ: c_int = 16;
-                    debug_assert_eq!(IN6ADDRSZ, mem::size_of::<in6_addr>() as i32);
-
                     let buf_addrs: &mut [in6_addr] = allocator.allocate_array(
-                        addrs.iter().map(|ipv6| to_in6_addr(*ipv6))
+                        addrs.iter().take(limits.max_addresses).map(|ipv6| shim::in6_addr(*ipv6))
                     )?;
 
                     let addr_ptrs: &mut [*mut c_char] = allocator.allocate_array(
@@ -98,21 +130,295 @@ impl<'a> HostEntry<'a> {
                             .map(|ipv6_ref| ipv6_ref as *mut in6_addr as *mut c_char)
                             .chain(iter::once(ptr::null_mut()))
                     )?;
-                    (AF_INET6, IN6ADDRSZ, relax_array_ptr(addr_ptrs))
+                    (AF_INET6, shim::IN6ADDRSZ, relax_array_ptr(addr_ptrs))
                 }
             };
 
+        let result = unsafe { zeroed_out(resultp) };
+        result.h_name = h_name;
+        result.h_aliases = h_aliases;
+        result.h_addrtype = h_addrtype;
+        result.h_length = h_length;
+        result.h_addr_list = h_addr_list;
+        if ptrcheck::ENABLED {
+            let buffer = ptrcheck::Buffer::new(buffer, buflen);
+            ptrcheck::expect_in_buffer(unsafe { buffer.check_hostent(result) }, "hostent");
+        }
+        Ok(())
+    }
+}
+
+/// Copy `s` into a null-terminated byte array, at compile time. This is how
+/// `nss_rustinfo!` makes a C string out of a `concat!`; `N` must be
+/// `s.len() + 1`.
+#[doc(hidden)]
+pub const fn c_string_array<const N: usize>(s: &str) -> [u8; N] {
+    let bytes = s.as_bytes();
+    assert!(bytes.len() + 1 == N);
+    let mut array = [0; N];
+    let mut i = 0;
+    while i < bytes.len() {
+        array[i] = bytes[i];
+        i += 1;
+    }
+    array
+}
+
+impl<'a> HostAddresses<'a> {
+    pub(crate) fn write_to(
+        &self,
+        limits: &HostLimits,
+        pat: *mut *mut gaih_addrtuple,
+        buffer: *mut c_char,
+        buflen: usize
+    ) -> Result<()> {
+        check_name_len(&self.name, limits)?;
+        let mut allocator = unsafe { BumpAllocator::from_ptr(buffer, buflen) }?;
+
+        let name = allocator.copy_c_str(&self.name)?.as_ptr() as *mut c_char;
+        let tuples: &mut [gaih_addrtuple] = allocator.allocate_array(
+            self.addrs.iter().take(limits.max_addresses).enumerate().map(|(i, ip)| {
+                let (family, addr) = shim::tuple_addr(*ip);
+                gaih_addrtuple {
+                    next: ptr::null_mut(),
+                    // Like glibc's own services, name only the first tuple.
+                    name: if i == 0 { name } else { ptr::null_mut() },
+                    family,
+                    addr,
+                    scopeid: 0,
+                }
+            })
+        )?;
+
+        // Link each tuple to the next.
+        for i in 1..tuples.len() {
+            let next: *mut gaih_addrtuple = &mut tuples[i];
+            tuples[i - 1].next = next;
+        }
+
         unsafe {
-            *resultp = hostent { h_name, h_aliases, h_addrtype, h_length, h_addr_list };
+            *pat = relax_array_ptr(tuples);
+        }
+        if ptrcheck::ENABLED {
+            let buffer = ptrcheck::Buffer::new(buffer, buflen);
+            ptrcheck::expect_in_buffer(unsafe { buffer.check_addrtuples(*pat) }, "gaih_addrtuple");
+        }
+        Ok(())
+    }
+}
+
+impl<'a> PasswdEntry<'a> {
+    pub(crate) fn write_to(
+        &self,
+        resultp: *mut passwd,
+        buffer: *mut c_char,
+        buflen: usize
+    ) -> Result<()> {
+        let mut allocator = unsafe { BumpAllocator::from_ptr(buffer, buflen) }?;
+        let mut copy = |s: &CStr| allocator.copy_c_str(s).map(|s| s.as_ptr() as *mut c_char);
+
+        let pw_name = copy(&self.name)?;
+        let pw_passwd = copy(&self.passwd)?;
+        let pw_gecos = copy(&self.gecos)?;
+        let pw_dir = copy(&self.dir)?;
+        let pw_shell = copy(&self.shell)?;
+        let result = unsafe { zeroed_out(resultp) };
+        result.pw_name = pw_name;
+        result.pw_passwd = pw_passwd;
+        result.pw_uid = self.uid;
+        result.pw_gid = self.gid;
+        result.pw_gecos = pw_gecos;
+        result.pw_dir = pw_dir;
+        result.pw_shell = pw_shell;
+        if ptrcheck::ENABLED {
+            let buffer = ptrcheck::Buffer::new(buffer, buflen);
+            ptrcheck::expect_in_buffer(unsafe { buffer.check_passwd(result) }, "passwd");
+        }
+        Ok(())
+    }
+}
+
+impl<'a> GroupEntry<'a> {
+    pub(crate) fn write_to(
+        &self,
+        resultp: *mut group,
+        buffer: *mut c_char,
+        buflen: usize
+    ) -> Result<()> {
+        let mut allocator = unsafe { BumpAllocator::from_ptr(buffer, buflen) }?;
+
+        let gr_name = allocator.copy_c_str(&self.name)?.as_ptr() as *mut c_char;
+        let gr_passwd = allocator.copy_c_str(&self.passwd)?.as_ptr() as *mut c_char;
+        let gr_mem = copy_c_str_array(&mut allocator, self.members.iter().map(|member| &**member))?;
+
+        let result = unsafe { zeroed_out(resultp) };
+        result.gr_name = gr_name;
+        result.gr_passwd = gr_passwd;
+        result.gr_gid = self.gid;
+        result.gr_mem = gr_mem;
+        if ptrcheck::ENABLED {
+            let buffer = ptrcheck::Buffer::new(buffer, buflen);
+            ptrcheck::expect_in_buffer(unsafe { buffer.check_group(result) }, "group");
+        }
+        Ok(())
+    }
+}
+
+impl<'a> ShadowEntry<'a> {
+    pub(crate) fn write_to(
+        &self,
+        resultp: *mut spwd,
+        buffer: *mut c_char,
+        buflen: usize
+    ) -> Result<()> {
+        let mut allocator = unsafe { BumpAllocator::from_ptr(buffer, buflen) }?;
+
+        let sp_namp = allocator.copy_c_str(&self.name)?.as_ptr() as *mut c_char;
+        let sp_pwdp = allocator.copy_c_str(&self.passwd)?.as_ptr() as *mut c_char;
+
+        let result = unsafe { zeroed_out(resultp) };
+        result.sp_namp = sp_namp;
+        result.sp_pwdp = sp_pwdp;
+        result.sp_lstchg = self.last_change;
+        result.sp_min = self.min;
+        result.sp_max = self.max;
+        result.sp_warn = self.warn;
+        result.sp_inact = self.inactive;
+        result.sp_expire = self.expire;
+        result.sp_flag = self.flag;
+        if ptrcheck::ENABLED {
+            let buffer = ptrcheck::Buffer::new(buffer, buflen);
+            ptrcheck::expect_in_buffer(unsafe { buffer.check_spwd(result) }, "spwd");
+        }
+        Ok(())
+    }
+}
+
+/// Call `body`, catching any panic so that it can't unwind into the C code
+/// that called us, which would be undefined behavior. A panic is logged and
+/// then reported to the caller using `report`.
+///
+/// If this thread is already inside the module, `body` isn't called, and the
+/// nested call fails with `Error::reentered()`; see `crate::reentry`.
+///
+/// On success, `errno` is restored to its value on entry; see `crate::errno`.
+///
+/// If tracing is on, the call to `function` with `args` is traced with the
+/// status returned; see `crate::trace`. With the `tracing` feature, it's
+/// also reported as a span; see `crate::spans`.
+fn call_guarded<B, R>(function: &'static str, args: fmt::Arguments<'_>, body: B, report: R) -> NssStatus
+where
+    B: FnOnce() -> NssStatus,
+    R: FnOnce(Error) -> NssStatus,
+{
+    let start = trace::start();
+    let saved_errno = SavedErrno::save();
+    let status = match LookupGuard::enter() {
+        None => report(Error::reentered()),
+        Some(_guard) => {
+            #[cfg(feature = "tracing")]
+            let span = spans::CallSpan::enter(function, args);
+            let status = match panic::catch_unwind(AssertUnwindSafe(body)) {
+                Ok(status) => status,
+                Err(payload) => {
+                    let err = Error::from_panic(payload);
+                    diag::log_panic(err.panic_message().unwrap_or(""));
+                    report(err)
+                }
+            };
+            #[cfg(feature = "tracing")]
+            span.finish(trace::status_name(status));
+            status
         }
+    };
+    if status == NssStatus::Success {
+        saved_errno.restore();
+    }
+    if let Some(start) = start {
+        trace::finish(function, args, status, start);
+    }
+    status
+}
+
+/// Check that none of the pointer arguments a C caller must supply is null,
+/// so that a malformed call fails with `EINVAL` instead of crashing the
+/// process. `errnop` and `h_errnop` needn't be checked: reporting an error
+/// skips writing through a null pointer.
+fn check_non_null(required: &[*const c_void]) -> Result<()> {
+    if required.iter().any(|p| p.is_null()) {
+        Err(Error::invalid_args())
+    } else {
         Ok(())
     }
 }
 
+/// Reject `name` if the service wants only valid hostnames and it isn't one.
+fn check_hostname<T: NameService>(name: &CStr) -> Result<()> {
+    if T::VALIDATE_HOSTNAMES && !is_valid_hostname(name) {
+        Err(Error::with_host(NssStatus::NotFound, ENOENT, HostError::HostNotFound))
+    } else {
+        Ok(())
+    }
+}
+
+/// Store the result of a lookup in a database other than `hosts`. `write`
+/// is called to store a successful result.
+unsafe fn report_lookup_result<E, W>(
+    lookup_result: Result<Option<E>>,
+    errnop: *mut c_int,
+    write: W,
+) -> NssStatus
+where
+    W: FnOnce(&E) -> Result<()>,
+{
+    match lookup_result.and_then(|entry| match entry {
+        None => Err(Error::with_errno(NssStatus::NotFound, ENOENT)),
+        Some(entry) => write(&entry),
+    }) {
+        Err(err) => err.report(errnop),
+        Ok(()) => NssStatus::Success,
+    }
+}
+
+/// Convert the result of a `cursor::next` call into a status for a
+/// `getXXent_r` function with no `h_errnop` parameter.
+unsafe fn report_next_result(next_result: Result<bool>, errnop: *mut c_int) -> NssStatus {
+    match next_result {
+        Err(err) => err.report(errnop),
+        Ok(false) => Error::with_errno(NssStatus::NotFound, ENOENT).report(errnop),
+        Ok(true) => NssStatus::Success,
+    }
+}
+
 /// Store the result of a `gethostbyname2_r()` lookup in the four
 /// out-parameters provided by the caller.
-pub fn write_host_lookup_result(
+///
+/// A successful result with no addresses is reported as `NO_DATA`; see
+/// `NameService::ALLOW_EMPTY_ADDRESS_LIST`. The result is cut down to
+/// `HostLimits::DEFAULT`.
+///
+/// # Safety
+///
+/// `resultp`, `errnop`, and `h_errnop` must be valid for writes, and `buffer`
+/// must point to `buflen` bytes of writable memory, as promised by the caller
+/// of the NSS function.
+pub unsafe fn write_host_lookup_result(
+    lookup_result: Result<Option<HostEntry>>,
+    resultp: *mut hostent,
+    buffer: *mut c_char,
+    buflen: usize,
+    errnop: *mut c_int,
+    h_errnop: *mut c_int,
+) -> NssStatus {
+    report_host_lookup_result(lookup_result, false, &HostLimits::DEFAULT,
+                              resultp, buffer, buflen, errnop, h_errnop)
+}
+
+#[allow(clippy::too_many_arguments)]
+unsafe fn report_host_lookup_result(
     lookup_result: Result<Option<HostEntry>>,
+    allow_empty: bool,
+    limits: &HostLimits,
     resultp: *mut hostent,
     buffer: *mut c_char,
     buflen: usize,
@@ -120,17 +426,20 @@ pub fn write_host_lookup_result(
     h_errnop: *mut c_int,
 ) -> NssStatus {
     match lookup_result {
-        Err(err) => unsafe {
-            err.report_with_host(errnop, h_errnop)
-        },
+        Err(err) => err.report_with_host(errnop, h_errnop),
 
-        Ok(None) => unsafe {
+        Ok(None) => {
             Error::with_errno(NssStatus::NotFound, ENOENT)
                 .report_with_host(errnop, h_errnop)
         }
 
-        Ok(Some(host)) => unsafe {
-            match host.write_to(resultp, buffer, buflen) {
+        Ok(Some(ref host)) if host.addr_list.is_empty() && !allow_empty => {
+            Error::with_host(NssStatus::NotFound, ENOENT, HostError::NoData)
+                .report_with_host(errnop, h_errnop)
+        }
+
+        Ok(Some(host)) => {
+            match host.write_to(limits, resultp, buffer, buflen) {
                 Err(err) => err.report_with_host(errnop, h_errnop),
                 Ok(()) => NssStatus::Success
             }
@@ -138,8 +447,17 @@ pub fn write_host_lookup_result(
     }
 }
 
+/// Implementation of the `extern "C"` function defined by
+/// `nssglue_gethostbyname_r!`.
+///
+/// # Safety
+///
+/// The arguments must satisfy the contract of the C function `gethostbyname_r`:
+/// `name` must be a valid null-terminated string, `result`, `errnop`, and `h_errnop`
+/// must be valid for writes, and `buffer` must point to `buflen` writable
+/// bytes.
 #[inline]
-pub unsafe fn call_gethostbyname_r<T: NameService>(
+pub unsafe fn call_gethostbyname_r<T: NameService + 'static>(
     name: *const c_char,
     result: *mut hostent,
     buffer: *mut c_char,
@@ -147,8 +465,25 @@ pub unsafe fn call_gethostbyname_r<T: NameService>(
     errnop: *mut c_int,
     h_errnop: *mut c_int,
 ) -> NssStatus {
-    let lookup_result = T::gethostbyname_r(CStr::from_ptr(name));
-    write_host_lookup_result(lookup_result, result, buffer, buflen, errnop, h_errnop)
+    call_guarded("gethostbyname_r", format_args!("{}", trace::Name(name)), || {
+        if let Err(err) = check_non_null(&[name as _, result as _, buffer as _]) {
+            return err.report_with_host(errnop, h_errnop);
+        }
+        let name = CStr::from_ptr(name);
+        if let Err(err) = check_hostname::<T>(name) {
+            return err.report_with_host(errnop, h_errnop);
+        }
+        let lookup_result = match T::LOOKUP_TIMEOUT {
+            None => T::gethostbyname_r(name),
+            Some(timeout) => watchdog::copy_name(name).and_then(|name| {
+                watchdog::call("gethostbyname_r", timeout, move || {
+                    Ok(T::gethostbyname_r(&name)?.map(HostEntry::into_owned))
+                })
+            }),
+        };
+        report_host_lookup_result(lookup_result, T::ALLOW_EMPTY_ADDRESS_LIST, &T::LIMITS,
+                                  result, buffer, buflen, errnop, h_errnop)
+    }, |err| err.report_with_host(errnop, h_errnop))
 }
 
 #[macro_export]
@@ -156,13 +491,13 @@ macro_rules! nssglue_gethostbyname_r {
     ($name:ident, $t:ty) => {
         #[no_mangle]
         pub unsafe extern "C" fn $name(
-            name: *const $crate::macros::c_char,
-            result: *mut $crate::macros::hostent,
-            buffer: *mut $crate::macros::c_char,
+            name: *const $crate::ffi::c_char,
+            result: *mut $crate::ffi::hostent,
+            buffer: *mut $crate::ffi::c_char,
             buflen: usize,
-            errnop: *mut $crate::macros::c_int,
-            h_errnop: *mut $crate::macros::c_int,
-        ) -> $crate::macros::NssStatus {
+            errnop: *mut $crate::ffi::c_int,
+            h_errnop: *mut $crate::ffi::c_int,
+        ) -> $crate::ffi::NssStatus {
             $crate::macros::call_gethostbyname_r::<$t>(
                 name,
                 result,
@@ -175,8 +510,17 @@ macro_rules! nssglue_gethostbyname_r {
     }
 }
 
+/// Implementation of the `extern "C"` function defined by
+/// `nssglue_gethostbyname2_r!`.
+///
+/// # Safety
+///
+/// The arguments must satisfy the contract of the C function `gethostbyname2_r`:
+/// `name` must be a valid null-terminated string, `result`, `errnop`, and `h_errnop`
+/// must be valid for writes, and `buffer` must point to `buflen` writable
+/// bytes.
 #[inline]
-pub unsafe fn call_gethostbyname2_r<T: NameService>(
+pub unsafe fn call_gethostbyname2_r<T: NameService + 'static>(
     name: *const c_char,
     af: c_int,
     result: *mut hostent,
@@ -185,15 +529,30 @@ pub unsafe fn call_gethostbyname2_r<T: NameService>(
     errnop: *mut c_int,
     h_errnop: *mut c_int,
 ) -> NssStatus {
-    let lookup_result = T::gethostbyname2_r(
-        CStr::from_ptr(name),
-        match af {
+    call_guarded("gethostbyname2_r", format_args!("{}, {}", trace::Name(name), trace::Family(af)), || {
+        if let Err(err) = check_non_null(&[name as _, result as _, buffer as _]) {
+            return err.report_with_host(errnop, h_errnop);
+        }
+        let af = match af {
             AF_INET => AddressFamily::Ipv4,
             AF_INET6 => AddressFamily::Ipv6,
             _ => return Error::invalid_args().report_with_host(errnop, h_errnop)
-        },
-    );
-    write_host_lookup_result(lookup_result, result, buffer, buflen, errnop, h_errnop)
+        };
+        let name = CStr::from_ptr(name);
+        if let Err(err) = check_hostname::<T>(name) {
+            return err.report_with_host(errnop, h_errnop);
+        }
+        let lookup_result = match T::LOOKUP_TIMEOUT {
+            None => T::gethostbyname2_r(name, af),
+            Some(timeout) => watchdog::copy_name(name).and_then(|name| {
+                watchdog::call("gethostbyname2_r", timeout, move || {
+                    Ok(T::gethostbyname2_r(&name, af)?.map(HostEntry::into_owned))
+                })
+            }),
+        };
+        report_host_lookup_result(lookup_result, T::ALLOW_EMPTY_ADDRESS_LIST, &T::LIMITS,
+                                  result, buffer, buflen, errnop, h_errnop)
+    }, |err| err.report_with_host(errnop, h_errnop))
 }
 
 /// This macro defines a function that implements `gethostbyname2_r` in a way
@@ -221,14 +580,14 @@ macro_rules! nssglue_gethostbyname2_r {
     ($name:ident, $t:ty) => {
         #[no_mangle]
         pub unsafe extern "C" fn $name(
-            name: *const $crate::macros::c_char,
-            af: $crate::macros::c_int,
-            result: *mut $crate::macros::hostent,
-            buffer: *mut $crate::macros::c_char,
+            name: *const $crate::ffi::c_char,
+            af: $crate::ffi::c_int,
+            result: *mut $crate::ffi::hostent,
+            buffer: *mut $crate::ffi::c_char,
             buflen: usize,
-            errnop: *mut $crate::macros::c_int,
-            h_errnop: *mut $crate::macros::c_int,
-        ) -> $crate::macros::NssStatus {
+            errnop: *mut $crate::ffi::c_int,
+            h_errnop: *mut $crate::ffi::c_int,
+        ) -> $crate::ffi::NssStatus {
             $crate::macros::call_gethostbyname2_r::<$t>(
                 name,
                 af,
@@ -242,8 +601,42 @@ macro_rules! nssglue_gethostbyname2_r {
     }
 }
 
+/// Read the `addr` argument of `gethostbyaddr_r`.
+///
+/// The address is in network byte order, so it's read as bytes, never as an
+/// integer. Callers may pass a pointer into a packet, so it may be unaligned.
+unsafe fn read_addr(addr: *const c_void, len: c_int, af: c_int) -> Result<IpAddr> {
+    match af {
+        AF_INET => {
+            if len != shim::INADDRSZ {
+                return Err(Error::invalid_args());
+            }
+            let octets: [u8; 4] = ptr::read_unaligned(addr as *const [u8; 4]);
+            Ok(IpAddr::from(Ipv4Addr::from(octets)))
+        }
+        AF_INET6 => {
+            if len != shim::IN6ADDRSZ {
+                return Err(Error::invalid_args());
+            }
+            let octets: [u8; 16] = ptr::read_unaligned(addr as *const [u8; 16]);
+            Ok(IpAddr::from(Ipv6Addr::from(octets)))
+        }
+        _ => Err(Error::invalid_args())
+    }
+}
+
+/// Implementation of the `extern "C"` function defined by
+/// `nssglue_gethostbyaddr_r!`.
+///
+/// # Safety
+///
+/// The arguments must satisfy the contract of the C function `gethostbyaddr_r`:
+/// `addr` must point to `len` readable bytes, `result`, `errnop`, and `h_errnop`
+/// must be valid for writes, and `buffer` must point to `buflen` writable
+/// bytes.
 #[inline]
-pub unsafe fn call_gethostbyaddr_r<T: NameService>(
+#[allow(clippy::too_many_arguments)]
+pub unsafe fn call_gethostbyaddr_r<T: NameService + 'static>(
     addr: *const c_void,
     len: c_int,
     af: c_int,
@@ -253,25 +646,23 @@ pub unsafe fn call_gethostbyaddr_r<T: NameService>(
     errnop: *mut c_int,
     h_errnop: *mut c_int,
 ) -> NssStatus {
-    let addr: IpAddr = match af {
-        AF_INET => {
-            if len as usize != mem::size_of::<u32>() {
-                return Error::invalid_args().report_with_host(errnop, h_errnop);
-            }
-            let inaddr: in_addr_t = *(addr as *const u32);
-            IpAddr::from(Ipv4Addr::from(inaddr))
+    call_guarded("gethostbyaddr_r", format_args!("{}", trace::Addr(addr, len, af)), || {
+        if let Err(err) = check_non_null(&[addr as _, result as _, buffer as _]) {
+            return err.report_with_host(errnop, h_errnop);
         }
-        AF_INET6 => {
-            if len as usize != mem::size_of::<[u8; 16]>() {
-                return Error::invalid_args().report_with_host(errnop, h_errnop);
-            }
-            let octets: [u8; 16] = *(addr as *const [u8; 16]);
-            IpAddr::from(Ipv6Addr::from(octets))
-        }
-        _ => return Error::invalid_args().report_with_host(errnop, h_errnop)
-    };
-    let lookup_result = T::gethostbyaddr_r(&addr);
-    write_host_lookup_result(lookup_result, result, buffer, buflen, errnop, h_errnop)
+        let addr = match read_addr(addr, len, af) {
+            Err(err) => return err.report_with_host(errnop, h_errnop),
+            Ok(addr) => addr,
+        };
+        let lookup_result = match T::LOOKUP_TIMEOUT {
+            None => T::gethostbyaddr_r(&addr),
+            Some(timeout) => watchdog::call("gethostbyaddr_r", timeout, move || {
+                Ok(T::gethostbyaddr_r(&addr)?.map(HostEntry::into_owned))
+            }),
+        };
+        report_host_lookup_result(lookup_result, T::ALLOW_EMPTY_ADDRESS_LIST, &T::LIMITS,
+                                  result, buffer, buflen, errnop, h_errnop)
+    }, |err| err.report_with_host(errnop, h_errnop))
 }
 
 #[macro_export]
@@ -279,15 +670,15 @@ macro_rules! nssglue_gethostbyaddr_r {
     ($name:ident, $t:ty) => {
         #[no_mangle]
         pub unsafe extern "C" fn $name(
-            addr: *const $crate::macros::c_void,
-            len: $crate::macros::c_int,
-            af: $crate::macros::c_int,
-            result: *mut $crate::macros::hostent,
-            buffer: *mut $crate::macros::c_char,
+            addr: *const $crate::ffi::c_void,
+            len: $crate::ffi::c_int,
+            af: $crate::ffi::c_int,
+            result: *mut $crate::ffi::hostent,
+            buffer: *mut $crate::ffi::c_char,
             buflen: usize,
-            errnop: *mut $crate::macros::c_int,
-            h_errnop: *mut $crate::macros::c_int,
-        ) -> $crate::macros::NssStatus {
+            errnop: *mut $crate::ffi::c_int,
+            h_errnop: *mut $crate::ffi::c_int,
+        ) -> $crate::ffi::NssStatus {
             $crate::macros::call_gethostbyaddr_r::<$t>(
                 addr,
                 len,
@@ -301,3 +692,1398 @@ macro_rules! nssglue_gethostbyaddr_r {
         }
     }
 }
+
+#[test]
+fn test_empty_address_list_is_no_data() {
+    use std::borrow::Cow;
+    use std::ffi::CString;
+
+    let name = CString::new("empty.example").unwrap();
+    let entry = || HostEntry {
+        name: Cow::Borrowed(name.as_c_str()),
+        aliases: vec![],
+        addr_list: HostAddressList::V4(vec![]),
+    };
+
+    let mut result: hostent = unsafe { mem::zeroed() };
+    let mut buffer = [0 as c_char; 256];
+    let mut errno = 0;
+    let mut h_errno = 0;
+    unsafe {
+        let status = write_host_lookup_result(
+            Ok(Some(entry())), &mut result, buffer.as_mut_ptr(), buffer.len(),
+            &mut errno, &mut h_errno);
+        assert_eq!(status, NssStatus::NotFound);
+        assert_eq!(h_errno, HostError::NoData as c_int);
+        assert!(result.h_name.is_null());
+
+        let status = report_host_lookup_result(
+            Ok(Some(entry())), true, &HostLimits::DEFAULT, &mut result, buffer.as_mut_ptr(), buffer.len(),
+            &mut errno, &mut h_errno);
+        assert_eq!(status, NssStatus::Success);
+        assert!((*result.h_addr_list).is_null());
+    }
+}
+
+/// Implementation of the `extern "C"` function defined by
+/// `nssglue_gethostbyname3_r!`.
+///
+/// # Safety
+///
+/// The arguments must satisfy the contract of the NSS function
+/// `gethostbyname3_r`: `name` must be a valid null-terminated string,
+/// `result`, `errnop`, and `h_errnop` must be valid for writes, `buffer` must
+/// point to `buflen` writable bytes, and `ttlp` and `canonp` must each be
+/// null or valid for writes.
+#[inline]
+#[allow(clippy::too_many_arguments)]
+pub unsafe fn call_gethostbyname3_r<T: NameService + 'static>(
+    name: *const c_char,
+    af: c_int,
+    result: *mut hostent,
+    buffer: *mut c_char,
+    buflen: usize,
+    errnop: *mut c_int,
+    h_errnop: *mut c_int,
+    ttlp: *mut i32,
+    canonp: *mut *mut c_char,
+) -> NssStatus {
+    call_guarded("gethostbyname3_r", format_args!("{}, {}", trace::Name(name), trace::Family(af)), || {
+        if let Err(err) = check_non_null(&[name as _, result as _, buffer as _]) {
+            return err.report_with_host(errnop, h_errnop);
+        }
+        let af = match af {
+            AF_INET => AddressFamily::Ipv4,
+            AF_INET6 => AddressFamily::Ipv6,
+            _ => return Error::invalid_args().report_with_host(errnop, h_errnop)
+        };
+        let name = CStr::from_ptr(name);
+        if let Err(err) = check_hostname::<T>(name) {
+            return err.report_with_host(errnop, h_errnop);
+        }
+        let found = match T::LOOKUP_TIMEOUT {
+            None => T::gethostbyname3_r(name, af),
+            Some(timeout) => watchdog::copy_name(name).and_then(|name| {
+                watchdog::call("gethostbyname3_r", timeout, move || {
+                    Ok(T::gethostbyname3_r(&name, af)?.map(HostEntryWithTtl::into_owned))
+                })
+            }),
+        };
+        let (lookup_result, ttl) = match found {
+            Err(err) => (Err(err), None),
+            Ok(None) => (Ok(None), None),
+            Ok(Some(found)) => (Ok(Some(found.entry)), found.ttl),
+        };
+        let status = report_host_lookup_result(lookup_result, T::ALLOW_EMPTY_ADDRESS_LIST, &T::LIMITS,
+                                               result, buffer, buflen, errnop, h_errnop);
+        if status == NssStatus::Success {
+            write_ttl(ttl, ttlp);
+            if !canonp.is_null() {
+                *canonp = (*result).h_name;
+            }
+        }
+        status
+    }, |err| err.report_with_host(errnop, h_errnop))
+}
+
+/// Store a TTL in the caller's `*ttlp`, if both exist.
+unsafe fn write_ttl(ttl: Option<u32>, ttlp: *mut i32) {
+    if let Some(ttl) = ttl {
+        if !ttlp.is_null() {
+            *ttlp = if ttl > i32::MAX as u32 { i32::MAX } else { ttl as i32 };
+        }
+    }
+}
+
+#[macro_export]
+macro_rules! nssglue_gethostbyname3_r {
+    ($name:ident, $t:ty) => {
+        #[no_mangle]
+        pub unsafe extern "C" fn $name(
+            name: *const $crate::ffi::c_char,
+            af: $crate::ffi::c_int,
+            result: *mut $crate::ffi::hostent,
+            buffer: *mut $crate::ffi::c_char,
+            buflen: usize,
+            errnop: *mut $crate::ffi::c_int,
+            h_errnop: *mut $crate::ffi::c_int,
+            ttlp: *mut i32,
+            canonp: *mut *mut $crate::ffi::c_char,
+        ) -> $crate::ffi::NssStatus {
+            $crate::macros::call_gethostbyname3_r::<$t>(
+                name,
+                af,
+                result,
+                buffer,
+                buflen,
+                errnop,
+                h_errnop,
+                ttlp,
+                canonp
+            )
+        }
+    }
+}
+
+/// Implementation of the `extern "C"` function defined by
+/// `nssglue_gethostbyname4_r!`.
+///
+/// # Safety
+///
+/// The arguments must satisfy the contract of the NSS function
+/// `gethostbyname4_r`: `name` must be a valid null-terminated string, `pat`,
+/// `errnop`, and `h_errnop` must be valid for writes, `buffer` must point to
+/// `buflen` writable bytes, and `ttlp` must be null or valid for writes.
+#[inline]
+pub unsafe fn call_gethostbyname4_r<T: NameService + 'static>(
+    name: *const c_char,
+    pat: *mut *mut gaih_addrtuple,
+    buffer: *mut c_char,
+    buflen: usize,
+    errnop: *mut c_int,
+    h_errnop: *mut c_int,
+    ttlp: *mut i32,
+) -> NssStatus {
+    call_guarded("gethostbyname4_r", format_args!("{}", trace::Name(name)), || {
+        if let Err(err) = check_non_null(&[name as _, pat as _, buffer as _]) {
+            return err.report_with_host(errnop, h_errnop);
+        }
+        let name = CStr::from_ptr(name);
+        if let Err(err) = check_hostname::<T>(name) {
+            return err.report_with_host(errnop, h_errnop);
+        }
+        let found = match T::LOOKUP_TIMEOUT {
+            None => T::gethostbyname4_r(name),
+            Some(timeout) => watchdog::copy_name(name).and_then(|name| {
+                watchdog::call("gethostbyname4_r", timeout, move || {
+                    Ok(T::gethostbyname4_r(&name)?.map(HostAddresses::into_owned))
+                })
+            }),
+        };
+        let lookup_result = found.and_then(|found| match found {
+            None => Err(Error::with_errno(NssStatus::NotFound, ENOENT)),
+            Some(ref found) if found.addrs.is_empty() => {
+                Err(Error::with_host(NssStatus::NotFound, ENOENT, HostError::NoData))
+            }
+            Some(found) => found.write_to(&T::LIMITS, pat, buffer, buflen).map(|()| found.ttl),
+        });
+        match lookup_result {
+            Err(err) => err.report_with_host(errnop, h_errnop),
+            Ok(ttl) => {
+                write_ttl(ttl, ttlp);
+                NssStatus::Success
+            }
+        }
+    }, |err| err.report_with_host(errnop, h_errnop))
+}
+
+#[macro_export]
+macro_rules! nssglue_gethostbyname4_r {
+    ($name:ident, $t:ty) => {
+        #[no_mangle]
+        pub unsafe extern "C" fn $name(
+            name: *const $crate::ffi::c_char,
+            pat: *mut *mut $crate::ffi::gaih_addrtuple,
+            buffer: *mut $crate::ffi::c_char,
+            buflen: usize,
+            errnop: *mut $crate::ffi::c_int,
+            h_errnop: *mut $crate::ffi::c_int,
+            ttlp: *mut i32,
+        ) -> $crate::ffi::NssStatus {
+            $crate::macros::call_gethostbyname4_r::<$t>(
+                name,
+                pat,
+                buffer,
+                buflen,
+                errnop,
+                h_errnop,
+                ttlp
+            )
+        }
+    }
+}
+
+/// Implementation of the `extern "C"` function defined by
+/// `nssglue_gethostbyaddr2_r!`.
+///
+/// # Safety
+///
+/// The arguments must satisfy the contract of the NSS function
+/// `gethostbyaddr2_r`: `addr` must point to `len` readable bytes, `result`,
+/// `errnop`, and `h_errnop` must be valid for writes, `buffer` must point to
+/// `buflen` writable bytes, and `ttlp` must be null or valid for writes.
+#[inline]
+#[allow(clippy::too_many_arguments)]
+pub unsafe fn call_gethostbyaddr2_r<T: NameService + 'static>(
+    addr: *const c_void,
+    len: c_int,
+    af: c_int,
+    result: *mut hostent,
+    buffer: *mut c_char,
+    buflen: usize,
+    errnop: *mut c_int,
+    h_errnop: *mut c_int,
+    ttlp: *mut i32,
+) -> NssStatus {
+    call_guarded("gethostbyaddr2_r", format_args!("{}", trace::Addr(addr, len, af)), || {
+        if let Err(err) = check_non_null(&[addr as _, result as _, buffer as _]) {
+            return err.report_with_host(errnop, h_errnop);
+        }
+        let addr = match read_addr(addr, len, af) {
+            Err(err) => return err.report_with_host(errnop, h_errnop),
+            Ok(addr) => addr,
+        };
+        let found = match T::LOOKUP_TIMEOUT {
+            None => T::gethostbyaddr2_r(&addr),
+            Some(timeout) => watchdog::call("gethostbyaddr2_r", timeout, move || {
+                Ok(T::gethostbyaddr2_r(&addr)?.map(HostEntryWithTtl::into_owned))
+            }),
+        };
+        let (lookup_result, ttl) = match found {
+            Err(err) => (Err(err), None),
+            Ok(None) => (Ok(None), None),
+            Ok(Some(found)) => (Ok(Some(found.entry)), found.ttl),
+        };
+        let status = report_host_lookup_result(lookup_result, T::ALLOW_EMPTY_ADDRESS_LIST, &T::LIMITS,
+                                               result, buffer, buflen, errnop, h_errnop);
+        if status == NssStatus::Success {
+            write_ttl(ttl, ttlp);
+        }
+        status
+    }, |err| err.report_with_host(errnop, h_errnop))
+}
+
+#[macro_export]
+macro_rules! nssglue_gethostbyaddr2_r {
+    ($name:ident, $t:ty) => {
+        #[no_mangle]
+        pub unsafe extern "C" fn $name(
+            addr: *const $crate::ffi::c_void,
+            len: $crate::ffi::c_int,
+            af: $crate::ffi::c_int,
+            result: *mut $crate::ffi::hostent,
+            buffer: *mut $crate::ffi::c_char,
+            buflen: usize,
+            errnop: *mut $crate::ffi::c_int,
+            h_errnop: *mut $crate::ffi::c_int,
+            ttlp: *mut i32,
+        ) -> $crate::ffi::NssStatus {
+            $crate::macros::call_gethostbyaddr2_r::<$t>(
+                addr,
+                len,
+                af,
+                result,
+                buffer,
+                buflen,
+                errnop,
+                h_errnop,
+                ttlp
+            )
+        }
+    }
+}
+
+/// Implementation of the `extern "C"` function defined by
+/// `nssglue_sethostent!`.
+#[inline]
+pub fn call_sethostent<T: NameService>(stayopen: c_int) -> NssStatus {
+    call_guarded("sethostent", format_args!("{}", stayopen), || {
+        match T::sethostent(stayopen != 0) {
+            Err(err) => err.status(),
+            Ok(entries) => {
+                cursor::set(&cursor::HOSTS, entries);
+                NssStatus::Success
+            }
+        }
+    }, |err| err.status())
+}
+
+#[macro_export]
+macro_rules! nssglue_sethostent {
+    ($name:ident, $t:ty) => {
+        #[no_mangle]
+        pub extern "C" fn $name(stayopen: $crate::ffi::c_int) -> $crate::ffi::NssStatus {
+            $crate::macros::call_sethostent::<$t>(stayopen)
+        }
+    }
+}
+
+/// Implementation of the `extern "C"` function defined by
+/// `nssglue_gethostent_r!`.
+///
+/// # Safety
+///
+/// The arguments must satisfy the contract of the NSS function
+/// `gethostent_r`: `result`, `errnop`, and `h_errnop` must be valid for
+/// writes, and `buffer` must point to `buflen` writable bytes.
+#[inline]
+pub unsafe fn call_gethostent_r<T: NameService>(
+    result: *mut hostent,
+    buffer: *mut c_char,
+    buflen: usize,
+    errnop: *mut c_int,
+    h_errnop: *mut c_int,
+) -> NssStatus {
+    call_guarded("gethostent_r", format_args!(""), || {
+        if let Err(err) = check_non_null(&[result as _, buffer as _]) {
+            return err.report_with_host(errnop, h_errnop);
+        }
+        let next_result = cursor::next(
+            &cursor::HOSTS,
+            || T::sethostent(false),
+            |entry| entry.write_to(&T::LIMITS, result, buffer, buflen),
+        );
+        match next_result {
+            Err(err) => err.report_with_host(errnop, h_errnop),
+            Ok(false) => {
+                Error::with_errno(NssStatus::NotFound, ENOENT)
+                    .report_with_host(errnop, h_errnop)
+            }
+            Ok(true) => NssStatus::Success,
+        }
+    }, |err| err.report_with_host(errnop, h_errnop))
+}
+
+#[macro_export]
+macro_rules! nssglue_gethostent_r {
+    ($name:ident, $t:ty) => {
+        #[no_mangle]
+        pub unsafe extern "C" fn $name(
+            result: *mut $crate::ffi::hostent,
+            buffer: *mut $crate::ffi::c_char,
+            buflen: usize,
+            errnop: *mut $crate::ffi::c_int,
+            h_errnop: *mut $crate::ffi::c_int,
+        ) -> $crate::ffi::NssStatus {
+            $crate::macros::call_gethostent_r::<$t>(
+                result,
+                buffer,
+                buflen,
+                errnop,
+                h_errnop
+            )
+        }
+    }
+}
+
+/// Implementation of the `extern "C"` function defined by
+/// `nssglue_endhostent!`.
+#[inline]
+pub fn call_endhostent<T: NameService>() -> NssStatus {
+    call_guarded("endhostent", format_args!(""), || {
+        cursor::end(&cursor::HOSTS);
+        NssStatus::Success
+    }, |err| err.status())
+}
+
+#[macro_export]
+macro_rules! nssglue_endhostent {
+    ($name:ident, $t:ty) => {
+        #[no_mangle]
+        pub extern "C" fn $name() -> $crate::ffi::NssStatus {
+            $crate::macros::call_endhostent::<$t>()
+        }
+    }
+}
+
+/// Implementation of the `extern "C"` function defined by
+/// `nssglue_getpwnam_r!`.
+///
+/// # Safety
+///
+/// The arguments must satisfy the contract of the NSS function `getpwnam_r`:
+/// `name` must be a valid null-terminated string, `result` and `errnop` must
+/// be valid for writes, and `buffer` must point to `buflen` writable bytes.
+#[inline]
+pub unsafe fn call_getpwnam_r<T: PasswdService + 'static>(
+    name: *const c_char,
+    result: *mut passwd,
+    buffer: *mut c_char,
+    buflen: usize,
+    errnop: *mut c_int,
+) -> NssStatus {
+    call_guarded("getpwnam_r", format_args!("{}", trace::Name(name)), || {
+        if let Err(err) = check_non_null(&[name as _, result as _, buffer as _]) {
+            return err.report(errnop);
+        }
+        let name = CStr::from_ptr(name);
+        let lookup_result = match T::LOOKUP_TIMEOUT {
+            None => T::getpwnam_r(name),
+            Some(timeout) => watchdog::copy_name(name).and_then(|name| {
+                watchdog::call("getpwnam_r", timeout, move || Ok(T::getpwnam_r(&name)?.map(PasswdEntry::into_owned)))
+            }),
+        };
+        report_lookup_result(lookup_result, errnop, |entry| entry.write_to(result, buffer, buflen))
+    }, |err| err.report(errnop))
+}
+
+#[macro_export]
+macro_rules! nssglue_getpwnam_r {
+    ($name:ident, $t:ty) => {
+        #[no_mangle]
+        pub unsafe extern "C" fn $name(
+            name: *const $crate::ffi::c_char,
+            result: *mut $crate::ffi::passwd,
+            buffer: *mut $crate::ffi::c_char,
+            buflen: usize,
+            errnop: *mut $crate::ffi::c_int,
+        ) -> $crate::ffi::NssStatus {
+            $crate::macros::call_getpwnam_r::<$t>(name, result, buffer, buflen, errnop)
+        }
+    }
+}
+
+/// Implementation of the `extern "C"` function defined by
+/// `nssglue_getpwuid_r!`.
+///
+/// # Safety
+///
+/// The arguments must satisfy the contract of the NSS function `getpwuid_r`:
+/// `result` and `errnop` must be valid for writes, and `buffer` must point to
+/// `buflen` writable bytes.
+#[inline]
+pub unsafe fn call_getpwuid_r<T: PasswdService + 'static>(
+    uid: uid_t,
+    result: *mut passwd,
+    buffer: *mut c_char,
+    buflen: usize,
+    errnop: *mut c_int,
+) -> NssStatus {
+    call_guarded("getpwuid_r", format_args!("{}", uid), || {
+        if let Err(err) = check_non_null(&[result as _, buffer as _]) {
+            return err.report(errnop);
+        }
+        let lookup_result = match T::LOOKUP_TIMEOUT {
+            None => T::getpwuid_r(uid),
+            Some(timeout) => watchdog::call("getpwuid_r", timeout, move || T::getpwuid_r(uid)),
+        };
+        report_lookup_result(lookup_result, errnop, |entry| entry.write_to(result, buffer, buflen))
+    }, |err| err.report(errnop))
+}
+
+#[macro_export]
+macro_rules! nssglue_getpwuid_r {
+    ($name:ident, $t:ty) => {
+        #[no_mangle]
+        pub unsafe extern "C" fn $name(
+            uid: $crate::ffi::uid_t,
+            result: *mut $crate::ffi::passwd,
+            buffer: *mut $crate::ffi::c_char,
+            buflen: usize,
+            errnop: *mut $crate::ffi::c_int,
+        ) -> $crate::ffi::NssStatus {
+            $crate::macros::call_getpwuid_r::<$t>(uid, result, buffer, buflen, errnop)
+        }
+    }
+}
+
+/// Implementation of the `extern "C"` function defined by
+/// `nssglue_setpwent!`.
+#[inline]
+pub fn call_setpwent<T: PasswdService>() -> NssStatus {
+    call_guarded("setpwent", format_args!(""), || {
+        match T::setpwent() {
+            Err(err) => err.status(),
+            Ok(entries) => {
+                cursor::set(&cursor::PASSWD, entries);
+                NssStatus::Success
+            }
+        }
+    }, |err| err.status())
+}
+
+#[macro_export]
+macro_rules! nssglue_setpwent {
+    ($name:ident, $t:ty) => {
+        #[no_mangle]
+        pub extern "C" fn $name() -> $crate::ffi::NssStatus {
+            $crate::macros::call_setpwent::<$t>()
+        }
+    }
+}
+
+/// Implementation of the `extern "C"` function defined by
+/// `nssglue_getpwent_r!`.
+///
+/// # Safety
+///
+/// The arguments must satisfy the contract of the NSS function `getpwent_r`:
+/// `result` and `errnop` must be valid for writes, and `buffer` must point to
+/// `buflen` writable bytes.
+#[inline]
+pub unsafe fn call_getpwent_r<T: PasswdService>(
+    result: *mut passwd,
+    buffer: *mut c_char,
+    buflen: usize,
+    errnop: *mut c_int,
+) -> NssStatus {
+    call_guarded("getpwent_r", format_args!(""), || {
+        if let Err(err) = check_non_null(&[result as _, buffer as _]) {
+            return err.report(errnop);
+        }
+        let next_result = cursor::next(
+            &cursor::PASSWD,
+            T::setpwent,
+            |entry| entry.write_to(result, buffer, buflen),
+        );
+        report_next_result(next_result, errnop)
+    }, |err| err.report(errnop))
+}
+
+#[macro_export]
+macro_rules! nssglue_getpwent_r {
+    ($name:ident, $t:ty) => {
+        #[no_mangle]
+        pub unsafe extern "C" fn $name(
+            result: *mut $crate::ffi::passwd,
+            buffer: *mut $crate::ffi::c_char,
+            buflen: usize,
+            errnop: *mut $crate::ffi::c_int,
+        ) -> $crate::ffi::NssStatus {
+            $crate::macros::call_getpwent_r::<$t>(result, buffer, buflen, errnop)
+        }
+    }
+}
+
+/// Implementation of the `extern "C"` function defined by
+/// `nssglue_endpwent!`.
+#[inline]
+pub fn call_endpwent<T: PasswdService>() -> NssStatus {
+    call_guarded("endpwent", format_args!(""), || {
+        cursor::end(&cursor::PASSWD);
+        NssStatus::Success
+    }, |err| err.status())
+}
+
+#[macro_export]
+macro_rules! nssglue_endpwent {
+    ($name:ident, $t:ty) => {
+        #[no_mangle]
+        pub extern "C" fn $name() -> $crate::ffi::NssStatus {
+            $crate::macros::call_endpwent::<$t>()
+        }
+    }
+}
+
+/// Implementation of the `extern "C"` function defined by
+/// `nssglue_getgrnam_r!`.
+///
+/// # Safety
+///
+/// The arguments must satisfy the contract of the NSS function `getgrnam_r`:
+/// `name` must be a valid null-terminated string, `result` and `errnop` must
+/// be valid for writes, and `buffer` must point to `buflen` writable bytes.
+#[inline]
+pub unsafe fn call_getgrnam_r<T: GroupService + 'static>(
+    name: *const c_char,
+    result: *mut group,
+    buffer: *mut c_char,
+    buflen: usize,
+    errnop: *mut c_int,
+) -> NssStatus {
+    call_guarded("getgrnam_r", format_args!("{}", trace::Name(name)), || {
+        if let Err(err) = check_non_null(&[name as _, result as _, buffer as _]) {
+            return err.report(errnop);
+        }
+        let name = CStr::from_ptr(name);
+        let lookup_result = match T::LOOKUP_TIMEOUT {
+            None => T::getgrnam_r(name),
+            Some(timeout) => watchdog::copy_name(name).and_then(|name| {
+                watchdog::call("getgrnam_r", timeout, move || Ok(T::getgrnam_r(&name)?.map(GroupEntry::into_owned)))
+            }),
+        };
+        report_lookup_result(lookup_result, errnop, |entry| entry.write_to(result, buffer, buflen))
+    }, |err| err.report(errnop))
+}
+
+#[macro_export]
+macro_rules! nssglue_getgrnam_r {
+    ($name:ident, $t:ty) => {
+        #[no_mangle]
+        pub unsafe extern "C" fn $name(
+            name: *const $crate::ffi::c_char,
+            result: *mut $crate::ffi::group,
+            buffer: *mut $crate::ffi::c_char,
+            buflen: usize,
+            errnop: *mut $crate::ffi::c_int,
+        ) -> $crate::ffi::NssStatus {
+            $crate::macros::call_getgrnam_r::<$t>(name, result, buffer, buflen, errnop)
+        }
+    }
+}
+
+/// Implementation of the `extern "C"` function defined by
+/// `nssglue_getgrgid_r!`.
+///
+/// # Safety
+///
+/// The arguments must satisfy the contract of the NSS function `getgrgid_r`:
+/// `result` and `errnop` must be valid for writes, and `buffer` must point to
+/// `buflen` writable bytes.
+#[inline]
+pub unsafe fn call_getgrgid_r<T: GroupService + 'static>(
+    gid: gid_t,
+    result: *mut group,
+    buffer: *mut c_char,
+    buflen: usize,
+    errnop: *mut c_int,
+) -> NssStatus {
+    call_guarded("getgrgid_r", format_args!("{}", gid), || {
+        if let Err(err) = check_non_null(&[result as _, buffer as _]) {
+            return err.report(errnop);
+        }
+        let lookup_result = match T::LOOKUP_TIMEOUT {
+            None => T::getgrgid_r(gid),
+            Some(timeout) => watchdog::call("getgrgid_r", timeout, move || T::getgrgid_r(gid)),
+        };
+        report_lookup_result(lookup_result, errnop, |entry| entry.write_to(result, buffer, buflen))
+    }, |err| err.report(errnop))
+}
+
+#[macro_export]
+macro_rules! nssglue_getgrgid_r {
+    ($name:ident, $t:ty) => {
+        #[no_mangle]
+        pub unsafe extern "C" fn $name(
+            gid: $crate::ffi::gid_t,
+            result: *mut $crate::ffi::group,
+            buffer: *mut $crate::ffi::c_char,
+            buflen: usize,
+            errnop: *mut $crate::ffi::c_int,
+        ) -> $crate::ffi::NssStatus {
+            $crate::macros::call_getgrgid_r::<$t>(gid, result, buffer, buflen, errnop)
+        }
+    }
+}
+
+/// Implementation of the `extern "C"` function defined by
+/// `nssglue_setgrent!`.
+#[inline]
+pub fn call_setgrent<T: GroupService>() -> NssStatus {
+    call_guarded("setgrent", format_args!(""), || {
+        match T::setgrent() {
+            Err(err) => err.status(),
+            Ok(entries) => {
+                cursor::set(&cursor::GROUP, entries);
+                NssStatus::Success
+            }
+        }
+    }, |err| err.status())
+}
+
+#[macro_export]
+macro_rules! nssglue_setgrent {
+    ($name:ident, $t:ty) => {
+        #[no_mangle]
+        pub extern "C" fn $name() -> $crate::ffi::NssStatus {
+            $crate::macros::call_setgrent::<$t>()
+        }
+    }
+}
+
+/// Implementation of the `extern "C"` function defined by
+/// `nssglue_getgrent_r!`.
+///
+/// # Safety
+///
+/// The arguments must satisfy the contract of the NSS function `getgrent_r`:
+/// `result` and `errnop` must be valid for writes, and `buffer` must point to
+/// `buflen` writable bytes.
+#[inline]
+pub unsafe fn call_getgrent_r<T: GroupService>(
+    result: *mut group,
+    buffer: *mut c_char,
+    buflen: usize,
+    errnop: *mut c_int,
+) -> NssStatus {
+    call_guarded("getgrent_r", format_args!(""), || {
+        if let Err(err) = check_non_null(&[result as _, buffer as _]) {
+            return err.report(errnop);
+        }
+        let next_result = cursor::next(
+            &cursor::GROUP,
+            T::setgrent,
+            |entry| entry.write_to(result, buffer, buflen),
+        );
+        report_next_result(next_result, errnop)
+    }, |err| err.report(errnop))
+}
+
+#[macro_export]
+macro_rules! nssglue_getgrent_r {
+    ($name:ident, $t:ty) => {
+        #[no_mangle]
+        pub unsafe extern "C" fn $name(
+            result: *mut $crate::ffi::group,
+            buffer: *mut $crate::ffi::c_char,
+            buflen: usize,
+            errnop: *mut $crate::ffi::c_int,
+        ) -> $crate::ffi::NssStatus {
+            $crate::macros::call_getgrent_r::<$t>(result, buffer, buflen, errnop)
+        }
+    }
+}
+
+/// Implementation of the `extern "C"` function defined by
+/// `nssglue_endgrent!`.
+#[inline]
+pub fn call_endgrent<T: GroupService>() -> NssStatus {
+    call_guarded("endgrent", format_args!(""), || {
+        cursor::end(&cursor::GROUP);
+        NssStatus::Success
+    }, |err| err.status())
+}
+
+#[macro_export]
+macro_rules! nssglue_endgrent {
+    ($name:ident, $t:ty) => {
+        #[no_mangle]
+        pub extern "C" fn $name() -> $crate::ffi::NssStatus {
+            $crate::macros::call_endgrent::<$t>()
+        }
+    }
+}
+
+/// Add `gids` to the caller's array of supplementary groups, as
+/// `initgroups_dyn` must: `*start` entries of `*groupsp` are in use, out of
+/// `*size`. The array is grown with `realloc` as needed, since the caller
+/// frees it, but never past `limit` entries if `limit` is positive. `group`
+/// and groups already in the array are skipped.
+unsafe fn append_groups(
+    gids: &[gid_t],
+    group: gid_t,
+    start: &mut c_long,
+    size: &mut c_long,
+    groupsp: &mut *mut gid_t,
+    limit: c_long,
+) -> Result<()> {
+    for &gid in gids {
+        let used = std::slice::from_raw_parts(*groupsp, *start as usize);
+        if gid == group || used.contains(&gid) {
+            continue;
+        }
+        if *start == *size {
+            if limit > 0 && *size >= limit {
+                // glibc takes this as "full", not as an error.
+                break;
+            }
+            let mut new_size = (*size).max(1).saturating_mul(2);
+            if limit > 0 {
+                new_size = new_size.min(limit);
+            }
+            let bytes = (new_size as usize).checked_mul(std::mem::size_of::<gid_t>())
+                .ok_or_else(Error::out_of_memory)?;
+            let grown = libc::realloc(*groupsp as *mut c_void, bytes) as *mut gid_t;
+            if grown.is_null() {
+                return Err(Error::out_of_memory());
+            }
+            *groupsp = grown;
+            *size = new_size;
+        }
+        *(*groupsp).add(*start as usize) = gid;
+        *start += 1;
+    }
+    Ok(())
+}
+
+/// Implementation of the `extern "C"` function defined by
+/// `nssglue_initgroups_dyn!`.
+///
+/// # Safety
+///
+/// The arguments must satisfy the contract of the NSS function
+/// `initgroups_dyn`: `user` must be a valid null-terminated string, `start`,
+/// `size`, and `groupsp` must be valid for reads and writes, and `*groupsp`
+/// must be a `malloc`ed array of `*size` groups, the first `*start` of which
+/// are in use.
+#[inline]
+#[allow(clippy::too_many_arguments)]
+pub unsafe fn call_initgroups_dyn<T: GroupService + 'static>(
+    user: *const c_char,
+    group: gid_t,
+    start: *mut c_long,
+    size: *mut c_long,
+    groupsp: *mut *mut gid_t,
+    limit: c_long,
+    errnop: *mut c_int,
+) -> NssStatus {
+    call_guarded("initgroups_dyn", format_args!("{}, {}", trace::Name(user), group), || {
+        if let Err(err) = check_non_null(&[user as _, start as _, size as _, groupsp as _]) {
+            return err.report(errnop);
+        }
+        let user = CStr::from_ptr(user);
+        let lookup_result = match T::LOOKUP_TIMEOUT {
+            None => T::initgroups_dyn(user, group),
+            Some(timeout) => watchdog::copy_name(user).and_then(|user| {
+                watchdog::call("initgroups_dyn", timeout, move || T::initgroups_dyn(&user, group))
+            }),
+        };
+        report_lookup_result(lookup_result, errnop, |gids| {
+            append_groups(gids, group, &mut *start, &mut *size, &mut *groupsp, limit)
+        })
+    }, |err| err.report(errnop))
+}
+
+#[macro_export]
+macro_rules! nssglue_initgroups_dyn {
+    ($name:ident, $t:ty) => {
+        #[no_mangle]
+        pub unsafe extern "C" fn $name(
+            user: *const $crate::ffi::c_char,
+            group: $crate::ffi::gid_t,
+            start: *mut $crate::ffi::c_long,
+            size: *mut $crate::ffi::c_long,
+            groupsp: *mut *mut $crate::ffi::gid_t,
+            limit: $crate::ffi::c_long,
+            errnop: *mut $crate::ffi::c_int,
+        ) -> $crate::ffi::NssStatus {
+            $crate::macros::call_initgroups_dyn::<$t>(user, group, start, size, groupsp, limit, errnop)
+        }
+    }
+}
+
+/// Implementation of the `extern "C"` function defined by
+/// `nssglue_getspnam_r!`.
+///
+/// # Safety
+///
+/// The arguments must satisfy the contract of the NSS function `getspnam_r`:
+/// `name` must be a valid null-terminated string, `result` and `errnop` must
+/// be valid for writes, and `buffer` must point to `buflen` writable bytes.
+#[inline]
+pub unsafe fn call_getspnam_r<T: ShadowService + 'static>(
+    name: *const c_char,
+    result: *mut spwd,
+    buffer: *mut c_char,
+    buflen: usize,
+    errnop: *mut c_int,
+) -> NssStatus {
+    call_guarded("getspnam_r", format_args!("{}", trace::Name(name)), || {
+        if let Err(err) = check_non_null(&[name as _, result as _, buffer as _]) {
+            return err.report(errnop);
+        }
+        let name = CStr::from_ptr(name);
+        let lookup_result = match T::LOOKUP_TIMEOUT {
+            None => T::getspnam_r(name),
+            Some(timeout) => watchdog::copy_name(name).and_then(|name| {
+                watchdog::call("getspnam_r", timeout, move || Ok(T::getspnam_r(&name)?.map(ShadowEntry::into_owned)))
+            }),
+        };
+        report_lookup_result(lookup_result, errnop, |entry| entry.write_to(result, buffer, buflen))
+    }, |err| err.report(errnop))
+}
+
+#[macro_export]
+macro_rules! nssglue_getspnam_r {
+    ($name:ident, $t:ty) => {
+        #[no_mangle]
+        pub unsafe extern "C" fn $name(
+            name: *const $crate::ffi::c_char,
+            result: *mut $crate::ffi::spwd,
+            buffer: *mut $crate::ffi::c_char,
+            buflen: usize,
+            errnop: *mut $crate::ffi::c_int,
+        ) -> $crate::ffi::NssStatus {
+            $crate::macros::call_getspnam_r::<$t>(name, result, buffer, buflen, errnop)
+        }
+    }
+}
+
+/// Implementation of the `extern "C"` function defined by
+/// `nssglue_setspent!`.
+#[inline]
+pub fn call_setspent<T: ShadowService>() -> NssStatus {
+    call_guarded("setspent", format_args!(""), || {
+        match T::setspent() {
+            Err(err) => err.status(),
+            Ok(entries) => {
+                cursor::set(&cursor::SHADOW, entries);
+                NssStatus::Success
+            }
+        }
+    }, |err| err.status())
+}
+
+#[macro_export]
+macro_rules! nssglue_setspent {
+    ($name:ident, $t:ty) => {
+        #[no_mangle]
+        pub extern "C" fn $name() -> $crate::ffi::NssStatus {
+            $crate::macros::call_setspent::<$t>()
+        }
+    }
+}
+
+/// Implementation of the `extern "C"` function defined by
+/// `nssglue_getspent_r!`.
+///
+/// # Safety
+///
+/// The arguments must satisfy the contract of the NSS function `getspent_r`:
+/// `result` and `errnop` must be valid for writes, and `buffer` must point to
+/// `buflen` writable bytes.
+#[inline]
+pub unsafe fn call_getspent_r<T: ShadowService>(
+    result: *mut spwd,
+    buffer: *mut c_char,
+    buflen: usize,
+    errnop: *mut c_int,
+) -> NssStatus {
+    call_guarded("getspent_r", format_args!(""), || {
+        if let Err(err) = check_non_null(&[result as _, buffer as _]) {
+            return err.report(errnop);
+        }
+        let next_result = cursor::next(
+            &cursor::SHADOW,
+            T::setspent,
+            |entry| entry.write_to(result, buffer, buflen),
+        );
+        report_next_result(next_result, errnop)
+    }, |err| err.report(errnop))
+}
+
+#[macro_export]
+macro_rules! nssglue_getspent_r {
+    ($name:ident, $t:ty) => {
+        #[no_mangle]
+        pub unsafe extern "C" fn $name(
+            result: *mut $crate::ffi::spwd,
+            buffer: *mut $crate::ffi::c_char,
+            buflen: usize,
+            errnop: *mut $crate::ffi::c_int,
+        ) -> $crate::ffi::NssStatus {
+            $crate::macros::call_getspent_r::<$t>(result, buffer, buflen, errnop)
+        }
+    }
+}
+
+/// Implementation of the `extern "C"` function defined by
+/// `nssglue_endspent!`.
+#[inline]
+pub fn call_endspent<T: ShadowService>() -> NssStatus {
+    call_guarded("endspent", format_args!(""), || {
+        cursor::end(&cursor::SHADOW);
+        NssStatus::Success
+    }, |err| err.status())
+}
+
+#[macro_export]
+macro_rules! nssglue_endspent {
+    ($name:ident, $t:ty) => {
+        #[no_mangle]
+        pub extern "C" fn $name() -> $crate::ffi::NssStatus {
+            $crate::macros::call_endspent::<$t>()
+        }
+    }
+}
+
+#[test]
+fn test_gethostbyname4_r_default() {
+    use std::borrow::Cow;
+    use std::ffi::CString;
+
+    struct Dual;
+    impl NameService for Dual {
+        fn gethostbyname2_r(name: &CStr, af: AddressFamily) -> Result<Option<HostEntry<'_>>> {
+            Ok(Some(HostEntry {
+                name: Cow::Borrowed(name),
+                aliases: vec![],
+                addr_list: match af {
+                    AddressFamily::Ipv4 => HostAddressList::V4(vec![Ipv4Addr::new(10, 0, 0, 1)]),
+                    AddressFamily::Ipv6 => HostAddressList::V6(vec![Ipv6Addr::LOCALHOST]),
+                },
+            }))
+        }
+
+        fn gethostbyaddr_r(_addr: &IpAddr) -> Result<Option<HostEntry<'_>>> {
+            Ok(None)
+        }
+    }
+
+    let name = CString::new("dual.example").unwrap();
+    let mut pat: *mut gaih_addrtuple = ptr::null_mut();
+    let mut buffer = [0 as c_char; 256];
+    let (mut errno, mut h_errno, mut ttl) = (0, 0, -1);
+    unsafe {
+        let status = call_gethostbyname4_r::<Dual>(
+            name.as_ptr(), &mut pat, buffer.as_mut_ptr(), buffer.len(),
+            &mut errno, &mut h_errno, &mut ttl);
+        assert_eq!(status, NssStatus::Success);
+        assert_eq!(ttl, -1);
+
+        let first = &*pat;
+        assert_eq!(CStr::from_ptr(first.name), name.as_c_str());
+        assert_eq!(first.family, AF_INET6);
+        assert_eq!(first.addr[3], u32::from_ne_bytes([0, 0, 0, 1]));
+
+        let second = &*first.next;
+        assert!(second.name.is_null() && second.next.is_null());
+        assert_eq!(second.family, AF_INET);
+        assert_eq!(second.addr[0], u32::from_ne_bytes([10, 0, 0, 1]));
+    }
+}
+
+#[test]
+fn test_panic_is_reported() {
+    struct Panicky;
+    impl PasswdService for Panicky {
+        fn getpwnam_r(_name: &CStr) -> Result<Option<PasswdEntry<'_>>> {
+            panic!("directory unreachable");
+        }
+
+        fn getpwuid_r(_uid: uid_t) -> Result<Option<PasswdEntry<'static>>> {
+            Ok(None)
+        }
+    }
+
+    let mut result: passwd = unsafe { mem::zeroed() };
+    let mut buffer = [0 as c_char; 64];
+    let mut errno = 0;
+    let status = unsafe {
+        call_getpwnam_r::<Panicky>(b"root\0".as_ptr() as *const c_char, &mut result,
+                                   buffer.as_mut_ptr(), buffer.len(), &mut errno)
+    };
+    assert_eq!((status, errno), (NssStatus::Unavailable, libc::EIO));
+}
+
+#[test]
+fn test_null_arguments_are_rejected() {
+    struct Unreachable;
+    impl PasswdService for Unreachable {
+        fn getpwnam_r(_name: &CStr) -> Result<Option<PasswdEntry<'_>>> {
+            panic!("called with invalid arguments");
+        }
+
+        fn getpwuid_r(_uid: uid_t) -> Result<Option<PasswdEntry<'static>>> {
+            panic!("called with invalid arguments");
+        }
+    }
+
+    let mut result: passwd = unsafe { mem::zeroed() };
+    let mut buffer = [0 as c_char; 64];
+    let mut errno = 0;
+    unsafe {
+        let status = call_getpwnam_r::<Unreachable>(ptr::null(), &mut result,
+                                                    buffer.as_mut_ptr(), buffer.len(), &mut errno);
+        assert_eq!((status, errno), (NssStatus::Unavailable, libc::EINVAL));
+
+        // With nowhere to store errno, the status alone must do.
+        let status = call_getpwuid_r::<Unreachable>(0, &mut result, ptr::null_mut(), 64,
+                                                    ptr::null_mut());
+        assert_eq!(status, NssStatus::Unavailable);
+    }
+}
+
+#[test]
+fn test_nested_call_is_refused() {
+    use crate::reentry::in_lookup;
+
+    struct Recursive;
+    impl PasswdService for Recursive {
+        fn getpwnam_r(name: &CStr) -> Result<Option<PasswdEntry<'_>>> {
+            assert!(in_lookup());
+            let mut result: passwd = unsafe { mem::zeroed() };
+            let mut buffer = [0 as c_char; 64];
+            let mut errno = 0;
+            let status = unsafe {
+                call_getpwnam_r::<Recursive>(name.as_ptr(), &mut result,
+                                             buffer.as_mut_ptr(), buffer.len(), &mut errno)
+            };
+            assert_eq!((status, errno), (NssStatus::Unavailable, libc::EDEADLK));
+            Ok(None)
+        }
+
+        fn getpwuid_r(_uid: uid_t) -> Result<Option<PasswdEntry<'static>>> {
+            Ok(None)
+        }
+    }
+
+    let mut result: passwd = unsafe { mem::zeroed() };
+    let mut buffer = [0 as c_char; 64];
+    let mut errno = 0;
+    let status = unsafe {
+        call_getpwnam_r::<Recursive>(b"root\0".as_ptr() as *const c_char, &mut result,
+                                     buffer.as_mut_ptr(), buffer.len(), &mut errno)
+    };
+    assert_eq!((status, errno), (NssStatus::NotFound, ENOENT));
+    assert!(!in_lookup());
+}
+
+#[test]
+#[cfg_attr(miri, ignore = "calls dladdr and dlopen, and leaks the timed-out thread")]
+fn test_lookup_timeout() {
+    use std::borrow::Cow;
+    use std::time::{Duration, Instant};
+
+    fn c(bytes: &'static [u8]) -> &'static CStr {
+        CStr::from_bytes_with_nul(bytes).unwrap()
+    }
+
+    struct Slow;
+    impl PasswdService for Slow {
+        const LOOKUP_TIMEOUT: Option<Duration> = Some(Duration::from_millis(50));
+
+        fn getpwnam_r(name: &CStr) -> Result<Option<PasswdEntry<'_>>> {
+            if name.to_bytes() == b"slow" {
+                std::thread::sleep(Duration::from_secs(5));
+            }
+            Ok(Some(PasswdEntry {
+                name: Cow::Borrowed(name),
+                passwd: Cow::Borrowed(c(b"x\0")),
+                uid: 1000,
+                gid: 1000,
+                gecos: Cow::Borrowed(c(b"\0")),
+                dir: Cow::Borrowed(c(b"/\0")),
+                shell: Cow::Borrowed(c(b"/bin/sh\0")),
+            }))
+        }
+
+        fn getpwuid_r(_uid: uid_t) -> Result<Option<PasswdEntry<'static>>> {
+            Ok(None)
+        }
+    }
+
+    let mut result: passwd = unsafe { mem::zeroed() };
+    let mut buffer = [0 as c_char; 64];
+    let mut errno = 0;
+    unsafe {
+        let status = call_getpwnam_r::<Slow>(c(b"fast\0").as_ptr(), &mut result,
+                                             buffer.as_mut_ptr(), buffer.len(), &mut errno);
+        assert_eq!(status, NssStatus::Success);
+        assert_eq!(CStr::from_ptr(result.pw_name), c(b"fast\0"));
+
+        let start = Instant::now();
+        let status = call_getpwnam_r::<Slow>(c(b"slow\0").as_ptr(), &mut result,
+                                             buffer.as_mut_ptr(), buffer.len(), &mut errno);
+        assert_eq!((status, errno), (NssStatus::TryAgain, libc::EAGAIN));
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+}
+
+#[test]
+fn test_host_limits() {
+    use std::borrow::Cow;
+    use std::ffi::CString;
+
+    let name = CString::new("many.example").unwrap();
+    let long = CString::new("x".repeat(2000)).unwrap();
+    let entry = HostEntry {
+        name: Cow::Borrowed(name.as_c_str()),
+        aliases: vec![Cow::Borrowed(long.as_c_str()), Cow::Borrowed(name.as_c_str())],
+        addr_list: HostAddressList::V4((0..100_000).map(Ipv4Addr::from).collect()),
+    };
+
+    let mut result: hostent = unsafe { mem::zeroed() };
+    let mut buffer = [0 as c_char; 1024];
+    let limits = HostLimits { max_addresses: 3, ..HostLimits::DEFAULT };
+    entry.write_to(&limits, &mut result, buffer.as_mut_ptr(), buffer.len()).unwrap();
+    unsafe {
+        assert_eq!(CStr::from_ptr(*result.h_aliases), name.as_c_str());
+        assert!((*result.h_aliases.add(1)).is_null());
+        assert!(!(*result.h_addr_list.add(2)).is_null());
+        assert!((*result.h_addr_list.add(3)).is_null());
+    }
+
+    let entry = HostEntry { name: Cow::Borrowed(long.as_c_str()), ..entry };
+    let err = entry.write_to(&limits, &mut result, buffer.as_mut_ptr(), buffer.len()).unwrap_err();
+    assert_eq!(err.status(), NssStatus::NotFound);
+}
+
+#[test]
+fn test_address_byte_order() {
+    use std::borrow::Cow;
+
+    // What 192.0.2.1 looks like when read as a native integer. Network byte
+    // order means the same bytes on every target, so a different integer.
+    #[cfg(target_endian = "little")]
+    const TEST_NET_1: in_addr_t = 0x0102_00c0;
+    #[cfg(target_endian = "big")]
+    const TEST_NET_1: in_addr_t = 0xc000_0201;
+
+    // Echo the address back, as a record for itself.
+    struct Echo;
+    impl NameService for Echo {
+        fn gethostbyname2_r(_name: &CStr, _af: AddressFamily) -> Result<Option<HostEntry<'_>>> {
+            Ok(None)
+        }
+
+        fn gethostbyaddr_r(addr: &IpAddr) -> Result<Option<HostEntry<'_>>> {
+            Ok(Some(HostEntry {
+                name: Cow::Borrowed(CStr::from_bytes_with_nul(b"echo\0").unwrap()),
+                aliases: vec![],
+                addr_list: match *addr {
+                    IpAddr::V4(ip) => HostAddressList::V4(vec![ip]),
+                    IpAddr::V6(ip) => HostAddressList::V6(vec![ip]),
+                },
+            }))
+        }
+    }
+
+    let ipv4 = Ipv4Addr::new(192, 0, 2, 1);
+    assert_eq!(shim::in_addr(ipv4), TEST_NET_1);
+
+    // Pass each address at an odd offset, as if it were in a packet.
+    let mut packet = [0_u8; 17];
+    packet[1..5].copy_from_slice(&ipv4.octets());
+    let ipv6: Ipv6Addr = "2001:db8::102:304".parse().unwrap();
+    let mut result: hostent = unsafe { mem::zeroed() };
+    let mut buffer = [0 as c_char; 256];
+    let (mut errno, mut h_errno) = (0, 0);
+    unsafe {
+        let status = call_gethostbyaddr_r::<Echo>(
+            packet[1..].as_ptr() as *const c_void, 4, AF_INET, &mut result,
+            buffer.as_mut_ptr(), buffer.len(), &mut errno, &mut h_errno);
+        assert_eq!(status, NssStatus::Success);
+        assert_eq!(*(*result.h_addr_list as *const [u8; 4]), [192, 0, 2, 1]);
+
+        packet[1..].copy_from_slice(&ipv6.octets());
+        let status = call_gethostbyaddr_r::<Echo>(
+            packet[1..].as_ptr() as *const c_void, 16, AF_INET6, &mut result,
+            buffer.as_mut_ptr(), buffer.len(), &mut errno, &mut h_errno);
+        assert_eq!(status, NssStatus::Success);
+        assert_eq!(*(*result.h_addr_list as *const [u8; 16]), ipv6.octets());
+
+        // The length must match the family exactly.
+        let status = call_gethostbyaddr_r::<Echo>(
+            packet.as_ptr() as *const c_void, 16, AF_INET, &mut result,
+            buffer.as_mut_ptr(), buffer.len(), &mut errno, &mut h_errno);
+        assert_eq!(status, NssStatus::Unavailable);
+    }
+}
+
+#[test]
+fn test_invalid_hostname_is_not_dispatched() {
+    struct Strict;
+    impl NameService for Strict {
+        const VALIDATE_HOSTNAMES: bool = true;
+
+        fn gethostbyname2_r(_name: &CStr, _af: AddressFamily) -> Result<Option<HostEntry<'_>>> {
+            panic!("called with an invalid name");
+        }
+
+        fn gethostbyaddr_r(_addr: &IpAddr) -> Result<Option<HostEntry<'_>>> {
+            Ok(None)
+        }
+    }
+
+    let mut result: hostent = unsafe { mem::zeroed() };
+    let mut buffer = [0 as c_char; 64];
+    let (mut errno, mut h_errno) = (0, 0);
+    let status = unsafe {
+        call_gethostbyname_r::<Strict>(b"../../etc/passwd\0".as_ptr() as *const c_char,
+                                       &mut result, buffer.as_mut_ptr(), buffer.len(),
+                                       &mut errno, &mut h_errno)
+    };
+    assert_eq!((status, h_errno), (NssStatus::NotFound, HostError::HostNotFound as c_int));
+}
+
+#[test]
+fn test_errno_is_restored_on_success() {
+    use std::borrow::Cow;
+
+    struct Clobbering;
+    impl PasswdService for Clobbering {
+        fn getpwnam_r(name: &CStr) -> Result<Option<PasswdEntry<'_>>> {
+            // Say, a connection attempt that failed before one that worked.
+            let _ = std::net::TcpStream::connect("127.0.0.1:1");
+            Ok(Some(PasswdEntry {
+                name: Cow::Borrowed(name),
+                passwd: Cow::Borrowed(name),
+                uid: 0,
+                gid: 0,
+                gecos: Cow::Borrowed(name),
+                dir: Cow::Borrowed(name),
+                shell: Cow::Borrowed(name),
+            }))
+        }
+
+        fn getpwuid_r(_uid: uid_t) -> Result<Option<PasswdEntry<'static>>> {
+            Ok(None)
+        }
+    }
+
+    let mut result: passwd = unsafe { mem::zeroed() };
+    let mut buffer = [0 as c_char; 64];
+    let mut errno = 0;
+    unsafe {
+        *libc::__errno_location() = libc::EINTR;
+        let status = call_getpwnam_r::<Clobbering>(b"root\0".as_ptr() as *const c_char,
+                                                   &mut result, buffer.as_mut_ptr(),
+                                                   buffer.len(), &mut errno);
+        assert_eq!(status, NssStatus::Success);
+        assert_eq!(*libc::__errno_location(), libc::EINTR);
+    }
+}
+
+#[test]
+fn test_padding_is_zeroed() {
+    use std::borrow::Cow;
+    use std::mem::MaybeUninit;
+
+    let name = CStr::from_bytes_with_nul(b"wheel\0").unwrap();
+    let entry = GroupEntry {
+        name: Cow::Borrowed(name),
+        passwd: Cow::Borrowed(name),
+        gid: 10,
+        members: vec![],
+    };
+    let mut result = MaybeUninit::<group>::uninit();
+    let mut buffer = [0xa5_u8 as c_char; 64];
+    unsafe {
+        ptr::write_bytes(result.as_mut_ptr(), 0xa5, 1);
+        entry.write_to(result.as_mut_ptr(), buffer.as_mut_ptr(), buffer.len()).unwrap();
+        let bytes = &*(result.as_ptr() as *const [u8; mem::size_of::<group>()]);
+        let after_gid = mem::offset_of!(group, gr_gid) + mem::size_of::<gid_t>();
+        assert!(bytes[after_gid..mem::offset_of!(group, gr_mem)].iter().all(|&b| b == 0));
+    }
+}
+
+#[test]
+fn test_initgroups_dyn_by_enumeration() {
+    use crate::interfaces::Entries;
+    use std::borrow::Cow;
+
+    // Without its own `initgroups_dyn`, a service's groups are enumerated.
+    struct Groups;
+    impl GroupService for Groups {
+        fn getgrnam_r(_name: &CStr) -> Result<Option<GroupEntry<'_>>> {
+            Ok(None)
+        }
+
+        fn getgrgid_r(_gid: gid_t) -> Result<Option<GroupEntry<'static>>> {
+            Ok(None)
+        }
+
+        fn setgrent() -> Result<Entries<GroupEntry<'static>>> {
+            let alice = CStr::from_bytes_with_nul(b"alice\0").unwrap();
+            let groups = (0..5).map(move |gid| Ok(GroupEntry {
+                name: Cow::Borrowed(alice),
+                passwd: Cow::Borrowed(alice),
+                gid,
+                members: if gid == 3 { vec![] } else { vec![Cow::Borrowed(alice)] },
+            }));
+            Ok(Box::new(groups.chain(iter::once(Ok(GroupEntry {
+                name: Cow::Borrowed(alice),
+                passwd: Cow::Borrowed(alice),
+                gid: 1,
+                members: vec![Cow::Borrowed(alice)],
+            })))))
+        }
+    }
+
+    // The array grows from one entry, leaving out the primary group and
+    // the repeat.
+    assert_eq!(crate::testing::getgrouplist::<Groups>("alice", 2).unwrap(), Some(vec![2, 0, 1, 4]));
+    assert_eq!(crate::testing::getgrouplist::<Groups>("bob", 2).unwrap(), None);
+
+    // With a limit, the array stops growing.
+    let user = b"alice\0".as_ptr() as *const c_char;
+    let (mut start, mut size, mut errno) = (0, 2, 0);
+    unsafe {
+        let mut groups = libc::malloc(2 * mem::size_of::<gid_t>()) as *mut gid_t;
+        let status = call_initgroups_dyn::<Groups>(user, 100, &mut start, &mut size, &mut groups, 3, &mut errno);
+        assert_eq!((status, start, size), (NssStatus::Success, 3, 3));
+        assert_eq!(std::slice::from_raw_parts(groups, 3), [0, 1, 2]);
+        libc::free(groups as *mut c_void);
+    }
+}
@@ -0,0 +1,215 @@
+//! A ready-made `hosts` service that resolves `.local` names with multicast
+//! DNS, without Avahi.
+
+use crate::diag;
+use crate::dns::{self, Header, Question, Reader, Writer};
+use crate::dns_client::{self, in_domain, random_id, Answer};
+use crate::errors::{Error, NssStatus, Result};
+use crate::interfaces::{AddressFamily, HostAddresses, HostEntry, HostEntryWithTtl, NameService};
+use libc::{EINVAL, EIO};
+use std::ffi::CStr;
+use std::io;
+use std::marker::PhantomData;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket};
+use std::time::{Duration, Instant};
+
+/// Settings for an `MdnsService`.
+pub trait MdnsConfig: 'static {
+    /// How long to wait for a responder to answer.
+    const TIMEOUT: Duration = Duration::from_secs(1);
+
+    /// If true, as in nss-mdns's `mdns_minimal`, only addresses that can't
+    /// be in DNS, `169.254.0.0/16` and `fe80::/10`, are looked up in
+    /// reverse. Otherwise every address is, which makes each reverse
+    /// lookup of an address nobody on the link has take `TIMEOUT`.
+    const MINIMAL: bool = true;
+}
+
+/// The usual settings.
+pub struct MdnsDefaults;
+
+impl MdnsConfig for MdnsDefaults {}
+
+/// A `hosts` service that resolves names ending in `.local` by asking the
+/// local link with multicast DNS (RFC 6762), for machines without Avahi:
+///
+/// ```ignore
+/// nssglue_hosts!("mdns", MdnsService);
+/// ```
+///
+/// Each lookup is a one-shot query: a single query to `224.0.0.251`, port
+/// 5353, from an ordinary port, and the first answer to arrive within
+/// `TIMEOUT` wins. A name nobody answers for isn't found. Other names are
+/// left to the next service, so `hosts: files mdns dns` keeps `.local`
+/// lookups from reaching the DNS servers. Reverse lookups go out as `PTR`
+/// queries (see `MdnsConfig::MINIMAL`). IPv6 addresses are looked up
+/// too, but queries are only sent over IPv4.
+///
+/// The TTLs in the answers are reported by `gethostbyname3_r` and
+/// `gethostbyname4_r`. Responders answer one-shot queries with TTLs of
+/// at most ten seconds.
+pub struct MdnsService<C = MdnsDefaults>(PhantomData<C>);
+
+/// Where mDNS queries go.
+const MDNS_GROUP: (Ipv4Addr, u16) = (Ipv4Addr::new(224, 0, 0, 251), 5353);
+
+fn is_local(name: &[u8]) -> bool {
+    let name = name.strip_suffix(b".").unwrap_or(name);
+    in_domain(name, b"local") && name.len() > b"local".len()
+}
+
+fn is_link_local(addr: &IpAddr) -> bool {
+    match *addr {
+        IpAddr::V4(ip) => ip.is_link_local(),
+        IpAddr::V6(ip) => ip.segments()[0] & 0xffc0 == 0xfe80,
+    }
+}
+
+/// Send a one-shot query about `name` to `dest`, and return the answers to
+/// each of `qtypes` from the first responder that has records for it, or
+/// `None` if there are none within `timeout`.
+fn query(dest: SocketAddr, name: &[u8], qtypes: &[u16], timeout: Duration) -> io::Result<Option<Vec<Answer>>> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    // Responders ignore packets that may have come from off the link.
+    socket.set_multicast_ttl_v4(255)?;
+
+    let header = Header { id: random_id(), qdcount: qtypes.len() as u16, ..Header::default() };
+    let questions: Vec<Question> = qtypes.iter()
+        .map(|&qtype| Question { name: name.to_vec(), qtype, qclass: dns::CLASS_IN })
+        .collect();
+    let mut writer = Writer::new(&header);
+    for question in &questions {
+        writer.question(question).ok_or_else(|| io::Error::from_raw_os_error(EINVAL))?;
+    }
+    socket.send_to(&writer.finish(), dest)?;
+
+    let deadline = Instant::now() + timeout;
+    let mut buf = [0_u8; 9000];
+    loop {
+        let now = Instant::now();
+        if now >= deadline {
+            return Ok(None);
+        }
+        socket.set_read_timeout(Some(deadline - now))?;
+        let len = match socket.recv_from(&mut buf) {
+            Ok((len, _)) => len,
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock || err.kind() == io::ErrorKind::TimedOut => {
+                return Ok(None);
+            }
+            Err(err) => return Err(err),
+        };
+        // Responders echo the ID of a one-shot query. They may put some
+        // of the records in the additional section.
+        let mut message = match Reader::new(&buf[..len]).message() {
+            Some(message) if message.header.flags & dns::FLAG_QR != 0 && message.header.id == header.id => message,
+            _ => continue,
+        };
+        message.answers.append(&mut message.additional);
+        let answers: Vec<Answer> = questions.iter()
+            .map(|question| Answer::new(dns::RCODE_NOERROR, question, &message.answers))
+            .collect();
+        if answers.iter().any(|answer| !answer.data.is_empty()) {
+            return Ok(Some(answers));
+        }
+    }
+}
+
+impl<C: MdnsConfig> MdnsService<C> {
+    fn query(name: &[u8], qtypes: &[u16]) -> Result<Option<Vec<Answer>>> {
+        query(MDNS_GROUP.into(), name, qtypes, C::TIMEOUT).map_err(|err| {
+            diag::log(format_args!("can't send an mDNS query: {}", err));
+            Error::with_errno(NssStatus::Unavailable, err.raw_os_error().unwrap_or(EIO))
+        })
+    }
+}
+
+impl<C: MdnsConfig> NameService for MdnsService<C> {
+    fn gethostbyname2_r(name: &CStr, af: AddressFamily) -> Result<Option<HostEntry<'_>>> {
+        Ok(Self::gethostbyname3_r(name, af)?.map(|found| found.entry))
+    }
+
+    fn gethostbyaddr_r(addr: &IpAddr) -> Result<Option<HostEntry<'_>>> {
+        Ok(Self::gethostbyaddr2_r(addr)?.map(|found| found.entry))
+    }
+
+    fn gethostbyname3_r(name: &CStr, af: AddressFamily) -> Result<Option<HostEntryWithTtl<'_>>> {
+        if !is_local(name.to_bytes()) {
+            return Ok(None);
+        }
+        match Self::query(name.to_bytes(), &[dns_client::address_type(af)])? {
+            Some(mut answers) => dns_client::host_entry(name, af, answers.remove(0)).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn gethostbyname4_r(name: &CStr) -> Result<Option<HostAddresses<'_>>> {
+        if !is_local(name.to_bytes()) {
+            return Ok(None);
+        }
+        match Self::query(name.to_bytes(), &[dns::TYPE_AAAA, dns::TYPE_A])? {
+            Some(answers) => dns_client::host_addresses(name, &answers).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn gethostbyaddr2_r(addr: &IpAddr) -> Result<Option<HostEntryWithTtl<'_>>> {
+        if C::MINIMAL && !is_link_local(addr) {
+            return Ok(None);
+        }
+        match Self::query(&dns_client::reverse_name(addr), &[dns::TYPE_PTR])? {
+            Some(mut answers) => dns_client::reverse_entry(addr, answers.remove(0)).map(Some),
+            None => Ok(None),
+        }
+    }
+}
+
+#[test]
+fn test_mdns_query() {
+    use std::net::Ipv6Addr;
+    use std::thread;
+
+    assert!(is_local(b"printer.local") && is_local(b"Printer.LOCAL.") && !is_local(b"local"));
+    assert!(!is_local(b"printer.localdomain"));
+    assert!(is_link_local(&"169.254.1.2".parse().unwrap()) && is_link_local(&"fe80::1".parse().unwrap()));
+    assert!(!is_link_local(&"192.168.1.2".parse().unwrap()));
+
+    // A responder that knows printer.local and answers the way one-shot
+    // queries get answered: the questions echoed, cache-flush classes, and
+    // the AAAA record in the additional section. It says nothing about
+    // other names.
+    let responder = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let dest = responder.local_addr().unwrap();
+    let responding = thread::spawn(move || {
+        let mut buf = [0_u8; 512];
+        for _ in 0..2 {
+            let (len, peer) = responder.recv_from(&mut buf).unwrap();
+            let query = Reader::new(&buf[..len]).message().unwrap();
+            if query.questions[0].name != b"printer.local" {
+                continue;
+            }
+            let header = Header { flags: dns::FLAG_QR | 0x0400, ancount: 1, arcount: 1, ..query.header };
+            let mut writer = Writer::new(&header);
+            for question in &query.questions {
+                writer.question(question).unwrap();
+            }
+            let start = writer.len();
+            writer.record(b"printer.local", dns::TYPE_A, 10, &[192, 168, 1, 20]).unwrap();
+            writer.record(b"printer.local", dns::TYPE_AAAA, 10, &"fe80::20".parse::<Ipv6Addr>().unwrap().octets())
+                .unwrap();
+            let mut response = writer.finish();
+            // Set the cache-flush bit of the A record's class, after its
+            // 15-byte name and its type.
+            response[start + 17] |= 0x80;
+            responder.send_to(&response, peer).unwrap();
+        }
+    });
+
+    let answers = query(dest, b"printer.local", &[dns::TYPE_AAAA, dns::TYPE_A], Duration::from_secs(5))
+        .unwrap().unwrap();
+    let name = CStr::from_bytes_with_nul(b"printer.local\0").unwrap();
+    let found = dns_client::host_addresses(name, &answers).unwrap();
+    assert_eq!(found.addrs, ["fe80::20".parse::<IpAddr>().unwrap(), "192.168.1.20".parse().unwrap()]);
+    assert_eq!(found.ttl, Some(10));
+    assert!(query(dest, b"nobody.local", &[dns::TYPE_A], Duration::from_millis(100)).unwrap().is_none());
+    responding.join().unwrap();
+}
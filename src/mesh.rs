@@ -0,0 +1,236 @@
+//! A ready-made `hosts` service that resolves the peers on a mesh VPN,
+//! such as Tailscale or a WireGuard network, to their overlay addresses.
+
+use crate::diag;
+use crate::errors::{Error, NssStatus, Result};
+use crate::host_table::{Host, HostTable};
+use crate::http;
+use crate::interfaces::{AddressFamily, Entries, HostEntry, NameService};
+use libc::{EINVAL, EIO};
+use serde_json::Value;
+use std::ffi::{CStr, CString};
+use std::fs;
+use std::io;
+use std::marker::PhantomData;
+use std::net::IpAddr;
+use std::os::unix::net::UnixStream;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Where a `MeshService` finds the peers, and which names it answers for.
+pub trait MeshConfig: 'static {
+    /// The VPN daemon's local API socket, asked for its status the way
+    /// `tailscale status --json` does (`GET /localapi/v0/status`).
+    const SOCKET: Option<&'static str> = Some("/var/run/tailscale/tailscaled.sock");
+
+    /// A file of peers, kept up to date by whatever runs the VPN, as a
+    /// JSON list like this:
+    ///
+    /// ```json
+    /// [{"name": "laptop", "aliases": ["laptop.wg"], "addresses": ["10.8.0.2", "fd00:8::2"]}]
+    /// ```
+    ///
+    /// If set, this is read instead of asking `SOCKET`.
+    const PEERS_FILE: Option<&'static str> = None;
+
+    /// If set, only names under this domain are looked up, with the domain
+    /// taken off: with `Some("mesh")`, `laptop.mesh` is the peer `laptop`.
+    /// Otherwise every name is looked up as it is.
+    const DOMAIN: Option<&'static str> = None;
+
+    /// How long to wait for the daemon to answer.
+    const TIMEOUT: Duration = Duration::from_secs(1);
+}
+
+/// The usual settings: Tailscale's socket, and every name.
+pub struct MeshDefaults;
+
+impl MeshConfig for MeshDefaults {}
+
+/// A `hosts` service that resolves the names of the peers on a mesh VPN to
+/// their overlay addresses, so `ssh laptop` works across the network
+/// without running a DNS server for it:
+///
+/// ```ignore
+/// nssglue_hosts!("mesh", MeshService);
+/// ```
+///
+/// With Tailscale, each peer (and this machine) answers to its MagicDNS
+/// name, both in full (`laptop.tailnet-1234.ts.net`, which is its
+/// canonical name) and short (`laptop`), and to its hostname. With a
+/// peers file, each peer answers to its name and aliases. Reverse lookups
+/// give the peer with the address, and `sethostent` lists every peer.
+///
+/// Every lookup asks the daemon, or reads the file, again, since peers
+/// come and go. If the daemon isn't running or the file doesn't exist,
+/// lookups report `NssStatus::Unavailable` quietly; other failures also go
+/// to the diagnostic sink.
+pub struct MeshService<C = MeshDefaults>(PhantomData<C>);
+
+fn host(names: impl IntoIterator<Item = String>, addrs: Vec<IpAddr>) -> Option<Host> {
+    let mut names = names.into_iter().filter_map(|name| CString::new(name).ok());
+    let name = names.next()?;
+    let mut aliases: Vec<CString> = vec![];
+    for alias in names {
+        if !alias.as_bytes().eq_ignore_ascii_case(name.as_bytes())
+            && !aliases.iter().any(|a| a.as_bytes().eq_ignore_ascii_case(alias.as_bytes()))
+        {
+            aliases.push(alias);
+        }
+    }
+    Some(Host { name, aliases, addrs })
+}
+
+/// Read the peers, and this machine, out of the daemon's status: the
+/// `Self` node and the `Peer` map, each with a `DNSName`, `HostName`, and
+/// `TailscaleIPs`.
+fn parse_status(json: &[u8]) -> std::result::Result<HostTable, String> {
+    let status: Value = serde_json::from_slice(json).map_err(|err| err.to_string())?;
+    let peers = status["Peer"].as_object().ok_or("expected a map of peers")?;
+    let hosts = status.get("Self").into_iter().chain(peers.values())
+        .filter_map(|peer| {
+            let dns_name = peer["DNSName"].as_str().unwrap_or("").trim_end_matches('.');
+            let short = dns_name.split('.').next().unwrap_or("");
+            let names = IntoIterator::into_iter([dns_name, short, peer["HostName"].as_str().unwrap_or("")])
+                .filter(|name| !name.is_empty())
+                .map(str::to_string);
+            let addrs = peer["TailscaleIPs"].as_array().into_iter().flatten()
+                .filter_map(|addr| addr.as_str()?.parse().ok())
+                .collect();
+            host(names, addrs)
+        })
+        .collect();
+    Ok(HostTable { hosts })
+}
+
+/// Read the peers out of the contents of a peers file.
+fn parse_peers(json: &[u8]) -> std::result::Result<HostTable, String> {
+    let list: Value = serde_json::from_slice(json).map_err(|err| err.to_string())?;
+    let list = list.as_array().ok_or("expected a list of peers")?;
+    let hosts = list.iter()
+        .filter_map(|peer| {
+            let names = peer["name"].as_str().into_iter()
+                .chain(peer["aliases"].as_array().into_iter().flatten().filter_map(Value::as_str))
+                .map(str::to_string);
+            let addrs = peer["addresses"].as_array().into_iter().flatten()
+                .filter_map(|addr| addr.as_str()?.parse().ok())
+                .collect();
+            host(names, addrs)
+        })
+        .collect();
+    Ok(HostTable { hosts })
+}
+
+/// The error to report for `err` from `path`, logging it unless it's just
+/// that nothing is there.
+fn io_error(path: &str, what: &str, err: io::Error) -> Error {
+    if err.kind() != io::ErrorKind::NotFound && err.kind() != io::ErrorKind::ConnectionRefused {
+        diag::log(format_args!("can't {} {}: {}", what, path, err));
+    }
+    Error::with_errno(NssStatus::Unavailable, err.raw_os_error().unwrap_or(EIO))
+}
+
+impl<C: MeshConfig> MeshService<C> {
+    /// The name to look for, or `None` if `name` isn't in `C::DOMAIN`.
+    fn peer_name(name: &CStr) -> Option<CString> {
+        let name = name.to_str().ok()?.trim_end_matches('.');
+        let name = match C::DOMAIN {
+            None => name,
+            Some(domain) => {
+                let domain = domain.trim_end_matches('.');
+                let dot = name.len().checked_sub(domain.len() + 1)?;
+                if dot == 0 || name.as_bytes()[dot] != b'.' || !name.get(dot + 1..)?.eq_ignore_ascii_case(domain) {
+                    return None;
+                }
+                name.get(..dot)?
+            }
+        };
+        CString::new(name).ok()
+    }
+
+    fn peers() -> Result<Arc<HostTable>> {
+        let (path, parsed) = match (C::PEERS_FILE, C::SOCKET) {
+            (Some(path), _) => {
+                let json = fs::read(path).map_err(|err| io_error(path, "read", err))?;
+                (path, parse_peers(&json))
+            }
+            (None, Some(path)) => {
+                let stream = UnixStream::connect(path).map_err(|err| io_error(path, "connect to", err))?;
+                let response = stream.set_read_timeout(Some(C::TIMEOUT))
+                    .and_then(|()| stream.set_write_timeout(Some(C::TIMEOUT)))
+                    .and_then(|()| http::get(&stream, "local-tailscaled.sock", "/localapi/v0/status", &[]))
+                    .map_err(|err| io_error(path, "get the status from", err))?;
+                if response.status != 200 {
+                    diag::log(format_args!("can't get the status from {}: HTTP status {}", path, response.status));
+                    return Err(Error::with_errno(NssStatus::Unavailable, EIO));
+                }
+                (path, parse_status(&response.body))
+            }
+            (None, None) => return Ok(Arc::new(HostTable::default())),
+        };
+        parsed.map(Arc::new).map_err(|message| {
+            diag::log(format_args!("can't read the peers from {}: {}", path, message));
+            Error::with_errno(NssStatus::Unavailable, EINVAL)
+        })
+    }
+}
+
+impl<C: MeshConfig> NameService for MeshService<C> {
+    fn gethostbyname2_r(name: &CStr, af: AddressFamily) -> Result<Option<HostEntry<'_>>> {
+        match Self::peer_name(name) {
+            Some(wanted) => Ok(Self::peers()?.by_name(&wanted, af)),
+            None => Ok(None),
+        }
+    }
+
+    fn gethostbyaddr_r(addr: &IpAddr) -> Result<Option<HostEntry<'_>>> {
+        Ok(Self::peers()?.by_addr(addr))
+    }
+
+    fn sethostent(_stay_open: bool) -> Result<Entries<HostEntry<'static>>> {
+        Ok(HostTable::entries(Self::peers()?))
+    }
+}
+
+#[test]
+fn test_mesh_peers() {
+    let status = br#"{
+        "Self": {"HostName": "Workstation", "DNSName": "workstation.tailnet-1234.ts.net.",
+                 "TailscaleIPs": ["100.64.0.1", "fd7a:115c:a1e0::1"]},
+        "Peer": {
+            "nodekey:01": {"HostName": "Jens-Laptop", "DNSName": "jens-laptop.tailnet-1234.ts.net.",
+                           "TailscaleIPs": ["100.64.0.2"]},
+            "nodekey:02": {"HostName": "DiskStation", "DNSName": "nas.tailnet-1234.ts.net.",
+                           "TailscaleIPs": ["100.64.0.3", "fd7a:115c:a1e0::3"]}
+        }
+    }"#;
+    let table = parse_status(status).unwrap();
+    assert_eq!(table.hosts.len(), 3);
+    let name = |s: &'static [u8]| CStr::from_bytes_with_nul(s).unwrap();
+    let entry = table.by_name(name(b"nas\0"), AddressFamily::Ipv6).unwrap();
+    assert_eq!(entry.name.to_str(), Ok("nas.tailnet-1234.ts.net"));
+    assert_eq!(entry.aliases.len(), 2);
+    assert!(table.by_name(name(b"diskstation\0"), AddressFamily::Ipv4).is_some());
+    let entry = table.by_name(name(b"jens-laptop\0"), AddressFamily::Ipv4).unwrap();
+    assert_eq!(entry.aliases.len(), 1);
+    assert!(table.by_name(name(b"jens-laptop\0"), AddressFamily::Ipv6).is_none());
+    let entry = table.by_addr(&"100.64.0.1".parse().unwrap()).unwrap();
+    assert_eq!(entry.name.to_str(), Ok("workstation.tailnet-1234.ts.net"));
+
+    let peers = br#"[{"name": "gw", "aliases": ["gw.wg"], "addresses": ["10.8.0.1"]},
+                     {"aliases": ["nameless"], "addresses": ["10.8.0.9"]}]"#;
+    let table = parse_peers(peers).unwrap();
+    assert_eq!(table.hosts.len(), 2);
+    assert!(table.by_name(name(b"GW.wg\0"), AddressFamily::Ipv4).is_some());
+    assert!(parse_peers(b"{}").is_err());
+
+    struct Mesh;
+    impl MeshConfig for Mesh {
+        const SOCKET: Option<&'static str> = Some("/nonexistent/tailscaled.sock");
+        const DOMAIN: Option<&'static str> = Some("mesh");
+    }
+    assert_eq!(MeshService::<Mesh>::peer_name(name(b"nas.MESH.\0")).as_deref(), Some(name(b"nas\0")));
+    assert!(MeshService::<Mesh>::gethostbyname2_r(name(b"example.com\0"), AddressFamily::Ipv4).unwrap().is_none());
+    let err = MeshService::<Mesh>::gethostbyname2_r(name(b"nas.mesh\0"), AddressFamily::Ipv4).unwrap_err();
+    assert_eq!((err.status(), err.errno()), (NssStatus::Unavailable, libc::ENOENT));
+}
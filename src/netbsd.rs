@@ -0,0 +1,266 @@
+//! NetBSD's name service switch, `nsdispatch(3)`.
+//!
+//! NetBSD loads `nss_NAME.so.0` and calls its `nss_module_register`, like
+//! FreeBSD (see `freebsd`), but its libc has no adapters from `va_list`
+//! methods to glibc-style functions. The methods here are those adapters:
+//! each reads its arguments, calls the glue function passed as the method's
+//! data, and translates the result to NetBSD's conventions.
+//!
+//! NetBSD releases before 3.0 can't load modules at all. Only the `passwd`
+//! and `group` databases are adapted; NetBSD's host lookups pass internal
+//! structures that vary between releases.
+
+use crate::errors::NssStatus;
+use libc::{c_char, c_int, c_void, gid_t, group, passwd, uid_t};
+use std::mem;
+
+pub use crate::nsdispatch::{ns_mtab, nss_method, nss_module_unregister_fn, register};
+
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+compile_error!("nsswitch_service: NetBSD modules are only supported on x86_64 and aarch64");
+
+// `<nsswitch.h>`
+const NS_SUCCESS: c_int = 1 << 0;
+const NS_UNAVAIL: c_int = 1 << 1;
+const NS_NOTFOUND: c_int = 1 << 2;
+const NS_TRYAGAIN: c_int = 1 << 3;
+
+/// A C `va_list`, as a method receives it, holding only integer and pointer
+/// arguments, which is all NSS methods take.
+pub struct VaList(*mut c_void);
+
+/// A type that can be read from a `VaList`: one that C passes in a single
+/// general-purpose register or stack slot.
+pub trait VaArg {
+    /// Convert from the 64-bit slot the argument was passed in.
+    fn from_slot(slot: u64) -> Self;
+}
+
+impl<T> VaArg for *const T {
+    fn from_slot(slot: u64) -> Self { slot as usize as *const T }
+}
+
+impl<T> VaArg for *mut T {
+    fn from_slot(slot: u64) -> Self { slot as usize as *mut T }
+}
+
+impl VaArg for usize {
+    fn from_slot(slot: u64) -> Self { slot as usize }
+}
+
+impl VaArg for u32 {
+    // Narrower arguments are in the low bits of their slot.
+    fn from_slot(slot: u64) -> Self { slot as u32 }
+}
+
+/// The x86-64 System V `va_list`.
+#[cfg(target_arch = "x86_64")]
+#[repr(C)]
+struct RawVaList {
+    gp_offset: u32,
+    fp_offset: u32,
+    overflow_arg_area: *mut u64,
+    reg_save_area: *mut u8,
+}
+
+/// The AArch64 `va_list`.
+#[cfg(target_arch = "aarch64")]
+#[repr(C)]
+struct RawVaList {
+    stack: *mut u64,
+    gr_top: *mut u8,
+    vr_top: *mut u8,
+    gr_offs: i32,
+    vr_offs: i32,
+}
+
+impl VaList {
+    /// # Safety
+    ///
+    /// `ap` must be the `va_list` argument of an `nss_method`.
+    pub unsafe fn new(ap: *mut c_void) -> VaList {
+        VaList(ap)
+    }
+
+    /// Read the next argument, as C's `va_arg` would.
+    ///
+    /// # Safety
+    ///
+    /// There must be a next argument, and it must have type `T`.
+    pub unsafe fn arg<T: VaArg>(&mut self) -> T {
+        T::from_slot(self.next_slot())
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    unsafe fn next_slot(&mut self) -> u64 {
+        // The first six integer arguments are saved in a 48-byte area;
+        // the rest are on the stack.
+        let ap = &mut *(self.0 as *mut RawVaList);
+        if ap.gp_offset < 48 {
+            let slot = (ap.reg_save_area.add(ap.gp_offset as usize) as *const u64).read();
+            ap.gp_offset += 8;
+            slot
+        } else {
+            let slot = ap.overflow_arg_area.read();
+            ap.overflow_arg_area = ap.overflow_arg_area.add(1);
+            slot
+        }
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    unsafe fn next_slot(&mut self) -> u64 {
+        // `gr_offs` counts up to 0 through the saved registers, below
+        // `gr_top`; after that, arguments are on the stack.
+        let ap = &mut *(self.0 as *mut RawVaList);
+        if ap.gr_offs < 0 {
+            let slot = (ap.gr_top.offset(ap.gr_offs as isize) as *const u64).read();
+            ap.gr_offs += 8;
+            slot
+        } else {
+            let slot = ap.stack.read();
+            ap.stack = ap.stack.add(1);
+            slot
+        }
+    }
+}
+
+type Lookup<K, R> = unsafe extern "C" fn(K, *mut R, *mut c_char, usize, *mut c_int) -> c_int;
+type NextEntry<R> = unsafe extern "C" fn(*mut R, *mut c_char, usize, *mut c_int) -> c_int;
+type Reset = unsafe extern "C" fn() -> c_int;
+
+/// Translate a glue function's result. On success, point `*result` at the
+/// record; on failure, leave the error code in `*retval`, which NetBSD
+/// returns from `getpwnam_r` and friends.
+unsafe fn finish<R>(status: c_int, errno: c_int, retval: *mut c_int, record: *mut R, result: *mut *mut R) -> c_int {
+    let status = NssStatus::from_raw(status);
+    if !result.is_null() {
+        *result = if status == Some(NssStatus::Success) { record } else { std::ptr::null_mut() };
+    }
+    let (ns, errno) = match status {
+        Some(NssStatus::Success) => return NS_SUCCESS,
+        Some(NssStatus::NotFound) => return NS_NOTFOUND,
+        Some(NssStatus::TryAgain) => (NS_TRYAGAIN, errno),
+        Some(NssStatus::Unavailable) | None => (NS_UNAVAIL, errno),
+    };
+    if !retval.is_null() {
+        *retval = errno;
+    }
+    ns
+}
+
+/// `getpwnam_r` and the like: `(int *retval, key, record, buffer, buflen,
+/// record **result)`.
+unsafe fn lookup<K: VaArg, R>(mdata: *mut c_void, ap: *mut c_void) -> c_int {
+    let f = mem::transmute::<*mut c_void, Lookup<K, R>>(mdata);
+    let mut ap = VaList::new(ap);
+    let retval: *mut c_int = ap.arg();
+    let key: K = ap.arg();
+    let record: *mut R = ap.arg();
+    let buffer: *mut c_char = ap.arg();
+    let buflen: usize = ap.arg();
+    let result: *mut *mut R = ap.arg();
+    let mut errno = 0;
+    let status = f(key, record, buffer, buflen, &mut errno);
+    finish(status, errno, retval, record, result)
+}
+
+/// `getpwent_r` and the like: `(int *retval, record, buffer, buflen,
+/// record **result)`.
+unsafe fn next_entry<R>(mdata: *mut c_void, ap: *mut c_void) -> c_int {
+    let f = mem::transmute::<*mut c_void, NextEntry<R>>(mdata);
+    let mut ap = VaList::new(ap);
+    let retval: *mut c_int = ap.arg();
+    let record: *mut R = ap.arg();
+    let buffer: *mut c_char = ap.arg();
+    let buflen: usize = ap.arg();
+    let result: *mut *mut R = ap.arg();
+    let mut errno = 0;
+    let status = f(record, buffer, buflen, &mut errno);
+    finish(status, errno, retval, record, result)
+}
+
+/// `setpwent`, `endpwent`, and the like, which take no arguments.
+unsafe fn reset(mdata: *mut c_void) -> c_int {
+    let f = mem::transmute::<*mut c_void, Reset>(mdata);
+    finish::<c_void>(f(), 0, std::ptr::null_mut(), std::ptr::null_mut(), std::ptr::null_mut())
+}
+
+/// # Safety
+///
+/// `mdata` must be the glue function for `getpwnam_r`, and `ap` its
+/// arguments, as `nsdispatch` passes them.
+pub unsafe extern "C" fn getpwnam_r(_retval: *mut c_void, mdata: *mut c_void, ap: *mut c_void) -> c_int {
+    lookup::<*const c_char, passwd>(mdata, ap)
+}
+
+/// # Safety
+///
+/// `mdata` must be the glue function for `getpwuid_r`, and `ap` its
+/// arguments, as `nsdispatch` passes them.
+pub unsafe extern "C" fn getpwuid_r(_retval: *mut c_void, mdata: *mut c_void, ap: *mut c_void) -> c_int {
+    lookup::<uid_t, passwd>(mdata, ap)
+}
+
+/// # Safety
+///
+/// `mdata` must be the glue function for `getpwent_r`, and `ap` its
+/// arguments, as `nsdispatch` passes them.
+pub unsafe extern "C" fn getpwent_r(_retval: *mut c_void, mdata: *mut c_void, ap: *mut c_void) -> c_int {
+    next_entry::<passwd>(mdata, ap)
+}
+
+/// # Safety
+///
+/// `mdata` must be the glue function for `setpwent`, and `ap` its
+/// arguments, as `nsdispatch` passes them.
+pub unsafe extern "C" fn setpwent(_retval: *mut c_void, mdata: *mut c_void, _ap: *mut c_void) -> c_int {
+    reset(mdata)
+}
+
+/// # Safety
+///
+/// `mdata` must be the glue function for `endpwent`, and `ap` its
+/// arguments, as `nsdispatch` passes them.
+pub unsafe extern "C" fn endpwent(_retval: *mut c_void, mdata: *mut c_void, _ap: *mut c_void) -> c_int {
+    reset(mdata)
+}
+
+/// # Safety
+///
+/// `mdata` must be the glue function for `getgrnam_r`, and `ap` its
+/// arguments, as `nsdispatch` passes them.
+pub unsafe extern "C" fn getgrnam_r(_retval: *mut c_void, mdata: *mut c_void, ap: *mut c_void) -> c_int {
+    lookup::<*const c_char, group>(mdata, ap)
+}
+
+/// # Safety
+///
+/// `mdata` must be the glue function for `getgrgid_r`, and `ap` its
+/// arguments, as `nsdispatch` passes them.
+pub unsafe extern "C" fn getgrgid_r(_retval: *mut c_void, mdata: *mut c_void, ap: *mut c_void) -> c_int {
+    lookup::<gid_t, group>(mdata, ap)
+}
+
+/// # Safety
+///
+/// `mdata` must be the glue function for `getgrent_r`, and `ap` its
+/// arguments, as `nsdispatch` passes them.
+pub unsafe extern "C" fn getgrent_r(_retval: *mut c_void, mdata: *mut c_void, ap: *mut c_void) -> c_int {
+    next_entry::<group>(mdata, ap)
+}
+
+/// # Safety
+///
+/// `mdata` must be the glue function for `setgrent`, and `ap` its
+/// arguments, as `nsdispatch` passes them.
+pub unsafe extern "C" fn setgrent(_retval: *mut c_void, mdata: *mut c_void, _ap: *mut c_void) -> c_int {
+    reset(mdata)
+}
+
+/// # Safety
+///
+/// `mdata` must be the glue function for `endgrent`, and `ap` its
+/// arguments, as `nsdispatch` passes them.
+pub unsafe extern "C" fn endgrent(_retval: *mut c_void, mdata: *mut c_void, _ap: *mut c_void) -> c_int {
+    reset(mdata)
+}
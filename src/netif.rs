@@ -0,0 +1,137 @@
+//! Which kinds of addresses this host can use, from its network interfaces.
+
+use crate::diag;
+use crate::fork::ForkSafeMutex;
+use crate::interfaces::AddressFamily;
+use libc::c_int;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::ptr;
+use std::time::{Duration, Instant};
+
+/// How long `Connectivity::current` trusts what it last found.
+const CACHE_FOR: Duration = Duration::from_secs(1);
+
+/// Which address families this host has addresses in, not counting loopback
+/// addresses or, for IPv6, link-local ones: the test `getaddrinfo` makes
+/// for `AI_ADDRCONFIG`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Connectivity {
+    /// True if an interface that's up has an IPv4 address.
+    pub ipv4: bool,
+    /// True if an interface that's up has a global IPv6 address.
+    pub ipv6: bool,
+}
+
+/// What `Connectivity::current` found last, and when.
+static LAST: ForkSafeMutex<Option<(Instant, Connectivity)>> = ForkSafeMutex::new();
+
+/// Forget the last answer, in the child after a fork.
+pub(crate) fn reset() {
+    LAST.reset();
+}
+
+impl Connectivity {
+    /// This host's connectivity, from `getifaddrs`. The answer is reused
+    /// for up to a second, so interfaces coming and going are noticed
+    /// quickly, without listing them on every lookup. If they can't be
+    /// listed, both families count as usable.
+    pub fn current() -> Connectivity {
+        let now = Instant::now();
+        if let Some((checked, connectivity)) = *LAST.lock() {
+            if now < checked + CACHE_FOR {
+                return connectivity;
+            }
+        }
+        let connectivity = Connectivity::from_interfaces().unwrap_or_else(|err| {
+            diag::log(format_args!("can't list network interfaces: {}", err));
+            Connectivity { ipv4: true, ipv6: true }
+        });
+        *LAST.lock() = Some((now, connectivity));
+        connectivity
+    }
+
+    /// The connectivity implied by the addresses of the interfaces that
+    /// are up.
+    fn from_addrs(addrs: impl IntoIterator<Item = IpAddr>) -> Connectivity {
+        let mut connectivity = Connectivity { ipv4: false, ipv6: false };
+        for addr in addrs {
+            match addr {
+                IpAddr::V4(addr) => connectivity.ipv4 |= !addr.is_loopback(),
+                IpAddr::V6(addr) => connectivity.ipv6 |= !addr.is_loopback() && !is_link_local(&addr),
+            }
+        }
+        connectivity
+    }
+
+    fn from_interfaces() -> io::Result<Connectivity> {
+        let mut list: *mut libc::ifaddrs = ptr::null_mut();
+        if unsafe { libc::getifaddrs(&mut list) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut addrs = Vec::new();
+        let mut next = list;
+        while let Some(ifa) = unsafe { next.as_ref() } {
+            next = ifa.ifa_next;
+            if ifa.ifa_flags & libc::IFF_UP as libc::c_uint == 0 || ifa.ifa_addr.is_null() {
+                continue;
+            }
+            match c_int::from(unsafe { (*ifa.ifa_addr).sa_family }) {
+                libc::AF_INET => {
+                    let sin = unsafe { &*(ifa.ifa_addr as *const libc::sockaddr_in) };
+                    addrs.push(IpAddr::V4(Ipv4Addr::from(u32::from_be(sin.sin_addr.s_addr))));
+                }
+                libc::AF_INET6 => {
+                    let sin6 = unsafe { &*(ifa.ifa_addr as *const libc::sockaddr_in6) };
+                    addrs.push(IpAddr::V6(Ipv6Addr::from(sin6.sin6_addr.s6_addr)));
+                }
+                _ => {}
+            }
+        }
+        unsafe { libc::freeifaddrs(list) };
+        Ok(Connectivity::from_addrs(addrs))
+    }
+
+    /// Whether this host could use addresses in the family `af`. If it has
+    /// neither kind, as when only loopback interfaces are up, it counts as
+    /// having both, as with `getaddrinfo`.
+    pub fn allows_family(&self, af: AddressFamily) -> bool {
+        let usable = match af {
+            AddressFamily::Ipv4 => self.ipv4,
+            AddressFamily::Ipv6 => self.ipv6,
+        };
+        usable || !(self.ipv4 || self.ipv6)
+    }
+
+    /// Whether this host could use `addr`. Loopback addresses are always
+    /// usable.
+    pub fn allows(&self, addr: &IpAddr) -> bool {
+        let af = if addr.is_ipv4() { AddressFamily::Ipv4 } else { AddressFamily::Ipv6 };
+        addr.is_loopback() || self.allows_family(af)
+    }
+}
+
+/// True if `addr` is in `fe80::/10`.
+fn is_link_local(addr: &Ipv6Addr) -> bool {
+    addr.segments()[0] & 0xffc0 == 0xfe80
+}
+
+#[test]
+fn test_connectivity() {
+    let addrs = |addrs: &[&str]| Connectivity::from_addrs(addrs.iter().map(|addr| addr.parse().unwrap()));
+    assert_eq!(addrs(&["127.0.0.1", "::1", "fe80::1"]), Connectivity { ipv4: false, ipv6: false });
+    assert_eq!(addrs(&["127.0.0.1", "10.0.0.2", "fe80::1"]), Connectivity { ipv4: true, ipv6: false });
+    assert_eq!(addrs(&["::1", "2001:db8::2"]), Connectivity { ipv4: false, ipv6: true });
+    assert_eq!(addrs(&["10.0.0.2", "fd00::2"]), Connectivity { ipv4: true, ipv6: true });
+
+    let v4 = Connectivity { ipv4: true, ipv6: false };
+    assert!(v4.allows(&"192.0.2.1".parse().unwrap()));
+    assert!(!v4.allows(&"2001:db8::1".parse().unwrap()));
+    assert!(v4.allows(&"::1".parse().unwrap()));
+    assert!(!v4.allows_family(AddressFamily::Ipv6));
+    assert!(Connectivity { ipv4: false, ipv6: false }.allows(&"2001:db8::1".parse().unwrap()));
+
+    // Whatever this machine has, it's listed without error.
+    assert!(Connectivity::from_interfaces().is_ok());
+    assert_eq!(Connectivity::current(), Connectivity::current());
+}
@@ -0,0 +1,676 @@
+//! A ready-made service for `hosts`, `passwd`, and `group` that asks NIS
+//! (YP) servers, in place of glibc's `libnss_nis`.
+
+use crate::diag;
+use crate::errors::{Error, NssStatus, Result};
+use crate::ffi::c_char;
+use crate::fork::ForkSafeMutex;
+use crate::host_table::HostTable;
+use crate::hosts_file;
+use crate::interfaces::{AddressFamily, Entries, GroupEntry, GroupService, HostEntry, NameService};
+use crate::interfaces::{PasswdEntry, PasswdService};
+use libc::{gid_t, uid_t, EAGAIN, EINVAL, EIO};
+use std::borrow::Cow;
+use std::convert::TryFrom;
+use std::ffi::{CStr, CString};
+use std::io;
+use std::iter;
+use std::marker::PhantomData;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket};
+use std::process;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Where a `NisService` finds its server.
+pub trait NisConfig: 'static {
+    /// The NIS domain. If `None`, the system's, as set by `domainname`.
+    const DOMAIN: Option<&'static str> = None;
+
+    /// The server, as an IP address, optionally with the port `ypserv`
+    /// listens on. If `None`, `ypbind` says which server to use; if there's
+    /// no port, the server's portmapper says. It's an address, not a name,
+    /// because looking up a name might ask NIS.
+    const SERVER: Option<&'static str> = None;
+
+    /// How long to wait for each answer. Requests are sent again every
+    /// second until then, as UDP loses some.
+    const TIMEOUT: Duration = Duration::from_secs(5);
+}
+
+/// The usual settings: the system's NIS domain, and whichever server
+/// `ypbind` found.
+pub struct NisDefaults;
+
+impl NisConfig for NisDefaults {}
+
+/// A service that gets hosts, users, and groups from NIS, for networks
+/// that still run it, without `libnss_nis`:
+///
+/// ```ignore
+/// nssglue_hosts!("rnis", NisService);
+/// nssglue_passwd!("rnis", NisService);
+/// nssglue_group!("rnis", NisService);
+/// ```
+///
+/// Lookups are `YPPROC_MATCH` calls on the usual maps: `hosts.byname`,
+/// `hosts.byaddr`, `passwd.byname`, `passwd.byuid`, `group.byname`, and
+/// `group.bygid`, whose values are lines in the syntax of the files in
+/// `/etc`. Enumeration walks `hosts.byname`, `passwd.byname`, and
+/// `group.byname` an entry at a time, and `initgroups` searches
+/// `group.byname`, as `libnss_nis` does.
+///
+/// A missing key or map isn't found. If no domain is set, or `ypbind`
+/// isn't running, lookups report `NssStatus::Unavailable` quietly; a
+/// server that doesn't answer within `TIMEOUT` is `NssStatus::TryAgain`.
+/// Other errors are logged and reported as `NssStatus::Unavailable`.
+pub struct NisService<C = NisDefaults>(PhantomData<C>);
+
+const PMAP_PROG: u32 = 100000;
+const PMAP_VERS: u32 = 2;
+const PMAPPROC_GETPORT: u32 = 3;
+const PMAP_PORT: u16 = 111;
+
+const YPBIND_PROG: u32 = 100007;
+const YPBIND_VERS: u32 = 2;
+const YPBINDPROC_DOMAIN: u32 = 1;
+const YPBIND_SUCC_VAL: u32 = 1;
+
+const YP_PROG: u32 = 100004;
+const YP_VERS: u32 = 2;
+const YPPROC_MATCH: u32 = 3;
+const YPPROC_FIRST: u32 = 4;
+const YPPROC_NEXT: u32 = 5;
+
+const IPPROTO_UDP: u32 = 17;
+
+/// The `ypstat` values that mean something here.
+const YP_TRUE: i32 = 1;
+const YP_NOMORE: i32 = 2;
+const YP_NOMAP: i32 = -1;
+const YP_NOKEY: i32 = -3;
+
+/// How often to send a request again.
+const RETRANSMIT: Duration = Duration::from_secs(1);
+
+/// Building XDR data.
+#[derive(Default)]
+struct Xdr(Vec<u8>);
+
+impl Xdr {
+    fn u32(&mut self, n: u32) -> &mut Self {
+        self.0.extend_from_slice(&n.to_be_bytes());
+        self
+    }
+
+    fn opaque(&mut self, bytes: &[u8]) -> &mut Self {
+        self.u32(bytes.len() as u32);
+        self.0.extend_from_slice(bytes);
+        while !self.0.len().is_multiple_of(4) {
+            self.0.push(0);
+        }
+        self
+    }
+}
+
+/// Reading it. Every method returns `None` if the data is cut short.
+struct XdrReader<'a>(&'a [u8]);
+
+impl<'a> XdrReader<'a> {
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        let padded = len.checked_add(3)? / 4 * 4;
+        if self.0.len() < padded {
+            return None;
+        }
+        let taken = &self.0[..len];
+        self.0 = &self.0[padded..];
+        Some(taken)
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        Some(u32::from_be_bytes(<[u8; 4]>::try_from(self.take(4)?).unwrap()))
+    }
+
+    fn i32(&mut self) -> Option<i32> {
+        Some(self.u32()? as i32)
+    }
+
+    fn opaque(&mut self) -> Option<&'a [u8]> {
+        let len = self.u32()? as usize;
+        self.take(len)
+    }
+}
+
+fn invalid() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "malformed RPC reply")
+}
+
+/// Call procedure `proc` of version `vers` of program `prog` at `addr`,
+/// with `args`, and return the results.
+fn rpc_call(addr: SocketAddr, prog: u32, vers: u32, proc: u32, args: &[u8], timeout: Duration)
+    -> io::Result<Vec<u8>>
+{
+    let local: IpAddr = if addr.is_ipv4() { Ipv4Addr::UNSPECIFIED.into() } else { IpAddr::from([0_u16; 8]) };
+    let socket = UdpSocket::bind((local, 0))?;
+    socket.connect(addr)?;
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |since| since.subsec_nanos());
+    let xid = nanos ^ process::id().rotate_left(16);
+
+    // An unauthenticated call.
+    let mut call = Xdr::default();
+    call.u32(xid).u32(0).u32(2).u32(prog).u32(vers).u32(proc).u32(0).u32(0).u32(0).u32(0);
+    call.0.extend_from_slice(args);
+
+    let deadline = Instant::now() + timeout;
+    let mut buf = vec![0; 65536];
+    loop {
+        let now = Instant::now();
+        if now >= deadline {
+            return Err(io::Error::from(io::ErrorKind::TimedOut));
+        }
+        socket.send(&call.0)?;
+        let resend = now + RETRANSMIT.min(deadline - now);
+        loop {
+            let now = Instant::now();
+            if now >= resend {
+                break;
+            }
+            socket.set_read_timeout(Some(resend - now))?;
+            let len = match socket.recv(&mut buf) {
+                Ok(len) => len,
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock || err.kind() == io::ErrorKind::TimedOut => break,
+                Err(err) => return Err(err),
+            };
+            let mut r = XdrReader(&buf[..len]);
+            if r.u32() != Some(xid) || r.u32() != Some(1) {
+                continue;
+            }
+            // MSG_ACCEPTED, then the server's verifier, then SUCCESS.
+            if r.u32().ok_or_else(invalid)? != 0 {
+                return Err(io::Error::new(io::ErrorKind::PermissionDenied, "RPC call rejected"));
+            }
+            r.u32().ok_or_else(invalid)?;
+            r.opaque().ok_or_else(invalid)?;
+            return match r.u32().ok_or_else(invalid)? {
+                0 => Ok(r.0.to_vec()),
+                stat => Err(io::Error::other(format!("RPC call failed with accept_stat {}", stat))),
+            };
+        }
+    }
+}
+
+/// The port of version `vers` of program `prog` on `addr`, over UDP,
+/// according to its portmapper.
+fn getport(addr: IpAddr, prog: u32, vers: u32, timeout: Duration) -> io::Result<u16> {
+    let mut args = Xdr::default();
+    args.u32(prog).u32(vers).u32(IPPROTO_UDP).u32(0);
+    let reply = rpc_call(SocketAddr::new(addr, PMAP_PORT), PMAP_PROG, PMAP_VERS, PMAPPROC_GETPORT, &args.0, timeout)?;
+    match XdrReader(&reply).u32().ok_or_else(invalid)? {
+        0 => Err(io::Error::new(io::ErrorKind::ConnectionRefused, format!("program {} isn't registered", prog))),
+        port => u16::try_from(port).map_err(|_| invalid()),
+    }
+}
+
+/// The server `ypbind` has found for `domain`.
+fn ypbind(domain: &str, timeout: Duration) -> io::Result<SocketAddr> {
+    let localhost = IpAddr::V4(Ipv4Addr::LOCALHOST);
+    let port = getport(localhost, YPBIND_PROG, YPBIND_VERS, timeout)?;
+    let mut args = Xdr::default();
+    args.opaque(domain.as_bytes());
+    let reply = rpc_call(SocketAddr::new(localhost, port), YPBIND_PROG, YPBIND_VERS, YPBINDPROC_DOMAIN, &args.0,
+                         timeout)?;
+    let mut r = XdrReader(&reply);
+    if r.u32().ok_or_else(invalid)? != YPBIND_SUCC_VAL {
+        return Err(io::Error::new(io::ErrorKind::NotFound, format!("ypbind has no server for {}", domain)));
+    }
+    // The address and port, as raw bytes in network order.
+    let addr = <[u8; 4]>::try_from(r.take(4).ok_or_else(invalid)?).unwrap();
+    let port = r.take(2).ok_or_else(invalid)?;
+    Ok(SocketAddr::new(Ipv4Addr::from(addr).into(), u16::from_be_bytes([port[0], port[1]])))
+}
+
+/// The value of `key` in `map`, or `None` if there's no such key or map.
+fn ypmatch(server: SocketAddr, domain: &str, map: &str, key: &[u8], timeout: Duration) -> io::Result<Option<Vec<u8>>> {
+    let mut args = Xdr::default();
+    args.opaque(domain.as_bytes()).opaque(map.as_bytes()).opaque(key);
+    let reply = rpc_call(server, YP_PROG, YP_VERS, YPPROC_MATCH, &args.0, timeout)?;
+    let mut r = XdrReader(&reply);
+    match r.i32().ok_or_else(invalid)? {
+        YP_TRUE => Ok(Some(r.opaque().ok_or_else(invalid)?.to_vec())),
+        YP_NOKEY | YP_NOMAP => Ok(None),
+        stat => Err(ypstat_error(stat)),
+    }
+}
+
+/// The key and value after `key` in `map`, or the first if `key` is
+/// `None`, or `None` if there are no more.
+fn ypnext(server: SocketAddr, domain: &str, map: &str, key: Option<&[u8]>, timeout: Duration)
+    -> io::Result<Option<(Vec<u8>, Vec<u8>)>>
+{
+    let mut args = Xdr::default();
+    args.opaque(domain.as_bytes()).opaque(map.as_bytes());
+    let proc = match key {
+        None => YPPROC_FIRST,
+        Some(key) => {
+            args.opaque(key);
+            YPPROC_NEXT
+        }
+    };
+    let reply = rpc_call(server, YP_PROG, YP_VERS, proc, &args.0, timeout)?;
+    let mut r = XdrReader(&reply);
+    match r.i32().ok_or_else(invalid)? {
+        // The value comes first.
+        YP_TRUE => {
+            let value = r.opaque().ok_or_else(invalid)?.to_vec();
+            Ok(Some((r.opaque().ok_or_else(invalid)?.to_vec(), value)))
+        }
+        YP_NOMORE | YP_NOKEY | YP_NOMAP => Ok(None),
+        stat => Err(ypstat_error(stat)),
+    }
+}
+
+fn ypstat_error(stat: i32) -> io::Error {
+    io::Error::other(format!("the NIS server answered with ypstat {}", stat))
+}
+
+/// The NIS domain set with `domainname`, if any.
+fn system_domain() -> Option<String> {
+    let mut buf = [0 as c_char; 256];
+    if unsafe { libc::getdomainname(buf.as_mut_ptr(), (buf.len() - 1) as _) } != 0 {
+        return None;
+    }
+    let domain = unsafe { CStr::from_ptr(buf.as_ptr()) }.to_str().ok()?;
+    if domain.is_empty() || domain == "(none)" {
+        None
+    } else {
+        Some(domain.to_string())
+    }
+}
+
+/// The server last found for each domain.
+static BINDINGS: ForkSafeMutex<Vec<(String, SocketAddr)>> = ForkSafeMutex::new();
+
+fn passwd_entry(line: &[u8]) -> Option<PasswdEntry<'static>> {
+    let line = std::str::from_utf8(line).ok()?.trim_end_matches('\n');
+    let fields: Vec<&str> = line.split(':').collect();
+    if fields.len() != 7 || fields[0].starts_with(['+', '-']) {
+        return None;
+    }
+    let string = |i: usize| Some(Cow::Owned(CString::new(fields[i]).ok()?));
+    Some(PasswdEntry {
+        name: string(0)?,
+        passwd: string(1)?,
+        uid: fields[2].parse().ok()?,
+        gid: fields[3].parse().ok()?,
+        gecos: string(4)?,
+        dir: string(5)?,
+        shell: string(6)?,
+    })
+}
+
+fn group_entry(line: &[u8]) -> Option<GroupEntry<'static>> {
+    let line = std::str::from_utf8(line).ok()?.trim_end_matches('\n');
+    let fields: Vec<&str> = line.split(':').collect();
+    if fields.len() != 4 || fields[0].starts_with(['+', '-']) {
+        return None;
+    }
+    Some(GroupEntry {
+        name: Cow::Owned(CString::new(fields[0]).ok()?),
+        passwd: Cow::Owned(CString::new(fields[1]).ok()?),
+        gid: fields[2].parse().ok()?,
+        members: fields[3].split(',')
+            .filter(|member| !member.is_empty())
+            .map(|member| Some(Cow::Owned(CString::new(member).ok()?)))
+            .collect::<Option<_>>()?,
+    })
+}
+
+fn host_table(line: &[u8]) -> HostTable {
+    HostTable { hosts: hosts_file::parse(&String::from_utf8_lossy(line)) }
+}
+
+impl<C: NisConfig> NisService<C> {
+    fn domain() -> Result<String> {
+        match C::DOMAIN {
+            Some(domain) => Ok(domain.to_string()),
+            None => system_domain().ok_or_else(|| Error::with_errno(NssStatus::Unavailable, EINVAL)),
+        }
+    }
+
+    /// The server to ask about `domain`. Finding one takes RPCs, which are
+    /// done without holding the lock, so two threads may both ask; the
+    /// first to finish is remembered.
+    fn server(domain: &str) -> io::Result<SocketAddr> {
+        if let Some(&(_, server)) = BINDINGS.lock().iter().find(|(d, _)| d == domain) {
+            return Ok(server);
+        }
+        let server = match C::SERVER {
+            None => ypbind(domain, C::TIMEOUT)?,
+            Some(server) => match server.parse::<SocketAddr>() {
+                Ok(server) => server,
+                Err(_) => {
+                    let addr: IpAddr = server.parse()
+                        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "not an IP address"))?;
+                    SocketAddr::new(addr, getport(addr, YP_PROG, YP_VERS, C::TIMEOUT)?)
+                }
+            },
+        };
+        let mut bindings = BINDINGS.lock();
+        match bindings.iter().find(|(d, _)| d == domain) {
+            Some(&(_, bound)) => Ok(bound),
+            None => {
+                bindings.push((domain.to_string(), server));
+                Ok(server)
+            }
+        }
+    }
+
+    /// Forget the server for `domain`, so the next lookup asks again.
+    fn unbind(domain: &str) {
+        BINDINGS.lock().retain(|(d, _)| d != domain);
+    }
+
+    /// Do `call` with the server for the domain, and report any error.
+    fn ask<T>(map: &str, call: impl Fn(SocketAddr, &str) -> io::Result<T>) -> Result<T> {
+        let domain = Self::domain()?;
+        Self::server(&domain)
+            .and_then(|server| call(server, &domain))
+            .map_err(|err| {
+                Self::unbind(&domain);
+                match err.kind() {
+                    io::ErrorKind::TimedOut => {
+                        diag::log(format_args!("no answer from the NIS server for {} about {}", domain, map));
+                        Error::with_errno(NssStatus::TryAgain, EAGAIN)
+                    }
+                    io::ErrorKind::ConnectionRefused | io::ErrorKind::NotFound => {
+                        Error::with_errno(NssStatus::Unavailable, err.raw_os_error().unwrap_or(EIO))
+                    }
+                    _ => {
+                        diag::log(format_args!("can't look in {} for {}: {}", map, domain, err));
+                        Error::with_errno(NssStatus::Unavailable, err.raw_os_error().unwrap_or(EIO))
+                    }
+                }
+            })
+    }
+
+    fn lookup<T>(map: &str, key: &[u8], parse: fn(&[u8]) -> Option<T>) -> Result<Option<T>> {
+        let value = Self::ask(map, |server, domain| ypmatch(server, domain, map, key, C::TIMEOUT))?;
+        Ok(value.and_then(|value| parse(&value)))
+    }
+
+    /// Every entry in `map`, an RPC at a time.
+    fn entries<T: 'static>(map: &'static str, parse: fn(&[u8]) -> Option<T>) -> Result<Entries<T>> {
+        let mut key: Option<Vec<u8>> = None;
+        let mut done = false;
+        Ok(Box::new(iter::from_fn(move || {
+            while !done {
+                let next = Self::ask(map, |server, domain| ypnext(server, domain, map, key.as_deref(), C::TIMEOUT));
+                match next {
+                    Ok(Some((next_key, value))) => {
+                        key = Some(next_key);
+                        if let Some(entry) = parse(&value) {
+                            return Some(Ok(entry));
+                        }
+                    }
+                    Ok(None) => done = true,
+                    Err(err) => {
+                        done = true;
+                        return Some(Err(err));
+                    }
+                }
+            }
+            None
+        })))
+    }
+}
+
+impl<C: NisConfig> NameService for NisService<C> {
+    fn gethostbyname2_r(name: &CStr, af: AddressFamily) -> Result<Option<HostEntry<'_>>> {
+        let table = Self::lookup("hosts.byname", name.to_bytes(), |line| Some(host_table(line)))?;
+        Ok(table.and_then(|table| table.by_name(name, af)))
+    }
+
+    fn gethostbyaddr_r(addr: &IpAddr) -> Result<Option<HostEntry<'_>>> {
+        let table = Self::lookup("hosts.byaddr", addr.to_string().as_bytes(), |line| Some(host_table(line)))?;
+        Ok(table.and_then(|table| table.by_addr(addr)))
+    }
+
+    fn sethostent(_stay_open: bool) -> Result<Entries<HostEntry<'static>>> {
+        let tables = Self::entries("hosts.byname", |line| Some(host_table(line)))?;
+        Ok(Box::new(tables.flat_map(|table| -> Entries<HostEntry<'static>> {
+            match table {
+                Ok(table) => HostTable::entries(table.into()),
+                Err(err) => Box::new(iter::once(Err(err))),
+            }
+        })))
+    }
+
+    fn on_fork_child() {
+        BINDINGS.reset();
+    }
+}
+
+impl<C: NisConfig> PasswdService for NisService<C> {
+    fn getpwnam_r(name: &CStr) -> Result<Option<PasswdEntry<'_>>> {
+        let entry = Self::lookup("passwd.byname", name.to_bytes(), passwd_entry)?;
+        Ok(entry.filter(|entry| *entry.name == *name))
+    }
+
+    fn getpwuid_r(uid: uid_t) -> Result<Option<PasswdEntry<'static>>> {
+        let entry = Self::lookup("passwd.byuid", uid.to_string().as_bytes(), passwd_entry)?;
+        Ok(entry.filter(|entry| entry.uid == uid))
+    }
+
+    fn setpwent() -> Result<Entries<PasswdEntry<'static>>> {
+        Self::entries("passwd.byname", passwd_entry)
+    }
+
+    fn on_fork_child() {
+        BINDINGS.reset();
+    }
+}
+
+impl<C: NisConfig> GroupService for NisService<C> {
+    fn getgrnam_r(name: &CStr) -> Result<Option<GroupEntry<'_>>> {
+        let entry = Self::lookup("group.byname", name.to_bytes(), group_entry)?;
+        Ok(entry.filter(|entry| *entry.name == *name))
+    }
+
+    fn getgrgid_r(gid: gid_t) -> Result<Option<GroupEntry<'static>>> {
+        let entry = Self::lookup("group.bygid", gid.to_string().as_bytes(), group_entry)?;
+        Ok(entry.filter(|entry| entry.gid == gid))
+    }
+
+    fn setgrent() -> Result<Entries<GroupEntry<'static>>> {
+        Self::entries("group.byname", group_entry)
+    }
+
+    fn on_fork_child() {
+        BINDINGS.reset();
+    }
+}
+
+#[test]
+fn test_nis_rpc() {
+    use std::thread;
+
+    let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let addr = server.local_addr().unwrap();
+    let maps: &[(&str, &[u8], &[u8])] = &[
+        ("passwd.byname", b"alice", b"alice:x:1001:100:Alice:/home/alice:/bin/bash"),
+        ("passwd.byname", b"bob", b"bob:x:1002:100::/home/bob:/bin/sh"),
+        ("hosts.byname", b"db1", b"10.0.0.5\tdb1.example.com db1"),
+    ];
+    thread::spawn(move || {
+        let mut buf = [0; 9000];
+        let mut dropped = false;
+        loop {
+            let (len, client) = server.recv_from(&mut buf).unwrap();
+            // Lose the first request, to check that it's sent again.
+            if !dropped {
+                dropped = true;
+                continue;
+            }
+            let mut r = XdrReader(&buf[..len]);
+            let xid = r.u32().unwrap();
+            let fixed: Vec<u32> = (0..9).map(|_| r.u32().unwrap()).collect();
+            assert_eq!(&fixed[..5], &[0, 2, YP_PROG, YP_VERS, fixed[4]]);
+            let (domain, map) = (r.opaque().unwrap(), r.opaque().unwrap());
+            assert_eq!(domain, b"example");
+            let key = r.opaque();
+            let mut reply = Xdr::default();
+            reply.u32(xid).u32(1).u32(0).u32(0).u32(0).u32(0);
+            let in_map: Vec<_> = maps.iter().filter(|m| m.0.as_bytes() == map).collect();
+            match fixed[4] {
+                YPPROC_MATCH => match in_map.iter().find(|m| Some(m.1) == key) {
+                    Some(m) => reply.u32(YP_TRUE as u32).opaque(m.2),
+                    None => reply.u32(YP_NOKEY as u32),
+                },
+                _ => {
+                    let i = match key {
+                        None => 0,
+                        Some(key) => in_map.iter().position(|m| m.1 == key).unwrap() + 1,
+                    };
+                    match in_map.get(i) {
+                        Some(m) => reply.u32(YP_TRUE as u32).opaque(m.2).opaque(m.1),
+                        None => reply.u32(YP_NOMORE as u32),
+                    }
+                }
+            };
+            server.send_to(&reply.0, client).unwrap();
+        }
+    });
+
+    let timeout = Duration::from_secs(5);
+    let alice = ypmatch(addr, "example", "passwd.byname", b"alice", timeout).unwrap().unwrap();
+    let entry = passwd_entry(&alice).unwrap();
+    assert_eq!((entry.name.to_str(), entry.uid, entry.shell.to_str()), (Ok("alice"), 1001, Ok("/bin/bash")));
+    assert_eq!(ypmatch(addr, "example", "passwd.byname", b"carol", timeout).unwrap(), None);
+    let (key, _) = ypnext(addr, "example", "passwd.byname", None, timeout).unwrap().unwrap();
+    let (key, bob) = ypnext(addr, "example", "passwd.byname", Some(&key), timeout).unwrap().unwrap();
+    assert_eq!((&key[..], passwd_entry(&bob).unwrap().gecos.to_str()), (&b"bob"[..], Ok("")));
+    assert!(ypnext(addr, "example", "passwd.byname", Some(&key), timeout).unwrap().is_none());
+    let db1 = ypmatch(addr, "example", "hosts.byname", b"db1", timeout).unwrap().unwrap();
+    let name = CString::new("DB1").unwrap();
+    assert!(host_table(&db1).by_name(&name, AddressFamily::Ipv4).is_some());
+}
+
+#[test]
+fn test_parse_entries() {
+    let group = group_entry(b"staff:*:50:alice,bob").unwrap();
+    assert_eq!((group.gid, group.members.len()), (50, 2));
+    assert_eq!(group_entry(b"empty:*:51:\n").unwrap().members.len(), 0);
+
+    // Compat entries, missing or extra fields, ids that aren't numbers, and
+    // text that can't be a C string are skipped.
+    assert!(group_entry(b"+:::").is_none());
+    assert!(group_entry(b"staff:*:fifty:alice").is_none());
+    assert!(group_entry(b"staff:*:50:al\0ice").is_none());
+    assert!(passwd_entry(b"alice:x:1001:100:Alice:/home/alice").is_none());
+    assert!(passwd_entry(b"alice:x:1001:100:Alice:/home/alice:/bin/sh:extra").is_none());
+    assert!(passwd_entry(b"-alice:x:1001:100:Alice:/home/alice:/bin/sh").is_none());
+    assert!(passwd_entry(b"alice:x:-1:100:Alice:/home/alice:/bin/sh").is_none());
+    assert!(passwd_entry(b"alice:x:1001:100:\xff:/home/alice:/bin/sh").is_none());
+}
+
+#[test]
+fn test_malformed_replies() {
+    use std::thread;
+
+    // A server that answers a request with `reply(xid)` for each of
+    // `replies`, in turn.
+    fn answering(replies: Vec<fn(u32) -> Xdr>) -> SocketAddr {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = server.local_addr().unwrap();
+        thread::spawn(move || {
+            let mut buf = [0; 9000];
+            let (len, client) = server.recv_from(&mut buf).unwrap();
+            let xid = XdrReader(&buf[..len]).u32().unwrap();
+            for reply in replies {
+                server.send_to(&reply(xid).0, client).unwrap();
+            }
+        });
+        addr
+    }
+    // A reply made of `words`.
+    fn words(words: &[u32]) -> Xdr {
+        let mut reply = Xdr::default();
+        for &word in words {
+            reply.u32(word);
+        }
+        reply
+    }
+    // A reply to `xid` that was accepted and succeeded, with `results`.
+    fn accepted(xid: u32, results: &[u32]) -> Xdr {
+        words(&[&[xid, 1, 0, 0, 0, 0], results].concat())
+    }
+
+    let timeout = Duration::from_secs(5);
+    let call = |reply: fn(u32) -> Xdr| rpc_call(answering(vec![reply]), YP_PROG, YP_VERS, 0, &[], timeout);
+
+    // Replies to something else are ignored, but a reply cut short isn't.
+    let addr = answering(vec![|xid| accepted(xid.wrapping_add(1), &[]), |xid| accepted(xid, &[])]);
+    assert_eq!(rpc_call(addr, YP_PROG, YP_VERS, 0, &[], timeout).unwrap(), b"");
+    assert_eq!(call(|xid| words(&[xid, 1, 0])).unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+    // Calls the server refused, or accepted and failed.
+    assert_eq!(call(|xid| words(&[xid, 1, 1, 0])).unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+    let unavailable = call(|xid| words(&[xid, 1, 0, 0, 0, 1])).unwrap_err();
+    assert!(unavailable.to_string().contains("accept_stat 1"), "{}", unavailable);
+
+    // YP results without the values they promise, and statuses that mean
+    // the server is in trouble rather than that there's no such entry.
+    let ypmatch_with = |reply| ypmatch(answering(vec![reply]), "example", "passwd.byname", b"alice", timeout);
+    assert_eq!(ypmatch_with(|xid| accepted(xid, &[YP_TRUE as u32])).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    assert_eq!(ypmatch_with(|xid| accepted(xid, &[YP_NOMAP as u32])).unwrap(), None);
+    let bad_database = ypmatch_with(|xid| accepted(xid, &[-4_i32 as u32])).unwrap_err();
+    assert!(bad_database.to_string().contains("ypstat -4"), "{}", bad_database);
+    let ypnext_with = |reply| ypnext(answering(vec![reply]), "example", "passwd.byname", None, timeout);
+    let value_only = |xid| {
+        let mut reply = accepted(xid, &[YP_TRUE as u32]);
+        reply.opaque(b"value");
+        reply
+    };
+    assert_eq!(ypnext_with(value_only).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    assert_eq!(ypnext_with(|xid| accepted(xid, &[YP_NOKEY as u32])).unwrap(), None);
+}
+
+#[test]
+fn test_rpc_timeout() {
+    // A server that never answers.
+    let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let start = Instant::now();
+    let err = rpc_call(server.local_addr().unwrap(), YP_PROG, YP_VERS, 0, &[], Duration::from_millis(200)).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    assert!(start.elapsed() >= Duration::from_millis(200));
+    assert!(start.elapsed() < RETRANSMIT);
+}
+
+#[test]
+fn test_service_errors() {
+    use libc::ECONNREFUSED;
+
+    struct NoServer;
+    impl NisConfig for NoServer {
+        const DOMAIN: Option<&'static str> = Some("no-server.test");
+        // Nothing listens on port 1 to answer.
+        const SERVER: Option<&'static str> = Some("127.0.0.1:1");
+    }
+    struct BadServer;
+    impl NisConfig for BadServer {
+        const DOMAIN: Option<&'static str> = Some("bad-server.test");
+        const SERVER: Option<&'static str> = Some("nis.example.com");
+    }
+    type Service = NisService<NoServer>;
+    let status = |err: Error| (err.status(), err.errno());
+
+    // A server that doesn't answer in time is a transient failure; one
+    // that isn't there, or can't be asked, is unavailable.
+    let failing = |kind: io::ErrorKind| Service::ask("passwd.byname", |_, _| Err::<(), _>(io::Error::from(kind)));
+    assert_eq!(status(failing(io::ErrorKind::TimedOut).unwrap_err()), (NssStatus::TryAgain, EAGAIN));
+    assert_eq!(status(failing(io::ErrorKind::InvalidData).unwrap_err()), (NssStatus::Unavailable, EIO));
+    let refused = Service::ask("passwd.byname", |_, _| Err::<(), _>(io::Error::from_raw_os_error(ECONNREFUSED)));
+    assert_eq!(status(refused.unwrap_err()), (NssStatus::Unavailable, ECONNREFUSED));
+
+    let alice = CString::new("alice").unwrap();
+    assert_eq!(status(Service::getpwnam_r(&alice).err().unwrap()), (NssStatus::Unavailable, ECONNREFUSED));
+    assert_eq!(status(NisService::<BadServer>::getgrgid_r(50).err().unwrap()), (NssStatus::Unavailable, EIO));
+}
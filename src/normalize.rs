@@ -0,0 +1,112 @@
+//! Putting hostnames in one standard form before a service sees them.
+
+use crate::errors::{Error, HostError, NssStatus, Result};
+use crate::hostname::{is_plausible_hostname, is_valid_hostname};
+use crate::interfaces::{AddressFamily, Entries, HostAddresses, HostEntry, HostEntryWithTtl, HostLimits, NameService};
+use libc::ENOENT;
+use std::borrow::Cow;
+use std::ffi::{CStr, CString};
+use std::marker::PhantomData;
+use std::net::IpAddr;
+use std::time::Duration;
+
+/// The `hosts` service `S`, but with names normalized first, so that `S`
+/// can compare them byte for byte:
+///
+/// ```ignore
+/// nssglue_hosts!("redis", Normalized<RedisService>);
+/// ```
+///
+/// ASCII letters are lowercased and one trailing dot is removed, so `S`
+/// sees `WWW.Example.COM.` as `www.example.com`. Non-ASCII bytes are left
+/// alone. Names that obviously aren't hostnames at all, such as the empty
+/// name, `a..b`, or names with spaces in them, aren't found, without asking
+/// `S`. For stricter checking, set `VALIDATE_HOSTNAMES` in `S`; it applies
+/// to the normalized name.
+///
+/// Results are `S`'s, unchanged. Reverse lookups and enumeration go to `S`
+/// unchanged too, as do `S`'s other settings, such as `LOOKUP_TIMEOUT`.
+pub struct Normalized<S>(PhantomData<S>);
+
+/// `name` in normal form, or `None` if it can't be a hostname.
+fn normalize(name: &CStr) -> Option<Cow<'_, CStr>> {
+    if !is_plausible_hostname(name) {
+        return None;
+    }
+    let bytes = name.to_bytes();
+    let trimmed = bytes.strip_suffix(b".").unwrap_or(bytes);
+    if trimmed.len() == bytes.len() && !bytes.iter().any(u8::is_ascii_uppercase) {
+        return Some(Cow::Borrowed(name));
+    }
+    // No NULs can appear in bytes taken from a `CStr`.
+    CString::new(trimmed.to_ascii_lowercase()).ok().map(Cow::Owned)
+}
+
+impl<S: NameService> Normalized<S> {
+    fn lookup<T>(name: &CStr, s: impl FnOnce(&CStr) -> Result<Option<T>>) -> Result<Option<T>> {
+        match normalize(name) {
+            Some(name) if !S::VALIDATE_HOSTNAMES || is_valid_hostname(&name) => s(&name),
+            _ => Err(Error::with_host(NssStatus::NotFound, ENOENT, HostError::HostNotFound)),
+        }
+    }
+}
+
+impl<S: NameService> NameService for Normalized<S> {
+    const ALLOW_EMPTY_ADDRESS_LIST: bool = S::ALLOW_EMPTY_ADDRESS_LIST;
+    const LOOKUP_TIMEOUT: Option<Duration> = S::LOOKUP_TIMEOUT;
+    const LIMITS: HostLimits = S::LIMITS;
+    // `lookup` checks the normalized name instead.
+    const VALIDATE_HOSTNAMES: bool = false;
+
+    fn gethostbyname_r(name: &CStr) -> Result<Option<HostEntry<'_>>> {
+        Self::lookup(name, |name| Ok(S::gethostbyname_r(name)?.map(HostEntry::into_owned)))
+    }
+
+    fn gethostbyname2_r(name: &CStr, af: AddressFamily) -> Result<Option<HostEntry<'_>>> {
+        Self::lookup(name, |name| Ok(S::gethostbyname2_r(name, af)?.map(HostEntry::into_owned)))
+    }
+
+    fn gethostbyaddr_r(addr: &IpAddr) -> Result<Option<HostEntry<'_>>> {
+        S::gethostbyaddr_r(addr)
+    }
+
+    fn gethostbyname3_r(name: &CStr, af: AddressFamily) -> Result<Option<HostEntryWithTtl<'_>>> {
+        Self::lookup(name, |name| Ok(S::gethostbyname3_r(name, af)?.map(HostEntryWithTtl::into_owned)))
+    }
+
+    fn gethostbyname4_r(name: &CStr) -> Result<Option<HostAddresses<'_>>> {
+        Self::lookup(name, |name| Ok(S::gethostbyname4_r(name)?.map(HostAddresses::into_owned)))
+    }
+
+    fn gethostbyaddr2_r(addr: &IpAddr) -> Result<Option<HostEntryWithTtl<'_>>> {
+        S::gethostbyaddr2_r(addr)
+    }
+
+    fn sethostent(stay_open: bool) -> Result<Entries<HostEntry<'static>>> {
+        S::sethostent(stay_open)
+    }
+
+    fn on_fork_child() {
+        S::on_fork_child()
+    }
+}
+
+#[test]
+fn test_normalized() {
+    use crate::testing::{gethostbyname2, FixedHosts};
+
+    let normal = |s: &str| normalize(&CString::new(s).unwrap()).map(|s| s.to_str().unwrap().to_string());
+    assert_eq!(normal("WWW.Example.COM.").as_deref(), Some("www.example.com"));
+    assert_eq!(normal("_Srv.BÜCHER.example").as_deref(), Some("_srv.bÜcher.example"));
+    assert_eq!(normal("host.test").as_deref(), Some("host.test"));
+    assert_eq!(normal("host.test.."), None);
+    assert_eq!(normal(""), None);
+    assert_eq!(normal("a b"), None);
+
+    type Hosts = Normalized<FixedHosts>;
+    let entry = gethostbyname2::<Hosts>("Host.TEST.", AddressFamily::Ipv4).unwrap().unwrap();
+    assert_eq!(entry.name.to_str(), Ok("host.test"));
+    assert!(gethostbyname2::<Hosts>("LOCALHOST", AddressFamily::Ipv4).unwrap().is_some());
+    let err = Hosts::gethostbyname2_r(CStr::from_bytes_with_nul(b".\0").unwrap(), AddressFamily::Ipv4).unwrap_err();
+    assert_eq!(err.host_error(), Some(HostError::HostNotFound));
+}
@@ -0,0 +1,68 @@
+//! What FreeBSD's and NetBSD's `nsdispatch(3)` have in common: an NSS
+//! module exports `nss_module_register`, which returns a table of methods.
+
+use libc::{c_char, c_int, c_uint, c_void};
+use std::ptr;
+
+/// The signature of an `nsdispatch` method. The last argument is really a
+/// C `va_list` holding the method's arguments.
+#[allow(non_camel_case_types)]
+pub type nss_method = unsafe extern "C" fn(retval: *mut c_void, mdata: *mut c_void, ap: *mut c_void) -> c_int;
+
+/// One method of an NSS module, as `nss_module_register` returns it.
+#[allow(non_camel_case_types)]
+#[repr(C)]
+pub struct ns_mtab {
+    pub database: *const c_char,
+    pub name: *const c_char,
+    pub method: Option<nss_method>,
+    pub mdata: *mut c_void,
+}
+
+/// Called by `nsdispatch` with the table and its length when it unloads the
+/// module.
+#[allow(non_camel_case_types)]
+pub type nss_module_unregister_fn = Option<unsafe extern "C" fn(mtab: *mut ns_mtab, nelems: c_uint)>;
+
+impl ns_mtab {
+    /// A table entry. `database` and `name` must be null-terminated.
+    pub fn new(database: &'static [u8], name: &'static [u8], method: nss_method, mdata: *mut c_void) -> ns_mtab {
+        debug_assert!(database.ends_with(b"\0") && name.ends_with(b"\0"));
+        ns_mtab {
+            database: database.as_ptr() as *const c_char,
+            name: name.as_ptr() as *const c_char,
+            method: Some(method),
+            mdata,
+        }
+    }
+}
+
+/// The body of a module's `nss_module_register`: hand `methods` to
+/// `nsdispatch`.
+///
+/// `nsdispatch` sorts the table in place, so it is moved to the heap rather
+/// than kept in a `static`, and freed again when the module is unloaded.
+///
+/// # Safety
+///
+/// `nelems` and `unreg` must be the pointers `nsdispatch` passed in.
+pub unsafe fn register(
+    methods: Vec<ns_mtab>,
+    nelems: *mut c_uint,
+    unreg: *mut nss_module_unregister_fn,
+) -> *mut ns_mtab {
+    if nelems.is_null() || unreg.is_null() {
+        return ptr::null_mut();
+    }
+    let len = methods.len();
+    let methods = Box::into_raw(methods.into_boxed_slice());
+    *nelems = len as c_uint;
+    *unreg = Some(unregister);
+    methods as *mut ns_mtab
+}
+
+unsafe extern "C" fn unregister(mtab: *mut ns_mtab, nelems: c_uint) {
+    if !mtab.is_null() {
+        drop(Box::from_raw(ptr::slice_from_raw_parts_mut(mtab, nelems as usize)));
+    }
+}
@@ -0,0 +1,404 @@
+//! A ready-made service for `passwd` and `group` that gets users and groups
+//! from a cloud VM's metadata server, as Google's OS Login does.
+
+use crate::config::env_var;
+use crate::diag;
+use crate::errors::{Error, NssStatus, Result};
+use crate::fork::ForkSafeMutex;
+use crate::http;
+use crate::interfaces::{no_entries, Entries, GroupEntry, GroupService, PasswdEntry, PasswdService};
+use libc::{gid_t, uid_t, EINVAL, EIO};
+use serde_json::Value;
+use std::borrow::Cow;
+use std::convert::TryFrom;
+use std::ffi::{CStr, CString};
+use std::io;
+use std::marker::PhantomData;
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::{Duration, Instant};
+
+/// Where an `OsLoginService` finds the directory, and how long it
+/// remembers what it says.
+pub trait OsLoginConfig: 'static {
+    /// The directory's URL, `http:` only. `users` and `groups` are
+    /// appended to it.
+    const URL: &'static str = "http://169.254.169.254/computeMetadata/v1/oslogin";
+
+    /// An environment variable that overrides `URL`, for pointing a program
+    /// at a test server. It's ignored in secure mode (see
+    /// `is_secure_mode`), so setuid programs always use `URL`.
+    const URL_VAR: Option<&'static str> = Some("NSS_OSLOGIN_URL");
+
+    /// Headers to send with every request. Metadata servers want one, so
+    /// that a web page can't trick a program into making the request.
+    const HEADERS: &'static [(&'static str, &'static str)] = &[("Metadata-Flavor", "Google")];
+
+    /// How long to remember a user or group.
+    const CACHE_TTL: Duration = Duration::from_secs(60);
+
+    /// How long to remember that there's no such user or group. Zero for
+    /// both turns caching off.
+    const NEGATIVE_TTL: Duration = Duration::from_secs(10);
+
+    /// If true, `setpwent` and `setgrent` list every user and group, a page
+    /// at a time. In a large organization, that's a lot of requests, so by
+    /// default there is no enumeration.
+    const ENUMERATE: bool = false;
+
+    /// How long to wait for the server.
+    const TIMEOUT: Duration = Duration::from_secs(2);
+}
+
+/// The usual settings: Google Compute Engine's metadata server.
+pub struct OsLoginDefaults;
+
+impl OsLoginConfig for OsLoginDefaults {}
+
+/// A service that gets users and groups from a cloud directory, so VMs
+/// don't need scripts that copy accounts into `/etc/passwd`:
+///
+/// ```ignore
+/// nssglue_passwd!("oslogin", OsLoginService);
+/// nssglue_group!("oslogin", OsLoginService);
+/// ```
+///
+/// It speaks the API of Google's OS Login metadata endpoints, which an
+/// in-house directory can serve too:
+///
+/// ```text
+/// GET /users?username=alice    {"loginProfiles": [{"posixAccounts": [{"username": "alice", "uid": "1001",
+///                               "gid": "1001", "homeDirectory": "/home/alice", "shell": "/bin/bash"}]}]}
+/// GET /users?uid=1001          (the same)
+/// GET /groups?groupname=staff  {"posixGroups": [{"name": "staff", "gid": "50"}]}
+/// GET /groups?gid=50           (the same)
+/// GET /users?groupname=staff   {"usernames": ["alice", "bob"]}
+/// GET /groups?username=alice   {"posixGroups": [{"name": "staff", "gid": "50"}]}
+/// ```
+///
+/// Ids may be numbers or strings. A 404 means there's no such user or
+/// group. Lists come a page at a time: each page has a `nextPageToken`,
+/// passed back as `pagetoken`, until the last. `initgroups_dyn` asks for
+/// the user's groups in one go, rather than searching every group.
+///
+/// Answers are cached for `CACHE_TTL`, and missing entries for
+/// `NEGATIVE_TTL`. Any other status, or JSON that doesn't fit, is logged,
+/// and the lookup reports `NssStatus::Unavailable`; so does a server that
+/// can't be reached, which goes unlogged if nothing is listening.
+pub struct OsLoginService<C = OsLoginDefaults>(PhantomData<C>);
+
+/// The most answers cached.
+const MAX_CACHED: usize = 256;
+
+/// How many entries to ask for per page.
+const PAGE_SIZE: usize = 1000;
+
+/// The most pages to read from one list, in case the server keeps
+/// handing out tokens.
+const MAX_PAGES: usize = 1000;
+
+/// A response body, or `None` for a 404.
+type Found = Option<Vec<u8>>;
+
+/// Answers, by URL, with when to forget them.
+static CACHE: ForkSafeMutex<Vec<(String, Instant, Found)>> = ForkSafeMutex::new();
+
+/// `GET` `url`, returning the body, or `None` for a 404.
+fn fetch(url: &str, headers: &[(&str, &str)], timeout: Duration) -> io::Result<Found> {
+    let (host, port, path) = match http::split_url(url) {
+        Some((false, host, port, path)) => (host, port, path),
+        _ => return Err(io::Error::new(io::ErrorKind::InvalidInput, "not an http: URL")),
+    };
+    let mut last_err = io::Error::from(io::ErrorKind::AddrNotAvailable);
+    for addr in (host, port).to_socket_addrs()? {
+        let tcp = match TcpStream::connect_timeout(&addr, timeout) {
+            Ok(tcp) => tcp,
+            Err(err) => {
+                last_err = err;
+                continue;
+            }
+        };
+        tcp.set_read_timeout(Some(timeout))?;
+        tcp.set_write_timeout(Some(timeout))?;
+        let authority = if port == 80 { host.to_string() } else { format!("{}:{}", host, port) };
+        let response = http::get(tcp, &authority, path, headers)?;
+        return match response.status {
+            200 => Ok(Some(response.body)),
+            404 => Ok(None),
+            status => Err(io::Error::other(format!("HTTP status {}", status))),
+        };
+    }
+    Err(last_err)
+}
+
+fn string(object: &Value, key: &str) -> Option<CString> {
+    CString::new(object.get(key)?.as_str()?).ok()
+}
+
+/// An id, which the API writes as a string, as protobuf's JSON mapping
+/// does with 64-bit integers.
+fn id(object: &Value, key: &str) -> Option<u32> {
+    let value = object.get(key)?;
+    match value.as_str() {
+        Some(s) => s.parse().ok(),
+        None => u32::try_from(value.as_u64()?).ok(),
+    }
+}
+
+fn passwd_entry(account: &Value) -> Option<PasswdEntry<'static>> {
+    let optional = |key| match account.get(key) {
+        None => Some(CString::default()),
+        Some(_) => string(account, key),
+    };
+    let shell = optional("shell")?;
+    Some(PasswdEntry {
+        name: Cow::Owned(string(account, "username")?),
+        passwd: Cow::Borrowed(CStr::from_bytes_with_nul(b"*\0").unwrap()),
+        uid: id(account, "uid")?,
+        gid: id(account, "gid")?,
+        gecos: Cow::Owned(optional("gecos")?),
+        dir: Cow::Owned(string(account, "homeDirectory")?),
+        shell: Cow::Owned(if shell.as_bytes().is_empty() { CString::new("/bin/bash").unwrap() } else { shell }),
+    })
+}
+
+/// The POSIX accounts in a page of login profiles, each profile's primary
+/// account first. Accounts for other operating systems are left out.
+fn accounts(page: &Value) -> Option<Vec<PasswdEntry<'static>>> {
+    let mut entries = vec![];
+    for profile in page["loginProfiles"].as_array().into_iter().flatten() {
+        let mut accounts: Vec<&Value> = profile["posixAccounts"].as_array().into_iter().flatten()
+            .filter(|account| account["operatingSystemType"].as_str().is_none_or(|os| os == "LINUX"))
+            .collect();
+        accounts.sort_by_key(|account| !account["primary"].as_bool().unwrap_or(false));
+        for account in accounts {
+            entries.push(passwd_entry(account)?);
+        }
+    }
+    Some(entries)
+}
+
+/// The groups in a page of `posixGroups`, without their members.
+fn groups(page: &Value) -> Option<Vec<GroupEntry<'static>>> {
+    page.get("posixGroups").map_or(Some(vec![]), |groups| {
+        groups.as_array()?.iter()
+            .map(|group| Some(GroupEntry {
+                name: Cow::Owned(string(group, "name")?),
+                passwd: Cow::Borrowed(CStr::from_bytes_with_nul(b"*\0").unwrap()),
+                gid: id(group, "gid")?,
+                members: vec![],
+            }))
+            .collect()
+    })
+}
+
+fn usernames(page: &Value) -> Option<Vec<Cow<'static, CStr>>> {
+    page.get("usernames").map_or(Some(vec![]), |names| {
+        names.as_array()?.iter().map(|name| Some(Cow::Owned(CString::new(name.as_str()?).ok()?))).collect()
+    })
+}
+
+impl<C: OsLoginConfig> OsLoginService<C> {
+    /// Get `what` (`users` or `groups`) with `query`, a page at a time,
+    /// and convert each page with `convert`. Returns `None` if there's no
+    /// such thing.
+    fn get<T>(what: &str, query: &[(&str, &[u8])], all_pages: bool, convert: fn(&Value) -> Option<Vec<T>>)
+        -> Result<Option<Vec<T>>>
+    {
+        let base = C::URL_VAR.and_then(env_var).unwrap_or_else(|| C::URL.to_string());
+        let query: Vec<String> = query.iter()
+            .map(|(key, value)| format!("{}={}", key, http::percent_encode(value)))
+            .collect();
+        let url = format!("{}/{}?{}", base.trim_end_matches('/'), what, query.join("&"));
+
+        let mut found = vec![];
+        let mut token = String::new();
+        for _ in 0..MAX_PAGES {
+            let page_url = if !all_pages {
+                url.clone()
+            } else if token.is_empty() {
+                format!("{}&pagesize={}", url, PAGE_SIZE)
+            } else {
+                format!("{}&pagesize={}&pagetoken={}", url, PAGE_SIZE, http::percent_encode(token.as_bytes()))
+            };
+            let body = match Self::fetch(&page_url)? {
+                Some(body) => body,
+                None if found.is_empty() => return Ok(None),
+                None => break,
+            };
+            let page: Option<Value> = serde_json::from_slice(&body).ok();
+            match page.as_ref().and_then(convert) {
+                Some(entries) => found.extend(entries),
+                None => {
+                    diag::log(format_args!("{} doesn't fit the schema: {}", page_url, String::from_utf8_lossy(&body)));
+                    return Err(Error::with_errno(NssStatus::Unavailable, EINVAL));
+                }
+            }
+            token = match page.as_ref().and_then(|page| page.get("nextPageToken")?.as_str()) {
+                Some(next) if all_pages && !next.is_empty() => next.to_string(),
+                _ => break,
+            };
+        }
+        Ok(Some(found))
+    }
+
+    fn fetch(url: &str) -> Result<Found> {
+        if let Some(found) = Self::cached(url) {
+            return Ok(found);
+        }
+        let found = fetch(url, C::HEADERS, C::TIMEOUT).map_err(|err| {
+            if err.kind() != io::ErrorKind::ConnectionRefused {
+                diag::log(format_args!("can't get {}: {}", url, err));
+            }
+            Error::with_errno(NssStatus::Unavailable, err.raw_os_error().unwrap_or(EIO))
+        })?;
+        Self::remember(url.to_string(), found.clone());
+        Ok(found)
+    }
+
+    fn cached(url: &str) -> Option<Found> {
+        let now = Instant::now();
+        let mut cache = CACHE.lock();
+        cache.retain(|&(_, expires, _)| expires > now);
+        cache.iter().find(|(u, _, _)| u == url).map(|(_, _, found)| found.clone())
+    }
+
+    fn remember(url: String, found: Found) {
+        let ttl = if found.is_some() { C::CACHE_TTL } else { C::NEGATIVE_TTL };
+        if ttl == Duration::ZERO {
+            return;
+        }
+        let mut cache = CACHE.lock();
+        if cache.len() >= MAX_CACHED {
+            cache.remove(0);
+        }
+        cache.push((url, Instant::now() + ttl, found));
+    }
+
+    /// `group`, with its members filled in.
+    fn with_members(mut group: GroupEntry<'static>) -> Result<GroupEntry<'static>> {
+        let name = group.name.to_bytes().to_vec();
+        group.members = Self::get("users", &[("groupname", &name)], true, usernames)?.unwrap_or_default();
+        Ok(group)
+    }
+
+    fn group(query: &[(&str, &[u8])], keep: impl Fn(&GroupEntry<'_>) -> bool) -> Result<Option<GroupEntry<'static>>> {
+        let found = Self::get("groups", query, false, groups)?.unwrap_or_default();
+        found.into_iter().find(keep).map(Self::with_members).transpose()
+    }
+}
+
+impl<C: OsLoginConfig> PasswdService for OsLoginService<C> {
+    fn getpwnam_r(name: &CStr) -> Result<Option<PasswdEntry<'_>>> {
+        let found = Self::get("users", &[("username", name.to_bytes())], false, accounts)?;
+        Ok(found.unwrap_or_default().into_iter().find(|entry| *entry.name == *name))
+    }
+
+    fn getpwuid_r(uid: uid_t) -> Result<Option<PasswdEntry<'static>>> {
+        let found = Self::get("users", &[("uid", uid.to_string().as_bytes())], false, accounts)?;
+        Ok(found.unwrap_or_default().into_iter().find(|entry| entry.uid == uid))
+    }
+
+    fn setpwent() -> Result<Entries<PasswdEntry<'static>>> {
+        if !C::ENUMERATE {
+            return Ok(no_entries());
+        }
+        let found = Self::get("users", &[], true, accounts)?.unwrap_or_default();
+        Ok(Box::new(found.into_iter().map(Ok)))
+    }
+
+    fn on_fork_child() {
+        CACHE.reset();
+    }
+}
+
+impl<C: OsLoginConfig> GroupService for OsLoginService<C> {
+    fn getgrnam_r(name: &CStr) -> Result<Option<GroupEntry<'_>>> {
+        Self::group(&[("groupname", name.to_bytes())], |group| *group.name == *name)
+    }
+
+    fn getgrgid_r(gid: gid_t) -> Result<Option<GroupEntry<'static>>> {
+        Self::group(&[("gid", gid.to_string().as_bytes())], |group| group.gid == gid)
+    }
+
+    fn setgrent() -> Result<Entries<GroupEntry<'static>>> {
+        if !C::ENUMERATE {
+            return Ok(no_entries());
+        }
+        let found = Self::get("groups", &[], true, groups)?.unwrap_or_default();
+        Ok(Box::new(found.into_iter().map(Self::with_members)))
+    }
+
+    fn initgroups_dyn(user: &CStr, _group: gid_t) -> Result<Option<Vec<gid_t>>> {
+        let found = Self::get("groups", &[("username", user.to_bytes())], true, groups)?;
+        Ok(found.map(|groups| groups.iter().map(|group| group.gid).collect()))
+    }
+
+    fn on_fork_child() {
+        CACHE.reset();
+    }
+}
+
+#[test]
+fn test_oslogin_lookups() {
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    struct Directory;
+    impl OsLoginConfig for Directory {
+        const URL_VAR: Option<&'static str> = Some("NSS_OSLOGIN_TEST_URL");
+        const ENUMERATE: bool = true;
+    }
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    std::env::set_var("NSS_OSLOGIN_TEST_URL", format!("http://{}/oslogin/", listener.local_addr().unwrap()));
+    let server = thread::spawn(move || {
+        let mut requests = vec![];
+        for _ in 0..7 {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = [0_u8; 1024];
+            let len = stream.read(&mut request).unwrap();
+            let request = String::from_utf8(request[..len].to_vec()).unwrap();
+            let path = request.split(' ').nth(1).unwrap().to_string();
+            let body = match &path[..] {
+                "/oslogin/users?username=alice" => r#"{"loginProfiles": [{"posixAccounts": [
+                    {"username": "alice_win", "uid": "1002", "gid": "1002", "homeDirectory": "C:\\",
+                     "operatingSystemType": "WINDOWS"},
+                    {"username": "alice", "uid": "1001", "gid": 1001, "homeDirectory": "/home/alice",
+                     "primary": true}]}]}"#,
+                "/oslogin/groups?groupname=staff" => r#"{"posixGroups": [{"name": "staff", "gid": "50"}]}"#,
+                "/oslogin/users?groupname=staff&pagesize=1000" => r#"{"usernames": ["alice"], "nextPageToken": "p/2"}"#,
+                "/oslogin/users?groupname=staff&pagesize=1000&pagetoken=p%2F2" => r#"{"usernames": ["bob"]}"#,
+                "/oslogin/groups?username=alice&pagesize=1000" => r#"{"posixGroups": [{"name": "staff", "gid": "50"},
+                    {"name": "ops", "gid": 60}]}"#,
+                "/oslogin/groups?pagesize=1000" => r#"{"posixGroups": []}"#,
+                _ => "",
+            };
+            let response = if body.is_empty() {
+                "HTTP/1.0 404 Not Found\r\n\r\n".to_string()
+            } else {
+                format!("HTTP/1.0 200 OK\r\n\r\n{}", body)
+            };
+            stream.write_all(response.as_bytes()).unwrap();
+            requests.push(request);
+        }
+        requests
+    });
+
+    let name = |s: &'static [u8]| CStr::from_bytes_with_nul(s).unwrap();
+    let alice = OsLoginService::<Directory>::getpwnam_r(name(b"alice\0")).unwrap().unwrap();
+    assert_eq!((alice.uid, alice.gid, alice.shell.to_str()), (1001, 1001, Ok("/bin/bash")));
+    assert!(OsLoginService::<Directory>::getpwnam_r(name(b"alice\0")).unwrap().is_some());
+    assert!(OsLoginService::<Directory>::getpwnam_r(name(b"mallory\0")).unwrap().is_none());
+    assert!(OsLoginService::<Directory>::getpwnam_r(name(b"mallory\0")).unwrap().is_none());
+    let staff = OsLoginService::<Directory>::getgrnam_r(name(b"staff\0")).unwrap().unwrap();
+    assert_eq!((staff.gid, staff.members.len()), (50, 2));
+    let gids = OsLoginService::<Directory>::initgroups_dyn(name(b"alice\0"), 1001).unwrap();
+    assert_eq!(gids, Some(vec![50, 60]));
+    assert_eq!(OsLoginService::<Directory>::setgrent().unwrap().count(), 0);
+
+    // The repeated lookups of alice and mallory came from the cache.
+    let requests = server.join().unwrap();
+    assert!(requests[0].contains("\r\nMetadata-Flavor: Google\r\n"));
+    assert!(requests[1].starts_with("GET /oslogin/users?username=mallory "));
+}
@@ -0,0 +1,40 @@
+//! Keeping the module loaded.
+//!
+//! glibc never unloads NSS modules, but other programs that load them can:
+//! a program that `dlopen`s a module directly, or a test harness, may
+//! `dlclose` it while a thread it started is still running or a callback it
+//! registered is still installed, and the next time that code runs, the
+//! process crashes. A module with threads or callbacks that outlive a call
+//! should pin itself in memory first.
+
+use std::mem;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Once;
+
+static PIN: Once = Once::new();
+static PINNED: AtomicBool = AtomicBool::new(false);
+
+/// Mark the shared library containing this crate `RTLD_NODELETE`, so that
+/// `dlclose` never unloads it. Call this before starting a thread or
+/// registering a callback that can outlive the current call; the glue does
+/// this itself before running a lookup on another thread (see
+/// `NameService::LOOKUP_TIMEOUT`). Fork hooks don't need it: they are
+/// removed when the module is unloaded.
+///
+/// Only the first call does anything. Returns false if the module couldn't
+/// be pinned, for example because it isn't a shared library at all.
+pub fn pin_module() -> bool {
+    PIN.call_once(|| PINNED.store(unsafe { pin_self() }, Ordering::Release));
+    PINNED.load(Ordering::Acquire)
+}
+
+unsafe fn pin_self() -> bool {
+    let mut info: libc::Dl_info = mem::zeroed();
+    if libc::dladdr(pin_module as *const libc::c_void, &mut info) == 0 || info.dli_fname.is_null() {
+        return false;
+    }
+    // Reopening a library that's already loaded, with RTLD_NOLOAD, just
+    // adds the flags. The extra reference is never released.
+    let handle = libc::dlopen(info.dli_fname, libc::RTLD_NOW | libc::RTLD_NOLOAD | libc::RTLD_NODELETE);
+    !handle.is_null()
+}
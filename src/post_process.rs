@@ -0,0 +1,208 @@
+//! Adjusting another service's answers before they are passed on.
+
+use crate::errors::Result;
+use crate::ffi::{gid_t, uid_t};
+use crate::interfaces::{AddressFamily, Entries, GroupEntry, GroupService, HostAddresses, HostEntry,
+                        HostEntryWithTtl, HostLimits, NameService, PasswdEntry, PasswdService, ShadowEntry,
+                        ShadowService};
+use std::ffi::CStr;
+use std::marker::PhantomData;
+use std::net::IpAddr;
+use std::time::Duration;
+
+/// The changes a `PostProcessed` service makes to what it finds. Each
+/// method is called on every entry of its kind that the service finds,
+/// whether by lookup or enumeration, and does nothing by default.
+pub trait PostProcess: 'static {
+    /// Change a host found by `gethostbyname_r`, `gethostbyname2_r`,
+    /// `gethostbyname3_r`, `gethostbyaddr_r`, `gethostbyaddr2_r`, or
+    /// enumeration.
+    fn host(_entry: &mut HostEntry<'_>) {}
+
+    /// Change the addresses found by `gethostbyname4_r`.
+    fn addresses(_addrs: &mut HostAddresses<'_>) {}
+
+    /// Change a user.
+    fn passwd(_entry: &mut PasswdEntry<'_>) {}
+
+    /// Change a group.
+    fn group(_entry: &mut GroupEntry<'_>) {}
+
+    /// Change the groups `initgroups_dyn` found for a user.
+    fn groups(_gids: &mut Vec<gid_t>) {}
+
+    /// Change a shadow password entry.
+    fn shadow(_entry: &mut ShadowEntry<'_>) {}
+}
+
+/// The service `S`, with its answers changed by `P` before the glue writes
+/// them out, so that a policy tweak doesn't mean changing `S`:
+///
+/// ```ignore
+/// struct PrivateOnly;
+///
+/// impl PostProcess for PrivateOnly {
+///     fn host(entry: &mut HostEntry<'_>) {
+///         if let HostAddressList::V4(addrs) = &mut entry.addr_list {
+///             addrs.retain(|addr| addr.is_private());
+///         }
+///     }
+/// }
+///
+/// nssglue_hosts!("corp", PostProcessed<ConsulService, PrivateOnly>);
+/// ```
+///
+/// Sorting addresses, keeping only the first few, or adding an alias work
+/// the same way. Only what `S` finds is changed; errors and names it
+/// doesn't find pass through. A host left with no addresses is reported
+/// the way `S::ALLOW_EMPTY_ADDRESS_LIST` says, as are `S`'s other settings,
+/// such as `LOOKUP_TIMEOUT`.
+pub struct PostProcessed<S, P>(PhantomData<(S, P)>);
+
+fn change<T>(found: Result<Option<T>>, f: fn(&mut T)) -> Result<Option<T>> {
+    Ok(found?.map(|mut found| {
+        f(&mut found);
+        found
+    }))
+}
+
+fn change_entries<E: 'static>(entries: Result<Entries<E>>, f: fn(&mut E)) -> Result<Entries<E>> {
+    Ok(Box::new(entries?.map(move |entry| {
+        entry.map(|mut entry| {
+            f(&mut entry);
+            entry
+        })
+    })))
+}
+
+impl<S: NameService, P: PostProcess> NameService for PostProcessed<S, P> {
+    const ALLOW_EMPTY_ADDRESS_LIST: bool = S::ALLOW_EMPTY_ADDRESS_LIST;
+    const LOOKUP_TIMEOUT: Option<Duration> = S::LOOKUP_TIMEOUT;
+    const LIMITS: HostLimits = S::LIMITS;
+    const VALIDATE_HOSTNAMES: bool = S::VALIDATE_HOSTNAMES;
+
+    fn gethostbyname_r(name: &CStr) -> Result<Option<HostEntry<'_>>> {
+        change(S::gethostbyname_r(name), P::host)
+    }
+
+    fn gethostbyname2_r(name: &CStr, af: AddressFamily) -> Result<Option<HostEntry<'_>>> {
+        change(S::gethostbyname2_r(name, af), P::host)
+    }
+
+    fn gethostbyaddr_r(addr: &IpAddr) -> Result<Option<HostEntry<'_>>> {
+        change(S::gethostbyaddr_r(addr), P::host)
+    }
+
+    fn gethostbyname3_r(name: &CStr, af: AddressFamily) -> Result<Option<HostEntryWithTtl<'_>>> {
+        change(S::gethostbyname3_r(name, af), |found| P::host(&mut found.entry))
+    }
+
+    fn gethostbyname4_r(name: &CStr) -> Result<Option<HostAddresses<'_>>> {
+        change(S::gethostbyname4_r(name), P::addresses)
+    }
+
+    fn gethostbyaddr2_r(addr: &IpAddr) -> Result<Option<HostEntryWithTtl<'_>>> {
+        change(S::gethostbyaddr2_r(addr), |found| P::host(&mut found.entry))
+    }
+
+    fn sethostent(stay_open: bool) -> Result<Entries<HostEntry<'static>>> {
+        change_entries(S::sethostent(stay_open), P::host)
+    }
+
+    fn on_fork_child() {
+        S::on_fork_child()
+    }
+}
+
+impl<S: PasswdService, P: PostProcess> PasswdService for PostProcessed<S, P> {
+    const LOOKUP_TIMEOUT: Option<Duration> = S::LOOKUP_TIMEOUT;
+
+    fn getpwnam_r(name: &CStr) -> Result<Option<PasswdEntry<'_>>> {
+        change(S::getpwnam_r(name), P::passwd)
+    }
+
+    fn getpwuid_r(uid: uid_t) -> Result<Option<PasswdEntry<'static>>> {
+        change(S::getpwuid_r(uid), P::passwd)
+    }
+
+    fn setpwent() -> Result<Entries<PasswdEntry<'static>>> {
+        change_entries(S::setpwent(), P::passwd)
+    }
+
+    fn on_fork_child() {
+        S::on_fork_child()
+    }
+}
+
+impl<S: GroupService, P: PostProcess> GroupService for PostProcessed<S, P> {
+    const LOOKUP_TIMEOUT: Option<Duration> = S::LOOKUP_TIMEOUT;
+
+    fn getgrnam_r(name: &CStr) -> Result<Option<GroupEntry<'_>>> {
+        change(S::getgrnam_r(name), P::group)
+    }
+
+    fn getgrgid_r(gid: gid_t) -> Result<Option<GroupEntry<'static>>> {
+        change(S::getgrgid_r(gid), P::group)
+    }
+
+    fn setgrent() -> Result<Entries<GroupEntry<'static>>> {
+        change_entries(S::setgrent(), P::group)
+    }
+
+    fn initgroups_dyn(user: &CStr, group: gid_t) -> Result<Option<Vec<gid_t>>> {
+        change(S::initgroups_dyn(user, group), P::groups)
+    }
+
+    fn on_fork_child() {
+        S::on_fork_child()
+    }
+}
+
+impl<S: ShadowService, P: PostProcess> ShadowService for PostProcessed<S, P> {
+    const LOOKUP_TIMEOUT: Option<Duration> = S::LOOKUP_TIMEOUT;
+
+    fn getspnam_r(name: &CStr) -> Result<Option<ShadowEntry<'_>>> {
+        change(S::getspnam_r(name), P::shadow)
+    }
+
+    fn setspent() -> Result<Entries<ShadowEntry<'static>>> {
+        change_entries(S::setspent(), P::shadow)
+    }
+
+    fn on_fork_child() {
+        S::on_fork_child()
+    }
+}
+
+#[test]
+fn test_post_processed() {
+    use crate::interfaces::HostAddressList;
+    use crate::testing::{gethostbyname2, gethostbyname4, FixedHosts};
+    use std::borrow::Cow;
+
+    struct Tweak;
+    impl PostProcess for Tweak {
+        fn host(entry: &mut HostEntry<'_>) {
+            entry.aliases.push(Cow::Borrowed(CStr::from_bytes_with_nul(b"extra.test\0").unwrap()));
+            if let HostAddressList::V4(addrs) = &mut entry.addr_list {
+                addrs.retain(|addr| !addr.is_documentation());
+            }
+        }
+
+        fn addresses(addrs: &mut HostAddresses<'_>) {
+            addrs.addrs.sort_by_key(|addr| addr.is_ipv4());
+            addrs.addrs.truncate(1);
+        }
+    }
+    type Hosts = PostProcessed<FixedHosts, Tweak>;
+
+    let entry = gethostbyname2::<Hosts>("localhost", AddressFamily::Ipv4).unwrap().unwrap();
+    assert_eq!(entry.aliases.last().map(|alias| alias.to_str()), Some(Ok("extra.test")));
+    assert_eq!(entry.addr_list.len(), 1);
+    let entry = Hosts::gethostbyname2_r(CStr::from_bytes_with_nul(b"host.test\0").unwrap(), AddressFamily::Ipv4);
+    assert!(entry.unwrap().unwrap().addr_list.is_empty());
+    let addrs = gethostbyname4::<Hosts>("host.test").unwrap().unwrap();
+    assert_eq!(addrs.addrs, ["2001:db8::1".parse::<IpAddr>().unwrap()]);
+    let extra = |entry: &HostEntry<'_>| entry.aliases.iter().any(|alias| alias.to_str() == Ok("extra.test"));
+    assert!(Hosts::sethostent(false).unwrap().all(|entry| extra(&entry.unwrap())));
+}
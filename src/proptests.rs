@@ -0,0 +1,184 @@
+//! Property tests for the writers: for any entry, any buffer size, and any
+//! alignment of the buffer's start, a write either succeeds and reads back
+//! as the same entry, or fails with `ERANGE` and leaves the caller's struct
+//! as it was. Nothing outside the buffer and the struct is ever written.
+//!
+//! These use only Rust-owned buffers, so they also run under Miri, which
+//! checks the writers for undefined behavior, with fewer cases.
+
+use crate::errors::Result;
+use crate::ffi::{c_char, gaih_addrtuple, group, hostent, passwd};
+use crate::interfaces::{GroupEntry, HostAddressList, HostAddresses, HostEntry, HostLimits, PasswdEntry};
+use proptest::prelude::*;
+use std::borrow::Cow;
+use std::ffi::{CStr, CString};
+use std::mem::{self, MaybeUninit};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::ptr;
+
+/// Bytes on each side of the buffer, and the fill for the caller's struct.
+const GUARD: usize = 32;
+const CANARY: u8 = 0xa5;
+
+fn c_string() -> impl Strategy<Value = CString> {
+    prop::collection::vec(1_u8.., 0..40).prop_map(|bytes| CString::new(bytes).unwrap())
+}
+
+fn c_strings() -> impl Strategy<Value = Vec<CString>> {
+    prop::collection::vec(c_string(), 0..10)
+}
+
+fn borrowed(strings: &[CString]) -> Vec<Cow<'_, CStr>> {
+    strings.iter().map(|s| Cow::Borrowed(s.as_c_str())).collect()
+}
+
+fn addr_list() -> impl Strategy<Value = HostAddressList> {
+    prop_oneof![
+        prop::collection::vec(any::<u32>().prop_map(Ipv4Addr::from), 0..20).prop_map(HostAddressList::V4),
+        prop::collection::vec(any::<u128>().prop_map(Ipv6Addr::from), 0..20).prop_map(HostAddressList::V6),
+    ]
+}
+
+fn ip_addr() -> impl Strategy<Value = IpAddr> {
+    prop_oneof![
+        any::<u32>().prop_map(|ip| IpAddr::from(Ipv4Addr::from(ip))),
+        any::<u128>().prop_map(|ip| IpAddr::from(Ipv6Addr::from(ip))),
+    ]
+}
+
+/// Call `write` with a buffer of `buflen` bytes starting `offset` bytes
+/// past an aligned address, and a struct full of `CANARY`. On success,
+/// `read` gets the struct while the buffer is still around.
+fn check_write<S, W, R>(buflen: usize, offset: usize, write: W, read: R) -> std::result::Result<(), TestCaseError>
+where
+    W: FnOnce(*mut S, *mut c_char, usize) -> Result<()>,
+    R: FnOnce(&S),
+{
+    let mut backing = vec![0_u64; (GUARD * 2 + offset + buflen).div_ceil(8)];
+    let bytes = unsafe {
+        std::slice::from_raw_parts_mut(backing.as_mut_ptr() as *mut u8, backing.len() * 8)
+    };
+    bytes.fill(CANARY);
+    let start = GUARD + offset;
+    let buffer = bytes[start..].as_mut_ptr() as *mut c_char;
+
+    let mut result = MaybeUninit::<S>::uninit();
+    unsafe {
+        ptr::write_bytes(result.as_mut_ptr(), CANARY, 1);
+    }
+    match write(result.as_mut_ptr(), buffer, buflen) {
+        Ok(()) => read(unsafe { &*result.as_ptr() }),
+        Err(err) => {
+            prop_assert!(err.is_insufficient_buffer(), "unexpected error {:?}", err);
+            let result_bytes = unsafe {
+                std::slice::from_raw_parts(result.as_ptr() as *const u8, mem::size_of::<S>())
+            };
+            prop_assert!(result_bytes.iter().all(|&b| b == CANARY), "failed write changed the result");
+        }
+    }
+    prop_assert!(bytes[..start].iter().all(|&b| b == CANARY), "wrote before the buffer");
+    prop_assert!(bytes[start + buflen..].iter().all(|&b| b == CANARY), "wrote after the buffer");
+    Ok(())
+}
+
+fn same_addrs(a: &HostAddressList, b: &HostAddressList) -> bool {
+    match (a, b) {
+        (HostAddressList::V4(a), HostAddressList::V4(b)) => a == b,
+        (HostAddressList::V6(a), HostAddressList::V6(b)) => a == b,
+        _ => false,
+    }
+}
+
+/// Miri is thousands of times slower, and keeps tests away from the file
+/// system, where proptest saves failing cases.
+fn config() -> ProptestConfig {
+    if cfg!(miri) {
+        ProptestConfig { cases: 8, failure_persistence: None, ..ProptestConfig::default() }
+    } else {
+        ProptestConfig::default()
+    }
+}
+
+proptest! {
+    #![proptest_config(config())]
+
+    #[test]
+    fn hostent_round_trips(
+        name in c_string(),
+        aliases in c_strings(),
+        addr_list in addr_list(),
+        buflen in 0_usize..2048,
+        offset in 0_usize..16,
+    ) {
+        let entry = HostEntry { name: Cow::Borrowed(name.as_c_str()), aliases: borrowed(&aliases), addr_list };
+        check_write(buflen, offset,
+            |result: *mut hostent, buffer, buflen| entry.write_to(&HostLimits::UNLIMITED, result, buffer, buflen),
+            |result| {
+                let copy = unsafe { HostEntry::read_from(result) };
+                assert_eq!(copy.name, entry.name);
+                assert_eq!(copy.aliases, entry.aliases);
+                assert!(same_addrs(&copy.addr_list, &entry.addr_list));
+            })?;
+    }
+
+    #[test]
+    fn addrtuples_round_trip(
+        name in c_string(),
+        addrs in prop::collection::vec(ip_addr(), 1..20),
+        buflen in 0_usize..2048,
+        offset in 0_usize..16,
+    ) {
+        let entry = HostAddresses { name: Cow::Borrowed(name.as_c_str()), addrs, ttl: None };
+        check_write(buflen, offset,
+            |pat: *mut *mut gaih_addrtuple, buffer, buflen| {
+                entry.write_to(&HostLimits::UNLIMITED, pat, buffer, buflen)
+            },
+            |&pat| {
+                let copy = unsafe { HostAddresses::read_from(pat) };
+                assert_eq!(copy.name, entry.name);
+                assert_eq!(copy.addrs, entry.addrs);
+            })?;
+    }
+
+    #[test]
+    fn passwd_round_trips(
+        strings in prop::collection::vec(c_string(), 5),
+        uid in any::<u32>(),
+        gid in any::<u32>(),
+        buflen in 0_usize..512,
+        offset in 0_usize..16,
+    ) {
+        let s = |i: usize| Cow::Borrowed(strings[i].as_c_str());
+        let entry = PasswdEntry { name: s(0), passwd: s(1), uid, gid, gecos: s(2), dir: s(3), shell: s(4) };
+        check_write(buflen, offset,
+            |result: *mut passwd, buffer, buflen| entry.write_to(result, buffer, buflen),
+            |result| {
+                let copy = unsafe { PasswdEntry::read_from(result) };
+                assert_eq!((&copy.name, &copy.passwd, copy.uid, copy.gid), (&entry.name, &entry.passwd, uid, gid));
+                assert_eq!((&copy.gecos, &copy.dir, &copy.shell), (&entry.gecos, &entry.dir, &entry.shell));
+            })?;
+    }
+
+    #[test]
+    fn group_round_trips(
+        name in c_string(),
+        members in c_strings(),
+        gid in any::<u32>(),
+        buflen in 0_usize..1024,
+        offset in 0_usize..16,
+    ) {
+        let x = CString::new("x").unwrap();
+        let entry = GroupEntry {
+            name: Cow::Borrowed(name.as_c_str()),
+            passwd: Cow::Borrowed(x.as_c_str()),
+            gid,
+            members: borrowed(&members),
+        };
+        check_write(buflen, offset,
+            |result: *mut group, buffer, buflen| entry.write_to(result, buffer, buflen),
+            |result| {
+                let copy = unsafe { GroupEntry::read_from(result) };
+                assert_eq!((&copy.name, copy.gid, &copy.members), (&entry.name, gid, &entry.members));
+            })?;
+    }
+}
@@ -0,0 +1,143 @@
+//! Checking that results point only into the caller's buffer.
+//!
+//! Everything a result points to must live in the buffer the caller passed
+//! in, since that's the only memory the caller knows to keep around. A
+//! writer that slips in a pointer to a temporary or to the service's own
+//! data produces a result that works in tests and crashes later. In debug
+//! builds, or with the `check-pointers` feature, the writers walk each
+//! result they fill in and panic if it points anywhere else, and the glue
+//! reports the panic like any other.
+
+use crate::ffi::{c_char, gaih_addrtuple, group, hostent, passwd, spwd};
+use std::ptr;
+
+/// Whether the checks run.
+pub(crate) const ENABLED: bool = cfg!(any(debug_assertions, feature = "check-pointers"));
+
+/// The caller's buffer.
+pub(crate) struct Buffer {
+    start: usize,
+    end: usize,
+}
+
+type Check = Result<(), &'static str>;
+
+impl Buffer {
+    pub(crate) fn new(buffer: *mut c_char, buflen: usize) -> Buffer {
+        // Saturate rather than wrap: on a 32-bit target a bogus `buflen` can
+        // easily reach past the top of the address space.
+        Buffer { start: buffer as usize, end: (buffer as usize).saturating_add(buflen) }
+    }
+
+    /// Check that the `len` bytes at `p` are in the buffer.
+    fn check_bytes(&self, p: *const u8, len: usize, what: &'static str) -> Check {
+        let p = p as usize;
+        if p >= self.start && p <= self.end && len <= self.end - p {
+            Ok(())
+        } else {
+            Err(what)
+        }
+    }
+
+    /// Check that `s` is a C string, NUL included, in the buffer.
+    fn check_c_str(&self, s: *const c_char, what: &'static str) -> Check {
+        self.check_bytes(s as *const u8, 0, what)?;
+        let room = self.end - s as usize;
+        // Look for the NUL without reading past the end of the buffer.
+        let bytes = unsafe { std::slice::from_raw_parts(s as *const u8, room) };
+        match bytes.iter().position(|&b| b == 0) {
+            Some(_) => Ok(()),
+            None => Err(what),
+        }
+    }
+
+    /// Check a null-terminated array of pointers, calling `check_item` on
+    /// each non-null pointer in it.
+    fn check_array<T>(
+        &self,
+        array: *const *mut T,
+        what: &'static str,
+        mut check_item: impl FnMut(*const T) -> Check,
+    ) -> Check {
+        let mut p = array;
+        loop {
+            self.check_bytes(p as *const u8, std::mem::size_of::<*mut T>(), what)?;
+            let item = unsafe { *p };
+            if item.is_null() {
+                return Ok(());
+            }
+            check_item(item)?;
+            p = p.wrapping_add(1);
+        }
+    }
+
+    pub(crate) unsafe fn check_hostent(&self, h: &hostent) -> Check {
+        self.check_c_str(h.h_name, "h_name")?;
+        self.check_array(h.h_aliases, "h_aliases", |alias| {
+            self.check_c_str(alias, "h_aliases[i]")
+        })?;
+        let len = h.h_length as usize;
+        self.check_array(h.h_addr_list, "h_addr_list", |addr| {
+            self.check_bytes(addr as *const u8, len, "h_addr_list[i]")
+        })
+    }
+
+    pub(crate) unsafe fn check_addrtuples(&self, mut tuple: *const gaih_addrtuple) -> Check {
+        while !tuple.is_null() {
+            self.check_bytes(tuple as *const u8, std::mem::size_of::<gaih_addrtuple>(), "pat")?;
+            let t = ptr::read(tuple);
+            if !t.name.is_null() {
+                self.check_c_str(t.name, "pat->name")?;
+            }
+            tuple = t.next;
+        }
+        Ok(())
+    }
+
+    pub(crate) unsafe fn check_passwd(&self, pw: &passwd) -> Check {
+        self.check_c_str(pw.pw_name, "pw_name")?;
+        self.check_c_str(pw.pw_passwd, "pw_passwd")?;
+        self.check_c_str(pw.pw_gecos, "pw_gecos")?;
+        self.check_c_str(pw.pw_dir, "pw_dir")?;
+        self.check_c_str(pw.pw_shell, "pw_shell")
+    }
+
+    pub(crate) unsafe fn check_group(&self, gr: &group) -> Check {
+        self.check_c_str(gr.gr_name, "gr_name")?;
+        self.check_c_str(gr.gr_passwd, "gr_passwd")?;
+        self.check_array(gr.gr_mem, "gr_mem", |member| self.check_c_str(member, "gr_mem[i]"))
+    }
+
+    pub(crate) unsafe fn check_spwd(&self, sp: &spwd) -> Check {
+        self.check_c_str(sp.sp_namp, "sp_namp")?;
+        self.check_c_str(sp.sp_pwdp, "sp_pwdp")
+    }
+}
+
+/// Panic if `check` failed. `record` is the type of the result, for the
+/// message.
+pub(crate) fn expect_in_buffer(check: Check, record: &str) {
+    if let Err(field) = check {
+        panic!("bug in nsswitch_service: {}: {} points outside the caller's buffer", record, field);
+    }
+}
+
+#[test]
+fn test_pointer_outside_buffer() {
+    let mut buffer = [0 as c_char; 16];
+    let elsewhere = b"elsewhere\0";
+    let mut aliases = [ptr::null_mut::<c_char>()];
+    let mut h: hostent = unsafe { std::mem::zeroed() };
+    h.h_name = buffer.as_mut_ptr();
+    h.h_aliases = buffer.as_mut_ptr() as *mut *mut c_char;
+    h.h_addr_list = buffer.as_mut_ptr() as *mut *mut c_char;
+    let check = Buffer::new(buffer.as_mut_ptr(), buffer.len());
+    unsafe {
+        assert_eq!(check.check_hostent(&h), Ok(()));
+        h.h_name = elsewhere.as_ptr() as *mut c_char;
+        assert_eq!(check.check_hostent(&h), Err("h_name"));
+        h.h_name = buffer.as_mut_ptr();
+        h.h_aliases = aliases.as_mut_ptr();
+        assert_eq!(check.check_hostent(&h), Err("h_aliases"));
+    }
+}
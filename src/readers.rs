@@ -0,0 +1,176 @@
+//! Reading C records back into entries, the reverse of what the glue does.
+//!
+//! Tests and tools use these to check what a module actually returned. The
+//! entries they return copy every string, so they outlive the caller's
+//! buffer.
+
+use crate::ffi::{c_char, gaih_addrtuple, group, hostent, passwd, spwd};
+use crate::interfaces::{GroupEntry, HostAddressList, HostAddresses, HostEntry, PasswdEntry, ShadowEntry};
+use libc::{AF_INET, AF_INET6};
+use std::borrow::Cow;
+use std::ffi::CStr;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::ptr;
+
+unsafe fn owned(s: *const c_char) -> Cow<'static, CStr> {
+    Cow::Owned(CStr::from_ptr(s).to_owned())
+}
+
+/// Read a null-terminated array of C strings.
+unsafe fn read_c_str_array(mut p: *const *mut c_char) -> Vec<Cow<'static, CStr>> {
+    let mut strings = vec![];
+    while !(*p).is_null() {
+        strings.push(owned(*p));
+        p = p.add(1);
+    }
+    strings
+}
+
+impl HostEntry<'static> {
+    /// Read a `hostent`, such as one filled in by `gethostbyname2_r`.
+    ///
+    /// # Safety
+    ///
+    /// `h` must be a valid `hostent`: its strings, arrays, and addresses
+    /// must all be readable and properly terminated.
+    ///
+    /// # Panics
+    ///
+    /// If `h_addrtype` is neither `AF_INET` nor `AF_INET6`, or `h_length`
+    /// doesn't match it.
+    pub unsafe fn read_from(h: &hostent) -> HostEntry<'static> {
+        let mut addr_list = match (h.h_addrtype, h.h_length) {
+            (AF_INET, 4) => HostAddressList::V4(vec![]),
+            (AF_INET6, 16) => HostAddressList::V6(vec![]),
+            (af, len) => panic!("hostent has address family {} and length {}", af, len),
+        };
+        let mut addrs = h.h_addr_list as *const *const u8;
+        while !(*addrs).is_null() {
+            match addr_list {
+                HostAddressList::V4(ref mut v) => {
+                    v.push(Ipv4Addr::from(ptr::read_unaligned(*addrs as *const [u8; 4])));
+                }
+                HostAddressList::V6(ref mut v) => {
+                    v.push(Ipv6Addr::from(ptr::read_unaligned(*addrs as *const [u8; 16])));
+                }
+            }
+            addrs = addrs.add(1);
+        }
+        HostEntry {
+            name: owned(h.h_name),
+            aliases: read_c_str_array(h.h_aliases),
+            addr_list,
+        }
+    }
+}
+
+impl HostAddresses<'static> {
+    /// Read a list of `gaih_addrtuple`s, such as one filled in by
+    /// `gethostbyname4_r`. The name is the first tuple's. The list doesn't
+    /// include a TTL, so `ttl` is `None`.
+    ///
+    /// # Safety
+    ///
+    /// `pat` must point to a valid list: every `next` must be null or point
+    /// to another tuple, and the first tuple's `name` must be a C string.
+    ///
+    /// # Panics
+    ///
+    /// If a tuple's `family` is neither `AF_INET` nor `AF_INET6`.
+    pub unsafe fn read_from(mut pat: *const gaih_addrtuple) -> HostAddresses<'static> {
+        let name = owned((*pat).name);
+        let mut addrs = vec![];
+        while !pat.is_null() {
+            let mut octets = [0_u8; 16];
+            for (chunk, word) in octets.chunks_mut(4).zip((*pat).addr.iter()) {
+                chunk.copy_from_slice(&word.to_ne_bytes());
+            }
+            addrs.push(match (*pat).family {
+                AF_INET => IpAddr::from([octets[0], octets[1], octets[2], octets[3]]),
+                AF_INET6 => IpAddr::from(octets),
+                af => panic!("gaih_addrtuple has address family {}", af),
+            });
+            pat = (*pat).next;
+        }
+        HostAddresses { name, addrs, ttl: None }
+    }
+}
+
+impl PasswdEntry<'static> {
+    /// Read a `passwd`, such as one filled in by `getpwnam_r`.
+    ///
+    /// # Safety
+    ///
+    /// Every string in `pw` must be a readable C string.
+    pub unsafe fn read_from(pw: &passwd) -> PasswdEntry<'static> {
+        PasswdEntry {
+            name: owned(pw.pw_name),
+            passwd: owned(pw.pw_passwd),
+            uid: pw.pw_uid,
+            gid: pw.pw_gid,
+            gecos: owned(pw.pw_gecos),
+            dir: owned(pw.pw_dir),
+            shell: owned(pw.pw_shell),
+        }
+    }
+}
+
+impl GroupEntry<'static> {
+    /// Read a `group`, such as one filled in by `getgrnam_r`.
+    ///
+    /// # Safety
+    ///
+    /// Every string in `gr` must be a readable C string, and `gr_mem` a
+    /// null-terminated array of them.
+    pub unsafe fn read_from(gr: &group) -> GroupEntry<'static> {
+        GroupEntry {
+            name: owned(gr.gr_name),
+            passwd: owned(gr.gr_passwd),
+            gid: gr.gr_gid,
+            members: read_c_str_array(gr.gr_mem),
+        }
+    }
+}
+
+impl ShadowEntry<'static> {
+    /// Read a `spwd`, such as one filled in by `getspnam_r`.
+    ///
+    /// # Safety
+    ///
+    /// Both strings in `sp` must be readable C strings.
+    pub unsafe fn read_from(sp: &spwd) -> ShadowEntry<'static> {
+        ShadowEntry {
+            name: owned(sp.sp_namp),
+            passwd: owned(sp.sp_pwdp),
+            last_change: sp.sp_lstchg,
+            min: sp.sp_min,
+            max: sp.sp_max,
+            warn: sp.sp_warn,
+            inactive: sp.sp_inact,
+            expire: sp.sp_expire,
+            flag: sp.sp_flag,
+        }
+    }
+}
+
+#[test]
+fn test_host_round_trip() {
+    use crate::interfaces::HostLimits;
+
+    let entry = HostEntry {
+        name: Cow::Borrowed(CStr::from_bytes_with_nul(b"round.example\0").unwrap()),
+        aliases: vec![Cow::Borrowed(CStr::from_bytes_with_nul(b"trip\0").unwrap())],
+        addr_list: HostAddressList::V6(vec![Ipv6Addr::LOCALHOST, "2001:db8::7".parse().unwrap()]),
+    };
+    let mut result: hostent = unsafe { std::mem::zeroed() };
+    let mut buffer = [0 as c_char; 256];
+    entry.write_to(&HostLimits::DEFAULT, &mut result, buffer.as_mut_ptr(), buffer.len()).unwrap();
+
+    let copy = unsafe { HostEntry::read_from(&result) };
+    assert_eq!(copy.name, entry.name);
+    assert_eq!(copy.aliases, entry.aliases);
+    match (copy.addr_list, entry.addr_list) {
+        (HostAddressList::V6(a), HostAddressList::V6(b)) => assert_eq!(a, b),
+        other => panic!("address lists differ: {:?}", other),
+    }
+}
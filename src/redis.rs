@@ -0,0 +1,287 @@
+//! A ready-made `hosts` service that looks hosts up in Redis, where some
+//! other system keeps them.
+
+use crate::diag;
+use crate::errors::{Error, HostError, NssStatus, Result};
+use crate::interfaces::{AddressFamily, HostAddressList, HostEntry, HostEntryWithTtl, NameService};
+use libc::{EINVAL, EIO, ENOENT};
+use std::borrow::Cow;
+use std::convert::TryFrom;
+use std::ffi::{CStr, CString};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::marker::PhantomData;
+use std::net::{IpAddr, SocketAddr, TcpStream};
+use std::os::unix::net::UnixStream;
+use std::time::Duration;
+
+/// Where a `RedisService` finds the server, and how hosts are kept there.
+pub trait RedisConfig: 'static {
+    /// The server: an address, or the path of a Unix socket.
+    const SERVER: &'static str = "127.0.0.1:6379";
+
+    /// The password to `AUTH` with, if any.
+    const PASSWORD: Option<&'static str> = None;
+
+    /// The database to `SELECT`.
+    const DATABASE: u32 = 0;
+
+    /// The key with a name's addresses. `{}` stands for the name, in
+    /// lowercase and without a final dot.
+    const NAME_KEY: &'static str = "hosts:name:{}";
+
+    /// The key with the name for an address. `{}` stands for the address,
+    /// written as in `10.0.0.5` or `fd00::5`.
+    const ADDR_KEY: &'static str = "hosts:addr:{}";
+
+    /// How long to wait for the server.
+    const TIMEOUT: Duration = Duration::from_secs(1);
+}
+
+/// The usual settings: the local server, database 0, and keys like
+/// `hosts:name:db1`.
+pub struct RedisDefaults;
+
+impl RedisConfig for RedisDefaults {}
+
+/// A `hosts` service that reads each host from Redis when it's looked up,
+/// for environments where hosts come and go too fast for files or DNS, and
+/// a provisioning system already writes them to Redis:
+///
+/// ```ignore
+/// nssglue_hosts!("redis", RedisService);
+/// ```
+///
+/// A name's key holds its addresses, separated by spaces, and an address's
+/// key holds its name, followed by any aliases:
+///
+/// ```text
+/// SET hosts:name:db1 "10.0.0.5 fd00::5" EX 300
+/// SET hosts:addr:10.0.0.5 "db1 db" EX 300
+/// ```
+///
+/// If a key expires, its time to live is passed on as the entry's TTL, so
+/// callers that cache answers, like `nscd`, don't keep them longer than
+/// Redis does. A missing key means the host isn't found. If the server
+/// can't be reached or says something went wrong, lookups report
+/// `NssStatus::Unavailable`.
+pub struct RedisService<C = RedisDefaults>(PhantomData<C>);
+
+/// A reply, in RESP (the Redis serialization protocol).
+#[derive(Debug, PartialEq)]
+enum Reply {
+    Status(String),
+    Error(String),
+    Integer(i64),
+    Bulk(Option<Vec<u8>>),
+    Array(Option<Vec<Reply>>),
+}
+
+fn invalid(what: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("malformed Redis reply: {}", what))
+}
+
+/// Append `command` to `out`, as an array of bulk strings.
+fn encode(command: &[&[u8]], out: &mut Vec<u8>) {
+    out.extend_from_slice(format!("*{}\r\n", command.len()).as_bytes());
+    for arg in command {
+        out.extend_from_slice(format!("${}\r\n", arg.len()).as_bytes());
+        out.extend_from_slice(arg);
+        out.extend_from_slice(b"\r\n");
+    }
+}
+
+fn read_line<R: BufRead>(reader: &mut R) -> io::Result<String> {
+    let mut line = Vec::new();
+    reader.read_until(b'\n', &mut line)?;
+    if !line.ends_with(b"\r\n") {
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+    }
+    line.truncate(line.len() - 2);
+    String::from_utf8(line).map_err(|_| invalid("not UTF-8"))
+}
+
+fn read_reply<R: BufRead>(reader: &mut R) -> io::Result<Reply> {
+    let line = read_line(reader)?;
+    let (kind, rest) = line.split_at(line.len().min(1));
+    let length = || rest.parse::<i64>().map_err(|_| invalid("bad length"));
+    Ok(match kind {
+        "+" => Reply::Status(rest.to_string()),
+        "-" => Reply::Error(rest.to_string()),
+        ":" => Reply::Integer(length()?),
+        "$" => match usize::try_from(length()?) {
+            Err(_) => Reply::Bulk(None),
+            Ok(len) => {
+                let mut data = vec![0; len + 2];
+                reader.read_exact(&mut data)?;
+                data.truncate(len);
+                Reply::Bulk(Some(data))
+            }
+        },
+        "*" => match usize::try_from(length()?) {
+            Err(_) => Reply::Array(None),
+            Ok(len) => Reply::Array(Some((0..len).map(|_| read_reply(reader)).collect::<io::Result<_>>()?)),
+        },
+        _ => return Err(invalid("unknown type")),
+    })
+}
+
+/// Send all of `commands` at once and read their replies.
+fn exchange<S: Read + Write>(mut stream: S, commands: &[Vec<&[u8]>]) -> io::Result<Vec<Reply>> {
+    let mut request = Vec::new();
+    for command in commands {
+        encode(command, &mut request);
+    }
+    stream.write_all(&request)?;
+    stream.flush()?;
+    let mut reader = BufReader::new(stream);
+    commands.iter().map(|_| read_reply(&mut reader)).collect()
+}
+
+/// An entry for `name` with those of the addresses in `value` of family
+/// `af`.
+fn forward_entry<'a>(name: &'a CStr, af: AddressFamily, value: &[u8], ttl: Option<u32>)
+    -> Result<HostEntryWithTtl<'a>>
+{
+    let addrs = String::from_utf8_lossy(value).split_whitespace()
+        .filter_map(|word| word.parse().ok())
+        .collect::<Vec<IpAddr>>();
+    let addr_list = match af {
+        AddressFamily::Ipv4 => HostAddressList::V4(addrs.iter().filter_map(|addr| match *addr {
+            IpAddr::V4(ip) => Some(ip),
+            IpAddr::V6(_) => None,
+        }).collect()),
+        AddressFamily::Ipv6 => HostAddressList::V6(addrs.iter().filter_map(|addr| match *addr {
+            IpAddr::V4(_) => None,
+            IpAddr::V6(ip) => Some(ip),
+        }).collect()),
+    };
+    if addr_list.is_empty() {
+        return Err(Error::with_host(NssStatus::NotFound, ENOENT, HostError::NoData));
+    }
+    Ok(HostEntryWithTtl { entry: HostEntry { name: Cow::Borrowed(name), aliases: vec![], addr_list }, ttl })
+}
+
+/// An entry for `addr` with the name and aliases in `value`, or `None` if
+/// it has no name.
+fn reverse_entry(addr: &IpAddr, value: &[u8], ttl: Option<u32>) -> Option<HostEntryWithTtl<'static>> {
+    let mut names = value.split(u8::is_ascii_whitespace)
+        .filter(|word| !word.is_empty())
+        .filter_map(|word| CString::new(word).ok())
+        .map(Cow::Owned);
+    let addr_list = match *addr {
+        IpAddr::V4(ip) => HostAddressList::V4(vec![ip]),
+        IpAddr::V6(ip) => HostAddressList::V6(vec![ip]),
+    };
+    Some(HostEntryWithTtl { entry: HostEntry { name: names.next()?, aliases: names.collect(), addr_list }, ttl })
+}
+
+impl<C: RedisConfig> RedisService<C> {
+    fn failed(message: std::fmt::Arguments<'_>, errno: i32) -> Error {
+        diag::log(format_args!("can't ask Redis at {}: {}", C::SERVER, message));
+        Error::with_errno(NssStatus::Unavailable, errno)
+    }
+
+    /// The value of `key`, and its TTL if it has one, or `None` if there's
+    /// no such key.
+    fn get(key: &str) -> Result<Option<(Vec<u8>, Option<u32>)>> {
+        let database = C::DATABASE.to_string();
+        let mut commands = vec![];
+        if let Some(password) = C::PASSWORD {
+            commands.push(vec![&b"AUTH"[..], password.as_bytes()]);
+        }
+        if C::DATABASE != 0 {
+            commands.push(vec![&b"SELECT"[..], database.as_bytes()]);
+        }
+        commands.push(vec![&b"GET"[..], key.as_bytes()]);
+        commands.push(vec![&b"TTL"[..], key.as_bytes()]);
+
+        let replies = if C::SERVER.starts_with('/') {
+            UnixStream::connect(C::SERVER).and_then(|stream| {
+                stream.set_read_timeout(Some(C::TIMEOUT))?;
+                stream.set_write_timeout(Some(C::TIMEOUT))?;
+                exchange(&stream, &commands)
+            })
+        } else {
+            let addr: SocketAddr = C::SERVER.parse()
+                .map_err(|_| Self::failed(format_args!("not an address or a socket path"), EINVAL))?;
+            TcpStream::connect_timeout(&addr, C::TIMEOUT).and_then(|stream| {
+                stream.set_read_timeout(Some(C::TIMEOUT))?;
+                stream.set_write_timeout(Some(C::TIMEOUT))?;
+                exchange(&stream, &commands)
+            })
+        };
+        let replies = replies.map_err(|err| Self::failed(format_args!("{}", err), err.raw_os_error().unwrap_or(EIO)))?;
+        if let Some(Reply::Error(message)) = replies.iter().find(|reply| matches!(reply, Reply::Error(_))) {
+            return Err(Self::failed(format_args!("{}", message), EIO));
+        }
+        match &replies[replies.len() - 2..] {
+            [Reply::Bulk(None), _] => Ok(None),
+            [Reply::Bulk(Some(value)), Reply::Integer(ttl)] => {
+                // -1 means the key doesn't expire.
+                Ok(Some((value.clone(), u32::try_from(*ttl).ok())))
+            }
+            _ => Err(Self::failed(format_args!("unexpected reply to GET {}", key), EINVAL)),
+        }
+    }
+}
+
+impl<C: RedisConfig> NameService for RedisService<C> {
+    fn gethostbyname2_r(name: &CStr, af: AddressFamily) -> Result<Option<HostEntry<'_>>> {
+        Ok(Self::gethostbyname3_r(name, af)?.map(|found| found.entry))
+    }
+
+    fn gethostbyaddr_r(addr: &IpAddr) -> Result<Option<HostEntry<'_>>> {
+        Ok(Self::gethostbyaddr2_r(addr)?.map(|found| found.entry))
+    }
+
+    fn gethostbyname3_r(name: &CStr, af: AddressFamily) -> Result<Option<HostEntryWithTtl<'_>>> {
+        let key_name = match name.to_str() {
+            Ok(key_name) => key_name.trim_end_matches('.').to_ascii_lowercase(),
+            Err(_) => return Ok(None),
+        };
+        match Self::get(&C::NAME_KEY.replace("{}", &key_name))? {
+            Some((value, ttl)) => forward_entry(name, af, &value, ttl).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn gethostbyaddr2_r(addr: &IpAddr) -> Result<Option<HostEntryWithTtl<'_>>> {
+        Ok(Self::get(&C::ADDR_KEY.replace("{}", &addr.to_string()))?
+            .and_then(|(value, ttl)| reverse_entry(addr, &value, ttl)))
+    }
+}
+
+#[test]
+fn test_redis_exchange() {
+    let (client, mut server) = UnixStream::pair().unwrap();
+    server.write_all(b"+OK\r\n$16\r\n10.0.0.5 fd00::5\r\n:300\r\n$-1\r\n*2\r\n:1\r\n$0\r\n\r\n-ERR wrong\r\n").unwrap();
+    let replies = exchange(&client, &[
+        vec![&b"SELECT"[..], b"2"],
+        vec![&b"GET"[..], b"hosts:name:db1"],
+        vec![&b"TTL"[..], b"hosts:name:db1"],
+        vec![&b"GET"[..], b"hosts:name:nope"],
+        vec![&b"MGET"[..]],
+        vec![&b"AUTH"[..]],
+    ]).unwrap();
+    assert_eq!(replies, [
+        Reply::Status("OK".to_string()),
+        Reply::Bulk(Some(b"10.0.0.5 fd00::5".to_vec())),
+        Reply::Integer(300),
+        Reply::Bulk(None),
+        Reply::Array(Some(vec![Reply::Integer(1), Reply::Bulk(Some(vec![]))])),
+        Reply::Error("ERR wrong".to_string()),
+    ]);
+    drop(client);
+    let mut request = Vec::new();
+    server.read_to_end(&mut request).unwrap();
+    assert!(request.starts_with(b"*2\r\n$6\r\nSELECT\r\n$1\r\n2\r\n*2\r\n$3\r\nGET\r\n$14\r\nhosts:name:db1\r\n"));
+
+    let name = CStr::from_bytes_with_nul(b"db1\0").unwrap();
+    let found = forward_entry(name, AddressFamily::Ipv6, b"10.0.0.5 fd00::5", Some(300)).unwrap();
+    assert_eq!((found.entry.addr_list.len(), found.ttl), (1, Some(300)));
+    let err = forward_entry(name, AddressFamily::Ipv6, b"10.0.0.5", None).unwrap_err();
+    assert_eq!(err.host_error(), Some(HostError::NoData));
+    let found = reverse_entry(&"10.0.0.5".parse().unwrap(), b"db1 db", None).unwrap();
+    assert_eq!((found.entry.name.to_str(), found.entry.aliases.len()), (Ok("db1"), 1));
+    assert!(reverse_entry(&"10.0.0.5".parse().unwrap(), b" ", None).is_none());
+}
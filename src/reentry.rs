@@ -0,0 +1,47 @@
+//! Detecting lookups that recurse back into the same module.
+//!
+//! A service that calls `getaddrinfo` to find its server, or uses a client
+//! library that does, asks glibc to look up a name, and glibc may well ask
+//! this module again. Depending on the service, that deadlocks on the
+//! service's own locks or recurses until the stack runs out. So the glue
+//! marks each thread while it's inside the module, and refuses nested calls
+//! immediately; glibc then goes on to the next service in
+//! `/etc/nsswitch.conf` as usual.
+
+use std::cell::Cell;
+
+thread_local! {
+    static IN_LOOKUP: Cell<bool> = const { Cell::new(false) };
+}
+
+/// True if the current thread is inside a call to this module, that is, if
+/// a service method is on the stack. A backend that can reach the network
+/// in more than one way can use this to pick one that doesn't involve name
+/// lookups.
+pub fn in_lookup() -> bool {
+    IN_LOOKUP.try_with(Cell::get).unwrap_or(false)
+}
+
+/// Marks the current thread as inside the module until dropped.
+pub(crate) struct LookupGuard(());
+
+impl LookupGuard {
+    /// Mark the current thread as inside the module. Returns `None` if it
+    /// already is: the caller must refuse the call.
+    pub(crate) fn enter() -> Option<LookupGuard> {
+        // If thread-local storage is already gone, the thread is exiting,
+        // and there's nothing left to protect.
+        let entered = IN_LOOKUP.try_with(|in_lookup| !in_lookup.replace(true)).unwrap_or(true);
+        if entered {
+            Some(LookupGuard(()))
+        } else {
+            None
+        }
+    }
+}
+
+impl Drop for LookupGuard {
+    fn drop(&mut self) {
+        let _ = IN_LOOKUP.try_with(|in_lookup| in_lookup.set(false));
+    }
+}
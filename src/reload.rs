@@ -0,0 +1,402 @@
+//! Noticing when configuration files change, and reloading services that
+//! read them.
+
+use crate::errors::Result;
+use crate::ffi::{gid_t, uid_t};
+use crate::fork::ForkSafeMutex;
+use crate::interfaces::{AddressFamily, Entries, GroupEntry, GroupService, HostAddresses, HostEntry,
+                        HostEntryWithTtl, HostLimits, NameService, PasswdEntry, PasswdService, ShadowEntry,
+                        ShadowService};
+use std::any::TypeId;
+use std::collections::HashMap;
+use std::ffi::CStr;
+use std::fs;
+use std::marker::PhantomData;
+use std::net::IpAddr;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+/// How often a `ConfigWatcher` without inotify looks at its files.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// What identifies a version of a file: its device, inode, size, and times
+/// of last change. `None` if it doesn't exist or can't be examined.
+type Stamp = Option<(u64, u64, u64, i64, i64, i64, i64)>;
+
+fn stamp(path: &Path) -> Stamp {
+    fs::metadata(path).ok().map(|meta| {
+        (meta.dev(), meta.ino(), meta.size(), meta.mtime(), meta.mtime_nsec(), meta.ctime(), meta.ctime_nsec())
+    })
+}
+
+/// Tells when any of a set of files has changed: been written, replaced,
+/// created, or removed.
+///
+/// On Linux, this asks inotify about the files' directories, so a check is
+/// one `read` that usually finds nothing. Anywhere else, or if inotify
+/// can't be used, it looks at the files' metadata at most once a second.
+/// Either way, a file only counts as changed if its metadata did, so
+/// writing the same directory's other files doesn't count, while replacing
+/// a symbolic link to a file, as Kubernetes does with mounted ConfigMaps,
+/// does.
+///
+/// The inotify descriptor is close-on-exec, and closed when the watcher is
+/// dropped.
+pub struct ConfigWatcher {
+    files: Vec<(PathBuf, Stamp)>,
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    inotify: Option<inotify::Inotify>,
+    next_poll: Instant,
+}
+
+impl ConfigWatcher {
+    /// Start watching `files`. Changes made before this are not reported.
+    pub fn new(files: &[&str]) -> ConfigWatcher {
+        let files: Vec<(PathBuf, Stamp)> = files.iter()
+            .map(|&file| (PathBuf::from(file), stamp(Path::new(file))))
+            .collect();
+        ConfigWatcher {
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            inotify: inotify::Inotify::watch(files.iter().map(|(path, _)| path.as_path())),
+            files,
+            next_poll: Instant::now() + POLL_INTERVAL,
+        }
+    }
+
+    /// True if any of the files has changed since the last call to
+    /// `changed` returned true, or since the watcher was made.
+    pub fn changed(&mut self) -> bool {
+        if !self.should_look() {
+            return false;
+        }
+        let mut changed = false;
+        for (path, old) in &mut self.files {
+            let new = stamp(path);
+            if new != *old {
+                *old = new;
+                changed = true;
+            }
+        }
+        changed
+    }
+
+    /// Whether it's worth looking at the files' metadata.
+    fn should_look(&mut self) -> bool {
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        {
+            if let Some(inotify) = &self.inotify {
+                match inotify.any_events() {
+                    Some(any) => return any,
+                    // Polling is all that's left.
+                    None => self.inotify = None,
+                }
+            }
+        }
+        let now = Instant::now();
+        if now < self.next_poll {
+            return false;
+        }
+        self.next_poll = now + POLL_INTERVAL;
+        true
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+mod inotify {
+    use libc::{c_int, EAGAIN, IN_ATTRIB, IN_CLOEXEC, IN_CLOSE_WRITE, IN_CREATE, IN_DELETE, IN_IGNORED,
+               IN_MOVED_FROM, IN_MOVED_TO, IN_NONBLOCK, IN_Q_OVERFLOW};
+    use std::collections::HashSet;
+    use std::ffi::CString;
+    use std::io;
+    use std::mem;
+    use std::os::unix::ffi::OsStrExt;
+    use std::path::Path;
+    use std::ptr;
+
+    /// What can happen to a file in a directory, short of plain writes,
+    /// which are seen when the file is closed.
+    const EVENTS: u32 = IN_ATTRIB | IN_CLOSE_WRITE | IN_CREATE | IN_DELETE | IN_MOVED_FROM | IN_MOVED_TO;
+
+    /// An inotify descriptor watching some directories.
+    pub(super) struct Inotify(c_int);
+
+    impl Inotify {
+        /// Watch the directories `files` are in, or return `None` if that
+        /// can't be done for every one of them.
+        pub(super) fn watch<'a>(files: impl Iterator<Item = &'a Path>) -> Option<Inotify> {
+            let fd = unsafe { libc::inotify_init1(IN_NONBLOCK | IN_CLOEXEC) };
+            if fd < 0 {
+                return None;
+            }
+            let inotify = Inotify(fd);
+            let dirs: HashSet<&Path> = files
+                .map(|file| file.parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or(Path::new(".")))
+                .collect();
+            for dir in dirs {
+                let dir = CString::new(dir.as_os_str().as_bytes()).ok()?;
+                if unsafe { libc::inotify_add_watch(inotify.0, dir.as_ptr(), EVENTS) } < 0 {
+                    return None;
+                }
+            }
+            Some(inotify)
+        }
+
+        /// Read every event waiting, and say whether there were any, or
+        /// `None` if the watches no longer work.
+        pub(super) fn any_events(&self) -> Option<bool> {
+            let mut any = false;
+            // Aligned for `inotify_event`.
+            let mut buf = [0_u64; 512];
+            loop {
+                let n = unsafe { libc::read(self.0, buf.as_mut_ptr() as *mut libc::c_void, mem::size_of_val(&buf)) };
+                if n < 0 {
+                    return match io::Error::last_os_error().raw_os_error() {
+                        Some(EAGAIN) => Some(any),
+                        Some(libc::EINTR) => continue,
+                        _ => None,
+                    };
+                }
+                let n = n as usize;
+                let mut offset = 0;
+                while offset + mem::size_of::<libc::inotify_event>() <= n {
+                    let event: libc::inotify_event = unsafe {
+                        ptr::read_unaligned((buf.as_ptr() as *const u8).add(offset) as *const libc::inotify_event)
+                    };
+                    if event.mask & IN_IGNORED != 0 {
+                        // A directory went away, and its watch with it.
+                        return None;
+                    }
+                    if event.mask & IN_Q_OVERFLOW != 0 || event.mask & EVENTS != 0 {
+                        any = true;
+                    }
+                    offset += mem::size_of::<libc::inotify_event>() + event.len as usize;
+                }
+            }
+        }
+    }
+
+    impl Drop for Inotify {
+        fn drop(&mut self) {
+            unsafe {
+                libc::close(self.0);
+            }
+        }
+    }
+}
+
+/// A service whose configuration comes from files it can read again.
+pub trait Reload: 'static {
+    /// The files to watch.
+    const FILES: &'static [&'static str];
+
+    /// Read the files again, and answer from what they say from now on. If
+    /// they can't be read or parsed, this should keep answering from what
+    /// it read before, so a half-finished edit doesn't break lookups.
+    fn reload();
+}
+
+/// The service `S`, reloaded whenever one of its files changes, so that
+/// editing its configuration takes effect in running processes, not just
+/// new ones:
+///
+/// ```ignore
+/// nssglue_hosts!("extra", Reloading<StaticMapService<Extra>>);
+/// ```
+///
+/// Each lookup first checks `S::FILES` with a `ConfigWatcher`, and calls
+/// `S::reload` if any have changed. The old configuration is used until
+/// the new one has been read, and, for the services in this crate, if it
+/// can't be. `S`'s settings, such as `LOOKUP_TIMEOUT`, are passed through
+/// unchanged.
+pub struct Reloading<S>(PhantomData<S>);
+
+/// The watcher for each `Reloading` type.
+static WATCHERS: ForkSafeMutex<HashMap<TypeId, ConfigWatcher>> = ForkSafeMutex::new();
+
+impl<S: Reload> Reloading<S> {
+    /// Reload `S` if its files have changed.
+    fn check() {
+        let changed = {
+            let mut watchers = WATCHERS.lock();
+            watchers.entry(TypeId::of::<S>()).or_insert_with(|| ConfigWatcher::new(S::FILES)).changed()
+        };
+        if changed {
+            S::reload();
+        }
+    }
+
+    fn on_fork_child() {
+        // The watchers' inotify descriptors are shared with the parent,
+        // which would otherwise see only some of the events.
+        WATCHERS.reset();
+    }
+}
+
+impl<S: NameService + Reload> NameService for Reloading<S> {
+    const ALLOW_EMPTY_ADDRESS_LIST: bool = S::ALLOW_EMPTY_ADDRESS_LIST;
+    const LOOKUP_TIMEOUT: Option<Duration> = S::LOOKUP_TIMEOUT;
+    const LIMITS: HostLimits = S::LIMITS;
+    const VALIDATE_HOSTNAMES: bool = S::VALIDATE_HOSTNAMES;
+
+    fn gethostbyname_r(name: &CStr) -> Result<Option<HostEntry<'_>>> {
+        Self::check();
+        S::gethostbyname_r(name)
+    }
+
+    fn gethostbyname2_r(name: &CStr, af: AddressFamily) -> Result<Option<HostEntry<'_>>> {
+        Self::check();
+        S::gethostbyname2_r(name, af)
+    }
+
+    fn gethostbyaddr_r(addr: &IpAddr) -> Result<Option<HostEntry<'_>>> {
+        Self::check();
+        S::gethostbyaddr_r(addr)
+    }
+
+    fn gethostbyname3_r(name: &CStr, af: AddressFamily) -> Result<Option<HostEntryWithTtl<'_>>> {
+        Self::check();
+        S::gethostbyname3_r(name, af)
+    }
+
+    fn gethostbyname4_r(name: &CStr) -> Result<Option<HostAddresses<'_>>> {
+        Self::check();
+        S::gethostbyname4_r(name)
+    }
+
+    fn gethostbyaddr2_r(addr: &IpAddr) -> Result<Option<HostEntryWithTtl<'_>>> {
+        Self::check();
+        S::gethostbyaddr2_r(addr)
+    }
+
+    fn sethostent(stay_open: bool) -> Result<Entries<HostEntry<'static>>> {
+        Self::check();
+        S::sethostent(stay_open)
+    }
+
+    fn on_fork_child() {
+        Self::on_fork_child();
+        S::on_fork_child()
+    }
+}
+
+impl<S: PasswdService + Reload> PasswdService for Reloading<S> {
+    const LOOKUP_TIMEOUT: Option<Duration> = S::LOOKUP_TIMEOUT;
+
+    fn getpwnam_r(name: &CStr) -> Result<Option<PasswdEntry<'_>>> {
+        Self::check();
+        S::getpwnam_r(name)
+    }
+
+    fn getpwuid_r(uid: uid_t) -> Result<Option<PasswdEntry<'static>>> {
+        Self::check();
+        S::getpwuid_r(uid)
+    }
+
+    fn setpwent() -> Result<Entries<PasswdEntry<'static>>> {
+        Self::check();
+        S::setpwent()
+    }
+
+    fn on_fork_child() {
+        Self::on_fork_child();
+        S::on_fork_child()
+    }
+}
+
+impl<S: GroupService + Reload> GroupService for Reloading<S> {
+    const LOOKUP_TIMEOUT: Option<Duration> = S::LOOKUP_TIMEOUT;
+
+    fn getgrnam_r(name: &CStr) -> Result<Option<GroupEntry<'_>>> {
+        Self::check();
+        S::getgrnam_r(name)
+    }
+
+    fn getgrgid_r(gid: gid_t) -> Result<Option<GroupEntry<'static>>> {
+        Self::check();
+        S::getgrgid_r(gid)
+    }
+
+    fn setgrent() -> Result<Entries<GroupEntry<'static>>> {
+        Self::check();
+        S::setgrent()
+    }
+
+    fn initgroups_dyn(user: &CStr, group: gid_t) -> Result<Option<Vec<gid_t>>> {
+        Self::check();
+        S::initgroups_dyn(user, group)
+    }
+
+    fn on_fork_child() {
+        Self::on_fork_child();
+        S::on_fork_child()
+    }
+}
+
+impl<S: ShadowService + Reload> ShadowService for Reloading<S> {
+    const LOOKUP_TIMEOUT: Option<Duration> = S::LOOKUP_TIMEOUT;
+
+    fn getspnam_r(name: &CStr) -> Result<Option<ShadowEntry<'_>>> {
+        Self::check();
+        S::getspnam_r(name)
+    }
+
+    fn setspent() -> Result<Entries<ShadowEntry<'static>>> {
+        Self::check();
+        S::setspent()
+    }
+
+    fn on_fork_child() {
+        Self::on_fork_child();
+        S::on_fork_child()
+    }
+}
+
+#[test]
+fn test_reloading() {
+    use crate::hosts_file::{HostsFileConfig, HostsFileService};
+    use crate::testing::gethostbyname2;
+    use std::io::Write;
+
+    const DIR: &str = "/tmp/nsswitch_service-test-reload";
+    const PATH: &str = "/tmp/nsswitch_service-test-reload/hosts";
+    let _ = fs::remove_dir_all(DIR);
+    fs::create_dir_all(DIR).unwrap();
+    fs::write(PATH, "10.0.0.1 before.test\n").unwrap();
+
+    // The watcher sees edits, and replacement by rename.
+    let mut watcher = ConfigWatcher::new(&[PATH]);
+    assert!(!watcher.changed());
+    fs::OpenOptions::new().append(true).open(PATH).unwrap().write_all(b"10.0.0.2 more.test\n").unwrap();
+    std::thread::sleep(POLL_INTERVAL);
+    assert!(watcher.changed());
+    assert!(!watcher.changed());
+    let replace = |text: &str| {
+        let new = format!("{}.new", PATH);
+        fs::write(&new, text).unwrap();
+        fs::rename(&new, PATH).unwrap();
+        std::thread::sleep(POLL_INTERVAL);
+    };
+    replace("10.0.0.3 renamed.test\n");
+    assert!(watcher.changed());
+
+    struct Temp;
+    impl HostsFileConfig for Temp {
+        const PATHS: &'static [&'static str] = &[PATH];
+    }
+    type Hosts = Reloading<HostsFileService<Temp>>;
+    let addr = |name| {
+        gethostbyname2::<Hosts>(name, AddressFamily::Ipv4).unwrap().map(|entry| entry.addr_list.len())
+    };
+    assert_eq!(addr("renamed.test"), Some(1));
+    replace("10.0.0.4 after.test\n");
+    assert_eq!(addr("after.test"), Some(1));
+    assert_eq!(addr("renamed.test"), None);
+
+    // A file that can't be read leaves the old table in place.
+    fs::remove_file(PATH).unwrap();
+    std::thread::sleep(POLL_INTERVAL);
+    assert_eq!(addr("after.test"), Some(1));
+
+    fs::remove_dir_all(DIR).unwrap();
+}
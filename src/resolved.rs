@@ -0,0 +1,289 @@
+//! A ready-made `hosts` service that asks systemd-resolved, over its
+//! varlink interface, as glibc's `resolve` module from systemd does.
+
+use crate::diag;
+use crate::errors::{Error, HostError, NssStatus, Result};
+use crate::interfaces::{AddressFamily, HostAddressList, HostAddresses, HostEntry, NameService};
+use libc::{AF_INET, AF_INET6, EAGAIN, EINVAL, EIO};
+use serde_json::{json, Value};
+use std::borrow::Cow;
+use std::convert::TryFrom;
+use std::ffi::{CStr, CString};
+use std::io::{self, Read, Write};
+use std::marker::PhantomData;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::os::unix::net::UnixStream;
+use std::time::Duration;
+
+/// How a `ResolvedService` reaches systemd-resolved.
+pub trait ResolvedConfig: 'static {
+    /// resolved's varlink socket.
+    const SOCKET: &'static str = "/run/systemd/resolve/io.systemd.Resolve";
+
+    /// The `SD_RESOLVED_*` flags to send with each query, as in
+    /// `resolvectl query`'s options. For example, `SD_RESOLVED_NO_SYNTHESIZE`
+    /// (`1 << 11`) leaves out the names resolved makes up itself, like
+    /// `localhost` and the machine's own hostname.
+    const FLAGS: u64 = 0;
+
+    /// How long to wait for an answer. resolved gives up on a query well
+    /// within this.
+    const TIMEOUT: Duration = Duration::from_secs(120);
+}
+
+/// The usual settings: resolved's own socket, with no flags.
+pub struct ResolvedDefaults;
+
+impl ResolvedConfig for ResolvedDefaults {}
+
+/// A `hosts` service that forwards each lookup to systemd-resolved, for
+/// modules that filter, log, or override names in front of it:
+///
+/// ```ignore
+/// struct Filtered;
+///
+/// impl NameService for Filtered {
+///     fn gethostbyname2_r(name: &CStr, af: AddressFamily) -> Result<Option<HostEntry<'_>>> {
+///         if is_blocked(name) {
+///             return Ok(None);
+///         }
+///         ResolvedService::<ResolvedDefaults>::gethostbyname2_r(name, af)
+///     }
+///     ...
+/// }
+/// ```
+///
+/// Lookups get the same answers `resolvectl query` does, including LLMNR,
+/// mDNS, and the names resolved synthesizes, subject to `FLAGS`.
+/// `gethostbyname4_r` (and so `getaddrinfo`) asks for both families in one
+/// query. A name resolved reports doesn't exist is `NssStatus::NotFound`,
+/// with `h_errno` set as the `dns` service would; timeouts and a lack of
+/// DNS servers are `NssStatus::TryAgain`.
+///
+/// If resolved isn't running, lookups report `NssStatus::Unavailable`
+/// quietly, so `hosts: mymodule [!UNAVAIL=return] dns` falls back to
+/// asking DNS servers directly, as it does with systemd's module. Other
+/// errors are logged.
+pub struct ResolvedService<C = ResolvedDefaults>(PhantomData<C>);
+
+/// A varlink error: its name and its parameters.
+type VarlinkError = (String, Value);
+
+/// Call `method` on the varlink service at `socket`, and return the reply's
+/// parameters or the error.
+fn call(socket: &str, method: &str, parameters: Value, timeout: Duration)
+    -> io::Result<std::result::Result<Value, VarlinkError>>
+{
+    let mut stream = UnixStream::connect(socket)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+    let mut request = serde_json::to_vec(&json!({"method": method, "parameters": parameters}))?;
+    request.push(0);
+    stream.write_all(&request)?;
+
+    // Each message ends with a NUL.
+    let mut reply = Vec::new();
+    let mut chunk = [0; 4096];
+    while !reply.contains(&0) {
+        let len = stream.read(&mut chunk)?;
+        if len == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        reply.extend_from_slice(&chunk[..len]);
+    }
+    reply.truncate(reply.iter().position(|&b| b == 0).unwrap());
+    let mut reply: Value = serde_json::from_slice(&reply)?;
+    let parameters = reply.get_mut("parameters").map_or(Value::Null, Value::take);
+    match reply.get("error") {
+        None => Ok(Ok(parameters)),
+        Some(error) => Ok(Err((error.as_str().unwrap_or("").to_string(), parameters))),
+    }
+}
+
+fn malformed(method: &str) -> Error {
+    diag::log(format_args!("malformed reply from resolved to {}", method));
+    Error::with_errno(NssStatus::Unavailable, EINVAL)
+}
+
+/// Call `method`, turning every failure into an `Error` the way systemd's
+/// module does.
+fn resolve(socket: &str, method: &str, parameters: Value, timeout: Duration) -> Result<Value> {
+    let reply = call(socket, method, parameters, timeout).map_err(|err| {
+        if !matches!(err.kind(), io::ErrorKind::NotFound | io::ErrorKind::ConnectionRefused) {
+            diag::log(format_args!("can't ask resolved: {}", err));
+        }
+        let errno = match err.kind() {
+            io::ErrorKind::InvalidData | io::ErrorKind::UnexpectedEof => EIO,
+            _ => err.raw_os_error().unwrap_or(EIO),
+        };
+        Error::with_errno(NssStatus::Unavailable, errno)
+    })?;
+    let (error, parameters) = match reply {
+        Ok(parameters) => return Ok(parameters),
+        Err(error) => error,
+    };
+    match error.strip_prefix("io.systemd.Resolve.").unwrap_or("") {
+        "NoSuchResourceRecord" => Err(Error::from_dns_rcode(0, None)),
+        "DNSError" => Err(Error::from_dns_rcode(parameters["rcode"].as_u64().map_or(2, |rcode| rcode as u16), None)),
+        "QueryTimedOut" | "MaxAttemptsReached" | "NoNameServers" | "NetworkDown" => {
+            Err(Error::with_host(NssStatus::TryAgain, EAGAIN, HostError::TryAgain))
+        }
+        _ => {
+            diag::log(format_args!("resolved failed {}: {}", method, error));
+            Err(Error::with_errno(NssStatus::Unavailable, EIO))
+        }
+    }
+}
+
+fn family_number(af: AddressFamily) -> i32 {
+    match af {
+        AddressFamily::Ipv4 => AF_INET,
+        AddressFamily::Ipv6 => AF_INET6,
+    }
+}
+
+/// An address in a reply: `{"family": 2, "address": [10, 0, 0, 5]}`.
+fn address(value: &Value) -> Option<IpAddr> {
+    let bytes = value["address"].as_array()?.iter()
+        .map(|b| u8::try_from(b.as_u64()?).ok())
+        .collect::<Option<Vec<u8>>>()?;
+    match i32::try_from(value["family"].as_i64()?).ok()? {
+        AF_INET => Some(IpAddr::V4(Ipv4Addr::from(<[u8; 4]>::try_from(&bytes[..]).ok()?))),
+        AF_INET6 => Some(IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(&bytes[..]).ok()?))),
+        _ => None,
+    }
+}
+
+/// Ask resolved for `name`'s addresses, of family `af` or, if that's
+/// `None`, of both.
+fn resolve_hostname(socket: &str, name: &str, af: Option<AddressFamily>, flags: u64, timeout: Duration)
+    -> Result<HostAddresses<'static>>
+{
+    let method = "io.systemd.Resolve.ResolveHostname";
+    let mut parameters = json!({"name": name, "flags": flags});
+    if let Some(af) = af {
+        parameters["family"] = json!(family_number(af));
+    }
+    let reply = resolve(socket, method, parameters, timeout)?;
+    let canonical = reply["name"].as_str().and_then(|name| CString::new(name).ok());
+    let addrs = reply["addresses"].as_array().and_then(|addrs| addrs.iter().map(address).collect::<Option<Vec<_>>>());
+    match (canonical, addrs) {
+        (Some(name), Some(addrs)) => Ok(HostAddresses { name: Cow::Owned(name), addrs, ttl: None }),
+        _ => Err(malformed(method)),
+    }
+}
+
+/// Ask resolved for the names of `addr`: the first, and any others as
+/// aliases.
+fn resolve_address(socket: &str, addr: &IpAddr, flags: u64, timeout: Duration) -> Result<HostEntry<'static>> {
+    let method = "io.systemd.Resolve.ResolveAddress";
+    let (family, bytes, addr_list) = match *addr {
+        IpAddr::V4(ip) => (AF_INET, ip.octets().to_vec(), HostAddressList::V4(vec![ip])),
+        IpAddr::V6(ip) => (AF_INET6, ip.octets().to_vec(), HostAddressList::V6(vec![ip])),
+    };
+    let parameters = json!({"family": family, "address": bytes, "flags": flags});
+    let reply = resolve(socket, method, parameters, timeout)?;
+    let mut names = reply["names"].as_array()
+        .and_then(|names| names.iter().map(|name| CString::new(name["name"].as_str()?).ok()).collect::<Option<Vec<_>>>())
+        .filter(|names| !names.is_empty())
+        .ok_or_else(|| malformed(method))?
+        .into_iter()
+        .map(Cow::Owned);
+    Ok(HostEntry { name: names.next().unwrap(), aliases: names.collect(), addr_list })
+}
+
+impl<C: ResolvedConfig> NameService for ResolvedService<C> {
+    fn gethostbyname2_r(name: &CStr, af: AddressFamily) -> Result<Option<HostEntry<'_>>> {
+        let name = match name.to_str() {
+            Ok(name) => name,
+            Err(_) => return Ok(None),
+        };
+        let found = resolve_hostname(C::SOCKET, name, Some(af), C::FLAGS, C::TIMEOUT)?;
+        let addr_list = match af {
+            AddressFamily::Ipv4 => HostAddressList::V4(found.addrs.iter().filter_map(|addr| match *addr {
+                IpAddr::V4(ip) => Some(ip),
+                IpAddr::V6(_) => None,
+            }).collect()),
+            AddressFamily::Ipv6 => HostAddressList::V6(found.addrs.iter().filter_map(|addr| match *addr {
+                IpAddr::V4(_) => None,
+                IpAddr::V6(ip) => Some(ip),
+            }).collect()),
+        };
+        if addr_list.is_empty() {
+            return Err(Error::from_dns_rcode(0, None));
+        }
+        // resolved only says how it got to the canonical name, not which
+        // names it passed through, so the name asked about is the alias.
+        let aliases = match *found.name.to_bytes() == *name.as_bytes() {
+            true => vec![],
+            false => vec![Cow::Owned(CString::new(name).unwrap())],
+        };
+        Ok(Some(HostEntry { name: found.name, aliases, addr_list }))
+    }
+
+    fn gethostbyname4_r(name: &CStr) -> Result<Option<HostAddresses<'_>>> {
+        match name.to_str() {
+            Ok(name) => resolve_hostname(C::SOCKET, name, None, C::FLAGS, C::TIMEOUT).map(Some),
+            Err(_) => Ok(None),
+        }
+    }
+
+    fn gethostbyaddr_r(addr: &IpAddr) -> Result<Option<HostEntry<'_>>> {
+        resolve_address(C::SOCKET, addr, C::FLAGS, C::TIMEOUT).map(Some)
+    }
+}
+
+#[test]
+fn test_resolved_varlink() {
+    use std::os::unix::net::UnixListener;
+    use std::thread;
+
+    let socket = std::env::temp_dir().join(format!("nsswitch_service-test-{}.resolve", std::process::id()));
+    let _ = std::fs::remove_file(&socket);
+    let listener = UnixListener::bind(&socket).unwrap();
+    let server = thread::spawn(move || {
+        let mut requests = vec![];
+        let replies: [&[u8]; 4] = [
+            br#"{"parameters": {"addresses": [{"ifindex": 2, "family": 2, "address": [10, 0, 0, 5]},
+                {"family": 10, "address": [253, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 5]}],
+                "name": "db1.corp.example", "flags": 1}}"#,
+            br#"{"error": "io.systemd.Resolve.DNSError", "parameters": {"rcode": 3}}"#,
+            br#"{"error": "io.systemd.Resolve.NoNameServers"}"#,
+            br#"{"parameters": {"names": [{"name": "db1.corp.example"}, {"name": "db1"}], "flags": 0}}"#,
+        ];
+        for reply in &replies {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = vec![];
+            let mut byte = [0];
+            while stream.read(&mut byte).unwrap() == 1 && byte[0] != 0 {
+                request.push(byte[0]);
+            }
+            requests.push(serde_json::from_slice::<Value>(&request).unwrap());
+            stream.write_all(reply).unwrap();
+            stream.write_all(b"\0").unwrap();
+        }
+        requests
+    });
+
+    let path = socket.to_str().unwrap();
+    let timeout = Duration::from_secs(5);
+    let found = resolve_hostname(path, "db1", None, 0, timeout).unwrap();
+    assert_eq!(found.name.to_str(), Ok("db1.corp.example"));
+    assert_eq!(found.addrs, vec!["10.0.0.5".parse::<IpAddr>().unwrap(), "fd00::5".parse().unwrap()]);
+    let err = resolve_hostname(path, "nope", Some(AddressFamily::Ipv4), 0, timeout).unwrap_err();
+    assert_eq!((err.status(), err.host_error()), (NssStatus::NotFound, Some(HostError::HostNotFound)));
+    let err = resolve_hostname(path, "db1", None, 0, timeout).unwrap_err();
+    assert_eq!(err.status(), NssStatus::TryAgain);
+    let entry = resolve_address(path, &"10.0.0.5".parse().unwrap(), 0, timeout).unwrap();
+    assert_eq!((entry.name.to_str(), entry.aliases.len()), (Ok("db1.corp.example"), 1));
+
+    let requests = server.join().unwrap();
+    assert_eq!(requests[0], json!({"method": "io.systemd.Resolve.ResolveHostname",
+                                   "parameters": {"name": "db1", "flags": 0}}));
+    assert_eq!(requests[1]["parameters"]["family"], json!(AF_INET));
+    assert_eq!(requests[3]["parameters"], json!({"family": AF_INET, "address": [10, 0, 0, 5], "flags": 0}));
+    std::fs::remove_file(&socket).unwrap();
+
+    let err = resolve_hostname(path, "db1", None, 0, timeout).unwrap_err();
+    assert_eq!((err.status(), err.errno()), (NssStatus::Unavailable, libc::ENOENT));
+}
@@ -0,0 +1,365 @@
+//! A ready-made service for `hosts`, `passwd`, and `group` that asks an
+//! HTTP API for each entry, as JSON.
+
+use crate::config::env_var;
+use crate::diag;
+use crate::errors::{Error, NssStatus, Result};
+use crate::fork::ForkSafeMutex;
+use crate::host_table::{Host, HostTable};
+use crate::http;
+use crate::interfaces::{AddressFamily, GroupEntry, GroupService, HostEntry, NameService, PasswdEntry};
+use crate::interfaces::PasswdService;
+use crate::tls;
+use libc::{gid_t, uid_t, EINVAL, EIO};
+use serde_json::Value;
+use std::borrow::Cow;
+use std::convert::TryFrom;
+use std::ffi::{CStr, CString};
+use std::fs;
+use std::io;
+use std::marker::PhantomData;
+use std::net::{IpAddr, TcpStream, ToSocketAddrs};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Where a `RestService` finds the API, and which paths it asks for.
+pub trait RestConfig: 'static {
+    /// The API's URL, `http:` or `https:`. The paths below are appended to
+    /// it.
+    const BASE_URL: &'static str = "http://127.0.0.1:8080";
+
+    /// An environment variable that overrides `BASE_URL`, for pointing a
+    /// program at a test server. It's ignored in secure mode (see
+    /// `is_secure_mode`), so setuid programs always use `BASE_URL`.
+    const BASE_URL_VAR: Option<&'static str> = Some("NSS_REST_URL");
+
+    /// The path to get a host by name. In each path, `{name}`, `{addr}`,
+    /// `{uid}`, or `{gid}` stands for what's looked up, percent-encoded.
+    /// `None` means the API can't look that up, and nothing is found.
+    const HOST_BY_NAME: Option<&'static str> = Some("/hosts/{name}");
+
+    /// The path to get a host by address, written as in `10.0.0.5` or
+    /// `fd00::5`.
+    const HOST_BY_ADDR: Option<&'static str> = Some("/hosts/by-addr/{addr}");
+
+    /// The path to get a user by name.
+    const USER_BY_NAME: Option<&'static str> = Some("/users/{name}");
+
+    /// The path to get a user by user id.
+    const USER_BY_UID: Option<&'static str> = Some("/users/by-uid/{uid}");
+
+    /// The path to get a group by name.
+    const GROUP_BY_NAME: Option<&'static str> = Some("/groups/{name}");
+
+    /// The path to get a group by group id.
+    const GROUP_BY_GID: Option<&'static str> = Some("/groups/by-gid/{gid}");
+
+    /// A file with a token to send as `Authorization: Bearer`. If this
+    /// process can't read it, requests go without, so the file can be
+    /// readable only by root.
+    const TOKEN_FILE: Option<&'static str> = None;
+
+    /// The certificate authorities to check an `https:` server's
+    /// certificate against, in PEM.
+    const CA_FILE: &'static str = "/etc/ssl/certs/ca-certificates.crt";
+
+    /// How long to remember an answer, including that there's no such
+    /// entry. Zero turns caching off.
+    const CACHE_TTL: Duration = Duration::from_secs(30);
+
+    /// How long to wait for the server.
+    const TIMEOUT: Duration = Duration::from_secs(2);
+}
+
+/// The usual settings: a server on `127.0.0.1:8080`, with the paths above.
+pub struct RestDefaults;
+
+impl RestConfig for RestDefaults {}
+
+/// A service that gets each host, user, and group from an HTTP API, for
+/// in-house directories that can serve a little JSON:
+///
+/// ```ignore
+/// struct Idp;
+///
+/// impl RestConfig for Idp {
+///     const BASE_URL: &'static str = "https://idp.example.com/api";
+/// }
+///
+/// nssglue_hosts!("idp", RestService<Idp>);
+/// nssglue_passwd!("idp", RestService<Idp>);
+/// nssglue_group!("idp", RestService<Idp>);
+/// ```
+///
+/// Each lookup is a `GET` of its path. A 404 means there's no such entry,
+/// and a 200 has the entry as a JSON object:
+///
+/// ```text
+/// GET /hosts/db1       {"name": "db1.example.com", "aliases": ["db1"], "addresses": ["10.0.0.5", "fd00::5"]}
+/// GET /users/alice     {"name": "alice", "uid": 1000, "gid": 1000, "gecos": "Alice", "home": "/home/alice",
+///                       "shell": "/bin/bash"}
+/// GET /groups/staff    {"name": "staff", "gid": 50, "members": ["alice", "bob"]}
+/// ```
+///
+/// A host's `aliases`, a user's `gecos` and `shell`, and a group's
+/// `members` may be left out; the shell defaults to `/bin/sh`. A host is
+/// found if the name looked up is its name or one of its aliases, and a
+/// user or group only if its name or id is the one looked up. There is no
+/// enumeration.
+///
+/// Answers are cached for `CACHE_TTL`. Any other status, or an object that
+/// doesn't fit the schema, is logged, and the lookup reports
+/// `NssStatus::Unavailable`; so does a server that can't be reached, which
+/// goes unlogged if nothing is listening.
+pub struct RestService<C = RestDefaults>(PhantomData<C>);
+
+/// The most answers cached.
+const MAX_CACHED: usize = 256;
+
+/// A response body, or `None` for a 404.
+type Found = Option<Vec<u8>>;
+
+/// Answers, by URL, with when to forget them.
+static CACHE: ForkSafeMutex<Vec<(String, Instant, Found)>> = ForkSafeMutex::new();
+
+/// `GET` `url`, returning the body, or `None` for a 404.
+fn fetch(url: &str, token: Option<&str>, ca_file: &str, timeout: Duration) -> io::Result<Found> {
+    let (tls, host, port, path) = http::split_url(url)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "not an http: or https: URL"))?;
+    let authorization = token.map(|token| format!("Bearer {}", token));
+    let headers: Vec<(&str, &str)> = authorization.iter().map(|value| ("Authorization", &value[..])).collect();
+    let response = if tls {
+        http::get_https(Arc::new(tls::client_config(ca_file)?), host, port, path, &headers, timeout)?
+    } else {
+        let mut last_err = io::Error::from(io::ErrorKind::AddrNotAvailable);
+        let mut response = None;
+        for addr in (host, port).to_socket_addrs()? {
+            match TcpStream::connect_timeout(&addr, timeout) {
+                Ok(tcp) => {
+                    tcp.set_read_timeout(Some(timeout))?;
+                    tcp.set_write_timeout(Some(timeout))?;
+                    let authority = if port == 80 { host.to_string() } else { format!("{}:{}", host, port) };
+                    response = Some(http::get(tcp, &authority, path, &headers)?);
+                    break;
+                }
+                Err(err) => last_err = err,
+            }
+        }
+        response.ok_or(last_err)?
+    };
+    match response.status {
+        200 => Ok(Some(response.body)),
+        404 => Ok(None),
+        status => Err(io::Error::other(format!("HTTP status {}", status))),
+    }
+}
+
+fn string(object: &Value, key: &str) -> Option<CString> {
+    CString::new(object.get(key)?.as_str()?).ok()
+}
+
+fn strings(object: &Value, key: &str) -> Option<Vec<CString>> {
+    match object.get(key) {
+        None => Some(vec![]),
+        Some(list) => list.as_array()?.iter().map(|item| CString::new(item.as_str()?).ok()).collect(),
+    }
+}
+
+fn id(object: &Value, key: &str) -> Option<u32> {
+    u32::try_from(object.get(key)?.as_u64()?).ok()
+}
+
+fn host(object: &Value) -> Option<Host> {
+    Some(Host {
+        name: string(object, "name")?,
+        aliases: strings(object, "aliases")?,
+        addrs: object.get("addresses")?.as_array()?.iter()
+            .map(|addr| addr.as_str()?.parse().ok())
+            .collect::<Option<_>>()?,
+    })
+}
+
+fn passwd_entry(object: &Value) -> Option<PasswdEntry<'static>> {
+    let shell = match object.get("shell") {
+        None => CString::new("/bin/sh").unwrap(),
+        Some(_) => string(object, "shell")?,
+    };
+    Some(PasswdEntry {
+        name: Cow::Owned(string(object, "name")?),
+        passwd: Cow::Borrowed(CStr::from_bytes_with_nul(b"x\0").unwrap()),
+        uid: id(object, "uid")?,
+        gid: id(object, "gid")?,
+        gecos: Cow::Owned(if object.get("gecos").is_some() { string(object, "gecos")? } else { CString::default() }),
+        dir: Cow::Owned(string(object, "home")?),
+        shell: Cow::Owned(shell),
+    })
+}
+
+fn group_entry(object: &Value) -> Option<GroupEntry<'static>> {
+    Some(GroupEntry {
+        name: Cow::Owned(string(object, "name")?),
+        passwd: Cow::Borrowed(CStr::from_bytes_with_nul(b"*\0").unwrap()),
+        gid: id(object, "gid")?,
+        members: strings(object, "members")?.into_iter().map(Cow::Owned).collect(),
+    })
+}
+
+impl<C: RestConfig> RestService<C> {
+    /// Get the object at `path` with `placeholder` replaced by `value`, or
+    /// `None` if there's no such path or no such object, and convert it
+    /// with `convert`.
+    fn get<T>(path: Option<&str>, placeholder: &str, value: &[u8], convert: fn(&Value) -> Option<T>)
+        -> Result<Option<T>>
+    {
+        let path = match path {
+            None => return Ok(None),
+            Some(path) => path,
+        };
+        let base = C::BASE_URL_VAR.and_then(env_var).unwrap_or_else(|| C::BASE_URL.to_string());
+        let url = format!("{}{}", base.trim_end_matches('/'), path.replace(placeholder, &http::percent_encode(value)));
+
+        let body = match Self::cached(&url) {
+            Some(body) => body,
+            None => {
+                let token = C::TOKEN_FILE.and_then(|file| fs::read_to_string(file).ok());
+                let body = fetch(&url, token.as_deref().map(str::trim), C::CA_FILE, C::TIMEOUT).map_err(|err| {
+                    if err.kind() != io::ErrorKind::ConnectionRefused {
+                        diag::log(format_args!("can't get {}: {}", url, err));
+                    }
+                    Error::with_errno(NssStatus::Unavailable, err.raw_os_error().unwrap_or(EIO))
+                })?;
+                Self::remember(url.clone(), body.clone());
+                body
+            }
+        };
+        let body = match body {
+            None => return Ok(None),
+            Some(body) => body,
+        };
+        let object: Option<Value> = serde_json::from_slice(&body).ok();
+        match object.as_ref().and_then(convert) {
+            Some(entry) => Ok(Some(entry)),
+            None => {
+                diag::log(format_args!("{} doesn't fit the schema: {}", url, String::from_utf8_lossy(&body)));
+                Err(Error::with_errno(NssStatus::Unavailable, EINVAL))
+            }
+        }
+    }
+
+    fn cached(url: &str) -> Option<Found> {
+        let now = Instant::now();
+        let mut cache = CACHE.lock();
+        cache.retain(|&(_, expires, _)| expires > now);
+        cache.iter().find(|(u, _, _)| u == url).map(|(_, _, body)| body.clone())
+    }
+
+    fn remember(url: String, body: Found) {
+        if C::CACHE_TTL == Duration::ZERO {
+            return;
+        }
+        let mut cache = CACHE.lock();
+        if cache.len() >= MAX_CACHED {
+            cache.remove(0);
+        }
+        cache.push((url, Instant::now() + C::CACHE_TTL, body));
+    }
+}
+
+impl<C: RestConfig> NameService for RestService<C> {
+    fn gethostbyname2_r(name: &CStr, af: AddressFamily) -> Result<Option<HostEntry<'_>>> {
+        let host = Self::get(C::HOST_BY_NAME, "{name}", name.to_bytes(), host)?;
+        Ok(host.and_then(|host| HostTable { hosts: vec![host] }.by_name(name, af)))
+    }
+
+    fn gethostbyaddr_r(addr: &IpAddr) -> Result<Option<HostEntry<'_>>> {
+        let host = Self::get(C::HOST_BY_ADDR, "{addr}", addr.to_string().as_bytes(), host)?;
+        Ok(host.and_then(|host| HostTable { hosts: vec![host] }.by_addr(addr)))
+    }
+
+    fn on_fork_child() {
+        CACHE.reset();
+    }
+}
+
+impl<C: RestConfig> PasswdService for RestService<C> {
+    fn getpwnam_r(name: &CStr) -> Result<Option<PasswdEntry<'_>>> {
+        let entry = Self::get(C::USER_BY_NAME, "{name}", name.to_bytes(), passwd_entry)?;
+        Ok(entry.filter(|entry| *entry.name == *name))
+    }
+
+    fn getpwuid_r(uid: uid_t) -> Result<Option<PasswdEntry<'static>>> {
+        let entry = Self::get(C::USER_BY_UID, "{uid}", uid.to_string().as_bytes(), passwd_entry)?;
+        Ok(entry.filter(|entry| entry.uid == uid))
+    }
+
+    fn on_fork_child() {
+        CACHE.reset();
+    }
+}
+
+impl<C: RestConfig> GroupService for RestService<C> {
+    fn getgrnam_r(name: &CStr) -> Result<Option<GroupEntry<'_>>> {
+        let entry = Self::get(C::GROUP_BY_NAME, "{name}", name.to_bytes(), group_entry)?;
+        Ok(entry.filter(|entry| *entry.name == *name))
+    }
+
+    fn getgrgid_r(gid: gid_t) -> Result<Option<GroupEntry<'static>>> {
+        let entry = Self::get(C::GROUP_BY_GID, "{gid}", gid.to_string().as_bytes(), group_entry)?;
+        Ok(entry.filter(|entry| entry.gid == gid))
+    }
+
+    fn on_fork_child() {
+        CACHE.reset();
+    }
+}
+
+#[test]
+fn test_rest_lookups() {
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    assert_eq!(http::percent_encode(b"al ice/../x"), "al%20ice%2F..%2Fx");
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let base = format!("http://{}/api", listener.local_addr().unwrap());
+    let server = thread::spawn(move || {
+        let mut requests = vec![];
+        for _ in 0..2 {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = [0_u8; 1024];
+            let len = stream.read(&mut request).unwrap();
+            let request = String::from_utf8(request[..len].to_vec()).unwrap();
+            let response: &[u8] = if request.starts_with("GET /api/users/alice ") {
+                b"HTTP/1.0 200 OK\r\n\r\n{\"name\": \"alice\", \"uid\": 1000, \"gid\": 100, \"home\": \"/home/alice\"}"
+            } else {
+                b"HTTP/1.0 404 Not Found\r\n\r\n"
+            };
+            stream.write_all(response).unwrap();
+            requests.push(request);
+        }
+        requests
+    });
+
+    let timeout = Duration::from_secs(5);
+    let alice = fetch(&format!("{}/users/alice", base), Some("t0ken"), "/nonexistent", timeout).unwrap().unwrap();
+    assert_eq!(fetch(&format!("{}/users/bob", base), None, "/nonexistent", timeout).unwrap(), None);
+    let requests = server.join().unwrap();
+    assert!(requests[0].contains("\r\nAuthorization: Bearer t0ken\r\n"));
+    assert!(!requests[1].contains("Authorization"));
+
+    let entry = passwd_entry(&serde_json::from_slice(&alice).unwrap()).unwrap();
+    assert_eq!((entry.name.to_str(), entry.uid, entry.gid), (Ok("alice"), 1000, 100));
+    assert_eq!((entry.gecos.to_str(), entry.shell.to_str()), (Ok(""), Ok("/bin/sh")));
+    assert!(passwd_entry(&serde_json::json!({"name": "alice", "uid": -1, "gid": 100, "home": "/"})).is_none());
+
+    let db1 = host(&serde_json::json!({"name": "db1.example.com", "aliases": ["db1"],
+                                       "addresses": ["10.0.0.5", "fd00::5"]})).unwrap();
+    let table = HostTable { hosts: vec![db1] };
+    let name = CString::new("DB1").unwrap();
+    assert_eq!(table.by_name(&name, AddressFamily::Ipv6).unwrap().name.to_str(), Ok("db1.example.com"));
+    assert!(host(&serde_json::json!({"name": "db1", "addresses": ["db1"]})).is_none());
+
+    let group = group_entry(&serde_json::json!({"name": "staff", "gid": 50})).unwrap();
+    assert!(group.members.is_empty());
+}
@@ -0,0 +1,242 @@
+//! Answering reverse lookups from a service's forward entries.
+
+use crate::diag;
+use crate::errors::Result;
+use crate::fork::ForkSafeMutex;
+use crate::interfaces::{AddressFamily, Entries, HostAddressList, HostAddresses, HostEntry, HostEntryWithTtl,
+                        HostLimits, NameService};
+use crate::reload::Reload;
+use std::any::{type_name, TypeId};
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::ffi::{CStr, CString};
+use std::marker::PhantomData;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// How a `ReverseMapped` service keeps its index.
+pub trait ReverseMapConfig: 'static {
+    /// The most addresses to index. Reverse lookups of addresses past this
+    /// go to the service, as if they weren't in it.
+    const MAX_ADDRESSES: usize = 65536;
+
+    /// How long to use an index before building it again. With the
+    /// default, `None`, it's kept until the service is reloaded (see
+    /// `Reload`).
+    const MAX_AGE: Option<Duration> = None;
+}
+
+/// The usual settings: up to 65536 addresses, kept until a reload.
+pub struct ReverseMapDefaults;
+
+impl ReverseMapConfig for ReverseMapDefaults {}
+
+/// The `hosts` service `S`, with reverse lookups answered from the hosts it
+/// lists, for services that only know how to look up names:
+///
+/// ```ignore
+/// nssglue_hosts!("extra", Reloading<ReverseMapped<StaticMapService<Extra>>>);
+/// ```
+///
+/// The first reverse lookup enumerates `S` (see `NameService::sethostent`)
+/// and indexes every address it finds by the first host that has it, as a
+/// hosts file would. An address in the index is found with that host's
+/// name and aliases; any other goes to `S`'s own `gethostbyaddr_r`. If
+/// enumeration fails, the failure is logged, reverse lookups go to `S`,
+/// and the next one tries again.
+///
+/// The index holds at most `C::MAX_ADDRESSES` addresses, and lasts until
+/// `C::MAX_AGE` has passed or `S` is reloaded: `ReverseMapped<S>` is
+/// `Reload` if `S` is, so `Reloading` rebuilds the index when it rereads
+/// `S`'s files, as above. Forward lookups and enumeration go to `S`
+/// unchanged, as do `S`'s settings, such as `LOOKUP_TIMEOUT`.
+pub struct ReverseMapped<S, C = ReverseMapDefaults>(PhantomData<(S, C)>);
+
+/// Hosts by address.
+#[derive(Default)]
+struct Index {
+    /// Each host's name and aliases.
+    hosts: Vec<(CString, Vec<CString>)>,
+    /// Each address's host, as an index into `hosts`.
+    addrs: HashMap<IpAddr, usize>,
+}
+
+impl Index {
+    fn by_addr(&self, addr: &IpAddr) -> Option<HostEntry<'static>> {
+        let (name, aliases) = &self.hosts[*self.addrs.get(addr)?];
+        Some(HostEntry {
+            name: Cow::Owned(name.clone()),
+            aliases: aliases.iter().cloned().map(Cow::Owned).collect(),
+            addr_list: match *addr {
+                IpAddr::V4(addr) => HostAddressList::V4(vec![addr]),
+                IpAddr::V6(addr) => HostAddressList::V6(vec![addr]),
+            },
+        })
+    }
+}
+
+/// Each `ReverseMapped` type's index, and when it was built.
+type Indexes = HashMap<TypeId, (Instant, Arc<Index>)>;
+
+/// Every index built so far.
+static INDEXES: ForkSafeMutex<Indexes> = ForkSafeMutex::new();
+
+impl<S: NameService + 'static, C: ReverseMapConfig> ReverseMapped<S, C> {
+    /// The index, built now if there isn't a current one.
+    fn index() -> Result<Arc<Index>> {
+        let now = Instant::now();
+        {
+            let indexes = INDEXES.lock();
+            if let Some((built, index)) = indexes.get(&TypeId::of::<Self>()) {
+                if C::MAX_AGE.is_none_or(|max_age| now < *built + max_age) {
+                    return Ok(index.clone());
+                }
+            }
+        }
+        let index = Arc::new(Self::build()?);
+        INDEXES.lock()
+            .insert(TypeId::of::<Self>(), (now, index.clone()));
+        Ok(index)
+    }
+
+    fn build() -> Result<Index> {
+        let mut index = Index::default();
+        for entry in S::sethostent(false)? {
+            let entry = entry?;
+            let addrs: Vec<IpAddr> = match entry.addr_list {
+                HostAddressList::V4(addrs) => addrs.into_iter().map(IpAddr::V4).collect(),
+                HostAddressList::V6(addrs) => addrs.into_iter().map(IpAddr::V6).collect(),
+            };
+            let host = index.hosts.len();
+            let mut used = false;
+            for addr in addrs {
+                if index.addrs.contains_key(&addr) {
+                    continue;
+                }
+                if index.addrs.len() >= C::MAX_ADDRESSES {
+                    diag::log(format_args!("{} has more than {} addresses; only some are indexed",
+                                           type_name::<S>(), C::MAX_ADDRESSES));
+                    return Ok(index);
+                }
+                index.addrs.insert(addr, host);
+                used = true;
+            }
+            if used {
+                let aliases = entry.aliases.into_iter().map(Cow::into_owned).collect();
+                index.hosts.push((entry.name.into_owned(), aliases));
+            }
+        }
+        Ok(index)
+    }
+
+    /// Look `addr` up in the index, or if it isn't there, with `s`.
+    fn lookup<T>(addr: &IpAddr, into: fn(HostEntry<'static>) -> T, s: impl FnOnce() -> Result<Option<T>>)
+        -> Result<Option<T>>
+    {
+        match Self::index() {
+            Ok(index) => match index.by_addr(addr) {
+                Some(found) => Ok(Some(into(found))),
+                None => s(),
+            },
+            Err(err) => {
+                if !err.is_insufficient_buffer() {
+                    diag::log(format_args!("can't list the hosts in {} to index them: {:?}, errno {}",
+                                           type_name::<S>(), err.status(), err.errno()));
+                }
+                s()
+            }
+        }
+    }
+
+    /// Forget the index, so the next reverse lookup builds a new one.
+    fn forget() {
+        INDEXES.lock().remove(&TypeId::of::<Self>());
+    }
+}
+
+impl<S: NameService + 'static, C: ReverseMapConfig> NameService for ReverseMapped<S, C> {
+    const ALLOW_EMPTY_ADDRESS_LIST: bool = S::ALLOW_EMPTY_ADDRESS_LIST;
+    const LOOKUP_TIMEOUT: Option<Duration> = S::LOOKUP_TIMEOUT;
+    const LIMITS: HostLimits = S::LIMITS;
+    const VALIDATE_HOSTNAMES: bool = S::VALIDATE_HOSTNAMES;
+
+    fn gethostbyname_r(name: &CStr) -> Result<Option<HostEntry<'_>>> {
+        S::gethostbyname_r(name)
+    }
+
+    fn gethostbyname2_r(name: &CStr, af: AddressFamily) -> Result<Option<HostEntry<'_>>> {
+        S::gethostbyname2_r(name, af)
+    }
+
+    fn gethostbyaddr_r(addr: &IpAddr) -> Result<Option<HostEntry<'_>>> {
+        Self::lookup(addr, |found| found, || S::gethostbyaddr_r(addr))
+    }
+
+    fn gethostbyname3_r(name: &CStr, af: AddressFamily) -> Result<Option<HostEntryWithTtl<'_>>> {
+        S::gethostbyname3_r(name, af)
+    }
+
+    fn gethostbyname4_r(name: &CStr) -> Result<Option<HostAddresses<'_>>> {
+        S::gethostbyname4_r(name)
+    }
+
+    fn gethostbyaddr2_r(addr: &IpAddr) -> Result<Option<HostEntryWithTtl<'_>>> {
+        Self::lookup(addr, |entry| HostEntryWithTtl { entry, ttl: None }, || S::gethostbyaddr2_r(addr))
+    }
+
+    fn sethostent(stay_open: bool) -> Result<Entries<HostEntry<'static>>> {
+        S::sethostent(stay_open)
+    }
+
+    fn on_fork_child() {
+        INDEXES.reset();
+        S::on_fork_child()
+    }
+}
+
+impl<S: NameService + Reload, C: ReverseMapConfig> Reload for ReverseMapped<S, C> {
+    const FILES: &'static [&'static str] = S::FILES;
+
+    fn reload() {
+        S::reload();
+        Self::forget();
+    }
+}
+
+#[test]
+fn test_reverse_mapped() {
+    use crate::testing::{gethostbyaddr, FixedHosts};
+
+    struct NoReverse;
+    impl NameService for NoReverse {
+        fn gethostbyname2_r(name: &CStr, af: AddressFamily) -> Result<Option<HostEntry<'_>>> {
+            FixedHosts::gethostbyname2_r(name, af)
+        }
+
+        fn gethostbyaddr_r(_addr: &IpAddr) -> Result<Option<HostEntry<'_>>> {
+            Ok(None)
+        }
+
+        fn sethostent(stay_open: bool) -> Result<Entries<HostEntry<'static>>> {
+            FixedHosts::sethostent(stay_open)
+        }
+    }
+
+    type Hosts = ReverseMapped<NoReverse>;
+    let entry = gethostbyaddr::<Hosts>("2001:db8::1".parse().unwrap()).unwrap().unwrap();
+    assert_eq!(entry.name.to_str(), Ok("host.test"));
+    assert_eq!(entry.aliases.len(), 1);
+    assert_eq!(entry.addr_list.len(), 1);
+    assert!(gethostbyaddr::<Hosts>("192.0.2.2".parse().unwrap()).unwrap().is_some());
+    assert!(gethostbyaddr::<Hosts>("192.0.2.99".parse().unwrap()).unwrap().is_none());
+
+    // Past `MAX_ADDRESSES`, addresses aren't indexed.
+    struct Small;
+    impl ReverseMapConfig for Small {
+        const MAX_ADDRESSES: usize = 2;
+    }
+    let index = ReverseMapped::<NoReverse, Small>::build().unwrap();
+    assert_eq!(index.addrs.len(), 2);
+    assert!(index.by_addr(&"2001:db8::2".parse().unwrap()).is_none());
+}
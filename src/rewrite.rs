@@ -0,0 +1,186 @@
+//! Looking up one name in place of another, the way a CNAME does.
+
+use crate::errors::Result;
+use crate::interfaces::{AddressFamily, Entries, HostAddresses, HostEntry, HostEntryWithTtl, HostLimits, NameService};
+use std::borrow::Cow;
+use std::ffi::{CStr, CString};
+use std::marker::PhantomData;
+use std::net::IpAddr;
+use std::time::Duration;
+
+/// The names a `Rewritten` service looks up in place of others.
+pub trait RewriteConfig: 'static {
+    /// Pairs of names: the name looked up, and the name to ask the service
+    /// for instead. A name that starts with `.` is a domain: `(".old.corp",
+    /// ".new.corp")` rewrites `old.corp` to `new.corp`, and every name
+    /// under it, such as `web.old.corp`, to the same name under
+    /// `new.corp`. Names match without regard to ASCII case or a trailing
+    /// dot. The first pair that matches is used, and its result isn't
+    /// rewritten again.
+    const RULES: &'static [(&'static str, &'static str)];
+
+    /// If true, a rewritten lookup's canonical name is the name looked up,
+    /// with the service's name as an alias, for programs that insist on
+    /// getting back the name they asked for. If false, as with a CNAME in
+    /// DNS, the service's name is the canonical one and the name looked up
+    /// is an alias.
+    const KEEP_NAME: bool = false;
+}
+
+/// The `hosts` service `S`, but with names looked up under other names, to
+/// keep old names working during a migration:
+///
+/// ```ignore
+/// struct Migration;
+///
+/// impl RewriteConfig for Migration {
+///     const RULES: &'static [(&'static str, &'static str)] = &[(".old.corp", ".new.corp")];
+/// }
+///
+/// nssglue_hosts!("rewrite", Rewritten<DnsService, Migration>);
+/// ```
+///
+/// Names no rule matches, reverse lookups, and enumeration go to `S`
+/// unchanged, as do `S`'s settings, such as `LOOKUP_TIMEOUT`.
+pub struct Rewritten<S, C>(PhantomData<(S, C)>);
+
+/// The name to look up in place of `name`, if a rule in `rules` says so.
+fn rewrite(rules: &[(&str, &str)], name: &CStr) -> Option<CString> {
+    let name = name.to_str().ok()?.trim_end_matches('.');
+    let rewritten = rules.iter().find_map(|&(from, to)| {
+        let to = to.trim_end_matches('.');
+        match from.strip_prefix('.') {
+            None => name.eq_ignore_ascii_case(from.trim_end_matches('.')).then(|| to.to_string()),
+            Some(domain) => {
+                let domain = domain.trim_end_matches('.');
+                let to = to.strip_prefix('.').unwrap_or(to);
+                if name.eq_ignore_ascii_case(domain) {
+                    return Some(to.to_string());
+                }
+                let dot = name.len().checked_sub(domain.len() + 1)?;
+                let (prefix, rest) = (name.get(..dot)?, name.get(dot..)?);
+                (rest.starts_with('.') && rest[1..].eq_ignore_ascii_case(domain)).then(|| format!("{}.{}", prefix, to))
+            }
+        }
+    })?;
+    CString::new(rewritten).ok()
+}
+
+impl<S, C: RewriteConfig> Rewritten<S, C> {
+    /// `entry`, found by looking up another name in place of `asked`.
+    fn fix(mut entry: HostEntry<'static>, asked: &CStr) -> HostEntry<'static> {
+        let asked: Cow<'static, CStr> = Cow::Owned(asked.to_owned());
+        let same = |a: &CStr, b: &CStr| a.to_bytes().eq_ignore_ascii_case(b.to_bytes());
+        if C::KEEP_NAME {
+            let found = std::mem::replace(&mut entry.name, asked.clone());
+            entry.aliases.retain(|alias| !same(alias, &asked));
+            if !same(&found, &asked) {
+                entry.aliases.insert(0, found);
+            }
+        } else if !same(&entry.name, &asked) && !entry.aliases.iter().any(|alias| same(alias, &asked)) {
+            entry.aliases.push(asked);
+        }
+        entry
+    }
+
+    fn lookup<T>(name: &CStr, fix: fn(T, &CStr) -> T, s: impl FnOnce(&CStr) -> Result<Option<T>>)
+        -> Result<Option<T>>
+    {
+        match rewrite(C::RULES, name) {
+            None => s(name),
+            Some(rewritten) => Ok(s(&rewritten)?.map(|found| fix(found, name))),
+        }
+    }
+}
+
+impl<S: NameService, C: RewriteConfig> NameService for Rewritten<S, C> {
+    const ALLOW_EMPTY_ADDRESS_LIST: bool = S::ALLOW_EMPTY_ADDRESS_LIST;
+    const LOOKUP_TIMEOUT: Option<Duration> = S::LOOKUP_TIMEOUT;
+    const LIMITS: HostLimits = S::LIMITS;
+    const VALIDATE_HOSTNAMES: bool = S::VALIDATE_HOSTNAMES;
+
+    fn gethostbyname_r(name: &CStr) -> Result<Option<HostEntry<'_>>> {
+        Self::lookup(name, Self::fix, |name| Ok(S::gethostbyname_r(name)?.map(HostEntry::into_owned)))
+    }
+
+    fn gethostbyname2_r(name: &CStr, af: AddressFamily) -> Result<Option<HostEntry<'_>>> {
+        Self::lookup(name, Self::fix, |name| Ok(S::gethostbyname2_r(name, af)?.map(HostEntry::into_owned)))
+    }
+
+    fn gethostbyaddr_r(addr: &IpAddr) -> Result<Option<HostEntry<'_>>> {
+        S::gethostbyaddr_r(addr)
+    }
+
+    fn gethostbyname3_r(name: &CStr, af: AddressFamily) -> Result<Option<HostEntryWithTtl<'_>>> {
+        Self::lookup(
+            name,
+            |found: HostEntryWithTtl<'static>, asked| HostEntryWithTtl { entry: Self::fix(found.entry, asked), ..found },
+            |name| Ok(S::gethostbyname3_r(name, af)?.map(HostEntryWithTtl::into_owned)),
+        )
+    }
+
+    fn gethostbyname4_r(name: &CStr) -> Result<Option<HostAddresses<'_>>> {
+        Self::lookup(
+            name,
+            |found: HostAddresses<'static>, asked| if C::KEEP_NAME {
+                HostAddresses { name: Cow::Owned(asked.to_owned()), ..found }
+            } else {
+                found
+            },
+            |name| Ok(S::gethostbyname4_r(name)?.map(HostAddresses::into_owned)),
+        )
+    }
+
+    fn gethostbyaddr2_r(addr: &IpAddr) -> Result<Option<HostEntryWithTtl<'_>>> {
+        S::gethostbyaddr2_r(addr)
+    }
+
+    fn sethostent(stay_open: bool) -> Result<Entries<HostEntry<'static>>> {
+        S::sethostent(stay_open)
+    }
+
+    fn on_fork_child() {
+        S::on_fork_child()
+    }
+}
+
+#[test]
+fn test_rewritten() {
+    use crate::testing::{gethostbyname2, gethostbyname4, FixedHosts};
+
+    let rules = &[("old.test", "host.test"), (".legacy.", ".test"), (".everything", "moved.example")];
+    let rewritten = |name: &str| {
+        let name = CString::new(name).unwrap();
+        rewrite(rules, &name).map(|name| name.into_string().unwrap())
+    };
+    assert_eq!(rewritten("OLD.test."), Some("host.test".to_string()));
+    assert_eq!(rewritten("sub.old.test"), None);
+    assert_eq!(rewritten("Web.Legacy"), Some("Web.test".to_string()));
+    assert_eq!(rewritten("legacy"), Some("test".to_string()));
+    assert_eq!(rewritten("notlegacy"), None);
+    assert_eq!(rewritten("a.b.everything"), Some("a.b.moved.example".to_string()));
+
+    struct Migration;
+    impl RewriteConfig for Migration {
+        const RULES: &'static [(&'static str, &'static str)] = &[("old.test", "host.test"), (".legacy", ".test")];
+    }
+    type Hosts = Rewritten<FixedHosts, Migration>;
+    let entry = gethostbyname2::<Hosts>("old.test", AddressFamily::Ipv4).unwrap().unwrap();
+    assert_eq!(entry.name.to_str(), Ok("host.test"));
+    let aliases: Vec<_> = entry.aliases.iter().map(|alias| alias.to_str().unwrap()).collect();
+    assert_eq!(aliases, ["www.test", "old.test"]);
+    assert!(gethostbyname2::<Hosts>("v4only.legacy", AddressFamily::Ipv4).unwrap().is_some());
+    assert!(gethostbyname2::<Hosts>("host.test", AddressFamily::Ipv4).unwrap().is_some());
+
+    struct Keep;
+    impl RewriteConfig for Keep {
+        const RULES: &'static [(&'static str, &'static str)] = Migration::RULES;
+        const KEEP_NAME: bool = true;
+    }
+    let entry = gethostbyname2::<Rewritten<FixedHosts, Keep>>("www.legacy", AddressFamily::Ipv6).unwrap().unwrap();
+    assert_eq!(entry.name.to_str(), Ok("www.legacy"));
+    let aliases: Vec<_> = entry.aliases.iter().map(|alias| alias.to_str().unwrap()).collect();
+    assert_eq!(aliases, ["host.test", "www.test"]);
+    let addrs = gethostbyname4::<Rewritten<FixedHosts, Keep>>("old.test").unwrap().unwrap();
+    assert_eq!((addrs.name.to_str(), addrs.addrs.len()), (Ok("old.test"), 2));
+}
@@ -0,0 +1,241 @@
+//! Trying a name in each of the search domains, as the DNS resolver does.
+
+use crate::errors::{Error, HostError, NssStatus, Result};
+use crate::fork::ForkSafeMutex;
+use crate::interfaces::{AddressFamily, Entries, HostAddresses, HostEntry, HostEntryWithTtl, HostLimits, NameService};
+use std::ffi::{CStr, CString};
+use std::fs;
+use std::marker::PhantomData;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Which domains a `Searched` service tries, and when.
+pub trait SearchConfig: 'static {
+    /// The search domains. If `None`, the `search` or `domain` line in
+    /// `RESOLV_CONF`.
+    const DOMAINS: Option<&'static [&'static str]> = None;
+
+    /// Names with fewer dots than this are tried in the search domains
+    /// first, and on their own last; other names, the other way around. If
+    /// `None`, the `ndots` option in `RESOLV_CONF`, or 1.
+    const NDOTS: Option<usize> = None;
+
+    /// The resolver configuration to read.
+    const RESOLV_CONF: &'static str = "/etc/resolv.conf";
+}
+
+/// The usual settings: whatever `/etc/resolv.conf` says.
+pub struct SearchDefaults;
+
+impl SearchConfig for SearchDefaults {}
+
+/// The `hosts` service `S`, but with short names tried in each search
+/// domain, the way glibc's `dns` service does, for services that only know
+/// fully qualified names:
+///
+/// ```ignore
+/// nssglue_hosts!("consul", Searched<ConsulService>);
+/// ```
+///
+/// With `search corp.example` and the default `ndots` of 1, `web` is
+/// looked up as `web.corp.example` and then `web`, and `web.dev` as
+/// `web.dev` and then `web.dev.corp.example`. A name that ends with a dot
+/// is looked up only as it is, without the dot.
+///
+/// The first name found is the answer. If none is, the result is `NO_DATA`
+/// if any of the names had that, or else `NssStatus::TryAgain` if any
+/// lookup failed that way, or else not found, as with glibc. Any other
+/// error is reported at once.
+///
+/// `RESOLV_CONF` is read once, the first time the module needs it. If it
+/// doesn't exist, there are no search domains. Reverse lookups and
+/// enumeration go to `S` unchanged, as do `S`'s settings, such as
+/// `LOOKUP_TIMEOUT`.
+pub struct Searched<S, C = SearchDefaults>(PhantomData<(S, C)>);
+
+/// The search domains, and `ndots`.
+#[derive(Debug, Default, PartialEq)]
+struct SearchList {
+    domains: Vec<String>,
+    ndots: Option<usize>,
+}
+
+impl SearchList {
+    /// Read the contents of a `resolv.conf`. As with glibc, the last
+    /// `search` or `domain` line wins.
+    fn parse(text: &str) -> SearchList {
+        let mut list = SearchList::default();
+        for line in text.lines() {
+            let mut words = line.split([';', '#']).next().unwrap_or("").split_whitespace();
+            match words.next() {
+                Some("search") => list.domains = words.map(str::to_string).collect(),
+                Some("domain") => list.domains = words.next().map(str::to_string).into_iter().collect(),
+                Some("options") => {
+                    for option in words {
+                        if let Some(ndots) = option.strip_prefix("ndots:").and_then(|n| n.parse::<usize>().ok()) {
+                            list.ndots = Some(ndots.min(15));
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        list
+    }
+}
+
+/// The names to try for `name`, in order.
+fn candidates(name: &CStr, domains: &[impl AsRef<str>], ndots: usize) -> Vec<CString> {
+    let text = match name.to_str() {
+        Ok(text) if !text.is_empty() && !text.ends_with('.') => text,
+        Ok(text) if text.len() > 1 => return vec![CString::new(&text[..text.len() - 1]).unwrap()],
+        _ => return vec![name.to_owned()],
+    };
+    let searched = domains.iter()
+        .map(|domain| domain.as_ref().trim_matches('.'))
+        .filter(|domain| !domain.is_empty())
+        .filter_map(|domain| CString::new(format!("{}.{}", text, domain)).ok());
+    if text.matches('.').count() >= ndots {
+        Some(name.to_owned()).into_iter().chain(searched).collect()
+    } else {
+        searched.chain(Some(name.to_owned())).collect()
+    }
+}
+
+/// Every `resolv.conf` read so far.
+static LISTS: ForkSafeMutex<Vec<(&'static str, Arc<SearchList>)>> = ForkSafeMutex::new();
+
+impl<S, C: SearchConfig> Searched<S, C> {
+    /// The search list from `RESOLV_CONF`, read the first time it's needed.
+    /// The file is read without holding the lock; if two threads both read
+    /// it, the first to finish wins.
+    fn search_list() -> Arc<SearchList> {
+        let cached = LISTS.lock().iter().find(|&&(path, _)| path == C::RESOLV_CONF).map(|(_, list)| list.clone());
+        if let Some(list) = cached {
+            return list;
+        }
+        let list = Arc::new(fs::read(C::RESOLV_CONF)
+            .map(|bytes| SearchList::parse(&String::from_utf8_lossy(&bytes)))
+            .unwrap_or_default());
+        let mut lists = LISTS.lock();
+        match lists.iter().find(|&&(path, _)| path == C::RESOLV_CONF) {
+            Some((_, existing)) => existing.clone(),
+            None => {
+                lists.push((C::RESOLV_CONF, list.clone()));
+                list
+            }
+        }
+    }
+
+    /// Look up `name` with `lookup` under each name in turn.
+    fn search<T>(name: &CStr, lookup: impl Fn(&CStr) -> Result<Option<T>>) -> Result<Option<T>> {
+        let names = match (C::DOMAINS, C::NDOTS) {
+            (Some(domains), Some(ndots)) => candidates(name, domains, ndots),
+            _ => {
+                let list = Self::search_list();
+                let ndots = C::NDOTS.or(list.ndots).unwrap_or(1);
+                match C::DOMAINS {
+                    Some(domains) => candidates(name, domains, ndots),
+                    None => candidates(name, &list.domains, ndots),
+                }
+            }
+        };
+
+        let mut no_data: Option<Error> = None;
+        let mut try_again: Option<Error> = None;
+        let mut not_found: Option<Error> = None;
+        for candidate in names {
+            match lookup(&candidate) {
+                Ok(Some(found)) => return Ok(Some(found)),
+                Ok(None) => {}
+                Err(err) if err.status() == NssStatus::NotFound => {
+                    let slot = if err.host_error() == Some(HostError::NoData) { &mut no_data } else { &mut not_found };
+                    slot.get_or_insert(err);
+                }
+                Err(err) if err.status() == NssStatus::TryAgain && !err.is_insufficient_buffer() => {
+                    try_again.get_or_insert(err);
+                }
+                Err(err) => return Err(err),
+            }
+        }
+        match no_data.or(try_again).or(not_found) {
+            Some(err) => Err(err),
+            None => Ok(None),
+        }
+    }
+}
+
+impl<S: NameService, C: SearchConfig> NameService for Searched<S, C> {
+    const ALLOW_EMPTY_ADDRESS_LIST: bool = S::ALLOW_EMPTY_ADDRESS_LIST;
+    const LOOKUP_TIMEOUT: Option<Duration> = S::LOOKUP_TIMEOUT;
+    const LIMITS: HostLimits = S::LIMITS;
+    const VALIDATE_HOSTNAMES: bool = S::VALIDATE_HOSTNAMES;
+
+    fn gethostbyname_r(name: &CStr) -> Result<Option<HostEntry<'_>>> {
+        Self::search(name, |name| Ok(S::gethostbyname_r(name)?.map(HostEntry::into_owned)))
+    }
+
+    fn gethostbyname2_r(name: &CStr, af: AddressFamily) -> Result<Option<HostEntry<'_>>> {
+        Self::search(name, |name| Ok(S::gethostbyname2_r(name, af)?.map(HostEntry::into_owned)))
+    }
+
+    fn gethostbyaddr_r(addr: &IpAddr) -> Result<Option<HostEntry<'_>>> {
+        S::gethostbyaddr_r(addr)
+    }
+
+    fn gethostbyname3_r(name: &CStr, af: AddressFamily) -> Result<Option<HostEntryWithTtl<'_>>> {
+        Self::search(name, |name| Ok(S::gethostbyname3_r(name, af)?.map(HostEntryWithTtl::into_owned)))
+    }
+
+    fn gethostbyname4_r(name: &CStr) -> Result<Option<HostAddresses<'_>>> {
+        Self::search(name, |name| Ok(S::gethostbyname4_r(name)?.map(HostAddresses::into_owned)))
+    }
+
+    fn gethostbyaddr2_r(addr: &IpAddr) -> Result<Option<HostEntryWithTtl<'_>>> {
+        S::gethostbyaddr2_r(addr)
+    }
+
+    fn sethostent(stay_open: bool) -> Result<Entries<HostEntry<'static>>> {
+        S::sethostent(stay_open)
+    }
+
+    fn on_fork_child() {
+        LISTS.reset();
+        S::on_fork_child()
+    }
+}
+
+#[test]
+fn test_searched() {
+    use crate::testing::{gethostbyname2, FixedHosts};
+
+    let list = SearchList::parse("nameserver 10.0.0.1\n\
+                                  domain ignored.example\n\
+                                  search corp.example. lab.example # comment\n\
+                                  options rotate ndots:2 timeout:1\n");
+    assert_eq!(list, SearchList { domains: vec!["corp.example.".into(), "lab.example".into()], ndots: Some(2) });
+    assert_eq!(SearchList::parse("search a\ndomain b\n").domains, ["b"]);
+
+    let name = |s: &'static [u8]| CStr::from_bytes_with_nul(s).unwrap();
+    let tried = |s, ndots| -> Vec<String> {
+        candidates(name(s), &list.domains, ndots).into_iter().map(|c| c.into_string().unwrap()).collect()
+    };
+    assert_eq!(tried(b"web\0", 1), ["web.corp.example", "web.lab.example", "web"]);
+    assert_eq!(tried(b"web.dev\0", 1), ["web.dev", "web.dev.corp.example", "web.dev.lab.example"]);
+    assert_eq!(tried(b"web.dev\0", 2), ["web.dev.corp.example", "web.dev.lab.example", "web.dev"]);
+    assert_eq!(tried(b"web.\0", 5), ["web"]);
+
+    struct Test;
+    impl SearchConfig for Test {
+        const DOMAINS: Option<&'static [&'static str]> = Some(&["example", "test"]);
+        const NDOTS: Option<usize> = Some(1);
+    }
+    type Hosts = Searched<FixedHosts, Test>;
+    let entry = gethostbyname2::<Hosts>("host", AddressFamily::Ipv4).unwrap().unwrap();
+    assert_eq!(entry.name.to_str(), Ok("host.test"));
+    assert!(gethostbyname2::<Hosts>("localhost.", AddressFamily::Ipv4).unwrap().is_some());
+    assert!(gethostbyname2::<Hosts>("nowhere", AddressFamily::Ipv4).unwrap().is_none());
+    let err = Hosts::gethostbyname2_r(name(b"v4only\0"), AddressFamily::Ipv6).unwrap_err();
+    assert_eq!(err.host_error(), Some(HostError::NoData));
+}
@@ -0,0 +1,70 @@
+//! Constructors for the small libc types the writers fill in.
+//!
+//! The `libc` crate's definitions of these vary from target to target, and
+//! some, like `in6_addr`, have private fields, so they can't be built with a
+//! struct literal. Everything that builds one goes through here, and the
+//! assertions below check at compile time that the layouts are the ones
+//! the writers assume.
+
+use crate::ffi::{c_char, c_int};
+use libc::{AF_INET, AF_INET6, in_addr_t, in6_addr};
+use std::mem;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+/// The size of an IPv4 address, `h_length` for `AF_INET`.
+pub(crate) const INADDRSZ: c_int = 4;
+
+/// The size of an IPv6 address, `h_length` for `AF_INET6`.
+pub(crate) const IN6ADDRSZ: c_int = 16;
+
+const _: () = assert!(mem::size_of::<in_addr_t>() == INADDRSZ as usize);
+const _: () = assert!(mem::size_of::<in6_addr>() == IN6ADDRSZ as usize);
+// Addresses are allocated in the buffer with no padding between them.
+const _: () = assert!(mem::align_of::<in6_addr>() <= mem::size_of::<in6_addr>());
+// Strings are copied byte for byte, so whether `c_char` is signed (x86) or
+// unsigned (ARM, s390x, PowerPC) never matters, but its size does.
+const _: () = assert!(mem::size_of::<c_char>() == 1);
+
+/// An `in_addr_t` holds an address in network byte order: its bytes in
+/// memory are the octets in order, whatever the host's byte order.
+pub(crate) fn in_addr(ip: Ipv4Addr) -> in_addr_t {
+    in_addr_t::from_ne_bytes(ip.octets())
+}
+
+pub(crate) fn in6_addr(ip: Ipv6Addr) -> in6_addr {
+    // All zeros is a valid `in6_addr`, whatever private fields this target
+    // has; only `s6_addr` is common to all of them.
+    let mut addr: in6_addr = unsafe { mem::zeroed() };
+    addr.s6_addr = ip.octets();
+    addr
+}
+
+/// The `family` and `addr` fields of a `gaih_addrtuple`. The address is in
+/// network byte order, like `in_addr_t`, so IPv4 addresses are the first
+/// four bytes rather than the low bits of the first word.
+pub(crate) fn tuple_addr(ip: IpAddr) -> (c_int, [u32; 4]) {
+    let (family, octets) = match ip {
+        IpAddr::V4(ipv4) => {
+            let mut octets = [0; 16];
+            octets[..4].copy_from_slice(&ipv4.octets());
+            (AF_INET, octets)
+        }
+        IpAddr::V6(ipv6) => (AF_INET6, ipv6.octets()),
+    };
+    let mut addr = [0_u32; 4];
+    for (word, chunk) in addr.iter_mut().zip(octets.chunks(4)) {
+        *word = u32::from_ne_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+    }
+    (family, addr)
+}
+
+#[test]
+fn test_shims() {
+    let ip: Ipv6Addr = "2001:db8::1".parse().unwrap();
+    assert_eq!(in6_addr(ip).s6_addr, ip.octets());
+
+    let (family, addr) = tuple_addr(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)));
+    assert_eq!(family, AF_INET);
+    let bytes: Vec<u8> = addr.iter().flat_map(|word| word.to_ne_bytes()).collect();
+    assert_eq!(bytes, [192, 0, 2, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+}
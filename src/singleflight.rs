@@ -0,0 +1,346 @@
+//! Making one call to a service for every thread that asks it the same
+//! thing at the same time.
+
+use crate::cached::{unpack, Key, Value};
+use crate::errors::{Error, NssStatus, Result};
+use crate::ffi::{gid_t, uid_t};
+use crate::fork::ForkSafeMutex;
+use crate::interfaces::{AddressFamily, Entries, GroupEntry, GroupService, HostAddresses, HostEntry,
+                        HostEntryWithTtl, HostLimits, NameService, PasswdEntry, PasswdService, ShadowEntry,
+                        ShadowService};
+use libc::EIO;
+use std::any::TypeId;
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::ffi::CStr;
+use std::marker::PhantomData;
+use std::net::IpAddr;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
+
+/// The service `S`, except that when several threads look up the same
+/// thing at once, only the first asks `S`, and the others wait for its
+/// answer, so 50 threads resolving one name make one query, not 50:
+///
+/// ```ignore
+/// nssglue_hosts!("consul", Singleflight<Cached<ConsulService>>);
+/// ```
+///
+/// Lookups of hosts, users, and groups, and `initgroups`, are shared this
+/// way; nothing is kept once the first thread has its answer, so this goes
+/// well outside `Cached`. If the first thread's call panics, the others
+/// get `NssStatus::Unavailable` with errno `EIO`. A call that looks up the
+/// same thing again from inside `S`, on the same thread, goes straight to
+/// `S`, rather than waiting for itself.
+///
+/// Enumeration and shadow passwords go straight to `S`, as do `S`'s
+/// settings, such as `LOOKUP_TIMEOUT`. In the child after a `fork`, no
+/// lookups are in progress, whatever the parent was doing.
+pub struct Singleflight<S>(PhantomData<S>);
+
+/// A lookup in progress, and once it's done, its answer.
+#[derive(Default)]
+struct Flight {
+    answer: Mutex<Option<Result<Option<Value>>>>,
+    landed: Condvar,
+}
+
+type Flights = HashMap<(TypeId, Key), Arc<Flight>>;
+
+/// Every lookup in progress. None of them are in the child of a fork,
+/// which doesn't have the threads doing them.
+static FLIGHTS: ForkSafeMutex<Flights> = ForkSafeMutex::new();
+
+thread_local! {
+    /// How many lookups this thread is making for other threads.
+    static LEADING: Cell<usize> = const { Cell::new(0) };
+}
+
+/// The thread making a lookup for the others. When dropped, even by a
+/// panic, it gives them the answer and takes the lookup out of `FLIGHTS`.
+struct Leader {
+    key: (TypeId, Key),
+    flight: Arc<Flight>,
+}
+
+impl Leader {
+    fn new(key: (TypeId, Key), flight: Arc<Flight>) -> Leader {
+        let _ = LEADING.try_with(|leading| leading.set(leading.get() + 1));
+        Leader { key, flight }
+    }
+
+    fn land(&self, answer: Result<Option<Value>>) {
+        *self.flight.answer.lock().unwrap_or_else(|poisoned| poisoned.into_inner()) = Some(answer);
+    }
+}
+
+impl Drop for Leader {
+    fn drop(&mut self) {
+        let mut flights = FLIGHTS.lock();
+        if flights.get(&self.key).is_some_and(|flight| Arc::ptr_eq(flight, &self.flight)) {
+            flights.remove(&self.key);
+        }
+        drop(flights);
+        let mut answer = self.flight.answer.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        answer.get_or_insert_with(|| Err(Error::with_errno(NssStatus::Unavailable, EIO)));
+        self.flight.landed.notify_all();
+        let _ = LEADING.try_with(|leading| leading.set(leading.get() - 1));
+    }
+}
+
+impl<S: 'static> Singleflight<S> {
+    /// Call `fetch`, unless another thread is already looking up `key`, and
+    /// then wait for its answer instead. `into` and `from` convert to and
+    /// from a `Value`.
+    fn lookup<T>(
+        key: Key,
+        into: fn(T) -> Value,
+        from: fn(&Value) -> Option<T>,
+        fetch: impl FnOnce() -> Result<Option<T>>,
+    ) -> Result<Option<T>> {
+        // This thread may be inside `S` for another thread already, and
+        // waiting for that would wait forever.
+        if LEADING.try_with(Cell::get).unwrap_or(0) > 0 {
+            return fetch();
+        }
+
+        let key = (TypeId::of::<Self>(), key);
+        let mut in_progress = FLIGHTS.lock();
+        if let Some(flight) = in_progress.get(&key).cloned() {
+            drop(in_progress);
+            let mut answer = flight.answer.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+            while answer.is_none() {
+                answer = flight.landed.wait(answer).unwrap_or_else(|poisoned| poisoned.into_inner());
+            }
+            if let Some(answer) = answer.as_ref().and_then(|answer| unpack(answer, from)) {
+                return answer;
+            }
+            drop(answer);
+            return fetch();
+        }
+        let flight = Arc::new(Flight::default());
+        in_progress.insert(key.clone(), flight.clone());
+        drop(in_progress);
+
+        let leader = Leader::new(key, flight);
+        match fetch() {
+            Ok(Some(found)) => {
+                let value = into(found);
+                let found = from(&value);
+                leader.land(Ok(Some(value)));
+                Ok(found)
+            }
+            Ok(None) => {
+                leader.land(Ok(None));
+                Ok(None)
+            }
+            Err(err) => {
+                leader.land(Err(err.clone()));
+                Err(err)
+            }
+        }
+    }
+}
+
+fn host(value: &Value) -> Option<HostEntryWithTtl<'static>> {
+    match value {
+        Value::Host(found) => Some(found.clone()),
+        _ => None,
+    }
+}
+
+fn passwd(value: &Value) -> Option<PasswdEntry<'static>> {
+    match value {
+        Value::Passwd(found) => Some(found.clone()),
+        _ => None,
+    }
+}
+
+fn group(value: &Value) -> Option<GroupEntry<'static>> {
+    match value {
+        Value::Group(found) => Some(found.clone()),
+        _ => None,
+    }
+}
+
+impl<S: NameService + 'static> NameService for Singleflight<S> {
+    const ALLOW_EMPTY_ADDRESS_LIST: bool = S::ALLOW_EMPTY_ADDRESS_LIST;
+    const LOOKUP_TIMEOUT: Option<Duration> = S::LOOKUP_TIMEOUT;
+    const LIMITS: HostLimits = S::LIMITS;
+    const VALIDATE_HOSTNAMES: bool = S::VALIDATE_HOSTNAMES;
+
+    fn gethostbyname2_r(name: &CStr, af: AddressFamily) -> Result<Option<HostEntry<'_>>> {
+        Ok(Self::gethostbyname3_r(name, af)?.map(|found| found.entry))
+    }
+
+    fn gethostbyaddr_r(addr: &IpAddr) -> Result<Option<HostEntry<'_>>> {
+        Ok(Self::gethostbyaddr2_r(addr)?.map(|found| found.entry))
+    }
+
+    fn gethostbyname3_r(name: &CStr, af: AddressFamily) -> Result<Option<HostEntryWithTtl<'_>>> {
+        Self::lookup(Key::Name(name.to_owned(), af), Value::Host, host,
+                     || Ok(S::gethostbyname3_r(name, af)?.map(HostEntryWithTtl::into_owned)))
+    }
+
+    fn gethostbyname4_r(name: &CStr) -> Result<Option<HostAddresses<'_>>> {
+        Self::lookup(
+            Key::AllAddresses(name.to_owned()),
+            Value::Addresses,
+            |value| match value {
+                Value::Addresses(found) => Some(found.clone()),
+                _ => None,
+            },
+            || Ok(S::gethostbyname4_r(name)?.map(HostAddresses::into_owned)),
+        )
+    }
+
+    fn gethostbyaddr2_r(addr: &IpAddr) -> Result<Option<HostEntryWithTtl<'_>>> {
+        Self::lookup(Key::Addr(*addr), Value::Host, host,
+                     || Ok(S::gethostbyaddr2_r(addr)?.map(HostEntryWithTtl::into_owned)))
+    }
+
+    fn sethostent(stay_open: bool) -> Result<Entries<HostEntry<'static>>> {
+        S::sethostent(stay_open)
+    }
+
+    fn on_fork_child() {
+        FLIGHTS.reset();
+        S::on_fork_child();
+    }
+}
+
+impl<S: PasswdService + 'static> PasswdService for Singleflight<S> {
+    const LOOKUP_TIMEOUT: Option<Duration> = S::LOOKUP_TIMEOUT;
+
+    fn getpwnam_r(name: &CStr) -> Result<Option<PasswdEntry<'_>>> {
+        Self::lookup(Key::User(name.to_owned()), Value::Passwd, passwd,
+                     || Ok(S::getpwnam_r(name)?.map(PasswdEntry::into_owned)))
+    }
+
+    fn getpwuid_r(uid: uid_t) -> Result<Option<PasswdEntry<'static>>> {
+        Self::lookup(Key::Uid(uid), Value::Passwd, passwd, || S::getpwuid_r(uid))
+    }
+
+    fn setpwent() -> Result<Entries<PasswdEntry<'static>>> {
+        S::setpwent()
+    }
+
+    fn on_fork_child() {
+        FLIGHTS.reset();
+        S::on_fork_child();
+    }
+}
+
+impl<S: GroupService + 'static> GroupService for Singleflight<S> {
+    const LOOKUP_TIMEOUT: Option<Duration> = S::LOOKUP_TIMEOUT;
+
+    fn getgrnam_r(name: &CStr) -> Result<Option<GroupEntry<'_>>> {
+        Self::lookup(Key::Group(name.to_owned()), Value::Group, group,
+                     || Ok(S::getgrnam_r(name)?.map(GroupEntry::into_owned)))
+    }
+
+    fn getgrgid_r(gid: gid_t) -> Result<Option<GroupEntry<'static>>> {
+        Self::lookup(Key::Gid(gid), Value::Group, group, || S::getgrgid_r(gid))
+    }
+
+    fn setgrent() -> Result<Entries<GroupEntry<'static>>> {
+        S::setgrent()
+    }
+
+    fn initgroups_dyn(user: &CStr, group: gid_t) -> Result<Option<Vec<gid_t>>> {
+        Self::lookup(
+            Key::Groups(user.to_owned(), group),
+            Value::Gids,
+            |value| match value {
+                Value::Gids(gids) => Some(gids.clone()),
+                _ => None,
+            },
+            || S::initgroups_dyn(user, group),
+        )
+    }
+
+    fn on_fork_child() {
+        FLIGHTS.reset();
+        S::on_fork_child();
+    }
+}
+
+impl<S: ShadowService> ShadowService for Singleflight<S> {
+    const LOOKUP_TIMEOUT: Option<Duration> = S::LOOKUP_TIMEOUT;
+
+    fn getspnam_r(name: &CStr) -> Result<Option<ShadowEntry<'_>>> {
+        S::getspnam_r(name)
+    }
+
+    fn setspent() -> Result<Entries<ShadowEntry<'static>>> {
+        S::setspent()
+    }
+
+    fn on_fork_child() {
+        S::on_fork_child();
+    }
+}
+
+#[test]
+fn test_singleflight() {
+    use crate::interfaces::HostAddressList;
+    use std::borrow::Cow;
+    use std::net::Ipv4Addr;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Barrier;
+    use std::thread;
+
+    static CALLS: AtomicUsize = AtomicUsize::new(0);
+    struct Slow;
+    impl NameService for Slow {
+        fn gethostbyname2_r(name: &CStr, af: AddressFamily) -> Result<Option<HostEntry<'_>>> {
+            CALLS.fetch_add(1, Ordering::SeqCst);
+            thread::sleep(Duration::from_millis(200));
+            match name.to_bytes() {
+                b"panic.test" => panic!("backend fell over"),
+                // Looking up the same name again, from inside the lookup.
+                b"again.test" if CALLS.load(Ordering::SeqCst) < 3 => {
+                    Singleflight::<Slow>::gethostbyname2_r(name, af).map(|found| found.map(HostEntry::into_owned))
+                }
+                _ => Ok(Some(HostEntry {
+                    name: Cow::Borrowed(name),
+                    aliases: vec![],
+                    addr_list: HostAddressList::V4(vec![Ipv4Addr::new(192, 0, 2, 1)]),
+                })),
+            }
+        }
+
+        fn gethostbyaddr_r(_addr: &IpAddr) -> Result<Option<HostEntry<'_>>> {
+            Ok(None)
+        }
+    }
+
+    let lookup_at_once = |name: &'static str| -> Vec<thread::Result<Result<bool>>> {
+        let barrier = Arc::new(Barrier::new(8));
+        let threads: Vec<_> = (0..8)
+            .map(|_| {
+                let barrier = barrier.clone();
+                thread::spawn(move || {
+                    let name = std::ffi::CString::new(name).unwrap();
+                    barrier.wait();
+                    Singleflight::<Slow>::gethostbyname2_r(&name, AddressFamily::Ipv4).map(|found| found.is_some())
+                })
+            })
+            .collect();
+        threads.into_iter().map(|thread| thread.join()).collect()
+    };
+
+    let answers = lookup_at_once("host.test");
+    assert!(answers.iter().all(|answer| matches!(answer, Ok(Ok(true)))));
+    assert_eq!(CALLS.swap(0, Ordering::SeqCst), 1);
+
+    // One thread panics; the rest get an error.
+    let answers = lookup_at_once("panic.test");
+    assert_eq!(answers.iter().filter(|answer| answer.is_err()).count(), 1);
+    assert!(answers.iter().flatten().all(|answer| answer.as_ref().is_err_and(|err| err.errno() == EIO)));
+    assert_eq!(CALLS.swap(0, Ordering::SeqCst), 1);
+
+    let name = CStr::from_bytes_with_nul(b"again.test\0").unwrap();
+    assert!(Singleflight::<Slow>::gethostbyname2_r(name, AddressFamily::Ipv4).unwrap().is_some());
+    assert_eq!(CALLS.load(Ordering::SeqCst), 3);
+}
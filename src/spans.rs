@@ -0,0 +1,165 @@
+//! Reporting calls, and what happens during them, to the `tracing` crate.
+//!
+//! An NSS module is a library with its own copy of `tracing`, loaded into
+//! programs that may have a subscriber of their own, which the module can't
+//! see, or none at all. So a module never sets a global default subscriber,
+//! which could replace the program's if it happened to share the module's
+//! copy. Instead it hands one to `init_tracing`, and the glue makes it the
+//! thread's default for the length of each call, and in the threads the
+//! crate starts to do a call's work. Nothing is reported until then.
+//!
+//! Each call through the glue is a `nss_call` span, with `function` and
+//! `args` fields (the arguments as traced; see `crate::trace`), and ends
+//! with a `debug` event carrying its `status` and `latency_us`. Within it,
+//! `Cached` reports hits, misses, and refreshes as `debug` events.
+//!
+//! Diagnostic messages, such as a backend failing or timing out, go to the
+//! `DiagnosticSink` as usual. After `log_to_tracing`, they're `warn` events
+//! instead, reported to the subscriber if they happen during a call and
+//! dropped otherwise. Unlike the other sinks, a subscriber may allocate and
+//! take locks, so this gives up diagnostics in the child after `fork` and
+//! in signal handlers; the message a bug aborts with goes to stderr.
+
+use crate::diag;
+use std::fmt;
+use std::sync::OnceLock;
+use std::time::Instant;
+use tracing::dispatcher::{self, DefaultGuard, Dispatch};
+use tracing::span::EnteredSpan;
+use tracing::Span;
+
+/// The subscriber given to `init_tracing`.
+static DISPATCH: OnceLock<Dispatch> = OnceLock::new();
+
+/// Report calls from now on to the subscriber that `make` returns, for
+/// example:
+///
+/// ```ignore
+/// nsswitch_service::init_tracing(|| {
+///     let subscriber = tracing_subscriber::fmt().with_writer(open_log_file()).finish();
+///     tracing::Dispatch::new(subscriber)
+/// });
+/// ```
+///
+/// Only the first call does anything; later ones return `false` without
+/// calling `make`, so it's safe to call this at the start of every lookup.
+/// The subscriber is never made the global default.
+pub fn init_tracing<F: FnOnce() -> Dispatch>(make: F) -> bool {
+    let mut made = false;
+    DISPATCH.get_or_init(|| {
+        made = true;
+        make()
+    });
+    made
+}
+
+/// Send this crate's diagnostic messages to the subscriber given to
+/// `init_tracing` from now on, as `warn` events. Call
+/// `set_diagnostic_sink` to send them elsewhere again.
+pub fn log_to_tracing() {
+    diag::log_to_tracing();
+}
+
+/// Make the subscriber given to `init_tracing`, if there is one, this
+/// thread's default until the guard is dropped.
+pub(crate) fn enter() -> Option<DefaultGuard> {
+    DISPATCH.get().map(dispatcher::set_default)
+}
+
+/// Report what this thread does as part of `span`, which a thread that's
+/// handling a call passed to it, until the guard is dropped.
+pub(crate) fn enter_thread(span: Span) -> (EnteredSpan, Option<DefaultGuard>) {
+    let dispatch = enter();
+    (span.entered(), dispatch)
+}
+
+/// The span of a call through the glue.
+pub(crate) struct CallSpan {
+    // Exited before the subscriber stops being the default.
+    span: EnteredSpan,
+    start: Instant,
+    _dispatch: Option<DefaultGuard>,
+}
+
+impl CallSpan {
+    pub(crate) fn enter(function: &'static str, args: fmt::Arguments<'_>) -> CallSpan {
+        let dispatch = enter();
+        CallSpan {
+            span: tracing::info_span!("nss_call", function, args = %args).entered(),
+            start: Instant::now(),
+            _dispatch: dispatch,
+        }
+    }
+
+    pub(crate) fn finish(self, status: &str) {
+        let latency_us = self.start.elapsed().as_micros() as u64;
+        tracing::debug!(parent: &self.span, status, latency_us, "returned");
+    }
+}
+
+/// Report a diagnostic message as an event, if this thread has a
+/// subscriber, for `log_to_tracing`.
+pub(crate) fn diagnostic(message: &str) {
+    tracing::warn!("{}", message);
+}
+
+#[test]
+fn test_init_tracing() {
+    use crate::testing::{gethostbyname2, FixedHosts};
+    use crate::AddressFamily;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tracing::span::{Attributes, Id, Record};
+    use tracing::{Event, Level, Metadata, Subscriber};
+
+    static SPANS: AtomicUsize = AtomicUsize::new(0);
+    static EVENTS: AtomicUsize = AtomicUsize::new(0);
+    static WARNINGS: AtomicUsize = AtomicUsize::new(0);
+
+    struct Counter;
+    impl Subscriber for Counter {
+        fn enabled(&self, _metadata: &Metadata<'_>) -> bool {
+            true
+        }
+
+        fn new_span(&self, _span: &Attributes<'_>) -> Id {
+            Id::from_u64(SPANS.fetch_add(1, Ordering::SeqCst) as u64 + 1)
+        }
+
+        fn record(&self, _span: &Id, _values: &Record<'_>) {}
+
+        fn record_follows_from(&self, _span: &Id, _follows: &Id) {}
+
+        fn event(&self, event: &Event<'_>) {
+            EVENTS.fetch_add(1, Ordering::SeqCst);
+            if *event.metadata().level() == Level::WARN {
+                WARNINGS.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+
+        fn enter(&self, _span: &Id) {}
+
+        fn exit(&self, _span: &Id) {}
+    }
+
+    assert!(init_tracing(|| Dispatch::new(Counter)));
+    assert!(!init_tracing(|| unreachable!()));
+    assert!(gethostbyname2::<FixedHosts>("host.test", AddressFamily::Ipv4).unwrap().is_some());
+    assert!(SPANS.load(Ordering::SeqCst) >= 1);
+    assert!(EVENTS.load(Ordering::SeqCst) >= 1);
+
+    // Outside a call, the subscriber isn't the default.
+    assert!(!dispatcher::get_default(|current| current.is::<Counter>()));
+    let guard = enter();
+    assert!(dispatcher::get_default(|current| current.is::<Counter>()));
+
+    // Diagnostics are events only after `log_to_tracing`.
+    diag::log(format_args!("not an event"));
+    assert_eq!(WARNINGS.load(Ordering::SeqCst), 0);
+    log_to_tracing();
+    diag::log(format_args!("an event"));
+    crate::set_diagnostic_sink(crate::DiagnosticSink::Stderr);
+    assert!(WARNINGS.load(Ordering::SeqCst) >= 1);
+
+    drop(guard);
+    assert!(!dispatcher::get_default(|current| current.is::<Counter>()));
+}
@@ -0,0 +1,316 @@
+//! Sending lookups to different services depending on the domain or
+//! network.
+
+use crate::diag;
+use crate::errors::{Error, HostError, NssStatus, Result};
+use crate::fork::ForkSafeMutex;
+use crate::hostname::is_valid_hostname;
+use crate::interfaces::{AddressFamily, Entries, HostAddresses, HostEntry, HostEntryWithTtl, NameService};
+use libc::ENOENT;
+use std::any::{type_name, TypeId};
+use std::collections::HashMap;
+use std::ffi::CStr;
+use std::marker::PhantomData;
+use std::net::IpAddr;
+
+/// A `hosts` service to send some lookups to. Make one with `Route::to`.
+#[derive(Clone, Copy)]
+pub struct Route {
+    gethostbyname_r: for<'a> fn(&'a CStr) -> Result<Option<HostEntry<'a>>>,
+    gethostbyname2_r: for<'a> fn(&'a CStr, AddressFamily) -> Result<Option<HostEntry<'a>>>,
+    gethostbyname3_r: for<'a> fn(&'a CStr, AddressFamily) -> Result<Option<HostEntryWithTtl<'a>>>,
+    gethostbyname4_r: for<'a> fn(&'a CStr) -> Result<Option<HostAddresses<'a>>>,
+    gethostbyaddr_r: for<'a> fn(&'a IpAddr) -> Result<Option<HostEntry<'a>>>,
+    gethostbyaddr2_r: for<'a> fn(&'a IpAddr) -> Result<Option<HostEntryWithTtl<'a>>>,
+    sethostent: fn(bool) -> Result<Entries<HostEntry<'static>>>,
+    on_fork_child: fn(),
+    validate_hostnames: bool,
+}
+
+impl Route {
+    /// A route to the service `S`.
+    pub const fn to<S: NameService>() -> Route {
+        Route {
+            gethostbyname_r: S::gethostbyname_r,
+            gethostbyname2_r: S::gethostbyname2_r,
+            gethostbyname3_r: S::gethostbyname3_r,
+            gethostbyname4_r: S::gethostbyname4_r,
+            gethostbyaddr_r: S::gethostbyaddr_r,
+            gethostbyaddr2_r: S::gethostbyaddr2_r,
+            sethostent: S::sethostent,
+            on_fork_child: S::on_fork_child,
+            validate_hostnames: S::VALIDATE_HOSTNAMES,
+        }
+    }
+}
+
+/// Which service a `SplitHorizon` sends each name and address to.
+pub trait SplitHorizonConfig: 'static {
+    /// Pairs of a domain and the service for names in it. `corp.example`
+    /// matches that name and every name under it, such as
+    /// `web.corp.example`, without regard to ASCII case or a trailing dot.
+    /// It may also be written `.corp.example` or `*.corp.example`. The empty
+    /// domain matches every name, for a default route. If more than one
+    /// domain matches, the longest wins, or if they are the same, the
+    /// first.
+    const ROUTES: &'static [(&'static str, Route)];
+
+    /// Pairs of a network and the service for reverse lookups of addresses
+    /// in it. A network is written as an address and prefix length, such as
+    /// `10.0.0.0/8` or `fd00::/8`, or as a single address. `0.0.0.0/0` and
+    /// `::/0` match every address of their family, for a default route. If
+    /// more than one network matches, the one with the longest prefix wins,
+    /// or if they are the same, the first. An entry that isn't a network
+    /// matches nothing, and is logged the first time it's needed. The
+    /// default is none, so reverse lookups aren't found.
+    const NETWORKS: &'static [(&'static str, Route)] = &[];
+}
+
+/// A `hosts` service that sends each lookup to another service chosen by
+/// the name's domain, so one module can carry a whole resolution policy:
+///
+/// ```ignore
+/// struct Policy;
+///
+/// impl SplitHorizonConfig for Policy {
+///     const ROUTES: &'static [(&'static str, Route)] = &[
+///         ("corp.example", Route::to::<DnsForwarderService<Vpn>>()),
+///         ("local", Route::to::<MdnsService>()),
+///     ];
+/// }
+///
+/// nssglue_hosts!("policy", SplitHorizon<Policy>);
+/// ```
+///
+/// Reverse lookups have no domain to go by, so they are routed by network
+/// instead, with `NETWORKS`:
+///
+/// ```ignore
+///     const NETWORKS: &'static [(&'static str, Route)] = &[
+///         ("10.0.0.0/8", Route::to::<IpamService>()),
+///         ("172.17.0.0/16", Route::to::<ContainerService>()),
+///     ];
+/// ```
+///
+/// Names and addresses no route matches aren't found. A route's
+/// `VALIDATE_HOSTNAMES` applies to the names sent to it, but its
+/// `LOOKUP_TIMEOUT`, `LIMITS`, and `ALLOW_EMPTY_ADDRESS_LIST` don't; wrap
+/// it in `Timeout` for a deadline. Enumeration lists the hosts of each
+/// route in `ROUTES` that would be routed to it, skipping routes that fail,
+/// unless they all do.
+pub struct SplitHorizon<C>(PhantomData<C>);
+
+/// The domain `suffix` stands for, without wildcards or dots at the ends.
+fn domain(suffix: &str) -> &[u8] {
+    let suffix = suffix.strip_prefix('*').unwrap_or(suffix);
+    let suffix = suffix.strip_prefix('.').unwrap_or(suffix);
+    suffix.strip_suffix('.').unwrap_or(suffix).as_bytes()
+}
+
+/// The index of the route for `name` in `routes`.
+fn choose(routes: &[(&str, Route)], name: &[u8]) -> Option<usize> {
+    let name = name.strip_suffix(b".").unwrap_or(name);
+    let mut best: Option<(usize, usize)> = None;
+    for (i, &(suffix, _)) in routes.iter().enumerate() {
+        let domain = domain(suffix);
+        let matches = domain.is_empty()
+            || name.eq_ignore_ascii_case(domain)
+            || name.len() > domain.len()
+                && name[name.len() - domain.len() - 1] == b'.'
+                && name[name.len() - domain.len()..].eq_ignore_ascii_case(domain);
+        if matches && best.is_none_or(|(_, len)| domain.len() > len) {
+            best = Some((i, domain.len()));
+        }
+    }
+    best.map(|(i, _)| i)
+}
+
+/// The address and prefix length of the network `network`.
+fn network(network: &str) -> Option<(IpAddr, u32)> {
+    let (addr, prefix) = match network.split_once('/') {
+        Some((addr, prefix)) => (addr.parse::<IpAddr>().ok()?, Some(prefix.parse::<u32>().ok()?)),
+        None => (network.parse::<IpAddr>().ok()?, None),
+    };
+    let bits = if addr.is_ipv4() { 32 } else { 128 };
+    let prefix = prefix.unwrap_or(bits);
+    (prefix <= bits).then_some((addr, prefix))
+}
+
+/// Each `SplitHorizon` type's `NETWORKS`, parsed, with `None` for entries
+/// that aren't networks.
+type Networks = HashMap<TypeId, Vec<Option<(IpAddr, u32)>>>;
+
+/// Every `NETWORKS` parsed so far. They're constants, so each is parsed the
+/// first time it's needed and kept.
+static PARSED_NETWORKS: ForkSafeMutex<Networks> = ForkSafeMutex::new();
+
+/// Parse `networks`, logging the entries that aren't networks, which match
+/// nothing.
+fn parse_networks(networks: &[(&str, Route)], owner: &str) -> Vec<Option<(IpAddr, u32)>> {
+    networks.iter().map(|&(written, _)| {
+        let parsed = network(written);
+        if parsed.is_none() {
+            diag::log(format_args!("{}: {:?} isn't a network; ignoring it", owner, written));
+        }
+        parsed
+    }).collect()
+}
+
+/// The index of the route for `addr` in `networks`, as parsed by
+/// `parse_networks`.
+fn choose_network(networks: &[Option<(IpAddr, u32)>], addr: &IpAddr) -> Option<usize> {
+    let mut best: Option<(usize, u32)> = None;
+    for (i, &network) in networks.iter().enumerate() {
+        let (matches, prefix) = match (network, addr) {
+            (Some((IpAddr::V4(network), prefix)), IpAddr::V4(addr)) => {
+                let mask = u32::MAX.checked_shl(32 - prefix).unwrap_or(0);
+                (u32::from(network) & mask == u32::from(*addr) & mask, prefix)
+            }
+            (Some((IpAddr::V6(network), prefix)), IpAddr::V6(addr)) => {
+                let mask = u128::MAX.checked_shl(128 - prefix).unwrap_or(0);
+                (u128::from(network) & mask == u128::from(*addr) & mask, prefix)
+            }
+            _ => (false, 0),
+        };
+        if matches && best.is_none_or(|(_, len)| prefix > len) {
+            best = Some((i, prefix));
+        }
+    }
+    best.map(|(i, _)| i)
+}
+
+impl<C: SplitHorizonConfig> SplitHorizon<C> {
+    /// The route for `name`, if it has one and the name is acceptable to it.
+    fn route(name: &CStr) -> Result<&'static Route> {
+        match choose(C::ROUTES, name.to_bytes()).map(|i| &C::ROUTES[i].1) {
+            Some(route) if !route.validate_hostnames || is_valid_hostname(name) => Ok(route),
+            _ => Err(Error::with_host(NssStatus::NotFound, ENOENT, HostError::HostNotFound)),
+        }
+    }
+
+    /// The route for `addr`, if it has one.
+    fn route_addr(addr: &IpAddr) -> Result<&'static Route> {
+        let mut parsed = PARSED_NETWORKS.lock();
+        let networks = parsed.entry(TypeId::of::<Self>())
+            .or_insert_with(|| parse_networks(C::NETWORKS, type_name::<C>()));
+        choose_network(networks, addr)
+            .map(|i| &C::NETWORKS[i].1)
+            .ok_or_else(|| Error::with_host(NssStatus::NotFound, ENOENT, HostError::HostNotFound))
+    }
+}
+
+impl<C: SplitHorizonConfig> NameService for SplitHorizon<C> {
+    fn gethostbyname_r(name: &CStr) -> Result<Option<HostEntry<'_>>> {
+        (Self::route(name)?.gethostbyname_r)(name)
+    }
+
+    fn gethostbyname2_r(name: &CStr, af: AddressFamily) -> Result<Option<HostEntry<'_>>> {
+        (Self::route(name)?.gethostbyname2_r)(name, af)
+    }
+
+    fn gethostbyaddr_r(addr: &IpAddr) -> Result<Option<HostEntry<'_>>> {
+        (Self::route_addr(addr)?.gethostbyaddr_r)(addr)
+    }
+
+    fn gethostbyname3_r(name: &CStr, af: AddressFamily) -> Result<Option<HostEntryWithTtl<'_>>> {
+        (Self::route(name)?.gethostbyname3_r)(name, af)
+    }
+
+    fn gethostbyname4_r(name: &CStr) -> Result<Option<HostAddresses<'_>>> {
+        (Self::route(name)?.gethostbyname4_r)(name)
+    }
+
+    fn gethostbyaddr2_r(addr: &IpAddr) -> Result<Option<HostEntryWithTtl<'_>>> {
+        (Self::route_addr(addr)?.gethostbyaddr2_r)(addr)
+    }
+
+    fn sethostent(stay_open: bool) -> Result<Entries<HostEntry<'static>>> {
+        let mut all: Vec<Entries<HostEntry<'static>>> = Vec::new();
+        let mut first_err = None;
+        for (i, (_, route)) in C::ROUTES.iter().enumerate() {
+            match (route.sethostent)(stay_open) {
+                Ok(entries) => all.push(Box::new(entries.filter(move |entry| match entry {
+                    Ok(entry) => choose(C::ROUTES, entry.name.to_bytes()) == Some(i),
+                    Err(_) => true,
+                }))),
+                Err(err) => {
+                    first_err.get_or_insert(err);
+                }
+            }
+        }
+        match first_err {
+            Some(err) if all.is_empty() => Err(err),
+            _ => Ok(Box::new(all.into_iter().flatten())),
+        }
+    }
+
+    fn on_fork_child() {
+        PARSED_NETWORKS.reset();
+        for (_, route) in C::ROUTES.iter().chain(C::NETWORKS) {
+            (route.on_fork_child)();
+        }
+    }
+}
+
+#[test]
+fn test_split_horizon() {
+    use crate::testing::{gethostbyaddr, gethostbyname2, AlwaysTryAgain, FixedHosts};
+
+    let routes = &[("*.test", Route::to::<FixedHosts>()),
+                   ("www.test.", Route::to::<AlwaysTryAgain>()),
+                   (".WWW.test", Route::to::<FixedHosts>())];
+    assert_eq!(choose(routes, b"host.TEST."), Some(0));
+    assert_eq!(choose(routes, b"www.test"), Some(1));
+    assert_eq!(choose(routes, b"a.www.test"), Some(1));
+    assert_eq!(choose(routes, b"test"), Some(0));
+    assert_eq!(choose(routes, b"latest"), None);
+    assert_eq!(choose(&[("", Route::to::<FixedHosts>())], b"anything"), Some(0));
+
+    let networks = &[("0.0.0.0/0", Route::to::<FixedHosts>()),
+                     ("10.0.0.0/8", Route::to::<FixedHosts>()),
+                     ("10.1.0.0/16", Route::to::<FixedHosts>()),
+                     ("10.1.2.3", Route::to::<FixedHosts>()),
+                     ("fd00::/8", Route::to::<FixedHosts>()),
+                     ("10.0.0.0/33", Route::to::<FixedHosts>())];
+    let parsed = parse_networks(networks, "test");
+    assert!(parsed[5].is_none());
+    let chosen = |addr: &str| choose_network(&parsed, &addr.parse().unwrap());
+    assert_eq!(chosen("192.0.2.1"), Some(0));
+    assert_eq!(chosen("10.200.0.1"), Some(1));
+    assert_eq!(chosen("10.1.2.4"), Some(2));
+    assert_eq!(chosen("10.1.2.3"), Some(3));
+    assert_eq!(chosen("fdab::1"), Some(4));
+    assert_eq!(chosen("2001:db8::1"), None);
+
+    struct Policy;
+    impl SplitHorizonConfig for Policy {
+        const ROUTES: &'static [(&'static str, Route)] = &[
+            ("test", Route::to::<FixedHosts>()),
+            ("www.test", Route::to::<AlwaysTryAgain>()),
+        ];
+        const NETWORKS: &'static [(&'static str, Route)] = &[
+            ("192.0.2.0/24", Route::to::<FixedHosts>()),
+            ("2001:db8::/32", Route::to::<AlwaysTryAgain>()),
+        ];
+    }
+    type Hosts = SplitHorizon<Policy>;
+    assert!(gethostbyname2::<Hosts>("host.test", AddressFamily::Ipv4).unwrap().is_some());
+    let err = Hosts::gethostbyname2_r(CStr::from_bytes_with_nul(b"www.test\0").unwrap(), AddressFamily::Ipv4);
+    assert_eq!(err.unwrap_err().status(), NssStatus::TryAgain);
+    let err = Hosts::gethostbyname2_r(CStr::from_bytes_with_nul(b"localhost\0").unwrap(), AddressFamily::Ipv4);
+    assert_eq!(err.unwrap_err().host_error(), Some(HostError::HostNotFound));
+
+    let host = gethostbyaddr::<Hosts>("192.0.2.2".parse().unwrap()).unwrap().unwrap();
+    assert_eq!(host.name.to_str(), Ok("v4only.test"));
+    assert!(gethostbyaddr::<Hosts>("198.51.100.1".parse().unwrap()).unwrap().is_none());
+    let err = Hosts::gethostbyaddr_r(&"2001:db8::2".parse().unwrap()).unwrap_err();
+    assert_eq!(err.status(), NssStatus::TryAgain);
+
+    // FixedHosts lists its hosts under `test` once per family; AlwaysTryAgain
+    // fails, and is skipped.
+    let names: Vec<_> = Hosts::sethostent(false).unwrap()
+        .map(|entry| entry.unwrap().name.into_owned().into_string().unwrap())
+        .collect();
+    assert_eq!(names.len(), 4);
+    assert!(names.iter().all(|name| name.ends_with(".test")));
+}
@@ -0,0 +1,297 @@
+//! A ready-made service for `hosts`, `passwd`, and `group` that reads them
+//! from a SQLite database.
+
+use crate::diag;
+use crate::errors::{Error, NssStatus, Result};
+use crate::host_table::{Host, HostTable};
+use crate::interfaces::{AddressFamily, Entries, GroupEntry, GroupService, HostEntry, NameService};
+use crate::interfaces::{PasswdEntry, PasswdService};
+use libc::{gid_t, uid_t, EIO, ENOENT};
+use rusqlite::{params, Connection, OpenFlags, OptionalExtension, Row, ToSql};
+use std::borrow::Cow;
+use std::convert::TryFrom;
+use std::ffi::{CStr, CString};
+use std::fs;
+use std::marker::PhantomData;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Where a `SqliteService` finds its database.
+pub trait SqliteConfig: 'static {
+    /// The database file.
+    const PATH: &'static str = "/var/lib/nss/directory.sqlite";
+
+    /// How long to wait for a writer to finish before giving up.
+    const BUSY_TIMEOUT: Duration = Duration::from_secs(1);
+}
+
+/// The usual settings.
+pub struct SqliteDefaults;
+
+impl SqliteConfig for SqliteDefaults {}
+
+/// A service that reads hosts, users, and groups from a SQLite database, for
+/// small fleets that want a directory they can audit and change in a
+/// transaction without running LDAP:
+///
+/// ```ignore
+/// nssglue_hosts!("sqlite", SqliteService);
+/// nssglue_passwd!("sqlite", SqliteService);
+/// nssglue_group!("sqlite", SqliteService);
+/// ```
+///
+/// The database has the tables in `SqliteService::SCHEMA`; it may have
+/// others. A host has a row in `hosts` for each address, written as in
+/// `10.0.0.5` or `fd00::5`, and a row in `host_aliases` for each alias.
+/// Host names match without regard to ASCII case; user and group names are
+/// exact.
+///
+/// The database is opened read-only for each lookup, so a change shows up
+/// as soon as its transaction commits, and a lookup never sees half of
+/// one. Enumeration (`getpwent` and friends) works for all three. If the
+/// file doesn't exist, lookups report `NssStatus::Unavailable` quietly.
+pub struct SqliteService<C = SqliteDefaults>(PhantomData<C>);
+
+impl<C: SqliteConfig> SqliteService<C> {
+    /// The tables the database must have.
+    pub const SCHEMA: &'static str = "
+        CREATE TABLE hosts (
+            name TEXT NOT NULL COLLATE NOCASE,
+            addr TEXT NOT NULL
+        );
+        CREATE INDEX hosts_name ON hosts (name);
+        CREATE INDEX hosts_addr ON hosts (addr);
+        CREATE TABLE host_aliases (
+            name TEXT NOT NULL COLLATE NOCASE,
+            alias TEXT NOT NULL COLLATE NOCASE
+        );
+        CREATE INDEX host_aliases_alias ON host_aliases (alias);
+        CREATE TABLE passwd (
+            name TEXT PRIMARY KEY,
+            passwd TEXT NOT NULL DEFAULT 'x',
+            uid INTEGER NOT NULL UNIQUE,
+            gid INTEGER NOT NULL,
+            gecos TEXT NOT NULL DEFAULT '',
+            dir TEXT NOT NULL,
+            shell TEXT NOT NULL DEFAULT '/bin/sh'
+        );
+        CREATE TABLE groups (
+            name TEXT PRIMARY KEY,
+            passwd TEXT NOT NULL DEFAULT 'x',
+            gid INTEGER NOT NULL UNIQUE
+        );
+        CREATE TABLE group_members (
+            group_name TEXT NOT NULL REFERENCES groups (name),
+            member TEXT NOT NULL
+        );
+        CREATE INDEX group_members_group ON group_members (group_name);
+    ";
+
+    fn failed(err: rusqlite::Error) -> Error {
+        diag::log(format_args!("can't read {}: {}", C::PATH, err));
+        Error::with_errno(NssStatus::Unavailable, EIO)
+    }
+
+    fn open() -> Result<Connection> {
+        // SQLite's own error for a missing file doesn't say which errno.
+        if let Err(err) = fs::metadata(C::PATH) {
+            if err.kind() != std::io::ErrorKind::NotFound {
+                diag::log(format_args!("can't read {}: {}", C::PATH, err));
+            }
+            return Err(Error::with_errno(NssStatus::Unavailable, err.raw_os_error().unwrap_or(ENOENT)));
+        }
+        let flags = OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX;
+        let connection = Connection::open_with_flags(C::PATH, flags).map_err(Self::failed)?;
+        connection.busy_timeout(C::BUSY_TIMEOUT).map_err(Self::failed)?;
+        Ok(connection)
+    }
+}
+
+/// A column as a C string, or an error if it has a NUL in it.
+fn c_string(row: &Row<'_>, column: usize) -> rusqlite::Result<CString> {
+    let text: String = row.get(column)?;
+    CString::new(text).map_err(|err| rusqlite::Error::FromSqlConversionFailure(column, rusqlite::types::Type::Text,
+                                                                               Box::new(err)))
+}
+
+/// A column as a user or group id.
+fn id(row: &Row<'_>, column: usize) -> rusqlite::Result<u32> {
+    let id: i64 = row.get(column)?;
+    u32::try_from(id).map_err(|err| rusqlite::Error::FromSqlConversionFailure(column, rusqlite::types::Type::Integer,
+                                                                             Box::new(err)))
+}
+
+/// The hosts whose canonical names `sql` selects, given `params`.
+fn hosts(connection: &Connection, sql: &str, params: &[&dyn ToSql]) -> rusqlite::Result<HostTable> {
+    let names = connection.prepare(sql)?
+        .query_map(params, |row| row.get::<_, String>(0))?
+        .collect::<rusqlite::Result<Vec<String>>>()?;
+    let mut addrs = connection.prepare("SELECT addr FROM hosts WHERE name = ?1 ORDER BY rowid")?;
+    let mut aliases = connection.prepare("SELECT alias FROM host_aliases WHERE name = ?1 ORDER BY rowid")?;
+    let mut table = HostTable::default();
+    for name in names {
+        let host = Host {
+            addrs: addrs.query_map([&name], |row| row.get::<_, String>(0))?
+                .filter_map(|addr| addr.map(|addr| addr.parse().ok()).transpose())
+                .collect::<rusqlite::Result<_>>()?,
+            aliases: aliases.query_map([&name], |row| c_string(row, 0))?.collect::<rusqlite::Result<_>>()?,
+            name: CString::new(name).unwrap_or_default(),
+        };
+        table.hosts.push(host);
+    }
+    Ok(table)
+}
+
+const PASSWD_COLUMNS: &str = "SELECT name, passwd, uid, gid, gecos, dir, shell FROM passwd";
+
+fn passwd_entry(row: &Row<'_>) -> rusqlite::Result<PasswdEntry<'static>> {
+    Ok(PasswdEntry {
+        name: Cow::Owned(c_string(row, 0)?),
+        passwd: Cow::Owned(c_string(row, 1)?),
+        uid: id(row, 2)?,
+        gid: id(row, 3)?,
+        gecos: Cow::Owned(c_string(row, 4)?),
+        dir: Cow::Owned(c_string(row, 5)?),
+        shell: Cow::Owned(c_string(row, 6)?),
+    })
+}
+
+const GROUP_COLUMNS: &str = "SELECT name, passwd, gid FROM groups";
+
+/// A group's entry, with its members.
+fn group_entry(connection: &Connection, row: &Row<'_>) -> rusqlite::Result<GroupEntry<'static>> {
+    let name = c_string(row, 0)?;
+    let members = connection
+        .prepare_cached("SELECT member FROM group_members WHERE group_name = ?1 ORDER BY rowid")?
+        .query_map([name.to_str().unwrap_or_default()], |row| c_string(row, 0).map(Cow::Owned))?
+        .collect::<rusqlite::Result<_>>()?;
+    Ok(GroupEntry { name: Cow::Owned(name), passwd: Cow::Owned(c_string(row, 1)?), gid: id(row, 2)?, members })
+}
+
+/// The first group `sql` selects, given `param`.
+fn find_group(connection: &Connection, sql: &str, param: &dyn ToSql) -> rusqlite::Result<Option<GroupEntry<'static>>> {
+    let mut statement = connection.prepare(sql)?;
+    let mut rows = statement.query([param])?;
+    rows.next()?.map(|row| group_entry(connection, row)).transpose()
+}
+
+impl<C: SqliteConfig> NameService for SqliteService<C> {
+    fn gethostbyname2_r(name: &CStr, af: AddressFamily) -> Result<Option<HostEntry<'_>>> {
+        let connection = Self::open()?;
+        let text = match name.to_str() {
+            Ok(text) => text,
+            Err(_) => return Ok(None),
+        };
+        let sql = "SELECT DISTINCT name FROM hosts WHERE name = ?1
+                   UNION SELECT name FROM host_aliases WHERE alias = ?1";
+        let table = hosts(&connection, sql, params![text]).map_err(Self::failed)?;
+        Ok(table.by_name(name, af))
+    }
+
+    fn gethostbyaddr_r(addr: &IpAddr) -> Result<Option<HostEntry<'_>>> {
+        let connection = Self::open()?;
+        let sql = "SELECT DISTINCT name FROM hosts WHERE addr = ?1";
+        let table = hosts(&connection, sql, params![addr.to_string()]).map_err(Self::failed)?;
+        Ok(table.by_addr(addr))
+    }
+
+    fn sethostent(_stay_open: bool) -> Result<Entries<HostEntry<'static>>> {
+        let connection = Self::open()?;
+        let sql = "SELECT name FROM hosts GROUP BY name ORDER BY min(rowid)";
+        let table = hosts(&connection, sql, params![]).map_err(Self::failed)?;
+        Ok(HostTable::entries(Arc::new(table)))
+    }
+}
+
+impl<C: SqliteConfig> PasswdService for SqliteService<C> {
+    fn getpwnam_r(name: &CStr) -> Result<Option<PasswdEntry<'_>>> {
+        let name = match name.to_str() {
+            Ok(name) => name,
+            Err(_) => return Ok(None),
+        };
+        Self::open()?
+            .query_row(&format!("{} WHERE name = ?1", PASSWD_COLUMNS), [name], passwd_entry)
+            .optional()
+            .map_err(Self::failed)
+    }
+
+    fn getpwuid_r(uid: uid_t) -> Result<Option<PasswdEntry<'static>>> {
+        Self::open()?
+            .query_row(&format!("{} WHERE uid = ?1", PASSWD_COLUMNS), [uid], passwd_entry)
+            .optional()
+            .map_err(Self::failed)
+    }
+
+    fn setpwent() -> Result<Entries<PasswdEntry<'static>>> {
+        let connection = Self::open()?;
+        let entries = connection.prepare(&format!("{} ORDER BY rowid", PASSWD_COLUMNS))
+            .and_then(|mut statement| statement.query_map([], passwd_entry)?.collect::<rusqlite::Result<Vec<_>>>())
+            .map_err(Self::failed)?;
+        Ok(Box::new(entries.into_iter().map(Ok)))
+    }
+}
+
+impl<C: SqliteConfig> GroupService for SqliteService<C> {
+    fn getgrnam_r(name: &CStr) -> Result<Option<GroupEntry<'_>>> {
+        let name = match name.to_str() {
+            Ok(name) => name,
+            Err(_) => return Ok(None),
+        };
+        find_group(&Self::open()?, &format!("{} WHERE name = ?1", GROUP_COLUMNS), &name).map_err(Self::failed)
+    }
+
+    fn getgrgid_r(gid: gid_t) -> Result<Option<GroupEntry<'static>>> {
+        find_group(&Self::open()?, &format!("{} WHERE gid = ?1", GROUP_COLUMNS), &gid).map_err(Self::failed)
+    }
+
+    fn setgrent() -> Result<Entries<GroupEntry<'static>>> {
+        let connection = Self::open()?;
+        let entries = connection.prepare(&format!("{} ORDER BY rowid", GROUP_COLUMNS))
+            .and_then(|mut statement| {
+                statement.query_map([], |row| group_entry(&connection, row))?.collect::<rusqlite::Result<Vec<_>>>()
+            })
+            .map_err(Self::failed)?;
+        Ok(Box::new(entries.into_iter().map(Ok)))
+    }
+}
+
+#[test]
+fn test_sqlite_directory() {
+    let path = std::env::temp_dir().join(format!("nsswitch_service-test-{}.sqlite", std::process::id()));
+    let path: &'static str = Box::leak(path.to_str().unwrap().to_string().into_boxed_str());
+    struct Test;
+    impl SqliteConfig for Test {
+        const PATH: &'static str = "/nonexistent/directory.sqlite";
+    }
+    let err = SqliteService::<Test>::getpwuid_r(0).unwrap_err();
+    assert_eq!((err.status(), err.errno()), (NssStatus::Unavailable, ENOENT));
+
+    let connection = Connection::open(path).unwrap();
+    connection.execute_batch(SqliteService::<Test>::SCHEMA).unwrap();
+    connection.execute_batch("
+        INSERT INTO hosts VALUES ('db1', '10.0.0.5'), ('db1', 'fd00::5'), ('web', '10.0.0.6');
+        INSERT INTO host_aliases VALUES ('db1', 'db');
+        INSERT INTO passwd VALUES ('alice', 'x', 1000, 1000, 'Alice', '/home/alice', '/bin/bash');
+        INSERT INTO passwd (name, uid, gid, dir) VALUES ('bob', 1001, 1000, '/home/bob');
+        INSERT INTO groups (name, gid) VALUES ('staff', 1000);
+        INSERT INTO group_members VALUES ('staff', 'alice'), ('staff', 'bob');
+    ").unwrap();
+
+    // The service itself needs the path to be a constant, so the queries
+    // are tried on this connection.
+    let sql = "SELECT DISTINCT name FROM hosts WHERE name = ?1 UNION SELECT name FROM host_aliases WHERE alias = ?1";
+    let table = hosts(&connection, sql, params!["DB"]).unwrap();
+    let name = CStr::from_bytes_with_nul(b"DB\0").unwrap();
+    let entry = table.by_name(name, AddressFamily::Ipv6).unwrap();
+    assert_eq!((entry.name.to_str(), entry.aliases.len()), (Ok("db1"), 1));
+    let all = hosts(&connection, "SELECT name FROM hosts GROUP BY name ORDER BY min(rowid)", params![]).unwrap();
+    assert_eq!(HostTable::entries(Arc::new(all)).count(), 3);
+
+    let bob = connection.query_row(&format!("{} WHERE uid = ?1", PASSWD_COLUMNS), [1001], passwd_entry).unwrap();
+    assert_eq!((bob.name.to_str(), bob.passwd.to_str(), bob.shell.to_str()), (Ok("bob"), Ok("x"), Ok("/bin/sh")));
+    let staff = find_group(&connection, &format!("{} WHERE gid = ?1", GROUP_COLUMNS), &1000).unwrap().unwrap();
+    assert_eq!(staff.members.len(), 2);
+    assert!(find_group(&connection, &format!("{} WHERE name = ?1", GROUP_COLUMNS), &"wheel").unwrap().is_none());
+    fs::remove_file(path).unwrap();
+}
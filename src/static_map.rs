@@ -0,0 +1,213 @@
+//! A ready-made `hosts` service that answers from a TOML or JSON file.
+
+use crate::diag;
+use crate::errors::{Error, HostError, NssStatus, Result};
+use crate::ffi::c_int;
+use crate::fork::ForkSafeMutex;
+use crate::host_table::{Host, HostTable};
+use crate::interfaces::{AddressFamily, Entries, HostEntry, NameService};
+use crate::reload::Reload;
+use libc::{EINVAL, EIO, ENOENT};
+use serde::Deserialize;
+use std::ffi::{CStr, CString};
+use std::fs;
+use std::marker::PhantomData;
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::Arc;
+
+/// Where a `StaticMapService` gets its hosts.
+pub trait StaticMapConfig: 'static {
+    /// The file to read. If the name ends in `.json`, it's JSON; otherwise
+    /// it's TOML.
+    const PATH: &'static str;
+}
+
+/// A `hosts` service that maps names to addresses listed in a file, for
+/// when all you need is a few extra names that resolve machine-wide.
+///
+/// The file lists hosts, each with a name, optional aliases, and
+/// addresses. In TOML:
+///
+/// ```toml
+/// [[hosts]]
+/// name = "build.internal"
+/// aliases = ["build"]
+/// addresses = ["10.0.0.5", "fd00::5"]
+/// ```
+///
+/// and in JSON, `{"hosts": [{"name": "build.internal", ...}]}`. Names match
+/// without regard to ASCII case, and the first host with a name or address
+/// wins. A host with no addresses of the family asked for is `NO_DATA`.
+///
+/// The file is read once, the first time the module needs it. If it can't
+/// be read or parsed, a message goes to the diagnostic sink and every
+/// lookup reports `NssStatus::Unavailable`, so glibc moves on to the next
+/// service. To use it, name the file and export the service under your
+/// module's name:
+///
+/// ```ignore
+/// struct Extra;
+///
+/// impl StaticMapConfig for Extra {
+///     const PATH: &'static str = "/etc/nss_extra.toml";
+/// }
+///
+/// nssglue_hosts!("extra", StaticMapService<Extra>);
+/// ```
+pub struct StaticMapService<C>(PhantomData<C>);
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct MapFile {
+    #[serde(default)]
+    hosts: Vec<MapHost>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct MapHost {
+    name: String,
+    #[serde(default)]
+    aliases: Vec<String>,
+    addresses: Vec<IpAddr>,
+}
+
+fn c_string(s: String) -> std::result::Result<CString, String> {
+    CString::new(s).map_err(|err| format!("name contains a NUL byte: {:?}", err.into_vec()))
+}
+
+/// Parse the contents of the file at `path`.
+fn parse(path: &Path, text: &str) -> std::result::Result<HostTable, String> {
+    let file: MapFile = if path.extension().is_some_and(|ext| ext == "json") {
+        serde_json::from_str(text).map_err(|err| err.to_string())?
+    } else {
+        toml::from_str(text).map_err(|err| err.to_string())?
+    };
+    let hosts = file.hosts.into_iter()
+        .map(|host| Ok(Host {
+            name: c_string(host.name)?,
+            aliases: host.aliases.into_iter().map(c_string).collect::<std::result::Result<_, _>>()?,
+            addrs: host.addresses,
+        }))
+        .collect::<std::result::Result<_, String>>()?;
+    Ok(HostTable { hosts })
+}
+
+/// Read and parse the file at `path`, or return the errno to report.
+fn load(path: &str) -> std::result::Result<Arc<HostTable>, c_int> {
+    let text = fs::read_to_string(path).map_err(|err| {
+        diag::log(format_args!("can't read {}: {}", path, err));
+        err.raw_os_error().unwrap_or(EIO)
+    })?;
+    match parse(Path::new(path), &text) {
+        Ok(table) => Ok(Arc::new(table)),
+        Err(message) => {
+            diag::log(format_args!("can't parse {}: {}", path, message));
+            Err(EINVAL)
+        }
+    }
+}
+
+type Loaded = std::result::Result<Arc<HostTable>, c_int>;
+
+/// Every file loaded so far, by path.
+static TABLES: ForkSafeMutex<Vec<(&'static str, Loaded)>> = ForkSafeMutex::new();
+
+impl<C: StaticMapConfig> StaticMapService<C> {
+    /// What was loaded from the file, if anything has been.
+    fn cached() -> Option<Loaded> {
+        TABLES.lock().iter().find(|&&(path, _)| path == C::PATH).map(|(_, loaded)| loaded.clone())
+    }
+
+    /// The table, loaded the first time it's needed. The file is read
+    /// without holding the lock; if two threads both read it, the first to
+    /// finish wins.
+    fn table() -> Result<Arc<HostTable>> {
+        let loaded = match Self::cached() {
+            Some(loaded) => loaded,
+            None => {
+                let loaded = load(C::PATH);
+                let mut tables = TABLES.lock();
+                match tables.iter().find(|&&(path, _)| path == C::PATH) {
+                    Some((_, existing)) => existing.clone(),
+                    None => {
+                        tables.push((C::PATH, loaded.clone()));
+                        loaded
+                    }
+                }
+            }
+        };
+        loaded.map_err(|errno| Error::with_errno(NssStatus::Unavailable, errno))
+    }
+}
+
+impl<C: StaticMapConfig> Reload for StaticMapService<C> {
+    const FILES: &'static [&'static str] = &[C::PATH];
+
+    fn reload() {
+        let new = load(C::PATH);
+        let mut tables = TABLES.lock();
+        match tables.iter_mut().find(|(path, _)| *path == C::PATH) {
+            // Keep the old table if the file is broken.
+            Some((_, loaded)) => if new.is_ok() || loaded.is_err() {
+                *loaded = new;
+            },
+            None => tables.push((C::PATH, new)),
+        }
+    }
+}
+
+impl<C: StaticMapConfig> NameService for StaticMapService<C> {
+    fn gethostbyname2_r(name: &CStr, af: AddressFamily) -> Result<Option<HostEntry<'_>>> {
+        let table = Self::table()?;
+        match table.by_name(name, af) {
+            None if table.has_name(name) => Err(Error::with_host(NssStatus::NotFound, ENOENT, HostError::NoData)),
+            found => Ok(found),
+        }
+    }
+
+    fn gethostbyaddr_r(addr: &IpAddr) -> Result<Option<HostEntry<'_>>> {
+        Ok(Self::table()?.by_addr(addr))
+    }
+
+    fn sethostent(_stay_open: bool) -> Result<Entries<HostEntry<'static>>> {
+        Ok(HostTable::entries(Self::table()?))
+    }
+
+    fn on_fork_child() {
+        TABLES.reset();
+    }
+}
+
+#[test]
+fn test_static_map() {
+    use crate::testing::{gethostbyaddr, gethostbyname2};
+
+    struct Example;
+    impl StaticMapConfig for Example {
+        const PATH: &'static str = concat!(env!("CARGO_MANIFEST_DIR"), "/examples/static_map.toml");
+    }
+    type Service = StaticMapService<Example>;
+
+    let host = gethostbyname2::<Service>("BUILD", AddressFamily::Ipv6).unwrap().unwrap();
+    assert_eq!(host.name.to_str(), Ok("build.internal"));
+    let err = gethostbyname2::<Service>("printer.internal", AddressFamily::Ipv6).unwrap_err();
+    assert_eq!(err.host_error(), Some(HostError::NoData));
+    let host = gethostbyaddr::<Service>("10.0.0.9".parse().unwrap()).unwrap().unwrap();
+    assert_eq!(host.name.to_str(), Ok("printer.internal"));
+    assert!(gethostbyname2::<Service>("nowhere.internal", AddressFamily::Ipv4).unwrap().is_none());
+
+    let json = r#"{"hosts": [{"name": "a.internal", "addresses": ["10.0.0.1"]}]}"#;
+    let table = parse(Path::new("map.json"), json).unwrap();
+    assert_eq!(table.hosts[0].addrs, ["10.0.0.1".parse::<IpAddr>().unwrap()]);
+    assert!(parse(Path::new("map.toml"), "[[hosts]]\nname = \"a\"\naddresses = [\"10.0.0.300\"]\n").is_err());
+    assert!(parse(Path::new("map.toml"), "[[hosts]]\nname = \"a\"\nadresses = []\n").is_err());
+
+    struct Missing;
+    impl StaticMapConfig for Missing {
+        const PATH: &'static str = "/nonexistent/static_map.toml";
+    }
+    let err = gethostbyname2::<StaticMapService<Missing>>("build", AddressFamily::Ipv4).unwrap_err();
+    assert_eq!((err.status(), err.errno()), (NssStatus::Unavailable, libc::ENOENT));
+}
@@ -0,0 +1,124 @@
+//! Sending diagnostic messages to the system log, a few at a time.
+//!
+//! An NSS module is loaded into every process on the machine that looks up
+//! a name, so when its backend goes down, every one of them has something
+//! to say about it, over and over. With `DiagnosticSink::Syslog`, that can
+//! be thousands of lines a second in the journal. `log_to_syslog` sends
+//! messages there too, but lets only `SyslogConfig::BURST` messages of each
+//! kind through per `SyslogConfig::INTERVAL`, in each process, and says
+//! how many it dropped when the next one gets through.
+//!
+//! A message's kind is where in the crate it's logged from, so "can't
+//! connect" and "can't parse" are limited separately, whatever server or
+//! name they mention. Like the rest of the diagnostics, this takes no locks
+//! and allocates nothing.
+
+use crate::diag;
+use libc::c_int;
+use std::convert::TryFrom;
+use std::panic::Location;
+use std::sync::atomic::{AtomicI32, AtomicU32, AtomicU64, Ordering};
+use std::time::Duration;
+
+/// Where and how often `log_to_syslog` logs.
+pub trait SyslogConfig: 'static {
+    /// The facility to log with. This is passed to each `syslog(3)` call,
+    /// rather than to `openlog`, which belongs to the program.
+    const FACILITY: c_int = libc::LOG_DAEMON;
+
+    /// The priority to log with.
+    const PRIORITY: c_int = libc::LOG_ERR;
+
+    /// How many messages of each kind to log per `INTERVAL`.
+    const BURST: u32 = 5;
+
+    /// How long `BURST` lasts. Whole seconds count; a zero-length interval
+    /// counts as one second.
+    const INTERVAL: Duration = Duration::from_secs(60);
+}
+
+/// The usual settings: the `daemon` facility, priority `err`, and five
+/// messages of a kind a minute.
+pub struct SyslogDefaults;
+
+impl SyslogConfig for SyslogDefaults {}
+
+static PRIORITY: AtomicI32 = AtomicI32::new(libc::LOG_DAEMON | libc::LOG_ERR);
+static BURST: AtomicU32 = AtomicU32::new(5);
+static INTERVAL_SECS: AtomicU32 = AtomicU32::new(60);
+
+/// How many kinds of message are counted separately. Kinds that hash alike
+/// share a count.
+const KINDS: usize = 64;
+
+/// For each kind of message, the second its interval started, in the high
+/// 32 bits, and how many messages have been logged since, in the low 32.
+static COUNTS: [AtomicU64; KINDS] = [const { AtomicU64::new(0) }; KINDS];
+
+/// Send this crate's diagnostic messages to the system log, as `C` says,
+/// from now on.
+pub fn log_to_syslog<C: SyslogConfig>() {
+    PRIORITY.store(C::FACILITY | C::PRIORITY, Ordering::Relaxed);
+    BURST.store(C::BURST, Ordering::Relaxed);
+    let secs = u32::try_from(C::INTERVAL.as_secs()).unwrap_or(u32::MAX).max(1);
+    INTERVAL_SECS.store(secs, Ordering::Relaxed);
+    diag::log_to_limited_syslog();
+}
+
+/// The priority, with the facility, to pass to `syslog(3)`.
+pub(crate) fn priority() -> c_int {
+    PRIORITY.load(Ordering::Relaxed)
+}
+
+/// Seconds on a clock that never goes backward.
+fn now_secs() -> u32 {
+    let mut now = libc::timespec { tv_sec: 0, tv_nsec: 0 };
+    // `clock_gettime` is async-signal-safe; `Instant::now` needn't be.
+    unsafe {
+        libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut now);
+    }
+    now.tv_sec as u32
+}
+
+/// Whether to log a message logged at `location`, at `now`, and if so, how
+/// many messages of its kind were dropped before it.
+fn admit_at(location: &Location<'_>, now: u32) -> Option<u32> {
+    let kind = (location.file().as_ptr() as usize).wrapping_mul(31).wrapping_add(location.line() as usize);
+    let count = &COUNTS[kind % KINDS];
+    let burst = BURST.load(Ordering::Relaxed);
+    let interval = INTERVAL_SECS.load(Ordering::Relaxed);
+    let mut old = count.load(Ordering::Relaxed);
+    loop {
+        let (start, logged) = ((old >> 32) as u32, old as u32);
+        let (new, admitted) = if logged == 0 || now.wrapping_sub(start) >= interval {
+            (u64::from(now) << 32 | 1, Some(logged.saturating_sub(burst)))
+        } else {
+            let admitted = if logged < burst { Some(0) } else { None };
+            (old + u64::from(logged < u32::MAX), admitted)
+        };
+        match count.compare_exchange_weak(old, new, Ordering::Relaxed, Ordering::Relaxed) {
+            Ok(_) => return admitted,
+            Err(current) => old = current,
+        }
+    }
+}
+
+/// Whether to log a message logged at `location` now, and if so, how many
+/// messages of its kind were dropped before it.
+pub(crate) fn admit(location: &Location<'_>) -> Option<u32> {
+    admit_at(location, now_secs())
+}
+
+#[test]
+fn test_admit() {
+    let here = Location::caller();
+    BURST.store(2, Ordering::Relaxed);
+    assert_eq!(admit_at(here, 1000), Some(0));
+    assert_eq!(admit_at(here, 1001), Some(0));
+    assert_eq!(admit_at(here, 1002), None);
+    assert_eq!(admit_at(here, 1003), None);
+    assert_eq!(admit_at(here, 1059), None);
+    assert_eq!(admit_at(here, 1060), Some(3));
+    assert_eq!(admit_at(here, 1061), Some(0));
+    BURST.store(5, Ordering::Relaxed);
+}
@@ -0,0 +1,259 @@
+//! Calling a service the way glibc does, for tests.
+//!
+//! Each function here calls the same glue the `nssglue_*!` macros export,
+//! with a buffer from the heap. If the service runs out of room, it tries
+//! again with a buffer twice the size, as glibc does, until the result
+//! fits. Then it reads the result back into an entry:
+//!
+//! ```
+//! use nsswitch_service::{testing, AddressFamily, HostAddressList, HostEntry, NameService, Result};
+//! use std::borrow::Cow;
+//! use std::ffi::CStr;
+//! use std::net::{IpAddr, Ipv6Addr};
+//!
+//! struct Localhost;
+//!
+//! impl NameService for Localhost {
+//!     fn gethostbyname2_r(name: &CStr, _af: AddressFamily) -> Result<Option<HostEntry<'_>>> {
+//!         Ok(Some(HostEntry {
+//!             name: Cow::Borrowed(name),
+//!             aliases: vec![],
+//!             addr_list: HostAddressList::V6(vec![Ipv6Addr::LOCALHOST]),
+//!         }))
+//!     }
+//!
+//!     fn gethostbyaddr_r(_addr: &IpAddr) -> Result<Option<HostEntry<'_>>> {
+//!         Ok(None)
+//!     }
+//! }
+//!
+//! let entry = testing::gethostbyname2::<Localhost>("me", AddressFamily::Ipv6).unwrap().unwrap();
+//! assert_eq!(entry.name.to_str(), Ok("me"));
+//! ```
+//!
+//! A lookup that finds nothing returns `Ok(None)`. Any other failure is an
+//! `Error` with the codes the glue reported.
+//!
+//! To test the built library instead, load it with `Module`, or on Linux,
+//! let glibc load it by running programs in a `Sandbox`.
+//!
+//! There are also a few services to test against: `FixedHosts`, a small
+//! table of hosts; `AlwaysTryAgain`, which always fails; `Slow`, which
+//! delays another service's lookups; and `FaultInjector`, which makes
+//! another service fail now and then, in a repeatable way.
+
+use crate::errors::{Error, NssStatus, Result, NETDB_INTERNAL};
+use crate::ffi::{c_int, c_long, c_void, gaih_addrtuple, gid_t, group, hostent, passwd, spwd, uid_t};
+use crate::foreign::retry;
+use crate::interfaces::{AddressFamily, GroupEntry, GroupService, HostAddresses, HostEntry,
+                        NameService, PasswdEntry, PasswdService, ShadowEntry, ShadowService};
+use crate::macros;
+use libc::{AF_INET, AF_INET6, ENOENT};
+use std::ffi::CString;
+use std::net::IpAddr;
+use std::{mem, ptr};
+
+mod faults;
+mod fixtures;
+mod module;
+#[cfg(target_os = "linux")]
+mod sandbox;
+
+pub use self::faults::{FaultInjector, FaultPlan};
+pub use self::fixtures::{AlwaysTryAgain, FixedHost, FixedHosts, Slow};
+pub use self::module::Module;
+#[cfg(target_os = "linux")]
+pub use self::sandbox::Sandbox;
+
+fn c_string(name: &str) -> CString {
+    CString::new(name).expect("name contains a NUL byte")
+}
+
+/// Look up a host the way `gethostbyname_r` does, with the service's
+/// `gethostbyname_r`.
+pub fn gethostbyname<T: NameService + 'static>(name: &str) -> Result<Option<HostEntry<'static>>> {
+    let name = c_string(name);
+    retry(|buffer, buflen, errno, h_errno| unsafe {
+        let mut result: hostent = mem::zeroed();
+        match macros::call_gethostbyname_r::<T>(name.as_ptr(), &mut result, buffer, buflen, errno, h_errno) {
+            NssStatus::Success => Ok(HostEntry::read_from(&result)),
+            status => Err(status),
+        }
+    })
+}
+
+/// Look up a host the way `gethostbyname2_r` does.
+pub fn gethostbyname2<T: NameService + 'static>(
+    name: &str,
+    af: AddressFamily,
+) -> Result<Option<HostEntry<'static>>> {
+    let name = c_string(name);
+    let af = match af {
+        AddressFamily::Ipv4 => AF_INET,
+        AddressFamily::Ipv6 => AF_INET6,
+    };
+    retry(|buffer, buflen, errno, h_errno| unsafe {
+        let mut result: hostent = mem::zeroed();
+        match macros::call_gethostbyname2_r::<T>(name.as_ptr(), af, &mut result, buffer, buflen, errno, h_errno) {
+            NssStatus::Success => Ok(HostEntry::read_from(&result)),
+            status => Err(status),
+        }
+    })
+}
+
+/// Look up all of a host's addresses the way `getaddrinfo` does, with
+/// `gethostbyname4_r`.
+pub fn gethostbyname4<T: NameService + 'static>(name: &str) -> Result<Option<HostAddresses<'static>>> {
+    let name = c_string(name);
+    retry(|buffer, buflen, errno, h_errno| unsafe {
+        let mut pat: *mut gaih_addrtuple = ptr::null_mut();
+        let mut ttl = -1;
+        match macros::call_gethostbyname4_r::<T>(name.as_ptr(), &mut pat, buffer, buflen, errno, h_errno,
+                                                 &mut ttl) {
+            NssStatus::Success => {
+                let ttl = if ttl < 0 { None } else { Some(ttl as u32) };
+                Ok(HostAddresses { ttl, ..HostAddresses::read_from(pat) })
+            }
+            status => Err(status),
+        }
+    })
+}
+
+/// Look up an address the way `gethostbyaddr_r` does.
+pub fn gethostbyaddr<T: NameService + 'static>(addr: IpAddr) -> Result<Option<HostEntry<'static>>> {
+    let (af, octets) = match addr {
+        IpAddr::V4(ip) => (AF_INET, ip.octets().to_vec()),
+        IpAddr::V6(ip) => (AF_INET6, ip.octets().to_vec()),
+    };
+    retry(|buffer, buflen, errno, h_errno| unsafe {
+        let mut result: hostent = mem::zeroed();
+        match macros::call_gethostbyaddr_r::<T>(octets.as_ptr() as *const c_void, octets.len() as c_int, af,
+                                                &mut result, buffer, buflen, errno, h_errno) {
+            NssStatus::Success => Ok(HostEntry::read_from(&result)),
+            status => Err(status),
+        }
+    })
+}
+
+/// Look up a user the way `getpwnam_r` does.
+pub fn getpwnam<T: PasswdService + 'static>(name: &str) -> Result<Option<PasswdEntry<'static>>> {
+    let name = c_string(name);
+    retry(|buffer, buflen, errno, _| unsafe {
+        let mut result: passwd = mem::zeroed();
+        match macros::call_getpwnam_r::<T>(name.as_ptr(), &mut result, buffer, buflen, errno) {
+            NssStatus::Success => Ok(PasswdEntry::read_from(&result)),
+            status => Err(status),
+        }
+    })
+}
+
+/// Look up a user the way `getpwuid_r` does.
+pub fn getpwuid<T: PasswdService + 'static>(uid: uid_t) -> Result<Option<PasswdEntry<'static>>> {
+    retry(|buffer, buflen, errno, _| unsafe {
+        let mut result: passwd = mem::zeroed();
+        match macros::call_getpwuid_r::<T>(uid, &mut result, buffer, buflen, errno) {
+            NssStatus::Success => Ok(PasswdEntry::read_from(&result)),
+            status => Err(status),
+        }
+    })
+}
+
+/// Look up a group the way `getgrnam_r` does.
+pub fn getgrnam<T: GroupService + 'static>(name: &str) -> Result<Option<GroupEntry<'static>>> {
+    let name = c_string(name);
+    retry(|buffer, buflen, errno, _| unsafe {
+        let mut result: group = mem::zeroed();
+        match macros::call_getgrnam_r::<T>(name.as_ptr(), &mut result, buffer, buflen, errno) {
+            NssStatus::Success => Ok(GroupEntry::read_from(&result)),
+            status => Err(status),
+        }
+    })
+}
+
+/// Look up a group the way `getgrgid_r` does.
+pub fn getgrgid<T: GroupService + 'static>(gid: gid_t) -> Result<Option<GroupEntry<'static>>> {
+    retry(|buffer, buflen, errno, _| unsafe {
+        let mut result: group = mem::zeroed();
+        match macros::call_getgrgid_r::<T>(gid, &mut result, buffer, buflen, errno) {
+            NssStatus::Success => Ok(GroupEntry::read_from(&result)),
+            status => Err(status),
+        }
+    })
+}
+
+/// List a user's groups the way `getgrouplist` does, with `initgroups_dyn`.
+/// As with `getgrouplist`, `group` comes first.
+pub fn getgrouplist<T: GroupService + 'static>(user: &str, group: gid_t) -> Result<Option<Vec<gid_t>>> {
+    let user = c_string(user);
+    unsafe {
+        // The glue grows the array with `realloc`, so it has to come from
+        // `malloc`.
+        let mut groups = libc::malloc(mem::size_of::<gid_t>()) as *mut gid_t;
+        assert!(!groups.is_null(), "out of memory");
+        *groups = group;
+        let (mut start, mut size): (c_long, c_long) = (1, 1);
+        let mut errno = 0;
+        let status = macros::call_initgroups_dyn::<T>(user.as_ptr(), group, &mut start, &mut size,
+                                                      &mut groups, -1, &mut errno);
+        let gids = std::slice::from_raw_parts(groups, start as usize).to_vec();
+        libc::free(groups as *mut c_void);
+        match status {
+            NssStatus::Success => Ok(Some(gids)),
+            NssStatus::NotFound if errno == ENOENT || errno == 0 => Ok(None),
+            status => Err(Error::from_raw_parts(status, errno, NETDB_INTERNAL)),
+        }
+    }
+}
+
+/// Look up a shadow entry the way `getspnam_r` does.
+pub fn getspnam<T: ShadowService + 'static>(name: &str) -> Result<Option<ShadowEntry<'static>>> {
+    let name = c_string(name);
+    retry(|buffer, buflen, errno, _| unsafe {
+        let mut result: spwd = mem::zeroed();
+        match macros::call_getspnam_r::<T>(name.as_ptr(), &mut result, buffer, buflen, errno) {
+            NssStatus::Success => Ok(ShadowEntry::read_from(&result)),
+            status => Err(status),
+        }
+    })
+}
+
+#[test]
+fn test_retry_with_bigger_buffer() {
+    use std::borrow::Cow;
+    use std::cell::Cell;
+    use std::ffi::CStr;
+
+    thread_local! {
+        static CALLS: Cell<usize> = const { Cell::new(0) };
+    }
+
+    struct Crowd;
+    impl GroupService for Crowd {
+        fn getgrnam_r(name: &CStr) -> Result<Option<GroupEntry<'_>>> {
+            CALLS.with(|calls| calls.set(calls.get() + 1));
+            if name.to_bytes() != b"crowd" {
+                return Ok(None);
+            }
+            Ok(Some(GroupEntry {
+                name: Cow::Borrowed(name),
+                passwd: Cow::Borrowed(CStr::from_bytes_with_nul(b"x\0").unwrap()),
+                gid: 1000,
+                members: (0..500).map(|i| Cow::Owned(CString::new(format!("member{}", i)).unwrap())).collect(),
+            }))
+        }
+
+        fn getgrgid_r(_gid: gid_t) -> Result<Option<GroupEntry<'static>>> {
+            Err(Error::with_errno(NssStatus::Unavailable, libc::EIO))
+        }
+    }
+
+    // About 8K of members: 1K, 2K, 4K, and 8K are too small.
+    let group = getgrnam::<Crowd>("crowd").unwrap().unwrap();
+    assert_eq!(CALLS.with(Cell::get), 5);
+    assert_eq!(group.members.len(), 500);
+    assert_eq!(group.members[499].to_str(), Ok("member499"));
+
+    assert!(getgrnam::<Crowd>("nobody").unwrap().is_none());
+    let err = getgrgid::<Crowd>(1000).unwrap_err();
+    assert_eq!((err.status(), err.errno()), (NssStatus::Unavailable, libc::EIO));
+}
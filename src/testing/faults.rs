@@ -0,0 +1,292 @@
+//! Injecting failures into a service, for testing how the glue, glibc, and
+//! programs cope with them.
+
+use crate::errors::{Error, HostError, NssStatus, Result};
+use crate::ffi::{gid_t, uid_t};
+use crate::interfaces::{AddressFamily, Entries, GroupEntry, GroupService, HostAddressList, HostAddresses,
+                        HostEntry, HostEntryWithTtl, HostLimits, NameService, PasswdEntry, PasswdService,
+                        ShadowEntry, ShadowService};
+use libc::EAGAIN;
+use std::any::TypeId;
+use std::collections::HashMap;
+use std::ffi::CStr;
+use std::marker::PhantomData;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+/// How often `FaultInjector` injects each kind of fault. Each is a
+/// probability from 0.0 (never, the default) to 1.0 (every lookup).
+///
+/// A plan is a type, since services are types:
+///
+/// ```
+/// use nsswitch_service::testing::{FaultInjector, FaultPlan, FixedHosts};
+///
+/// struct Flaky;
+///
+/// impl FaultPlan for Flaky {
+///     const TRY_AGAIN: f64 = 0.25;
+/// }
+///
+/// type FlakyHosts = FaultInjector<FixedHosts, Flaky>;
+/// ```
+pub trait FaultPlan: 'static {
+    /// The seed for the plan's random numbers. The same lookups in the same
+    /// order always get the same faults.
+    const SEED: u64 = 1;
+
+    /// Fail with `NssStatus::TryAgain`, errno `EAGAIN`, and h_errno
+    /// `TRY_AGAIN`, without calling the service.
+    const TRY_AGAIN: f64 = 0.0;
+
+    /// Return only the first address of a host, with no aliases, or a
+    /// group with no members.
+    const TRUNCATE: f64 = 0.0;
+
+    /// Panic instead of calling the service.
+    const PANIC: f64 = 0.0;
+
+    /// Sleep for `DELAY_FOR` before doing anything else. This is decided
+    /// separately from the other faults.
+    const DELAY: f64 = 0.0;
+    const DELAY_FOR: Duration = Duration::from_millis(100);
+}
+
+/// The service `S`, with faults injected at random as plan `P` says.
+///
+/// The random numbers are deterministic: each plan has one sequence for
+/// the whole process, starting from `P::SEED`, and every lookup through
+/// any `FaultInjector` with that plan takes the next ones. So a test that
+/// does the same lookups gets the same faults every time, as long as it
+/// has the plan to itself. `reset` starts the sequence over.
+///
+/// `S`'s settings, such as `LOOKUP_TIMEOUT`, are passed through unchanged.
+pub struct FaultInjector<S, P>(PhantomData<(S, P)>);
+
+enum Fault {
+    None,
+    TryAgain,
+    Truncate,
+    Panic,
+}
+
+/// The state of each plan's random number generator.
+static STATES: Mutex<Option<HashMap<TypeId, u64>>> = Mutex::new(None);
+
+/// A number from 0.0 to 1.0 (exclusive), the next in `P`'s sequence
+/// (SplitMix64).
+fn random<P: FaultPlan>() -> f64 {
+    let mut states = STATES.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    let state = states.get_or_insert_with(HashMap::new).entry(TypeId::of::<P>()).or_insert(P::SEED);
+    *state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^= z >> 31;
+    (z >> 11) as f64 / (1_u64 << 53) as f64
+}
+
+fn truncate_host(mut entry: HostEntry<'_>) -> HostEntry<'_> {
+    entry.aliases.clear();
+    match entry.addr_list {
+        HostAddressList::V4(ref mut addrs) => addrs.truncate(1),
+        HostAddressList::V6(ref mut addrs) => addrs.truncate(1),
+    }
+    entry
+}
+
+fn truncate_host_with_ttl(entry: HostEntryWithTtl<'_>) -> HostEntryWithTtl<'_> {
+    HostEntryWithTtl { entry: truncate_host(entry.entry), ttl: entry.ttl }
+}
+
+impl<S, P: FaultPlan> FaultInjector<S, P> {
+    /// Start `P`'s random numbers over from `P::SEED`.
+    pub fn reset() {
+        let mut states = STATES.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if let Some(states) = states.as_mut() {
+            states.remove(&TypeId::of::<P>());
+        }
+    }
+
+    /// Decide what to do to this lookup. Delays happen right away.
+    fn fault() -> Fault {
+        if random::<P>() < P::DELAY {
+            thread::sleep(P::DELAY_FOR);
+        }
+        let x = random::<P>();
+        if x < P::PANIC {
+            Fault::Panic
+        } else if x < P::PANIC + P::TRY_AGAIN {
+            Fault::TryAgain
+        } else if x < P::PANIC + P::TRY_AGAIN + P::TRUNCATE {
+            Fault::Truncate
+        } else {
+            Fault::None
+        }
+    }
+
+    /// Call `lookup`, unless a fault says otherwise.
+    fn inject<R>(truncate: fn(R) -> R, lookup: impl FnOnce() -> Result<Option<R>>) -> Result<Option<R>> {
+        match Self::fault() {
+            Fault::None => lookup(),
+            Fault::TryAgain => Err(Error::with_host(NssStatus::TryAgain, EAGAIN, HostError::TryAgain)),
+            Fault::Truncate => Ok(lookup()?.map(truncate)),
+            Fault::Panic => panic!("fault injected by FaultInjector"),
+        }
+    }
+
+    /// Enumeration gets every fault but truncation.
+    fn inject_entries<E>(setent: impl FnOnce() -> Result<Entries<E>>) -> Result<Entries<E>> {
+        match Self::fault() {
+            Fault::None | Fault::Truncate => setent(),
+            Fault::TryAgain => Err(Error::with_host(NssStatus::TryAgain, EAGAIN, HostError::TryAgain)),
+            Fault::Panic => panic!("fault injected by FaultInjector"),
+        }
+    }
+}
+
+impl<S: NameService, P: FaultPlan> NameService for FaultInjector<S, P> {
+    const ALLOW_EMPTY_ADDRESS_LIST: bool = S::ALLOW_EMPTY_ADDRESS_LIST;
+    const LOOKUP_TIMEOUT: Option<Duration> = S::LOOKUP_TIMEOUT;
+    const LIMITS: HostLimits = S::LIMITS;
+    const VALIDATE_HOSTNAMES: bool = S::VALIDATE_HOSTNAMES;
+
+    fn gethostbyname_r(name: &CStr) -> Result<Option<HostEntry<'_>>> {
+        Self::inject(truncate_host, || S::gethostbyname_r(name))
+    }
+
+    fn gethostbyname2_r(name: &CStr, af: AddressFamily) -> Result<Option<HostEntry<'_>>> {
+        Self::inject(truncate_host, || S::gethostbyname2_r(name, af))
+    }
+
+    fn gethostbyaddr_r(addr: &IpAddr) -> Result<Option<HostEntry<'_>>> {
+        Self::inject(truncate_host, || S::gethostbyaddr_r(addr))
+    }
+
+    fn gethostbyname3_r(name: &CStr, af: AddressFamily) -> Result<Option<HostEntryWithTtl<'_>>> {
+        Self::inject(truncate_host_with_ttl, || S::gethostbyname3_r(name, af))
+    }
+
+    fn gethostbyname4_r(name: &CStr) -> Result<Option<HostAddresses<'_>>> {
+        fn truncate(mut addrs: HostAddresses<'_>) -> HostAddresses<'_> {
+            addrs.addrs.truncate(1);
+            addrs
+        }
+        Self::inject(truncate, || S::gethostbyname4_r(name))
+    }
+
+    fn gethostbyaddr2_r(addr: &IpAddr) -> Result<Option<HostEntryWithTtl<'_>>> {
+        Self::inject(truncate_host_with_ttl, || S::gethostbyaddr2_r(addr))
+    }
+
+    fn sethostent(stay_open: bool) -> Result<Entries<HostEntry<'static>>> {
+        Self::inject_entries(|| S::sethostent(stay_open))
+    }
+
+    fn on_fork_child() {
+        S::on_fork_child()
+    }
+}
+
+impl<S: PasswdService, P: FaultPlan> PasswdService for FaultInjector<S, P> {
+    const LOOKUP_TIMEOUT: Option<Duration> = S::LOOKUP_TIMEOUT;
+
+    fn getpwnam_r(name: &CStr) -> Result<Option<PasswdEntry<'_>>> {
+        Self::inject(|entry| entry, || S::getpwnam_r(name))
+    }
+
+    fn getpwuid_r(uid: uid_t) -> Result<Option<PasswdEntry<'static>>> {
+        Self::inject(|entry| entry, || S::getpwuid_r(uid))
+    }
+
+    fn setpwent() -> Result<Entries<PasswdEntry<'static>>> {
+        Self::inject_entries(S::setpwent)
+    }
+
+    fn on_fork_child() {
+        S::on_fork_child()
+    }
+}
+
+fn truncate_group(mut entry: GroupEntry<'_>) -> GroupEntry<'_> {
+    entry.members.clear();
+    entry
+}
+
+impl<S: GroupService, P: FaultPlan> GroupService for FaultInjector<S, P> {
+    const LOOKUP_TIMEOUT: Option<Duration> = S::LOOKUP_TIMEOUT;
+
+    fn getgrnam_r(name: &CStr) -> Result<Option<GroupEntry<'_>>> {
+        Self::inject(truncate_group, || S::getgrnam_r(name))
+    }
+
+    fn getgrgid_r(gid: gid_t) -> Result<Option<GroupEntry<'static>>> {
+        Self::inject(truncate_group, || S::getgrgid_r(gid))
+    }
+
+    fn setgrent() -> Result<Entries<GroupEntry<'static>>> {
+        Self::inject_entries(S::setgrent)
+    }
+
+    fn initgroups_dyn(user: &CStr, group: gid_t) -> Result<Option<Vec<gid_t>>> {
+        Self::inject(|gids| gids, || S::initgroups_dyn(user, group))
+    }
+
+    fn on_fork_child() {
+        S::on_fork_child()
+    }
+}
+
+impl<S: ShadowService, P: FaultPlan> ShadowService for FaultInjector<S, P> {
+    const LOOKUP_TIMEOUT: Option<Duration> = S::LOOKUP_TIMEOUT;
+
+    fn getspnam_r(name: &CStr) -> Result<Option<ShadowEntry<'_>>> {
+        Self::inject(|entry| entry, || S::getspnam_r(name))
+    }
+
+    fn setspent() -> Result<Entries<ShadowEntry<'static>>> {
+        Self::inject_entries(S::setspent)
+    }
+
+    fn on_fork_child() {
+        S::on_fork_child()
+    }
+}
+
+#[test]
+fn test_fault_injector() {
+    use crate::testing::{gethostbyname2, FixedHosts};
+
+    struct Flaky;
+    impl FaultPlan for Flaky {
+        const TRY_AGAIN: f64 = 0.5;
+    }
+    type FlakyHosts = FaultInjector<FixedHosts, Flaky>;
+
+    let outcomes = || -> Vec<bool> {
+        FlakyHosts::reset();
+        (0..100).map(|_| gethostbyname2::<FlakyHosts>("host.test", AddressFamily::Ipv4).is_ok()).collect()
+    };
+    let first = outcomes();
+    let failures = first.iter().filter(|&&ok| !ok).count();
+    assert!((25..=75).contains(&failures), "{} of 100 lookups failed", failures);
+    assert_eq!(outcomes(), first);
+
+    struct Short;
+    impl FaultPlan for Short {
+        const TRUNCATE: f64 = 1.0;
+    }
+    let host = gethostbyname2::<FaultInjector<FixedHosts, Short>>("host.test", AddressFamily::Ipv4)
+        .unwrap().unwrap();
+    assert!(host.aliases.is_empty());
+
+    struct Panicky;
+    impl FaultPlan for Panicky {
+        const PANIC: f64 = 1.0;
+    }
+    let err = gethostbyname2::<FaultInjector<FixedHosts, Panicky>>("localhost", AddressFamily::Ipv4)
+        .unwrap_err();
+    assert_eq!((err.status(), err.errno()), (NssStatus::Unavailable, libc::EIO));
+}
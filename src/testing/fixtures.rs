@@ -0,0 +1,299 @@
+//! Services for tests: a fixed table of hosts, a service that always fails
+//! with a transient error, and a wrapper that slows another service down.
+
+use crate::errors::{Error, HostError, NssStatus, Result};
+use crate::ffi::{gid_t, uid_t};
+use crate::interfaces::{AddressFamily, Entries, GroupEntry, GroupService, HostAddressList, HostAddresses,
+                        HostEntry, HostEntryWithTtl, HostLimits, NameService, PasswdEntry, PasswdService};
+use libc::{EAGAIN, ENOENT};
+use std::borrow::Cow;
+use std::ffi::CStr;
+use std::marker::PhantomData;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::thread;
+use std::time::Duration;
+
+/// A host in `FixedHosts::HOSTS`: its name, aliases, and addresses.
+pub type FixedHost = (&'static str, &'static [&'static str], &'static [IpAddr]);
+
+/// A service that answers from a fixed table, `FixedHosts::HOSTS`, using
+/// addresses reserved for documentation:
+///
+/// | name          | aliases    | addresses                  |
+/// |---------------|------------|----------------------------|
+/// | `localhost`   |            | `127.0.0.1`, `::1`         |
+/// | `host.test`   | `www.test` | `192.0.2.1`, `2001:db8::1` |
+/// | `v4only.test` |            | `192.0.2.2`                |
+/// | `v6only.test` |            | `2001:db8::2`              |
+///
+/// Names match without regard to ASCII case. A name with no addresses of
+/// the family asked for gets `NO_DATA`, and an unknown name or address gets
+/// `Ok(None)`. A reverse lookup returns just the address asked about, as
+/// the `files` service does. Enumeration lists each host once per family.
+pub struct FixedHosts;
+
+impl FixedHosts {
+    pub const HOSTS: &'static [FixedHost] = &[
+        ("localhost", &[], &[
+            IpAddr::V4(Ipv4Addr::LOCALHOST),
+            IpAddr::V6(Ipv6Addr::LOCALHOST),
+        ]),
+        ("host.test", &["www.test"], &[
+            IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)),
+            IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1)),
+        ]),
+        ("v4only.test", &[], &[IpAddr::V4(Ipv4Addr::new(192, 0, 2, 2))]),
+        ("v6only.test", &[], &[IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 2))]),
+    ];
+}
+
+fn c_string(s: &str) -> Cow<'static, CStr> {
+    Cow::Owned(super::c_string(s))
+}
+
+/// An entry for `host` with those of its addresses `keep` accepts, or
+/// `None` if there aren't any.
+fn fixed_entry(host: &FixedHost, af: AddressFamily, keep: impl Fn(&IpAddr) -> bool) -> Option<HostEntry<'static>> {
+    let &(name, aliases, addrs) = host;
+    let addrs = addrs.iter().filter(|&addr| keep(addr));
+    let addr_list = match af {
+        AddressFamily::Ipv4 => HostAddressList::V4(addrs.filter_map(|addr| match *addr {
+            IpAddr::V4(ip) => Some(ip),
+            IpAddr::V6(_) => None,
+        }).collect()),
+        AddressFamily::Ipv6 => HostAddressList::V6(addrs.filter_map(|addr| match *addr {
+            IpAddr::V4(_) => None,
+            IpAddr::V6(ip) => Some(ip),
+        }).collect()),
+    };
+    let empty = match addr_list {
+        HostAddressList::V4(ref addrs) => addrs.is_empty(),
+        HostAddressList::V6(ref addrs) => addrs.is_empty(),
+    };
+    if empty {
+        return None;
+    }
+    Some(HostEntry {
+        name: c_string(name),
+        aliases: aliases.iter().map(|&alias| c_string(alias)).collect(),
+        addr_list,
+    })
+}
+
+impl NameService for FixedHosts {
+    fn gethostbyname2_r(name: &CStr, af: AddressFamily) -> Result<Option<HostEntry<'_>>> {
+        let name = name.to_bytes();
+        let host = FixedHosts::HOSTS.iter().find(|&&(host, aliases, _)| {
+            host.as_bytes().eq_ignore_ascii_case(name)
+                || aliases.iter().any(|alias| alias.as_bytes().eq_ignore_ascii_case(name))
+        });
+        match host {
+            None => Ok(None),
+            Some(host) => match fixed_entry(host, af, |_| true) {
+                None => Err(Error::with_host(NssStatus::NotFound, ENOENT, HostError::NoData)),
+                found => Ok(found),
+            },
+        }
+    }
+
+    fn gethostbyaddr_r(addr: &IpAddr) -> Result<Option<HostEntry<'_>>> {
+        let af = if addr.is_ipv4() { AddressFamily::Ipv4 } else { AddressFamily::Ipv6 };
+        Ok(FixedHosts::HOSTS.iter().find_map(|host| fixed_entry(host, af, |a| a == addr)))
+    }
+
+    fn sethostent(_stay_open: bool) -> Result<Entries<HostEntry<'static>>> {
+        let entries = FixedHosts::HOSTS.iter()
+            .flat_map(|host| {
+                IntoIterator::into_iter([AddressFamily::Ipv4, AddressFamily::Ipv6])
+                    .filter_map(move |af| fixed_entry(host, af, |_| true))
+            })
+            .map(Ok);
+        Ok(Box::new(entries))
+    }
+}
+
+/// A service whose every lookup fails with `NssStatus::TryAgain`: errno
+/// `EAGAIN`, and for hosts, h_errno `TRY_AGAIN`. This is what a service
+/// reports when its server is unreachable.
+pub struct AlwaysTryAgain;
+
+fn try_again<T>() -> Result<T> {
+    Err(Error::with_host(NssStatus::TryAgain, EAGAIN, HostError::TryAgain))
+}
+
+impl NameService for AlwaysTryAgain {
+    fn gethostbyname2_r(_name: &CStr, _af: AddressFamily) -> Result<Option<HostEntry<'_>>> {
+        try_again()
+    }
+
+    fn gethostbyaddr_r(_addr: &IpAddr) -> Result<Option<HostEntry<'_>>> {
+        try_again()
+    }
+
+    fn sethostent(_stay_open: bool) -> Result<Entries<HostEntry<'static>>> {
+        try_again()
+    }
+}
+
+impl PasswdService for AlwaysTryAgain {
+    fn getpwnam_r(_name: &CStr) -> Result<Option<PasswdEntry<'_>>> {
+        Err(Error::with_errno(NssStatus::TryAgain, EAGAIN))
+    }
+
+    fn getpwuid_r(_uid: uid_t) -> Result<Option<PasswdEntry<'static>>> {
+        Err(Error::with_errno(NssStatus::TryAgain, EAGAIN))
+    }
+
+    fn setpwent() -> Result<Entries<PasswdEntry<'static>>> {
+        Err(Error::with_errno(NssStatus::TryAgain, EAGAIN))
+    }
+}
+
+impl GroupService for AlwaysTryAgain {
+    fn getgrnam_r(_name: &CStr) -> Result<Option<GroupEntry<'_>>> {
+        Err(Error::with_errno(NssStatus::TryAgain, EAGAIN))
+    }
+
+    fn getgrgid_r(_gid: gid_t) -> Result<Option<GroupEntry<'static>>> {
+        Err(Error::with_errno(NssStatus::TryAgain, EAGAIN))
+    }
+
+    fn setgrent() -> Result<Entries<GroupEntry<'static>>> {
+        Err(Error::with_errno(NssStatus::TryAgain, EAGAIN))
+    }
+}
+
+/// The service `S`, except that every lookup first sleeps for `MILLIS`
+/// milliseconds, for testing timeouts. Services are types, not values, so
+/// the delay is part of the type: `Slow<FixedHosts, 500>`.
+///
+/// Everything else, including `S`'s settings such as `LOOKUP_TIMEOUT`,
+/// is passed through unchanged.
+pub struct Slow<S, const MILLIS: u64>(PhantomData<S>);
+
+impl<S, const MILLIS: u64> Slow<S, MILLIS> {
+    pub const DELAY: Duration = Duration::from_millis(MILLIS);
+
+    fn sleep() {
+        thread::sleep(Self::DELAY);
+    }
+}
+
+impl<S: NameService, const MILLIS: u64> NameService for Slow<S, MILLIS> {
+    const ALLOW_EMPTY_ADDRESS_LIST: bool = S::ALLOW_EMPTY_ADDRESS_LIST;
+    const LOOKUP_TIMEOUT: Option<Duration> = S::LOOKUP_TIMEOUT;
+    const LIMITS: HostLimits = S::LIMITS;
+    const VALIDATE_HOSTNAMES: bool = S::VALIDATE_HOSTNAMES;
+
+    fn gethostbyname_r(name: &CStr) -> Result<Option<HostEntry<'_>>> {
+        Self::sleep();
+        S::gethostbyname_r(name)
+    }
+
+    fn gethostbyname2_r(name: &CStr, af: AddressFamily) -> Result<Option<HostEntry<'_>>> {
+        Self::sleep();
+        S::gethostbyname2_r(name, af)
+    }
+
+    fn gethostbyaddr_r(addr: &IpAddr) -> Result<Option<HostEntry<'_>>> {
+        Self::sleep();
+        S::gethostbyaddr_r(addr)
+    }
+
+    fn gethostbyname3_r(name: &CStr, af: AddressFamily) -> Result<Option<HostEntryWithTtl<'_>>> {
+        Self::sleep();
+        S::gethostbyname3_r(name, af)
+    }
+
+    fn gethostbyname4_r(name: &CStr) -> Result<Option<HostAddresses<'_>>> {
+        Self::sleep();
+        S::gethostbyname4_r(name)
+    }
+
+    fn gethostbyaddr2_r(addr: &IpAddr) -> Result<Option<HostEntryWithTtl<'_>>> {
+        Self::sleep();
+        S::gethostbyaddr2_r(addr)
+    }
+
+    fn sethostent(stay_open: bool) -> Result<Entries<HostEntry<'static>>> {
+        Self::sleep();
+        S::sethostent(stay_open)
+    }
+
+    fn on_fork_child() {
+        S::on_fork_child()
+    }
+}
+
+impl<S: PasswdService, const MILLIS: u64> PasswdService for Slow<S, MILLIS> {
+    const LOOKUP_TIMEOUT: Option<Duration> = S::LOOKUP_TIMEOUT;
+
+    fn getpwnam_r(name: &CStr) -> Result<Option<PasswdEntry<'_>>> {
+        Self::sleep();
+        S::getpwnam_r(name)
+    }
+
+    fn getpwuid_r(uid: uid_t) -> Result<Option<PasswdEntry<'static>>> {
+        Self::sleep();
+        S::getpwuid_r(uid)
+    }
+
+    fn setpwent() -> Result<Entries<PasswdEntry<'static>>> {
+        Self::sleep();
+        S::setpwent()
+    }
+
+    fn on_fork_child() {
+        S::on_fork_child()
+    }
+}
+
+impl<S: GroupService, const MILLIS: u64> GroupService for Slow<S, MILLIS> {
+    const LOOKUP_TIMEOUT: Option<Duration> = S::LOOKUP_TIMEOUT;
+
+    fn getgrnam_r(name: &CStr) -> Result<Option<GroupEntry<'_>>> {
+        Self::sleep();
+        S::getgrnam_r(name)
+    }
+
+    fn getgrgid_r(gid: gid_t) -> Result<Option<GroupEntry<'static>>> {
+        Self::sleep();
+        S::getgrgid_r(gid)
+    }
+
+    fn setgrent() -> Result<Entries<GroupEntry<'static>>> {
+        Self::sleep();
+        S::setgrent()
+    }
+
+    fn on_fork_child() {
+        S::on_fork_child()
+    }
+}
+
+#[test]
+fn test_fixtures() {
+    use crate::testing::{gethostbyaddr, gethostbyname2, gethostbyname4, getpwnam};
+    use std::time::Instant;
+
+    let host = gethostbyname2::<FixedHosts>("WWW.test", AddressFamily::Ipv6).unwrap().unwrap();
+    assert_eq!(host.name.to_str(), Ok("host.test"));
+    let addrs = gethostbyname4::<FixedHosts>("localhost").unwrap().unwrap().addrs;
+    assert_eq!(addrs, [IpAddr::V6(Ipv6Addr::LOCALHOST), IpAddr::V4(Ipv4Addr::LOCALHOST)]);
+    let err = gethostbyname2::<FixedHosts>("v4only.test", AddressFamily::Ipv6).unwrap_err();
+    assert_eq!(err.host_error(), Some(HostError::NoData));
+    assert!(gethostbyname2::<FixedHosts>("nowhere.test", AddressFamily::Ipv4).unwrap().is_none());
+    let host = gethostbyaddr::<FixedHosts>("192.0.2.2".parse().unwrap()).unwrap().unwrap();
+    assert_eq!(host.name.to_str(), Ok("v4only.test"));
+    assert_eq!(FixedHosts::sethostent(false).unwrap().count(), 6);
+
+    let err = gethostbyname2::<AlwaysTryAgain>("host.test", AddressFamily::Ipv4).unwrap_err();
+    assert_eq!((err.status(), err.host_error()), (NssStatus::TryAgain, Some(HostError::TryAgain)));
+    let err = getpwnam::<AlwaysTryAgain>("root").unwrap_err();
+    assert_eq!((err.status(), err.errno()), (NssStatus::TryAgain, EAGAIN));
+
+    let start = Instant::now();
+    let host = gethostbyname2::<Slow<FixedHosts, 50>>("host.test", AddressFamily::Ipv4).unwrap().unwrap();
+    assert!(start.elapsed() >= Slow::<FixedHosts, 50>::DELAY);
+    assert_eq!(host.name.to_str(), Ok("host.test"));
+}
@@ -0,0 +1,313 @@
+//! Calling a built module through `dlopen`, the way glibc loads it.
+
+use super::c_string;
+use crate::errors::{Error, NssStatus, Result};
+use crate::ffi::{c_int, c_void, gaih_addrtuple, gid_t, group, hostent, passwd, uid_t};
+use crate::foreign::{retry, EndEntFn, GrByGidFn, GrByNameFn, GrEntFn, HostByAddrFn, HostByName2Fn, HostByName4Fn};
+use crate::foreign::{HostByNameFn, HostEntFn, PwByNameFn, PwByUidFn, PwEntFn, SetEntFn, SetHostEntFn};
+use crate::interfaces::{AddressFamily, GroupEntry, HostAddresses, HostEntry, PasswdEntry};
+use libc::{AF_INET, AF_INET6};
+use std::ffi::{CStr, CString};
+use std::io;
+use std::net::IpAddr;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::{mem, ptr};
+
+/// A module loaded with `dlopen`.
+///
+/// Unlike the functions in `testing`, which call a service directly, this
+/// calls the module's exported `_nss_NAME_*` functions by name, so it
+/// catches what only shows up in the built library: misspelled or missing
+/// symbols, symbols the version script hides, and functions whose
+/// signatures don't match what glibc passes.
+///
+/// ```no_run
+/// use nsswitch_service::testing::Module;
+///
+/// let module = Module::open("target/debug/examples/libnss_loopback.so").unwrap();
+/// assert!(module.gethostbyname("localhost.test").unwrap().is_some());
+/// ```
+///
+/// Calling a function the module doesn't export panics.
+pub struct Module {
+    handle: *mut c_void,
+    name: String,
+}
+
+fn dlerror() -> io::Error {
+    let message = unsafe { libc::dlerror() };
+    let message = if message.is_null() {
+        "unknown dlopen error".to_string()
+    } else {
+        unsafe { CStr::from_ptr(message) }.to_string_lossy().into_owned()
+    };
+    io::Error::other(message)
+}
+
+/// The service name in a library's file name: `loopback` in
+/// `libnss_loopback.so.2` (glibc) or `nss_loopback.so.1` (the BSDs and
+/// illumos).
+pub(super) fn service_name(path: &Path) -> Option<&str> {
+    let file_name = path.file_name()?.to_str()?;
+    let rest = file_name.strip_prefix("lib").unwrap_or(file_name).strip_prefix("nss_")?;
+    let name = &rest[..rest.find('.')?];
+    if name.is_empty() { None } else { Some(name) }
+}
+
+impl Module {
+    /// Load the module at `path`. The service name comes from the file name,
+    /// which must look like `libnss_NAME.so` or `libnss_NAME.so.2`. A path
+    /// with no `/` in it is looked up like any other library.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Module> {
+        let path = path.as_ref();
+        let name = service_name(path)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput,
+                                          format!("not an NSS module file name: {}", path.display())))?;
+        let c_path = CString::new(path.as_os_str().as_bytes())
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+        // glibc loads modules with RTLD_LAZY, but binding every symbol now
+        // turns an unresolved reference into an error here.
+        let handle = unsafe { libc::dlopen(c_path.as_ptr(), libc::RTLD_NOW | libc::RTLD_LOCAL) };
+        if handle.is_null() {
+            return Err(dlerror());
+        }
+        Ok(Module { handle, name: name.to_string() })
+    }
+
+    /// The service name, as in `_nss_NAME_gethostbyname2_r`.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The address of the module's `_nss_NAME_function`, or `None` if it
+    /// doesn't export one.
+    pub fn symbol(&self, function: &str) -> Option<*mut c_void> {
+        let symbol = c_string(&format!("_nss_{}_{}", self.name, function));
+        let p = unsafe { libc::dlsym(self.handle, symbol.as_ptr()) };
+        if p.is_null() { None } else { Some(p) }
+    }
+
+    /// The module's `_nss_NAME_function`, as a function of type `F`.
+    ///
+    /// # Safety
+    ///
+    /// `F` must be the function's real type.
+    unsafe fn function<F: Copy>(&self, function: &str) -> F {
+        match self.symbol(function) {
+            None => panic!("module {} has no function _nss_{}_{}", self.name, self.name, function),
+            Some(p) => mem::transmute_copy(&p),
+        }
+    }
+
+    pub fn gethostbyname(&self, name: &str) -> Result<Option<HostEntry<'static>>> {
+        let f: HostByNameFn = unsafe { self.function("gethostbyname_r") };
+        let name = c_string(name);
+        retry(|buffer, buflen, errno, h_errno| unsafe {
+            let mut result: hostent = mem::zeroed();
+            match status(f(name.as_ptr(), &mut result, buffer, buflen, errno, h_errno)) {
+                NssStatus::Success => Ok(HostEntry::read_from(&result)),
+                status => Err(status),
+            }
+        })
+    }
+
+    pub fn gethostbyname2(&self, name: &str, af: AddressFamily) -> Result<Option<HostEntry<'static>>> {
+        let f: HostByName2Fn = unsafe { self.function("gethostbyname2_r") };
+        let name = c_string(name);
+        let af = match af {
+            AddressFamily::Ipv4 => AF_INET,
+            AddressFamily::Ipv6 => AF_INET6,
+        };
+        retry(|buffer, buflen, errno, h_errno| unsafe {
+            let mut result: hostent = mem::zeroed();
+            match status(f(name.as_ptr(), af, &mut result, buffer, buflen, errno, h_errno)) {
+                NssStatus::Success => Ok(HostEntry::read_from(&result)),
+                status => Err(status),
+            }
+        })
+    }
+
+    pub fn gethostbyname4(&self, name: &str) -> Result<Option<HostAddresses<'static>>> {
+        let f: HostByName4Fn = unsafe { self.function("gethostbyname4_r") };
+        let name = c_string(name);
+        retry(|buffer, buflen, errno, h_errno| unsafe {
+            let mut pat: *mut gaih_addrtuple = ptr::null_mut();
+            let mut ttl = -1;
+            match status(f(name.as_ptr(), &mut pat, buffer, buflen, errno, h_errno, &mut ttl)) {
+                NssStatus::Success => {
+                    let ttl = if ttl < 0 { None } else { Some(ttl as u32) };
+                    Ok(HostAddresses { ttl, ..HostAddresses::read_from(pat) })
+                }
+                status => Err(status),
+            }
+        })
+    }
+
+    pub fn gethostbyaddr(&self, addr: IpAddr) -> Result<Option<HostEntry<'static>>> {
+        let f: HostByAddrFn = unsafe { self.function("gethostbyaddr_r") };
+        let (af, octets) = match addr {
+            IpAddr::V4(ip) => (AF_INET, ip.octets().to_vec()),
+            IpAddr::V6(ip) => (AF_INET6, ip.octets().to_vec()),
+        };
+        retry(|buffer, buflen, errno, h_errno| unsafe {
+            let mut result: hostent = mem::zeroed();
+            match status(f(octets.as_ptr() as *const c_void, octets.len() as c_int, af,
+                           &mut result, buffer, buflen, errno, h_errno)) {
+                NssStatus::Success => Ok(HostEntry::read_from(&result)),
+                status => Err(status),
+            }
+        })
+    }
+
+    pub fn getpwnam(&self, name: &str) -> Result<Option<PasswdEntry<'static>>> {
+        let f: PwByNameFn = unsafe { self.function("getpwnam_r") };
+        let name = c_string(name);
+        retry(|buffer, buflen, errno, _| unsafe {
+            let mut result: passwd = mem::zeroed();
+            match status(f(name.as_ptr(), &mut result, buffer, buflen, errno)) {
+                NssStatus::Success => Ok(PasswdEntry::read_from(&result)),
+                status => Err(status),
+            }
+        })
+    }
+
+    pub fn getpwuid(&self, uid: uid_t) -> Result<Option<PasswdEntry<'static>>> {
+        let f: PwByUidFn = unsafe { self.function("getpwuid_r") };
+        retry(|buffer, buflen, errno, _| unsafe {
+            let mut result: passwd = mem::zeroed();
+            match status(f(uid, &mut result, buffer, buflen, errno)) {
+                NssStatus::Success => Ok(PasswdEntry::read_from(&result)),
+                status => Err(status),
+            }
+        })
+    }
+
+    pub fn getgrnam(&self, name: &str) -> Result<Option<GroupEntry<'static>>> {
+        let f: GrByNameFn = unsafe { self.function("getgrnam_r") };
+        let name = c_string(name);
+        retry(|buffer, buflen, errno, _| unsafe {
+            let mut result: group = mem::zeroed();
+            match status(f(name.as_ptr(), &mut result, buffer, buflen, errno)) {
+                NssStatus::Success => Ok(GroupEntry::read_from(&result)),
+                status => Err(status),
+            }
+        })
+    }
+
+    pub fn getgrgid(&self, gid: gid_t) -> Result<Option<GroupEntry<'static>>> {
+        let f: GrByGidFn = unsafe { self.function("getgrgid_r") };
+        retry(|buffer, buflen, errno, _| unsafe {
+            let mut result: group = mem::zeroed();
+            match status(f(gid, &mut result, buffer, buflen, errno)) {
+                NssStatus::Success => Ok(GroupEntry::read_from(&result)),
+                status => Err(status),
+            }
+        })
+    }
+
+    /// Read every host with `sethostent`, `gethostent_r`, and `endhostent`.
+    pub fn hosts(&self) -> Result<Vec<HostEntry<'static>>> {
+        let set: SetHostEntFn = unsafe { self.function("sethostent") };
+        let get: HostEntFn = unsafe { self.function("gethostent_r") };
+        let end: EndEntFn = unsafe { self.function("endhostent") };
+        check_setent(unsafe { set(0) })?;
+        enumerate(|| retry(|buffer, buflen, errno, h_errno| unsafe {
+            let mut result: hostent = mem::zeroed();
+            match status(get(&mut result, buffer, buflen, errno, h_errno)) {
+                NssStatus::Success => Ok(HostEntry::read_from(&result)),
+                status => Err(status),
+            }
+        }), end)
+    }
+
+    /// Read every user with `setpwent`, `getpwent_r`, and `endpwent`.
+    pub fn users(&self) -> Result<Vec<PasswdEntry<'static>>> {
+        let set: SetEntFn = unsafe { self.function("setpwent") };
+        let get: PwEntFn = unsafe { self.function("getpwent_r") };
+        let end: EndEntFn = unsafe { self.function("endpwent") };
+        check_setent(unsafe { set() })?;
+        enumerate(|| retry(|buffer, buflen, errno, _| unsafe {
+            let mut result: passwd = mem::zeroed();
+            match status(get(&mut result, buffer, buflen, errno)) {
+                NssStatus::Success => Ok(PasswdEntry::read_from(&result)),
+                status => Err(status),
+            }
+        }), end)
+    }
+
+    /// Read every group with `setgrent`, `getgrent_r`, and `endgrent`.
+    pub fn groups(&self) -> Result<Vec<GroupEntry<'static>>> {
+        let set: SetEntFn = unsafe { self.function("setgrent") };
+        let get: GrEntFn = unsafe { self.function("getgrent_r") };
+        let end: EndEntFn = unsafe { self.function("endgrent") };
+        check_setent(unsafe { set() })?;
+        enumerate(|| retry(|buffer, buflen, errno, _| unsafe {
+            let mut result: group = mem::zeroed();
+            match status(get(&mut result, buffer, buflen, errno)) {
+                NssStatus::Success => Ok(GroupEntry::read_from(&result)),
+                status => Err(status),
+            }
+        }), end)
+    }
+}
+
+/// Read entries with `next` until it finds no more, then call `end`,
+/// whatever happened.
+fn enumerate<E, F>(mut next: F, end: EndEntFn) -> Result<Vec<E>>
+where
+    F: FnMut() -> Result<Option<E>>,
+{
+    let mut entries = vec![];
+    let result = loop {
+        match next() {
+            Ok(Some(entry)) => entries.push(entry),
+            Ok(None) => break Ok(entries),
+            Err(err) => break Err(err),
+        }
+    };
+    unsafe {
+        end();
+    }
+    result
+}
+
+/// Check the status of a `setXXent` call.
+fn check_setent(raw: c_int) -> Result<()> {
+    match status(raw) {
+        NssStatus::Success => Ok(()),
+        status => Err(Error::with_errno(status, libc::EIO)),
+    }
+}
+
+/// Check a status returned by a module, which could be anything.
+fn status(raw: c_int) -> NssStatus {
+    NssStatus::from_raw(raw).unwrap_or_else(|| panic!("module returned unknown status {}", raw))
+}
+
+impl Drop for Module {
+    fn drop(&mut self) {
+        unsafe {
+            libc::dlclose(self.handle);
+        }
+    }
+}
+
+#[cfg(all(target_os = "linux", target_env = "gnu"))]
+#[test]
+#[cfg_attr(miri, ignore = "calls dlopen")]
+fn test_open_system_module() {
+    assert_eq!(service_name(Path::new("target/debug/libnss_loopback.so")), Some("loopback"));
+    assert_eq!(service_name(Path::new("nss_loopback.so.1")), Some("loopback"));
+    assert_eq!(service_name(Path::new("libloopback.so")), None);
+
+    // glibc's own `files` service, which every glibc system has.
+    let module = Module::open("libnss_files.so.2").unwrap();
+    assert_eq!(module.name(), "files");
+    assert!(module.symbol("getpwnam_r").is_some());
+    assert!(module.symbol("no_such_function").is_none());
+    let root = module.getpwuid(0).unwrap().unwrap();
+    assert_eq!(root.name.to_str(), Ok("root"));
+    assert!(module.users().unwrap().iter().any(|user| user.uid == 0));
+    assert!(Module::open("libnss_no_such_module.so.2").is_err());
+}
@@ -0,0 +1,122 @@
+//! Running real programs against a built module, with glibc itself loading
+//! it, without installing anything.
+
+use super::module::service_name;
+use std::ffi::OsStr;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// A directory holding a module and an `nsswitch.conf` that uses it, and a
+/// way to run programs that see them.
+///
+/// glibc reads only `/etc/nsswitch.conf`, so the programs run in a mount
+/// namespace of their own, made with `unshare(1)` from util-linux, in which
+/// the staged file is mounted over it. That needs unprivileged user
+/// namespaces, which some distributions turn off. The module is found
+/// through `LD_LIBRARY_PATH`, and nscd, if it's running, is hidden so
+/// that every lookup goes through the module.
+///
+/// ```no_run
+/// use nsswitch_service::testing::Sandbox;
+///
+/// let sandbox = Sandbox::new("target/debug/examples/libnss_loopback.so").unwrap();
+/// let output = sandbox.getent("ahosts", &["localhost.test"]).unwrap();
+/// assert!(output.status.success());
+/// ```
+///
+/// This catches what only shows up when glibc drives the module: how it
+/// calls each function, which functions it calls for `getaddrinfo`, and
+/// how it treats the results. The directory is deleted on drop.
+pub struct Sandbox {
+    dir: PathBuf,
+    name: String,
+}
+
+impl Sandbox {
+    /// Stage the module at `path`, whose file name must look like
+    /// `libnss_NAME.so`, with an `nsswitch.conf` that uses only this module
+    /// for `hosts`, `passwd`, and `group`.
+    pub fn new<P: AsRef<Path>>(path: P) -> io::Result<Sandbox> {
+        let path = path.as_ref();
+        let name = service_name(path)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput,
+                                          format!("not an NSS module file name: {}", path.display())))?
+            .to_string();
+
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+        let dir = std::env::temp_dir().join(format!(
+            "nsswitch-sandbox-{}-{}", std::process::id(), COUNT.fetch_add(1, Ordering::Relaxed)));
+        let sandbox = Sandbox { dir, name };
+        fs::create_dir(&sandbox.dir)?;
+        fs::create_dir(sandbox.dir.join("lib"))?;
+        fs::create_dir(sandbox.dir.join("empty"))?;
+        fs::copy(path, sandbox.dir.join("lib").join(format!("libnss_{}.so.2", sandbox.name)))?;
+        let name = &sandbox.name;
+        sandbox.set_nsswitch_conf(&format!("hosts: {}\npasswd: {}\ngroup: {}\n", name, name, name))?;
+        Ok(sandbox)
+    }
+
+    /// The module's service name, for writing an `nsswitch.conf`.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Replace the staged `nsswitch.conf`, for example to put the module
+    /// in front of `files` or to add `[NOTFOUND=return]` actions.
+    pub fn set_nsswitch_conf(&self, text: &str) -> io::Result<()> {
+        fs::write(self.dir.join("nsswitch.conf"), text)
+    }
+
+    /// A command that runs `program` in the sandbox.
+    pub fn command<S: AsRef<OsStr>>(&self, program: S) -> Command {
+        // `unshare --map-root-user` makes us root in a new user namespace,
+        // which is allowed to mount things in the new mount namespace.
+        let script = "mount --bind \"$0/nsswitch.conf\" /etc/nsswitch.conf && \
+                      for d in /run/nscd /var/run/nscd /var/db/nscd; do \
+                          if [ -d \"$d\" ]; then mount --bind \"$0/empty\" \"$d\" || exit; fi; \
+                      done && \
+                      exec \"$@\"";
+        let mut command = Command::new("unshare");
+        command.args(["--user", "--map-root-user", "--mount", "sh", "-c", script])
+            .arg(&self.dir)
+            .arg(program)
+            .env("LD_LIBRARY_PATH", self.dir.join("lib"));
+        command
+    }
+
+    /// Run `getent database keys...` in the sandbox. Use the `ahosts`
+    /// database to go through `getaddrinfo`.
+    pub fn getent(&self, database: &str, keys: &[&str]) -> io::Result<Output> {
+        self.command("getent").arg(database).args(keys).output()
+    }
+}
+
+impl Drop for Sandbox {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+#[test]
+#[ignore = "needs unshare(1) and unprivileged user namespaces"]
+fn test_sandbox_runs_getent() {
+    // The system's own `files` module, staged like any other.
+    let files = ["/lib/x86_64-linux-gnu", "/lib/aarch64-linux-gnu", "/lib64", "/usr/lib64", "/usr/lib"]
+        .iter()
+        .map(|dir| Path::new(dir).join("libnss_files.so.2"))
+        .find(|path| path.exists())
+        .expect("can't find libnss_files.so.2");
+    let sandbox = Sandbox::new(&files).unwrap();
+    assert_eq!(sandbox.name(), "files");
+
+    let output = sandbox.getent("ahosts", &["localhost"]).unwrap();
+    assert!(output.status.success(), "getent failed: {}", String::from_utf8_lossy(&output.stderr));
+    assert!(String::from_utf8_lossy(&output.stdout).contains("STREAM localhost"));
+
+    // The staged nsswitch.conf is the one glibc reads.
+    sandbox.set_nsswitch_conf("hosts: nosuchservice\n").unwrap();
+    assert_eq!(sandbox.getent("hosts", &["localhost"]).unwrap().status.code(), Some(2));
+}
@@ -0,0 +1,196 @@
+//! Giving up on one service's lookups after a while.
+
+use crate::errors::Result;
+use crate::ffi::{gid_t, uid_t};
+use crate::interfaces::{AddressFamily, Entries, GroupEntry, GroupService, HostAddresses, HostEntry,
+                        HostEntryWithTtl, HostLimits, NameService, PasswdEntry, PasswdService, ShadowEntry,
+                        ShadowService};
+use crate::watchdog::{self, copy_name};
+use std::ffi::CStr;
+use std::marker::PhantomData;
+use std::net::IpAddr;
+use std::time::Duration;
+
+/// How long a `Timeout` service waits.
+pub trait TimeoutConfig: 'static {
+    /// How long to wait for each lookup.
+    const TIMEOUT: Duration = Duration::from_secs(5);
+}
+
+/// The usual settings.
+pub struct TimeoutDefaults;
+
+impl TimeoutConfig for TimeoutDefaults {}
+
+/// The service `S`, but with each lookup given up on after `C::TIMEOUT`,
+/// with `NssStatus::TryAgain`, errno `EAGAIN`, and h_errno `TRY_AGAIN`:
+///
+/// ```ignore
+/// nssglue_hosts!("corp", ChainService<Timeout<ConsulService>, HostsFileService<Corp>>);
+/// ```
+///
+/// This works the way `LOOKUP_TIMEOUT` does, on a thread of its own that is
+/// left to finish in the background, but for one service, so it can go
+/// inside other combinators: above, a slow Consul agent makes the chain
+/// fall back to the file instead of holding up the whole lookup. It also
+/// covers the start of enumeration, but not each entry. Overruns are logged.
+/// The limit on lookups left running is shared with `LOOKUP_TIMEOUT`.
+///
+/// `S`'s settings, including its own `LOOKUP_TIMEOUT`, are passed through
+/// unchanged.
+pub struct Timeout<S, C = TimeoutDefaults>(PhantomData<(S, C)>);
+
+impl<S: NameService + 'static, C: TimeoutConfig> NameService for Timeout<S, C> {
+    const ALLOW_EMPTY_ADDRESS_LIST: bool = S::ALLOW_EMPTY_ADDRESS_LIST;
+    const LOOKUP_TIMEOUT: Option<Duration> = S::LOOKUP_TIMEOUT;
+    const LIMITS: HostLimits = S::LIMITS;
+    const VALIDATE_HOSTNAMES: bool = S::VALIDATE_HOSTNAMES;
+
+    fn gethostbyname_r(name: &CStr) -> Result<Option<HostEntry<'_>>> {
+        let name = copy_name(name)?;
+        watchdog::call("gethostbyname_r", C::TIMEOUT, move || {
+            Ok(S::gethostbyname_r(&name)?.map(HostEntry::into_owned))
+        })
+    }
+
+    fn gethostbyname2_r(name: &CStr, af: AddressFamily) -> Result<Option<HostEntry<'_>>> {
+        let name = copy_name(name)?;
+        watchdog::call("gethostbyname2_r", C::TIMEOUT, move || {
+            Ok(S::gethostbyname2_r(&name, af)?.map(HostEntry::into_owned))
+        })
+    }
+
+    fn gethostbyaddr_r(addr: &IpAddr) -> Result<Option<HostEntry<'_>>> {
+        let addr = *addr;
+        watchdog::call("gethostbyaddr_r", C::TIMEOUT, move || {
+            Ok(S::gethostbyaddr_r(&addr)?.map(HostEntry::into_owned))
+        })
+    }
+
+    fn gethostbyname3_r(name: &CStr, af: AddressFamily) -> Result<Option<HostEntryWithTtl<'_>>> {
+        let name = copy_name(name)?;
+        watchdog::call("gethostbyname3_r", C::TIMEOUT, move || {
+            Ok(S::gethostbyname3_r(&name, af)?.map(HostEntryWithTtl::into_owned))
+        })
+    }
+
+    fn gethostbyname4_r(name: &CStr) -> Result<Option<HostAddresses<'_>>> {
+        let name = copy_name(name)?;
+        watchdog::call("gethostbyname4_r", C::TIMEOUT, move || {
+            Ok(S::gethostbyname4_r(&name)?.map(HostAddresses::into_owned))
+        })
+    }
+
+    fn gethostbyaddr2_r(addr: &IpAddr) -> Result<Option<HostEntryWithTtl<'_>>> {
+        let addr = *addr;
+        watchdog::call("gethostbyaddr2_r", C::TIMEOUT, move || {
+            Ok(S::gethostbyaddr2_r(&addr)?.map(HostEntryWithTtl::into_owned))
+        })
+    }
+
+    fn sethostent(stay_open: bool) -> Result<Entries<HostEntry<'static>>> {
+        watchdog::call("sethostent", C::TIMEOUT, move || S::sethostent(stay_open))
+    }
+
+    fn on_fork_child() {
+        S::on_fork_child()
+    }
+}
+
+impl<S: PasswdService + 'static, C: TimeoutConfig> PasswdService for Timeout<S, C> {
+    const LOOKUP_TIMEOUT: Option<Duration> = S::LOOKUP_TIMEOUT;
+
+    fn getpwnam_r(name: &CStr) -> Result<Option<PasswdEntry<'_>>> {
+        let name = copy_name(name)?;
+        watchdog::call("getpwnam_r", C::TIMEOUT, move || Ok(S::getpwnam_r(&name)?.map(PasswdEntry::into_owned)))
+    }
+
+    fn getpwuid_r(uid: uid_t) -> Result<Option<PasswdEntry<'static>>> {
+        watchdog::call("getpwuid_r", C::TIMEOUT, move || S::getpwuid_r(uid))
+    }
+
+    fn setpwent() -> Result<Entries<PasswdEntry<'static>>> {
+        watchdog::call("setpwent", C::TIMEOUT, S::setpwent)
+    }
+
+    fn on_fork_child() {
+        S::on_fork_child()
+    }
+}
+
+impl<S: GroupService + 'static, C: TimeoutConfig> GroupService for Timeout<S, C> {
+    const LOOKUP_TIMEOUT: Option<Duration> = S::LOOKUP_TIMEOUT;
+
+    fn getgrnam_r(name: &CStr) -> Result<Option<GroupEntry<'_>>> {
+        let name = copy_name(name)?;
+        watchdog::call("getgrnam_r", C::TIMEOUT, move || Ok(S::getgrnam_r(&name)?.map(GroupEntry::into_owned)))
+    }
+
+    fn getgrgid_r(gid: gid_t) -> Result<Option<GroupEntry<'static>>> {
+        watchdog::call("getgrgid_r", C::TIMEOUT, move || S::getgrgid_r(gid))
+    }
+
+    fn setgrent() -> Result<Entries<GroupEntry<'static>>> {
+        watchdog::call("setgrent", C::TIMEOUT, S::setgrent)
+    }
+
+    fn initgroups_dyn(user: &CStr, group: gid_t) -> Result<Option<Vec<gid_t>>> {
+        let user = copy_name(user)?;
+        watchdog::call("initgroups_dyn", C::TIMEOUT, move || S::initgroups_dyn(&user, group))
+    }
+
+    fn on_fork_child() {
+        S::on_fork_child()
+    }
+}
+
+impl<S: ShadowService + 'static, C: TimeoutConfig> ShadowService for Timeout<S, C> {
+    const LOOKUP_TIMEOUT: Option<Duration> = S::LOOKUP_TIMEOUT;
+
+    fn getspnam_r(name: &CStr) -> Result<Option<ShadowEntry<'_>>> {
+        let name = copy_name(name)?;
+        watchdog::call("getspnam_r", C::TIMEOUT, move || Ok(S::getspnam_r(&name)?.map(ShadowEntry::into_owned)))
+    }
+
+    fn setspent() -> Result<Entries<ShadowEntry<'static>>> {
+        watchdog::call("setspent", C::TIMEOUT, S::setspent)
+    }
+
+    fn on_fork_child() {
+        S::on_fork_child()
+    }
+}
+
+#[test]
+fn test_timeout() {
+    use crate::errors::{Error, HostError, NssStatus};
+    use crate::testing::{gethostbyname2, FixedHosts};
+    use std::time::Instant;
+
+    struct Slow;
+    impl NameService for Slow {
+        fn gethostbyname2_r(name: &CStr, af: AddressFamily) -> Result<Option<HostEntry<'_>>> {
+            if name.to_bytes() == b"slow.test" {
+                std::thread::sleep(Duration::from_secs(2));
+            }
+            FixedHosts::gethostbyname2_r(name, af)
+        }
+
+        fn gethostbyaddr_r(addr: &IpAddr) -> Result<Option<HostEntry<'_>>> {
+            FixedHosts::gethostbyaddr_r(addr)
+        }
+    }
+
+    struct Quick;
+    impl TimeoutConfig for Quick {
+        const TIMEOUT: Duration = Duration::from_millis(100);
+    }
+    type Hosts = Timeout<Slow, Quick>;
+    assert!(gethostbyname2::<Hosts>("host.test", AddressFamily::Ipv4).unwrap().is_some());
+
+    let start = Instant::now();
+    let name = CStr::from_bytes_with_nul(b"slow.test\0").unwrap();
+    let err: Error = Hosts::gethostbyname2_r(name, AddressFamily::Ipv4).unwrap_err();
+    assert!(start.elapsed() < Duration::from_secs(1));
+    assert_eq!((err.status(), err.host_error()), (NssStatus::TryAgain, Some(HostError::TryAgain)));
+}
@@ -0,0 +1,23 @@
+//! TLS client settings for the services that check a server's certificate
+//! against a file of certificate authorities.
+
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::CertificateDer;
+use std::fs;
+use std::io;
+use std::sync::Arc;
+
+/// Settings that trust the certificate authorities in `ca_file`, in PEM.
+pub(crate) fn client_config(ca_file: &str) -> io::Result<rustls::ClientConfig> {
+    let other = |err: &dyn std::fmt::Display| io::Error::other(format!("{}: {}", ca_file, err));
+    let pem = fs::read(ca_file)?;
+    let mut roots = rustls::RootCertStore::empty();
+    for cert in CertificateDer::pem_slice_iter(&pem) {
+        roots.add(cert.map_err(|err| other(&err))?).map_err(|err| other(&err))?;
+    }
+    Ok(rustls::ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .map_err(|err| other(&err))?
+        .with_root_certificates(roots)
+        .with_no_client_auth())
+}
@@ -0,0 +1,226 @@
+//! Tracing every call through the glue, for finding out why a name doesn't
+//! resolve without reaching for `strace`.
+//!
+//! When tracing is on, each `_nss_*` function the glue defines writes one
+//! line when it returns: the function, its arguments (names, address
+//! families, addresses, IDs, but never buffers), the status it returns, and
+//! how long it took:
+//!
+//! ```text
+//! nsswitch resolver: gethostbyname2_r("db.internal", AF_INET6) = NSS_STATUS_NOTFOUND in 1.2ms
+//! ```
+//!
+//! Lines are written like diagnostic messages, with a single `write(2)`, so
+//! lines from different threads and processes sharing a file don't mix.
+//! Tracing is turned on by the `NSS_DEBUG` environment variable, or by the
+//! module itself with `set_trace_sink`. With the `journald` feature, calls
+//! can also be traced to the journal, with each part in a field of its own;
+//! see `crate::journald`.
+
+use crate::config::env_var_os;
+use crate::diag;
+use crate::errno::SavedErrno;
+use crate::ffi::{c_char, c_int, c_void, NssStatus};
+use std::ffi::CStr;
+use std::fmt;
+use std::fs::OpenOptions;
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::IntoRawFd;
+use std::path::Path;
+use std::ptr;
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::time::Instant;
+
+/// The environment variable that turns on tracing: `1` or `stderr` to
+/// trace to stderr, `0` or empty not to, and anything else to append to
+/// the file of that name. With the `journald` feature, `journald` traces
+/// to the journal. It's ignored in secure mode (see
+/// `is_secure_mode`), so a user can't use it to make a setuid program
+/// write a file.
+pub const TRACE_ENV_VAR: &str = "NSS_DEBUG";
+
+/// Where trace lines go.
+#[derive(Clone, Copy, Debug)]
+pub enum TraceSink<'a> {
+    /// Don't trace.
+    Off,
+
+    /// Write each line to file descriptor 2.
+    Stderr,
+
+    /// Append each line to a file, creating it, readable only by its owner,
+    /// if it doesn't exist.
+    File(&'a Path),
+
+    /// Send each call to the systemd journal, as `crate::journald` says.
+    #[cfg(feature = "journald")]
+    Journald,
+}
+
+/// `FD` before `TRACE_ENV_VAR` has been read.
+const UNSET: c_int = -2;
+
+/// `FD` when tracing is off.
+const OFF: c_int = -1;
+
+/// `FD` when tracing to the journal.
+#[cfg(feature = "journald")]
+const JOURNALD: c_int = -3;
+
+/// The file descriptor trace lines are written to, or one of the constants
+/// above.
+static FD: AtomicI32 = AtomicI32::new(UNSET);
+
+/// Trace to `sink` from now on, whatever `TRACE_ENV_VAR` says. This fails
+/// only if a file can't be opened, and then leaves tracing as it was.
+///
+/// Files traced to earlier are left open, since another thread may be
+/// writing to one.
+pub fn set_trace_sink(sink: TraceSink<'_>) -> io::Result<()> {
+    let fd = match sink {
+        TraceSink::Off => OFF,
+        TraceSink::Stderr => 2,
+        TraceSink::File(path) => open(path)?,
+        #[cfg(feature = "journald")]
+        TraceSink::Journald => JOURNALD,
+    };
+    FD.store(fd, Ordering::Release);
+    Ok(())
+}
+
+fn open(path: &Path) -> io::Result<c_int> {
+    // Opened close-on-exec, as `std` always does.
+    let file = OpenOptions::new().append(true).create(true).mode(0o600).open(path)?;
+    Ok(file.into_raw_fd())
+}
+
+/// The descriptor to trace to, or `OFF`.
+fn fd() -> c_int {
+    let fd = FD.load(Ordering::Acquire);
+    if fd != UNSET {
+        return fd;
+    }
+    let fd = match env_var_os(TRACE_ENV_VAR) {
+        None => OFF,
+        Some(value) if value.is_empty() || value == "0" => OFF,
+        Some(value) if value == "1" || value == "stderr" => 2,
+        #[cfg(feature = "journald")]
+        Some(value) if value == "journald" => JOURNALD,
+        Some(path) => open(Path::new(&path)).unwrap_or_else(|err| {
+            diag::log(format_args!("can't open {} to trace to: {}", Path::new(&path).display(), err));
+            OFF
+        }),
+    };
+    match FD.compare_exchange(UNSET, fd, Ordering::AcqRel, Ordering::Acquire) {
+        Ok(_) => fd,
+        Err(current) => {
+            // `set_trace_sink` was called meanwhile.
+            if fd > 2 {
+                unsafe {
+                    libc::close(fd);
+                }
+            }
+            current
+        }
+    }
+}
+
+/// If tracing is on, the time a call started.
+pub(crate) fn start() -> Option<Instant> {
+    if fd() == OFF {
+        None
+    } else {
+        Some(Instant::now())
+    }
+}
+
+/// Trace a call to `function` with `args` that started at `start` and
+/// returned `status`. `errno` is left alone.
+pub(crate) fn finish(function: &'static str, args: fmt::Arguments<'_>, status: NssStatus, start: Instant) {
+    let fd = FD.load(Ordering::Acquire);
+    if fd == OFF || fd == UNSET {
+        return;
+    }
+    let saved_errno = SavedErrno::save();
+    let (status, elapsed) = (status_name(status), start.elapsed());
+    match fd {
+        #[cfg(feature = "journald")]
+        JOURNALD => crate::journald::send_call(function, args, status, elapsed),
+        _ => diag::write_line(fd, format_args!("{}({}) = {} in {:?}", function, args, status, elapsed)),
+    }
+    saved_errno.restore();
+}
+
+pub(crate) fn status_name(status: NssStatus) -> &'static str {
+    match status {
+        NssStatus::TryAgain => "NSS_STATUS_TRYAGAIN",
+        NssStatus::Unavailable => "NSS_STATUS_UNAVAIL",
+        NssStatus::NotFound => "NSS_STATUS_NOTFOUND",
+        NssStatus::Success => "NSS_STATUS_SUCCESS",
+    }
+}
+
+/// A name argument, for tracing: the string, quoted, or `NULL`.
+pub(crate) struct Name(pub(crate) *const c_char);
+
+impl fmt::Display for Name {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.0.is_null() {
+            return f.write_str("NULL");
+        }
+        // The glue's caller promises a valid string, and the glue checks
+        // for `NULL`.
+        let name = unsafe { CStr::from_ptr(self.0) };
+        write!(f, "{:?}", String::from_utf8_lossy(name.to_bytes()))
+    }
+}
+
+/// An address family argument, for tracing.
+pub(crate) struct Family(pub(crate) c_int);
+
+impl fmt::Display for Family {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            libc::AF_INET => f.write_str("AF_INET"),
+            libc::AF_INET6 => f.write_str("AF_INET6"),
+            af => write!(f, "{}", af),
+        }
+    }
+}
+
+/// An address argument, with its length and family, for tracing.
+pub(crate) struct Addr(pub(crate) *const c_void, pub(crate) c_int, pub(crate) c_int);
+
+impl fmt::Display for Addr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let Addr(addr, len, af) = *self;
+        if addr.is_null() {
+            return f.write_str("NULL");
+        }
+        // The glue's caller promises `len` readable bytes.
+        match (af, len) {
+            (libc::AF_INET, 4) => {
+                let octets: [u8; 4] = unsafe { ptr::read_unaligned(addr as *const [u8; 4]) };
+                write!(f, "{}", Ipv4Addr::from(octets))
+            }
+            (libc::AF_INET6, 16) => {
+                let octets: [u8; 16] = unsafe { ptr::read_unaligned(addr as *const [u8; 16]) };
+                write!(f, "{}", Ipv6Addr::from(octets))
+            }
+            _ => write!(f, "<{} bytes>", len),
+        }
+    }
+}
+
+#[test]
+fn test_trace_arguments() {
+    let name = CStr::from_bytes_with_nul(b"db.internal\0").unwrap();
+    let octets = [192_u8, 0, 2, 1];
+    let call = format!("f({}, {}, {}, {}, {})", Name(name.as_ptr()), Name(ptr::null()), Family(libc::AF_INET6),
+                       Addr(octets.as_ptr() as *const c_void, 4, libc::AF_INET),
+                       Addr(octets.as_ptr() as *const c_void, 3, libc::AF_INET));
+    assert_eq!(call, r#"f("db.internal", NULL, AF_INET6, 192.0.2.1, <3 bytes>)"#);
+    assert_eq!(status_name(NssStatus::Unavailable), "NSS_STATUS_UNAVAIL");
+}
@@ -0,0 +1,133 @@
+//! Running lookups with a deadline; see `NameService::LOOKUP_TIMEOUT`.
+//!
+//! There's no way to interrupt a thread, so the lookup runs on a thread of
+//! its own and the caller stops waiting for it. Everything the lookup uses
+//! must be owned, since it may outlive the caller's arguments.
+//!
+//! Abandoned lookups keep their threads until they finish, so a backend that
+//! hangs for good would otherwise cost a thread per lookup, without limit.
+//! Once `MAX_WORKERS` lookups are running, further lookups time out at once.
+
+use crate::diag;
+use crate::errors::{Error, NssStatus, Result};
+use crate::pin::pin_module;
+use crate::reentry::LookupGuard;
+#[cfg(feature = "tracing")]
+use crate::spans;
+use std::ffi::{CStr, CString};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread;
+use std::time::Duration;
+
+/// The most lookup threads that may be running at once, finished or not.
+const MAX_WORKERS: usize = 16;
+
+/// Places for lookup threads, one for each that's running.
+struct Workers {
+    running: AtomicUsize,
+}
+
+static WORKERS: Workers = Workers { running: AtomicUsize::new(0) };
+
+impl Workers {
+    /// Take a place, if there's one free.
+    fn start(&'static self) -> Option<Worker> {
+        let take = |running: usize| if running < MAX_WORKERS { Some(running + 1) } else { None };
+        self.running.fetch_update(Ordering::AcqRel, Ordering::Acquire, take).ok().map(|_| Worker(self))
+    }
+}
+
+/// A lookup thread's place, given up when dropped.
+struct Worker(&'static Workers);
+
+impl Drop for Worker {
+    fn drop(&mut self) {
+        self.0.running.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// Copy `name` for a lookup thread, reporting failure to allocate as an
+/// error rather than aborting.
+pub(crate) fn copy_name(name: &CStr) -> Result<CString> {
+    let bytes = name.to_bytes();
+    let mut copy = Vec::new();
+    copy.try_reserve_exact(bytes.len() + 1)?;
+    copy.extend_from_slice(bytes);
+    // `bytes` came from a `CStr`, so it has no NULs.
+    Ok(CString::new(copy).unwrap_or_default())
+}
+
+/// Call `lookup` on a new thread and wait up to `timeout` for it to finish.
+/// A panic in `lookup` is propagated to the caller. If `MAX_WORKERS` lookups
+/// are already running, this times out without calling `lookup`.
+pub(crate) fn call<R, F>(function: &str, timeout: Duration, lookup: F) -> Result<R>
+where
+    R: Send + 'static,
+    F: FnOnce() -> Result<R> + Send + 'static,
+{
+    // The thread may still be running module code after the caller returns,
+    // and after the caller unloads the module, if it does that.
+    pin_module();
+
+    let worker = match WORKERS.start() {
+        Some(worker) => worker,
+        None => {
+            diag::log(format_args!("{} not started: {} earlier lookups are still running, reporting \
+                                    NSS_STATUS_TRYAGAIN", function, MAX_WORKERS));
+            return Err(Error::timed_out());
+        }
+    };
+
+    // With room for the result, the worker never blocks on sending it, even
+    // if nobody is waiting anymore.
+    let (sender, receiver) = mpsc::sync_channel(1);
+    #[cfg(feature = "tracing")]
+    let span = tracing::Span::current();
+    let spawned = thread::Builder::new()
+        .name("nss lookup".to_string())
+        .spawn(move || {
+            let _worker = worker;
+            // The worker is part of the lookup, as far as reentry goes.
+            let _guard = LookupGuard::enter();
+            #[cfg(feature = "tracing")]
+            let _span = spans::enter_thread(span);
+            let _ = sender.send(panic::catch_unwind(AssertUnwindSafe(lookup)));
+        });
+    if spawned.is_err() {
+        return Err(Error::timed_out());
+    }
+
+    match receiver.recv_timeout(timeout) {
+        Ok(Ok(result)) => result,
+        Ok(Err(payload)) => panic::resume_unwind(payload),
+        Err(RecvTimeoutError::Timeout) => {
+            diag::log_timeout(function, timeout);
+            Err(Error::timed_out())
+        }
+        Err(RecvTimeoutError::Disconnected) => {
+            // Only if the thread died some way `catch_unwind` doesn't see,
+            // such as a foreign exception.
+            diag::log(format_args!("{} lookup thread exited without a result, reporting NSS_STATUS_UNAVAIL",
+                                   function));
+            Err(Error::with_errno(NssStatus::Unavailable, libc::EIO))
+        }
+    }
+}
+
+#[test]
+fn test_worker_limit() {
+    static PLACES: Workers = Workers { running: AtomicUsize::new(0) };
+    let mut running: Vec<Worker> = (0..MAX_WORKERS).map(|_| PLACES.start().unwrap()).collect();
+    assert!(PLACES.start().is_none());
+    running.pop();
+    assert!(PLACES.start().is_some());
+
+    assert_eq!(call("gethostbyname2_r", Duration::from_secs(10), || Ok(7)).unwrap(), 7);
+    let err = call("gethostbyname2_r", Duration::from_millis(1), || {
+        thread::sleep(Duration::from_millis(100));
+        Ok(())
+    }).unwrap_err();
+    assert_eq!(err.status(), NssStatus::TryAgain);
+}
@@ -0,0 +1,272 @@
+//! A ready-made `hosts` service for dnsmasq's `address=` rules, which map a
+//! domain and everything under it to fixed addresses.
+
+use crate::config::env_var;
+use crate::diag;
+use crate::errors::{Error, HostError, NssStatus, Result};
+use crate::ffi::c_int;
+use crate::fork::ForkSafeMutex;
+use crate::interfaces::{AddressFamily, HostAddressList, HostEntry, NameService};
+use libc::{EINVAL, EIO, ENOENT};
+use std::borrow::Cow;
+use std::ffi::CStr;
+use std::fs;
+use std::io;
+use std::marker::PhantomData;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::sync::Arc;
+
+/// Where a `WildcardService` gets its rules. Either or both may be given.
+pub trait WildcardConfig: 'static {
+    /// A file of rules, one per line. Other lines, such as comments and
+    /// dnsmasq's other options, are ignored, so this can be a
+    /// `dnsmasq.conf`. It's fine if the file doesn't exist.
+    const PATH: Option<&'static str> = None;
+
+    /// An environment variable with more rules, separated by whitespace.
+    /// Setuid programs ignore it (see `env_var`).
+    const ENV_VAR: Option<&'static str> = None;
+}
+
+/// A `hosts` service that answers from dnsmasq-style rules, the usual way
+/// to point a development or staging domain somewhere else:
+///
+/// ```text
+/// address=/internal.example/10.1.2.3
+/// address=/internal.example/fd00::3
+/// address=/a.test/b.test/127.0.0.1
+/// address=/ads.example/#
+/// address=/blocked.example/
+/// ```
+///
+/// A rule matches its domains and every name under them, so the first line
+/// answers `internal.example` and `db.internal.example` alike. The longest
+/// matching domain wins, and the domain `#` matches every name. The
+/// addresses of every rule for that domain are combined. The target `#`
+/// means `0.0.0.0` and `::`, and no target means the name doesn't exist.
+/// A name with no addresses of the family asked for is `NO_DATA`, and names
+/// no rule matches are left to the next service. There are no reverse
+/// lookups.
+///
+/// The rules are read once, the first time the module needs them. If a rule
+/// doesn't parse, a message goes to the diagnostic sink and every lookup
+/// reports `NssStatus::Unavailable`.
+///
+/// ```ignore
+/// struct DevDomains;
+///
+/// impl WildcardConfig for DevDomains {
+///     const PATH: Option<&'static str> = Some("/etc/nss_wildcard.conf");
+///     const ENV_VAR: Option<&'static str> = Some("NSS_WILDCARD_RULES");
+/// }
+///
+/// nssglue_hosts!("wildcard", WildcardService<DevDomains>);
+/// ```
+pub struct WildcardService<C>(PhantomData<C>);
+
+/// One `address=` rule for one domain.
+#[derive(Debug, PartialEq)]
+struct Rule {
+    /// Lowercase, with no trailing dot; empty for `#`.
+    domain: String,
+    /// `None` if the name doesn't exist.
+    addrs: Option<Vec<IpAddr>>,
+}
+
+#[derive(Debug, Default)]
+struct Rules {
+    rules: Vec<Rule>,
+}
+
+fn normalize(domain: &str) -> String {
+    domain.trim_end_matches('.').to_ascii_lowercase()
+}
+
+/// Parse `address=/domain/.../target`, with or without `address=`.
+fn parse_rule(text: &str, rules: &mut Vec<Rule>) -> std::result::Result<(), String> {
+    let spec = text.strip_prefix("address=").unwrap_or(text);
+    let parts: Vec<&str> = match spec.strip_prefix('/') {
+        Some(rest) => rest.split('/').collect(),
+        None => return Err(format!("expected address=/domain/address: {}", text)),
+    };
+    let (target, domains) = parts.split_last().unwrap();
+    if domains.is_empty() {
+        return Err(format!("no domain: {}", text));
+    }
+    let addrs = match *target {
+        "" => None,
+        "#" => Some(vec![IpAddr::V4(Ipv4Addr::UNSPECIFIED), IpAddr::V6(Ipv6Addr::UNSPECIFIED)]),
+        addr => Some(vec![addr.parse().map_err(|_| format!("bad address {:?}: {}", addr, text))?]),
+    };
+    for &domain in domains {
+        let domain = if domain == "#" { String::new() } else { normalize(domain) };
+        rules.push(Rule { domain, addrs: addrs.clone() });
+    }
+    Ok(())
+}
+
+impl Rules {
+    /// Parse the `address=` lines of a config file.
+    fn parse_file(&mut self, text: &str) -> std::result::Result<(), String> {
+        text.lines()
+            .map(str::trim)
+            .filter(|line| line.starts_with("address="))
+            .try_for_each(|line| parse_rule(line, &mut self.rules))
+    }
+
+    /// Parse whitespace-separated rules.
+    fn parse_list(&mut self, text: &str) -> std::result::Result<(), String> {
+        text.split_whitespace().try_for_each(|rule| parse_rule(rule, &mut self.rules))
+    }
+
+    /// The addresses for `name`, `Some(None)` if it doesn't exist, or
+    /// `None` if no rule matches.
+    fn lookup(&self, name: &str) -> Option<Option<Vec<IpAddr>>> {
+        let name = normalize(name);
+        let matches = |domain: &str| {
+            domain.is_empty()
+                || name == domain
+                || (name.ends_with(domain) && name[..name.len() - domain.len()].ends_with('.'))
+        };
+        let longest = self.rules.iter()
+            .filter(|rule| matches(&rule.domain))
+            .map(|rule| rule.domain.len())
+            .max()?;
+        let mut result: Option<Vec<IpAddr>> = None;
+        for rule in self.rules.iter().filter(|rule| rule.domain.len() == longest && matches(&rule.domain)) {
+            if let Some(ref addrs) = rule.addrs {
+                result.get_or_insert_with(Vec::new).extend(addrs);
+            }
+        }
+        Some(result)
+    }
+}
+
+/// Read the rules `C` points to, or return the errno to report.
+fn load<C: WildcardConfig>() -> std::result::Result<Arc<Rules>, c_int> {
+    let mut rules = Rules::default();
+    if let Some(path) = C::PATH {
+        match fs::read_to_string(path) {
+            Ok(text) => rules.parse_file(&text).map_err(|message| {
+                diag::log(format_args!("can't parse {}: {}", path, message));
+                EINVAL
+            })?,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => {
+                diag::log(format_args!("can't read {}: {}", path, err));
+                return Err(err.raw_os_error().unwrap_or(EIO));
+            }
+        }
+    }
+    if let Some(var) = C::ENV_VAR {
+        if let Some(text) = env_var(var) {
+            rules.parse_list(&text).map_err(|message| {
+                diag::log(format_args!("can't parse ${}: {}", var, message));
+                EINVAL
+            })?;
+        }
+    }
+    Ok(Arc::new(rules))
+}
+
+type Loaded = std::result::Result<Arc<Rules>, c_int>;
+
+/// A configuration: `PATH` and `ENV_VAR`.
+type Key = (Option<&'static str>, Option<&'static str>);
+
+/// The rules loaded so far, by configuration.
+static RULES: ForkSafeMutex<Vec<(Key, Loaded)>> = ForkSafeMutex::new();
+
+impl<C: WildcardConfig> WildcardService<C> {
+    const KEY: Key = (C::PATH, C::ENV_VAR);
+
+    /// What was loaded for this configuration, if anything has been.
+    fn cached() -> Option<Loaded> {
+        RULES.lock().iter().find(|&&(key, _)| key == Self::KEY).map(|(_, loaded)| loaded.clone())
+    }
+
+    /// The rules, loaded the first time they're needed. The file is read
+    /// without holding the lock; if two threads both read it, the first to
+    /// finish wins.
+    fn rules() -> Result<Arc<Rules>> {
+        let loaded = match Self::cached() {
+            Some(loaded) => loaded,
+            None => {
+                let loaded = load::<C>();
+                let mut rules = RULES.lock();
+                match rules.iter().find(|&&(key, _)| key == Self::KEY) {
+                    Some((_, existing)) => existing.clone(),
+                    None => {
+                        rules.push((Self::KEY, loaded.clone()));
+                        loaded
+                    }
+                }
+            }
+        };
+        loaded.map_err(|errno| Error::with_errno(NssStatus::Unavailable, errno))
+    }
+}
+
+impl<C: WildcardConfig> NameService for WildcardService<C> {
+    fn gethostbyname2_r(name: &CStr, af: AddressFamily) -> Result<Option<HostEntry<'_>>> {
+        let name_str = match name.to_str() {
+            Ok(s) => s,
+            Err(_) => return Ok(None),
+        };
+        let addrs = match Self::rules()?.lookup(name_str) {
+            None => return Ok(None),
+            Some(None) => return Err(Error::from_dns_rcode(3, None)),
+            Some(Some(addrs)) => addrs,
+        };
+        let addr_list = match af {
+            AddressFamily::Ipv4 => HostAddressList::V4(addrs.iter().filter_map(|addr| match *addr {
+                IpAddr::V4(ip) => Some(ip),
+                IpAddr::V6(_) => None,
+            }).collect()),
+            AddressFamily::Ipv6 => HostAddressList::V6(addrs.iter().filter_map(|addr| match *addr {
+                IpAddr::V4(_) => None,
+                IpAddr::V6(ip) => Some(ip),
+            }).collect()),
+        };
+        if addr_list.is_empty() {
+            return Err(Error::with_host(NssStatus::NotFound, ENOENT, HostError::NoData));
+        }
+        Ok(Some(HostEntry { name: Cow::Borrowed(name), aliases: vec![], addr_list }))
+    }
+
+    fn gethostbyaddr_r(_addr: &IpAddr) -> Result<Option<HostEntry<'_>>> {
+        Ok(None)
+    }
+
+    fn on_fork_child() {
+        RULES.reset();
+    }
+}
+
+#[test]
+fn test_wildcard_rules() {
+    let mut rules = Rules::default();
+    rules.parse_file("# dnsmasq.conf\n\
+                      no-resolv\n\
+                      address=/internal.example/10.1.2.3\n\
+                      address=/internal.example/fd00::3\n\
+                      address=/db.internal.example/10.1.2.4\n\
+                      address=/ads.example/#\n").unwrap();
+    rules.parse_list("address=/a.test/B.test./127.0.0.1 /blocked.example/").unwrap();
+
+    let addrs = |list: &[&str]| Some(Some(list.iter().map(|a| a.parse().unwrap()).collect::<Vec<IpAddr>>()));
+    assert_eq!(rules.lookup("internal.example"), addrs(&["10.1.2.3", "fd00::3"]));
+    assert_eq!(rules.lookup("Web.Internal.Example."), addrs(&["10.1.2.3", "fd00::3"]));
+    assert_eq!(rules.lookup("x.db.internal.example"), addrs(&["10.1.2.4"]));
+    assert_eq!(rules.lookup("ads.example"), addrs(&["0.0.0.0", "::"]));
+    assert_eq!(rules.lookup("www.b.test"), addrs(&["127.0.0.1"]));
+    assert_eq!(rules.lookup("blocked.example"), Some(None));
+    assert_eq!(rules.lookup("notinternal.example"), None);
+
+    rules.parse_list("/#/192.0.2.1").unwrap();
+    assert_eq!(rules.lookup("anything.else"), addrs(&["192.0.2.1"]));
+
+    assert!(Rules::default().parse_list("address=/x.test/10.0.0.300").is_err());
+    assert!(Rules::default().parse_list("address=x.test").is_err());
+    assert!(Rules::default().parse_list("address=/10.0.0.1").is_err());
+}
@@ -0,0 +1,237 @@
+//! A ready-made `hosts` service that resolves NetBIOS names, asking a WINS
+//! server or broadcasting on the local network, like Samba's `nss_wins`.
+
+use crate::diag;
+use crate::dns::{self, Header, Question, Reader, Writer};
+use crate::dns_client::random_id;
+use crate::errors::{Error, NssStatus, Result};
+use crate::interfaces::{AddressFamily, HostAddressList, HostEntry, HostEntryWithTtl, NameService};
+use libc::EINVAL;
+use std::borrow::Cow;
+use std::ffi::CStr;
+use std::io;
+use std::marker::PhantomData;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket};
+use std::time::{Duration, Instant};
+
+/// Where a `WinsService` looks for names.
+pub trait WinsConfig: 'static {
+    /// WINS servers to ask, in order, as addresses with or without a port
+    /// (137 by default).
+    const SERVERS: &'static [&'static str] = &[];
+
+    /// Broadcast addresses to send a query to if no WINS server has the
+    /// name. Empty to never broadcast.
+    const BROADCAST: &'static [&'static str] = &["255.255.255.255"];
+
+    /// How long to wait for each server, and for answers to a broadcast.
+    const TIMEOUT: Duration = Duration::from_secs(1);
+}
+
+/// The usual settings: no WINS server, and broadcasts.
+pub struct WinsDefaults;
+
+impl WinsConfig for WinsDefaults {}
+
+/// A `hosts` service that resolves single-label names as NetBIOS names
+/// (RFC 1001 and 1002), for networks where Windows machines are only known
+/// by those, on systems without Samba's `nss_wins`:
+///
+/// ```ignore
+/// struct Office;
+///
+/// impl WinsConfig for Office {
+///     const SERVERS: &'static [&'static str] = &["192.168.1.2"];
+/// }
+///
+/// nssglue_hosts!("wins", WinsService<Office>);
+/// ```
+///
+/// A name is looked up as a workstation name (suffix `0x00`) by asking each
+/// WINS server in turn, and then by broadcasting a query on port 137, until
+/// some machine has it. The entry's name is the name as given, and its
+/// TTL is the one the answer gave. NetBIOS names are IPv4 only, so IPv6
+/// lookups, and names that can't be NetBIOS names (more than 15 bytes, or
+/// with dots), are left to the next service. There are no reverse lookups.
+pub struct WinsService<C = WinsDefaults>(PhantomData<C>);
+
+const NETBIOS_PORT: u16 = 137;
+
+/// The type of a NetBIOS general name service resource record.
+const TYPE_NB: u16 = 0x20;
+
+/// The NetBIOS flag for a query that was broadcast.
+const FLAG_BROADCAST: u16 = 0x0010;
+
+/// `name` with NetBIOS suffix `suffix`, in the first-level encoding of RFC
+/// 1001, section 14.1, or `None` if it can't be a NetBIOS name.
+fn encode_name(name: &[u8], suffix: u8) -> Option<Vec<u8>> {
+    if name.is_empty() || name.len() > 15 || name.contains(&b'.') {
+        return None;
+    }
+    let mut padded = [b' '; 16];
+    padded[..name.len()].copy_from_slice(&name.to_ascii_uppercase());
+    padded[15] = suffix;
+    Some(padded.iter().flat_map(|&b| IntoIterator::into_iter([b'A' + (b >> 4), b'A' + (b & 0xf)])).collect())
+}
+
+/// The addresses and TTL in a positive name query response about
+/// `encoded`.
+fn read_addrs(message: &dns::Message<'_>, encoded: &[u8]) -> Option<(Vec<Ipv4Addr>, u32)> {
+    let record = message.answers.iter()
+        .find(|record| record.rtype == TYPE_NB && record.name.eq_ignore_ascii_case(encoded))?;
+    // Each address follows two bytes of flags.
+    let addrs: Vec<Ipv4Addr> = record.data.chunks_exact(6)
+        .map(|entry| Ipv4Addr::new(entry[2], entry[3], entry[4], entry[5]))
+        .collect();
+    if addrs.is_empty() {
+        None
+    } else {
+        Some((addrs, record.ttl))
+    }
+}
+
+/// Ask `dest` about `encoded` and wait up to `timeout` for a positive
+/// answer. A negative answer from a WINS server ends the wait.
+fn query(socket: &UdpSocket, dest: SocketAddr, encoded: &[u8], broadcast: bool, timeout: Duration)
+    -> io::Result<Option<(Vec<Ipv4Addr>, u32)>>
+{
+    let flags = if broadcast { dns::FLAG_RD | FLAG_BROADCAST } else { dns::FLAG_RD };
+    let header = Header { id: random_id(), flags, qdcount: 1, ..Header::default() };
+    let mut writer = Writer::new(&header);
+    writer.question(&Question { name: encoded.to_vec(), qtype: TYPE_NB, qclass: dns::CLASS_IN })
+        .ok_or_else(|| io::Error::from_raw_os_error(EINVAL))?;
+    socket.send_to(&writer.finish(), dest)?;
+
+    let deadline = Instant::now() + timeout;
+    let mut buf = [0_u8; 576];
+    loop {
+        let now = Instant::now();
+        if now >= deadline {
+            return Ok(None);
+        }
+        socket.set_read_timeout(Some(deadline - now))?;
+        let (len, from) = match socket.recv_from(&mut buf) {
+            Ok(received) => received,
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock || err.kind() == io::ErrorKind::TimedOut => {
+                return Ok(None);
+            }
+            Err(err) => return Err(err),
+        };
+        let message = match Reader::new(&buf[..len]).message() {
+            Some(message) if message.header.flags & dns::FLAG_QR != 0 && message.header.id == header.id => message,
+            _ => continue,
+        };
+        if message.header.flags & 0xf != dns::RCODE_NOERROR {
+            if !broadcast && from == dest {
+                return Ok(None);
+            }
+            continue;
+        }
+        if let Some(found) = read_addrs(&message, encoded) {
+            return Ok(Some(found));
+        }
+    }
+}
+
+fn parse_addr(addr: &str, diag_what: &str) -> Result<SocketAddr> {
+    addr.parse()
+        .or_else(|_| addr.parse::<IpAddr>().map(|ip| SocketAddr::new(ip, NETBIOS_PORT)))
+        .map_err(|_| {
+            diag::log(format_args!("not a {} address: {:?}", diag_what, addr));
+            Error::with_errno(NssStatus::Unavailable, EINVAL)
+        })
+}
+
+impl<C: WinsConfig> WinsService<C> {
+    /// Look up `name` on the WINS servers and then by broadcast.
+    fn resolve(name: &[u8]) -> Result<Option<(Vec<Ipv4Addr>, u32)>> {
+        let encoded = match encode_name(name, 0x00) {
+            Some(encoded) => encoded,
+            None => return Ok(None),
+        };
+        let dests = C::SERVERS.iter().map(|addr| Ok((parse_addr(addr, "WINS server")?, false)))
+            .chain(C::BROADCAST.iter().map(|addr| Ok((parse_addr(addr, "broadcast")?, true))))
+            .collect::<Result<Vec<_>>>()?;
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))
+            .and_then(|socket| socket.set_broadcast(true).map(|()| socket))
+            .map_err(|err| {
+                diag::log(format_args!("can't open a socket for NetBIOS queries: {}", err));
+                Error::with_errno(NssStatus::Unavailable, err.raw_os_error().unwrap_or(EINVAL))
+            })?;
+        for (dest, broadcast) in dests {
+            match query(&socket, dest, &encoded, broadcast, C::TIMEOUT) {
+                Ok(Some(found)) => return Ok(Some(found)),
+                Ok(None) => {}
+                Err(err) => diag::log(format_args!("can't ask {} about {}: {}",
+                                                   dest, String::from_utf8_lossy(name), err)),
+            }
+        }
+        Ok(None)
+    }
+}
+
+impl<C: WinsConfig> NameService for WinsService<C> {
+    fn gethostbyname2_r(name: &CStr, af: AddressFamily) -> Result<Option<HostEntry<'_>>> {
+        Ok(Self::gethostbyname3_r(name, af)?.map(|found| found.entry))
+    }
+
+    fn gethostbyaddr_r(_addr: &IpAddr) -> Result<Option<HostEntry<'_>>> {
+        Ok(None)
+    }
+
+    fn gethostbyname3_r(name: &CStr, af: AddressFamily) -> Result<Option<HostEntryWithTtl<'_>>> {
+        if af != AddressFamily::Ipv4 {
+            return Ok(None);
+        }
+        let bytes = name.to_bytes();
+        Ok(Self::resolve(bytes.strip_suffix(b".").unwrap_or(bytes))?.map(|(addrs, ttl)| HostEntryWithTtl {
+            entry: HostEntry { name: Cow::Borrowed(name), aliases: vec![], addr_list: HostAddressList::V4(addrs) },
+            ttl: Some(ttl),
+        }))
+    }
+}
+
+#[test]
+fn test_wins_query() {
+    use std::thread;
+
+    // The example in RFC 1001, section 14.1.
+    assert_eq!(encode_name(b"Fred", 0x20).unwrap(), b"EGFCEFEECACACACACACACACACACACACA");
+    assert_eq!(encode_name(b"fred", 0x00).unwrap(), b"EGFCEFEECACACACACACACACACACACAAA");
+    assert!(encode_name(b"fileserver.corp", 0x00).is_none() && encode_name(b"sixteen-letters!", 0).is_none());
+
+    // A WINS server that knows FRED, as a multihomed host, and says no to
+    // anything else. Its answers don't repeat the question, as in RFC 1002.
+    let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let dest = server.local_addr().unwrap();
+    let serving = thread::spawn(move || {
+        let mut buf = [0_u8; 576];
+        for _ in 0..2 {
+            let (len, peer) = server.recv_from(&mut buf).unwrap();
+            let query = Reader::new(&buf[..len]).message().unwrap();
+            let question = &query.questions[0];
+            let known = question.name == encode_name(b"FRED", 0x00).unwrap();
+            let header = Header {
+                flags: dns::FLAG_QR | if known { 0 } else { dns::RCODE_NXDOMAIN },
+                qdcount: 0,
+                ancount: known as u16,
+                ..query.header
+            };
+            let mut writer = Writer::new(&header);
+            if known {
+                writer.record(&question.name, TYPE_NB, 300, &[0, 0, 10, 0, 0, 7, 0x60, 0, 10, 1, 0, 7]).unwrap();
+            }
+            server.send_to(&writer.finish(), peer).unwrap();
+        }
+    });
+
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let timeout = Duration::from_secs(5);
+    let found = query(&socket, dest, &encode_name(b"fred", 0x00).unwrap(), false, timeout).unwrap();
+    assert_eq!(found, Some((vec![Ipv4Addr::new(10, 0, 0, 7), Ipv4Addr::new(10, 1, 0, 7)], 300)));
+    let start = Instant::now();
+    assert_eq!(query(&socket, dest, &encode_name(b"barney", 0x00).unwrap(), false, timeout).unwrap(), None);
+    assert!(start.elapsed() < timeout);
+    serving.join().unwrap();
+}
@@ -0,0 +1,63 @@
+//! Load the `nss_exports` example, which `cargo test` builds along with the
+//! tests, and check that the export macros exported what they should.
+
+#![cfg(all(target_os = "linux", target_env = "gnu"))]
+
+use nsswitch_service::testing::Module;
+use nsswitch_service::{AddressFamily, HostAddressList};
+use std::env;
+use std::net::Ipv4Addr;
+
+fn open() -> Module {
+    // This test is target/debug/deps/exports-HASH.
+    let mut path = env::current_exe().unwrap();
+    path.pop();
+    path.pop();
+    path.push("examples/libnss_exports.so");
+    Module::open(&path).unwrap_or_else(|err| panic!("can't load {} ({}); run `cargo test`", path.display(), err))
+}
+
+#[test]
+#[cfg_attr(miri, ignore = "calls dlopen")]
+fn test_exports() {
+    let module = open();
+
+    // `#[nss_export]` exports the methods the impl defines, and no others.
+    for function in &["gethostbyname2_r", "gethostbyaddr_r", "sethostent", "gethostent_r", "endhostent"] {
+        assert!(module.symbol(function).is_some(), "{} is missing", function);
+    }
+    for function in &["gethostbyname_r", "gethostbyname3_r", "gethostbyname4_r", "gethostbyaddr2_r"] {
+        assert!(module.symbol(function).is_none(), "{} shouldn't be exported", function);
+    }
+    let found = module.gethostbyname2("exports.test", AddressFamily::Ipv4).unwrap().unwrap();
+    assert!(matches!(found.addr_list, HostAddressList::V4(ref addrs) if addrs == &[Ipv4Addr::new(192, 0, 2, 1)]));
+    assert_eq!(module.hosts().unwrap().len(), 1);
+
+    // `nss_module!` exports every function of each database it's given.
+    for function in &["getpwnam_r", "getpwuid_r", "setpwent", "getgrnam_r", "initgroups_dyn", "endgrent"] {
+        assert!(module.symbol(function).is_some(), "{} is missing", function);
+    }
+    assert!(module.symbol("getspnam_r").is_none());
+    assert_eq!(module.getpwnam("exporter").unwrap().unwrap().uid, 4242);
+    assert_eq!(module.getgrgid(4242).unwrap().unwrap().members.len(), 1);
+    assert!(module.getpwuid(0).unwrap().is_none());
+    assert!(module.users().unwrap().is_empty());
+
+    // The hook was registered when the library was loaded, and runs in the
+    // child after a fork.
+    assert!(module.gethostbyname2("forked.test", AddressFamily::Ipv4).unwrap().is_none());
+    unsafe {
+        match libc::fork() {
+            -1 => panic!("fork failed"),
+            0 => {
+                let found = module.gethostbyname2("forked.test", AddressFamily::Ipv4);
+                libc::_exit(if matches!(found, Ok(Some(_))) { 0 } else { 1 });
+            }
+            child => {
+                let mut status = 0;
+                assert_eq!(libc::waitpid(child, &mut status, 0), child);
+                assert!(libc::WIFEXITED(status) && libc::WEXITSTATUS(status) == 0);
+            }
+        }
+    }
+}